@@ -0,0 +1,82 @@
+//! Whole-program benchmarks, running small guest kernels from `guests/`
+//! to completion.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use yove::xous::MachineBuilder;
+
+struct Kernel {
+    name: &'static str,
+    program: &'static [u8],
+    exit_code: u32,
+
+    /// What to report throughput in, if not guest instructions.
+    elements: Option<u64>,
+}
+
+const KERNELS: [Kernel; 3] = [
+    Kernel {
+        name: "dhrystone",
+        program: include_bytes!("guests/dhrystone.elf"),
+        exit_code: 0,
+        elements: None,
+    },
+    Kernel {
+        name: "coremark",
+        program: include_bytes!("guests/coremark.elf"),
+        exit_code: 0x83fe,
+        elements: None,
+    },
+    Kernel {
+        name: "syscall_roundtrip",
+        program: include_bytes!("guests/syscall.elf"),
+        exit_code: 0,
+        // `ecall` doesn't retire, so count the syscalls instead
+        elements: Some(10_000),
+    },
+];
+
+/// Run `kernel` to completion, returning the number of instructions it retired.
+fn run(kernel: &Kernel) -> u64 {
+    run_with(kernel, MachineBuilder::new())
+}
+
+fn run_with(kernel: &Kernel, builder: MachineBuilder) -> u64 {
+    let mut machine = builder.build(kernel.program).unwrap();
+    assert_eq!(kernel.exit_code, machine.run().unwrap(), "{}", kernel.name);
+    machine.instructions_retired()
+}
+
+fn kernels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kernel");
+    group.sample_size(20);
+    for kernel in &KERNELS {
+        // Report throughput in guest instructions per second
+        let elements = kernel.elements.unwrap_or_else(|| run(kernel));
+        group.throughput(Throughput::Elements(elements));
+        group.bench_function(kernel.name, |b| b.iter(|| run(kernel)));
+    }
+    group.finish();
+}
+
+/// Dhrystone with the default RAM and with 512MB, whose pages are mostly
+/// never given host memory, to show that looking them up costs the same.
+fn memory_sizes(c: &mut Criterion) {
+    let kernel = &KERNELS[0];
+    let mut group = c.benchmark_group("memory_size");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(run(kernel)));
+    for megabytes in [16, 512] {
+        group.bench_function(format!("{}MB", megabytes), |b| {
+            b.iter(|| {
+                run_with(
+                    kernel,
+                    MachineBuilder::new().memory_size(megabytes * 1024 * 1024),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, kernels, memory_sizes);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+//! Bulk copies between the host and guest memory: loading a program, and
+//! reading and writing a buffer as lends and patches do, both word aligned
+//! and not.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use yove::xous::{Machine, MachineBuilder};
+
+const PROGRAM: &[u8] = include_bytes!("guests/coremark.elf");
+
+/// How much each read and write copies.
+const LENGTH: u32 = 16 * 4096;
+
+/// The start of `LENGTH` bytes of mapped memory in `machine`.
+fn buffer(machine: &Machine) -> u32 {
+    let pages: Vec<u32> = machine.mappings().iter().map(|m| m.virt).collect();
+    *pages
+        .iter()
+        .find(|&&page| (1..LENGTH / 4096 + 1).all(|n| pages.contains(&(page + n * 4096))))
+        .expect("no run of mapped pages long enough")
+}
+
+fn load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load");
+    group.throughput(Throughput::Bytes(PROGRAM.len() as u64));
+    group.bench_function("coremark", |b| {
+        b.iter(|| MachineBuilder::new().build(PROGRAM).unwrap())
+    });
+    group.finish();
+}
+
+fn copies(c: &mut Criterion) {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    let start = buffer(&machine);
+    let data = vec![0x5a; LENGTH as usize];
+    let mut group = c.benchmark_group("copy");
+    group.throughput(Throughput::Bytes(LENGTH as u64));
+    for (name, offset) in [("aligned", 0), ("unaligned", 1)] {
+        group.bench_function(format!("read/{}", name), |b| {
+            b.iter(|| machine.read_memory(start + offset, LENGTH).unwrap())
+        });
+        group.bench_function(format!("write/{}", name), |b| {
+            b.iter(|| machine.patch(start + offset, &data).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, load, copies);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use yove::xous::MachineBuilder;
+
+/// A guest that drains ring 0 until the host closes it. See `guests/ring_consumer.S`.
+const RING_CONSUMER: &[u8] = include_bytes!("guests/ring_consumer.elf");
+
+/// Stream `total` bytes from the host to the guest in `chunk`-sized writes.
+fn stream_to_guest(total: usize, chunk: usize) -> Duration {
+    let mut machine = MachineBuilder::new().build(RING_CONSUMER).unwrap();
+    let ring = machine.ring_buffer(0);
+    let guest = std::thread::spawn(move || machine.run().unwrap());
+    while !ring.is_attached() {
+        ring.wait(Duration::from_millis(10));
+    }
+
+    let data = vec![0x5a; chunk];
+    let start = Instant::now();
+    let mut remaining = total;
+    while remaining > 0 {
+        let written = ring.write(&data[..chunk.min(remaining)]);
+        if written == 0 {
+            ring.wait(Duration::from_millis(1));
+        }
+        remaining -= written;
+    }
+    ring.close();
+    assert_eq!(guest.join().unwrap(), 0);
+    start.elapsed()
+}
+
+fn ring_buffer(c: &mut Criterion) {
+    const TOTAL: usize = 16 * 1024 * 1024;
+    let mut group = c.benchmark_group("ring_buffer");
+    group.throughput(Throughput::Bytes(TOTAL as u64));
+    group.sample_size(10);
+    for chunk in [256, 4096, 32768] {
+        group.bench_function(format!("host_to_guest/{}", chunk), |b| {
+            b.iter_custom(|iterations| (0..iterations).map(|_| stream_to_guest(TOTAL, chunk)).sum())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ring_buffer);
+criterion_main!(benches);
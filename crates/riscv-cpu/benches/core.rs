@@ -0,0 +1,212 @@
+//! Microbenchmarks for the pieces of the interpreter loop.
+
+use std::hint::black_box;
+use std::sync::{Arc, RwLock};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use riscv_cpu::cpu::{Memory, CSR_MSTATUS_ADDRESS, CSR_SATP_ADDRESS};
+use riscv_cpu::mmu::{MemoryAccessType, SystemBus};
+use riscv_cpu::syscall::{SyscallBackend, SyscallResult};
+use riscv_cpu::Cpu;
+
+const MEMORY_BASE: u32 = 0x8000_0000;
+const MEMORY_SIZE: usize = 64 * 1024;
+
+/// Plain RAM with no translation cache, so every access under paging walks
+/// the page table.
+#[derive(Clone)]
+struct FlatMemory {
+    data: Arc<RwLock<Vec<u32>>>,
+}
+
+impl FlatMemory {
+    fn new() -> Self {
+        FlatMemory {
+            data: Arc::new(RwLock::new(vec![0; MEMORY_SIZE / 4])),
+        }
+    }
+
+    fn index(address: u32) -> usize {
+        ((address - MEMORY_BASE) / 4) as usize
+    }
+}
+
+impl Memory for FlatMemory {
+    fn read_u8(&self, address: u32) -> u8 {
+        (self.read_u32(address & !3) >> ((address & 3) * 8)) as u8
+    }
+
+    fn read_u16(&self, address: u32) -> u16 {
+        u16::from_le_bytes([self.read_u8(address), self.read_u8(address + 1)])
+    }
+
+    fn read_u32(&self, address: u32) -> u32 {
+        if address & 3 != 0 {
+            return u32::from_le_bytes(std::array::from_fn(|i| self.read_u8(address + i as u32)));
+        }
+        self.data.read().unwrap()[Self::index(address)]
+    }
+
+    fn write_u8(&self, address: u32, value: u8) {
+        let shift = (address & 3) * 8;
+        let mut data = self.data.write().unwrap();
+        let word = &mut data[Self::index(address & !3)];
+        *word = (*word & !(0xff << shift)) | (value as u32) << shift;
+    }
+
+    fn write_u16(&self, address: u32, value: u16) {
+        for (offset, byte) in value.to_le_bytes().into_iter().enumerate() {
+            self.write_u8(address + offset as u32, byte);
+        }
+    }
+
+    fn write_u32(&self, address: u32, value: u32) {
+        if address & 3 != 0 {
+            for (offset, byte) in value.to_le_bytes().into_iter().enumerate() {
+                self.write_u8(address + offset as u32, byte);
+            }
+            return;
+        }
+        self.data.write().unwrap()[Self::index(address)] = value;
+    }
+
+    fn validate_address(&self, address: u32) -> bool {
+        (MEMORY_BASE..MEMORY_BASE + MEMORY_SIZE as u32).contains(&address)
+    }
+
+    fn translate(&self, _v_address: u32, _access_type: &MemoryAccessType) -> Option<u32> {
+        None
+    }
+
+    fn reserve(&self, _core: u32, _p_address: u32) {}
+
+    fn clear_reservation(&self, _core: u32, _p_address: u32) -> bool {
+        true
+    }
+
+    fn clone(&self) -> Box<dyn Memory + Send + Sync> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+impl SyscallBackend for FlatMemory {
+    fn syscall(&self, _args: [i32; 8]) -> SyscallResult {
+        [0; 8].into()
+    }
+}
+
+impl SystemBus for FlatMemory {}
+
+fn create_cpu() -> (Cpu, FlatMemory) {
+    let memory = FlatMemory::new();
+    (Cpu::new(Box::new(Clone::clone(&memory))), memory)
+}
+
+/// A mix of common instructions, from several dispatch entries.
+const DECODE_WORDS: [u32; 8] = [
+    0x00108093, // addi x1, x1, 1
+    0x00a12023, // sw x10, 0(x2)
+    0x00012503, // lw x10, 0(x2)
+    0xfe051ee3, // bnez x10, -4
+    0x008000ef, // jal x1, 8
+    0x02b50533, // mul x10, x10, x11
+    0x0805252f, // amoswap.w x10, x0, (x10)
+    0x10500073, // wfi
+];
+
+fn decode(c: &mut Criterion) {
+    let (cpu, _memory) = create_cpu();
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(DECODE_WORDS.len() as u64));
+    group.bench_function("mixed", |b| {
+        b.iter(|| {
+            for word in DECODE_WORDS {
+                black_box(cpu.instruction_name(black_box(word)));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn uncompress(c: &mut Criterion) {
+    let (cpu, _memory) = create_cpu();
+    // Every halfword that is a compressed instruction (the low bits aren't 0b11)
+    let halfwords: Vec<u16> = (0..=u16::MAX).filter(|h| h & 3 != 3).collect();
+    let mut group = c.benchmark_group("uncompress");
+    group.throughput(Throughput::Elements(halfwords.len() as u64));
+    group.bench_function("all", |b| {
+        b.iter(|| {
+            for halfword in &halfwords {
+                black_box(cpu.expand_compressed(black_box(*halfword)));
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Run a tight `addi`/`bne` loop through `Cpu::tick()`.
+fn tick_loop(c: &mut Criterion) {
+    const ITERATIONS: u64 = 10_000;
+    let (mut cpu, memory) = create_cpu();
+    // addi x1, x1, 1; bne x1, x2, -4; j 0
+    memory.write_u32(MEMORY_BASE, 0x00108093);
+    memory.write_u32(MEMORY_BASE + 4, 0xfe209ee3);
+    memory.write_u32(MEMORY_BASE + 8, 0x0000006f);
+
+    let mut group = c.benchmark_group("tick");
+    group.throughput(Throughput::Elements(ITERATIONS * 2));
+    group.bench_function("addi_bne_loop", |b| {
+        b.iter(|| {
+            cpu.update_pc(MEMORY_BASE);
+            cpu.write_register(1, 0);
+            cpu.write_register(2, ITERATIONS as i32);
+            let start = cpu.instructions_retired();
+            while cpu.instructions_retired() - start < ITERATIONS * 2 {
+                cpu.tick();
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Load through an Sv32 mapping, walking the page table every time.
+fn page_walk(c: &mut Criterion) {
+    const ROOT_TABLE: u32 = MEMORY_BASE + 0x1000;
+    const LEAF_TABLE: u32 = MEMORY_BASE + 0x2000;
+    const DATA_PAGE: u32 = MEMORY_BASE + 0x3000;
+    const DATA_VIRT: u32 = 0x1000_0000;
+    const PTE_V: u32 = 1 << 0;
+    const PTE_RWAD: u32 = (1 << 1) | (1 << 2) | (1 << 6) | (1 << 7);
+
+    let (mut cpu, memory) = create_cpu();
+    memory.write_u32(
+        ROOT_TABLE + (DATA_VIRT >> 22) * 4,
+        (LEAF_TABLE >> 12) << 10 | PTE_V,
+    );
+    memory.write_u32(
+        LEAF_TABLE + ((DATA_VIRT >> 12) & 0x3ff) * 4,
+        (DATA_PAGE >> 12) << 10 | PTE_RWAD | PTE_V,
+    );
+    memory.write_u32(DATA_PAGE, 0x1234_5678);
+
+    // Drop to supervisor mode so that loads are translated
+    cpu.write_csr(CSR_SATP_ADDRESS, 0x8000_0000 | ROOT_TABLE >> 12)
+        .unwrap();
+    cpu.write_csr(CSR_MSTATUS_ADDRESS, 1 << 11).unwrap();
+    cpu.execute_opcode(0x30200073).unwrap(); // mret
+    cpu.write_register(2, DATA_VIRT as i32);
+
+    let mut group = c.benchmark_group("page_walk");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("sv32_load", |b| {
+        b.iter(|| {
+            // lw x10, 0(x2)
+            cpu.execute_opcode(0x00012503).unwrap();
+            assert_eq!(0x1234_5678, cpu.read_register(10));
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode, uncompress, tick_loop, page_walk);
+criterion_main!(benches);
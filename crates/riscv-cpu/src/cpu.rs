@@ -1,16 +1,29 @@
-use std::{sync::mpsc::Receiver, thread::JoinHandle};
-
+mod commit;
 mod instructions;
+mod registers;
+mod state;
+mod vector;
 
 #[cfg(test)]
 mod tests;
 
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
 use crate::mmu::SystemBus;
+use crate::syscall::Suspension;
 
+pub use self::commit::Commit;
 use self::instructions::{Instruction, InstructionOperation};
+pub use self::registers::{Register, RegisterFile};
+pub use self::state::{CpuState, StateDelta};
+pub use self::vector::{MAX_VLEN, MIN_VLEN};
 
-pub use super::mmu::Memory;
 use super::mmu::{AddressingMode, Mmu};
+pub use super::mmu::{Memory, MemoryAccess};
 
 const CSR_CAPACITY: usize = 4096;
 
@@ -30,6 +43,7 @@ const CSR_SEDELEG_ADDRESS: u16 = 0x102;
 const CSR_SIDELEG_ADDRESS: u16 = 0x103;
 const CSR_SIE_ADDRESS: u16 = 0x104;
 const CSR_STVEC_ADDRESS: u16 = 0x105;
+pub const CSR_SCOUNTEREN_ADDRESS: u16 = 0x106;
 const _CSR_SSCRATCH_ADDRESS: u16 = 0x140;
 pub const CSR_SEPC_ADDRESS: u16 = 0x141;
 const CSR_SCAUSE_ADDRESS: u16 = 0x142;
@@ -43,6 +57,7 @@ const CSR_MIDELEG_ADDRESS: u16 = 0x303;
 const CSR_MIE_ADDRESS: u16 = 0x304;
 
 const CSR_MTVEC_ADDRESS: u16 = 0x305;
+pub const CSR_MCOUNTEREN_ADDRESS: u16 = 0x306;
 const _CSR_MSCRATCH_ADDRESS: u16 = 0x340;
 const CSR_MEPC_ADDRESS: u16 = 0x341;
 const CSR_MCAUSE_ADDRESS: u16 = 0x342;
@@ -51,11 +66,74 @@ const CSR_MIP_ADDRESS: u16 = 0x344;
 const _CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
 const _CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
 const _CSR_MCYCLE_ADDRESS: u16 = 0xb00;
-const CSR_CYCLE_ADDRESS: u16 = 0xc00;
-// const CSR_TIME_ADDRESS: u16 = 0xc01;
-const _CSR_INSERT_ADDRESS: u16 = 0xc02;
+pub const CSR_CYCLE_ADDRESS: u16 = 0xc00;
+pub const CSR_TIME_ADDRESS: u16 = 0xc01;
+pub const CSR_INSTRET_ADDRESS: u16 = 0xc02;
+pub const CSR_CYCLEH_ADDRESS: u16 = 0xc80;
+pub const CSR_TIMEH_ADDRESS: u16 = 0xc81;
+pub const CSR_INSTRETH_ADDRESS: u16 = 0xc82;
 pub const CSR_MHARTID_ADDRESS: u16 = 0xf14;
 
+/// Counters that change on every tick, which `Cpu::capture_state` leaves out
+/// so that they don't show up in every `StateDelta`.
+const CSR_COUNTERS: [u16; 6] = [
+    CSR_CYCLE_ADDRESS,
+    CSR_TIME_ADDRESS,
+    CSR_INSTRET_ADDRESS,
+    CSR_CYCLEH_ADDRESS,
+    CSR_TIMEH_ADDRESS,
+    CSR_INSTRETH_ADDRESS,
+];
+
+/// A user-mode CSR from the custom read/write range. Writing to it passes the
+/// value to `Memory::hypercall`, and reading it returns what that call returned.
+pub const CSR_HYPERCALL_ADDRESS: u16 = 0x8c0;
+
+/// The CSRs this CPU implements, as inclusive ranges of addresses. Accessing
+/// any other CSR raises an illegal instruction exception unless the CPU was
+/// made permissive with `Cpu::permissive_csrs`. The PMP, hardware performance
+/// monitor, and ID CSRs read as zero. The vector CSRs are only there with a
+/// vector unit.
+const IMPLEMENTED_CSRS: [(u16, u16); 17] = [
+    (0x000, 0x005), // ustatus to utvec, and the floating point CSRs
+    (0x040, 0x044), // uscratch to uip
+    (0x100, 0x100), // sstatus
+    (0x102, 0x106), // sedeleg to scounteren
+    (0x140, 0x144), // sscratch to sip
+    (0x180, 0x180), // satp
+    (0x300, 0x306), // mstatus to mcounteren
+    (0x320, 0x320), // mcountinhibit
+    (0x323, 0x33f), // mhpmevent3 to mhpmevent31
+    (0x340, 0x344), // mscratch to mip
+    (0x3a0, 0x3a3), // pmpcfg0 to pmpcfg3
+    (0x3b0, 0x3bf), // pmpaddr0 to pmpaddr15
+    (0xb00, 0xb1f), // mcycle to mhpmcounter31
+    (0xb80, 0xb9f), // mcycleh to mhpmcounter31h
+    (0xc00, 0xc1f), // cycle to hpmcounter31
+    (0xc80, 0xc9f), // cycleh to hpmcounter31h
+    (0xf11, 0xf15), // mvendorid to mconfigptr
+];
+
+/// `IMPLEMENTED_CSRS` and the hypercall CSR as a bit for each CSR address.
+static IMPLEMENTED_CSR_BITMAP: [u64; CSR_CAPACITY / 64] = implemented_csr_bitmap();
+
+const fn implemented_csr_bitmap() -> [u64; CSR_CAPACITY / 64] {
+    let mut bitmap = [0u64; CSR_CAPACITY / 64];
+    let mut range = 0;
+    while range < IMPLEMENTED_CSRS.len() {
+        let (first, last) = IMPLEMENTED_CSRS[range];
+        let mut address = first as usize;
+        while address <= last as usize {
+            bitmap[address / 64] |= 1 << (address % 64);
+            address += 1;
+        }
+        range += 1;
+    }
+    let hypercall = CSR_HYPERCALL_ADDRESS as usize;
+    bitmap[hypercall / 64] |= 1 << (hypercall % 64);
+    bitmap
+}
+
 const MIP_MEIP: u32 = 0x800;
 pub const MIP_MTIP: u32 = 0x080;
 pub const MIP_MSIP: u32 = 0x008;
@@ -63,24 +141,117 @@ pub const MIP_SEIP: u32 = 0x200;
 const MIP_STIP: u32 = 0x020;
 const MIP_SSIP: u32 = 0x002;
 
-pub type ResponseData = ([i32; 8], Option<Vec<u8>>);
+/// Interrupts in decreasing priority order, as given by the privileged spec.
+const INTERRUPT_PRIORITY: [(u32, TrapType); 6] = [
+    (MIP_MEIP, TrapType::MachineExternalInterrupt),
+    (MIP_MSIP, TrapType::MachineSoftwareInterrupt),
+    (MIP_MTIP, TrapType::MachineTimerInterrupt),
+    (MIP_SEIP, TrapType::SupervisorExternalInterrupt),
+    (MIP_SSIP, TrapType::SupervisorSoftwareInterrupt),
+    (MIP_STIP, TrapType::SupervisorTimerInterrupt),
+];
+
+// Fields of the RV32 `mstatus` register. All other bits are WPRI and read as zero.
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_UBE: u32 = 1 << 6;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_VS: u32 = 3 << 9;
+pub const MSTATUS_MPP: u32 = 3 << 11;
+pub const MSTATUS_FS: u32 = 3 << 13;
+pub const MSTATUS_XS: u32 = 3 << 15;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+pub const MSTATUS_SD: u32 = 1 << 31;
+
+/// Bits of `mstatus` that software can change. UBE is hardwired to zero since
+/// only little-endian accesses are supported, and VS and XS are zero because
+/// there are no vector or custom extensions with state to manage.
+const MSTATUS_WRITABLE: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+
+/// The view of `mstatus` exposed through `sstatus`.
+const SSTATUS_MASK: u32 = MSTATUS_SIE
+    | MSTATUS_SPIE
+    | MSTATUS_UBE
+    | MSTATUS_SPP
+    | MSTATUS_FS
+    | MSTATUS_XS
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_SD;
+
+/// Turn an arbitrary value written to `mstatus` into one that the hardware
+/// could actually hold: WPRI and unimplemented fields read as zero, the reserved
+/// privilege level in MPP becomes User mode (as it does on Spike), and SD
+/// summarizes whether any extension state is dirty.
+fn legalize_mstatus(value: u32) -> u32 {
+    let mut status = value & MSTATUS_WRITABLE;
+    if status & MSTATUS_MPP == 2 << 11 {
+        status &= !MSTATUS_MPP;
+    }
+    let dirty = |field: u32| status & field == field;
+    if dirty(MSTATUS_FS) || dirty(MSTATUS_XS) || dirty(MSTATUS_VS) {
+        status |= MSTATUS_SD;
+    }
+    status
+}
 
 pub enum TickResult {
     Ok,
     ExitThread(u32),
-    PauseEmulation(Receiver<ResponseData>),
-    JoinThread(JoinHandle<u32>),
+
+    /// The syscall backend suspended an `ecall`. The PC is past it and its
+    /// arguments are still in `a0` through `a7`.
+    Suspended(Suspension),
+    TerminateProcess(u32),
     CpuTrap(Trap),
 }
 
+/// The MODE field of `mtvec` and `stvec`, in the low two bits
+const TVEC_MODE_MASK: u32 = 0x3;
+const TVEC_MODE_VECTORED: u32 = 1;
+
+/// Returns what a trap vector CSR holding `old` holds after `value` is written
+/// to it. MODE is WARL, and the reserved modes, 2 and 3, leave it as it was.
+fn legalize_tvec(old: u32, value: u32) -> u32 {
+    match value & TVEC_MODE_MASK {
+        0 | TVEC_MODE_VECTORED => value,
+        _ => (value & !TVEC_MODE_MASK) | (old & TVEC_MODE_MASK),
+    }
+}
+
 /// Emulates a RISC-V CPU core
 pub struct Cpu {
     clock: u32,
+
+    /// Instructions that completed without trapping.
+    instret: u64,
+
+    /// The uncompressed word of the most recently executed instruction.
+    last_instruction: u32,
     privilege_mode: PrivilegeMode,
     wfi: bool,
     // using only lower 32bits of x, pc, and csr registers
     // for 32-bit mode
-    x: [i32; 32],
+    x: RegisterFile,
     pc: u32,
     csr: [u32; CSR_CAPACITY],
     mmu: Mmu,
@@ -88,15 +259,93 @@ pub struct Cpu {
     _dump_flag: bool,
     unsigned_data_mask: u32,
 
-    /// An array of known instructions. Consulting this requires a full search.
+    /// An array of known instructions.
     instructions: [instructions::Instruction; instructions::INSTRUCTION_NUM],
 
+    /// Indices into `instructions` by opcode and `funct3`, so that decoding
+    /// a word only has to check the handful of instructions that share them.
+    dispatch: &'static [[u8; instructions::DISPATCH_WIDTH]],
+
+    /// Set by an `ecall` that the syscall backend didn't return from, for
+    /// `tick()` to pass on in place of `TickResult::Ok`.
+    stopped: Option<TickResult>,
+
     /// Dumb cache to speed up C-instruction decompression. We can fit every possible
     /// C instruction here since there are only 64k of them, taking up 256k of memory.
     c_cache: Vec<Option<u32>>,
+
+    /// The vector registers, if there's a vector unit.
+    vector: Option<vector::VectorRegisters>,
+
+    /// The CSRs written by the instruction being executed, if commits are
+    /// being logged.
+    csr_writes: Option<Vec<u16>>,
+    last_commit: Option<Commit>,
+
+    /// Whether CSRs that aren't implemented can be read and written as
+    /// plain storage, rather than raising an illegal instruction exception.
+    permissive_csrs: bool,
+
+    /// What to do with compressed HINTs.
+    hints: HintPolicy,
 }
 
-#[derive(Clone, Copy, Debug)]
+/// What the CPU does with the compressed encodings that the spec sets aside
+/// as HINTs, such as `c.addi` or `c.mv` with `x0` as the destination. None
+/// of them is given a meaning yet, so they run as no-ops, but code that
+/// uses them is more likely to be data run by mistake, or built for a
+/// different core, than to mean it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HintPolicy {
+    #[default]
+    Ignore,
+
+    /// Run them as no-ops, and tell `Memory::hint` about each one.
+    Report,
+
+    /// Raise an illegal instruction exception.
+    Trap,
+}
+
+impl core::str::FromStr for HintPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "ignore" => Ok(HintPolicy::Ignore),
+            "report" => Ok(HintPolicy::Report),
+            "trap" => Ok(HintPolicy::Trap),
+            _ => Err(format!("unknown hint policy {:?}", policy)),
+        }
+    }
+}
+
+/// Whether the compressed instruction `halfword` is a HINT on RV32C: one of
+/// the encodings whose destination is `x0`, or a shift by zero, that would
+/// otherwise be a legal instruction. Shifts with `shamt[5]` set are left out,
+/// since they're reserved for custom extensions instead.
+fn is_compressed_hint(halfword: u32) -> bool {
+    let rd = (halfword >> 7) & 0x1f; // [11:7]
+    let low = (halfword >> 2) & 0x1f; // [6:2]
+    let bit12 = (halfword >> 12) & 1;
+    match (halfword & 0x3, (halfword >> 13) & 0x7) {
+        // C.NOP with an immediate, and C.ADDI without one
+        (1, 0) => (rd == 0) != (bit12 == 0 && low == 0),
+        // C.LI
+        (1, 2) => rd == 0,
+        // C.LUI with a nonzero immediate
+        (1, 3) => rd == 0 && (bit12 != 0 || low != 0),
+        // C.SRLI and C.SRAI by zero
+        (1, 4) => (halfword >> 11) & 1 == 0 && bit12 == 0 && low == 0,
+        // C.SLLI to x0 or by zero
+        (2, 0) => bit12 == 0 && (rd == 0 || low == 0),
+        // C.MV and C.ADD to x0
+        (2, 4) => rd == 0 && low != 0,
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PrivilegeMode {
     User,
     Supervisor,
@@ -104,13 +353,20 @@ pub enum PrivilegeMode {
     Machine,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Trap {
     pub trap_type: TrapType,
-    pub value: u32, // Trap type specific value
+
+    /// What gets written to the `tval` CSR, following the privileged spec.
+    /// Address misaligned, access, and page faults hold the virtual address
+    /// that faulted, which for an access that spans two pages is the first
+    /// address in the page that faulted. Illegal instructions hold the
+    /// instruction, only 16 bits of it if compressed. Environment calls and
+    /// interrupts hold zero.
+    pub value: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TrapType {
     InstructionAddressMisaligned,
     InstructionAccessFault,
@@ -135,8 +391,6 @@ pub enum TrapType {
     UserExternalInterrupt,
     SupervisorExternalInterrupt,
     MachineExternalInterrupt,
-    PauseEmulation(Receiver<ResponseData>),
-    JoinThread(JoinHandle<u32>),
 }
 
 fn _get_privilege_mode_name(mode: &PrivilegeMode) -> &'static str {
@@ -168,7 +422,7 @@ pub fn decode_privilege_mode(encoding: u32) -> PrivilegeMode {
     }
 }
 
-fn _get_trap_type_name(trap_type: &TrapType) -> &'static str {
+fn get_trap_type_name(trap_type: &TrapType) -> &'static str {
     match trap_type {
         TrapType::InstructionAddressMisaligned => "InstructionAddressMisaligned",
         TrapType::InstructionAccessFault => "InstructionAccessFault",
@@ -193,8 +447,30 @@ fn _get_trap_type_name(trap_type: &TrapType) -> &'static str {
         TrapType::UserExternalInterrupt => "UserExternalInterrupt",
         TrapType::SupervisorExternalInterrupt => "SupervisorExternalInterrupt",
         TrapType::MachineExternalInterrupt => "MachineExternalInterrupt",
-        TrapType::PauseEmulation(_) => "PauseEmulation",
-        TrapType::JoinThread(_) => "JoinThread",
+    }
+}
+
+impl core::fmt::Display for Trap {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{} (value {:08x})",
+            get_trap_type_name(&self.trap_type),
+            self.value
+        )
+    }
+}
+
+impl core::error::Error for Trap {}
+
+/// `trap`, holding the instruction `bits` if it's an illegal instruction.
+fn with_instruction(trap: Trap, bits: u32) -> Trap {
+    match trap.trap_type {
+        TrapType::IllegalInstruction => Trap {
+            trap_type: TrapType::IllegalInstruction,
+            value: bits,
+        },
+        _ => trap,
     }
 }
 
@@ -215,8 +491,6 @@ fn get_trap_cause(trap: &Trap) -> u32 {
         TrapType::InstructionPageFault => 12,
         TrapType::LoadPageFault => 13,
         TrapType::StorePageFault => 15,
-        TrapType::PauseEmulation(_) => 16,
-        TrapType::JoinThread(_) => 17,
         TrapType::UserSoftwareInterrupt => interrupt_bit,
         TrapType::SupervisorSoftwareInterrupt => interrupt_bit + 1,
         TrapType::MachineSoftwareInterrupt => interrupt_bit + 3,
@@ -232,6 +506,7 @@ fn get_trap_cause(trap: &Trap) -> u32 {
 pub struct CpuBuilder {
     pc: u32,
     sp: u32,
+    vlen: Option<u32>,
     memory: Box<dyn SystemBus>,
 }
 
@@ -241,6 +516,7 @@ impl CpuBuilder {
             memory,
             pc: 0,
             sp: 0,
+            vlen: None,
         }
     }
 
@@ -253,8 +529,19 @@ impl CpuBuilder {
         self.sp = sp;
         self
     }
+
+    /// Give the CPU a vector unit with registers `vlen` bits long. See
+    /// `Cpu::enable_vector`.
+    pub fn vlen(mut self, vlen: u32) -> Self {
+        self.vlen = Some(vlen);
+        self
+    }
+
     pub fn build(self) -> Cpu {
         let mut cpu = Cpu::new(self.memory);
+        if let Some(vlen) = self.vlen {
+            cpu.enable_vector(vlen);
+        }
         cpu.update_pc(self.pc);
         cpu.write_register(2, self.sp as i32);
         cpu
@@ -269,9 +556,11 @@ impl Cpu {
     pub fn new(memory: Box<dyn SystemBus>) -> Self {
         Cpu {
             clock: 0,
+            instret: 0,
+            last_instruction: 0,
             privilege_mode: PrivilegeMode::Machine,
             wfi: false,
-            x: [0; 32],
+            x: RegisterFile::default(),
             pc: 0,
             csr: [0; CSR_CAPACITY],
             mmu: Mmu::new(memory.clone()),
@@ -279,7 +568,14 @@ impl Cpu {
             unsigned_data_mask: !0,
             memory,
             instructions: instructions::get_instructions(),
+            dispatch: &instructions::DISPATCH_TABLE,
+            stopped: None,
             c_cache: vec![None; 65536],
+            vector: None,
+            csr_writes: None,
+            last_commit: None,
+            permissive_csrs: false,
+            hints: HintPolicy::Ignore,
         }
     }
 
@@ -294,26 +590,27 @@ impl Cpu {
     /// Reads integer register content
     ///
     /// # Arguments
-    /// * `reg` Register number. Must be 0-31
+    /// * `reg` Register number. Panics unless it is 0-31
     pub fn read_register(&self, reg: u8) -> i32 {
-        debug_assert!(reg <= 31, "reg must be 0-31. {}", reg);
-        match reg {
-            0 => 0, // 0th register is hardwired zero
-            _ => self.x[reg as usize],
-        }
+        self.x[Self::register(reg)]
     }
 
-    /// Writes integer register content
+    /// Writes integer register content. Writes to `x0` are ignored.
     ///
     /// # Arguments
-    /// * `reg` Register number. Must be 0-31
-    /// * `val` 64-bit value
+    /// * `reg` Register number. Panics unless it is 0-31
+    /// * `val` 32-bit value
     pub fn write_register(&mut self, reg: u8, val: i32) {
-        debug_assert!(reg <= 31, "reg must be 0-31. {}", reg);
-        if reg == 0 {
-            return;
-        }
-        self.x[reg as usize] = val;
+        self.x[Self::register(reg)] = val;
+    }
+
+    fn register(reg: u8) -> Register {
+        Register::new(reg).unwrap_or_else(|| panic!("reg must be 0-31. {}", reg))
+    }
+
+    /// Returns every integer register, starting with `x0`
+    pub fn registers(&self) -> &[i32; 32] {
+        self.x.as_array()
     }
 
     /// Reads Program counter content
@@ -321,29 +618,124 @@ impl Cpu {
         self.pc
     }
 
+    /// The privilege mode the CPU is currently running in
+    pub fn privilege_mode(&self) -> PrivilegeMode {
+        self.privilege_mode
+    }
+
+    /// Reads CSR content without checking privilege or causing any side effects
+    ///
+    /// # Arguments
+    /// * `address` CSR address
+    pub fn peek_csr(&self, address: u16) -> u32 {
+        self.read_csr_raw(address)
+    }
+
+    /// Take a copy of the PC, privilege mode, registers, and every CSR other
+    /// than the counters, to compare with later using `state_delta`.
+    pub fn capture_state(&self) -> CpuState {
+        CpuState {
+            pc: self.pc,
+            privilege_mode: self.privilege_mode,
+            registers: *self.x.as_array(),
+            csrs: (0..CSR_CAPACITY as u16)
+                .filter(|address| !CSR_COUNTERS.contains(address))
+                .map(|address| (address, self.csr[address as usize]))
+                .filter(|&(_, value)| value != 0)
+                .collect(),
+        }
+    }
+
+    /// What has changed since `prev` was captured, for showing what an
+    /// instruction or a run of them did.
+    pub fn state_delta(&self, prev: &CpuState) -> StateDelta {
+        self.capture_state().delta(prev)
+    }
+
+    /// Assert the interrupt lines `bits`, which are `mip` bits such as
+    /// `MIP_MEIP`, the way a device would. They stay pending until
+    /// `lower_interrupt` clears them. This also ends a `wfi` even if the
+    /// interrupt isn't enabled, which the spec allows, so that a guest with
+    /// no trap handler of its own can still be woken.
+    pub fn raise_interrupt(&mut self, bits: u32) {
+        self.csr[CSR_MIP_ADDRESS as usize] |= bits;
+        self.wfi = false;
+    }
+
+    /// Deassert the interrupt lines `bits`.
+    pub fn lower_interrupt(&mut self, bits: u32) {
+        self.csr[CSR_MIP_ADDRESS as usize] &= !bits;
+    }
+
+    /// Whether the CPU is stopped in a `wfi`.
+    pub fn waiting_for_interrupt(&self) -> bool {
+        self.wfi
+    }
+
+    /// Move the CPU onto hart `id`, the way a kernel switches a thread onto
+    /// another core: `mhartid` reads as `id` from then on, and the LR/SC
+    /// reservation held by whatever ran there before is dropped.
+    pub fn switch_hart(&mut self, id: u32) {
+        self.write_csr_raw(CSR_MHARTID_ADDRESS, id);
+        self.mmu.drop_reservation(id);
+    }
+
+    /// Add a Zve32x vector unit with registers `vlen` bits long, which must be
+    /// a power of two from `MIN_VLEN` to `MAX_VLEN`. Without one, every vector
+    /// instruction is illegal. Only some of the extension is implemented, and
+    /// the rest of it is illegal too.
+    ///
+    /// # Panics
+    /// If `vlen` isn't one of the lengths allowed.
+    pub fn enable_vector(&mut self, vlen: u32) {
+        assert!(
+            vlen.is_power_of_two() && (MIN_VLEN..=MAX_VLEN).contains(&vlen),
+            "VLEN must be a power of two from {} to {}. {}",
+            MIN_VLEN,
+            MAX_VLEN,
+            vlen
+        );
+        self.vector = Some(vector::VectorRegisters::new(vlen));
+        self.write_csr_raw(vector::CSR_VLENB_ADDRESS, vlen / 8);
+        self.write_csr_raw(vector::CSR_VTYPE_ADDRESS, vector::VTYPE_VILL);
+        self.write_csr_raw(vector::CSR_VL_ADDRESS, 0);
+    }
+
+    /// Let the guest read and write every CSR address, treating those that
+    /// aren't implemented as plain storage, which is what the CPU did before
+    /// it raised illegal instruction exceptions for them. For guests that
+    /// probe CSRs a real core wouldn't have.
+    pub fn permissive_csrs(&mut self, permissive: bool) {
+        self.permissive_csrs = permissive;
+    }
+
+    /// Decide what to do with compressed HINTs, which by default run as
+    /// no-ops without anyone hearing about it.
+    pub fn hint_policy(&mut self, policy: HintPolicy) {
+        self.hints = policy;
+    }
+
+    /// Record what each instruction does when it retires, for `last_commit`.
+    pub fn log_commits(&mut self, log: bool) {
+        self.csr_writes = log.then(Vec::new);
+        self.x.record_writes(log);
+        self.last_commit = None;
+        self.mmu.record_accesses(log);
+    }
+
+    /// What the instruction run by the last `tick` did, if commits are being
+    /// logged and it retired.
+    pub fn last_commit(&self) -> Option<&Commit> {
+        self.last_commit.as_ref()
+    }
+
     /// Runs program one cycle. Fetch, decode, and execution are completed in a cycle so far.
     pub fn tick(&mut self) -> TickResult {
-        match self.tick_operate() {
-            Ok(()) => {}
-            Err(Trap {
-                trap_type: TrapType::PauseEmulation(rx),
-                ..
-            }) => {
-                return TickResult::PauseEmulation(rx);
-            }
-            Err(Trap {
-                trap_type: TrapType::JoinThread(handle),
-                ..
-            }) => {
-                return TickResult::JoinThread(handle);
-            }
-            Err(Trap {
-                trap_type: TrapType::InstructionPageFault,
-                value: 0xff803000,
-            }) => {
-                return TickResult::ExitThread(self.read_register(10) as u32);
-            }
-            Err(e) => return TickResult::CpuTrap(e),
+        if let Err(e) = self.tick_operate() {
+            return TickResult::CpuTrap(e);
+        }
+        if let Some(stopped) = self.stopped.take() {
+            return stopped;
         }
         // self.mmu.tick(&mut self.csr[CSR_MIP_ADDRESS as usize]);
         self.handle_interrupt(self.pc);
@@ -359,6 +751,10 @@ impl Cpu {
 
     // @TODO: Rename?
     fn tick_operate(&mut self) -> Result<(), Trap> {
+        let logging = self.csr_writes.is_some();
+        if logging {
+            self.begin_commit();
+        }
         if self.wfi {
             if (self.read_csr_raw(CSR_MIE_ADDRESS) & self.read_csr_raw(CSR_MIP_ADDRESS)) != 0 {
                 self.wfi = false;
@@ -366,6 +762,7 @@ impl Cpu {
             return Ok(());
         }
 
+        self.mmu.update_pc(self.pc);
         let original_word = self.fetch()?;
         let instruction_address = self.pc;
         let word = if (original_word & 0x3) == 0x3 {
@@ -373,7 +770,17 @@ impl Cpu {
             original_word
         } else {
             self.pc = self.pc.wrapping_add(2); // 16-bit length compressed instruction
-            self.uncompress(original_word & 0xffff)
+            let halfword = original_word & 0xffff;
+            if self.hints != HintPolicy::Ignore && is_compressed_hint(halfword) {
+                if self.hints == HintPolicy::Trap {
+                    return Err(Trap {
+                        trap_type: TrapType::IllegalInstruction,
+                        value: halfword,
+                    });
+                }
+                self.memory.hint(instruction_address, halfword as u16);
+            }
+            self.uncompress(halfword)
         };
         // println!(
         //     "PC @ {:08x}  Original word: 0x{:04x}  Uncompressed: 0x{:08x}",
@@ -382,7 +789,15 @@ impl Cpu {
         //     word
         // );
 
-        let operation = self.decode(word)?;
+        // Illegal instruction traps hold the instruction as it was fetched
+        let bits = match original_word & 0x3 {
+            0x3 => original_word,
+            _ => original_word & 0xffff,
+        };
+        let operation = self
+            .decode(word)
+            .map_err(|trap| with_instruction(trap, bits))?;
+        self.last_instruction = word;
 
         // println!(
         //     "pc @ 0x{:08x}: 0x{:08x} (0x{:08x}) {} {}",
@@ -393,14 +808,98 @@ impl Cpu {
         //     (inst.disassemble)(self, word, self.pc, true)
         // );
         // let result = (inst.operation)(self, word, instruction_address);
+        let privilege_mode = self.privilege_mode;
         let result = operation(self, word, instruction_address);
-        self.x[0] = 0; // hardwired zero
+        if result.is_ok() && self.stopped.is_none() {
+            self.instret += 1;
+            if logging {
+                self.last_commit = Some(self.end_commit(instruction_address, bits, privilege_mode));
+            }
+        }
 
-        result
+        result.map_err(|trap| with_instruction(trap, bits))
+    }
+
+    /// Forget what was written before this instruction, so that only its own
+    /// writes end up in its `Commit`.
+    fn begin_commit(&mut self) {
+        self.last_commit = None;
+        self.x.take_written();
+        self.mmu.take_accesses();
+        if let Some(writes) = &mut self.csr_writes {
+            writes.clear();
+        }
+    }
+
+    fn end_commit(&mut self, pc: u32, bits: u32, privilege_mode: PrivilegeMode) -> Commit {
+        let written = self.x.take_written();
+        let mut csrs = self.csr_writes.take().unwrap_or_default();
+        let csr_values = csrs
+            .iter()
+            .map(|&address| (address, self.read_csr_raw(address)))
+            .collect();
+        csrs.clear();
+        self.csr_writes = Some(csrs);
+        Commit {
+            hart: self.read_csr_raw(CSR_MHARTID_ADDRESS),
+            privilege_mode,
+            pc,
+            instruction: bits,
+            compressed: bits & 0x3 != 0x3,
+            registers: (1..32)
+                .filter(|index| written & (1 << index) != 0)
+                .map(|index| (index as u8, self.x.as_array()[index]))
+                .collect(),
+            csrs: csr_values,
+            memory: self.mmu.take_accesses(),
+        }
+    }
+
+    /// The number of instructions that have run to completion. Instructions
+    /// that trap, including `ecall`, and cycles spent in `wfi` aren't counted.
+    pub fn instructions_retired(&self) -> u64 {
+        self.instret
+    }
+
+    /// The most recently executed instruction, expanded to 32 bits if it was
+    /// compressed. Along with the PC before and after `tick()`, this is
+    /// enough to follow control flow.
+    pub fn last_instruction(&self) -> u32 {
+        self.last_instruction
+    }
+
+    /// The name of the instruction that `word` decodes to, or `None` if it is
+    /// illegal. `word` must already be uncompressed.
+    pub fn instruction_name(&self, word: u32) -> Option<&'static str> {
+        self.decode_raw(word)
+            .ok()
+            .map(|instruction| instruction.name)
+    }
+
+    /// Pairs of instructions in the decode table that some word matches
+    /// both of. Decoding takes the first match, so the second of each pair
+    /// can never run. This is empty unless the table has a mistake in it.
+    pub fn decoder_conflicts(&self) -> Vec<(&'static str, &'static str)> {
+        let mut conflicts = vec![];
+        for (index, a) in self.instructions.iter().enumerate() {
+            for b in &self.instructions[index + 1..] {
+                if (a.data ^ b.data) & a.mask & b.mask == 0 {
+                    conflicts.push((a.name, b.name));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Expand a 16-bit compressed instruction to its 32-bit equivalent,
+    /// bypassing the expansion cache.
+    pub fn expand_compressed(&self, halfword: u16) -> u32 {
+        self.uncompress_inner(halfword as u32)
     }
 
     pub fn execute_opcode(&mut self, op: u32) -> Result<(), Trap> {
         (self.decode_raw(op)?.operation)(self, op, self.pc)
+            .map_err(|trap| with_instruction(trap, op))
     }
 
     /// Decodes a word instruction data and returns a reference to
@@ -411,7 +910,7 @@ impl Cpu {
         let index = self
             .decode_and_get_instruction_index(word)
             .map_err(|_| Trap {
-                value: self.pc.wrapping_sub(4),
+                value: word,
                 trap_type: TrapType::IllegalInstruction,
             })?;
         // TODO: Come up with a fancy cache here
@@ -426,7 +925,7 @@ impl Cpu {
         self.decode_and_get_instruction_index(word)
             .map(|index| &self.instructions[index])
             .map_err(|_| Trap {
-                value: self.pc.wrapping_sub(4),
+                value: word,
                 trap_type: TrapType::IllegalInstruction,
             })
     }
@@ -437,119 +936,47 @@ impl Cpu {
     /// # Arguments
     /// * `word` word instruction data decoded
     fn decode_and_get_instruction_index(&self, word: u32) -> Result<usize, ()> {
-        for (idx, inst) in self.instructions.iter().enumerate() {
-            if (word & inst.mask) == inst.data {
-                return Ok(idx);
-            }
-        }
-        Err(())
+        self.dispatch[instructions::dispatch_index(word)]
+            .iter()
+            .take_while(|&&index| index != instructions::DISPATCH_END)
+            .map(|&index| index as usize)
+            .find(|&index| {
+                let instruction = &self.instructions[index];
+                word & instruction.mask == instruction.data
+            })
+            .ok_or(())
     }
 
     fn handle_interrupt(&mut self, instruction_address: u32) {
-        // @TODO: Optimize
-        let minterrupt = self.read_csr_raw(CSR_MIP_ADDRESS) & self.read_csr_raw(CSR_MIE_ADDRESS);
-
-        if (minterrupt & MIP_MEIP) != 0
-            && self.handle_trap(
-                Trap {
-                    trap_type: TrapType::MachineExternalInterrupt,
-                    value: self.pc, // dummy
-                },
-                instruction_address,
-                true,
-            )
-        {
-            // Who should clear mip bit?
-            self.write_csr_raw(
-                CSR_MIP_ADDRESS,
-                self.read_csr_raw(CSR_MIP_ADDRESS) & !MIP_MEIP,
-            );
-            self.wfi = false;
-            return;
-        }
-        if (minterrupt & MIP_MSIP) != 0
-            && self.handle_trap(
-                Trap {
-                    trap_type: TrapType::MachineSoftwareInterrupt,
-                    value: self.pc, // dummy
-                },
-                instruction_address,
-                true,
-            )
-        {
-            self.write_csr_raw(
-                CSR_MIP_ADDRESS,
-                self.read_csr_raw(CSR_MIP_ADDRESS) & !MIP_MSIP,
-            );
-            self.wfi = false;
-            return;
-        }
-        if (minterrupt & MIP_MTIP) != 0
-            && self.handle_trap(
-                Trap {
-                    trap_type: TrapType::MachineTimerInterrupt,
-                    value: self.pc, // dummy
-                },
-                instruction_address,
-                true,
-            )
-        {
-            self.write_csr_raw(
-                CSR_MIP_ADDRESS,
-                self.read_csr_raw(CSR_MIP_ADDRESS) & !MIP_MTIP,
-            );
-            self.wfi = false;
-            return;
-        }
-        if (minterrupt & MIP_SEIP) != 0
-            && self.handle_trap(
-                Trap {
-                    trap_type: TrapType::SupervisorExternalInterrupt,
-                    value: self.pc, // dummy
-                },
-                instruction_address,
-                true,
-            )
-        {
-            self.write_csr_raw(
-                CSR_MIP_ADDRESS,
-                self.read_csr_raw(CSR_MIP_ADDRESS) & !MIP_SEIP,
-            );
-            self.wfi = false;
-            return;
-        }
-        if (minterrupt & MIP_SSIP) != 0
-            && self.handle_trap(
-                Trap {
-                    trap_type: TrapType::SupervisorSoftwareInterrupt,
-                    value: self.pc, // dummy
-                },
-                instruction_address,
-                true,
-            )
-        {
-            self.write_csr_raw(
-                CSR_MIP_ADDRESS,
-                self.read_csr_raw(CSR_MIP_ADDRESS) & !MIP_SSIP,
-            );
-            self.wfi = false;
+        let pending = self.read_csr_raw(CSR_MIP_ADDRESS) & self.read_csr_raw(CSR_MIE_ADDRESS);
+        if pending == 0 {
             return;
         }
-        if (minterrupt & MIP_STIP) != 0
-            && self.handle_trap(
-                Trap {
-                    trap_type: TrapType::SupervisorTimerInterrupt,
-                    value: self.pc, // dummy
-                },
-                instruction_address,
-                true,
-            )
-        {
-            self.write_csr_raw(
-                CSR_MIP_ADDRESS,
-                self.read_csr_raw(CSR_MIP_ADDRESS) & !MIP_STIP,
-            );
-            self.wfi = false;
+
+        // A pending and enabled interrupt resumes a WFI even if interrupts are
+        // globally disabled.
+        self.wfi = false;
+
+        // Interrupts that are taken in M-mode come before those delegated to S-mode,
+        // and within each group interrupts are taken in priority order. MIP bits are
+        // level-triggered, so clearing them is up to the source of the interrupt.
+        let mideleg = self.read_csr_raw(CSR_MIDELEG_ADDRESS);
+        for delegated in [false, true] {
+            for (bit, trap_type) in INTERRUPT_PRIORITY {
+                if pending & bit == 0 || (mideleg & bit != 0) != delegated {
+                    continue;
+                }
+                if self.handle_trap(
+                    Trap {
+                        trap_type,
+                        value: 0,
+                    },
+                    instruction_address,
+                    true,
+                ) {
+                    return;
+                }
+            }
         }
     }
 
@@ -653,50 +1080,32 @@ impl Cpu {
             // where x is a new privilege mode.
 
             match trap.trap_type {
-                TrapType::UserSoftwareInterrupt => {
-                    if usie == 0 {
-                        return false;
-                    }
+                TrapType::UserSoftwareInterrupt if usie == 0 => {
+                    return false;
                 }
-                TrapType::SupervisorSoftwareInterrupt => {
-                    if ssie == 0 {
-                        return false;
-                    }
+                TrapType::SupervisorSoftwareInterrupt if ssie == 0 => {
+                    return false;
                 }
-                TrapType::MachineSoftwareInterrupt => {
-                    if msie == 0 {
-                        return false;
-                    }
+                TrapType::MachineSoftwareInterrupt if msie == 0 => {
+                    return false;
                 }
-                TrapType::UserTimerInterrupt => {
-                    if utie == 0 {
-                        return false;
-                    }
+                TrapType::UserTimerInterrupt if utie == 0 => {
+                    return false;
                 }
-                TrapType::SupervisorTimerInterrupt => {
-                    if stie == 0 {
-                        return false;
-                    }
+                TrapType::SupervisorTimerInterrupt if stie == 0 => {
+                    return false;
                 }
-                TrapType::MachineTimerInterrupt => {
-                    if mtie == 0 {
-                        return false;
-                    }
+                TrapType::MachineTimerInterrupt if mtie == 0 => {
+                    return false;
                 }
-                TrapType::UserExternalInterrupt => {
-                    if ueie == 0 {
-                        return false;
-                    }
+                TrapType::UserExternalInterrupt if ueie == 0 => {
+                    return false;
                 }
-                TrapType::SupervisorExternalInterrupt => {
-                    if seie == 0 {
-                        return false;
-                    }
+                TrapType::SupervisorExternalInterrupt if seie == 0 => {
+                    return false;
                 }
-                TrapType::MachineExternalInterrupt => {
-                    if meie == 0 {
-                        return false;
-                    }
+                TrapType::MachineExternalInterrupt if meie == 0 => {
+                    return false;
                 }
                 _ => {}
             };
@@ -704,6 +1113,10 @@ impl Cpu {
 
         // So, this trap should be taken
 
+        // Taking a trap ends any LR/SC sequence
+        let core = self.read_csr_raw(CSR_MHARTID_ADDRESS);
+        self.mmu.drop_reservation(core);
+
         self.privilege_mode = new_privilege_mode;
         self.mmu.update_privilege_mode(self.privilege_mode);
         let csr_epc_address = match self.privilege_mode {
@@ -734,11 +1147,13 @@ impl Cpu {
         self.write_csr_raw(csr_epc_address, instruction_address);
         self.write_csr_raw(csr_cause_address, cause);
         self.write_csr_raw(csr_tval_address, trap.value);
-        self.pc = self.read_csr_raw(csr_tvec_address);
+        let tvec = self.read_csr_raw(csr_tvec_address);
+        self.pc = tvec & !TVEC_MODE_MASK;
 
-        // Add 4 * cause if tvec has vector type address
-        if (self.pc & 0x3) != 0 {
-            self.pc = (self.pc & !0x3) + 4 * (cause & 0xffff);
+        // In vectored mode, interrupts go to BASE + 4 * cause, while exceptions
+        // still go to BASE
+        if is_interrupt && tvec & TVEC_MODE_MASK == TVEC_MODE_VECTORED {
+            self.pc = self.pc.wrapping_add(4 * (cause & 0xffff));
         }
 
         match self.privilege_mode {
@@ -767,11 +1182,10 @@ impl Cpu {
         true
     }
 
+    /// Fetches the instruction at the PC. If that faults, the PC is left
+    /// pointing at the instruction, which is where the trap is taken from.
     fn fetch(&mut self) -> Result<u32, Trap> {
-        self.mmu.fetch_word(self.pc).map_err(|e| {
-            self.pc = self.pc.wrapping_add(4); // @TODO: What if instruction is compressed?
-            e
-        })
+        self.mmu.fetch_instruction(self.pc)
     }
 
     fn has_csr_access_privilege(&self, address: u16) -> bool {
@@ -779,25 +1193,69 @@ impl Cpu {
         privilege as u8 <= get_privilege_encoding(&self.privilege_mode)
     }
 
+    /// Whether there's a CSR at `address`, either in `IMPLEMENTED_CSRS` or
+    /// belonging to the vector unit.
+    fn csr_implemented(&self, address: u16) -> bool {
+        let address = address as usize;
+        if self.permissive_csrs || IMPLEMENTED_CSR_BITMAP[address / 64] & 1 << (address % 64) != 0 {
+            return true;
+        }
+        self.vector.is_some()
+            && matches!(
+                address as u16,
+                vector::CSR_VSTART_ADDRESS
+                    | vector::CSR_VL_ADDRESS
+                    | vector::CSR_VTYPE_ADDRESS
+                    | vector::CSR_VLENB_ADDRESS
+            )
+    }
+
+    /// Whether the counter CSR at `address` may be read in the current
+    /// privilege mode. Supervisor mode needs its bit set in `mcounteren`, and
+    /// user mode needs it set in `scounteren` as well. Every other CSR is
+    /// left to `has_csr_access_privilege`.
+    fn counter_enabled(&self, address: u16) -> bool {
+        if !matches!(address, 0xc00..=0xc1f | 0xc80..=0xc9f) {
+            return true;
+        }
+        let bit = 1 << (address & 0x1f);
+        let mcounteren = self.csr[CSR_MCOUNTEREN_ADDRESS as usize];
+        let scounteren = self.csr[CSR_SCOUNTEREN_ADDRESS as usize];
+        match self.privilege_mode {
+            PrivilegeMode::Machine => true,
+            PrivilegeMode::Supervisor => mcounteren & bit != 0,
+            _ => mcounteren & scounteren & bit != 0,
+        }
+    }
+
     fn read_csr(&mut self, address: u16) -> Result<u32, Trap> {
-        match self.has_csr_access_privilege(address) {
-            true => Ok(self.read_csr_raw(address)),
-            false => Err(Trap {
-                trap_type: TrapType::IllegalInstruction,
-                value: self.pc.wrapping_sub(4), // @TODO: Is this always correct?
-            }),
+        let illegal = Trap {
+            trap_type: TrapType::IllegalInstruction,
+            value: 0, // Replaced with the instruction by `tick_operate()`
+        };
+        if !self.has_csr_access_privilege(address) || !self.csr_implemented(address) {
+            return Err(illegal);
+        }
+        let value = self.read_csr_raw(address);
+        if self.counter_enabled(address) {
+            return Ok(value);
         }
+        // Give the layer that would handle the trap a chance to emulate the read
+        self.memory.counter(address, value).ok_or(illegal)
     }
 
     pub fn write_csr(&mut self, address: u16, value: u32) -> Result<(), Trap> {
-        if self.has_csr_access_privilege(address) {
-            /*
-            // Checking writability fails some tests so disabling so far
+        if self.has_csr_access_privilege(address) && self.csr_implemented(address) {
+            // CSRs numbered 0xc00 and up in each privilege level, such as the
+            // counters and the vector unit's `vl`, `vtype`, and `vlenb`, are
+            // read-only
             let read_only = ((address >> 10) & 0x3) == 0x3;
             if read_only {
-                return Err(Exception::IllegalInstruction);
+                return Err(Trap {
+                    trap_type: TrapType::IllegalInstruction,
+                    value: 0, // Replaced with the instruction by `tick_operate()`
+                });
             }
-            */
             self.write_csr_raw(address, value);
             if address == CSR_SATP_ADDRESS {
                 self.update_addressing_mode(value);
@@ -806,7 +1264,7 @@ impl Cpu {
         } else {
             Err(Trap {
                 trap_type: TrapType::IllegalInstruction,
-                value: self.pc.wrapping_sub(4), // @TODO: Is this always correct?
+                value: 0, // Replaced with the instruction by `tick_operate()`
             })
         }
     }
@@ -817,15 +1275,26 @@ impl Cpu {
             // @TODO: Mask shuld consider of 32-bit mode
             CSR_FFLAGS_ADDRESS => self.csr[CSR_FCSR_ADDRESS as usize] & 0x1f,
             CSR_FRM_ADDRESS => (self.csr[CSR_FCSR_ADDRESS as usize] >> 5) & 0x7,
-            CSR_SSTATUS_ADDRESS => self.csr[CSR_MSTATUS_ADDRESS as usize] & 0x800d_e162,
+            CSR_SSTATUS_ADDRESS => self.csr[CSR_MSTATUS_ADDRESS as usize] & SSTATUS_MASK,
             CSR_SIE_ADDRESS => self.csr[CSR_MIE_ADDRESS as usize] & 0x222,
             CSR_SIP_ADDRESS => self.csr[CSR_MIP_ADDRESS as usize] & 0x222,
-            // CSR_TIME_ADDRESS => self.mmu.get_clint().read_mtime(),
+            CSR_INSTRET_ADDRESS => self.instret as u32,
+            CSR_INSTRETH_ADDRESS => (self.instret >> 32) as u32,
+            CSR_TIME_ADDRESS | CSR_TIMEH_ADDRESS => match self.memory.time() {
+                Some(time) if address == CSR_TIMEH_ADDRESS => (time >> 32) as u32,
+                Some(time) => time as u32,
+                None => self.csr[address as usize],
+            },
             _ => self.csr[address as usize],
         }
     }
 
     fn write_csr_raw(&mut self, address: u16, value: u32) {
+        if let Some(writes) = &mut self.csr_writes {
+            if !writes.contains(&address) {
+                writes.push(address);
+            }
+        }
         match address {
             CSR_FFLAGS_ADDRESS => {
                 self.csr[CSR_FCSR_ADDRESS as usize] &= !0x1f;
@@ -836,8 +1305,9 @@ impl Cpu {
                 self.csr[CSR_FCSR_ADDRESS as usize] |= (value << 5) & 0xe0;
             }
             CSR_SSTATUS_ADDRESS => {
-                self.csr[CSR_MSTATUS_ADDRESS as usize] &= !0x800de162;
-                self.csr[CSR_MSTATUS_ADDRESS as usize] |= value & 0x800de162;
+                let status = self.csr[CSR_MSTATUS_ADDRESS as usize] & !SSTATUS_MASK;
+                self.csr[CSR_MSTATUS_ADDRESS as usize] =
+                    legalize_mstatus(status | (value & SSTATUS_MASK));
                 self.mmu
                     .update_mstatus(self.read_csr_raw(CSR_MSTATUS_ADDRESS));
             }
@@ -855,14 +1325,19 @@ impl Cpu {
             CSR_MEDELEG_ADDRESS => {
                 self.csr[address as usize] = value;
             }
-            CSR_MTVEC_ADDRESS => {
-                self.csr[address as usize] = value;
+            CSR_MTVEC_ADDRESS | CSR_STVEC_ADDRESS | CSR_UTVEC_ADDRESS => {
+                self.csr[address as usize] = legalize_tvec(self.csr[address as usize], value);
             }
             CSR_MSTATUS_ADDRESS => {
-                self.csr[address as usize] = value;
+                self.csr[address as usize] = legalize_mstatus(value);
                 self.mmu
                     .update_mstatus(self.read_csr_raw(CSR_MSTATUS_ADDRESS));
             }
+            CSR_HYPERCALL_ADDRESS => {
+                // CSR instructions are never compressed
+                let pc = self.pc.wrapping_sub(4);
+                self.csr[address as usize] = self.memory.hypercall(value, pc, self.x.as_array());
+            }
             // CSR_TIME_ADDRESS => {
             //     self.mmu.get_mut_clint().write_mtime(value);
             // }
@@ -877,9 +1352,10 @@ impl Cpu {
             0 => AddressingMode::None,
             _ => AddressingMode::SV32,
         };
+        let asid = (value >> 22) & 0x1ff;
         let ppn = value & 0x3fffff;
         self.mmu.update_addressing_mode(addressing_mode);
-        self.mmu.update_ppn(ppn);
+        self.mmu.switch_address_space(asid, ppn);
     }
 
     // // @TODO: Rename to better name?
@@ -894,7 +1370,7 @@ impl Cpu {
 
     // @TODO: Rename to better name?
     fn most_negative(&self) -> i32 {
-        std::i32::MIN
+        i32::MIN
     }
 
     // @TODO: Optimize
@@ -922,7 +1398,7 @@ impl Cpu {
 						((halfword >> 1) & 0x3c0) | // nzuimm{9:6] <= [10:7]
 						((halfword >> 4) & 0x4) | // nzuimm[2] <= [6]
 						((halfword >> 2) & 0x8); // nzuimm[3] <= [5]
-                         // nzuimm == 0 is reserved instruction
+                               // nzuimm == 0 is reserved instruction
                     if nzuimm != 0 {
                         return (nzuimm << 20) | (2 << 15) | ((rd + 8) << 7) | 0x13;
                     }
@@ -1031,8 +1507,8 @@ impl Cpu {
                             // addi r, r, imm
                             return (imm << 20) | (r << 15) | (r << 7) | 0x13;
                         }
-                        // @TODO: Support HINTs
-                        // r == 0 and imm != 0 is HINTs
+                        // r == 0 and imm != 0 is a HINT
+                        return 0x13;
                     }
                     1 => {
                         // C.JAL
@@ -1078,8 +1554,8 @@ impl Cpu {
                         if r != 0 {
                             return (imm << 20) | (r << 7) | 0x13;
                         }
-                        // @TODO: Support HINTs
-                        // r == 0 is for HINTs
+                        // r == 0 is a HINT
+                        return 0x13;
                     }
                     3 => {
                         let r = (halfword >> 7) & 0x1f; // [11:7]
@@ -1114,6 +1590,10 @@ impl Cpu {
                             }
                             // nzimm == 0 is for reserved instruction
                         }
+                        if r == 0 && halfword & 0x107c != 0 {
+                            // C.LUI to x0 with a nonzero immediate is a HINT
+                            return 0x13;
+                        }
                     }
                     4 => {
                         let funct2 = (halfword >> 10) & 0x3; // [11:10]
@@ -1274,7 +1754,7 @@ impl Cpu {
 							((offset >> 5) & 0x3f); // imm2[5:0] <= [10:5]
                         let imm1 = (offset & 0x1e) | // imm1[4:1] <= [4:1]
 							((offset >> 11) & 0x1); // imm1[0] <= [11]
-                        return (imm2 << 25) | ((r + 8) << 20) | (imm1 << 7) | 0x63;
+                        return (imm2 << 25) | ((r + 8) << 15) | (imm1 << 7) | 0x63;
                     }
                     7 => {
                         // C.BNEZ
@@ -1293,7 +1773,7 @@ impl Cpu {
 							((offset >> 5) & 0x3f); // imm2[5:0] <= [10:5]
                         let imm1 = (offset & 0x1e) | // imm1[4:1] <= [4:1]
 							((offset >> 11) & 0x1); // imm1[0] <= [11]
-                        return (imm2 << 25) | ((r + 8) << 20) | (1 << 12) | (imm1 << 7) | 0x63;
+                        return (imm2 << 25) | ((r + 8) << 15) | (1 << 12) | (imm1 << 7) | 0x63;
                     }
                     _ => {} // No happens
                 };
@@ -1309,7 +1789,11 @@ impl Cpu {
                         if r != 0 {
                             return (shamt << 20) | (r << 15) | (1 << 12) | (r << 7) | 0x13;
                         }
-                        // r == 0 is reserved instruction?
+                        // r == 0 is a HINT, unless shamt[5] is set, which is
+                        // reserved for custom extensions on RV32
+                        if shamt & 0x20 == 0 {
+                            return 0x13;
+                        }
                     }
                     1 => {
                         // C.FLDSP
@@ -1366,8 +1850,10 @@ impl Cpu {
                                     // println!("C.MV RS1:{:x} RS2:{:x}", rs1, rs2);
                                     return (rs2 << 20) | (rs1 << 7) | 0x33;
                                 }
-                                // rs1 == 0 && rs2 != 0 is Hints
-                                // @TODO: Support Hints
+                                // rs1 == 0 && rs2 != 0 is a HINT
+                                if rs2 != 0 {
+                                    return 0x13;
+                                }
                             }
                             1 => {
                                 if rs1 == 0 && rs2 == 0 {
@@ -1385,8 +1871,8 @@ impl Cpu {
                                     // add rs1, rs1, rs2
                                     return (rs2 << 20) | (rs1 << 15) | (rs1 << 7) | 0x33;
                                 }
-                                // rs1 == 0 && rs2 != 0 is Hists
-                                // @TODO: Supports Hinsts
+                                // rs1 == 0 && rs2 != 0 is a HINT
+                                return 0x13;
                             }
                             _ => {} // Not happens
                         };
@@ -1452,7 +1938,7 @@ impl Cpu {
         // for example updating page table entry or update peripheral hardware registers.
         // But ideally disassembling doesn't want to cause any side effect.
         // How can we avoid side effect?
-        let Ok(mut original_word) = self.mmu.fetch_word(self.pc) else {
+        let Ok(mut original_word) = self.mmu.fetch_instruction(self.pc) else {
             return format!("PC:{:016x}, InstructionPageFault Trap!\n", self.pc);
         };
 
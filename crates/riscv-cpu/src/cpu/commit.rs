@@ -0,0 +1,114 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{get_privilege_encoding, PrivilegeMode};
+use crate::mmu::MemoryAccess;
+
+/// What one instruction did when it retired, as recorded by a `Cpu` with
+/// `log_commits` on. It displays as a line of spike's `--log-commits`
+/// output, such as
+/// `core   0: 3 0x80000000 (0x00000297) x5  0x80000000`, so that a run can
+/// be diffed against spike's.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Commit {
+    /// The value of `mhartid`
+    pub hart: u32,
+    /// The mode the instruction ran in
+    pub privilege_mode: PrivilegeMode,
+    pub pc: u32,
+    /// The instruction as it was fetched, which is only the low 16 bits if
+    /// it's compressed
+    pub instruction: u32,
+    pub compressed: bool,
+    /// Registers written, by number, with the value written. Writes to `x0`
+    /// are left out.
+    pub registers: Vec<(u8, i32)>,
+    /// CSRs written, by address, with the value they read back as
+    pub csrs: Vec<(u16, u32)>,
+    /// Loads and stores, in the order they were made
+    pub memory: Vec<MemoryAccess>,
+}
+
+/// The name spike gives a CSR, for CSRs this CPU knows about.
+fn csr_name(address: u16) -> &'static str {
+    match address {
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0x100 => "sstatus",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x106 => "scounteren",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x180 => "satp",
+        0x300 => "mstatus",
+        0x301 => "misa",
+        0x302 => "medeleg",
+        0x303 => "mideleg",
+        0x304 => "mie",
+        0x305 => "mtvec",
+        0x306 => "mcounteren",
+        0x340 => "mscratch",
+        0x341 => "mepc",
+        0x342 => "mcause",
+        0x343 => "mtval",
+        0x344 => "mip",
+        0xc00 => "cycle",
+        0xc01 => "time",
+        0xc02 => "instret",
+        0xc80 => "cycleh",
+        0xc81 => "timeh",
+        0xc82 => "instreth",
+        0xf14 => "mhartid",
+        _ => "unknown",
+    }
+}
+
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "core{:4}: {} 0x{:08x} (",
+            self.hart,
+            get_privilege_encoding(&self.privilege_mode),
+            self.pc
+        )?;
+        match self.compressed {
+            true => write!(f, "0x{:04x})", self.instruction)?,
+            false => write!(f, "0x{:08x})", self.instruction)?,
+        }
+        for &(index, value) in &self.registers {
+            write!(f, " x{:<2} 0x{:08x}", index, value)?;
+        }
+        for &(address, value) in &self.csrs {
+            write!(f, " c{}_{} 0x{:08x}", address, csr_name(address), value)?;
+        }
+        // spike lists every load before any store
+        for access in &self.memory {
+            if let MemoryAccess::Read { address, .. } = access {
+                write!(f, " mem 0x{:08x}", address)?;
+            }
+        }
+        for access in &self.memory {
+            if let MemoryAccess::Write {
+                address,
+                size,
+                value,
+            } = access
+            {
+                write!(
+                    f,
+                    " mem 0x{:08x} 0x{:0width$x}",
+                    address,
+                    value,
+                    width = *size as usize * 2
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,11 @@
+use alloc::format;
+use alloc::string::String;
+
+use super::vector::{self, shift, signed, Avl};
 use super::{
-    decode_privilege_mode, Cpu, PrivilegeMode, Trap, TrapType, CSR_MEPC_ADDRESS,
-    CSR_MHARTID_ADDRESS, CSR_MSTATUS_ADDRESS, CSR_SEPC_ADDRESS, CSR_SSTATUS_ADDRESS,
+    decode_privilege_mode, Cpu, PrivilegeMode, Register, TickResult, Trap, TrapType,
+    CSR_MEPC_ADDRESS, CSR_MHARTID_ADDRESS, CSR_MSTATUS_ADDRESS, CSR_SEPC_ADDRESS,
+    CSR_SSTATUS_ADDRESS,
 };
 
 pub type InstructionOperation = fn(cpu: &mut Cpu, word: u32, address: u32) -> Result<(), Trap>;
@@ -13,9 +18,70 @@ pub struct Instruction {
     pub disassemble: fn(cpu: &Cpu, word: u32, address: u32, evaluate: bool) -> String,
 }
 
-pub const INSTRUCTION_NUM: usize = 86;
+pub const INSTRUCTION_NUM: usize = 142;
+
+/// The bits of an instruction that pick its entry in the dispatch table:
+/// the major opcode in bits 6:2 and `funct3` in bits 14:12.
+const DISPATCH_FIELDS: u32 = 0x0000_707c;
+
+/// The number of entries in the dispatch table, one for every value of the
+/// fields in `DISPATCH_FIELDS`.
+pub const DISPATCH_ENTRIES: usize = 256;
+
+/// The most instructions any entry of the dispatch table can hold.
+pub const DISPATCH_WIDTH: usize = 24;
+
+/// Marks the unused end of a dispatch table entry.
+pub const DISPATCH_END: u8 = u8::MAX;
+
+// The dispatch table holds instruction indices as bytes, with one to spare
+// for `DISPATCH_END`
+const _: () = assert!(INSTRUCTION_NUM < DISPATCH_END as usize);
+
+/// The entry in the dispatch table for `word`.
+pub const fn dispatch_index(word: u32) -> usize {
+    (((word >> 2) & 0x1f) | ((word >> 7) & 0xe0)) as usize
+}
+
+/// For every entry of the dispatch table, the indices of the instructions
+/// that a word with that opcode and `funct3` could decode to. Instructions
+/// that don't fix `funct3`, such as `JAL` and `LUI`, are in all eight of the
+/// entries for their opcode. No two instructions overlap, so the order
+/// within an entry doesn't matter.
+///
+/// Every `Cpu` has the same instructions, so the table is built at compile
+/// time and shared by all of them. Each entry ends at the first
+/// `DISPATCH_END`.
+pub static DISPATCH_TABLE: [[u8; DISPATCH_WIDTH]; DISPATCH_ENTRIES] =
+    build_dispatch_table(&get_instructions());
+
+const fn build_dispatch_table(
+    instructions: &[Instruction],
+) -> [[u8; DISPATCH_WIDTH]; DISPATCH_ENTRIES] {
+    let mut table = [[DISPATCH_END; DISPATCH_WIDTH]; DISPATCH_ENTRIES];
+    let mut lengths = [0; DISPATCH_ENTRIES];
+    let mut index = 0;
+    while index < instructions.len() {
+        let fixed = instructions[index].mask & DISPATCH_FIELDS;
+        let mut entry = 0;
+        while entry < DISPATCH_ENTRIES as u32 {
+            let word = (entry & 0x1f) << 2 | (entry >> 5) << 12;
+            if (word ^ instructions[index].data) & fixed == 0 {
+                let slot = dispatch_index(word);
+                assert!(
+                    lengths[slot] < DISPATCH_WIDTH,
+                    "DISPATCH_WIDTH is too small"
+                );
+                table[slot][lengths[slot]] = index as u8;
+                lengths[slot] += 1;
+            }
+            entry += 1;
+        }
+        index += 1;
+    }
+    table
+}
 
-// @TODO: Reorder in often used order as
 pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
     [
         Instruction {
@@ -206,12 +272,12 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             disassemble: dump_format_r,
         },
         Instruction {
-            mask: 0xfc00707f,
+            mask: 0xfe00707f,
             data: 0x00001013,
             name: "SLLI",
             operation: |cpu, word, _address| {
                 let f = parse_format_r(word);
-                let shamt = f.rs2;
+                let shamt = f.rs2.index();
                 cpu.x[f.rd] = cpu.x[f.rs1] << shamt;
                 Ok(())
             },
@@ -252,7 +318,7 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             disassemble: dump_format_j,
         },
         Instruction {
-            mask: 0xfc00707f,
+            mask: 0xfe00707f,
             data: 0x00005013,
             name: "SRLI",
             operation: |cpu, word, _address| {
@@ -331,7 +397,7 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             disassemble: dump_format_r,
         },
         Instruction {
-            mask: 0xfc00707f,
+            mask: 0xfe00707f,
             data: 0x40005013,
             name: "SRAI",
             operation: |cpu, word, _address| {
@@ -494,7 +560,8 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             data: 0x0000000f,
             name: "FENCE",
             operation: |_cpu, _word, _address| {
-                // Do nothing?
+                // Memory accesses happen one at a time, in program order, so
+                // there's nothing to wait for
                 Ok(())
             },
             disassemble: dump_empty,
@@ -514,19 +581,7 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             mask: 0xf800707f,
             data: 0x0800202f,
             name: "AMOSWAP.W",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data as i32,
-                    Err(e) => return Err(e),
-                };
-                match cpu.mmu.store_word(cpu.x[f.rs1] as u32, cpu.x[f.rs2] as u32) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp;
-                Ok(())
-            },
+            operation: |cpu, word, _address| amo(cpu, word, |_, operand| operand),
             disassemble: dump_format_r,
         },
         Instruction {
@@ -534,20 +589,7 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             data: 0x0000202f,
             name: "AMOADD.W",
             operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data as i32,
-                    Err(e) => return Err(e),
-                };
-                match cpu
-                    .mmu
-                    .store_word(cpu.x[f.rs1] as u32, cpu.x[f.rs2].wrapping_add(tmp) as u32)
-                {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp;
-                Ok(())
+                amo(cpu, word, |data, operand| data.wrapping_add(operand))
             },
             disassemble: dump_format_r,
         },
@@ -557,11 +599,8 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             name: "LR.W",
             operation: |cpu, word, _address| {
                 let f = parse_format_r(word);
-                // @TODO: Implement properly
-                let address = cpu.x[f.rs1] as u32;
                 let core = cpu.read_csr_raw(CSR_MHARTID_ADDRESS);
-                cpu.x[f.rd] = cpu.mmu.load_word(address)? as i32;
-                cpu.mmu.reserve(core, address);
+                cpu.x[f.rd] = cpu.mmu.load_reserved(cpu.x[f.rs1] as u32, core)? as i32;
                 Ok(())
             },
             disassemble: dump_format_r,
@@ -572,14 +611,12 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             name: "SC.W",
             operation: |cpu, word, _address| {
                 let f = parse_format_r(word);
-                let address = cpu.x[f.rs1] as u32;
                 let core = cpu.read_csr_raw(CSR_MHARTID_ADDRESS);
-                if cpu.mmu.clear_reservation(core, address) {
-                    cpu.mmu.store_word(address, cpu.x[f.rs2] as u32)?;
-                    cpu.x[f.rd] = 0;
-                } else {
-                    cpu.x[f.rd] = 1;
-                }
+                let stored =
+                    cpu.mmu
+                        .store_conditional(cpu.x[f.rs1] as u32, cpu.x[f.rs2] as u32, core)?;
+                // Zero for success, and one for failure
+                cpu.x[f.rd] = !stored as i32;
                 Ok(())
             },
             disassemble: dump_format_r,
@@ -598,93 +635,25 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             },
             disassemble: dump_format_r,
         },
-        Instruction {
-            mask: 0x0000707f,
-            data: 0x0000001b,
-            name: "ADDIW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_i(word);
-                cpu.x[f.rd] = cpu.x[f.rs1].wrapping_add(f.imm) as i32;
-                Ok(())
-            },
-            disassemble: dump_format_i,
-        },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x0000003b,
-            name: "ADDW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                cpu.x[f.rd] = cpu.x[f.rs1].wrapping_add(cpu.x[f.rs2]) as i32;
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
         Instruction {
             mask: 0xf800707f,
             data: 0x2000202f,
             name: "AMOXOR.W",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data as i32,
-                    Err(e) => return Err(e),
-                };
-                match cpu
-                    .mmu
-                    .store_word(cpu.x[f.rs1] as u32, (cpu.x[f.rs2] ^ tmp) as u32)
-                {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp;
-                Ok(())
-            },
+            operation: |cpu, word, _address| amo(cpu, word, |data, operand| data ^ operand),
             disassemble: dump_format_r,
         },
         Instruction {
             mask: 0xf800707f,
             data: 0x6000202f,
             name: "AMOAND.W",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data as i32,
-                    Err(e) => return Err(e),
-                };
-                match cpu
-                    .mmu
-                    .store_word(cpu.x[f.rs1] as u32, (cpu.x[f.rs2] & tmp) as u32)
-                {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp;
-                Ok(())
-            },
+            operation: |cpu, word, _address| amo(cpu, word, |data, operand| data & operand),
             disassemble: dump_format_r,
         },
         Instruction {
             mask: 0xf800707f,
             data: 0xc000202f,
             name: "AMOMINU.W",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data,
-                    Err(e) => return Err(e),
-                };
-                let min = match cpu.x[f.rs2] as u32 <= tmp {
-                    true => cpu.x[f.rs2] as u32,
-                    false => tmp,
-                };
-                match cpu.mmu.store_word(cpu.x[f.rs1] as u32, min) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp as i32;
-                Ok(())
-            },
+            operation: |cpu, word, _address| amo(cpu, word, |data, operand| data.min(operand)),
             disassemble: dump_format_r,
         },
         Instruction {
@@ -692,21 +661,9 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             data: 0x8000202f,
             name: "AMOMIN.W",
             operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data as i32,
-                    Err(e) => return Err(e),
-                };
-                let min = match cpu.x[f.rs2] as i32 <= tmp {
-                    true => cpu.x[f.rs2] as i32,
-                    false => tmp,
-                };
-                match cpu.mmu.store_word(cpu.x[f.rs1] as u32, min as u32) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp as i32;
-                Ok(())
+                amo(cpu, word, |data, operand| {
+                    (data as i32).min(operand as i32) as u32
+                })
             },
             disassemble: dump_format_r,
         },
@@ -714,23 +671,7 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             mask: 0xf800707f,
             data: 0xe000202f,
             name: "AMOMAXU.W",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data,
-                    Err(e) => return Err(e),
-                };
-                let max = match cpu.x[f.rs2] as u32 >= tmp {
-                    true => cpu.x[f.rs2] as u32,
-                    false => tmp,
-                };
-                match cpu.mmu.store_word(cpu.x[f.rs1] as u32, max) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp as i32;
-                Ok(())
-            },
+            operation: |cpu, word, _address| amo(cpu, word, |data, operand| data.max(operand)),
             disassemble: dump_format_r,
         },
         Instruction {
@@ -738,21 +679,9 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             data: 0xa000202f,
             name: "AMOMAX.W",
             operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data as i32,
-                    Err(e) => return Err(e),
-                };
-                let max = match cpu.x[f.rs2] as i32 >= tmp {
-                    true => cpu.x[f.rs2] as i32,
-                    false => tmp,
-                };
-                match cpu.mmu.store_word(cpu.x[f.rs1] as u32, max as u32) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp as i32;
-                Ok(())
+                amo(cpu, word, |data, operand| {
+                    (data as i32).max(operand as i32) as u32
+                })
             },
             disassemble: dump_format_r,
         },
@@ -760,22 +689,7 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             mask: 0xf800707f,
             data: 0x4000202f,
             name: "AMOOR.W",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u32) {
-                    Ok(data) => data as i32,
-                    Err(e) => return Err(e),
-                };
-                match cpu
-                    .mmu
-                    .store_word(cpu.x[f.rs1] as u32, (cpu.x[f.rs2] | tmp) as u32)
-                {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
-                cpu.x[f.rd] = tmp;
-                Ok(())
-            },
+            operation: |cpu, word, _address| amo(cpu, word, |data, operand| data | operand),
             disassemble: dump_format_r,
         },
         Instruction {
@@ -789,11 +703,15 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
                     Err(e) => return Err(e),
                 };
                 let tmp = cpu.x[f.rs];
-                cpu.x[f.rd] = cpu.sign_extend(data);
-                match cpu.write_csr(f.csr, (cpu.x[f.rd] & !tmp) as u32) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
+                let data = cpu.sign_extend(data);
+                cpu.x[f.rd] = data;
+                // rs1 == x0 reads the CSR without writing it
+                if f.rs != Register::ZERO {
+                    match cpu.write_csr(f.csr, (data & !tmp) as u32) {
+                        Ok(()) => {}
+                        Err(e) => return Err(e),
+                    };
+                }
                 Ok(())
             },
             disassemble: dump_format_csr,
@@ -808,11 +726,15 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
                     Ok(data) => data as i32,
                     Err(e) => return Err(e),
                 };
-                cpu.x[f.rd] = cpu.sign_extend(data);
-                match cpu.write_csr(f.csr, (cpu.x[f.rd] & !(f.rs as i32)) as u32) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
+                let data = cpu.sign_extend(data);
+                cpu.x[f.rd] = data;
+                // A zero immediate reads the CSR without writing it
+                if f.uimm != 0 {
+                    match cpu.write_csr(f.csr, (data & !(f.uimm as i32)) as u32) {
+                        Ok(()) => {}
+                        Err(e) => return Err(e),
+                    };
+                }
                 Ok(())
             },
             disassemble: dump_format_csr,
@@ -828,11 +750,15 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
                     Err(e) => return Err(e),
                 };
                 let tmp = cpu.x[f.rs];
-                cpu.x[f.rd] = cpu.sign_extend(data);
-                match cpu.write_csr(f.csr, cpu.unsigned_data(cpu.x[f.rd] | tmp)) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
+                let data = cpu.sign_extend(data);
+                cpu.x[f.rd] = data;
+                // rs1 == x0 reads the CSR without writing it
+                if f.rs != Register::ZERO {
+                    match cpu.write_csr(f.csr, cpu.unsigned_data(data | tmp)) {
+                        Ok(()) => {}
+                        Err(e) => return Err(e),
+                    };
+                }
                 Ok(())
             },
             disassemble: dump_format_csr,
@@ -847,11 +773,15 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
                     Ok(data) => data as i32,
                     Err(e) => return Err(e),
                 };
-                cpu.x[f.rd] = cpu.sign_extend(data);
-                match cpu.write_csr(f.csr, cpu.unsigned_data(cpu.x[f.rd] | (f.rs as i32))) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                };
+                let data = cpu.sign_extend(data);
+                cpu.x[f.rd] = data;
+                // A zero immediate reads the CSR without writing it
+                if f.uimm != 0 {
+                    match cpu.write_csr(f.csr, cpu.unsigned_data(data | (f.uimm as i32))) {
+                        Ok(()) => {}
+                        Err(e) => return Err(e),
+                    };
+                }
                 Ok(())
             },
             disassemble: dump_format_csr,
@@ -887,7 +817,7 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
                     Err(e) => return Err(e),
                 };
                 cpu.x[f.rd] = cpu.sign_extend(data);
-                match cpu.write_csr(f.csr, f.rs as u32) {
+                match cpu.write_csr(f.csr, f.uimm) {
                     Ok(()) => {}
                     Err(e) => return Err(e),
                 };
@@ -931,42 +861,6 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             },
             disassemble: dump_format_r,
         },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x0200503b,
-            name: "DIVUW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let dividend = cpu.unsigned_data(cpu.x[f.rs1]) as u32;
-                let divisor = cpu.unsigned_data(cpu.x[f.rs2]) as u32;
-                if divisor == 0 {
-                    cpu.x[f.rd] = -1;
-                } else {
-                    cpu.x[f.rd] = dividend.wrapping_div(divisor) as i32
-                }
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x0200403b,
-            name: "DIVW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let dividend = cpu.x[f.rs1] as i32;
-                let divisor = cpu.x[f.rs2] as i32;
-                if divisor == 0 {
-                    cpu.x[f.rd] = -1;
-                } else if dividend == std::i32::MIN && divisor == -1 {
-                    cpu.x[f.rd] = dividend as i32;
-                } else {
-                    cpu.x[f.rd] = dividend.wrapping_div(divisor) as i32
-                }
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
         Instruction {
             mask: 0xffffffff,
             data: 0x00100073,
@@ -981,30 +875,32 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             mask: 0xffffffff,
             data: 0x00000073,
             name: "ECALL",
-            operation: |cpu, _word, address| {
+            operation: |cpu, _word, _address| {
                 let mut args = [0i32; 8];
-                for (src, dest) in cpu.x[10..].iter().zip(args.iter_mut()) {
-                    *dest = *src;
+                for (offset, dest) in args.iter_mut().enumerate() {
+                    *dest = cpu.x[Register::A0.offset(offset as u8)];
                 }
-                use crate::mmu::SyscallResult;
+                use crate::syscall::SyscallResult;
                 match cpu.memory.syscall(args) {
                     SyscallResult::Ok(result) => {
-                        for (src, dest) in result.iter().zip(cpu.x[10..].iter_mut()) {
-                            *dest = *src;
+                        for (offset, src) in result.iter().enumerate() {
+                            cpu.x[Register::A0.offset(offset as u8)] = *src;
                         }
                         Ok(())
                     }
-                    SyscallResult::Defer(receiver) => Err(Trap {
-                        trap_type: TrapType::PauseEmulation(receiver),
-                        value: address,
-                    }),
-                    SyscallResult::JoinThread(handle) => Err(Trap {
-                        trap_type: TrapType::JoinThread(handle),
-                        value: address,
-                    }),
-                    SyscallResult::Terminate(_) => panic!("Unhandled termination"),
+                    SyscallResult::Suspend(suspension) => {
+                        cpu.stopped = Some(TickResult::Suspended(suspension));
+                        Ok(())
+                    }
+                    SyscallResult::Terminate(result) => {
+                        cpu.stopped = Some(TickResult::TerminateProcess(result));
+                        Ok(())
+                    }
+                    SyscallResult::ExitThread(result) => {
+                        cpu.stopped = Some(TickResult::ExitThread(result));
+                        Ok(())
+                    }
                     SyscallResult::Continue => {
-                        println!("Got \"ECALL\" from address {:08x} -- issuing trap", address);
                         let exception_type = match cpu.privilege_mode {
                             PrivilegeMode::User => TrapType::EnvironmentCallFromUMode,
                             PrivilegeMode::Supervisor => TrapType::EnvironmentCallFromSMode,
@@ -1013,7 +909,7 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
                         };
                         Err(Trap {
                             trap_type: exception_type,
-                            value: address,
+                            value: 0,
                         })
                     }
                 }
@@ -1025,25 +921,12 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             data: 0x0000100f,
             name: "FENCE.I",
             operation: |_cpu, _word, _address| {
-                // Do nothing?
+                // Instructions are fetched from memory every time they run,
+                // so stores to code are already seen
                 Ok(())
             },
             disassemble: dump_empty,
         },
-        Instruction {
-            mask: 0x0000707f,
-            data: 0x00006003,
-            name: "LWU",
-            operation: |cpu, word, _address| {
-                let f = parse_format_i(word);
-                cpu.x[f.rd] = match cpu.mmu.load_word(cpu.x[f.rs1].wrapping_add(f.imm) as u32) {
-                    Ok(data) => data as i32,
-                    Err(e) => return Err(e),
-                };
-                Ok(())
-            },
-            disassemble: dump_format_i_mem,
-        },
         Instruction {
             mask: 0xfe00707f,
             data: 0x02001033,
@@ -1069,18 +952,6 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             },
             disassemble: dump_format_r,
         },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x0200003b,
-            name: "MULW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                cpu.x[f.rd] =
-                    cpu.sign_extend((cpu.x[f.rs1] as i32).wrapping_mul(cpu.x[f.rs2] as i32) as i32);
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
         Instruction {
             mask: 0xffffffff,
             data: 0x30200073,
@@ -1147,73 +1018,19 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             },
             disassemble: dump_format_r,
         },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x0200703b,
-            name: "REMUW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let dividend = cpu.x[f.rs1] as u32;
-                let divisor = cpu.x[f.rs2] as u32;
-                cpu.x[f.rd] = match divisor {
-                    0 => dividend as i32,
-                    _ => dividend.wrapping_rem(divisor) as i32,
-                };
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x0200603b,
-            name: "REMW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let dividend = cpu.x[f.rs1] as i32;
-                let divisor = cpu.x[f.rs2] as i32;
-                if divisor == 0 {
-                    cpu.x[f.rd] = dividend as i32;
-                } else if dividend == std::i32::MIN && divisor == -1 {
-                    cpu.x[f.rd] = 0;
-                } else {
-                    cpu.x[f.rd] = dividend.wrapping_rem(divisor) as i32;
-                }
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
         Instruction {
             mask: 0xfe007fff,
             data: 0x12000073,
             name: "SFENCE.VMA",
-            operation: |_cpu, _word, _address| {
-                // Do nothing?
-                Ok(())
-            },
-            disassemble: dump_empty,
-        },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x0000101b,
-            name: "SLLIW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let shamt = f.rs2 as u32;
-                cpu.x[f.rd] = (cpu.x[f.rs1] << shamt) as i32;
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x0000103b,
-            name: "SLLW",
             operation: |cpu, word, _address| {
                 let f = parse_format_r(word);
-                cpu.x[f.rd] = (cpu.x[f.rs1] as u32).wrapping_shl(cpu.x[f.rs2] as u32) as i32;
+                // x0 in either operand means "all" rather than address or ASID 0
+                let v_address = (f.rs1 != Register::ZERO).then(|| cpu.x[f.rs1] as u32);
+                let asid = (f.rs2 != Register::ZERO).then(|| cpu.x[f.rs2] as u32 & 0x1ff);
+                cpu.mmu.flush_tlb(v_address, asid);
                 Ok(())
             },
-            disassemble: dump_format_r,
+            disassemble: dump_empty,
         },
         Instruction {
             mask: 0xfe00707f,
@@ -1226,29 +1043,6 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             },
             disassemble: dump_format_r,
         },
-        Instruction {
-            mask: 0xfc00707f,
-            data: 0x4000501b,
-            name: "SRAIW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let shamt = (word >> 20) & 0x1f;
-                cpu.x[f.rd] = (cpu.x[f.rs1] as i32) >> shamt;
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
-        Instruction {
-            mask: 0xfe00707f,
-            data: 0x4000503b,
-            name: "SRAW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                cpu.x[f.rd] = (cpu.x[f.rs1] as i32).wrapping_shr(cpu.x[f.rs2] as u32) as i32;
-                Ok(())
-            },
-            disassemble: dump_format_r,
-        },
         Instruction {
             mask: 0xffffffff,
             data: 0x10200073,
@@ -1282,73 +1076,629 @@ pub const fn get_instructions() -> [Instruction; INSTRUCTION_NUM] {
             disassemble: dump_empty,
         },
         Instruction {
-            mask: 0xfc00707f,
-            data: 0x0000501b,
-            name: "SRLIW",
-            operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                let mask = 0x1f;
-                let shamt = (word >> 20) & mask;
-                cpu.x[f.rd] = ((cpu.x[f.rs1] as u32) >> shamt) as i32;
+            mask: 0xffffffff,
+            data: 0x10500073,
+            name: "WFI",
+            operation: |cpu, _word, _address| {
+                cpu.wfi = true;
                 Ok(())
             },
-            disassemble: dump_format_r,
+            disassemble: dump_empty,
+        },
+        Instruction {
+            mask: 0x8000707f,
+            data: 0x00007057,
+            name: "VSETVLI",
+            operation: |cpu, word, _address| {
+                let rs1 = Register::from_field(word >> 15);
+                let rd = Register::from_field(word >> 7);
+                vector::set_vector_length(cpu, rd, Avl::Register(rs1), (word >> 20) & 0x7ff)
+            },
+            disassemble: vector::dump_set_vector_length,
+        },
+        Instruction {
+            mask: 0xc000707f,
+            data: 0xc0007057,
+            name: "VSETIVLI",
+            operation: |cpu, word, _address| {
+                let avl = Avl::Immediate((word >> 15) & 0x1f);
+                let rd = Register::from_field(word >> 7);
+                vector::set_vector_length(cpu, rd, avl, (word >> 20) & 0x3ff)
+            },
+            disassemble: vector::dump_set_vector_length,
         },
         Instruction {
             mask: 0xfe00707f,
-            data: 0x0000503b,
-            name: "SRLW",
+            data: 0x80007057,
+            name: "VSETVL",
             operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                cpu.x[f.rd] = (cpu.x[f.rs1] as u32).wrapping_shr(cpu.x[f.rs2] as u32) as i32;
-                Ok(())
+                let rs1 = Register::from_field(word >> 15);
+                let vtype = cpu.x[Register::from_field(word >> 20)] as u32;
+                let rd = Register::from_field(word >> 7);
+                vector::set_vector_length(cpu, rd, Avl::Register(rs1), vtype)
             },
-            disassemble: dump_format_r,
+            disassemble: vector::dump_set_vector_length,
+        },
+        Instruction {
+            mask: 0xfdf0707f,
+            data: 0x00000007,
+            name: "VLE8.V",
+            operation: |cpu, word, _address| vector::unit_stride(cpu, word, 1, false),
+            disassemble: vector::dump_unit_stride,
+        },
+        Instruction {
+            mask: 0xfdf0707f,
+            data: 0x00005007,
+            name: "VLE16.V",
+            operation: |cpu, word, _address| vector::unit_stride(cpu, word, 2, false),
+            disassemble: vector::dump_unit_stride,
+        },
+        Instruction {
+            mask: 0xfdf0707f,
+            data: 0x00006007,
+            name: "VLE32.V",
+            operation: |cpu, word, _address| vector::unit_stride(cpu, word, 4, false),
+            disassemble: vector::dump_unit_stride,
+        },
+        Instruction {
+            mask: 0xfdf0707f,
+            data: 0x00000027,
+            name: "VSE8.V",
+            operation: |cpu, word, _address| vector::unit_stride(cpu, word, 1, true),
+            disassemble: vector::dump_unit_stride,
+        },
+        Instruction {
+            mask: 0xfdf0707f,
+            data: 0x00005027,
+            name: "VSE16.V",
+            operation: |cpu, word, _address| vector::unit_stride(cpu, word, 2, true),
+            disassemble: vector::dump_unit_stride,
+        },
+        Instruction {
+            mask: 0xfdf0707f,
+            data: 0x00006027,
+            name: "VSE32.V",
+            operation: |cpu, word, _address| vector::unit_stride(cpu, word, 4, true),
+            disassemble: vector::dump_unit_stride,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x00000057,
+            name: "VADD.VV",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| a.wrapping_add(b))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x00004057,
+            name: "VADD.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| a.wrapping_add(b))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x00003057,
+            name: "VADD.VI",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| a.wrapping_add(b))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x08000057,
+            name: "VSUB.VV",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| a.wrapping_sub(b))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x08004057,
+            name: "VSUB.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| a.wrapping_sub(b))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x0c004057,
+            name: "VRSUB.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| b.wrapping_sub(a))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x0c003057,
+            name: "VRSUB.VI",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| b.wrapping_sub(a))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x10000057,
+            name: "VMINU.VV",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a.min(b)),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x10004057,
+            name: "VMINU.VX",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a.min(b)),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x14000057,
+            name: "VMIN.VV",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| {
+                    signed(a, sew).min(signed(b, sew)) as u32
+                })
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x14004057,
+            name: "VMIN.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| {
+                    signed(a, sew).min(signed(b, sew)) as u32
+                })
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x18000057,
+            name: "VMAXU.VV",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a.max(b)),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x18004057,
+            name: "VMAXU.VX",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a.max(b)),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x1c000057,
+            name: "VMAX.VV",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| {
+                    signed(a, sew).max(signed(b, sew)) as u32
+                })
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x1c004057,
+            name: "VMAX.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| {
+                    signed(a, sew).max(signed(b, sew)) as u32
+                })
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x24000057,
+            name: "VAND.VV",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a & b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x24004057,
+            name: "VAND.VX",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a & b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x24003057,
+            name: "VAND.VI",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a & b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x28000057,
+            name: "VOR.VV",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a | b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x28004057,
+            name: "VOR.VX",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a | b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x28003057,
+            name: "VOR.VI",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a | b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x2c000057,
+            name: "VXOR.VV",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a ^ b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x2c004057,
+            name: "VXOR.VX",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a ^ b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x2c003057,
+            name: "VXOR.VI",
+            operation: |cpu, word, _address| vector::integer(cpu, word, |a, b, _sew| a ^ b),
+            disassemble: vector::dump_arithmetic,
         },
         Instruction {
             mask: 0xfe00707f,
-            data: 0x4000003b,
-            name: "SUBW",
+            data: 0x5c000057,
+            name: "VMERGE.VVM",
+            operation: |cpu, word, _address| vector::merge(cpu, word),
+            disassemble: vector::dump_merge,
+        },
+        Instruction {
+            mask: 0xfff0707f,
+            data: 0x5e000057,
+            name: "VMV.V.V",
+            operation: |cpu, word, _address| vector::merge(cpu, word),
+            disassemble: vector::dump_merge,
+        },
+        Instruction {
+            mask: 0xfe00707f,
+            data: 0x5c004057,
+            name: "VMERGE.VXM",
+            operation: |cpu, word, _address| vector::merge(cpu, word),
+            disassemble: vector::dump_merge,
+        },
+        Instruction {
+            mask: 0xfff0707f,
+            data: 0x5e004057,
+            name: "VMV.V.X",
+            operation: |cpu, word, _address| vector::merge(cpu, word),
+            disassemble: vector::dump_merge,
+        },
+        Instruction {
+            mask: 0xfe00707f,
+            data: 0x5c003057,
+            name: "VMERGE.VIM",
+            operation: |cpu, word, _address| vector::merge(cpu, word),
+            disassemble: vector::dump_merge,
+        },
+        Instruction {
+            mask: 0xfff0707f,
+            data: 0x5e003057,
+            name: "VMV.V.I",
+            operation: |cpu, word, _address| vector::merge(cpu, word),
+            disassemble: vector::dump_merge,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x60000057,
+            name: "VMSEQ.VV",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a == b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x60004057,
+            name: "VMSEQ.VX",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a == b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x60003057,
+            name: "VMSEQ.VI",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a == b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x64000057,
+            name: "VMSNE.VV",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a != b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x64004057,
+            name: "VMSNE.VX",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a != b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x64003057,
+            name: "VMSNE.VI",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a != b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x68000057,
+            name: "VMSLTU.VV",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a < b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x68004057,
+            name: "VMSLTU.VX",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a < b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x6c000057,
+            name: "VMSLT.VV",
             operation: |cpu, word, _address| {
-                let f = parse_format_r(word);
-                cpu.x[f.rd] = cpu.x[f.rs1].wrapping_sub(cpu.x[f.rs2]) as i32;
-                Ok(())
+                vector::compare(cpu, word, |a, b, sew| signed(a, sew) < signed(b, sew))
             },
-            disassemble: dump_format_r,
+            disassemble: vector::dump_arithmetic,
         },
         Instruction {
-            mask: 0xffffffff,
-            data: 0x00200073,
-            name: "URET",
-            operation: |_cpu, _word, _address| {
-                // @TODO: Implement
-                panic!("URET instruction is not implemented yet.");
+            mask: 0xfc00707f,
+            data: 0x6c004057,
+            name: "VMSLT.VX",
+            operation: |cpu, word, _address| {
+                vector::compare(cpu, word, |a, b, sew| signed(a, sew) < signed(b, sew))
             },
-            disassemble: dump_empty,
+            disassemble: vector::dump_arithmetic,
         },
         Instruction {
-            mask: 0xffffffff,
-            data: 0x10500073,
-            name: "WFI",
-            operation: |cpu, _word, _address| {
-                cpu.wfi = true;
-                Ok(())
+            mask: 0xfc00707f,
+            data: 0x70000057,
+            name: "VMSLEU.VV",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a <= b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x70004057,
+            name: "VMSLEU.VX",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a <= b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x70003057,
+            name: "VMSLEU.VI",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a <= b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x74000057,
+            name: "VMSLE.VV",
+            operation: |cpu, word, _address| {
+                vector::compare(cpu, word, |a, b, sew| signed(a, sew) <= signed(b, sew))
             },
-            disassemble: dump_empty,
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x74004057,
+            name: "VMSLE.VX",
+            operation: |cpu, word, _address| {
+                vector::compare(cpu, word, |a, b, sew| signed(a, sew) <= signed(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x74003057,
+            name: "VMSLE.VI",
+            operation: |cpu, word, _address| {
+                vector::compare(cpu, word, |a, b, sew| signed(a, sew) <= signed(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x78004057,
+            name: "VMSGTU.VX",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a > b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x78003057,
+            name: "VMSGTU.VI",
+            operation: |cpu, word, _address| vector::compare(cpu, word, |a, b, _sew| a > b),
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x7c004057,
+            name: "VMSGT.VX",
+            operation: |cpu, word, _address| {
+                vector::compare(cpu, word, |a, b, sew| signed(a, sew) > signed(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x7c003057,
+            name: "VMSGT.VI",
+            operation: |cpu, word, _address| {
+                vector::compare(cpu, word, |a, b, sew| signed(a, sew) > signed(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x94000057,
+            name: "VSLL.VV",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| a << shift(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x94004057,
+            name: "VSLL.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| a << shift(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x94003057,
+            name: "VSLL.VI",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| a << shift(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0xa0000057,
+            name: "VSRL.VV",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| a >> shift(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0xa0004057,
+            name: "VSRL.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| a >> shift(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0xa0003057,
+            name: "VSRL.VI",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| a >> shift(b, sew))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0xa4000057,
+            name: "VSRA.VV",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| {
+                    (signed(a, sew) >> shift(b, sew)) as u32
+                })
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0xa4004057,
+            name: "VSRA.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| {
+                    (signed(a, sew) >> shift(b, sew)) as u32
+                })
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0xa4003057,
+            name: "VSRA.VI",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, sew| {
+                    (signed(a, sew) >> shift(b, sew)) as u32
+                })
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x94002057,
+            name: "VMUL.VV",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| a.wrapping_mul(b))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfc00707f,
+            data: 0x94006057,
+            name: "VMUL.VX",
+            operation: |cpu, word, _address| {
+                vector::integer(cpu, word, |a, b, _sew| a.wrapping_mul(b))
+            },
+            disassemble: vector::dump_arithmetic,
+        },
+        Instruction {
+            mask: 0xfe0ff07f,
+            data: 0x42002057,
+            name: "VMV.X.S",
+            operation: |cpu, word, _address| vector::move_to_scalar(cpu, word),
+            disassemble: vector::dump_move_to_scalar,
+        },
+        Instruction {
+            mask: 0xfff0707f,
+            data: 0x42006057,
+            name: "VMV.S.X",
+            operation: |cpu, word, _address| vector::move_to_vector(cpu, word),
+            disassemble: vector::dump_move_to_vector,
         },
     ]
 }
 
 struct FormatB {
-    rs1: usize,
-    rs2: usize,
+    rs1: Register,
+    rs2: Register,
     imm: u32,
 }
 
+/// Run an AMO, which replaces the word at `rs1` with `operation` of it and
+/// `rs2` in one atomic access, and sets `rd` to what it held.
+fn amo(cpu: &mut Cpu, word: u32, operation: fn(u32, u32) -> u32) -> Result<(), Trap> {
+    let f = parse_format_r(word);
+    let operand = cpu.x[f.rs2] as u32;
+    let data = cpu
+        .mmu
+        .amo(cpu.x[f.rs1] as u32, &mut |data| operation(data, operand))?;
+    cpu.x[f.rd] = data as i32;
+    Ok(())
+}
+
 fn parse_format_b(word: u32) -> FormatB {
     FormatB {
-        rs1: ((word >> 15) & 0x1f) as usize, // [19:15]
-        rs2: ((word >> 20) & 0x1f) as usize, // [24:20]
+        rs1: Register::from_field(word >> 15), // [19:15]
+        rs2: Register::from_field(word >> 20), // [24:20]
         imm: (
             match word & 0x80000000 { // imm[31:12] = [31]
 				0x80000000 => 0xfffff000,
@@ -1379,15 +1729,17 @@ fn dump_format_b(cpu: &Cpu, word: u32, address: u32, evaluate: bool) -> String {
 
 struct FormatCSR {
     csr: u16,
-    rs: usize,
-    rd: usize,
+    rs: Register,
+    uimm: u32,
+    rd: Register,
 }
 
 fn parse_format_csr(word: u32) -> FormatCSR {
     FormatCSR {
-        csr: ((word >> 20) & 0xfff) as u16, // [31:20]
-        rs: ((word >> 15) & 0x1f) as usize, // [19:15], also uimm
-        rd: ((word >> 7) & 0x1f) as usize,  // [11:7]
+        csr: ((word >> 20) & 0xfff) as u16,   // [31:20]
+        rs: Register::from_field(word >> 15), // [19:15]
+        uimm: (word >> 15) & 0x1f,            // [19:15]
+        rd: Register::from_field(word >> 7),  // [11:7]
     }
 }
 
@@ -1411,15 +1763,15 @@ fn dump_format_csr(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> Strin
 }
 
 struct FormatI {
-    rd: usize,
-    rs1: usize,
+    rd: Register,
+    rs1: Register,
     imm: i32,
 }
 
 fn parse_format_i(word: u32) -> FormatI {
     FormatI {
-        rd: ((word >> 7) & 0x1f) as usize,   // [11:7]
-        rs1: ((word >> 15) & 0x1f) as usize, // [19:15]
+        rd: Register::from_field(word >> 7),   // [11:7]
+        rs1: Register::from_field(word >> 15), // [19:15]
         imm: (
             if word & 0x80000000 != 0 {
                 // imm[31:11] = [31]
@@ -1463,13 +1815,13 @@ fn dump_format_i_mem(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> Str
 }
 
 struct FormatJ {
-    rd: usize,
+    rd: Register,
     imm: u32,
 }
 
 fn parse_format_j(word: u32) -> FormatJ {
     FormatJ {
-        rd: ((word >> 7) & 0x1f) as usize, // [11:7]
+        rd: Register::from_field(word >> 7), // [11:7]
         imm: (
             match word & 0x80000000 { // imm[31:20] = [31]
 				0x80000000 => 0xfff00000,
@@ -1495,23 +1847,23 @@ fn dump_format_j(cpu: &Cpu, word: u32, address: u32, evaluate: bool) -> String {
 }
 
 struct FormatR {
-    rd: usize,
-    rs1: usize,
-    rs2: usize,
+    rd: Register,
+    rs1: Register,
+    rs2: Register,
 }
 
 fn parse_format_r(word: u32) -> FormatR {
     // println!(
     //     "parse_format_r({:x}) -> rd:{} rs1:{} rs2:{}",
     //     word,
-    //     ((word >> 7) & 0x1f) as usize,
-    //     ((word >> 15) & 0x1f) as usize,
-    //     ((word >> 20) & 0x1f) as usize
+    //     Register::from_field(word >> 7),
+    //     Register::from_field(word >> 15),
+    //     Register::from_field(word >> 20)
     // );
     FormatR {
-        rd: ((word >> 7) & 0x1f) as usize,   // [11:7]
-        rs1: ((word >> 15) & 0x1f) as usize, // [19:15]
-        rs2: ((word >> 20) & 0x1f) as usize, // [24:20]
+        rd: Register::from_field(word >> 7),   // [11:7]
+        rs1: Register::from_field(word >> 15), // [19:15]
+        rs2: Register::from_field(word >> 20), // [24:20]
     }
 }
 
@@ -1543,10 +1895,10 @@ fn dump_format_r(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> String
 
 // fn parse_format_r2(word: u32) -> FormatR2 {
 //     FormatR2 {
-//         rd: ((word >> 7) & 0x1f) as usize,   // [11:7]
-//         rs1: ((word >> 15) & 0x1f) as usize, // [19:15]
-//         rs2: ((word >> 20) & 0x1f) as usize, // [24:20]
-//         rs3: ((word >> 27) & 0x1f) as usize, // [31:27]
+//         rd: Register::from_field(word >> 7),   // [11:7]
+//         rs1: Register::from_field(word >> 15), // [19:15]
+//         rs2: Register::from_field(word >> 20), // [24:20]
+//         rs3: Register::from_field(word >> 27), // [31:27]
 //     }
 // }
 
@@ -1573,8 +1925,8 @@ fn dump_format_r(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> String
 // }
 
 struct FormatS {
-    rs1: usize,
-    rs2: usize,
+    rs1: Register,
+    rs2: Register,
     imm: i32,
 }
 
@@ -1582,8 +1934,8 @@ fn parse_format_s(word: u32) -> FormatS {
     // println!(
     //     "parse_format_s(0x{:08x}): rs1:{} rs2:{} imm:{}",
     //     word,
-    //     ((word >> 15) & 0x1f) as usize,
-    //     ((word >> 20) & 0x1f) as usize,
+    //     Register::from_field(word >> 15),
+    //     Register::from_field(word >> 20),
     //     (match word & 0x80000000 {
     //             0x80000000 => 0xfffff000,
     //             _ => 0,
@@ -1592,8 +1944,8 @@ fn parse_format_s(word: u32) -> FormatS {
     //         ((word >> 7) & 0x1f)) as i32
     // );
     FormatS {
-        rs1: ((word >> 15) & 0x1f) as usize, // [19:15]
-        rs2: ((word >> 20) & 0x1f) as usize, // [24:20]
+        rs1: Register::from_field(word >> 15), // [19:15]
+        rs2: Register::from_field(word >> 20), // [24:20]
         imm: (
             match word & 0x80000000 {
 				0x80000000 => 0xfffff000,
@@ -1622,13 +1974,13 @@ fn dump_format_s(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> String
 }
 
 struct FormatU {
-    rd: usize,
+    rd: Register,
     imm: u32,
 }
 
 fn parse_format_u(word: u32) -> FormatU {
     FormatU {
-        rd: ((word >> 7) & 0x1f) as usize, // [11:7]
+        rd: Register::from_field(word >> 7), // [11:7]
         imm: word & 0xfffff000,
     }
 }
@@ -1648,8 +2000,8 @@ fn dump_empty(_cpu: &Cpu, _word: u32, _address: u32, _evaluate: bool) -> String
     String::new()
 }
 
-fn get_register_name(num: usize) -> &'static str {
-    match num {
+pub(super) fn get_register_name(register: Register) -> &'static str {
+    match register.index() {
         0 => "zero",
         1 => "ra",
         2 => "sp",
@@ -1682,6 +2034,6 @@ fn get_register_name(num: usize) -> &'static str {
         29 => "t4",
         30 => "t5",
         31 => "t6",
-        _ => panic!("Unknown register num {}", num),
+        _ => unreachable!("registers are below 32"),
     }
 }
@@ -0,0 +1,92 @@
+use core::ops::{Index, IndexMut};
+
+/// An integer register number. It's always below 32, so indexing a
+/// `RegisterFile` with it can't go out of bounds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Register(u8);
+
+impl Register {
+    /// The hardwired zero register
+    pub const ZERO: Register = Register(0);
+
+    /// The first argument and return value register
+    pub const A0: Register = Register(10);
+
+    /// Returns the register numbered `index`, or `None` if there isn't one
+    pub fn new(index: u8) -> Option<Self> {
+        (index < 32).then_some(Register(index))
+    }
+
+    /// Returns the register in the low five bits of `field`, ignoring the
+    /// rest, so that fields can be passed in straight from an instruction
+    pub const fn from_field(field: u32) -> Self {
+        Register((field & 0x1f) as u8)
+    }
+
+    /// Returns the register `offset` places after this one, wrapping around
+    pub const fn offset(self, offset: u8) -> Self {
+        Register(self.0.wrapping_add(offset) & 0x1f)
+    }
+
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Returns the register's ABI name, such as `a0` for `x10`
+    pub fn name(self) -> &'static str {
+        super::instructions::get_register_name(self)
+    }
+}
+
+/// The integer registers. `x0` reads as zero and ignores writes, since
+/// writes to it through `IndexMut` land in a scratch slot that is never read.
+#[derive(Clone, Default)]
+pub struct RegisterFile {
+    x: [i32; 32],
+    discard: i32,
+    /// A bit for each register written through `IndexMut`, if writes are
+    /// being recorded
+    written: u32,
+    record_writes: bool,
+}
+
+impl RegisterFile {
+    /// Returns every register, starting with `x0`
+    pub fn as_array(&self) -> &[i32; 32] {
+        &self.x
+    }
+
+    /// Note which registers are written, to be collected with
+    /// `take_written`. Writes aren't recorded otherwise, since every
+    /// instruction makes them
+    pub fn record_writes(&mut self, record: bool) {
+        self.record_writes = record;
+        self.written = 0;
+    }
+
+    /// Returns a bit for each register written since this was last called,
+    /// with `x0` in bit 0, or 0 if writes aren't being recorded
+    pub fn take_written(&mut self) -> u32 {
+        core::mem::take(&mut self.written)
+    }
+}
+
+impl Index<Register> for RegisterFile {
+    type Output = i32;
+
+    fn index(&self, register: Register) -> &i32 {
+        &self.x[register.index()]
+    }
+}
+
+impl IndexMut<Register> for RegisterFile {
+    fn index_mut(&mut self, register: Register) -> &mut i32 {
+        if self.record_writes {
+            self.written |= 1 << register.index();
+        }
+        match register {
+            Register::ZERO => &mut self.discard,
+            _ => &mut self.x[register.index()],
+        }
+    }
+}
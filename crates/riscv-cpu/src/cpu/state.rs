@@ -0,0 +1,97 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::instructions::get_register_name;
+use super::{PrivilegeMode, Register};
+
+/// The state of a `Cpu` at one point in time, as taken by
+/// `Cpu::capture_state`, for comparing with a later state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuState {
+    pub pc: u32,
+    pub privilege_mode: PrivilegeMode,
+    /// The integer registers, starting with `x0`
+    pub registers: [i32; 32],
+    /// CSRs by address. Any CSR that's missing reads as zero.
+    pub csrs: BTreeMap<u16, u32>,
+}
+
+/// What changed from one `CpuState` to another, each as `(before, after)`.
+/// It displays as a single line, such as
+/// `pc 80000010 -> 80000014, a0 00000000 -> 00000003`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StateDelta {
+    pub pc: Option<(u32, u32)>,
+    pub privilege_mode: Option<(PrivilegeMode, PrivilegeMode)>,
+    /// Registers that changed, by number, in order
+    pub registers: Vec<(u8, i32, i32)>,
+    /// CSRs that changed, by address, in order
+    pub csrs: Vec<(u16, u32, u32)>,
+}
+
+impl CpuState {
+    /// How this state differs from the earlier state `prev`
+    pub fn delta(&self, prev: &CpuState) -> StateDelta {
+        let csr = |state: &CpuState, address: &u16| state.csrs.get(address).copied().unwrap_or(0);
+        let mut addresses: Vec<u16> = prev.csrs.keys().chain(self.csrs.keys()).copied().collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+        StateDelta {
+            pc: (prev.pc != self.pc).then_some((prev.pc, self.pc)),
+            privilege_mode: (prev.privilege_mode != self.privilege_mode)
+                .then_some((prev.privilege_mode, self.privilege_mode)),
+            registers: (0..32)
+                .filter(|&index| prev.registers[index] != self.registers[index])
+                .map(|index| (index as u8, prev.registers[index], self.registers[index]))
+                .collect(),
+            csrs: addresses
+                .iter()
+                .filter(|address| csr(prev, address) != csr(self, address))
+                .map(|address| (*address, csr(prev, address), csr(self, address)))
+                .collect(),
+        }
+    }
+}
+
+impl StateDelta {
+    /// Whether nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.pc.is_none()
+            && self.privilege_mode.is_none()
+            && self.registers.is_empty()
+            && self.csrs.is_empty()
+    }
+}
+
+impl fmt::Display for StateDelta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut changes = vec![];
+        if let Some((before, after)) = self.pc {
+            changes.push(format!("pc {:08x} -> {:08x}", before, after));
+        }
+        if let Some((before, after)) = self.privilege_mode {
+            changes.push(format!("privilege {:?} -> {:?}", before, after));
+        }
+        for &(index, before, after) in &self.registers {
+            changes.push(format!(
+                "{} {:08x} -> {:08x}",
+                get_register_name(Register::from_field(index as u32)),
+                before,
+                after
+            ));
+        }
+        for &(address, before, after) in &self.csrs {
+            changes.push(format!(
+                "csr {:03x} {:08x} -> {:08x}",
+                address, before, after
+            ));
+        }
+        match changes.is_empty() {
+            true => write!(f, "no change"),
+            false => write!(f, "{}", changes.join(", ")),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 mod memory;
+mod opcodes;
 use super::*;
+use proptest::prelude::*;
 const MEMORY_BASE: u32 = 0x8000_0000;
 
 fn create_cpu(memory_capacity: usize) -> (Cpu, Box<memory::Memory>) {
@@ -39,7 +41,7 @@ fn read_register() {
     }
 
     for i in 0..31 {
-        cpu.x[i] = i as i32 + 1;
+        cpu.x[Register::from_field(i)] = i as i32 + 1;
     }
 
     for i in 0..31 {
@@ -51,7 +53,7 @@ fn read_register() {
     }
 
     for i in 0..31 {
-        cpu.x[i] = (0xffffffff - i) as i32;
+        cpu.x[Register::from_field(i)] = (0xffffffff - i) as i32;
     }
 
     for i in 0..31 {
@@ -66,6 +68,22 @@ fn read_register() {
     // greater than 32?
 }
 
+#[test]
+fn zero_register_discards_writes() {
+    let mut cpu = create_cpu(0).0;
+    cpu.write_register(1, 7);
+
+    // addi x0, x1, 5
+    cpu.execute_opcode(0x0050_8013).unwrap();
+    assert_eq!(0, cpu.read_register(0));
+
+    // csrrs x0, mscratch, x1 still sets bits from the old value
+    cpu.write_csr(0x340, 0x10).unwrap();
+    cpu.execute_opcode(0x3400_a073).unwrap();
+    assert_eq!(0x17, cpu.peek_csr(0x340));
+    assert_eq!(0, cpu.read_register(0));
+}
+
 #[test]
 fn tick() {
     let mut cpu = create_cpu(4).0;
@@ -94,6 +112,111 @@ fn tick() {
     assert_eq!(8, cpu.read_register(8));
 }
 
+#[test]
+fn instructions_retired() {
+    let mut cpu = create_cpu(8).0;
+    cpu.update_pc(MEMORY_BASE);
+    // addi x1, x1, 1
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE, 0x00108093)
+        .unwrap();
+    // An illegal instruction
+    cpu.get_mut_mmu().store_word(MEMORY_BASE + 4, 0).unwrap();
+
+    cpu.tick();
+    assert_eq!(1, cpu.instructions_retired());
+
+    // Instructions that trap don't retire
+    cpu.tick();
+    assert_eq!(1, cpu.instructions_retired());
+
+    // Nor do cycles spent waiting for an interrupt
+    cpu.wfi = true;
+    cpu.tick();
+    assert_eq!(1, cpu.instructions_retired());
+}
+
+#[test]
+fn state_delta() {
+    let mut cpu = create_cpu(8).0;
+    cpu.update_pc(MEMORY_BASE);
+    // addi x10, x0, 3
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE, 0x0030_0513)
+        .unwrap();
+    // csrrw x0, mscratch, x10
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE + 4, 0x3405_1073)
+        .unwrap();
+
+    let start = cpu.capture_state();
+    assert!(cpu.state_delta(&start).is_empty());
+    assert_eq!("no change", cpu.state_delta(&start).to_string());
+
+    cpu.tick();
+    assert_eq!(
+        "pc 80000000 -> 80000004, a0 00000000 -> 00000003",
+        cpu.state_delta(&start).to_string()
+    );
+
+    // The cycle counter moved too, but counters are left out
+    let after_addi = cpu.capture_state();
+    cpu.tick();
+    let delta = cpu.state_delta(&after_addi);
+    assert_eq!(Some((MEMORY_BASE + 4, MEMORY_BASE + 8)), delta.pc);
+    assert!(delta.registers.is_empty());
+    assert_eq!(vec![(0x340, 0, 3)], delta.csrs);
+}
+
+#[test]
+fn log_commits() {
+    let mut cpu = create_cpu(32).0;
+    cpu.update_pc(MEMORY_BASE);
+    let program = [
+        0x0000_0297, // auipc x5, 0
+        0x0252_a023, // sw x5, 32(x5)
+        0x0202_a303, // lw x6, 32(x5)
+        0x3402_9073, // csrrw x0, mscratch, x5
+        0x0000_0285, // c.addi x5, 1
+    ];
+    for (index, word) in program.iter().enumerate() {
+        cpu.get_mut_mmu()
+            .store_word(MEMORY_BASE + index as u32 * 4, *word)
+            .unwrap();
+    }
+
+    // Nothing is recorded until it's turned on
+    cpu.tick();
+    assert_eq!(None, cpu.last_commit());
+    cpu.update_pc(MEMORY_BASE);
+    cpu.log_commits(true);
+
+    let mut lines = vec![];
+    for _ in 0..program.len() {
+        assert!(matches!(cpu.tick(), TickResult::Ok));
+        lines.push(cpu.last_commit().unwrap().to_string());
+    }
+    assert_eq!(
+        vec![
+            "core   0: 3 0x80000000 (0x00000297) x5  0x80000000",
+            "core   0: 3 0x80000004 (0x0252a023) mem 0x80000020 0x80000000",
+            "core   0: 3 0x80000008 (0x0202a303) x6  0x80000000 mem 0x80000020",
+            "core   0: 3 0x8000000c (0x34029073) c832_mscratch 0x80000000",
+            "core   0: 3 0x80000010 (0x0285) x5  0x80000001",
+        ],
+        lines
+    );
+}
+
+#[test]
+fn instruction_name() {
+    let cpu = create_cpu(0).0;
+    assert_eq!(Some("ADDI"), cpu.instruction_name(0x00108093));
+    assert_eq!(None, cpu.instruction_name(0));
+    // c.addi4spn x8, x2, 8 expands to addi x8, x2, 8
+    assert_eq!(0x00810413, cpu.expand_compressed(0x0020));
+}
+
 #[test]
 fn tick_operate() {
     let mut cpu = create_cpu(4).0;
@@ -157,7 +280,7 @@ fn decode_raw() {
     // .decode_raw() returns error for invalid word data.
     match cpu.decode_raw(0x0) {
         Ok(_inst) => panic!("Unexpectedly succeeded in decoding"),
-        Err(_trap) => assert!(true),
+        Err(_trap) => {}
     };
     // @TODO: Should I test all instructions?
 }
@@ -167,13 +290,448 @@ fn uncompress() {
     let mut cpu = create_cpu(0).0;
     // .uncompress() doesn't directly return an instruction but
     // it returns uncompressed word. Then you need to call .decode_raw().
-    match cpu.decode_raw(cpu.uncompress(0x20)) {
+    let word = cpu.uncompress(0x20);
+    match cpu.decode_raw(word) {
         Ok(inst) => assert_eq!(inst.name, "ADDI"),
         Err(_e) => panic!("Failed to decode"),
     };
     // @TODO: Should I test all compressed instructions?
 }
 
+/// What the RVC spec says a 16-bit encoding means on RV32IMAC.
+#[derive(Debug)]
+enum Rvc {
+    /// The instruction's name once expanded, and the 32-bit word it expands to.
+    Valid(&'static str, u32),
+
+    /// Reserved encodings, which must raise an illegal instruction exception.
+    Reserved,
+
+    /// HINTs, which run as no-ops unless the `HintPolicy` says otherwise.
+    Hint,
+
+    /// Encodings reserved for custom extensions, and instructions from RV64
+    /// or the F and D extensions, none of which are checked here.
+    Other,
+}
+
+/// Marks a bit of a compressed instruction that isn't part of the immediate.
+const X: u8 = u8::MAX;
+
+/// Gather an immediate from bits [12:2] of `halfword`, where `layout` lists
+/// the immediate bit held by each of those bits, written the same way as the
+/// tables in the RVC chapter of the spec.
+fn rvc_imm(halfword: u16, layout: [u8; 11]) -> u32 {
+    let mut imm = 0;
+    for (index, bit) in layout.into_iter().enumerate() {
+        if bit != X {
+            imm |= ((halfword as u32 >> (12 - index)) & 1) << bit;
+        }
+    }
+    imm
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as u32
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    ((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (((imm >> 12) & 1) << 31)
+        | (((imm >> 5) & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xf) << 8)
+        | (((imm >> 11) & 1) << 7)
+        | 0x63
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    (((imm >> 20) & 1) << 31)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 11) & 1) << 20)
+        | (((imm >> 12) & 0xff) << 12)
+        | (rd << 7)
+        | 0x6f
+}
+
+/// An independent model of RV32C, written from the spec's encoding tables
+/// rather than from `uncompress_inner`.
+fn rvc_reference(halfword: u16) -> Rvc {
+    use Rvc::*;
+    let bits = |high: u32, low: u32| (halfword as u32 >> low) & ((1 << (high - low + 1)) - 1);
+    let rd = bits(11, 7);
+    let rs2 = bits(6, 2);
+    let rd_prime = bits(4, 2) + 8;
+    let rs1_prime = bits(9, 7) + 8;
+    let imm6 = sign_extend(rvc_imm(halfword, [5, X, X, X, X, X, 4, 3, 2, 1, 0]), 6);
+    let shamt = rvc_imm(halfword, [5, X, X, X, X, X, 4, 3, 2, 1, 0]);
+    let jump_offset = sign_extend(rvc_imm(halfword, [11, 4, 9, 8, 10, 6, 7, 3, 2, 1, 5]), 12);
+    let branch_offset = sign_extend(rvc_imm(halfword, [8, 4, 3, X, X, X, 7, 6, 2, 1, 5]), 9);
+    let word_offset = rvc_imm(halfword, [5, 4, 3, X, X, X, 2, 6, X, X, X]);
+
+    match (bits(1, 0), bits(15, 13)) {
+        // C.ADDI4SPN, with nzuimm == 0 reserved. This includes the all-zero
+        // halfword, which is defined to be illegal.
+        (0, 0) => match rvc_imm(halfword, [5, 4, 9, 8, 7, 6, 2, 3, X, X, X]) {
+            0 => Reserved,
+            nzuimm => Valid("ADDI", i_type(nzuimm, 2, 0, rd_prime, 0x13)),
+        },
+        // C.LW
+        (0, 2) => Valid("LW", i_type(word_offset, rs1_prime, 2, rd_prime, 0x03)),
+        (0, 4) => Reserved,
+        // C.SW
+        (0, 6) => Valid("SW", s_type(word_offset, rd_prime, rs1_prime, 2, 0x23)),
+        // C.NOP and C.ADDI
+        (1, 0) if rd == 0 && imm6 == 0 => Valid("ADDI", 0x13),
+        (1, 0) if rd == 0 || imm6 == 0 => Hint,
+        (1, 0) => Valid("ADDI", i_type(imm6, rd, 0, rd, 0x13)),
+        // C.JAL
+        (1, 1) => Valid("JAL", j_type(jump_offset, 1)),
+        // C.LI
+        (1, 2) if rd == 0 => Hint,
+        (1, 2) => Valid("ADDI", i_type(imm6, 0, 0, rd, 0x13)),
+        // C.ADDI16SP, with nzimm == 0 reserved
+        (1, 3) if rd == 2 => {
+            match sign_extend(rvc_imm(halfword, [9, X, X, X, X, X, 4, 6, 8, 7, 5]), 10) {
+                0 => Reserved,
+                nzimm => Valid("ADDI", i_type(nzimm, 2, 0, 2, 0x13)),
+            }
+        }
+        // C.LUI, with nzimm == 0 reserved, which is a HINT when rd == 0
+        (1, 3) => match sign_extend(
+            rvc_imm(halfword, [17, X, X, X, X, X, 16, 15, 14, 13, 12]),
+            18,
+        ) {
+            0 => Reserved,
+            _ if rd == 0 => Hint,
+            nzimm => Valid("LUI", nzimm | (rd << 7) | 0x37),
+        },
+        (1, 4) => match (bits(11, 10), bits(12, 12), bits(6, 5)) {
+            // shamt[5] set is reserved for custom extensions on RV32, and
+            // shamt == 0 is a HINT
+            (0 | 1, _, _) if shamt & 0x20 != 0 => Other,
+            (0 | 1, _, _) if shamt == 0 => Hint,
+            // C.SRLI
+            (0, _, _) => Valid("SRLI", i_type(shamt, rs1_prime, 5, rs1_prime, 0x13)),
+            // C.SRAI
+            (1, _, _) => Valid("SRAI", i_type(0x400 | shamt, rs1_prime, 5, rs1_prime, 0x13)),
+            // C.ANDI
+            (2, _, _) => Valid("ANDI", i_type(imm6, rs1_prime, 7, rs1_prime, 0x13)),
+            // C.SUB, C.XOR, C.OR, and C.AND
+            (3, 0, 0) => Valid("SUB", r_type(0x20, rd_prime, rs1_prime, 0, rs1_prime, 0x33)),
+            (3, 0, 1) => Valid("XOR", r_type(0, rd_prime, rs1_prime, 4, rs1_prime, 0x33)),
+            (3, 0, 2) => Valid("OR", r_type(0, rd_prime, rs1_prime, 6, rs1_prime, 0x33)),
+            (3, 0, 3) => Valid("AND", r_type(0, rd_prime, rs1_prime, 7, rs1_prime, 0x33)),
+            // C.SUBW and C.ADDW only exist on RV64
+            (3, 1, 0 | 1) => Other,
+            _ => Reserved,
+        },
+        // C.J
+        (1, 5) => Valid("JAL", j_type(jump_offset, 0)),
+        // C.BEQZ and C.BNEZ
+        (1, 6) => Valid("BEQ", b_type(branch_offset, 0, rs1_prime, 0)),
+        (1, 7) => Valid("BNE", b_type(branch_offset, 0, rs1_prime, 1)),
+        // C.SLLI, which is a HINT when rd == 0 or shamt == 0
+        (2, 0) if shamt & 0x20 != 0 => Other,
+        (2, 0) if rd == 0 || shamt == 0 => Hint,
+        (2, 0) => Valid("SLLI", i_type(shamt, rd, 1, rd, 0x13)),
+        // C.LWSP, with rd == 0 reserved
+        (2, 2) if rd == 0 => Reserved,
+        (2, 2) => {
+            let offset = rvc_imm(halfword, [5, X, X, X, X, X, 4, 3, 2, 7, 6]);
+            Valid("LW", i_type(offset, 2, 2, rd, 0x03))
+        }
+        (2, 4) => match (bits(12, 12), rd, rs2) {
+            // C.JR, with rs1 == 0 reserved
+            (0, 0, 0) => Reserved,
+            (0, _, 0) => Valid("JALR", i_type(0, rd, 0, 0, 0x67)),
+            // C.MV, which is a HINT when rd == 0
+            (0, 0, _) => Hint,
+            (0, _, _) => Valid("ADD", r_type(0, rs2, 0, 0, rd, 0x33)),
+            // C.EBREAK
+            (_, 0, 0) => Valid("EBREAK", 0x0010_0073),
+            // C.JALR
+            (_, _, 0) => Valid("JALR", i_type(0, rd, 0, 1, 0x67)),
+            // C.ADD, which is a HINT when rd == 0
+            (_, 0, _) => Hint,
+            (_, _, _) => Valid("ADD", r_type(0, rs2, rd, 0, rd, 0x33)),
+        },
+        // C.SWSP
+        (2, 6) => {
+            let offset = rvc_imm(halfword, [5, 4, 3, 2, 7, 6, X, X, X, X, X]);
+            Valid("SW", s_type(offset, rs2, 2, 2, 0x23))
+        }
+        // Floating point loads and stores, or their RV64 replacements
+        _ => Other,
+    }
+}
+
+/// Every 16-bit encoding, which is to say every halfword that doesn't have
+/// both of its low bits set.
+fn any_compressed() -> impl Strategy<Value = u16> {
+    any::<u16>().prop_filter("not a compressed instruction", |halfword| halfword & 3 != 3)
+}
+
+/// Encodings that the spec marks as reserved, with their free bits filled in
+/// at random.
+fn reserved_compressed() -> impl Strategy<Value = u16> {
+    prop_oneof![
+        // C.ADDI4SPN with nzuimm == 0
+        (0u16..8).prop_map(|rd| rd << 2),
+        // Quadrant 0 with funct3 == 4
+        any::<u16>().prop_map(|bits| 0x8000 | (bits & 0x1ffc)),
+        // C.ADDI16SP with nzimm == 0
+        Just(0x6101u16),
+        // C.LUI with nzimm == 0
+        (0u16..32)
+            .prop_filter("rd == 2 is C.ADDI16SP", |rd| *rd != 2)
+            .prop_map(|rd| 0x6001 | (rd << 7)),
+        // The two unallocated arithmetic encodings after C.SUBW and C.ADDW
+        any::<u16>().prop_map(|bits| 0x9c41 | (bits & 0x03bc)),
+        // C.LWSP with rd == 0
+        any::<u16>().prop_map(|bits| 0x4002 | (bits & 0x107c)),
+        // C.JR with rs1 == 0
+        Just(0x8002u16),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(8192))]
+
+    #[test]
+    fn uncompress_matches_spec(halfword in any_compressed()) {
+        let cpu = create_cpu(0).0;
+        if let Rvc::Valid(name, word) = rvc_reference(halfword) {
+            let expanded = cpu.expand_compressed(halfword);
+            prop_assert_eq!(
+                word, expanded,
+                "{:04x} expanded to {:08x} rather than {:08x}", halfword, expanded, word
+            );
+            prop_assert_eq!(Some(name), cpu.instruction_name(expanded));
+        }
+    }
+}
+
+/// Encodings that the spec marks as HINTs.
+fn hint_compressed() -> impl Strategy<Value = u16> {
+    any_compressed().prop_filter("not a HINT", |halfword| {
+        matches!(rvc_reference(*halfword), Rvc::Hint)
+    })
+}
+
+#[test]
+fn compressed_hints_match_spec() {
+    for halfword in (0..=u16::MAX).filter(|halfword| halfword & 3 != 3) {
+        assert_eq!(
+            matches!(rvc_reference(halfword), Rvc::Hint),
+            is_compressed_hint(halfword as u32),
+            "{:04x}",
+            halfword
+        );
+    }
+}
+
+/// Run the compressed instruction `halfword` with the HINT policy `policy`,
+/// returning the result, the registers, and the PC afterwards.
+fn run_compressed(
+    halfword: u16,
+    policy: HintPolicy,
+) -> (Result<(), Trap>, [i32; 32], u32, Box<memory::Memory>) {
+    let (mut cpu, memory) = create_cpu(4);
+    cpu.hint_policy(policy);
+    for register in 1..32 {
+        cpu.write_register(register, register as i32 * 0x0101_0101);
+    }
+    cpu.update_pc(MEMORY_BASE);
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE, halfword as u32)
+        .unwrap();
+    let result = cpu.tick_operate();
+    let mut registers = [0; 32];
+    for (index, register) in registers.iter_mut().enumerate() {
+        *register = cpu.read_register(index as u8);
+    }
+    (result, registers, cpu.read_pc(), memory)
+}
+
+proptest! {
+    #[test]
+    fn hints_run_as_no_ops(halfword in hint_compressed()) {
+        let (_, before, _, _) = run_compressed(0x0001, HintPolicy::Ignore);
+        for policy in [HintPolicy::Ignore, HintPolicy::Report] {
+            let (result, registers, pc, memory) = run_compressed(halfword, policy);
+            prop_assert!(result.is_ok(), "{:04x} gave {:?}", halfword, result);
+            prop_assert_eq!(before, registers, "{:04x} changed registers", halfword);
+            prop_assert_eq!(MEMORY_BASE + 2, pc);
+            let reported = match policy {
+                HintPolicy::Report => vec![(MEMORY_BASE, halfword)],
+                _ => vec![],
+            };
+            prop_assert_eq!(reported, memory.hints());
+        }
+    }
+
+    #[test]
+    fn strict_hints_are_illegal(halfword in hint_compressed()) {
+        match run_compressed(halfword, HintPolicy::Trap).0 {
+            Err(Trap { trap_type: TrapType::IllegalInstruction, value }) => {
+                prop_assert_eq!(halfword as u32, value);
+            }
+            result => prop_assert!(false, "{:04x} gave {:?}", halfword, result),
+        }
+    }
+}
+
+#[test]
+fn strict_mode_runs_the_instructions_hints_resemble() {
+    // c.nop, c.addi a0, 1, c.li a0, 5, c.mv a0, a1, and c.add a0, a1
+    for halfword in [0x0001, 0x0505, 0x4515, 0x852e, 0x952e] {
+        let (strict, registers, ..) = run_compressed(halfword, HintPolicy::Trap);
+        let (_, expected, _, memory) = run_compressed(halfword, HintPolicy::Report);
+        assert_eq!(Ok(()), strict, "{:04x}", halfword);
+        assert_eq!(expected, registers);
+        assert!(memory.hints().is_empty());
+    }
+}
+
+proptest! {
+    #[test]
+    fn uncompress_reserved_is_illegal(halfword in reserved_compressed()) {
+        prop_assert!(matches!(rvc_reference(halfword), Rvc::Reserved));
+
+        let (mut cpu, _memory) = create_cpu(4);
+        cpu.update_pc(MEMORY_BASE);
+        cpu.get_mut_mmu().store_word(MEMORY_BASE, halfword as u32).unwrap();
+        match cpu.tick_operate() {
+            Err(Trap { trap_type: TrapType::IllegalInstruction, value }) => {
+                prop_assert_eq!(halfword as u32, value);
+            }
+            result => prop_assert!(false, "{:04x} gave {:?}", halfword, result),
+        }
+    }
+}
+
+/// The reference encoding that `word` decodes to, if any.
+fn opcode_reference(word: u32) -> Option<&'static str> {
+    let mut matches = opcodes::RV32
+        .iter()
+        .filter(|(_, mask, data)| word & mask == *data);
+    let name = matches.next().map(|(name, _, _)| *name);
+    assert!(matches.next().is_none(), "{:08x} has two encodings", word);
+    name
+}
+
+#[test]
+fn decoder_table_matches_opcodes() {
+    let instructions = instructions::get_instructions();
+    for (name, mask, data) in opcodes::RV32 {
+        let found: Vec<_> = instructions
+            .iter()
+            .filter(|instruction| instruction.name.eq_ignore_ascii_case(name))
+            .collect();
+        assert_eq!(
+            1,
+            found.len(),
+            "{} is in the table {} times",
+            name,
+            found.len()
+        );
+        assert_eq!(
+            (mask, data),
+            (found[0].mask, found[0].data),
+            "{} has the wrong encoding",
+            name
+        );
+    }
+    for instruction in &instructions {
+        assert!(
+            opcodes::RV32
+                .iter()
+                .any(|(name, _, _)| instruction.name.eq_ignore_ascii_case(name)),
+            "{} isn't an RV32 instruction",
+            instruction.name
+        );
+    }
+}
+
+#[test]
+fn dispatch_reaches_every_instruction() {
+    let cpu = create_cpu(0).0;
+    for instruction in &cpu.instructions {
+        assert_eq!(
+            Some(instruction.name),
+            cpu.instruction_name(instruction.data),
+            "{} isn't in its dispatch entry",
+            instruction.name
+        );
+    }
+}
+
+#[test]
+fn decoder_table_does_not_overlap() {
+    let cpu = create_cpu(0).0;
+    assert_eq!(Vec::<(&str, &str)>::new(), cpu.decoder_conflicts());
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(65536))]
+
+    #[test]
+    fn decode_matches_opcodes(word in any::<u32>().prop_map(|word| word | 3)) {
+        let cpu = create_cpu(0).0;
+        let decoded = cpu.decode_raw(word).ok().map(|instruction| instruction.name);
+        match opcode_reference(word) {
+            Some(name) => prop_assert!(
+                decoded.is_some_and(|decoded| decoded.eq_ignore_ascii_case(name)),
+                "{:08x} decoded as {:?} rather than {}", word, decoded, name
+            ),
+            None => prop_assert!(decoded.is_none(), "{:08x} decoded as {:?}", word, decoded),
+        }
+    }
+}
+
+#[test]
+fn raised_interrupt_ends_wfi() {
+    let mut cpu = create_cpu(4).0;
+    cpu.execute_opcode(0x10500073).unwrap();
+    assert!(cpu.waiting_for_interrupt());
+    // Nothing is enabled, so the interrupt wakes the CPU without being taken
+    cpu.raise_interrupt(MIP_SEIP);
+    assert!(!cpu.waiting_for_interrupt());
+    assert_eq!(MIP_SEIP, cpu.peek_csr(CSR_MIP_ADDRESS));
+    cpu.lower_interrupt(MIP_SEIP);
+    assert_eq!(0, cpu.peek_csr(CSR_MIP_ADDRESS));
+}
+
+#[test]
+fn switching_harts_drops_the_reservation() {
+    let mut cpu = create_cpu(4).0;
+    cpu.write_register(10, MEMORY_BASE as i32);
+    // lr.w a1, (a0)
+    cpu.execute_opcode(0x100525af).unwrap();
+    cpu.switch_hart(3);
+    assert_eq!(3, cpu.peek_csr(CSR_MHARTID_ADDRESS));
+    cpu.execute_opcode(0x100525af).unwrap();
+    // Another thread taking over hart 3 ends the sequence
+    cpu.switch_hart(3);
+    // sc.w a2, a1, (a0)
+    cpu.execute_opcode(0x18b5262f).unwrap();
+    assert_eq!(1, cpu.read_register(12));
+}
+
 #[test]
 fn wfi() {
     let wfi_instruction = 0x10500073;
@@ -246,7 +804,865 @@ fn interrupt() {
     // @TODO: Test xIE bit in CSR status register
     // @TODO: Test privilege levels
     // @TODO: Test delegation
-    // @TODO: Test vector type handlers
+}
+
+#[test]
+fn vectored_mode_offsets_only_interrupts() {
+    let base = 0x10000000;
+    let mut cpu = create_cpu(4).0;
+    cpu.update_pc(MEMORY_BASE);
+    cpu.write_csr_raw(CSR_MTVEC_ADDRESS, base | 1);
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_MIE);
+    cpu.write_csr_raw(CSR_MIE_ADDRESS, MIP_MTIP);
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_MTIP);
+
+    // Interrupts go to BASE + 4 * cause
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert_eq!(base + 4 * 7, cpu.read_pc());
+    assert_eq!(0x80000007, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+
+    // Exceptions go to BASE, whatever their cause
+    let trap = Trap {
+        trap_type: TrapType::IllegalInstruction,
+        value: 0,
+    };
+    assert!(cpu.handle_trap(trap, MEMORY_BASE, false));
+    assert_eq!(base, cpu.read_pc());
+    assert_eq!(2, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+
+    // In direct mode, interrupts go to BASE too
+    cpu.write_csr_raw(CSR_MTVEC_ADDRESS, base);
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_MIE);
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert_eq!(base, cpu.read_pc());
+    assert_eq!(0x80000007, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+}
+
+#[test]
+fn vectored_mode_for_delegated_traps() {
+    let base = 0x20000000;
+    let mut cpu = create_cpu(4).0;
+    cpu.privilege_mode = PrivilegeMode::User;
+    cpu.update_pc(MEMORY_BASE);
+    cpu.write_csr_raw(CSR_MTVEC_ADDRESS, 0x10000001);
+    cpu.write_csr_raw(CSR_STVEC_ADDRESS, base | 1);
+    cpu.write_csr_raw(CSR_MIDELEG_ADDRESS, MIP_SEIP);
+    cpu.write_csr_raw(CSR_MEDELEG_ADDRESS, 1 << 8);
+    cpu.write_csr_raw(CSR_MIE_ADDRESS, MIP_SEIP);
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_SEIP);
+
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert_eq!(PrivilegeMode::Supervisor, cpu.privilege_mode);
+    assert_eq!(base + 4 * 9, cpu.read_pc());
+    assert_eq!(0x80000009, cpu.read_csr_raw(CSR_SCAUSE_ADDRESS));
+
+    // An ecall from U-mode is delegated too, and goes to BASE
+    cpu.privilege_mode = PrivilegeMode::User;
+    let trap = Trap {
+        trap_type: TrapType::EnvironmentCallFromUMode,
+        value: 0,
+    };
+    assert!(cpu.handle_trap(trap, MEMORY_BASE, false));
+    assert_eq!(PrivilegeMode::Supervisor, cpu.privilege_mode);
+    assert_eq!(base, cpu.read_pc());
+    assert_eq!(8, cpu.read_csr_raw(CSR_SCAUSE_ADDRESS));
+}
+
+#[test]
+fn reserved_trap_vector_modes_are_ignored() {
+    let mut cpu = create_cpu(4).0;
+    for address in [CSR_MTVEC_ADDRESS, CSR_STVEC_ADDRESS] {
+        // The base is written, but the mode stays as it was
+        cpu.write_csr_raw(address, 0x10000001);
+        cpu.write_csr_raw(address, 0x20000002);
+        assert_eq!(0x20000001, cpu.read_csr_raw(address));
+        cpu.write_csr_raw(address, 0x30000000);
+        cpu.write_csr_raw(address, 0x40000003);
+        assert_eq!(0x40000000, cpu.read_csr_raw(address));
+    }
+}
+
+#[test]
+fn interrupt_priority() {
+    let handler_vector = 0x10000000;
+    let mut cpu = create_cpu(4).0;
+    cpu.update_pc(MEMORY_BASE);
+    cpu.write_csr_raw(CSR_MTVEC_ADDRESS, handler_vector);
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_MIE);
+    cpu.write_csr_raw(CSR_MIE_ADDRESS, MIP_MTIP | MIP_MSIP | MIP_MEIP);
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_MTIP | MIP_MSIP | MIP_MEIP);
+
+    // External interrupts have the highest priority
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert_eq!(handler_vector, cpu.read_pc());
+    assert_eq!(0x8000000b, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+
+    // Taking the trap doesn't acknowledge the interrupt
+    assert_eq!(
+        MIP_MTIP | MIP_MSIP | MIP_MEIP,
+        cpu.read_csr_raw(CSR_MIP_ADDRESS)
+    );
+}
+
+#[test]
+fn nested_interrupt() {
+    let handler_vector = 0x10000000;
+    let mut cpu = create_cpu(4).0;
+    cpu.update_pc(MEMORY_BASE);
+    cpu.write_csr_raw(CSR_MTVEC_ADDRESS, handler_vector);
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_MIE);
+    cpu.write_csr_raw(CSR_MIE_ADDRESS, MIP_MTIP | MIP_MSIP);
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_MTIP);
+
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert_eq!(handler_vector, cpu.read_pc());
+    assert_eq!(MEMORY_BASE, cpu.read_csr_raw(CSR_MEPC_ADDRESS));
+    assert_eq!(0x80000007, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+    // MIE was saved in MPIE and cleared
+    assert_eq!(
+        MSTATUS_MPIE | MSTATUS_MPP,
+        cpu.read_csr_raw(CSR_MSTATUS_ADDRESS)
+    );
+
+    // A software interrupt arriving inside the handler is held off...
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_MTIP | MIP_MSIP);
+    cpu.handle_interrupt(handler_vector);
+    assert_eq!(handler_vector, cpu.read_pc());
+    assert_eq!(0x80000007, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+
+    // ...until the handler re-enables interrupts, at which point it nests
+    let mstatus = cpu.read_csr_raw(CSR_MSTATUS_ADDRESS);
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, mstatus | MSTATUS_MIE);
+    cpu.update_pc(handler_vector + 4);
+    cpu.handle_interrupt(handler_vector + 4);
+    assert_eq!(handler_vector, cpu.read_pc());
+    assert_eq!(handler_vector + 4, cpu.read_csr_raw(CSR_MEPC_ADDRESS));
+    assert_eq!(0x80000003, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+}
+
+#[test]
+fn machine_interrupts_before_delegated_interrupts() {
+    let mut cpu = create_cpu(4).0;
+    cpu.privilege_mode = PrivilegeMode::Supervisor;
+    cpu.update_pc(MEMORY_BASE);
+    cpu.write_csr_raw(CSR_MTVEC_ADDRESS, 0x10000000);
+    cpu.write_csr_raw(CSR_STVEC_ADDRESS, 0x20000000);
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_SIE);
+
+    // SEIP is delegated to S-mode and has a higher priority than SSIP,
+    // but SSIP is taken in M-mode so it goes first.
+    cpu.write_csr_raw(CSR_MIDELEG_ADDRESS, MIP_SEIP);
+    cpu.write_csr_raw(CSR_MIE_ADDRESS, MIP_SEIP | MIP_SSIP);
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_SEIP | MIP_SSIP);
+
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert_eq!(PrivilegeMode::Machine, cpu.privilege_mode);
+    assert_eq!(0x10000000, cpu.read_pc());
+    assert_eq!(0x80000001, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+
+    // Once the M-mode interrupt is gone, the delegated one is taken in S-mode
+    cpu.privilege_mode = PrivilegeMode::Supervisor;
+    cpu.update_pc(MEMORY_BASE);
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_SEIP);
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_SIE);
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert_eq!(PrivilegeMode::Supervisor, cpu.privilege_mode);
+    assert_eq!(0x20000000, cpu.read_pc());
+    assert_eq!(0x80000009, cpu.read_csr_raw(CSR_SCAUSE_ADDRESS));
+}
+
+#[test]
+fn wfi_wakes_on_disabled_interrupt() {
+    let mut cpu = create_cpu(4).0;
+    cpu.update_pc(MEMORY_BASE);
+    cpu.wfi = true;
+    cpu.write_csr_raw(CSR_MIE_ADDRESS, MIP_MTIP);
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_MTIP);
+
+    // MIE is clear, so the interrupt isn't taken but the hart still wakes up
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert!(!cpu.wfi);
+    assert_eq!(MEMORY_BASE, cpu.read_pc());
+}
+
+#[test]
+fn mstatus_legalization() {
+    let mut cpu = create_cpu(4).0;
+
+    // WPRI bits and the RV64-only SXL/UXL positions read back as zero
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x7f80_0000 | MSTATUS_MIE);
+    assert_eq!(MSTATUS_MIE, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS));
+
+    // SD is read-only and is only set when FS is dirty
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_SD);
+    assert_eq!(0, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS));
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_FS);
+    assert_eq!(
+        MSTATUS_FS | MSTATUS_SD,
+        cpu.read_csr_raw(CSR_MSTATUS_ADDRESS)
+    );
+
+    // UBE, VS, and XS are hardwired to zero
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_UBE | MSTATUS_VS | MSTATUS_XS);
+    assert_eq!(0, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS));
+
+    // The reserved privilege level in MPP is legalized, valid levels are kept
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 2 << 11);
+    assert_eq!(0, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS));
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_MPP);
+    assert_eq!(MSTATUS_MPP, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS));
+}
+
+#[test]
+fn sstatus_is_a_view_of_mstatus() {
+    let mut cpu = create_cpu(4).0;
+
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_MIE | MSTATUS_MPP | MSTATUS_SIE);
+    assert_eq!(MSTATUS_SIE, cpu.read_csr_raw(CSR_SSTATUS_ADDRESS));
+
+    // Machine-only fields can't be changed through sstatus
+    cpu.write_csr_raw(CSR_SSTATUS_ADDRESS, !0);
+    assert_eq!(
+        MSTATUS_MIE
+            | MSTATUS_MPP
+            | MSTATUS_SIE
+            | MSTATUS_SPIE
+            | MSTATUS_SPP
+            | MSTATUS_FS
+            | MSTATUS_SUM
+            | MSTATUS_MXR
+            | MSTATUS_SD,
+        cpu.read_csr_raw(CSR_MSTATUS_ADDRESS)
+    );
+    assert_eq!(
+        MSTATUS_SIE
+            | MSTATUS_SPIE
+            | MSTATUS_SPP
+            | MSTATUS_FS
+            | MSTATUS_SUM
+            | MSTATUS_MXR
+            | MSTATUS_SD,
+        cpu.read_csr_raw(CSR_SSTATUS_ADDRESS)
+    );
+}
+
+#[test]
+fn invalid_physical_address() {
+    let mut cpu = create_cpu(16).0;
+    let outside = MEMORY_BASE + 0x1000;
+    // lw a0, 0(t0)
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE, 0x0002_a503)
+        .unwrap();
+    cpu.write_register(5, outside as i32);
+
+    cpu.get_mut_mmu().check_physical_addresses(true);
+    cpu.update_pc(MEMORY_BASE);
+    match cpu.tick_operate() {
+        Err(Trap {
+            trap_type: TrapType::LoadAccessFault,
+            value,
+        }) => assert_eq!(outside, value),
+        result => panic!("expected a load access fault, got {:?}", result),
+    }
+}
+
+// The trap values below follow the privileged spec's description of `mtval`
+
+#[test]
+fn fetch_fault_in_second_half_of_instruction() {
+    let mut cpu = create_cpu(0x1000).0;
+    // The first half of addi a0, a0, 1, with the second half past the end of RAM
+    cpu.get_mut_mmu()
+        .store_halfword(MEMORY_BASE + 0xffe, 0x0513)
+        .unwrap();
+    cpu.get_mut_mmu().check_physical_addresses(true);
+    cpu.update_pc(MEMORY_BASE + 0xffe);
+    match cpu.tick_operate() {
+        Err(Trap {
+            trap_type: TrapType::InstructionAccessFault,
+            value,
+        }) => assert_eq!(MEMORY_BASE + 0x1000, value),
+        result => panic!("expected an instruction access fault, got {:?}", result),
+    }
+    // The trap is taken from the start of the instruction
+    assert_eq!(MEMORY_BASE + 0xffe, cpu.read_pc());
+}
+
+#[test]
+fn compressed_instruction_at_end_of_memory() {
+    let mut cpu = create_cpu(0x1000).0;
+    // c.li a0, 5
+    cpu.get_mut_mmu()
+        .store_halfword(MEMORY_BASE + 0xffe, 0x4515)
+        .unwrap();
+    cpu.get_mut_mmu().check_physical_addresses(true);
+    cpu.update_pc(MEMORY_BASE + 0xffe);
+    cpu.tick_operate().unwrap();
+    assert_eq!(5, cpu.read_register(10));
+}
+
+#[test]
+fn access_fault_in_second_page_of_load_and_store() {
+    let mut cpu = create_cpu(0x1000).0;
+    cpu.write_register(5, (MEMORY_BASE + 0xffe) as i32);
+    cpu.get_mut_mmu().check_physical_addresses(true);
+    // lw a0, 0(t0)
+    match cpu.execute_opcode(0x0002_a503) {
+        Err(Trap {
+            trap_type: TrapType::LoadAccessFault,
+            value,
+        }) => assert_eq!(MEMORY_BASE + 0x1000, value),
+        result => panic!("expected a load access fault, got {:?}", result),
+    }
+    // sw a0, 0(t0)
+    match cpu.execute_opcode(0x00a2_a023) {
+        Err(Trap {
+            trap_type: TrapType::StoreAccessFault,
+            value,
+        }) => assert_eq!(MEMORY_BASE + 0x1000, value),
+        result => panic!("expected a store access fault, got {:?}", result),
+    }
+}
+
+#[test]
+fn illegal_instruction_holds_the_instruction() {
+    let mut cpu = create_cpu(16).0;
+    // csrr a0, mstatus, which user mode can't read
+    let csrr = 0x3000_2573;
+    cpu.get_mut_mmu().store_word(MEMORY_BASE, csrr).unwrap();
+    cpu.update_pc(MEMORY_BASE);
+    cpu.privilege_mode = PrivilegeMode::User;
+    match cpu.tick_operate() {
+        Err(Trap {
+            trap_type: TrapType::IllegalInstruction,
+            value,
+        }) => assert_eq!(csrr, value),
+        result => panic!("expected an illegal instruction, got {:?}", result),
+    }
+}
+
+/// Store `program` at `MEMORY_BASE` and `data` 0x100 bytes after it, then
+/// run the program with `a0` through `a4` set to `registers`.
+fn run_vector_program(cpu: &mut Cpu, program: &[u32], data: &[u32], registers: [i32; 5]) {
+    for (index, word) in program.iter().chain(data).enumerate() {
+        let offset = match index < program.len() {
+            true => index * 4,
+            false => 0x100 + (index - program.len()) * 4,
+        };
+        cpu.get_mut_mmu()
+            .store_word(MEMORY_BASE + offset as u32, *word)
+            .unwrap();
+    }
+    for (index, value) in registers.iter().enumerate() {
+        cpu.write_register(10 + index as u8, *value);
+    }
+    cpu.update_pc(MEMORY_BASE);
+    for _ in program {
+        cpu.tick_operate().unwrap();
+    }
+}
+
+fn load_words(cpu: &Cpu, address: u32, count: u32) -> Vec<u32> {
+    (0..count)
+        .map(|index| cpu.phys_read_u32(address + index * 4))
+        .collect()
+}
+
+#[test]
+fn vector_arithmetic() {
+    let mut cpu = create_cpu(1024).0;
+    cpu.enable_vector(128);
+    let program = [
+        0x0d05_72d7, // vsetvli t0, a0, e32, m1, ta, ma
+        0x0205_e087, // vle32.v v1, (a1)
+        0x0206_6107, // vle32.v v2, (a2)
+        0x0211_01d7, // vadd.vv v3, v1, v2
+        0x9637_61d7, // vmul.vx v3, v3, a4
+        0x0206_e1a7, // vse32.v v3, (a3)
+    ];
+    let data = [1, 2, 3, 4, 10, 20, 30, 40];
+    let (a, b, out) = (
+        MEMORY_BASE + 0x100,
+        MEMORY_BASE + 0x110,
+        MEMORY_BASE + 0x120,
+    );
+    // Ask for 10 elements, of which only 4 fit in a register
+    run_vector_program(
+        &mut cpu,
+        &program,
+        &data,
+        [10, a as i32, b as i32, out as i32, 3],
+    );
+    assert_eq!(4, cpu.read_register(5));
+    assert_eq!(4, cpu.read_csr_raw(0xc20));
+    assert_eq!(vec![33, 66, 99, 132, 0], load_words(&cpu, out, 5));
+}
+
+#[test]
+fn vector_masks() {
+    let mut cpu = create_cpu(1024).0;
+    cpu.enable_vector(128);
+    let program = [
+        0x0d05_72d7, // vsetvli t0, a0, e32, m1, ta, ma
+        0x0205_e087, // vle32.v v1, (a1)
+        0x6211_3057, // vmseq.vi v0, v1, 2
+        0x5c1f_b2d7, // vmerge.vim v5, v1, -1, v0
+        0x5e03_b357, // vmv.v.i v6, 7
+        0x0815_4357, // vsub.vx v6, v1, a0, v0.t
+        0x4250_27d7, // vmv.x.s a5, v5
+        0x0206_e2a7, // vse32.v v5, (a3)
+        0x0206_6327, // vse32.v v6, (a2)
+    ];
+    let (a, b, out) = (
+        MEMORY_BASE + 0x100,
+        MEMORY_BASE + 0x110,
+        MEMORY_BASE + 0x120,
+    );
+    run_vector_program(
+        &mut cpu,
+        &program,
+        &[1, 2, 3, 4],
+        [4, a as i32, b as i32, out as i32, 0],
+    );
+    assert_eq!(1, cpu.read_register(15));
+    assert_eq!(vec![1, 0xffff_ffff, 3, 4], load_words(&cpu, out, 4));
+    // Only the element where v0 is set was subtracted from
+    assert_eq!(vec![7, -2i32 as u32, 7, 7], load_words(&cpu, b, 4));
+}
+
+#[test]
+fn vector_instructions_trap_unless_supported() {
+    let vsetvli = 0x0d05_72d7; // vsetvli t0, a0, e32, m1, ta, ma
+    let vadd = 0x0211_01d7; // vadd.vv v3, v1, v2
+    let vdiv = 0x8621_a0d7; // vdiv.vv v1, v2, v3
+    let run = |cpu: &mut Cpu, word: u32| {
+        cpu.get_mut_mmu().store_word(MEMORY_BASE, word).unwrap();
+        cpu.update_pc(MEMORY_BASE);
+        match cpu.tick_operate() {
+            Ok(()) => Ok(()),
+            Err(Trap {
+                trap_type: TrapType::IllegalInstruction,
+                value,
+            }) => Err(value),
+            Err(trap) => panic!("unexpected trap {:?}", trap),
+        }
+    };
+
+    // There's no vector unit unless one is asked for
+    let mut cpu = create_cpu(16).0;
+    assert_eq!(Err(vsetvli), run(&mut cpu, vsetvli));
+
+    // vtype starts out unsupported, so only vsetvli can run
+    cpu.enable_vector(64);
+    assert_eq!(Err(vadd), run(&mut cpu, vadd));
+    cpu.write_register(10, 16);
+    assert_eq!(Ok(()), run(&mut cpu, vsetvli));
+    assert_eq!(2, cpu.read_register(5));
+    assert_eq!(Ok(()), run(&mut cpu, vadd));
+
+    // Division isn't implemented
+    assert_eq!(Err(vdiv), run(&mut cpu, vdiv));
+    assert_eq!(Some("VADD.VV"), cpu.instruction_name(vadd));
+}
+
+#[test]
+fn vector_length_csrs_are_read_only() {
+    let mut cpu = create_cpu(16).0;
+    cpu.enable_vector(64);
+    cpu.privilege_mode = PrivilegeMode::User;
+    // vsetvli t0, a0, e32, m1, ta, ma
+    run_vector_program(&mut cpu, &[0x0d05_72d7], &[], [16, 0x7fff_ffff, 0, 0, 0]);
+    for csrw in [
+        0xc205_9073, // csrw vl, a1
+        0xc215_9073, // csrw vtype, a1
+        0xc225_9073, // csrw vlenb, a1
+    ] {
+        cpu.get_mut_mmu().store_word(MEMORY_BASE, csrw).unwrap();
+        cpu.update_pc(MEMORY_BASE);
+        match cpu.tick_operate() {
+            Err(Trap {
+                trap_type: TrapType::IllegalInstruction,
+                value,
+            }) => assert_eq!(csrw, value),
+            result => panic!("expected an illegal instruction, got {:?}", result),
+        }
+    }
+    assert_eq!(2, cpu.read_csr_raw(0xc20));
+
+    // Even if vl were larger than fits, only the elements that fit are added
+    cpu.write_csr_raw(0xc20, 0x7fff_ffff);
+    // vadd.vv v3, v1, v2
+    run_vector_program(&mut cpu, &[0x0211_01d7], &[], [0; 5]);
+}
+
+#[test]
+fn unimplemented_csrs_trap_unless_permissive() {
+    let csrrw = 0x7c05_9573; // csrrw a0, 0x7c0, a1
+    let rdvlenb = 0xc220_2573; // csrr a0, vlenb
+    let run = |cpu: &mut Cpu, word: u32| {
+        cpu.get_mut_mmu().store_word(MEMORY_BASE, word).unwrap();
+        cpu.update_pc(MEMORY_BASE);
+        match cpu.tick_operate() {
+            Ok(()) => Ok(cpu.read_register(10) as u32),
+            Err(Trap {
+                trap_type: TrapType::IllegalInstruction,
+                value,
+            }) => Err(value),
+            Err(trap) => panic!("unexpected trap {:?}", trap),
+        }
+    };
+
+    // Nothing implements 0x7c0, and vlenb needs a vector unit
+    let mut cpu = create_cpu(16).0;
+    cpu.write_register(11, 5);
+    assert_eq!(Err(csrrw), run(&mut cpu, csrrw));
+    assert_eq!(Err(rdvlenb), run(&mut cpu, rdvlenb));
+    cpu.enable_vector(64);
+    assert_eq!(Ok(8), run(&mut cpu, rdvlenb));
+    assert!(cpu.write_csr(0x7c0, 1).is_err());
+
+    // Permissive CPUs treat it as storage, as they all used to
+    cpu.permissive_csrs(true);
+    assert_eq!(Ok(0), run(&mut cpu, csrrw));
+    assert_eq!(Ok(5), run(&mut cpu, csrrw));
+}
+
+#[test]
+fn counter_enables() {
+    let (mut cpu, memory) = create_cpu(16);
+    // csrr a0, instret
+    let rdinstret = 0xc020_2573;
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE, rdinstret)
+        .unwrap();
+    let read = |cpu: &mut Cpu, mode| {
+        cpu.update_pc(MEMORY_BASE);
+        cpu.privilege_mode = mode;
+        let instret = cpu.instructions_retired() as u32;
+        match cpu.tick_operate() {
+            Ok(()) => Ok((cpu.read_register(10) as u32, instret)),
+            Err(Trap {
+                trap_type: TrapType::IllegalInstruction,
+                value,
+            }) => Err(value),
+            Err(trap) => panic!("unexpected trap {:?}", trap),
+        }
+    };
+
+    let (value, instret) = read(&mut cpu, PrivilegeMode::Machine).unwrap();
+    assert_eq!(instret, value);
+    assert_eq!(Err(rdinstret), read(&mut cpu, PrivilegeMode::Supervisor));
+    assert_eq!(Err(rdinstret), read(&mut cpu, PrivilegeMode::User));
+
+    cpu.write_csr_raw(CSR_MCOUNTEREN_ADDRESS, 1 << 2);
+    assert!(read(&mut cpu, PrivilegeMode::Supervisor).is_ok());
+    assert_eq!(Err(rdinstret), read(&mut cpu, PrivilegeMode::User));
+
+    cpu.write_csr_raw(CSR_SCOUNTEREN_ADDRESS, 1 << 2);
+    assert!(read(&mut cpu, PrivilegeMode::User).is_ok());
+
+    // The bit for `cycle` doesn't allow `instret`
+    cpu.write_csr_raw(CSR_MCOUNTEREN_ADDRESS, 1 << 0);
+    cpu.write_csr_raw(CSR_SCOUNTEREN_ADDRESS, 1 << 0);
+    assert_eq!(Err(rdinstret), read(&mut cpu, PrivilegeMode::User));
+
+    memory.set_emulated_counter(42);
+    assert_eq!(42, read(&mut cpu, PrivilegeMode::User).unwrap().0);
+}
+
+#[test]
+fn environment_call_and_interrupt_values_are_zero() {
+    let mut cpu = create_cpu(16).0;
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE, 0x0000_0073)
+        .unwrap();
+    cpu.update_pc(MEMORY_BASE);
+    match cpu.tick_operate() {
+        Err(Trap {
+            trap_type: TrapType::EnvironmentCallFromMMode,
+            value,
+        }) => assert_eq!(0, value),
+        result => panic!("expected an environment call, got {:?}", result),
+    }
+
+    cpu.write_csr_raw(CSR_MTVAL_ADDRESS, 0x1234);
+    cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, MSTATUS_MIE);
+    cpu.write_csr_raw(CSR_MIE_ADDRESS, MIP_MSIP);
+    cpu.write_csr_raw(CSR_MIP_ADDRESS, MIP_MSIP);
+    cpu.handle_interrupt(MEMORY_BASE);
+    assert_eq!(0, cpu.read_csr_raw(CSR_MTVAL_ADDRESS));
+    assert_eq!(0x8000_0003, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+}
+
+#[test]
+fn hypercall_csr() {
+    let (mut cpu, memory) = create_cpu(16);
+    cpu.update_pc(MEMORY_BASE);
+    cpu.write_register(5, 41);
+    // csrw 0x8c0, t0
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE, 0x8c02_9073)
+        .unwrap();
+    // csrr a0, 0x8c0
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE + 4, 0x8c00_2573)
+        .unwrap();
+
+    cpu.tick();
+    assert_eq!(vec![(41, MEMORY_BASE)], memory.hypercalls());
+
+    // Reading the CSR returns the result without making another call
+    cpu.tick();
+    assert_eq!(42, cpu.read_register(10));
+    assert_eq!(1, memory.hypercalls().len());
+}
+
+#[test]
+fn time_csr() {
+    let (mut cpu, memory) = create_cpu(16);
+    cpu.update_pc(MEMORY_BASE);
+    // csrr a0, time
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE, 0xc010_2573)
+        .unwrap();
+    // csrr a1, timeh
+    cpu.get_mut_mmu()
+        .store_word(MEMORY_BASE + 4, 0xc810_25f3)
+        .unwrap();
+
+    memory.set_time(0x1_2345_6789);
+    cpu.tick();
+    cpu.tick();
+    assert_eq!(0x2345_6789, cpu.read_register(10));
+    assert_eq!(1, cpu.read_register(11));
+}
+
+// lr.w a0, (a1)
+const LR_W: u32 = 0x1005_a52f;
+// sc.w a0, a2, (a1)
+const SC_W: u32 = 0x18c5_a52f;
+
+/// A CPU with `value` at `address`, which is in `a1`, and 9 in `a2`.
+fn create_lr_sc_cpu(address: u32, value: u32) -> (Cpu, Box<memory::Memory>) {
+    let (mut cpu, memory) = create_cpu(64);
+    cpu.get_mut_mmu().store_word(address & !3, value).unwrap();
+    cpu.write_register(11, address as i32);
+    cpu.write_register(12, 9);
+    (cpu, memory)
+}
+
+#[test]
+fn lr_sc_stores_and_reports_success() {
+    let (mut cpu, _) = create_lr_sc_cpu(MEMORY_BASE + 8, 5);
+    cpu.execute_opcode(LR_W).unwrap();
+    assert_eq!(5, cpu.read_register(10));
+    cpu.execute_opcode(SC_W).unwrap();
+    assert_eq!(0, cpu.read_register(10));
+    assert_eq!(9, cpu.get_mut_mmu().load_word(MEMORY_BASE + 8).unwrap());
+}
+
+#[test]
+fn sc_fails_outside_reservation_and_ends_it() {
+    let (mut cpu, _) = create_lr_sc_cpu(MEMORY_BASE + 8, 5);
+    cpu.execute_opcode(LR_W).unwrap();
+
+    // The reservation covers only the word that was loaded
+    cpu.write_register(11, (MEMORY_BASE + 12) as i32);
+    cpu.execute_opcode(SC_W).unwrap();
+    assert_eq!(1, cpu.read_register(10));
+    assert_eq!(0, cpu.get_mut_mmu().load_word(MEMORY_BASE + 12).unwrap());
+
+    // The failed SC dropped the reservation
+    cpu.write_register(11, (MEMORY_BASE + 8) as i32);
+    cpu.execute_opcode(SC_W).unwrap();
+    assert_eq!(1, cpu.read_register(10));
+    assert_eq!(5, cpu.get_mut_mmu().load_word(MEMORY_BASE + 8).unwrap());
+}
+
+#[test]
+fn sc_fails_without_lr() {
+    let (mut cpu, _) = create_lr_sc_cpu(MEMORY_BASE + 8, 5);
+    cpu.execute_opcode(SC_W).unwrap();
+    assert_eq!(1, cpu.read_register(10));
+    assert_eq!(5, cpu.get_mut_mmu().load_word(MEMORY_BASE + 8).unwrap());
+}
+
+#[test]
+fn sc_fails_after_another_core_reserves() {
+    let (mut cpu, memory) = create_lr_sc_cpu(MEMORY_BASE + 8, 5);
+    cpu.execute_opcode(LR_W).unwrap();
+    memory.clear_reservation(0, MEMORY_BASE + 8);
+    memory.reserve(1, MEMORY_BASE + 8);
+    cpu.execute_opcode(SC_W).unwrap();
+    assert_eq!(1, cpu.read_register(10));
+}
+
+#[test]
+fn trap_ends_reservation() {
+    let (mut cpu, _) = create_lr_sc_cpu(MEMORY_BASE + 8, 5);
+    cpu.execute_opcode(LR_W).unwrap();
+    let trap = Trap {
+        trap_type: TrapType::Breakpoint,
+        value: 0,
+    };
+    cpu.handle_trap(trap, MEMORY_BASE, false);
+    cpu.execute_opcode(SC_W).unwrap();
+    assert_eq!(1, cpu.read_register(10));
+}
+
+#[test]
+fn misaligned_lr_sc_trap() {
+    let (mut cpu, _) = create_lr_sc_cpu(MEMORY_BASE + 10, 5);
+    let trap = cpu.execute_opcode(LR_W).unwrap_err();
+    assert!(matches!(trap.trap_type, TrapType::LoadAddressMisaligned));
+    assert_eq!(MEMORY_BASE + 10, trap.value);
+    let trap = cpu.execute_opcode(SC_W).unwrap_err();
+    assert!(matches!(trap.trap_type, TrapType::StoreAddressMisaligned));
+}
+
+#[test]
+fn lr_sc_outside_memory_fault() {
+    let (mut cpu, _) = create_cpu(64);
+    cpu.write_register(11, 0x1000_0000);
+    let trap = cpu.execute_opcode(LR_W).unwrap_err();
+    assert!(matches!(trap.trap_type, TrapType::LoadAccessFault));
+    let trap = cpu.execute_opcode(SC_W).unwrap_err();
+    assert!(matches!(trap.trap_type, TrapType::StoreAccessFault));
+}
+
+// amoadd.w a0, a2, (a1)
+const AMOADD_W: u32 = 0x00c5_a52f;
+// amoswap.w a0, a2, (a1)
+const AMOSWAP_W: u32 = 0x08c5_a52f;
+
+#[test]
+fn amo_reads_and_writes() {
+    let (mut cpu, _) = create_lr_sc_cpu(MEMORY_BASE + 8, 5);
+    cpu.execute_opcode(AMOADD_W).unwrap();
+    assert_eq!(5, cpu.read_register(10));
+    assert_eq!(14, cpu.get_mut_mmu().load_word(MEMORY_BASE + 8).unwrap());
+}
+
+#[test]
+fn amo_min_and_max_compare_signed_or_unsigned() {
+    for (amo, stored) in [
+        (0x80c5_a52f, 0xffff_ffff), // amomin.w a0, a2, (a1)
+        (0xc0c5_a52f, 9),           // amominu.w a0, a2, (a1)
+        (0xa0c5_a52f, 9),           // amomax.w a0, a2, (a1)
+        (0xe0c5_a52f, 0xffff_ffff), // amomaxu.w a0, a2, (a1)
+    ] {
+        let (mut cpu, _) = create_lr_sc_cpu(MEMORY_BASE + 8, 0xffff_ffff);
+        cpu.execute_opcode(amo).unwrap();
+        assert_eq!(-1, cpu.read_register(10));
+        assert_eq!(
+            stored,
+            cpu.get_mut_mmu().load_word(MEMORY_BASE + 8).unwrap()
+        );
+    }
+}
+
+#[test]
+fn misaligned_amo_traps_without_storing() {
+    for amo in [AMOADD_W, AMOSWAP_W] {
+        let (mut cpu, _) = create_lr_sc_cpu(MEMORY_BASE + 10, 5);
+        cpu.write_register(10, 77);
+        let trap = cpu.execute_opcode(amo).unwrap_err();
+        assert!(matches!(trap.trap_type, TrapType::StoreAddressMisaligned));
+        assert_eq!(MEMORY_BASE + 10, trap.value);
+        assert_eq!(77, cpu.read_register(10));
+        assert_eq!(5, cpu.get_mut_mmu().load_word(MEMORY_BASE + 8).unwrap());
+    }
+}
+
+#[test]
+fn amo_outside_memory_faults() {
+    let (mut cpu, _) = create_cpu(64);
+    cpu.write_register(11, 0x1000_0000);
+    let trap = cpu.execute_opcode(AMOADD_W).unwrap_err();
+    assert!(matches!(trap.trap_type, TrapType::StoreAccessFault));
+    assert_eq!(0x1000_0000, trap.value);
+}
+
+#[test]
+fn address_space_ids() {
+    const ROOT_TABLE: u32 = MEMORY_BASE + 0x1000;
+    const LEAF_TABLE: u32 = MEMORY_BASE + 0x2000;
+    const PAGE_A: u32 = MEMORY_BASE + 0x3000;
+    const PAGE_B: u32 = MEMORY_BASE + 0x4000;
+    const DATA_VIRT: u32 = 0x1000_0000;
+    const PTE_V: u32 = 1 << 0;
+    const PTE_RWAD: u32 = (1 << 1) | (1 << 2) | (1 << 6) | (1 << 7);
+    const LEAF_PTE: u32 = LEAF_TABLE + ((DATA_VIRT >> 12) & 0x3ff) * 4;
+
+    let (mut cpu, memory) = create_cpu(0x5000);
+    memory.write_u32(
+        ROOT_TABLE + (DATA_VIRT >> 22) * 4,
+        (LEAF_TABLE >> 12) << 10 | PTE_V,
+    );
+    memory.write_u32(LEAF_PTE, (PAGE_A >> 12) << 10 | PTE_RWAD | PTE_V);
+    memory.write_u32(PAGE_A, 0xaaaa_aaaa);
+    memory.write_u32(PAGE_B, 0xbbbb_bbbb);
+
+    cpu.write_csr(CSR_SATP_ADDRESS, 0x8000_0000 | 1 << 22 | ROOT_TABLE >> 12)
+        .unwrap();
+    cpu.write_csr(CSR_MSTATUS_ADDRESS, 1 << 11).unwrap();
+    cpu.execute_opcode(0x30200073).unwrap(); // mret
+    assert_eq!(1, cpu.get_mut_mmu().asid());
+    assert_eq!(0xaaaa_aaaa, cpu.get_mut_mmu().load_word(DATA_VIRT).unwrap());
+
+    // Without a fence, ASID 1 keeps using its cached translation
+    memory.write_u32(LEAF_PTE, (PAGE_B >> 12) << 10 | PTE_RWAD | PTE_V);
+    assert_eq!(0xaaaa_aaaa, cpu.get_mut_mmu().load_word(DATA_VIRT).unwrap());
+
+    // ASID 2 walks the table, and switching back to ASID 1 doesn't flush it
+    cpu.get_mut_mmu().switch_address_space(2, ROOT_TABLE >> 12);
+    assert_eq!(0xbbbb_bbbb, cpu.get_mut_mmu().load_word(DATA_VIRT).unwrap());
+    cpu.get_mut_mmu().switch_address_space(1, ROOT_TABLE >> 12);
+    assert_eq!(0xaaaa_aaaa, cpu.get_mut_mmu().load_word(DATA_VIRT).unwrap());
+
+    // Neither fence rw,rw, whose succ field is where sfence.vma's rs2 would
+    // be, nor fence.i flushes it
+    cpu.write_register(19, 1);
+    cpu.execute_opcode(0x0330_000f).unwrap();
+    cpu.execute_opcode(0x0000_100f).unwrap();
+    assert_eq!(0xaaaa_aaaa, cpu.get_mut_mmu().load_word(DATA_VIRT).unwrap());
+
+    // sfence.vma x0, x5 flushes ASID 1 only
+    cpu.write_register(5, 1);
+    cpu.execute_opcode(0x1250_0073).unwrap();
+    assert_eq!(0xbbbb_bbbb, cpu.get_mut_mmu().load_word(DATA_VIRT).unwrap());
+}
+
+#[test]
+fn faulting_accesses_leave_pte_alone() {
+    const ROOT_TABLE: u32 = MEMORY_BASE + 0x1000;
+    const LEAF_TABLE: u32 = MEMORY_BASE + 0x2000;
+    const DATA_PAGE: u32 = MEMORY_BASE + 0x3000;
+    const DATA_VIRT: u32 = 0x1000_0000;
+    const PTE_V: u32 = 1 << 0;
+    const PTE_R: u32 = 1 << 1;
+    const LEAF_PTE: u32 = LEAF_TABLE + ((DATA_VIRT >> 12) & 0x3ff) * 4;
+
+    let (mut cpu, memory) = create_cpu(0x4000);
+    memory.write_u32(
+        ROOT_TABLE + (DATA_VIRT >> 22) * 4,
+        (LEAF_TABLE >> 12) << 10 | PTE_V,
+    );
+    memory.write_u32(LEAF_PTE, (DATA_PAGE >> 12) << 10 | PTE_R | PTE_V);
+
+    cpu.write_csr(CSR_SATP_ADDRESS, 0x8000_0000 | ROOT_TABLE >> 12)
+        .unwrap();
+    cpu.write_csr(CSR_MSTATUS_ADDRESS, 1 << 11).unwrap();
+    cpu.execute_opcode(0x30200073).unwrap(); // mret
+
+    // A store to a read-only page sets neither A nor D
+    let trap = cpu.get_mut_mmu().store_word(DATA_VIRT, 0).unwrap_err();
+    assert!(matches!(trap.trap_type, TrapType::StorePageFault));
+    assert_eq!(0, memory.read_u32(LEAF_PTE) & (1 << 6 | 1 << 7));
+
+    cpu.get_mut_mmu().load_word(DATA_VIRT).unwrap();
+    assert_eq!(1 << 6, memory.read_u32(LEAF_PTE) & (1 << 6 | 1 << 7));
 }
 
 #[test]
@@ -276,6 +1692,41 @@ fn syscall() {
     // @TODO: Test vector type handlers
 }
 
+#[test]
+fn syscall_backend() {
+    let (mut cpu, memory) = create_cpu(16);
+    for offset in [0, 4] {
+        cpu.get_mut_mmu()
+            .store_word(MEMORY_BASE + offset, 0x00000073)
+            .unwrap();
+    }
+    cpu.update_pc(MEMORY_BASE);
+    cpu.write_register(10, 7);
+    cpu.write_register(17, 9);
+
+    memory.set_next_syscall([1, 2, 3, 4, 5, 6, 7, 8].into());
+    assert!(matches!(cpu.tick(), TickResult::Ok));
+    assert_eq!(MEMORY_BASE + 4, cpu.read_pc());
+    assert_eq!((1, 8), (cpu.read_register(10), cpu.read_register(17)));
+    assert_eq!(1, cpu.instructions_retired());
+
+    // A suspended syscall leaves its arguments alone and doesn't retire
+    memory.set_next_syscall(crate::syscall::SyscallResult::Suspend(Box::new(42u32)));
+    match cpu.tick() {
+        TickResult::Suspended(suspension) => {
+            assert_eq!(42, *suspension.downcast::<u32>().unwrap());
+        }
+        _ => panic!("the ecall wasn't suspended"),
+    }
+    assert_eq!(MEMORY_BASE + 8, cpu.read_pc());
+    assert_eq!(1, cpu.read_register(10));
+    assert_eq!(1, cpu.instructions_retired());
+    assert_eq!(
+        vec![[7, 0, 0, 0, 0, 0, 0, 9], [1, 2, 3, 4, 5, 6, 7, 8]],
+        memory.syscalls()
+    );
+}
+
 #[test]
 fn hardocded_zero() {
     let mut cpu = create_cpu(8).0;
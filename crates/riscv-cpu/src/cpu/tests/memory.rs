@@ -1,4 +1,5 @@
-use crate::mmu::SystemBus;
+use crate::mmu::{MemoryAccessType, SystemBus};
+use crate::syscall::{SyscallBackend, SyscallResult};
 
 use super::Memory as CpuMemory;
 use std::{
@@ -28,6 +29,26 @@ pub struct Memory {
 
     /// Which addresses are reserved
     reservations: Arc<Mutex<HashMap<u32, u32>>>,
+
+    /// Values written to the hypercall CSR, and the PC that wrote them
+    hypercalls: Arc<Mutex<Vec<(u32, u32)>>>,
+
+    /// What the `time` CSR reads, if it's been set
+    time: Arc<Mutex<Option<u64>>>,
+
+    /// What counter reads that `mcounteren` and `scounteren` don't allow
+    /// return, if they're emulated rather than trapping
+    emulated_counter: Arc<Mutex<Option<u32>>>,
+
+    /// The arguments of every `ecall`, in order
+    syscalls: Arc<Mutex<Vec<[i32; 8]>>>,
+
+    /// What the next `ecall` returns. Once it's been used, syscalls are
+    /// passed to the CPU as exceptions.
+    next_syscall: Arc<Mutex<Option<SyscallResult>>>,
+
+    /// The PC and encoding of every compressed HINT reported
+    hints: Arc<Mutex<Vec<(u32, u16)>>>,
 }
 
 impl Memory {
@@ -39,6 +60,12 @@ impl Memory {
             vm_result: Arc::new(Mutex::new(None)),
             tohost: Arc::new(AtomicU32::new(tohost)),
             reservations: Arc::new(Mutex::new(HashMap::new())),
+            hypercalls: Arc::new(Mutex::new(vec![])),
+            time: Arc::new(Mutex::new(None)),
+            emulated_counter: Arc::new(Mutex::new(None)),
+            syscalls: Arc::new(Mutex::new(vec![])),
+            next_syscall: Arc::new(Mutex::new(None)),
+            hints: Arc::new(Mutex::new(vec![])),
         }
     }
 
@@ -52,6 +79,36 @@ impl Memory {
         *self.vm_result.lock().unwrap()
     }
 
+    #[allow(dead_code)]
+    pub fn hypercalls(&self) -> Vec<(u32, u32)> {
+        self.hypercalls.lock().unwrap().clone()
+    }
+
+    #[allow(dead_code)]
+    pub fn set_time(&self, time: u64) {
+        *self.time.lock().unwrap() = Some(time);
+    }
+
+    #[allow(dead_code)]
+    pub fn syscalls(&self) -> Vec<[i32; 8]> {
+        self.syscalls.lock().unwrap().clone()
+    }
+
+    #[allow(dead_code)]
+    pub fn set_next_syscall(&self, result: SyscallResult) {
+        *self.next_syscall.lock().unwrap() = Some(result);
+    }
+
+    #[allow(dead_code)]
+    pub fn hints(&self) -> Vec<(u32, u16)> {
+        self.hints.lock().unwrap().clone()
+    }
+
+    #[allow(dead_code)]
+    pub fn set_emulated_counter(&self, value: u32) {
+        *self.emulated_counter.lock().unwrap() = Some(value);
+    }
+
     pub fn set_tohost(&mut self, tohost: u32) {
         self.tohost.store(tohost, Ordering::Relaxed);
     }
@@ -90,7 +147,7 @@ impl CpuMemory for Memory {
     /// * `value`
     fn write_u8(&self, address: u32, value: u8) {
         let address = address as usize - MEMORY_BASE;
-        let index = (address >> 2) as usize;
+        let index = address >> 2;
         let pos = (address % 4) * 8;
         if address == self.tohost.load(Ordering::Relaxed) as usize {
             panic!("tohost write_u8: {:04x}", value);
@@ -106,7 +163,7 @@ impl CpuMemory for Memory {
     /// * `address`
     /// * `value`
     fn write_u16(&self, address: u32, value: u16) {
-        if (address % 2) == 0 {
+        if address.is_multiple_of(2) {
             let mut data = self.data.lock().unwrap();
             if address == self.tohost.load(Ordering::Relaxed) {
                 panic!("tohost write_u16: {:04x}", value);
@@ -126,7 +183,7 @@ impl CpuMemory for Memory {
     /// * `address`
     /// * `value`
     fn write_u32(&self, address: u32, value: u32) {
-        if (address % 4) == 0 {
+        if address.is_multiple_of(4) {
             let mut data = self.data.lock().unwrap();
             if address == self.tohost.load(Ordering::Relaxed) {
                 println!("tohost write_u32: {:08x}", value);
@@ -138,7 +195,7 @@ impl CpuMemory for Memory {
             let index = (address >> 2) as usize;
             data[index] = value;
         } else {
-            self.write_bytes(address, value as u32, 4);
+            self.write_bytes(address, value, 4);
         }
     }
 
@@ -159,7 +216,7 @@ impl CpuMemory for Memory {
     /// # Arguments
     /// * `address`
     fn read_u16(&self, address: u32) -> u16 {
-        if (address % 2) == 0 {
+        if address.is_multiple_of(2) {
             let data = self.data.lock().unwrap();
             let address = address - MEMORY_BASE as u32;
             let index = (address / 4) as usize;
@@ -175,13 +232,13 @@ impl CpuMemory for Memory {
     /// # Arguments
     /// * `address`
     fn read_u32(&self, address: u32) -> u32 {
-        if (address % 4) == 0 {
+        if address.is_multiple_of(4) {
             let data = self.data.lock().unwrap();
             let address = address - MEMORY_BASE as u32;
             let index = (address / 4) as usize;
             data[index]
         } else {
-            self.read_bytes(address, 4) as u32
+            self.read_bytes(address, 4)
         }
     }
 
@@ -190,16 +247,14 @@ impl CpuMemory for Memory {
     /// # Arguments
     /// * `address`
     fn validate_address(&self, address: u32) -> bool {
-        let address = address - MEMORY_BASE as u32;
-        (address as usize) < self.data.lock().unwrap().len()
-    }
-
-    fn syscall(&self, _args: [i32; 8]) -> crate::mmu::SyscallResult {
-        crate::mmu::SyscallResult::Continue
+        // Only the `memory_size` bytes asked for are valid, though there's
+        // storage for more
+        let address = address.wrapping_sub(MEMORY_BASE as u32);
+        (address as usize) < self.data.lock().unwrap().len() * 2
     }
 
-    fn translate(&self, v_address: u32) -> Option<u32> {
-        Some(v_address)
+    fn translate(&self, _v_address: u32, _access_type: &MemoryAccessType) -> Option<u32> {
+        None
     }
 
     fn reserve(&self, core: u32, p_address: u32) {
@@ -210,9 +265,41 @@ impl CpuMemory for Memory {
         self.reservations.lock().unwrap().remove(&core) == Some(p_address)
     }
 
+    fn reservable(&self, p_address: u32) -> bool {
+        p_address
+            .checked_sub(self.base as u32)
+            .is_some_and(|offset| (offset as usize) < self.data.lock().unwrap().len() * 4)
+    }
+
     fn clone(&self) -> Box<dyn CpuMemory + Send + Sync> {
         Box::new(Clone::clone(self))
     }
+
+    fn invalid_access(
+        &self,
+        _p_address: u32,
+        _pc: u32,
+        _access_type: &crate::mmu::MemoryAccessType,
+    ) -> bool {
+        true
+    }
+
+    fn hypercall(&self, value: u32, pc: u32, _registers: &[i32; 32]) -> u32 {
+        self.hypercalls.lock().unwrap().push((value, pc));
+        value + 1
+    }
+
+    fn time(&self) -> Option<u64> {
+        *self.time.lock().unwrap()
+    }
+
+    fn counter(&self, _address: u16, _value: u32) -> Option<u32> {
+        *self.emulated_counter.lock().unwrap()
+    }
+
+    fn hint(&self, pc: u32, halfword: u16) {
+        self.hints.lock().unwrap().push((pc, halfword));
+    }
 }
 
 impl Default for Memory {
@@ -221,4 +308,15 @@ impl Default for Memory {
     }
 }
 
+impl SyscallBackend for Memory {
+    fn syscall(&self, args: [i32; 8]) -> SyscallResult {
+        self.syscalls.lock().unwrap().push(args);
+        self.next_syscall
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or(SyscallResult::Continue)
+    }
+}
+
 impl SystemBus for Memory {}
@@ -0,0 +1,162 @@
+//! The encodings of every RV32IMA, Zicsr, Zifencei, privileged, and vector
+//! instruction the CPU implements, as `(name, mask, match)`. These are the
+//! `MASK_` and `MATCH_` constants from the `encoding.out.h` that
+//! riscv-opcodes generates, copied here rather than typed out again from
+//! the instruction table, so that a mistake in one shows up as a mismatch
+//! with the other.
+
+pub const RV32: [(&str, u32, u32); 142] = [
+    // rv_i
+    ("lui", 0x0000_007f, 0x0000_0037),
+    ("auipc", 0x0000_007f, 0x0000_0017),
+    ("jal", 0x0000_007f, 0x0000_006f),
+    ("jalr", 0x0000_707f, 0x0000_0067),
+    ("beq", 0x0000_707f, 0x0000_0063),
+    ("bne", 0x0000_707f, 0x0000_1063),
+    ("blt", 0x0000_707f, 0x0000_4063),
+    ("bge", 0x0000_707f, 0x0000_5063),
+    ("bltu", 0x0000_707f, 0x0000_6063),
+    ("bgeu", 0x0000_707f, 0x0000_7063),
+    ("lb", 0x0000_707f, 0x0000_0003),
+    ("lh", 0x0000_707f, 0x0000_1003),
+    ("lw", 0x0000_707f, 0x0000_2003),
+    ("lbu", 0x0000_707f, 0x0000_4003),
+    ("lhu", 0x0000_707f, 0x0000_5003),
+    ("sb", 0x0000_707f, 0x0000_0023),
+    ("sh", 0x0000_707f, 0x0000_1023),
+    ("sw", 0x0000_707f, 0x0000_2023),
+    ("addi", 0x0000_707f, 0x0000_0013),
+    ("slti", 0x0000_707f, 0x0000_2013),
+    ("sltiu", 0x0000_707f, 0x0000_3013),
+    ("xori", 0x0000_707f, 0x0000_4013),
+    ("ori", 0x0000_707f, 0x0000_6013),
+    ("andi", 0x0000_707f, 0x0000_7013),
+    // rv32_i, where shift amounts are only five bits
+    ("slli", 0xfe00_707f, 0x0000_1013),
+    ("srli", 0xfe00_707f, 0x0000_5013),
+    ("srai", 0xfe00_707f, 0x4000_5013),
+    ("add", 0xfe00_707f, 0x0000_0033),
+    ("sub", 0xfe00_707f, 0x4000_0033),
+    ("sll", 0xfe00_707f, 0x0000_1033),
+    ("slt", 0xfe00_707f, 0x0000_2033),
+    ("sltu", 0xfe00_707f, 0x0000_3033),
+    ("xor", 0xfe00_707f, 0x0000_4033),
+    ("srl", 0xfe00_707f, 0x0000_5033),
+    ("sra", 0xfe00_707f, 0x4000_5033),
+    ("or", 0xfe00_707f, 0x0000_6033),
+    ("and", 0xfe00_707f, 0x0000_7033),
+    ("fence", 0x0000_707f, 0x0000_000f),
+    ("ecall", 0xffff_ffff, 0x0000_0073),
+    ("ebreak", 0xffff_ffff, 0x0010_0073),
+    // rv_zifencei
+    ("fence.i", 0x0000_707f, 0x0000_100f),
+    // rv_zicsr
+    ("csrrw", 0x0000_707f, 0x0000_1073),
+    ("csrrs", 0x0000_707f, 0x0000_2073),
+    ("csrrc", 0x0000_707f, 0x0000_3073),
+    ("csrrwi", 0x0000_707f, 0x0000_5073),
+    ("csrrsi", 0x0000_707f, 0x0000_6073),
+    ("csrrci", 0x0000_707f, 0x0000_7073),
+    // rv_m
+    ("mul", 0xfe00_707f, 0x0200_0033),
+    ("mulh", 0xfe00_707f, 0x0200_1033),
+    ("mulhsu", 0xfe00_707f, 0x0200_2033),
+    ("mulhu", 0xfe00_707f, 0x0200_3033),
+    ("div", 0xfe00_707f, 0x0200_4033),
+    ("divu", 0xfe00_707f, 0x0200_5033),
+    ("rem", 0xfe00_707f, 0x0200_6033),
+    ("remu", 0xfe00_707f, 0x0200_7033),
+    // rv_a
+    ("lr.w", 0xf9f0_707f, 0x1000_202f),
+    ("sc.w", 0xf800_707f, 0x1800_202f),
+    ("amoswap.w", 0xf800_707f, 0x0800_202f),
+    ("amoadd.w", 0xf800_707f, 0x0000_202f),
+    ("amoxor.w", 0xf800_707f, 0x2000_202f),
+    ("amoand.w", 0xf800_707f, 0x6000_202f),
+    ("amoor.w", 0xf800_707f, 0x4000_202f),
+    ("amomin.w", 0xf800_707f, 0x8000_202f),
+    ("amomax.w", 0xf800_707f, 0xa000_202f),
+    ("amominu.w", 0xf800_707f, 0xc000_202f),
+    ("amomaxu.w", 0xf800_707f, 0xe000_202f),
+    // rv_s and rv_system
+    ("sret", 0xffff_ffff, 0x1020_0073),
+    ("mret", 0xffff_ffff, 0x3020_0073),
+    ("wfi", 0xffff_ffff, 0x1050_0073),
+    ("sfence.vma", 0xfe00_7fff, 0x1200_0073),
+    // rv_v, only the part of Zve32x that is implemented. riscv-opcodes
+    // leaves `nf` out of the unit-stride loads and stores since segment loads
+    // and stores are listed separately, but they aren't implemented, so here
+    // `nf` must be zero.
+    ("vsetvli", 0x8000_707f, 0x0000_7057),
+    ("vsetivli", 0xc000_707f, 0xc000_7057),
+    ("vsetvl", 0xfe00_707f, 0x8000_7057),
+    ("vle8.v", 0xfdf0_707f, 0x0000_0007),
+    ("vle16.v", 0xfdf0_707f, 0x0000_5007),
+    ("vle32.v", 0xfdf0_707f, 0x0000_6007),
+    ("vse8.v", 0xfdf0_707f, 0x0000_0027),
+    ("vse16.v", 0xfdf0_707f, 0x0000_5027),
+    ("vse32.v", 0xfdf0_707f, 0x0000_6027),
+    ("vadd.vv", 0xfc00_707f, 0x0000_0057),
+    ("vadd.vx", 0xfc00_707f, 0x0000_4057),
+    ("vadd.vi", 0xfc00_707f, 0x0000_3057),
+    ("vsub.vv", 0xfc00_707f, 0x0800_0057),
+    ("vsub.vx", 0xfc00_707f, 0x0800_4057),
+    ("vrsub.vx", 0xfc00_707f, 0x0c00_4057),
+    ("vrsub.vi", 0xfc00_707f, 0x0c00_3057),
+    ("vminu.vv", 0xfc00_707f, 0x1000_0057),
+    ("vminu.vx", 0xfc00_707f, 0x1000_4057),
+    ("vmin.vv", 0xfc00_707f, 0x1400_0057),
+    ("vmin.vx", 0xfc00_707f, 0x1400_4057),
+    ("vmaxu.vv", 0xfc00_707f, 0x1800_0057),
+    ("vmaxu.vx", 0xfc00_707f, 0x1800_4057),
+    ("vmax.vv", 0xfc00_707f, 0x1c00_0057),
+    ("vmax.vx", 0xfc00_707f, 0x1c00_4057),
+    ("vand.vv", 0xfc00_707f, 0x2400_0057),
+    ("vand.vx", 0xfc00_707f, 0x2400_4057),
+    ("vand.vi", 0xfc00_707f, 0x2400_3057),
+    ("vor.vv", 0xfc00_707f, 0x2800_0057),
+    ("vor.vx", 0xfc00_707f, 0x2800_4057),
+    ("vor.vi", 0xfc00_707f, 0x2800_3057),
+    ("vxor.vv", 0xfc00_707f, 0x2c00_0057),
+    ("vxor.vx", 0xfc00_707f, 0x2c00_4057),
+    ("vxor.vi", 0xfc00_707f, 0x2c00_3057),
+    ("vmerge.vvm", 0xfe00_707f, 0x5c00_0057),
+    ("vmv.v.v", 0xfff0_707f, 0x5e00_0057),
+    ("vmerge.vxm", 0xfe00_707f, 0x5c00_4057),
+    ("vmv.v.x", 0xfff0_707f, 0x5e00_4057),
+    ("vmerge.vim", 0xfe00_707f, 0x5c00_3057),
+    ("vmv.v.i", 0xfff0_707f, 0x5e00_3057),
+    ("vmseq.vv", 0xfc00_707f, 0x6000_0057),
+    ("vmseq.vx", 0xfc00_707f, 0x6000_4057),
+    ("vmseq.vi", 0xfc00_707f, 0x6000_3057),
+    ("vmsne.vv", 0xfc00_707f, 0x6400_0057),
+    ("vmsne.vx", 0xfc00_707f, 0x6400_4057),
+    ("vmsne.vi", 0xfc00_707f, 0x6400_3057),
+    ("vmsltu.vv", 0xfc00_707f, 0x6800_0057),
+    ("vmsltu.vx", 0xfc00_707f, 0x6800_4057),
+    ("vmslt.vv", 0xfc00_707f, 0x6c00_0057),
+    ("vmslt.vx", 0xfc00_707f, 0x6c00_4057),
+    ("vmsleu.vv", 0xfc00_707f, 0x7000_0057),
+    ("vmsleu.vx", 0xfc00_707f, 0x7000_4057),
+    ("vmsleu.vi", 0xfc00_707f, 0x7000_3057),
+    ("vmsle.vv", 0xfc00_707f, 0x7400_0057),
+    ("vmsle.vx", 0xfc00_707f, 0x7400_4057),
+    ("vmsle.vi", 0xfc00_707f, 0x7400_3057),
+    ("vmsgtu.vx", 0xfc00_707f, 0x7800_4057),
+    ("vmsgtu.vi", 0xfc00_707f, 0x7800_3057),
+    ("vmsgt.vx", 0xfc00_707f, 0x7c00_4057),
+    ("vmsgt.vi", 0xfc00_707f, 0x7c00_3057),
+    ("vsll.vv", 0xfc00_707f, 0x9400_0057),
+    ("vsll.vx", 0xfc00_707f, 0x9400_4057),
+    ("vsll.vi", 0xfc00_707f, 0x9400_3057),
+    ("vsrl.vv", 0xfc00_707f, 0xa000_0057),
+    ("vsrl.vx", 0xfc00_707f, 0xa000_4057),
+    ("vsrl.vi", 0xfc00_707f, 0xa000_3057),
+    ("vsra.vv", 0xfc00_707f, 0xa400_0057),
+    ("vsra.vx", 0xfc00_707f, 0xa400_4057),
+    ("vsra.vi", 0xfc00_707f, 0xa400_3057),
+    ("vmul.vv", 0xfc00_707f, 0x9400_2057),
+    ("vmul.vx", 0xfc00_707f, 0x9400_6057),
+    ("vmv.x.s", 0xfe0f_f07f, 0x4200_2057),
+    ("vmv.s.x", 0xfff0_707f, 0x4200_6057),
+];
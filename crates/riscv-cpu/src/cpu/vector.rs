@@ -0,0 +1,534 @@
+//! A partial Zve32x vector unit: `vsetvl` and its immediate forms,
+//! unit-stride loads and stores of 8, 16, and 32-bit elements, and the
+//! integer add, subtract, logic, shift, min/max, multiply, compare, merge, and
+//! move instructions. Every other vector instruction is illegal, as is any
+//! vector instruction on a `Cpu` without a vector unit or while `vtype` holds
+//! a configuration this unit doesn't support.
+//!
+//! Inactive elements and the tail past `vl` are left undisturbed, which is
+//! allowed whatever `vma` and `vta` ask for.
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::instructions::get_register_name;
+use super::{Cpu, Register, Trap, TrapType};
+
+pub(super) const CSR_VSTART_ADDRESS: u16 = 0x008;
+pub(super) const CSR_VL_ADDRESS: u16 = 0xc20;
+pub(super) const CSR_VTYPE_ADDRESS: u16 = 0xc21;
+pub(super) const CSR_VLENB_ADDRESS: u16 = 0xc22;
+
+/// Set in `vtype` when it was given a configuration that isn't supported,
+/// after which only `vsetvl` and its immediate forms may run.
+pub(super) const VTYPE_VILL: u32 = 1 << 31;
+
+/// The widest element, in bits. Zve32x has no 64-bit elements.
+const ELEN: u32 = 32;
+
+/// The smallest and largest `vlen` that `Cpu::enable_vector` takes.
+pub const MIN_VLEN: u32 = ELEN;
+pub const MAX_VLEN: u32 = 65536;
+
+/// The vector registers `v0` through `v31`, each `vlenb` bytes long and laid
+/// out one after the other, so that a register group of several registers
+/// is contiguous.
+pub(super) struct VectorRegisters {
+    vlenb: usize,
+    bytes: Vec<u8>,
+}
+
+impl VectorRegisters {
+    pub(super) fn new(vlen: u32) -> Self {
+        let vlenb = vlen as usize / 8;
+        VectorRegisters {
+            vlenb,
+            bytes: vec![0; vlenb * 32],
+        }
+    }
+
+    pub(super) fn vlen(&self) -> u32 {
+        self.vlenb as u32 * 8
+    }
+
+    /// Element `index` of the group starting at `register`, for elements
+    /// `width` bytes wide.
+    fn element(&self, register: u32, index: usize, width: usize) -> u32 {
+        let start = register as usize * self.vlenb + index * width;
+        self.bytes[start..start + width]
+            .iter()
+            .rev()
+            .fold(0, |value, byte| (value << 8) | *byte as u32)
+    }
+
+    fn set_element(&mut self, register: u32, index: usize, width: usize, value: u32) {
+        let start = register as usize * self.vlenb + index * width;
+        self.bytes[start..start + width].copy_from_slice(&value.to_le_bytes()[..width]);
+    }
+
+    /// Bit `index` of `register`, which holds a mask
+    fn mask(&self, register: u32, index: usize) -> bool {
+        self.bytes[register as usize * self.vlenb + index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn set_mask(&mut self, register: u32, index: usize, value: bool) {
+        let byte = &mut self.bytes[register as usize * self.vlenb + index / 8];
+        *byte = (*byte & !(1 << (index % 8))) | ((value as u8) << (index % 8));
+    }
+}
+
+/// A supported `vtype`: elements of `sew` bits, in groups of `2^lmul_log2`
+/// registers.
+#[derive(Clone, Copy)]
+struct VectorType {
+    sew: u32,
+    lmul_log2: i32,
+}
+
+impl VectorType {
+    fn decode(vtype: u32) -> Option<Self> {
+        let vsew = (vtype >> 3) & 0x7;
+        let vlmul = vtype & 0x7;
+        // Only vma and vta may be set above vsew
+        if vtype & !0xff != 0 || vsew > 2 || vlmul == 4 {
+            return None;
+        }
+        let sew = 8 << vsew;
+        let lmul_log2 = match vlmul {
+            0..=3 => vlmul as i32,
+            _ => vlmul as i32 - 8,
+        };
+        // A fractional LMUL must still leave room for a whole element of ELEN
+        if lmul_log2 < 0 && sew > ELEN >> -lmul_log2 {
+            return None;
+        }
+        Some(VectorType { sew, lmul_log2 })
+    }
+
+    /// The most elements an instruction can operate on
+    fn vlmax(&self, vlen: u32) -> u32 {
+        match self.lmul_log2 {
+            l if l >= 0 => (vlen << l) / self.sew,
+            l => (vlen >> -l) / self.sew,
+        }
+    }
+
+    /// The number of registers in a group
+    fn registers(&self) -> u32 {
+        1 << self.lmul_log2.max(0)
+    }
+
+    fn width(&self) -> usize {
+        self.sew as usize / 8
+    }
+}
+
+fn illegal() -> Trap {
+    Trap {
+        trap_type: TrapType::IllegalInstruction,
+        value: 0, // Replaced with the instruction by `tick_operate()`
+    }
+}
+
+/// Whether the instruction is masked by `v0`, rather than being unmasked
+fn masked(word: u32) -> bool {
+    word & (1 << 25) == 0
+}
+
+/// `value` truncated to `sew` bits
+fn truncate(value: u32, sew: u32) -> u32 {
+    match sew {
+        32 => value,
+        _ => value & ((1 << sew) - 1),
+    }
+}
+
+/// `value`, an element of `sew` bits, sign-extended
+pub(super) fn signed(value: u32, sew: u32) -> i32 {
+    ((value << (32 - sew)) as i32) >> (32 - sew)
+}
+
+/// The amount a shift by `value` shifts an element of `sew` bits
+pub(super) fn shift(value: u32, sew: u32) -> u32 {
+    value & (sew - 1)
+}
+
+impl Cpu {
+    /// The current `vtype`, or an illegal instruction trap if there's no
+    /// vector unit or `vtype` isn't supported.
+    fn vector_type(&self) -> Result<VectorType, Trap> {
+        if self.vector.is_none() {
+            return Err(illegal());
+        }
+        VectorType::decode(self.read_csr_raw(CSR_VTYPE_ADDRESS)).ok_or_else(illegal)
+    }
+
+    fn vector_registers(&mut self) -> &mut VectorRegisters {
+        self.vector.as_mut().unwrap()
+    }
+
+    /// The elements an instruction of type `config` covers, from `vstart`
+    /// up to `vl`, which is never more than fit in the registers it uses.
+    fn vector_body(&self, config: VectorType) -> core::ops::Range<usize> {
+        let vlmax = config.vlmax(self.vector.as_ref().map_or(0, VectorRegisters::vlen));
+        let vl = self.read_csr_raw(CSR_VL_ADDRESS).min(vlmax);
+        self.read_csr_raw(CSR_VSTART_ADDRESS) as usize..vl as usize
+    }
+}
+
+/// Whether `register` starts a group of `registers`
+fn aligned(register: u32, registers: u32) -> bool {
+    register.is_multiple_of(registers)
+}
+
+/// Where the application vector length for `vsetvl` and its immediate forms
+/// comes from.
+pub(super) enum Avl {
+    Register(Register),
+    Immediate(u32),
+}
+
+/// Set `vl` and `vtype` for `vsetvl` and its immediate forms, writing the
+/// new `vl` to `rd`.
+pub(super) fn set_vector_length(
+    cpu: &mut Cpu,
+    rd: Register,
+    avl: Avl,
+    vtype: u32,
+) -> Result<(), Trap> {
+    let Some(vector) = &cpu.vector else {
+        return Err(illegal());
+    };
+    let vlen = vector.vlen();
+    let (vl, vtype) = match VectorType::decode(vtype) {
+        Some(config) => {
+            let avl = match avl {
+                Avl::Immediate(avl) => avl,
+                Avl::Register(rs1) if rs1 != Register::ZERO => cpu.x[rs1] as u32,
+                // Ask for as many as there can be
+                Avl::Register(_) if rd != Register::ZERO => u32::MAX,
+                // Keep the vector length, changing only the type
+                Avl::Register(_) => cpu.read_csr_raw(CSR_VL_ADDRESS),
+            };
+            (avl.min(config.vlmax(vlen)), vtype)
+        }
+        None => (0, VTYPE_VILL),
+    };
+    cpu.write_csr_raw(CSR_VL_ADDRESS, vl);
+    cpu.write_csr_raw(CSR_VTYPE_ADDRESS, vtype);
+    cpu.write_csr_raw(CSR_VSTART_ADDRESS, 0);
+    cpu.x[rd] = vl as i32;
+    Ok(())
+}
+
+/// A unit-stride load or store of elements `width` bytes wide.
+pub(super) fn unit_stride(cpu: &mut Cpu, word: u32, width: usize, store: bool) -> Result<(), Trap> {
+    let config = cpu.vector_type()?;
+    // The elements take up as many registers as SEW elements would in LMUL
+    let emul_log2 =
+        (width as u32 * 8).ilog2() as i32 - config.sew.ilog2() as i32 + config.lmul_log2;
+    if !(-3..=3).contains(&emul_log2) {
+        return Err(illegal());
+    }
+    let vd = (word >> 7) & 0x1f;
+    let registers = 1 << emul_log2.max(0);
+    if !aligned(vd, registers) || (!store && masked(word) && vd == 0) {
+        return Err(illegal());
+    }
+    let base = cpu.x[Register::from_field(word >> 15)] as u32;
+    for index in cpu.vector_body(config) {
+        if masked(word) && !cpu.vector_registers().mask(0, index) {
+            continue;
+        }
+        let address = base.wrapping_add((index * width) as u32);
+        let result = match store {
+            true => {
+                let value = cpu.vector_registers().element(vd, index, width);
+                match width {
+                    1 => cpu.mmu.store(address, value as u8),
+                    2 => cpu.mmu.store_halfword(address, value as u16),
+                    _ => cpu.mmu.store_word(address, value),
+                }
+            }
+            false => {
+                let value = match width {
+                    1 => cpu.mmu.load(address).map(|value| value as u32),
+                    2 => cpu.mmu.load_halfword(address).map(|value| value as u32),
+                    _ => cpu.mmu.load_word(address),
+                };
+                value.map(|value| cpu.vector_registers().set_element(vd, index, width, value))
+            }
+        };
+        if let Err(trap) = result {
+            // Resume from this element once the trap has been handled
+            cpu.write_csr_raw(CSR_VSTART_ADDRESS, index as u32);
+            return Err(trap);
+        }
+    }
+    cpu.write_csr_raw(CSR_VSTART_ADDRESS, 0);
+    Ok(())
+}
+
+/// The second operand of an arithmetic instruction for element `index`:
+/// an element of `vs1`, `rs1`, or a 5-bit signed immediate, depending on
+/// `funct3`.
+fn operand(cpu: &mut Cpu, word: u32, index: usize, sew: u32) -> u32 {
+    let field = (word >> 15) & 0x1f;
+    let value = match (word >> 12) & 0x7 {
+        // OPIVV and OPMVV
+        0b000 | 0b010 => cpu
+            .vector_registers()
+            .element(field, index, sew as usize / 8),
+        // OPIVI
+        0b011 => (((field as i32) << 27) >> 27) as u32,
+        // OPIVX and OPMVX
+        _ => cpu.x[Register::from_field(field)] as u32,
+    };
+    truncate(value, sew)
+}
+
+fn takes_vector_operand(word: u32) -> bool {
+    matches!((word >> 12) & 0x7, 0b000 | 0b010)
+}
+
+/// Run an element-wise instruction, setting each active element of `vd` to
+/// `operation(vs2, operand, sew)`.
+pub(super) fn integer(
+    cpu: &mut Cpu,
+    word: u32,
+    operation: fn(u32, u32, u32) -> u32,
+) -> Result<(), Trap> {
+    let config = cpu.vector_type()?;
+    let (vd, vs1, vs2) = ((word >> 7) & 0x1f, (word >> 15) & 0x1f, (word >> 20) & 0x1f);
+    let registers = config.registers();
+    if !aligned(vd, registers)
+        || !aligned(vs2, registers)
+        || (takes_vector_operand(word) && !aligned(vs1, registers))
+        || (masked(word) && vd == 0)
+    {
+        return Err(illegal());
+    }
+    let (sew, width) = (config.sew, config.width());
+    for index in cpu.vector_body(config) {
+        if masked(word) && !cpu.vector_registers().mask(0, index) {
+            continue;
+        }
+        let a = cpu.vector_registers().element(vs2, index, width);
+        let b = operand(cpu, word, index, sew);
+        let result = operation(a, b, sew);
+        cpu.vector_registers().set_element(vd, index, width, result);
+    }
+    cpu.write_csr_raw(CSR_VSTART_ADDRESS, 0);
+    Ok(())
+}
+
+/// Run a comparison, setting bit `i` of `vd` to `comparison(vs2, operand,
+/// sew)` for each active element.
+pub(super) fn compare(
+    cpu: &mut Cpu,
+    word: u32,
+    comparison: fn(u32, u32, u32) -> bool,
+) -> Result<(), Trap> {
+    let config = cpu.vector_type()?;
+    let (vd, vs1, vs2) = ((word >> 7) & 0x1f, (word >> 15) & 0x1f, (word >> 20) & 0x1f);
+    let registers = config.registers();
+    if !aligned(vs2, registers) || (takes_vector_operand(word) && !aligned(vs1, registers)) {
+        return Err(illegal());
+    }
+    let (sew, width) = (config.sew, config.width());
+    for index in cpu.vector_body(config) {
+        if masked(word) && !cpu.vector_registers().mask(0, index) {
+            continue;
+        }
+        let a = cpu.vector_registers().element(vs2, index, width);
+        let b = operand(cpu, word, index, sew);
+        let result = comparison(a, b, sew);
+        cpu.vector_registers().set_mask(vd, index, result);
+    }
+    cpu.write_csr_raw(CSR_VSTART_ADDRESS, 0);
+    Ok(())
+}
+
+/// `vmerge`, which takes the operand where `v0` is set and `vs2` elsewhere,
+/// or, unmasked, `vmv.v`, which always takes the operand.
+pub(super) fn merge(cpu: &mut Cpu, word: u32) -> Result<(), Trap> {
+    let config = cpu.vector_type()?;
+    let (vd, vs1, vs2) = ((word >> 7) & 0x1f, (word >> 15) & 0x1f, (word >> 20) & 0x1f);
+    let registers = config.registers();
+    if !aligned(vd, registers)
+        || !aligned(vs2, registers)
+        || (takes_vector_operand(word) && !aligned(vs1, registers))
+        || (masked(word) && vd == 0)
+    {
+        return Err(illegal());
+    }
+    let (sew, width) = (config.sew, config.width());
+    for index in cpu.vector_body(config) {
+        let value = match masked(word) && !cpu.vector_registers().mask(0, index) {
+            true => cpu.vector_registers().element(vs2, index, width),
+            false => operand(cpu, word, index, sew),
+        };
+        cpu.vector_registers().set_element(vd, index, width, value);
+    }
+    cpu.write_csr_raw(CSR_VSTART_ADDRESS, 0);
+    Ok(())
+}
+
+/// `vmv.x.s`, which sign-extends element 0 of `vs2` into `rd`, even if `vl`
+/// is zero.
+pub(super) fn move_to_scalar(cpu: &mut Cpu, word: u32) -> Result<(), Trap> {
+    let config = cpu.vector_type()?;
+    let vs2 = (word >> 20) & 0x1f;
+    let value = cpu.vector_registers().element(vs2, 0, config.width());
+    cpu.x[Register::from_field(word >> 7)] = signed(value, config.sew);
+    cpu.write_csr_raw(CSR_VSTART_ADDRESS, 0);
+    Ok(())
+}
+
+/// `vmv.s.x`, which sets element 0 of `vd` to `rs1` unless `vl` is zero.
+pub(super) fn move_to_vector(cpu: &mut Cpu, word: u32) -> Result<(), Trap> {
+    let config = cpu.vector_type()?;
+    let value = cpu.x[Register::from_field(word >> 15)] as u32;
+    if !cpu.vector_body(config).is_empty() {
+        cpu.vector_registers()
+            .set_element((word >> 7) & 0x1f, 0, config.width(), value);
+    }
+    cpu.write_csr_raw(CSR_VSTART_ADDRESS, 0);
+    Ok(())
+}
+
+fn vector_register_name(register: u32) -> String {
+    format!("v{}", register)
+}
+
+/// `vtype` as assembler writes it, such as `e32,m1,ta,mu`
+fn vtype_name(vtype: u32) -> String {
+    let lmul = match vtype & 0x7 {
+        0 => "m1",
+        1 => "m2",
+        2 => "m4",
+        3 => "m8",
+        5 => "mf8",
+        6 => "mf4",
+        7 => "mf2",
+        _ => "m?",
+    };
+    format!(
+        "e{},{},{},{}",
+        8 << ((vtype >> 3) & 0x7),
+        lmul,
+        if vtype & (1 << 6) != 0 { "ta" } else { "tu" },
+        if vtype & (1 << 7) != 0 { "ma" } else { "mu" }
+    )
+}
+
+fn dump_mask(word: u32) -> &'static str {
+    match masked(word) {
+        true => ",v0.t",
+        false => "",
+    }
+}
+
+pub(super) fn dump_set_vector_length(
+    cpu: &Cpu,
+    word: u32,
+    _address: u32,
+    evaluate: bool,
+) -> String {
+    let rd = Register::from_field(word >> 7);
+    let rs1 = Register::from_field(word >> 15);
+    let mut s = String::new();
+    s += get_register_name(rd);
+    if evaluate {
+        s += &format!(":{:x}", cpu.x[rd]);
+    }
+    match word >> 30 {
+        // vsetivli
+        0b11 => s += &format!(",{}", (word >> 15) & 0x1f),
+        _ => {
+            s += &format!(",{}", get_register_name(rs1));
+            if evaluate {
+                s += &format!(":{:x}", cpu.x[rs1]);
+            }
+        }
+    }
+    match word >> 31 {
+        // vsetvl
+        1 if word >> 30 == 0b10 => {
+            let rs2 = Register::from_field(word >> 20);
+            s += &format!(",{}", get_register_name(rs2));
+            if evaluate {
+                s += &format!(":{:x}", cpu.x[rs2]);
+            }
+        }
+        _ => s += &format!(",{}", vtype_name((word >> 20) & 0x3ff)),
+    }
+    s
+}
+
+pub(super) fn dump_unit_stride(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> String {
+    let rs1 = Register::from_field(word >> 15);
+    let mut s = vector_register_name((word >> 7) & 0x1f);
+    s += &format!(",({}", get_register_name(rs1));
+    if evaluate {
+        s += &format!(":{:x}", cpu.x[rs1]);
+    }
+    s += ")";
+    s += dump_mask(word);
+    s
+}
+
+pub(super) fn dump_arithmetic(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> String {
+    let field = (word >> 15) & 0x1f;
+    let mut s = vector_register_name((word >> 7) & 0x1f);
+    s += &format!(",{}", vector_register_name((word >> 20) & 0x1f));
+    match (word >> 12) & 0x7 {
+        0b000 | 0b010 => s += &format!(",{}", vector_register_name(field)),
+        0b011 => s += &format!(",{}", ((field as i32) << 27) >> 27),
+        _ => {
+            let rs1 = Register::from_field(field);
+            s += &format!(",{}", get_register_name(rs1));
+            if evaluate {
+                s += &format!(":{:x}", cpu.x[rs1]);
+            }
+        }
+    }
+    s += dump_mask(word);
+    s
+}
+
+/// `vmerge` and `vmv.v`, which have no `vs2` when they're unmasked
+pub(super) fn dump_merge(cpu: &Cpu, word: u32, address: u32, evaluate: bool) -> String {
+    match masked(word) {
+        true => dump_arithmetic(cpu, word & !(1 << 25), address, evaluate) + ",v0",
+        false => {
+            // Drop the `vs2` of `vd,vs2,operand`
+            let s = dump_arithmetic(cpu, word, address, evaluate);
+            let (vd, rest) = s.split_once(',').unwrap();
+            let (_, operand) = rest.split_once(',').unwrap();
+            format!("{},{}", vd, operand)
+        }
+    }
+}
+
+pub(super) fn dump_move_to_scalar(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> String {
+    let rd = Register::from_field(word >> 7);
+    let mut s = get_register_name(rd).to_owned();
+    if evaluate {
+        s += &format!(":{:x}", cpu.x[rd]);
+    }
+    s + "," + &vector_register_name((word >> 20) & 0x1f)
+}
+
+pub(super) fn dump_move_to_vector(cpu: &Cpu, word: u32, _address: u32, evaluate: bool) -> String {
+    let rs1 = Register::from_field(word >> 15);
+    let mut s = vector_register_name((word >> 7) & 0x1f);
+    s += &format!(",{}", get_register_name(rs1));
+    if evaluate {
+        s += &format!(":{:x}", cpu.x[rs1]);
+    }
+    s
+}
@@ -1,4 +1,11 @@
+//! A RISC-V RV32IMAC interpreter. It only needs `core` and `alloc`, so it
+//! can run in embedded and WASM hosts as well as under an OS shim.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
 pub mod cpu;
 pub mod mmu;
+pub mod syscall;
 
 pub use cpu::{Cpu, CpuBuilder};
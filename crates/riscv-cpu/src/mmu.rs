@@ -1,28 +1,10 @@
-use std::{sync::mpsc::Receiver, thread::JoinHandle};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
 
-use crate::cpu::{decode_privilege_mode, PrivilegeMode, ResponseData, Trap, TrapType};
-
-pub enum SyscallResult {
-    Ok([i32; 8]),
-    Defer(Receiver<ResponseData>),
-    Terminate(usize /* Result */),
-    JoinThread(JoinHandle<u32>),
-
-    /// Pass the exception to the CPU
-    Continue,
-}
-
-impl From<[i32; 8]> for SyscallResult {
-    fn from(args: [i32; 8]) -> Self {
-        SyscallResult::Ok(args)
-    }
-}
-
-impl From<std::sync::mpsc::Receiver<ResponseData>> for SyscallResult {
-    fn from(receiver: std::sync::mpsc::Receiver<ResponseData>) -> Self {
-        SyscallResult::Defer(receiver)
-    }
-}
+use crate::cpu::{decode_privilege_mode, PrivilegeMode, Trap, TrapType};
+use crate::syscall::SyscallBackend;
 
 pub trait Memory {
     fn read_u8(&self, p_address: u32) -> u8;
@@ -32,14 +14,152 @@ pub trait Memory {
     fn write_u16(&self, p_address: u32, value: u16);
     fn write_u32(&self, p_address: u32, value: u32);
     fn validate_address(&self, address: u32) -> bool;
-    fn syscall(&self, args: [i32; 8]) -> SyscallResult;
-    fn translate(&self, v_address: u32) -> Option<u32>;
+
+    /// The physical address `v_address` maps to, if the implementation
+    /// already knows and the page allows `access_type`. Returning `None`
+    /// leaves the MMU to walk the page tables, which is where the page fault
+    /// for an access the page doesn't allow is raised.
+    fn translate(&self, v_address: u32, access_type: &MemoryAccessType) -> Option<u32>;
+    /// Reserve the word at `p_address` for `core`, replacing any reservation
+    /// it already held. The reservation must be dropped when another core
+    /// stores to that word.
     fn reserve(&self, core: u32, p_address: u32);
+
+    /// Drop the reservation held by `core`, returning whether it was for the
+    /// word at `p_address` and hadn't been broken.
     fn clear_reservation(&self, core: u32, p_address: u32) -> bool;
+
+    /// Read the aligned word at `p_address` and reserve it for `core`, for
+    /// `LR.W`. Implementations shared between threads must make sure no
+    /// store from another core comes between the two.
+    fn load_reserved(&self, core: u32, p_address: u32) -> u32 {
+        let value = self.read_u32(p_address);
+        self.reserve(core, p_address);
+        value
+    }
+
+    /// Store `value` to the aligned word at `p_address` if `core` still
+    /// holds a reservation for it, for `SC.W`, and return whether it did.
+    /// The reservation is dropped either way. Implementations shared between
+    /// threads must make sure no store from another core comes between the
+    /// check and the store.
+    fn store_conditional(&self, core: u32, p_address: u32, value: u32) -> bool {
+        let reserved = self.clear_reservation(core, p_address);
+        if reserved {
+            self.write_u32(p_address, value);
+        }
+        reserved
+    }
+
+    /// Replace the aligned word at `p_address` with `operation` of what it
+    /// held, for an AMO, and return what it held. Implementations shared
+    /// between threads must make sure no other core accesses the word
+    /// between the read and the write.
+    fn update_u32(&self, p_address: u32, operation: &mut dyn FnMut(u32) -> u32) -> u32 {
+        let value = self.read_u32(p_address);
+        self.write_u32(p_address, operation(value));
+        value
+    }
     fn clone(&self) -> Box<dyn Memory + Send + Sync>;
+
+    /// Read an instruction byte. Implementations that want to tell instruction
+    /// fetches apart from data reads can override this.
+    fn fetch_u8(&self, p_address: u32) -> u8 {
+        self.read_u8(p_address)
+    }
+
+    /// Read four instruction bytes. See `fetch_u8`.
+    fn fetch_u32(&self, p_address: u32) -> u32 {
+        self.read_u32(p_address)
+    }
+
+    /// Called when the instruction at `pc` accesses `p_address` and
+    /// `validate_address` rejects it. Only called once the MMU has been asked to
+    /// check physical addresses. Return `true` to raise an access fault, or
+    /// `false` to let the access go ahead anyway.
+    fn invalid_access(&self, _p_address: u32, _pc: u32, _access_type: &MemoryAccessType) -> bool {
+        false
+    }
+
+    /// Called when translating `v_address` fails, before a page fault is
+    /// raised. Return `true` once the page has been mapped to have the access
+    /// retried, such as for pages that are only allocated when first touched.
+    fn page_fault(&self, _v_address: u32) -> bool {
+        false
+    }
+
+    /// Called when the guest at `pc` writes `value` to `CSR_HYPERCALL_ADDRESS`.
+    /// The return value is what the guest reads back from the CSR.
+    fn hypercall(&self, _value: u32, _pc: u32, _registers: &[i32; 32]) -> u32 {
+        0
+    }
+
+    /// Whether LR/SC and AMOs may be used on `p_address`. They raise access
+    /// faults anywhere this returns `false`, such as on MMIO.
+    fn reservable(&self, _p_address: u32) -> bool {
+        true
+    }
+
+    /// The current time in microseconds, which the guest reads from the
+    /// `time` and `timeh` CSRs. Return `None` to leave those CSRs as plain
+    /// storage.
+    fn time(&self) -> Option<u64> {
+        None
+    }
+
+    /// Called when the guest reads the counter CSR `address`, such as
+    /// `cycle` or `timeh`, from a privilege mode that `mcounteren` and
+    /// `scounteren` don't allow to. `value` is what the CSR holds. Return
+    /// what the guest should read instead, as a more privileged layer
+    /// emulating the read would, or `None` to raise an illegal instruction.
+    fn counter(&self, _address: u16, _value: u32) -> Option<u32> {
+        None
+    }
+
+    /// Called when the instruction at `pc` is the compressed HINT `halfword`
+    /// and the CPU's `HintPolicy` is `Report`. It runs as a no-op either way.
+    fn hint(&self, _pc: u32, _halfword: u16) {}
+}
+
+pub trait SystemBus: Memory + SyscallBackend + Send + Sync {}
+
+/// Number of entries in the software TLB. Must be a power of two.
+const TLB_ENTRIES: usize = 64;
+
+/// A cached SV32 translation of one 4 KiB virtual page.
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    asid: u32,
+    vpn: u32,
+    ppn: u32,
+
+    /// The leaf PTE, including any A and D bits the walk set.
+    pte: u32,
+}
+
+/// Picks the TLB slot for a page. Mixing in the ASID keeps address spaces that
+/// use the same virtual pages from evicting each other's entries.
+fn tlb_index(asid: u32, vpn: u32) -> usize {
+    (vpn ^ asid.wrapping_mul(0x9e37_79b9)) as usize & (TLB_ENTRIES - 1)
 }
 
-pub trait SystemBus: Memory + Send + Sync {}
+impl TlbEntry {
+    fn matches(&self, asid: u32, vpn: u32) -> bool {
+        self.vpn == vpn && (self.asid == asid || (self.pte >> 5) & 1 != 0)
+    }
+
+    /// Whether the access can use this entry without walking the page table
+    /// again, which it must do if it needs to set the D bit.
+    fn permits(&self, access_type: &MemoryAccessType) -> bool {
+        let bit = match access_type {
+            MemoryAccessType::Execute => 3,
+            MemoryAccessType::Read => 1,
+            MemoryAccessType::Write => return self.pte & (1 << 2 | 1 << 7) == 1 << 2 | 1 << 7,
+            MemoryAccessType::DontCare => return true,
+        };
+        (self.pte >> bit) & 1 != 0
+    }
+}
 
 /// Emulates Memory Management Unit. It holds the Main memory and peripheral
 /// devices, maps address to them, and accesses them depending on address.
@@ -49,6 +169,10 @@ pub trait SystemBus: Memory + Send + Sync {}
 pub struct Mmu {
     // clock: u64,
     ppn: u32,
+
+    /// Address space ID from `satp`, which tags entries in the TLB.
+    asid: u32,
+    tlb: Vec<Cell<Option<TlbEntry>>>,
     addressing_mode: AddressingMode,
     privilege_mode: PrivilegeMode,
     memory: Box<dyn Memory + Send + Sync>,
@@ -56,6 +180,24 @@ pub struct Mmu {
     /// Address translation can be affected `mstatus` (MPRV, MPP in machine mode)
     /// then `Mmu` has copy of it.
     mstatus: u32,
+
+    /// Whether to check translated addresses with `Memory::validate_address`.
+    check_physical: bool,
+
+    /// Address of the instruction being executed, for `Memory::invalid_access`.
+    pc: u32,
+
+    /// The loads and stores made since `take_accesses`, if they're being
+    /// recorded.
+    accesses: Option<RefCell<Vec<MemoryAccess>>>,
+}
+
+/// A load or store that completed, by virtual address, as recorded for
+/// `Cpu::last_commit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryAccess {
+    Read { address: u32, size: u32 },
+    Write { address: u32, size: u32, value: u32 },
 }
 
 #[derive(Debug, PartialEq)]
@@ -64,7 +206,8 @@ pub enum AddressingMode {
     SV32,
 }
 
-enum MemoryAccessType {
+#[derive(Debug)]
+pub enum MemoryAccessType {
     Execute,
     Read,
     Write,
@@ -87,10 +230,15 @@ impl Mmu {
         Mmu {
             // clock: 0,
             ppn: 0,
+            asid: 0,
+            tlb: vec![Cell::new(None); TLB_ENTRIES],
             addressing_mode: AddressingMode::None,
             privilege_mode: PrivilegeMode::Machine,
             memory,
             mstatus: 0,
+            check_physical: false,
+            pc: 0,
+            accesses: None,
         }
     }
 
@@ -122,6 +270,38 @@ impl Mmu {
         self.mstatus = mstatus;
     }
 
+    /// Check every translated address with `Memory::validate_address`, and
+    /// report the ones that fail to `Memory::invalid_access`.
+    pub fn check_physical_addresses(&mut self, check: bool) {
+        self.check_physical = check;
+    }
+
+    /// Record every load and store, to be collected with `take_accesses`.
+    pub fn record_accesses(&mut self, record: bool) {
+        self.accesses = record.then(RefCell::default);
+    }
+
+    /// The loads and stores made since this was last called, in order, if
+    /// they're being recorded.
+    pub fn take_accesses(&self) -> Vec<MemoryAccess> {
+        self.accesses
+            .as_ref()
+            .map(|accesses| accesses.take())
+            .unwrap_or_default()
+    }
+
+    fn note(&self, access: MemoryAccess) {
+        if let Some(accesses) = &self.accesses {
+            accesses.borrow_mut().push(access);
+        }
+    }
+
+    /// Updates the address of the instruction being executed. `CPU` calls this
+    /// before each instruction.
+    pub fn update_pc(&mut self, pc: u32) {
+        self.pc = pc;
+    }
+
     /// Updates PPN used for address translation
     ///
     /// # Arguments
@@ -130,18 +310,55 @@ impl Mmu {
         self.ppn = ppn;
     }
 
+    /// Updates the address space ID used to tag TLB entries
+    ///
+    /// # Arguments
+    /// * `asid`
+    pub fn update_asid(&mut self, asid: u32) {
+        self.asid = asid;
+    }
+
+    /// Returns the current address space ID.
+    pub fn asid(&self) -> u32 {
+        self.asid
+    }
+
+    /// Switches to the page table rooted at `ppn`, tagged with `asid`. Entries
+    /// cached for other address spaces stay in the TLB, so switching back
+    /// doesn't need to walk the page table again. The caller must flush `asid`
+    /// if its page table has changed since it was last used.
+    pub fn switch_address_space(&mut self, asid: u32, ppn: u32) {
+        self.asid = asid;
+        self.ppn = ppn;
+    }
+
+    /// Removes cached translations, as `SFENCE.VMA` does. `v_address` limits
+    /// the flush to one page, and `asid` to one address space, in which case
+    /// global mappings are kept.
+    pub fn flush_tlb(&mut self, v_address: Option<u32>, asid: Option<u32>) {
+        let vpn = v_address.map(|address| address >> 12);
+        for slot in self.tlb.iter() {
+            let Some(entry) = slot.get() else {
+                continue;
+            };
+            if vpn.is_some_and(|vpn| vpn != entry.vpn) {
+                continue;
+            }
+            if asid.is_some_and(|asid| asid != entry.asid || (entry.pte >> 5) & 1 != 0) {
+                continue;
+            }
+            slot.set(None);
+        }
+    }
+
     /// Fetches an instruction byte. This method takes virtual address
     /// and translates into physical address inside.
     ///
     /// # Arguments
     /// * `v_address` Virtual address
     fn fetch(&self, v_address: u32) -> Result<u8, Trap> {
-        self.translate_address(v_address, &MemoryAccessType::Execute)
-            .map(|p_address| self.load_raw(p_address))
-            .map_err(|()| Trap {
-                trap_type: TrapType::InstructionPageFault,
-                value: v_address,
-            })
+        self.translate_checked(v_address, &MemoryAccessType::Execute)
+            .map(|p_address| self.memory.fetch_u8(p_address))
     }
 
     /// Fetches instruction four bytes. This method takes virtual address
@@ -154,13 +371,8 @@ impl Mmu {
         if (v_address & 0xfff) <= (0x1000 - width) {
             // Fast path. All bytes fetched are in the same page so
             // translating an address only once.
-            let effective_address = v_address;
-            self.translate_address(effective_address, &MemoryAccessType::Execute)
-                .map(|p_address| self.load_word_raw(p_address))
-                .map_err(|()| Trap {
-                    trap_type: TrapType::InstructionPageFault,
-                    value: effective_address,
-                })
+            self.translate_checked(v_address, &MemoryAccessType::Execute)
+                .map(|p_address| self.memory.fetch_u32(p_address))
         } else {
             let mut data = 0;
             for i in 0..width {
@@ -170,20 +382,39 @@ impl Mmu {
         }
     }
 
+    /// Fetches the instruction at `v_address`, which only covers two bytes if
+    /// it's compressed. Near the end of a page, the second half of the
+    /// instruction is only fetched if it has one, so a compressed instruction
+    /// doesn't fault on the page after it. If fetching the second half faults,
+    /// the trap holds the address of that half rather than of the instruction.
+    ///
+    /// # Arguments
+    /// * `v_address` Virtual address
+    pub fn fetch_instruction(&self, v_address: u32) -> Result<u32, Trap> {
+        if (v_address & 0xfff) <= (0x1000 - 4) {
+            return self
+                .translate_checked(v_address, &MemoryAccessType::Execute)
+                .map(|p_address| self.memory.fetch_u32(p_address));
+        }
+        let fetch_halfword =
+            |v_address: u32| -> Result<u32, Trap> {
+                Ok(self.fetch(v_address)? as u32
+                    | (self.fetch(v_address.wrapping_add(1))? as u32) << 8)
+            };
+        let low = fetch_halfword(v_address)?;
+        if low & 0x3 != 0x3 {
+            return Ok(low);
+        }
+        Ok(low | fetch_halfword(v_address.wrapping_add(2))? << 16)
+    }
+
     /// Loads an byte. This method takes virtual address and translates
     /// into physical address inside.
     ///
     /// # Arguments
     /// * `v_address` Virtual address
     pub fn load(&self, v_address: u32) -> Result<u8, Trap> {
-        let effective_address = v_address;
-        match self.translate_address(effective_address, &MemoryAccessType::Read) {
-            Ok(p_address) => Ok(self.load_raw(p_address)),
-            Err(()) => Err(Trap {
-                trap_type: TrapType::LoadPageFault,
-                value: v_address,
-            }),
-        }
+        self.load_bytes(v_address, 1).map(|data| data as u8)
     }
 
     /// Loads multiple bytes. This method takes virtual address and translates
@@ -198,32 +429,133 @@ impl Mmu {
             "Width must be 1, 2, or 4. {:X}",
             width
         );
-        if (v_address & 0xfff) <= (0x1000 - width) {
-            let p_address = self
-                .translate_address(v_address, &MemoryAccessType::Read)
-                .map_err(|()| Trap {
-                    trap_type: TrapType::LoadPageFault,
-                    value: v_address,
-                })?;
+        let data = if (v_address & 0xfff) <= (0x1000 - width) {
+            let p_address = self.translate_checked(v_address, &MemoryAccessType::Read)?;
 
             // Fast path. All bytes fetched are in the same page so
             // translating an address only once.
             match width {
-                1 => Ok(self.load_raw(p_address) as u32),
-                2 => Ok(self.load_halfword_raw(p_address) as u32),
-                4 => Ok(self.load_word_raw(p_address)),
+                1 => self.load_raw(p_address) as u32,
+                2 => self.load_halfword_raw(p_address) as u32,
+                4 => self.load_word_raw(p_address),
                 _ => panic!("Width must be 1, 2, or 4. {:X}", width),
             }
         } else {
             let mut data = 0;
             for i in 0..width {
-                match self.load(v_address.wrapping_add(i)) {
-                    Ok(byte) => data |= (byte as u32) << (i * 8),
-                    Err(e) => return Err(e),
-                };
+                let p_address =
+                    self.translate_checked(v_address.wrapping_add(i), &MemoryAccessType::Read)?;
+                data |= (self.load_raw(p_address) as u32) << (i * 8);
             }
-            Ok(data)
+            data
+        };
+        self.note(MemoryAccess::Read {
+            address: v_address,
+            size: width,
+        });
+        Ok(data)
+    }
+
+    /// Loads a word and reserves it for `core`, for `LR.W`. The address must
+    /// be aligned and reservable.
+    pub fn load_reserved(&mut self, v_address: u32, core: u32) -> Result<u32, Trap> {
+        if v_address & 3 != 0 {
+            return Err(Trap {
+                trap_type: TrapType::LoadAddressMisaligned,
+                value: v_address,
+            });
+        }
+        let p_address = self.translate_checked(v_address, &MemoryAccessType::Read)?;
+        if !self.memory.reservable(p_address) {
+            return Err(Trap {
+                trap_type: TrapType::LoadAccessFault,
+                value: v_address,
+            });
+        }
+        let data = self.memory.load_reserved(core, p_address);
+        self.note(MemoryAccess::Read {
+            address: v_address,
+            size: 4,
+        });
+        Ok(data)
+    }
+
+    /// Replaces the word at `v_address` with `operation` of what it held, as
+    /// one atomic access, and returns what it held, for AMOs. The word must
+    /// be aligned, writable, and reservable. Faults are reported as
+    /// store/AMO faults, as they are for the store half of the operation.
+    pub fn amo(
+        &mut self,
+        v_address: u32,
+        operation: &mut dyn FnMut(u32) -> u32,
+    ) -> Result<u32, Trap> {
+        if v_address & 3 != 0 {
+            return Err(Trap {
+                trap_type: TrapType::StoreAddressMisaligned,
+                value: v_address,
+            });
+        }
+        let p_address = self.translate_checked(v_address, &MemoryAccessType::Write)?;
+        if !self.memory.reservable(p_address) {
+            return Err(Trap {
+                trap_type: TrapType::StoreAccessFault,
+                value: v_address,
+            });
+        }
+        let mut stored = 0;
+        let data = self.memory.update_u32(p_address, &mut |data| {
+            stored = operation(data);
+            stored
+        });
+        self.note(MemoryAccess::Read {
+            address: v_address,
+            size: 4,
+        });
+        self.note(MemoryAccess::Write {
+            address: v_address,
+            size: 4,
+            value: stored,
+        });
+        Ok(data)
+    }
+
+    /// Stores a word if `core` still holds a reservation for it, for `SC.W`.
+    /// Returns whether the store happened. Either way, the reservation is gone
+    /// afterwards.
+    pub fn store_conditional(
+        &mut self,
+        v_address: u32,
+        value: u32,
+        core: u32,
+    ) -> Result<bool, Trap> {
+        if v_address & 3 != 0 {
+            return Err(Trap {
+                trap_type: TrapType::StoreAddressMisaligned,
+                value: v_address,
+            });
+        }
+        let p_address = self.translate_checked(v_address, &MemoryAccessType::Write)?;
+        if !self.memory.reservable(p_address) {
+            return Err(Trap {
+                trap_type: TrapType::StoreAccessFault,
+                value: v_address,
+            });
+        }
+        let reserved = self.memory.store_conditional(core, p_address, value);
+        if reserved {
+            self.note(MemoryAccess::Write {
+                address: v_address,
+                size: 4,
+                value,
+            });
         }
+        Ok(reserved)
+    }
+
+    /// Drops any reservation held by `core`, as happens when it takes a trap.
+    pub fn drop_reservation(&mut self, core: u32) {
+        // The address doesn't matter, the reservation is dropped regardless
+        self.memory.clear_reservation(core, 0);
     }
 
     /// Loads two bytes. This method takes virtual address and translates
@@ -251,12 +583,7 @@ impl Mmu {
     /// * `v_address` Virtual address
     /// * `value`
     pub fn store(&self, v_address: u32, value: u8) -> Result<(), Trap> {
-        self.translate_address(v_address, &MemoryAccessType::Write)
-            .map(|p_address| self.store_raw(p_address, value))
-            .map_err(|()| Trap {
-                trap_type: TrapType::StorePageFault,
-                value: v_address,
-            })
+        self.store_bytes(v_address, value as u32, 1)
     }
 
     /// Stores multiple bytes. This method takes virtual address and translates
@@ -273,33 +600,31 @@ impl Mmu {
             width
         );
         match (v_address & 0xfff) <= (0x1000 - width) {
-            true => match self.translate_address(v_address, &MemoryAccessType::Write) {
-                Ok(p_address) => {
-                    // Fast path. All bytes fetched are in the same page so
-                    // translating an address only once.
-                    match width {
-                        1 => self.store_raw(p_address, value as u8),
-                        2 => self.store_halfword_raw(p_address, value as u16),
-                        4 => self.store_word_raw(p_address, value),
-                        _ => panic!("Width must be 1, 2, 4, or 8. {:X}", width),
-                    }
-                    Ok(())
+            true => {
+                let p_address = self.translate_checked(v_address, &MemoryAccessType::Write)?;
+                // Fast path. All bytes fetched are in the same page so
+                // translating an address only once.
+                match width {
+                    1 => self.store_raw(p_address, value as u8),
+                    2 => self.store_halfword_raw(p_address, value as u16),
+                    4 => self.store_word_raw(p_address, value),
+                    _ => panic!("Width must be 1, 2, 4, or 8. {:X}", width),
                 }
-                Err(()) => Err(Trap {
-                    trap_type: TrapType::StorePageFault,
-                    value: v_address,
-                }),
-            },
+            }
             false => {
                 for i in 0..width {
-                    match self.store(v_address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8) {
-                        Ok(()) => {}
-                        Err(e) => return Err(e),
-                    }
+                    let p_address = self
+                        .translate_checked(v_address.wrapping_add(i), &MemoryAccessType::Write)?;
+                    self.store_raw(p_address, ((value >> (i * 8)) & 0xff) as u8);
                 }
-                Ok(())
             }
         }
+        self.note(MemoryAccess::Write {
+            address: v_address,
+            size: width,
+            value,
+        });
+        Ok(())
     }
 
     /// Stores two bytes. This method takes virtual address and translates
@@ -390,16 +715,48 @@ impl Mmu {
             .map(|p_address| self.memory.validate_address(p_address))
     }
 
-    pub fn reserve(&mut self, core: u32, p_address: u32) {
-        self.memory.reserve(core, p_address)
-    }
-
-    pub fn clear_reservation(&mut self, core: u32, p_address: u32) -> bool {
-        self.memory.clear_reservation(core, p_address)
+    /// Translates a virtual address for an access by the CPU, raising a page
+    /// fault if it isn't mapped and, when physical addresses are being checked,
+    /// an access fault if `Memory::invalid_access` asks for one.
+    fn translate_checked(
+        &self,
+        v_address: u32,
+        access_type: &MemoryAccessType,
+    ) -> Result<u32, Trap> {
+        let (page_fault, access_fault) = match access_type {
+            MemoryAccessType::Execute => (
+                TrapType::InstructionPageFault,
+                TrapType::InstructionAccessFault,
+            ),
+            MemoryAccessType::Read | MemoryAccessType::DontCare => {
+                (TrapType::LoadPageFault, TrapType::LoadAccessFault)
+            }
+            MemoryAccessType::Write => (TrapType::StorePageFault, TrapType::StoreAccessFault),
+        };
+        let p_address = self
+            .translate_address(v_address, access_type)
+            .or_else(|()| match self.memory.page_fault(v_address) {
+                true => self.translate_address(v_address, access_type),
+                false => Err(()),
+            })
+            .map_err(|()| Trap {
+                trap_type: page_fault,
+                value: v_address,
+            })?;
+        if self.check_physical
+            && !self.memory.validate_address(p_address)
+            && self.memory.invalid_access(p_address, self.pc, access_type)
+        {
+            return Err(Trap {
+                trap_type: access_fault,
+                value: v_address,
+            });
+        }
+        Ok(p_address)
     }
 
     fn translate_address(&self, v_address: u32, access_type: &MemoryAccessType) -> Result<u32, ()> {
-        if let Some(address) = self.memory.translate(v_address) {
+        if let Some(address) = self.memory.translate(v_address, access_type) {
             return Ok(address);
         }
         if let AddressingMode::None = self.addressing_mode {
@@ -438,8 +795,23 @@ impl Mmu {
                     }
                 }
                 PrivilegeMode::User | PrivilegeMode::Supervisor => {
+                    let vpn = address >> 12;
+                    let slot = &self.tlb[tlb_index(self.asid, vpn)];
+                    if let Some(entry) = slot.get() {
+                        if entry.matches(self.asid, vpn) && entry.permits(access_type) {
+                            return Ok(entry.ppn << 12 | (address & 0xfff));
+                        }
+                    }
                     let vpns = [(address >> 12) & 0x3ff, (address >> 22) & 0x3ff];
-                    self.traverse_page(address, 1, self.ppn, &vpns, access_type)
+                    let (p_address, pte) =
+                        self.traverse_page(address, 1, self.ppn, &vpns, access_type)?;
+                    slot.set(Some(TlbEntry {
+                        asid: self.asid,
+                        vpn,
+                        ppn: p_address >> 12,
+                        pte,
+                    }));
+                    Ok(p_address)
                 }
                 _ => Ok(address),
             },
@@ -453,7 +825,7 @@ impl Mmu {
         parent_ppn: u32,
         vpns: &[u32],
         access_type: &MemoryAccessType,
-    ) -> Result<u32, ()> {
+    ) -> Result<(u32, u32), ()> {
         assert!(self.addressing_mode == AddressingMode::SV32);
         let pagesize = 4096;
         let ptesize = 4;
@@ -482,23 +854,8 @@ impl Mmu {
             };
         }
 
-        // Leaf page found
-
-        if a == 0
-            || (match access_type {
-                MemoryAccessType::Write => d == 0,
-                _ => false,
-            })
-        {
-            let new_pte = pte
-                | (1 << 6)
-                | (match access_type {
-                    MemoryAccessType::Write => 1 << 7,
-                    _ => 0,
-                });
-            self.store_word_raw(pte_address, new_pte);
-        }
-
+        // Leaf page found. An access it doesn't permit faults without
+        // setting the A or D bits.
         match access_type {
             MemoryAccessType::Execute if x == 0 => {
                 return Err(());
@@ -512,6 +869,21 @@ impl Mmu {
             _ => {}
         };
 
+        let mut pte = pte;
+        if a == 0
+            || (match access_type {
+                MemoryAccessType::Write => d == 0,
+                _ => false,
+            })
+        {
+            pte |= (1 << 6)
+                | (match access_type {
+                    MemoryAccessType::Write => 1 << 7,
+                    _ => 0,
+                });
+            self.store_word_raw(pte_address, pte);
+        }
+
         let offset = v_address & 0xfff; // [11:0]
                                         // @TODO: Optimize
         let p_address = match level {
@@ -525,6 +897,6 @@ impl Mmu {
             _ => panic!(), // Shouldn't happen
         };
 
-        Ok(p_address)
+        Ok((p_address, pte))
     }
 }
@@ -0,0 +1,43 @@
+//! How `ecall` reaches whatever stands in for the kernel. A
+//! [`SyscallBackend`] decides what each syscall does: it can answer it
+//! directly, as a host shim does, pass it on to the guest's own trap
+//! handler, or stop the thread until the answer is ready.
+
+use alloc::boxed::Box;
+use core::any::Any;
+
+/// Whatever a backend needs to finish a syscall it suspended. The CPU never
+/// looks inside: [`Cpu::tick`](crate::cpu::Cpu::tick) hands it back in
+/// [`TickResult::Suspended`](crate::cpu::TickResult::Suspended), and the
+/// code that runs the CPU downcasts it to the type its backend made.
+pub type Suspension = Box<dyn Any + Send>;
+
+pub enum SyscallResult {
+    /// Return these values to the guest in `a0` through `a7`.
+    Ok([i32; 8]),
+
+    /// Stop the thread just past the `ecall`, with the syscall's arguments
+    /// still in its registers, until whoever runs the CPU has the result.
+    Suspend(Suspension),
+
+    /// End the whole process with this exit code
+    Terminate(u32),
+
+    /// End the calling thread, which returns this value to whoever joins it
+    ExitThread(u32),
+
+    /// Pass the exception to the CPU
+    Continue,
+}
+
+impl From<[i32; 8]> for SyscallResult {
+    fn from(args: [i32; 8]) -> Self {
+        SyscallResult::Ok(args)
+    }
+}
+
+/// What the CPU calls on `ecall`.
+pub trait SyscallBackend {
+    /// Handle the syscall whose arguments are in `a0` through `a7`.
+    fn syscall(&self, args: [i32; 8]) -> SyscallResult;
+}
@@ -0,0 +1,25 @@
+//! Make arbitrary syscalls, as laid out in `yove::xous::fuzz`, and fail if
+//! the shim panics or leaves the page tables inconsistent. Run with:
+//!
+//!   cargo +nightly fuzz run syscalls
+//!
+//! and copy anything it finds into `tests/fuzz/` once it's fixed.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use yove::xous::{fuzz, MachineBuilder};
+
+/// None of the program runs, but a machine needs one to start.
+const PROGRAM: &[u8] = include_bytes!("../../tests/guests/exit.elf");
+
+fuzz_target!(|input: &[u8]| {
+    // The least RAM there can be, to keep each run quick
+    let machine = MachineBuilder::new()
+        .memory_size(1024 * 1024)
+        .build(PROGRAM)
+        .unwrap();
+    if let Err(error) = fuzz::run(&machine, input) {
+        panic!("{}", error);
+    }
+});
@@ -0,0 +1,127 @@
+//! The error type shared by everything that can stop a `Machine`.
+
+use riscv_cpu::cpu::Trap;
+
+use crate::xous::abuse::Abuse;
+use crate::xous::LoadError;
+
+/// Why a `Machine` couldn't be built or couldn't keep running.
+#[derive(Debug, thiserror::Error)]
+pub enum YoveError {
+    /// The program couldn't be loaded, or a new thread couldn't be started.
+    #[error("couldn't load program: {0}")]
+    Load(#[from] LoadError),
+
+    /// A guest thread took a trap that the emulator has no handler for.
+    #[error("thread {tid} took an unhandled trap at pc {pc:08x}")]
+    Trap {
+        tid: i32,
+        pc: u32,
+        #[source]
+        trap: Trap,
+    },
+
+    /// A guest thread misused a syscall, and the abuse policy for that kind
+    /// of misuse is to end the process.
+    #[error("thread {tid} misused a syscall ({abuse}): {detail}")]
+    Abuse {
+        tid: i32,
+        abuse: Abuse,
+        detail: String,
+    },
+
+    /// A guest thread made a syscall that the emulator doesn't implement.
+    #[error("thread {tid} made unhandled syscall {number} with arguments {args:x?}")]
+    Syscall {
+        tid: i32,
+        number: i32,
+        args: [i32; 7],
+    },
+
+    /// A thread waited longer than the timeout set with
+    /// `MachineBuilder::response_timeout` for a service to respond to it.
+    #[error("thread {tid} at pc {pc:08x} waited more than {timeout_ms} ms for {service} to respond to opcode {opcode}")]
+    Hang {
+        timeout_ms: u64,
+        tid: i32,
+        pc: u32,
+        service: String,
+        opcode: u32,
+    },
+
+    /// The program stopped petting the watchdog enabled with
+    /// `MachineBuilder::watchdog`. `tid`, `pc`, and `registers` are the state
+    /// of the thread that noticed.
+    #[error("watchdog expired after {timeout_ms} ms without being petted, with thread {tid} at pc {pc:08x}")]
+    Watchdog {
+        timeout_ms: u64,
+        tid: i32,
+        pc: u32,
+        registers: Box<[i32; 32]>,
+    },
+
+    /// The power went out part way through a write to the flash, at a
+    /// fault added with `MachineBuilder::flash_fault`.
+    #[error("power lost while writing flash offset {offset:#x}")]
+    PowerLoss { offset: u32 },
+
+    /// A return went somewhere other than the address after the call it
+    /// matches, with the shadow stack enabled by `MachineBuilder::shadow_stack`.
+    #[error("thread {tid} returned from pc {pc:08x} to {actual:08x} instead of {expected:08x}")]
+    ShadowStack {
+        tid: i32,
+        pc: u32,
+        expected: u32,
+        actual: u32,
+    },
+
+    /// The emulator panicked while running the main guest thread. Panics on
+    /// other threads only stop that thread, and are listed by
+    /// `Machine::thread_faults`.
+    #[error("emulator panicked while running thread {tid} at pc {pc:08x}: {message}")]
+    Panic { tid: i32, pc: u32, message: String },
+
+    /// A thread was asked to step that doesn't exist, or that runs on its
+    /// own host thread rather than being driven by `Machine::step`.
+    #[error("thread {tid} isn't one that can be stepped")]
+    UnknownThread { tid: i32 },
+
+    /// `Machine::patch` or `Machine::read_memory` was given a range that
+    /// isn't all mapped.
+    #[error("{address:08x} isn't mapped")]
+    Unmapped { address: u32 },
+
+    /// `Machine::patch_symbol` was given more bytes than the function has.
+    #[error("a {patch} byte patch doesn't fit in {symbol}, which is {size} bytes")]
+    PatchTooLarge {
+        symbol: String,
+        size: u32,
+        patch: usize,
+    },
+
+    /// `Machine::seek` was asked to go back to an instruction count the
+    /// machine has already passed, and the machine can't be built again.
+    #[error("can't seek back to instruction {instructions}, {retired} have already run")]
+    SeekBackwards { retired: u64, instructions: u64 },
+
+    /// `Snapshot::restore` ran the program again, but it exited or stopped
+    /// for good before reaching the instruction the snapshot was taken at.
+    #[error(
+        "the snapshot was taken at instruction {instructions}, but the program got no further than {reached}"
+    )]
+    Restore { instructions: u64, reached: u64 },
+
+    /// The guest panicked, caught as enabled with
+    /// `MachineBuilder::catch_guest_panics`.
+    #[error("thread {} panicked at pc {:08x}: {}", .0.tid, .0.pc, .0.message)]
+    GuestPanic(Box<crate::xous::guest_panic::GuestPanic>),
+
+    /// A breakpoint script enabled with `MachineBuilder::breakpoint_script`
+    /// ran a `stop` action.
+    #[error("thread {tid} stopped by a breakpoint script at pc {pc:08x}")]
+    ScriptStop { tid: i32, pc: u32 },
+
+    /// Reading the program or writing results failed.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,12 @@
+pub mod error;
+pub mod logger;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod selftest;
+pub mod xous;
+
+pub use error::YoveError;
+
+#[cfg(target_arch = "wasm32")]
+mod wasm;
@@ -0,0 +1,114 @@
+//! The emulator's own diagnostics, as opposed to anything the guest prints.
+//! Everything in the library logs through the `log` crate, and this logger
+//! writes it to stderr with a `[yove ...]` prefix so that it can't be
+//! mistaken for guest output, which is written without one.
+
+use std::io::Write;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// The filter used when none is given.
+pub const DEFAULT_FILTER: &str = "info";
+
+/// Which messages to write, parsed from a filter such as
+/// `warn,yove::xous::services=debug`: a default level, then levels for
+/// modules and everything under them. The longest matching module wins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl std::str::FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let level = |level: &str| {
+            level
+                .parse::<LevelFilter>()
+                .map_err(|_| format!("invalid log level {:?} in {:?}", level, spec))
+        };
+        let mut filter = LogFilter {
+            default: LevelFilter::Info,
+            modules: vec![],
+        };
+        for directive in spec.split(',').filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, module_level)) => filter
+                    .modules
+                    .push((module.to_owned(), level(module_level)?)),
+                None => filter.default = level(directive)?,
+            }
+        }
+        // Check the most specific modules first
+        filter
+            .modules
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(filter)
+    }
+}
+
+impl LogFilter {
+    /// The most verbose level written for messages from `target`.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level written for any module.
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+struct Logger {
+    filter: LogFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        };
+        let mut stderr = std::io::stderr().lock();
+        writeln!(
+            stderr,
+            "[yove {} {}] {}",
+            level,
+            record.target(),
+            record.args()
+        )
+        .ok();
+    }
+
+    fn flush(&self) {
+        std::io::stderr().flush().ok();
+    }
+}
+
+/// Write the library's log messages that `filter` lets through to stderr.
+/// This can only be done once per process.
+pub fn init(filter: LogFilter) -> Result<(), log::SetLoggerError> {
+    log::set_max_level(filter.max_level());
+    log::set_boxed_logger(Box::new(Logger { filter }))
+}
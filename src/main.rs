@@ -1,20 +1,971 @@
-mod xous;
+use std::io::{Read, Write};
+use yove::logger::{LogFilter, DEFAULT_FILTER};
+#[cfg(feature = "png")]
+use yove::xous::framebuffer::Screenshot;
+use yove::xous::{
+    abuse,
+    audio::{AudioSink, WavWriter, CODEC_RATE},
+    branch_stats::DEFAULT_BTB_ENTRIES,
+    breakpoint_script::BreakpointScript,
+    cfg::CfgFormat,
+    counters::CounterPolicy,
+    flash::{Flash, DEFAULT_FLASH_SIZE},
+    guest_panic::DEFAULT_PANIC_SYMBOLS,
+    heatmap::HeatmapFormat,
+    keyboard::KeyScript,
+    profiler::ProfileFormat,
+    snapshot::Snapshot,
+    trace::parse_csr,
+    Machine, MachineBuilder, MachineEvent, MmuFormat,
+};
+use yove::YoveError;
 
-use std::io::Read;
-use xous::Machine;
+/// Default number of instructions between profiler samples.
+const DEFAULT_PROFILE_INTERVAL: u64 = 10_000;
+
+/// Default number of instructions kept for `--vcd`, `--ctf`, and `--execution-trace`.
+const DEFAULT_EXECUTION_LIMIT: usize = 1_000_000;
+
+fn usage(program_name: &str) -> ! {
+    eprintln!(
+        "Usage: {} [options] <target-program> [args...]\n       \
+                {} selftest\n       \
+                {} resume <image>\n\
+         Check that the emulator works on this platform by running the riscv-tests\n\
+         ISA suite and checks of the decoder, MMU, and LR/SC, or carry on from an\n\
+         image written by --suspend-to, with the options it was written with.\n\
+         Options:\n  \
+           --inject-fault <site>[,<condition>...]:<fault>[:<probability>[:<after-ms>]]\n      \
+               Inject faults into syscalls. <site> is send, alloc, syscall, or the\n      \
+               name of one syscall, such as MapMemory. <condition> is nth=<n> for\n      \
+               only the nth call matched, or size><bytes> for only calls mapping or\n      \
+               adding more than that. <fault> is queue-full, drop, oom, delay=<ms>,\n      \
+               or error=<name or number>, such as error=OutOfMemory.\n  \
+           --flash <file>\n      \
+               Start the flash with the image in <file>, if it exists, and save the\n      \
+               flash back to it when the program stops, even if the power was lost.\n  \
+           --flash-fault [program:|erase:]<fault>@<offset> | <fault>[:<probability>]\n      \
+               Inject a fault into flash writes, the first time one reaches <offset>\n      \
+               or at random, in programs or erases only if prefixed. <fault> is\n      \
+               bit-flip[=<bit>], torn-write, or power-loss.\n  \
+           --seed <n>\n      \
+               Seed everything random about the run: the TRNG server, the fault\n      \
+               injector, and the layout. Without it, a seed is picked and printed.\n  \
+           --fault-seed <n>\n      \
+               Seed the fault injector to reproduce an earlier run.\n  \
+           --randomize-layout\n      \
+               Place the heap, stack, and mapped memory at random addresses.\n  \
+           --layout-seed <n>\n      \
+               Seed the layout randomization to reproduce an earlier run.\n  \
+           --profile <file>\n      \
+               Write a sampling profile of the program on exit. Files ending in .pb or\n      \
+               .pprof are written in pprof format, anything else as `perf script` text.\n  \
+           --profile-interval <n>\n      \
+               Sample every <n> instructions (default {}).\n  \
+           --heatmap <file>\n      \
+               Count accesses to each page of memory and write them on exit as .csv,\n      \
+               .json, or (if built with the `png` feature) .png.\n  \
+           --trace <file>\n      \
+               Act on requests the program writes to the hypercall CSR (0x8c0), and\n      \
+               write the markers, register dumps, and coverage they produce on exit,\n      \
+               along with when each thread was created, blocked, and unblocked.\n  \
+           --chrome-trace <file>\n      \
+               Write when each thread was running or blocked, and why, as a Chrome\n      \
+               trace for Perfetto or chrome://tracing.\n  \
+           --vcd <file>\n      \
+               Record every instruction and write the PC, privilege level, and any\n      \
+               --trace-csr registers of each thread as a VCD waveform on exit.\n  \
+           --ctf <directory>\n      \
+               Record every instruction and write it as a CTF trace for Trace Compass.\n  \
+           --execution-trace <file>\n      \
+               Record every instruction and write it as text, with the registers and\n      \
+               --trace-csr registers each one changed.\n  \
+           --log-commits <file>\n      \
+               Write a line for every instruction that retires, in the format of\n      \
+               spike's --log-commits, with the registers and CSRs it wrote and the\n      \
+               memory it accessed.\n  \
+           --trace-csr <csr>[,<csr>...]\n      \
+               CSRs to record for --vcd, --ctf, and --execution-trace, by name (satp,\n      \
+               sepc, ...) or number.\n  \
+           --trace-limit <n>\n      \
+               Keep only the last <n> recorded instructions (default {}).\n  \
+           --cfg-out <file>\n      \
+               Record the basic blocks, branches, and calls that run and write them on\n      \
+               exit as a .dot graph or .json.\n  \
+           --branch-stats <file>\n      \
+               Model a VexRiscv's branch predictor and prefetch buffer, and write the\n      \
+               mispredictions and fetch misses of each function on exit.\n  \
+           --btb-entries <n>\n      \
+               Give the modelled branch target buffer <n> entries (default {}).\n  \
+           --dump-mmu <file>\n      \
+               Write the program's page tables on exit as .txt, .json, or a .dot\n      \
+               graph.\n  \
+           --screenshot <file>\n      \
+               Emulate the display and write what it shows on exit as a PNG. Needs\n      \
+               the `png` feature.\n  \
+           --screenshot-interval <ms>\n      \
+               Also capture the display every <ms> milliseconds, written next to the\n      \
+               --screenshot file with the time appended to its name.\n  \
+           --strict-memory\n      \
+               Raise an access fault when the program touches a physical address\n      \
+               outside of RAM, instead of reading zero and ignoring writes.\n  \
+           --uninitialized-reads\n      \
+               Warn when the program reads memory that it mapped but never wrote.\n      \
+               With --strict-memory, such reads raise an access fault instead.\n  \
+           --w-xor-x\n      \
+               Never let a page be both writable and executable, and fault on\n      \
+               writes to the program's code or jumps into its data.\n  \
+           --read-only-sections\n      \
+               Map the program's code and constants read-only, and fault on writes\n      \
+               to them, naming the symbol written over.\n  \
+           --shadow-stack\n      \
+               Keep a shadow stack of return addresses and stop the program as soon\n      \
+               as a return goes anywhere other than where it was called from.\n  \
+           --shadow-stack-allow <symbol>|<address>[-<end>]\n      \
+               Don't check jumps made by this function or address range, such as\n      \
+               longjmp or a context switch. Implies --shadow-stack.\n  \
+           --catch-panics\n      \
+               Stop as soon as the program panics, and print the panic message, a\n      \
+               backtrace, and with --execution-trace the last instructions it ran.\n  \
+           --panic-symbol <name>\n      \
+               Also catch panics when the program reaches the function <name>.\n      \
+               Implies --catch-panics.\n  \
+           --breakpoint-script <path>\n      \
+               Take the actions in <path> whenever the program reaches a function or\n      \
+               address it names: turn --execution-trace recording on or off, dump\n      \
+               memory to stderr, set a register, or stop.\n  \
+           --memory-size <mb>\n      \
+               Give the program <mb> megabytes of RAM, up to 768 (default 16).\n  \
+           --harts <n>\n      \
+               Run the program's threads on <n> host threads rather than one each.\n  \
+           --memory-limit <mb>\n      \
+               Allocate at most <mb> megabytes of RAM to the program at once, failing\n      \
+               allocations past that with OutOfMemory. Pages keep their host memory\n      \
+               once written, even after the program unmaps them.\n  \
+           --thread-limit <n>\n      \
+               Run at most <n> of the program's threads at once, failing CreateThread\n      \
+               past that with ThreadNotAvailable.\n  \
+           --vlen <bits>\n      \
+               Give the CPU a vector unit with <bits>-bit registers, implementing\n      \
+               vsetvli, unit-stride loads and stores, and integer arithmetic from\n      \
+               Zve32x. Without it, vector instructions are illegal.\n  \
+           --permissive-csrs\n      \
+               Let the program read and write CSRs the CPU doesn't implement, which\n      \
+               otherwise raise an illegal instruction exception.\n  \
+           --hints <ignore|report|trap>\n      \
+               What to do with compressed HINTs, such as c.li to x0: run them as\n      \
+               no-ops (the default), run them and warn about each, or raise an\n      \
+               illegal instruction exception.\n  \
+           --counters <native|deterministic|trap>\n      \
+               What the program reads from the cycle, time, and instret counters:\n      \
+               what the emulated hart counts (the default), values that follow only\n      \
+               the instructions each thread has run, or an illegal instruction.\n  \
+           --on-abuse <kind>=<warn|trap|kill-thread|kill-process>\n      \
+               What to do when the program misuses a syscall: warn and fail the\n      \
+               call, raise an exception, end the thread, or end the process. <kind>\n      \
+               is invalid-argument, unmapped-lend, bad-connection, unknown-syscall,\n      \
+               unknown-opcode, invalid-message, or all. Unknown syscalls end the process by default,\n      \
+               and the rest warn. May be given more than once.\n  \
+           --megapages\n      \
+               Map large regions of memory with 4MB megapages where possible.\n  \
+           --demand-paging\n      \
+               Allocate stack and heap pages when the program first touches them.\n  \
+           --server-queue-depth <n>\n      \
+               Fail messages to a server holding <n> unanswered messages with\n      \
+               ServerQueueFull (default 32).\n  \
+           --allow-any-machine\n      \
+               Load the program even if its ELF header says it isn't for RISC-V.\n  \
+           --list-names\n      \
+               Print every name the program registered with the name server or\n      \
+               connected to through it when it exits.\n  \
+           --stack-usage\n      \
+               Print how much of its stack each thread used at most when the\n      \
+               program exits.\n  \
+           --log <level>[,<module>=<level>...]\n      \
+               Which of the emulator's own messages to print to stderr, prefixed with\n      \
+               [yove], for example warn,yove::xous::services=debug (default {}).\n      \
+               Levels are off, error, warn, info, debug, and trace.\n  \
+           --metrics <address>\n      \
+               Serve health metrics in the Prometheus text format at /metrics on\n      \
+               <address>, such as 127.0.0.1:9100, while the program runs.\n  \
+           --message-stats\n      \
+               Print how many messages the program sent to each opcode of each server,\n      \
+               and how long they took to be answered, when it exits.\n  \
+           --strace\n      \
+               Print every syscall the program makes, what it returned, and how long\n      \
+               it took to stderr.\n  \
+           --verbose-load\n      \
+               Print the address, size, and permissions of every segment and section\n      \
+               loaded, the sections in each segment, and how long loading took.\n  \
+           --time-scale <factor>\n      \
+               Run the program's clock <factor> times as fast as real time, so that\n      \
+               2 halves every timeout and 0 stops the clock.\n  \
+           --start-time <ms>\n      \
+               Start the program's clock at <ms> rather than zero, such as where an\n      \
+               earlier run left off.\n  \
+           --watchdog <ms>\n      \
+               Stop the program and dump its registers if it goes more than <ms>\n      \
+               milliseconds without petting the watchdog through the ticktimer.\n  \
+           --ec <file>\n      \
+               Report the battery, charger, and wifi status in <file> through the\n      \
+               COM server, one <key> = <value> per line.\n  \
+           --allow-host-exec [<name>=]<program>\n      \
+               Let the program run <program> on the host, with arguments of its\n      \
+               choosing, by asking the yove-host-exec server for <name>, which is\n      \
+               the file name of <program> if not given. Nothing else may be run,\n      \
+               and every request is logged. May be given more than once.\n  \
+           --usb-serial <path>\n      \
+               Connect the USB serial port to <path>, such as the other end of a pty.\n  \
+           --usb-keyboard <path>\n      \
+               Write what the program types as a USB keyboard to <path>.\n  \
+           --key-script <path>\n      \
+               Press keys on the keyboard as the script in <path> says, typing text\n      \
+               and waiting for the program to log messages along the way.\n  \
+           --wav <file>[:<rate>]\n      \
+               Record the audio the program plays through the codec server to a WAV\n      \
+               file at <rate> Hz (default 8000).\n  \
+           --audio\n      \
+               Play the program's audio through the host's default output device.\n      \
+               Needs the `audio` feature.\n  \
+           --bridge <name>\n      \
+               Pass messages to the server called <name> on to the device given by\n      \
+               --bridge-device rather than emulating it. May be given more than once.\n  \
+           --bridge-device <path>\n      \
+               The serial port of a device running the yove bridge server.\n  \
+           --response-timeout <ms>\n      \
+               Stop the program if a thread waits more than <ms> milliseconds for a\n      \
+               service to respond, saying which service and opcode it was waiting on.\n  \
+           --suspend-to <image>\n      \
+               Run the program one step at a time, and write <image> whenever it\n      \
+               suspends through the suspend/resume manager, to be carried on from\n      \
+               with `resume`. Counters are deterministic unless --counters is given.\n      \
+               Restoring runs the program again to where it suspended, so the run\n      \
+               must not depend on the host, and no host stream or flash may be used.\n  \
+           --exit-on-suspend\n      \
+               Stop once the first image has been written by --suspend-to.",
+        program_name,
+        program_name,
+        program_name,
+        DEFAULT_PROFILE_INTERVAL,
+        DEFAULT_EXECUTION_LIMIT,
+        DEFAULT_BTB_ENTRIES,
+        DEFAULT_FILTER
+    );
+    std::process::exit(1);
+}
+
+/// Write the display as `path`, and the screenshots taken on the timer next
+/// to it with the time they were taken appended to the name.
+#[cfg(feature = "png")]
+fn write_screenshots(xous: &Machine, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let write = |path: &str, screenshot: &Screenshot| {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(path)?);
+        screenshot.write_png(&mut output)
+    };
+    let stem = path.strip_suffix(".png").unwrap_or(path);
+    for (elapsed_ms, screenshot) in xous.screenshots() {
+        write(&format!("{}-{}.png", stem, elapsed_ms), &screenshot)?;
+    }
+    if let Some(screenshot) = xous.screenshot() {
+        write(path, &screenshot)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "png"))]
+fn write_screenshots(_xous: &Machine, _path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Err("yove was built without the `png` feature".into())
+}
+
+#[cfg(feature = "audio")]
+fn live_audio() -> Result<Box<dyn AudioSink>, Box<dyn std::error::Error>> {
+    Ok(Box::new(yove::xous::audio::LiveOutput::open()?))
+}
+
+#[cfg(not(feature = "audio"))]
+fn live_audio() -> Result<Box<dyn AudioSink>, Box<dyn std::error::Error>> {
+    Err("yove was built without the `audio` feature".into())
+}
+
+/// Open `path` to be read without blocking, where that's supported, so that
+/// the thread reading it can be stopped when the program exits.
+fn open_nonblocking(path: &str) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    // The value of O_NONBLOCK, which std doesn't name
+    #[cfg(target_os = "linux")]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, 0o4000);
+    #[cfg(target_os = "macos")]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, 0x4);
+    options.open(path)
+}
+
+/// Run `yove selftest`, printing each check as it's reported and exiting
+/// with 1 if any of them failed.
+fn selftest() -> ! {
+    let checks = yove::selftest::run();
+    let mut failed = 0;
+    for check in &checks {
+        match &check.result {
+            Ok(()) => println!("ok    {}", check.name),
+            Err(reason) => {
+                println!("FAIL  {}: {}", check.name, reason);
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "selftest: {} passed, {} failed",
+        checks.len() - failed,
+        failed
+    );
+    std::process::exit((failed > 0) as i32);
+}
+
+/// Write `snapshot` to `path`, followed by the `options` yove was run with,
+/// so that `yove resume` can build the machine the same way.
+fn write_image(path: &str, snapshot: &Snapshot, options: &[String]) -> std::io::Result<()> {
+    let mut output = std::io::BufWriter::new(std::fs::File::create(path)?);
+    snapshot.write(&mut output)?;
+    output.write_all(&(options.len() as u32).to_le_bytes())?;
+    for option in options {
+        output.write_all(&(option.len() as u32).to_le_bytes())?;
+        output.write_all(option.as_bytes())?;
+    }
+    output.flush()
+}
+
+/// Read an image written by `write_image()`.
+fn read_image(path: &str) -> Result<(Snapshot, Vec<String>), Box<dyn std::error::Error>> {
+    let mut input = std::io::BufReader::new(std::fs::File::open(path)?);
+    let snapshot = Snapshot::read(&mut input)?;
+    let mut word = [0; 4];
+    input.read_exact(&mut word)?;
+    let mut options = vec![];
+    for _ in 0..u32::from_le_bytes(word) {
+        input.read_exact(&mut word)?;
+        let mut option = vec![];
+        (&mut input)
+            .take(u32::from_le_bytes(word) as u64)
+            .read_to_end(&mut option)?;
+        options.push(String::from_utf8(option)?);
+    }
+    Ok((snapshot, options))
+}
+
+/// Drive the machine with `step()`, which runs the threads in the same
+/// order every time, writing an image to `path` whenever the guest
+/// suspends. Returns 0 after the first one if `exit` is set.
+fn run_suspending(
+    xous: &mut Machine,
+    path: &str,
+    exit: bool,
+    options: &[String],
+) -> Result<u32, YoveError> {
+    // A restored machine has already made the suspend it was written at
+    let mut suspends = xous.suspends();
+    loop {
+        match xous.step()? {
+            MachineEvent::Exited(code) => return Ok(code),
+            MachineEvent::Idle => std::thread::sleep(std::time::Duration::from_millis(1)),
+            MachineEvent::Running | MachineEvent::Stopped { .. } => {}
+        }
+        if xous.suspends() != suspends {
+            suspends = xous.suspends();
+            let snapshot = xous.snapshot().expect("checked before running");
+            write_image(path, &snapshot, options)?;
+            eprintln!(
+                "Suspended after {} instructions, written to {}",
+                snapshot.instructions(),
+                path
+            );
+            if exit {
+                return Ok(0);
+            }
+        }
+    }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = std::env::args().collect::<Vec<_>>();
-    let Some(target_program) = args.get(1) else {
-        return Err(format!("Usage: {} <target-program>", args.first().expect("jurubas")).into());
+    let mut args = std::env::args();
+    let program_name = args.next().unwrap_or_else(|| "yove".to_owned());
+    let mut options: Vec<String> = args.collect();
+    if options.first().map(String::as_str) == Some("selftest") {
+        selftest();
+    }
+    let mut resumed = None;
+    if options.first().map(String::as_str) == Some("resume") {
+        if options.len() != 2 {
+            usage(&program_name);
+        }
+        let (snapshot, stored) = read_image(&options[1])?;
+        resumed = Some(snapshot);
+        options = stored;
+    }
+    let mut args = options.clone().into_iter().peekable();
+
+    let mut builder = MachineBuilder::new();
+    let mut target_program = None;
+    let mut profile_path = None;
+    let mut profile_interval = DEFAULT_PROFILE_INTERVAL;
+    let mut heatmap = None;
+    let mut trace_path = None;
+    let mut chrome_trace_path = None;
+    let mut vcd_path = None;
+    let mut ctf_path = None;
+    let mut execution_trace_path = None;
+    let mut trace_csrs = Vec::new();
+    let mut trace_limit = DEFAULT_EXECUTION_LIMIT;
+    let mut shadow_stack = None;
+    let mut panic_symbols: Option<Vec<String>> = None;
+    let mut cfg = None;
+    let mut branch_stats_path = None;
+    let mut btb_entries = DEFAULT_BTB_ENTRIES;
+    let mut mmu = None;
+    let mut screenshot_path = None;
+    let mut list_names = false;
+    let mut stack_usage = false;
+    let mut verbose_load = false;
+    let mut bridged = Vec::new();
+    let mut flash_path = None;
+    let mut seed = None;
+    let mut bridge_device = None;
+    let mut counters = None;
+    let mut suspend_to = None;
+    let mut exit_on_suspend = false;
+    let mut log_filter: LogFilter = DEFAULT_FILTER.parse()?;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--inject-fault" => {
+                let spec = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.fault(spec.parse()?);
+            }
+            "--flash" => flash_path = Some(args.next().unwrap_or_else(|| usage(&program_name))),
+            "--flash-fault" => {
+                let spec = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.flash_fault(spec.parse()?);
+            }
+            "--seed" => {
+                let value: u64 = args
+                    .next()
+                    .unwrap_or_else(|| usage(&program_name))
+                    .parse()?;
+                seed = Some(value);
+                builder = builder.seed(value);
+            }
+            "--fault-seed" => {
+                let seed = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.fault_seed(seed.parse()?);
+            }
+            "--randomize-layout" => builder = builder.randomize_layout(),
+            "--layout-seed" => {
+                let seed = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.layout_seed(seed.parse()?);
+            }
+            "--profile" => {
+                profile_path = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--profile-interval" => {
+                let interval = args.next().unwrap_or_else(|| usage(&program_name));
+                profile_interval = interval.parse()?;
+            }
+            "--heatmap" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                heatmap = Some((HeatmapFormat::from_path(&path)?, path));
+            }
+            "--cfg-out" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                cfg = Some((CfgFormat::from_path(&path)?, path));
+            }
+            "--branch-stats" => {
+                branch_stats_path = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--btb-entries" => {
+                let entries = args.next().unwrap_or_else(|| usage(&program_name));
+                btb_entries = entries.parse()?;
+            }
+            "--dump-mmu" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                mmu = Some((MmuFormat::from_path(&path)?, path));
+            }
+            "--screenshot" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                if cfg!(not(feature = "png")) {
+                    return Err("yove was built without the `png` feature".into());
+                }
+                builder = builder.framebuffer();
+                screenshot_path = Some(path);
+            }
+            "--screenshot-interval" => {
+                let interval_ms = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.screenshot_interval(interval_ms.parse()?);
+            }
+            "--strict-memory" => builder = builder.strict_memory(),
+            "--w-xor-x" => builder = builder.enforce_w_xor_x(),
+            "--read-only-sections" => builder = builder.read_only_sections(),
+            "--log" => {
+                let spec = args.next().unwrap_or_else(|| usage(&program_name));
+                log_filter = spec.parse()?;
+            }
+            "--metrics" => {
+                let address = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.serve_metrics(std::net::TcpListener::bind(address)?);
+            }
+            "--message-stats" => builder = builder.message_stats(),
+            "--strace" => builder = builder.strace(),
+            "--list-names" => list_names = true,
+            "--stack-usage" => stack_usage = true,
+            "--verbose-load" => verbose_load = true,
+            "--memory-size" => {
+                let megabytes: u32 = args
+                    .next()
+                    .unwrap_or_else(|| usage(&program_name))
+                    .parse()?;
+                builder = builder.memory_size(megabytes.saturating_mul(1024 * 1024));
+            }
+            "--harts" => {
+                let count = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.harts(count.parse()?);
+            }
+            "--memory-limit" => {
+                let megabytes: u32 = args
+                    .next()
+                    .unwrap_or_else(|| usage(&program_name))
+                    .parse()?;
+                builder = builder.memory_limit(megabytes.saturating_mul(1024 * 1024));
+            }
+            "--thread-limit" => {
+                let count = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.thread_limit(count.parse()?);
+            }
+            "--vlen" => {
+                let vlen = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.vector(vlen.parse()?);
+            }
+            "--hints" => {
+                let policy = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.compressed_hints(policy.parse()?);
+            }
+            "--counters" => {
+                let policy = args.next().unwrap_or_else(|| usage(&program_name));
+                counters = Some(policy.parse()?);
+            }
+            "--on-abuse" => {
+                let rule = args.next().unwrap_or_else(|| usage(&program_name));
+                let (kinds, handler) = abuse::parse_rule(&rule)?;
+                for kind in kinds {
+                    builder = builder.on_abuse(kind, handler);
+                }
+            }
+            "--permissive-csrs" => builder = builder.permissive_csrs(),
+            "--megapages" => builder = builder.megapages(),
+            "--demand-paging" => builder = builder.demand_paging(),
+            "--server-queue-depth" => {
+                let depth = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.server_queue_depth(depth.parse()?);
+            }
+            "--allow-any-machine" => builder = builder.allow_any_machine(),
+            "--uninitialized-reads" => builder = builder.detect_uninitialized_reads(),
+            "--shadow-stack" => {
+                shadow_stack.get_or_insert_with(Vec::new);
+            }
+            "--shadow-stack-allow" => {
+                let allow = args.next().unwrap_or_else(|| usage(&program_name));
+                shadow_stack
+                    .get_or_insert_with(Vec::new)
+                    .push(allow.parse()?);
+            }
+            "--catch-panics" => {
+                panic_symbols.get_or_insert_with(Vec::new);
+            }
+            "--panic-symbol" => {
+                let name = args.next().unwrap_or_else(|| usage(&program_name));
+                panic_symbols.get_or_insert_with(Vec::new).push(name);
+            }
+            "--breakpoint-script" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                let script = std::fs::read_to_string(path)?;
+                builder = builder.breakpoint_script(BreakpointScript::parse(&script)?);
+            }
+            "--start-time" => {
+                let ms: u64 = args
+                    .next()
+                    .unwrap_or_else(|| usage(&program_name))
+                    .parse()?;
+                builder = builder.start_time_us(ms.saturating_mul(1000));
+            }
+            "--time-scale" => {
+                let scale: f64 = args
+                    .next()
+                    .unwrap_or_else(|| usage(&program_name))
+                    .parse()?;
+                if !(scale >= 0.0 && scale.is_finite()) {
+                    return Err(format!("invalid time scale {}", scale).into());
+                }
+                builder = builder.time_scale(scale);
+            }
+            "--watchdog" => {
+                let timeout_ms = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.watchdog(timeout_ms.parse()?);
+            }
+            "--ec" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.ec(std::fs::read_to_string(path)?.parse()?);
+            }
+            "--allow-host-exec" => {
+                let command = args.next().unwrap_or_else(|| usage(&program_name));
+                let (name, program) = match command.split_once('=') {
+                    Some((name, program)) => (name.to_owned(), program.to_owned()),
+                    None => {
+                        let name = std::path::Path::new(&command)
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .ok_or_else(|| format!("can't name host command {:?}", command))?;
+                        (name.to_owned(), command.clone())
+                    }
+                };
+                builder = builder.allow_host_exec(&name, program);
+            }
+            "--usb-serial" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                let port = std::fs::OpenOptions::new().write(true).open(&path)?;
+                builder = builder.usb_serial(Box::new(open_nonblocking(&path)?), Box::new(port));
+            }
+            "--usb-keyboard" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.usb_keyboard(Box::new(std::fs::File::create(path)?));
+            }
+            "--key-script" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                let script = std::fs::read_to_string(path)?;
+                builder = builder.key_script(KeyScript::parse(&script)?);
+            }
+            "--wav" => {
+                let spec = args.next().unwrap_or_else(|| usage(&program_name));
+                let (path, rate) = match spec.rsplit_once(':') {
+                    Some((path, rate)) => match rate.parse::<u32>() {
+                        Ok(rate) => (path, rate),
+                        Err(_) => (spec.as_str(), CODEC_RATE),
+                    },
+                    None => (spec.as_str(), CODEC_RATE),
+                };
+                if rate == 0 {
+                    return Err("invalid WAV rate 0".into());
+                }
+                builder = builder.audio(Box::new(WavWriter::create(path, rate)?));
+            }
+            "--audio" => builder = builder.audio(live_audio()?),
+            "--bridge" => {
+                bridged.push(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--bridge-device" => {
+                bridge_device = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--response-timeout" => {
+                let timeout_ms = args.next().unwrap_or_else(|| usage(&program_name));
+                builder = builder.response_timeout(timeout_ms.parse()?);
+            }
+            "--suspend-to" => {
+                suspend_to = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--exit-on-suspend" => exit_on_suspend = true,
+            "--trace" => {
+                trace_path = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--chrome-trace" => {
+                chrome_trace_path = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--vcd" => {
+                vcd_path = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--ctf" => {
+                ctf_path = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--log-commits" => {
+                let path = args.next().unwrap_or_else(|| usage(&program_name));
+                let output = std::io::BufWriter::new(std::fs::File::create(path)?);
+                builder = builder.log_commits(Box::new(output));
+            }
+            "--execution-trace" => {
+                execution_trace_path = Some(args.next().unwrap_or_else(|| usage(&program_name)));
+            }
+            "--trace-csr" => {
+                let csrs = args.next().unwrap_or_else(|| usage(&program_name));
+                for csr in csrs.split(',') {
+                    trace_csrs.push(parse_csr(csr)?);
+                }
+            }
+            "--trace-limit" => {
+                let limit = args.next().unwrap_or_else(|| usage(&program_name));
+                trace_limit = limit.parse()?;
+            }
+            "--" => {}
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option {}", arg);
+                usage(&program_name);
+            }
+            _ => {
+                target_program = Some(arg);
+                break;
+            }
+        }
+    }
+    let Some(target_program) = target_program else {
+        usage(&program_name);
     };
+    yove::logger::init(log_filter)?;
+
+    // Everything after the target program belongs to the target program
+    let mut guest_args = vec![target_program.clone()];
+    args.next_if_eq("--");
+    guest_args.extend(args);
 
     let mut std_tests = Vec::new();
-    std::fs::File::open(target_program)?.read_to_end(&mut std_tests)?;
+    if resumed.is_none() {
+        std::fs::File::open(&target_program)?.read_to_end(&mut std_tests)?;
+    }
 
-    let mut xous = Machine::new(&std_tests)?;
+    match (counters, &suspend_to) {
+        (Some(policy), _) => builder = builder.counters(policy),
+        (None, Some(_)) => builder = builder.counters(CounterPolicy::Deterministic),
+        (None, None) => {}
+    }
+    if exit_on_suspend && suspend_to.is_none() {
+        return Err("--exit-on-suspend needs a --suspend-to".into());
+    }
 
-    xous.run()?;
+    if profile_path.is_some() {
+        builder = builder.profile(profile_interval);
+    }
+    if heatmap.is_some() {
+        builder = builder.heatmap();
+    }
+    if trace_path.is_some() || chrome_trace_path.is_some() {
+        builder = builder.trace();
+    }
+    if cfg.is_some() {
+        builder = builder.control_flow_graph();
+    }
+    if branch_stats_path.is_some() {
+        builder = builder.branch_stats(btb_entries);
+    }
+    if let Some(allow) = shadow_stack {
+        builder = builder.shadow_stack(allow);
+    }
+    if let Some(extra) = panic_symbols {
+        let mut symbols: Vec<String> = DEFAULT_PANIC_SYMBOLS
+            .iter()
+            .map(|&name| name.to_owned())
+            .collect();
+        symbols.extend(extra);
+        builder = builder.catch_guest_panics(symbols);
+    }
+    if vcd_path.is_some() || ctf_path.is_some() || execution_trace_path.is_some() {
+        builder = builder.record_execution(trace_limit, trace_csrs);
+    }
+    if let Some(path) = &flash_path {
+        let flash = Flash::new(DEFAULT_FLASH_SIZE);
+        match std::fs::read(path) {
+            Ok(image) if flash.load(0, &image) => {}
+            Ok(_) => return Err(format!("{} is larger than the flash", path).into()),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        builder = builder.flash(std::sync::Arc::new(flash));
+    }
+    match (bridge_device, bridged.is_empty()) {
+        (Some(path), false) => {
+            let port = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)?;
+            builder = builder.bridge(bridged, Box::new(port.try_clone()?), Box::new(port));
+        }
+        (None, false) => return Err("--bridge needs a --bridge-device".into()),
+        (Some(_), true) => return Err("--bridge-device needs at least one --bridge".into()),
+        (None, true) => {}
+    }
 
-    Ok(())
+    let builder = builder.args(guest_args);
+    let mut xous = match &resumed {
+        Some(snapshot) => snapshot.restore(builder)?,
+        None => builder.build(&std_tests)?,
+    };
+    if suspend_to.is_some() && xous.snapshot().is_none() {
+        return Err("--suspend-to can't be used with flash or any host stream".into());
+    }
+    if verbose_load {
+        let info = xous.program_info();
+        for segment in &info.segments {
+            eprintln!(
+                "Segment {:08x}-{:08x} {}{}{} {:>8} bytes  {}",
+                segment.address,
+                segment.address + segment.size,
+                if segment.readable { 'r' } else { '-' },
+                if segment.writable { 'w' } else { '-' },
+                if segment.executable { 'x' } else { '-' },
+                segment.size,
+                segment.sections.join(" ")
+            );
+        }
+        for section in &info.sections {
+            eprintln!(
+                "Loaded {:08x}-{:08x} r{}{} {:>8} bytes  {}{}",
+                section.address,
+                section.address + section.size,
+                if section.writable { 'w' } else { '-' },
+                if section.executable { 'x' } else { '-' },
+                section.size,
+                section.name,
+                if section.zeroed { " (zeroed)" } else { "" }
+            );
+        }
+        eprintln!(
+            "Loaded {} sections, {} bytes, in {} us",
+            info.sections.len(),
+            info.sections
+                .iter()
+                .map(|section| section.size)
+                .sum::<u32>(),
+            info.load_us
+        );
+    }
+    if seed.is_none() {
+        eprintln!(
+            "Seed: {} (repeat the run with --seed {})",
+            xous.seed(),
+            xous.seed()
+        );
+    }
+
+    let result = match &suspend_to {
+        Some(path) => run_suspending(&mut xous, path, exit_on_suspend, &options),
+        None => xous.run(),
+    };
+
+    for access in xous.invalid_accesses() {
+        eprintln!(
+            "Thread {} at pc {:08x}: {:?} of {:08x}, which is outside of RAM",
+            access.tid, access.pc, access.access, access.address
+        );
+    }
+    if list_names {
+        for info in xous.names() {
+            let limit = info
+                .connection_limit
+                .map_or_else(|| "no limit".to_owned(), |limit| format!("limit {}", limit));
+            eprintln!(
+                "Name {:?}: server {:032x}, {} connections, {}{}",
+                info.name,
+                info.hash,
+                info.connections,
+                limit,
+                if info.registered {
+                    ""
+                } else {
+                    ", provided by yove"
+                }
+            );
+        }
+    }
+    if stack_usage {
+        for stats in xous.thread_stats() {
+            eprintln!(
+                "Thread {}: {} of {} bytes of stack ({:.1}%), lowest sp {:08x}",
+                stats.tid,
+                stats.stack_used(),
+                stats.stack.len(),
+                stats.stack_used() as f64 * 100.0 / stats.stack.len().max(1) as f64,
+                stats.lowest_sp
+            );
+        }
+    }
+    if let Some(stats) = xous.message_stats() {
+        stats.write(&mut std::io::stderr())?;
+    }
+    if let Some(log) = xous.commit_log() {
+        log.flush()?;
+    }
+    if let Some(path) = flash_path {
+        std::fs::write(path, xous.flash().image())?;
+    }
+    if let Err(YoveError::Watchdog { registers, .. }) = &result {
+        for (index, value) in registers.iter().enumerate() {
+            let name = riscv_cpu::cpu::Register::from_field(index as u32).name();
+            eprintln!("{:<4} = {:08x}", name, value);
+        }
+    }
+    if let Err(YoveError::GuestPanic(panic)) = &result {
+        eprintln!("Backtrace:");
+        for (index, frame) in panic.backtrace.iter().enumerate() {
+            match &frame.function {
+                Some((name, offset)) => {
+                    eprintln!("{:>4}: {:08x} {}+{:#x}", index, frame.address, name, offset)
+                }
+                None => eprintln!("{:>4}: {:08x}", index, frame.address),
+            }
+        }
+        if !panic.recent.is_empty() {
+            eprintln!("Last instructions:");
+            for step in &panic.recent {
+                eprintln!("  {:08x}", step.pc);
+            }
+        }
+    }
+    let exit_code = result?;
+
+    if let (Some(path), Some(profiler)) = (profile_path, xous.profiler()) {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        profiler.write(ProfileFormat::from_path(&path), &mut output)?;
+    }
+
+    if let Some((format, path)) = heatmap {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        xous.write_heatmap(format, &mut output)?;
+    }
+
+    if let (Some(path), Some(tracer)) = (trace_path, xous.tracer()) {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        tracer.write(&mut output)?;
+    }
+
+    if let (Some(path), Some(tracer)) = (chrome_trace_path, xous.tracer()) {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        tracer.write_chrome_trace(&mut output)?;
+    }
+
+    if let (Some((format, path)), Some(graph)) = (cfg, xous.control_flow_graph()) {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        graph.write(format, &mut output)?;
+    }
+
+    if let (Some(path), Some(stats)) = (branch_stats_path, xous.branch_stats()) {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        stats.write(&mut output)?;
+    }
+
+    if let Some((format, path)) = mmu {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        xous.dump_mmu(&mut output, format)?;
+    }
+
+    if let Some(path) = screenshot_path {
+        write_screenshots(&xous, &path)?;
+    }
+
+    if let (Some(path), Some(tracer)) = (vcd_path, xous.tracer()) {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        tracer.write_vcd(&mut output)?;
+    }
+
+    if let (Some(path), Some(tracer)) = (ctf_path, xous.tracer()) {
+        tracer.write_ctf(std::path::Path::new(&path))?;
+    }
+
+    if let (Some(path), Some(tracer)) = (execution_trace_path, xous.tracer()) {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        tracer.write_execution(&mut output)?;
+    }
+
+    std::process::exit(exit_code as i32);
 }
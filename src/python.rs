@@ -0,0 +1,207 @@
+//! Bindings for scripting the emulator from Python, such as from a notebook
+//! running a fault injection campaign. Build with `--features python` and
+//! package the library with maturin, which adds pyo3's `extension-module`
+//! feature, then:
+//!
+//! ```python
+//! import yove
+//! machine = yove.Machine(open("app.elf", "rb").read(), seed=1234)
+//! machine.add_breakpoint(machine.symbol("main"))
+//! machine.on_breakpoint(lambda tid, pc: print(hex(pc)))
+//! print(machine.run())
+//! ```
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::xous::{self, MachineBuilder, MachineEvent};
+
+create_exception!(yove, YoveError, PyException);
+
+impl From<crate::YoveError> for PyErr {
+    fn from(error: crate::YoveError) -> Self {
+        YoveError::new_err(error.to_string())
+    }
+}
+
+/// An emulated Xous process, driven from Python one step at a time.
+#[pyclass(unsendable)]
+struct Machine {
+    machine: xous::Machine,
+
+    /// Called with the thread and address when a thread stops at a
+    /// breakpoint. The run stops there if it returns `False`.
+    on_breakpoint: Option<PyObject>,
+
+    /// Called whenever every thread is waiting.
+    on_idle: Option<PyObject>,
+
+    /// Called with the exit code when the program exits.
+    on_exit: Option<PyObject>,
+}
+
+#[pymethods]
+impl Machine {
+    /// Load the ELF file in `program`, passing it `args`. Faults are injected
+    /// by the rules in `faults`, written as for `--inject-fault`.
+    #[new]
+    #[pyo3(signature = (program, args = vec![], seed = None, faults = vec![]))]
+    fn new(
+        program: &[u8],
+        args: Vec<String>,
+        seed: Option<u64>,
+        faults: Vec<String>,
+    ) -> PyResult<Self> {
+        let mut builder = MachineBuilder::new().args(args);
+        if let Some(seed) = seed {
+            builder = builder.seed(seed);
+        }
+        for rule in faults {
+            builder = builder.fault(rule.parse().map_err(PyValueError::new_err)?);
+        }
+        Ok(Machine {
+            machine: builder.build(program)?,
+            on_breakpoint: None,
+            on_idle: None,
+            on_exit: None,
+        })
+    }
+
+    /// Step the machine once, returning `("running",)`, `("idle",)`,
+    /// `("exited", code)`, or `("stopped", tid, pc)`.
+    fn step(&mut self, py: Python<'_>) -> PyResult<PyObject> {
+        event(py, self.machine.step()?)
+    }
+
+    /// Step the machine until the program exits, calling the callbacks along
+    /// the way, and return the exit code. Returns `None` if a thread stops
+    /// at a breakpoint that no callback lets it past.
+    fn run(&mut self, py: Python<'_>) -> PyResult<Option<u32>> {
+        loop {
+            // Let Ctrl-C in a notebook interrupt a program that never exits
+            py.check_signals()?;
+            match self.machine.step()? {
+                MachineEvent::Running => {}
+                MachineEvent::Idle => match &self.on_idle {
+                    Some(callback) => {
+                        callback.call0(py)?;
+                    }
+                    None => std::thread::sleep(std::time::Duration::from_millis(1)),
+                },
+                MachineEvent::Exited(code) => {
+                    if let Some(callback) = &self.on_exit {
+                        callback.call1(py, (code,))?;
+                    }
+                    return Ok(Some(code));
+                }
+                MachineEvent::Stopped { tid, pc } => {
+                    let Some(callback) = &self.on_breakpoint else {
+                        return Ok(None);
+                    };
+                    let carry_on = callback.call1(py, (tid, pc))?;
+                    if carry_on.bind(py).eq(false)? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_breakpoint(&mut self, callback: PyObject) {
+        self.on_breakpoint = Some(callback);
+    }
+
+    fn on_idle(&mut self, callback: PyObject) {
+        self.on_idle = Some(callback);
+    }
+
+    fn on_exit(&mut self, callback: PyObject) {
+        self.on_exit = Some(callback);
+    }
+
+    /// Read `length` bytes of memory at `address`.
+    fn read<'py>(
+        &self,
+        py: Python<'py>,
+        address: u32,
+        length: u32,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let bytes = self.machine.read_memory(address, length)?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    /// Write `data` to memory at `address`, including over code.
+    fn write(&mut self, address: u32, data: &[u8]) -> PyResult<()> {
+        Ok(self.machine.patch(address, data)?)
+    }
+
+    /// The address of the function `name`, or `None` if there isn't one.
+    fn symbol(&self, name: &str) -> Option<u32> {
+        self.machine.symbol_address(name)
+    }
+
+    /// Make the function `name` return `value` as soon as it's called.
+    fn stub(&mut self, name: &str, value: i32) -> PyResult<u32> {
+        Ok(self.machine.stub_symbol(name, value)?)
+    }
+
+    fn add_breakpoint(&mut self, address: u32) {
+        self.machine.add_breakpoint(address);
+    }
+
+    /// Set a breakpoint at `location`, such as `"main"` or `"decrypt+0x10"`,
+    /// returning its address, or `None` if the symbol isn't loaded yet.
+    fn add_breakpoint_at(&mut self, location: &str) -> PyResult<Option<u32>> {
+        let location = location.parse().map_err(PyValueError::new_err)?;
+        Ok(self.machine.add_breakpoint_at(location))
+    }
+
+    fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.machine.remove_breakpoint(address)
+    }
+
+    fn breakpoints(&self) -> Vec<u32> {
+        self.machine.breakpoints().collect()
+    }
+
+    /// The program counter and the 32 integer registers of thread `tid`.
+    fn registers(&self, tid: i32) -> PyResult<(u32, Vec<i32>)> {
+        let state = self.machine.thread_state(tid)?;
+        Ok((state.pc, state.registers.to_vec()))
+    }
+
+    /// Run thread `tid` for a single instruction, returning what happened as
+    /// `step()` does.
+    fn step_thread(&mut self, py: Python<'_>, tid: i32) -> PyResult<PyObject> {
+        event(py, self.machine.step_thread(tid)?)
+    }
+
+    #[getter]
+    fn instructions_retired(&self) -> u64 {
+        self.machine.instructions_retired()
+    }
+
+    #[getter]
+    fn seed(&self) -> u64 {
+        self.machine.seed()
+    }
+}
+
+fn event(py: Python<'_>, event: MachineEvent) -> PyResult<PyObject> {
+    let event = match event {
+        MachineEvent::Running => ("running",).into_pyobject(py)?,
+        MachineEvent::Idle => ("idle",).into_pyobject(py)?,
+        MachineEvent::Exited(code) => ("exited", code).into_pyobject(py)?,
+        MachineEvent::Stopped { tid, pc } => ("stopped", tid, pc).into_pyobject(py)?,
+    };
+    Ok(event.into_any().unbind())
+}
+
+#[pymodule]
+fn yove(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Machine>()?;
+    m.add("YoveError", m.py().get_type::<YoveError>())?;
+    Ok(())
+}
@@ -0,0 +1,436 @@
+//! `yove selftest`: a quick check that this build of the emulator works on
+//! the platform it was built for. It runs the riscv-tests ISA suite that the
+//! CPU is tested against, which is built into the binary, and a few checks
+//! of the decoder, the page table walker, and LR/SC that don't need a guest
+//! program at all.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use riscv_cpu::cpu::{Memory, TickResult, Trap, TrapType, CSR_MSTATUS_ADDRESS, CSR_SATP_ADDRESS};
+use riscv_cpu::mmu::{MemoryAccessType, SystemBus};
+use riscv_cpu::syscall::{SyscallBackend, SyscallResult};
+use riscv_cpu::Cpu;
+
+/// Where the riscv-tests expect RAM, and how much of it they need.
+const RAM_BASE: u32 = 0x8000_0000;
+const RAM_SIZE: usize = 128 * 1024;
+
+/// How many instructions an ISA test may run before it counts as hung. The
+/// longest of them runs a few thousand.
+const ISA_TEST_LIMIT: usize = 1_000_000;
+
+macro_rules! isa_tests {
+    ($($name:literal),* $(,)?) => {
+        [$((
+            $name,
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/crates/riscv-cpu/riscv-tests/isa/",
+                $name
+            )) as &[u8],
+        )),*]
+    };
+}
+
+/// The riscv-tests ISA tests for RV32IMAC, run in machine mode.
+const ISA_TESTS: [(&str, &[u8]); 59] = isa_tests![
+    "rv32ua-p-amoadd_w",
+    "rv32ua-p-amoand_w",
+    "rv32ua-p-amomax_w",
+    "rv32ua-p-amomaxu_w",
+    "rv32ua-p-amomin_w",
+    "rv32ua-p-amominu_w",
+    "rv32ua-p-amoor_w",
+    "rv32ua-p-amoswap_w",
+    "rv32ua-p-amoxor_w",
+    "rv32ua-p-lrsc",
+    "rv32uc-p-rvc",
+    "rv32ui-p-add",
+    "rv32ui-p-addi",
+    "rv32ui-p-and",
+    "rv32ui-p-andi",
+    "rv32ui-p-auipc",
+    "rv32ui-p-beq",
+    "rv32ui-p-bge",
+    "rv32ui-p-bgeu",
+    "rv32ui-p-blt",
+    "rv32ui-p-bltu",
+    "rv32ui-p-bne",
+    "rv32ui-p-fence_i",
+    "rv32ui-p-jal",
+    "rv32ui-p-jalr",
+    "rv32ui-p-lb",
+    "rv32ui-p-lbu",
+    "rv32ui-p-lh",
+    "rv32ui-p-lhu",
+    "rv32ui-p-lui",
+    "rv32ui-p-lw",
+    "rv32ui-p-ma_data",
+    "rv32ui-p-or",
+    "rv32ui-p-ori",
+    "rv32ui-p-sb",
+    "rv32ui-p-sh",
+    "rv32ui-p-simple",
+    "rv32ui-p-sll",
+    "rv32ui-p-slli",
+    "rv32ui-p-slt",
+    "rv32ui-p-slti",
+    "rv32ui-p-sltiu",
+    "rv32ui-p-sltu",
+    "rv32ui-p-sra",
+    "rv32ui-p-srai",
+    "rv32ui-p-srl",
+    "rv32ui-p-srli",
+    "rv32ui-p-sub",
+    "rv32ui-p-sw",
+    "rv32ui-p-xor",
+    "rv32ui-p-xori",
+    "rv32um-p-div",
+    "rv32um-p-divu",
+    "rv32um-p-mul",
+    "rv32um-p-mulh",
+    "rv32um-p-mulhsu",
+    "rv32um-p-mulhu",
+    "rv32um-p-rem",
+    "rv32um-p-remu",
+];
+
+/// The outcome of one check, and why it failed if it did.
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub result: Result<(), String>,
+}
+
+/// Run every check, in the order they're reported.
+pub fn run() -> Vec<Check> {
+    let mut checks: Vec<Check> = ISA_TESTS
+        .iter()
+        .map(|(name, program)| check(name, || isa_test(program)))
+        .collect();
+    checks.push(check("decoder", check_decoder));
+    checks.push(check("mmu", check_mmu));
+    checks.push(check("lr/sc", check_lr_sc));
+    checks
+}
+
+/// Run `check`, counting a panic as a failure rather than ending the run.
+fn check(name: &str, check: impl FnOnce() -> Result<(), String>) -> Check {
+    let result =
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(check)).unwrap_or_else(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned());
+            Err(format!("the emulator panicked: {}", message))
+        });
+    Check {
+        name: name.to_owned(),
+        result,
+    }
+}
+
+/// `Err(message)` unless `condition` holds.
+fn ensure(condition: bool, message: impl FnOnce() -> String) -> Result<(), String> {
+    condition.then_some(()).ok_or_else(message)
+}
+
+/// RAM at `RAM_BASE` with nothing else on the bus, which is all that the
+/// riscv-tests need. Their result is whatever they write to `tohost`.
+#[derive(Clone)]
+struct BareMetal {
+    ram: Arc<RwLock<Vec<u8>>>,
+    tohost: Arc<AtomicU32>,
+    result: Arc<Mutex<Option<u32>>>,
+    reservations: Arc<Mutex<HashMap<u32, u32>>>,
+}
+
+impl BareMetal {
+    fn new() -> Self {
+        BareMetal {
+            ram: Arc::new(RwLock::new(vec![0; RAM_SIZE])),
+            tohost: Arc::new(AtomicU32::new(0)),
+            result: Arc::new(Mutex::new(None)),
+            reservations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn offset(address: u32, length: usize) -> Option<usize> {
+        let offset = address.checked_sub(RAM_BASE)? as usize;
+        (offset + length <= RAM_SIZE).then_some(offset)
+    }
+
+    fn read<const N: usize>(&self, address: u32) -> [u8; N] {
+        match Self::offset(address, N) {
+            Some(offset) => self.ram.read().unwrap()[offset..offset + N]
+                .try_into()
+                .unwrap(),
+            None => [0; N],
+        }
+    }
+
+    fn write(&self, address: u32, bytes: &[u8]) {
+        if let Some(offset) = Self::offset(address, bytes.len()) {
+            self.ram.write().unwrap()[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+}
+
+impl Memory for BareMetal {
+    fn read_u8(&self, address: u32) -> u8 {
+        self.read::<1>(address)[0]
+    }
+
+    fn read_u16(&self, address: u32) -> u16 {
+        u16::from_le_bytes(self.read(address))
+    }
+
+    fn read_u32(&self, address: u32) -> u32 {
+        u32::from_le_bytes(self.read(address))
+    }
+
+    fn write_u8(&self, address: u32, value: u8) {
+        self.write(address, &[value]);
+    }
+
+    fn write_u16(&self, address: u32, value: u16) {
+        self.write(address, &value.to_le_bytes());
+    }
+
+    fn write_u32(&self, address: u32, value: u32) {
+        if address != 0 && address == self.tohost.load(Ordering::Relaxed) {
+            *self.result.lock().unwrap() = Some(value);
+        }
+        self.write(address, &value.to_le_bytes());
+    }
+
+    fn validate_address(&self, address: u32) -> bool {
+        Self::offset(address, 1).is_some()
+    }
+
+    fn translate(&self, _v_address: u32, _access_type: &MemoryAccessType) -> Option<u32> {
+        None
+    }
+
+    fn reserve(&self, core: u32, p_address: u32) {
+        self.reservations.lock().unwrap().insert(core, p_address);
+    }
+
+    fn clear_reservation(&self, core: u32, p_address: u32) -> bool {
+        self.reservations.lock().unwrap().remove(&core) == Some(p_address)
+    }
+
+    fn clone(&self) -> Box<dyn Memory + Send + Sync> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+impl SyscallBackend for BareMetal {
+    fn syscall(&self, _args: [i32; 8]) -> SyscallResult {
+        SyscallResult::Continue
+    }
+}
+
+impl SystemBus for BareMetal {}
+
+fn create_cpu() -> (Cpu, BareMetal) {
+    let memory = BareMetal::new();
+    (Cpu::new(Box::new(Clone::clone(&memory))), memory)
+}
+
+/// Load the sections of a riscv-tests ELF into RAM, returning its entry
+/// point and where its `tohost` is.
+fn load_isa_test(memory: &BareMetal, program: &[u8]) -> Result<(u32, u32), String> {
+    use goblin::elf::section_header::{SHF_ALLOC, SHT_NOBITS};
+
+    let elf = goblin::elf::Elf::parse(program).map_err(|error| error.to_string())?;
+    for section in &elf.section_headers {
+        if section.sh_flags as u32 & SHF_ALLOC == 0 || section.sh_type == SHT_NOBITS {
+            continue;
+        }
+        let contents = program
+            .get(section.file_range().unwrap_or_default())
+            .ok_or("a section is outside of the file")?;
+        memory.write(section.sh_addr as u32, contents);
+    }
+    let tohost = elf
+        .syms
+        .iter()
+        .find(|sym| elf.strtab.get_at(sym.st_name) == Some("tohost"))
+        .ok_or("there's no tohost symbol")?;
+    Ok((elf.entry as u32, tohost.st_value as u32))
+}
+
+/// Run one of the riscv-tests, which writes 1 to `tohost` if it passes, or
+/// the number of the test case that failed shifted left by one otherwise.
+fn isa_test(program: &[u8]) -> Result<(), String> {
+    let (mut cpu, memory) = create_cpu();
+    let (entry, tohost) = load_isa_test(&memory, program)?;
+    memory.tohost.store(tohost, Ordering::Relaxed);
+    cpu.update_pc(entry);
+
+    for _ in 0..ISA_TEST_LIMIT {
+        if let Some(result) = *memory.result.lock().unwrap() {
+            return ensure(result == 1, || format!("case {} failed", result >> 1));
+        }
+        let pc = cpu.read_pc();
+        match cpu.tick() {
+            TickResult::Ok => {}
+            // The tests handle their own exceptions, including the `ecall`
+            // that reports the result
+            TickResult::CpuTrap(trap) => {
+                cpu.handle_trap(trap, pc, false);
+            }
+            _ => return Err(format!("stopped unexpectedly at {:08x}", pc)),
+        }
+    }
+    Err(format!(
+        "didn't finish within {} instructions",
+        ISA_TEST_LIMIT
+    ))
+}
+
+/// Every word decodes to at most one instruction.
+fn check_decoder() -> Result<(), String> {
+    let (cpu, _memory) = create_cpu();
+    let conflicts = cpu.decoder_conflicts();
+    ensure(conflicts.is_empty(), || {
+        format!("these instructions overlap: {:?}", conflicts)
+    })
+}
+
+/// Loads and stores through an Sv32 page table go where it says, set the
+/// accessed and dirty bits as they should, and fault where it doesn't allow
+/// them.
+fn check_mmu() -> Result<(), String> {
+    const ROOT_TABLE: u32 = RAM_BASE + 0x1000;
+    const LEAF_TABLE: u32 = RAM_BASE + 0x2000;
+    const DATA_PAGE: u32 = RAM_BASE + 0x3000;
+    const DATA_VIRT: u32 = 0x1000_0000;
+    const READ_ONLY_VIRT: u32 = DATA_VIRT + 0x1000;
+    const UNMAPPED_VIRT: u32 = DATA_VIRT + 0x2000;
+    const PTE_V: u32 = 1 << 0;
+    const PTE_R: u32 = 1 << 1;
+    const PTE_W: u32 = 1 << 2;
+    const PTE_A: u32 = 1 << 6;
+    const PTE_D: u32 = 1 << 7;
+
+    let (mut cpu, memory) = create_cpu();
+    let leaf = |virt: u32| LEAF_TABLE + ((virt >> 12) & 0x3ff) * 4;
+    memory.write_u32(
+        ROOT_TABLE + (DATA_VIRT >> 22) * 4,
+        (LEAF_TABLE >> 12) << 10 | PTE_V,
+    );
+    memory.write_u32(
+        leaf(DATA_VIRT),
+        (DATA_PAGE >> 12) << 10 | PTE_R | PTE_W | PTE_V,
+    );
+    memory.write_u32(
+        leaf(READ_ONLY_VIRT),
+        (DATA_PAGE >> 12) << 10 | PTE_R | PTE_V,
+    );
+    memory.write_u32(DATA_PAGE, 0x1234_5678);
+
+    // Drop to supervisor mode so that loads and stores are translated
+    cpu.write_csr(CSR_SATP_ADDRESS, 0x8000_0000 | ROOT_TABLE >> 12)
+        .map_err(|trap| trap.to_string())?;
+    cpu.write_csr(CSR_MSTATUS_ADDRESS, 1 << 11)
+        .map_err(|trap| trap.to_string())?;
+    cpu.execute_opcode(0x30200073) // mret
+        .map_err(|trap| trap.to_string())?;
+    let mmu = cpu.get_mut_mmu();
+
+    let loaded = mmu.load_word(DATA_VIRT).map_err(|trap| trap.to_string())?;
+    ensure(loaded == 0x1234_5678, || {
+        format!("a load through the page table read {:08x}", loaded)
+    })?;
+    let pte = memory.read_u32(leaf(DATA_VIRT));
+    ensure(pte & (PTE_A | PTE_D) == PTE_A, || {
+        format!("a load left the PTE as {:08x}", pte)
+    })?;
+
+    mmu.store_word(DATA_VIRT + 4, 0xcafe_f00d)
+        .map_err(|trap| trap.to_string())?;
+    let stored = memory.read_u32(DATA_PAGE + 4);
+    ensure(stored == 0xcafe_f00d, || {
+        format!("a store through the page table wrote {:08x}", stored)
+    })?;
+    let pte = memory.read_u32(leaf(DATA_VIRT));
+    ensure(pte & PTE_D != 0, || {
+        format!("a store left the PTE as {:08x}", pte)
+    })?;
+
+    let faults = |result: Result<(), Trap>, expected: TrapType, address: u32| match result {
+        Err(Trap { trap_type, value }) => {
+            std::mem::discriminant(&trap_type) == std::mem::discriminant(&expected)
+                && value == address
+        }
+        Ok(()) => false,
+    };
+    ensure(
+        faults(
+            mmu.store_word(READ_ONLY_VIRT, 0).map(|_| ()),
+            TrapType::StorePageFault,
+            READ_ONLY_VIRT,
+        ),
+        || "a store to a read-only page didn't fault".to_owned(),
+    )?;
+    let pte = memory.read_u32(leaf(READ_ONLY_VIRT));
+    ensure(pte & PTE_D == 0, || {
+        format!("a faulting store left the PTE as {:08x}", pte)
+    })?;
+    ensure(
+        faults(
+            mmu.load_word(UNMAPPED_VIRT).map(|_| ()),
+            TrapType::LoadPageFault,
+            UNMAPPED_VIRT,
+        ),
+        || "a load from an unmapped page didn't fault".to_owned(),
+    )
+}
+
+/// An `sc.w` succeeds only on the word its hart reserved with `lr.w`, and
+/// only once.
+fn check_lr_sc() -> Result<(), String> {
+    const LR_W_A1_A0: u32 = 0x1005_25af;
+    const SC_W_A2_A3_A0: u32 = 0x18d5_262f;
+    const SC_W_A2_A3_A4: u32 = 0x18d7_262f;
+    const WORD: u32 = RAM_BASE + 0x100;
+
+    let (mut cpu, memory) = create_cpu();
+    memory.write_u32(WORD, 5);
+    cpu.write_register(10, WORD as i32);
+    cpu.write_register(13, 7);
+    cpu.write_register(14, (WORD + 4) as i32);
+    let run = |cpu: &mut Cpu, word: u32| {
+        cpu.execute_opcode(word).map_err(|trap| trap.to_string())?;
+        Ok::<_, String>(cpu.read_register(12))
+    };
+
+    run(&mut cpu, LR_W_A1_A0)?;
+    ensure(cpu.read_register(11) == 5, || {
+        format!("lr.w read {}", cpu.read_register(11))
+    })?;
+    ensure(run(&mut cpu, SC_W_A2_A3_A0)? == 0, || {
+        "sc.w failed with a reservation".to_owned()
+    })?;
+    ensure(memory.read_u32(WORD) == 7, || {
+        "a successful sc.w didn't store".to_owned()
+    })?;
+
+    cpu.write_register(13, 9);
+    ensure(run(&mut cpu, SC_W_A2_A3_A0)? != 0, || {
+        "sc.w succeeded twice on one reservation".to_owned()
+    })?;
+    run(&mut cpu, LR_W_A1_A0)?;
+    ensure(run(&mut cpu, SC_W_A2_A3_A4)? != 0, || {
+        "sc.w succeeded on a word that wasn't reserved".to_owned()
+    })?;
+    ensure(
+        memory.read_u32(WORD) == 7 && memory.read_u32(WORD + 4) == 0,
+        || "a failed sc.w stored anyway".to_owned(),
+    )
+}
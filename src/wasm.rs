@@ -0,0 +1,88 @@
+//! Bindings for running yove in a web page. Build with
+//! `cargo build --lib --target wasm32-unknown-unknown` and process the result with
+//! `wasm-bindgen --target web`.
+
+use std::sync::Arc;
+
+use wasm_bindgen::prelude::*;
+
+use crate::xous::platform::Platform;
+use crate::xous::{Machine, MachineBuilder, MachineEvent};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Date, js_name = now)]
+    fn date_now() -> f64;
+
+    #[wasm_bindgen(js_namespace = Math, js_name = random)]
+    fn math_random() -> f64;
+
+    #[wasm_bindgen(js_namespace = console, js_name = log)]
+    fn console_log(s: &str);
+
+    #[wasm_bindgen(js_namespace = console, js_name = error)]
+    fn console_error(s: &str);
+}
+
+/// A `Platform` that uses the browser's clock and sends guest output to the console.
+struct BrowserPlatform {
+    start: f64,
+}
+
+impl Platform for BrowserPlatform {
+    fn elapsed_ms(&self) -> u64 {
+        (date_now() - self.start) as u64
+    }
+
+    fn random_u32(&self) -> u32 {
+        (math_random() * 4294967296.0) as u32
+    }
+
+    fn write_stdout(&self, data: &[u8]) {
+        console_log(&String::from_utf8_lossy(data));
+    }
+
+    fn write_stderr(&self, data: &[u8]) {
+        console_error(&String::from_utf8_lossy(data));
+    }
+}
+
+/// An emulated Xous process that is driven by the page's event loop.
+#[wasm_bindgen]
+pub struct Emulator {
+    machine: Machine,
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /// Load the ELF file in `program`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(program: &[u8]) -> Result<Emulator, JsError> {
+        let platform = Arc::new(BrowserPlatform { start: date_now() });
+        let machine = MachineBuilder::new()
+            .platform(platform)
+            .build(program)
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(Emulator { machine })
+    }
+
+    /// Call `Machine::step` up to `rounds` times, returning the exit code once
+    /// the program has finished. Call this again from `requestAnimationFrame()`
+    /// or a timer until it returns a value.
+    pub fn step(&mut self, rounds: u32) -> Result<Option<u32>, JsError> {
+        for _ in 0..rounds {
+            match self
+                .machine
+                .step()
+                .map_err(|e| JsError::new(&e.to_string()))?
+            {
+                MachineEvent::Running => {}
+                // Nothing here sets breakpoints, but a stop would want the
+                // page's attention all the same
+                MachineEvent::Idle | MachineEvent::Stopped { .. } => break,
+                MachineEvent::Exited(val) => return Ok(Some(val)),
+            }
+        }
+        Ok(None)
+    }
+}
@@ -1,31 +1,132 @@
-use riscv_cpu::{cpu::Memory as OtherMemory, mmu::SystemBus};
+use riscv_cpu::{
+    cpu::{Memory as OtherMemory, MAX_VLEN, MIN_VLEN},
+    mmu::{MemoryAccessType, SystemBus},
+    syscall::SyscallBackend,
+};
+pub mod abuse;
+mod address_space;
+pub mod audio;
+mod backing;
+pub mod branch_stats;
+pub mod breakpoint_script;
+pub mod bridge;
+pub mod cfg;
+pub mod clock;
+pub mod commit_log;
+mod connections;
+pub mod counters;
 mod definitions;
+pub mod ec;
+pub mod faults;
+pub mod flash;
+pub mod framebuffer;
+pub mod fuzz;
+pub mod guest_panic;
+#[cfg(not(target_arch = "wasm32"))]
+mod harts;
+pub mod heatmap;
+pub mod host_exec;
+pub mod keyboard;
+pub mod message_stats;
+mod metrics;
+pub mod notify;
+pub mod page_tables;
+pub mod pause;
+pub mod perf_events;
+pub mod platform;
+pub mod preopen;
+pub mod profiler;
+pub mod program;
+mod read_only;
+mod rng;
+mod section_map;
 mod services;
+pub mod shadow_stack;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod snapshot;
+mod strace;
 mod syscalls;
+pub mod trace;
+mod translation_cache;
+pub mod uninit;
+pub mod usb;
+mod w_xor_x;
+pub mod watchdog;
 
 use definitions::{Syscall, SyscallNumber, SyscallResultNumber};
-pub use riscv_cpu::mmu::SyscallResult;
+pub use riscv_cpu::cpu::{CpuState, StateDelta};
+pub use riscv_cpu::syscall::SyscallResult;
 use std::{
-    collections::{BTreeSet, HashMap},
-    num::NonZeroU32,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Range,
     sync::{
-        atomic::{AtomicI32, AtomicU32, Ordering},
+        atomic::{AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering},
         mpsc::{Receiver, Sender},
         Arc, Mutex, RwLock,
     },
-    thread::JoinHandle,
 };
 
 use self::definitions::SyscallErrorNumber;
+use self::platform::Platform;
+use self::services::{MessageKind, ResponseData};
+use crate::YoveError;
+
+pub use self::definitions::MemoryType;
+pub use self::page_tables::MmuFormat;
+pub use self::services::name::NameInfo;
+pub use self::services::ring_buffer::{RingBuffer, RingDirection};
+pub use self::services::LendBuffer;
 
 const MEMORY_BASE: u32 = 0x8000_0000;
 const ALLOCATION_START: u32 = 0x4000_0000;
-const ALLOCATION_END: u32 = ALLOCATION_START + 5 * 1024 * 1024;
 const HEAP_START: u32 = 0xa000_0000;
-const HEAP_END: u32 = HEAP_START + 5 * 1024 * 1024;
 const STACK_START: u32 = 0xc000_0000;
 const STACK_END: u32 = 0xc002_0000;
 
+/// The ID of the emulated process, which is the first one Xous starts after
+/// the kernel.
+const PROCESS_ID: i32 = 2;
+
+/// The ID of the kernel, the emulated process's parent. Yove doesn't run
+/// any other processes, so the emulated process never has children.
+const KERNEL_PROCESS_ID: i32 = 1;
+
+/// The end of the addresses the guest may unmap. Everything from here up is
+/// the kernel's, as in Xous, including the page tables and the exit
+/// trampoline.
+const USER_AREA_END: u32 = 0xff00_0000;
+
+/// Where the level 0 page tables can be read, as Xous maps them into every
+/// process: the table for the 4MB at `vpn1 << 22` is at page `vpn1` from here.
+const PAGE_TABLE_OFFSET: u32 = 0xff40_0000;
+
+/// Where the root page table can be read, just after the level 0 tables.
+const PAGE_TABLE_ROOT_OFFSET: u32 = 0xff80_0000;
+
+/// Where threads return to when they're done. Xous's kernel ends a thread
+/// when it faults here, but Yove maps the code in `EXIT_TRAMPOLINE_CODE`.
+const EXIT_TRAMPOLINE: u32 = 0xff80_3000;
+
+/// Pass the value the thread returned to the `ExitThread` syscall:
+/// `mv a1, a0`, `li a0, 64`, `ecall`.
+const EXIT_TRAMPOLINE_CODE: [u32; 3] = [0x0005_0593, 0x0400_0513, 0x0000_0073];
+
+/// How much RAM the guest gets unless `MachineBuilder::memory_size()` says otherwise.
+const DEFAULT_MEMORY_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The smallest amount of RAM a guest can be given.
+const MIN_MEMORY_SIZE: u32 = 1024 * 1024;
+
+/// The most RAM a guest can be given, which is as far as RAM can reach
+/// before running into the framebuffer.
+const MAX_MEMORY_SIZE: u32 = framebuffer::FRAMEBUFFER_ADDRESS - MEMORY_BASE;
+
+/// With a randomized layout, the heap starts up to this many pages past `HEAP_START`.
+const HEAP_RANDOM_PAGES: u32 = 256;
+
+/// With a randomized layout, the stack starts up to this many bytes below `STACK_END`.
+const STACK_RANDOM_BYTES: u32 = 16 * 1024;
+
 /// Magic number indicating we have an environment block
 const ENV_MAGIC: [u8; 4] = *b"EnvB";
 
@@ -35,25 +136,58 @@ const ARGS_MAGIC: [u8; 4] = *b"ArgL";
 /// Magic number indicating the loader has passed application parameters
 const PARAMS_MAGIC: [u8; 4] = *b"AppP";
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum LoadError {
+    #[error("Incorrect format")]
     IncorrectFormat,
+    #[error("Incorrect bit size")]
     BitSizeError,
+    #[error("Program is big-endian, but RISC-V programs must be little-endian")]
+    BigEndian,
+    #[error(
+        "Program is for {} (machine {0}), not RISC-V",
+        goblin::elf::header::machine_to_str(*.0)
+    )]
+    WrongMachine(u16),
+    #[error(
+        "Program is {} (type {0}) rather than an executable",
+        goblin::elf::header::et_to_str(*.0)
+    )]
+    NotExecutable(u16),
+    #[error("Couldn't write to SATP register")]
     SatpWriteError,
+    #[error("Couldn't write to MSTATUS register")]
     MstatusWriteError,
+    #[error("CPU trap: {0}")]
     CpuTrap(riscv_cpu::cpu::Trap),
-}
-
-impl std::fmt::Display for LoadError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            LoadError::IncorrectFormat => write!(f, "Incorrect format"),
-            LoadError::BitSizeError => write!(f, "Incorrect bit size"),
-            LoadError::SatpWriteError => write!(f, "Couldn't write to SATP register"),
-            LoadError::MstatusWriteError => write!(f, "Couldn't write to MSTATUS register"),
-            LoadError::CpuTrap(trap) => write!(f, "CPU trap: {:?}", trap),
-        }
-    }
+    #[error("Symbol {0} isn't in the program")]
+    UnknownSymbol(String),
+    #[error("Memory size {0:#x} isn't a multiple of 4096 between 1MB and 768MB")]
+    InvalidMemorySize(u32),
+    #[error("A machine needs at least one hart")]
+    NoHarts,
+    #[error("VLEN {0} isn't a power of two between 32 and 65536")]
+    InvalidVlen(u32),
+    #[error("The profiler can't sample every 0 instructions")]
+    InvalidProfileInterval,
+    #[error("The watchdog can't expire after 0 ms")]
+    InvalidWatchdogTimeout,
+    #[error("An execution recording can't keep 0 instructions")]
+    InvalidTraceLimit,
+    #[error("Screenshots can't be taken every 0 ms")]
+    InvalidScreenshotInterval,
+    #[error("Ran out of memory for the program at {0:08x}")]
+    OutOfMemory(u32),
+    #[error("Sections {0} and {1} overlap")]
+    OverlappingSections(String, String),
+    #[error(
+        "Section {name} at {address:08x} ({size:#x} bytes) is outside of the program's memory"
+    )]
+    SectionOutOfRange {
+        name: String,
+        address: u32,
+        size: u32,
+    },
 }
 
 const MMUFLAG_VALID: u32 = 0x01;
@@ -65,107 +199,841 @@ const MMUFLAG_USERMODE: u32 = 0x10;
 const MMUFLAG_ACCESSED: u32 = 0x40;
 const MMUFLAG_DIRTY: u32 = 0x80;
 
-impl std::error::Error for LoadError {}
+/// One of the bits reserved for software in a page table entry. It marks an
+/// invalid entry as a page that gets allocated the first time it's touched.
+const MMUFLAG_LAZY: u32 = 0x100;
+
+/// The flags of every page mapped for the guest.
+const USER_PAGE_FLAGS: u32 = MMUFLAG_VALID
+    | MMUFLAG_WRITABLE
+    | MMUFLAG_READABLE
+    | MMUFLAG_EXECUTABLE
+    | MMUFLAG_USERMODE
+    | MMUFLAG_DIRTY
+    | MMUFLAG_ACCESSED;
+
+/// The size of the region a single level 1 page table entry maps, either
+/// through a level 0 table or directly as a megapage.
+const MEGAPAGE_SIZE: u32 = 4 * 1024 * 1024;
+
+/// A run of virtual memory mapped to contiguous physical memory by one page
+/// table entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub virt: u32,
+    pub phys: u32,
+
+    /// 4096 for a page, or 4MB for a megapage.
+    pub size: u32,
+}
+
+/// A region of the guest's address space, as `SetMemRegion` would set it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryRegion {
+    pub kind: MemoryType,
+    pub start: u32,
+    pub size: u32,
+}
+
 // pub type ResponseData = ([i32; 8], Option<(Vec<u8>, u32)>);
 
 enum MemoryCommand {
     // Exit,
     // ExitThread(u32 /* tid */, u32 /* result */),
     CreateThread(
-        u32,                                         /* entry point */
-        u32,                                         /* stack pointer */
-        u32,                                         /* stack length */
-        u32,                                         /* argument 1 */
-        u32,                                         /* argument 2 */
-        u32,                                         /* argument 3 */
-        u32,                                         /* argument 4 */
-        Sender<(i32, std::thread::JoinHandle<u32>)>, /* Thread ID + Result*/
+        i32,                  /* thread ID */
+        u32,                  /* entry point */
+        u32,                  /* stack pointer */
+        u32,                  /* stack length */
+        u32,                  /* argument 1 */
+        u32,                  /* argument 2 */
+        u32,                  /* argument 3 */
+        u32,                  /* argument 4 */
+        Sender<ResponseData>, /* Join result */
     ),
     // JoinThread(u32, Sender<ResponseData>),
 }
 
+/// How many accesses outside of RAM are kept for `Machine::invalid_accesses`.
+const INVALID_ACCESS_LOG_LIMIT: usize = 32;
+
+/// How many messages a server holds without responding before further
+/// messages to it fail with `ServerQueueFull`, unless set with
+/// `MachineBuilder::server_queue_depth()`.
+const DEFAULT_SERVER_QUEUE_DEPTH: usize = 32;
+
+/// An access by the guest to a physical address with nothing behind it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidAccess {
+    pub tid: i32,
+
+    /// Address of the instruction that made the access.
+    pub pc: u32,
+
+    /// The physical address that was accessed.
+    pub address: u32,
+    pub access: heatmap::Access,
+}
+
+/// A guest thread that was stopped because the emulator panicked while
+/// running it, for example in a service that the thread called.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadFault {
+    pub tid: i32,
+
+    /// Where the thread was when the emulator panicked.
+    pub pc: u32,
+    pub message: String,
+}
+
+/// How much one guest thread has run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadStats {
+    pub tid: i32,
+
+    /// Instructions the thread has retired, kept up to date while it runs.
+    pub instructions_retired: u64,
+    pub exited: bool,
+
+    /// The stack the thread was given, and the lowest its stack pointer has
+    /// been.
+    pub stack: Range<u32>,
+    pub lowest_sp: u32,
+}
+
+impl ThreadStats {
+    /// How many bytes of its stack the thread has used at most, counting
+    /// down from the top. More than the size of the stack means it
+    /// overflowed.
+    pub fn stack_used(&self) -> u32 {
+        self.stack.end.saturating_sub(self.lowest_sp)
+    }
+}
+
+/// The instruction counter and stack high-water mark of one guest thread,
+/// shared between its `Worker` and anyone asking about it.
+struct ThreadAccount {
+    instructions: Arc<AtomicU64>,
+    exited: bool,
+    stack: Range<u32>,
+    lowest_sp: Arc<AtomicU32>,
+}
+
+/// The message a panic was raised with.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// The result of advancing a `Worker` by a single step.
+#[derive(Debug)]
+pub enum WorkerEvent {
+    /// The CPU executed an instruction (or took a trap) and can continue.
+    Ran,
+
+    /// The thread is waiting on a response from a service or another thread.
+    Blocked,
+
+    /// The thread exited with the given return value.
+    Exited(u32),
+
+    /// The thread terminated the whole process with the given exit code.
+    Terminated(u32),
+
+    /// The thread hit an error that the process can't recover from.
+    Failed(YoveError),
+}
+
 struct Worker {
     cpu: riscv_cpu::Cpu,
     // cmd: Sender<MemoryCommand>,
     tid: i32,
     memory: Box<Memory>,
+
+    /// A response that must arrive before the CPU can continue.
+    pending: Option<Receiver<ResponseData>>,
+
+    /// What the thread is waiting on while `pending` is set, and the host
+    /// time in milliseconds when it started waiting.
+    blocked: Option<(trace::BlockReason, u64)>,
+
+    /// Where the exit value of this thread gets sent for `JoinThread`.
+    join: Option<Sender<ResponseData>>,
+
+    /// Instructions left to run before the profiler takes the next sample.
+    instructions_until_sample: u64,
+
+    /// Return addresses of this thread's calls, if the shadow stack is enabled.
+    shadow_stack: Option<shadow_stack::ShadowStack>,
+
+    /// The basic block this thread is running, for the control flow graph.
+    cfg_block: cfg::CurrentBlock,
+
+    /// Whether this thread runs on its own host thread, and so has to stop
+    /// when the machine is paused.
+    gated: bool,
+
+    /// Whether this thread was reported stopped at the instruction it's on,
+    /// so that stepping the machine runs that instruction rather than
+    /// stopping there again.
+    stopped: bool,
+
+    /// While a `Machine::step_out` is in progress, how many calls deeper this
+    /// thread is than where it started. It returns once this reaches -1.
+    call_depth: Option<i32>,
+
+    /// The interrupt lines last raised in this thread's CPU.
+    interrupt_lines: u32,
+
+    /// The lowest the stack pointer has been, which is only published to
+    /// the thread's account when it goes lower.
+    lowest_sp: u32,
 }
 
 impl Worker {
     fn new(
-        cpu: riscv_cpu::Cpu,
+        mut cpu: riscv_cpu::Cpu,
         // cmd: Sender<MemoryCommand>,
         tid: i32,
         memory: Box<Memory>,
+        join: Option<Sender<ResponseData>>,
     ) -> Self {
+        cpu.log_commits(memory.commit_log.is_some());
+        if let Some(vlen) = memory.vlen {
+            cpu.enable_vector(vlen);
+        }
+        cpu.permissive_csrs(memory.permissive_csrs);
+        cpu.hint_policy(memory.hints);
+        let instructions_until_sample = memory.profiler.as_ref().map_or(0, |p| p.interval());
+        let shadow_stack = memory
+            .shadow_stack
+            .as_ref()
+            .map(|_| shadow_stack::ShadowStack::default());
+        let lowest_sp = cpu.read_register(2) as u32;
+        memory.thread_lowest_sp.store(lowest_sp, Ordering::Relaxed);
         Self {
             cpu,
             // cmd,
             tid,
             memory,
+            pending: None,
+            blocked: None,
+            join,
+            instructions_until_sample,
+            shadow_stack,
+            cfg_block: cfg::CurrentBlock::default(),
+            gated: false,
+            stopped: false,
+            call_depth: None,
+            interrupt_lines: 0,
+            lowest_sp,
+        }
+    }
+
+    fn sample(&mut self) {
+        let Some(profiler) = &self.memory.profiler else {
+            return;
+        };
+        self.instructions_until_sample -= 1;
+        if self.instructions_until_sample == 0 {
+            self.instructions_until_sample = profiler.interval();
+            let fp = self.cpu.read_register(8) as u32;
+            profiler.sample(&self.memory, self.tid, self.cpu.read_pc(), fp);
+        }
+    }
+
+    /// Load the response to a paused syscall into the CPU. Returns how the
+    /// thread ended if the abuse policy stopped it because the memory it
+    /// mutably lent was unmapped or made read-only before it came back.
+    fn resume(&mut self, (result, data): ResponseData) -> Option<WorkerEvent> {
+        self.blocked = None;
+        if let Some(stats) = &self.memory.message_stats {
+            stats.reply(self.tid, self.memory.platform.elapsed_us());
+        }
+        self.memory.queue_slots.lock().unwrap().remove(&self.tid);
+        self.memory
+            .schedule(self.tid, trace::SchedulerEvent::Unblocked);
+        let mut unwritable = None;
+        if let Some(data) = data {
+            let syscall_type = self.cpu.read_register(10);
+            let message_kind = self.cpu.read_register(12);
+            let memory_offset = self.cpu.read_register(14) as u32;
+            let memory_size = self.cpu.read_register(15) as usize;
+
+            assert!(syscall_type == SyscallNumber::SendMessage as i32);
+            // As when a service returns right away, only a mutable lend that
+            // succeeded changes the sender's memory, and then only where the
+            // service wrote to what it lent. Another thread may have taken
+            // the memory away meanwhile, and those bytes are dropped.
+            if MessageKind::from_u32(message_kind as u32) == Some(MessageKind::MutableLend)
+                && result[0] == SyscallResultNumber::MemoryReturned as i32
+            {
+                let mmu = self.cpu.get_mut_mmu();
+                for (at, bytes) in data.writes() {
+                    let length = bytes.len().min(memory_size.saturating_sub(at));
+                    for (offset, byte) in bytes[..length].iter().enumerate() {
+                        let address = memory_offset + (at + offset) as u32;
+                        if mmu.store(address, *byte).is_err() {
+                            unwritable.get_or_insert(address);
+                        }
+                    }
+                }
+            }
+            if let Some(address) = unwritable {
+                let failure = YoveError::Abuse {
+                    tid: self.tid,
+                    abuse: abuse::Abuse::UnmappedLend,
+                    detail: format!(
+                        "{:08x}, lent at {:08x}, was unmapped or made read-only before it came back",
+                        address, memory_offset
+                    ),
+                };
+                let outcome = syscalls::handle_abuse(
+                    &self.memory,
+                    abuse::Abuse::UnmappedLend,
+                    result.into(),
+                    failure,
+                );
+                // The `ecall` has already retired
+                let pc = self.cpu.read_pc().wrapping_sub(4);
+                match outcome {
+                    SyscallResult::Ok(_) => {}
+                    SyscallResult::ExitThread(val) => return Some(self.exit(val)),
+                    SyscallResult::Terminate(code) => return Some(self.terminate(code, pc)),
+                    SyscallResult::Continue => {
+                        self.retire();
+                        return Some(WorkerEvent::Failed(YoveError::Trap {
+                            tid: self.tid,
+                            pc,
+                            trap: riscv_cpu::cpu::Trap {
+                                trap_type: riscv_cpu::cpu::TrapType::EnvironmentCallFromUMode,
+                                value: 0,
+                            },
+                        }));
+                    }
+                    SyscallResult::Suspend(_) => unreachable!("the abuse policy doesn't suspend"),
+                }
+            }
+        }
+        for (index, value) in result.iter().enumerate() {
+            self.cpu.write_register(10 + index as u8, *value);
+        }
+        None
+    }
+
+    /// Add this thread's instruction count to the machine-wide total, and
+    /// mark it as exited in its account.
+    fn retire(&self) {
+        self.memory.queue_slots.lock().unwrap().remove(&self.tid);
+        let instructions = self.cpu.instructions_retired();
+        self.memory
+            .instructions_retired
+            .fetch_add(instructions, Ordering::Relaxed);
+        self.memory
+            .thread_instructions
+            .store(instructions, Ordering::Relaxed);
+        if let Some(account) = self.memory.threads.lock().unwrap().get_mut(&self.tid) {
+            if !account.exited {
+                self.memory.live_threads.fetch_sub(1, Ordering::Relaxed);
+                log::debug!(
+                    "thread {} used {} of its {} bytes of stack",
+                    self.tid,
+                    account.stack.end.saturating_sub(self.lowest_sp),
+                    account.stack.len()
+                );
+            }
+            account.exited = true;
+        }
+    }
+
+    /// End the process with `code`, from the syscall at `pc`, or with the
+    /// failure that ended it.
+    fn terminate(&mut self, code: u32, pc: u32) -> WorkerEvent {
+        self.retire();
+        self.memory.retire_address_space();
+        self.memory
+            .schedule(self.tid, trace::SchedulerEvent::Exited(code));
+        let panicked = self
+            .memory
+            .guest_panics
+            .as_ref()
+            .filter(|catcher| code != 0 && catcher.panicking());
+        match (self.memory.failure.lock().unwrap().take(), panicked) {
+            (Some(error), _) => WorkerEvent::Failed(error),
+            (None, Some(catcher)) => {
+                WorkerEvent::Failed(self.guest_panic(catcher, pc, false, None))
+            }
+            (None, None) => WorkerEvent::Terminated(code),
+        }
+    }
+
+    fn exit(&mut self, val: u32) -> WorkerEvent {
+        self.retire();
+        self.memory
+            .schedule(self.tid, trace::SchedulerEvent::Exited(val));
+        if self.tid == 0 {
+            // The process ends with its main thread
+            self.memory.retire_address_space();
+        }
+        if let Some(join) = self.join.take() {
+            // Nobody may be joining this thread, so a send error is fine.
+            join.send((
+                [
+                    SyscallResultNumber::Scalar1 as i32,
+                    val as i32,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                ],
+                None,
+            ))
+            .ok();
+        }
+        WorkerEvent::Exited(val)
+    }
+
+    fn watchdog_expired(&mut self, timeout_ms: u64) -> WorkerEvent {
+        self.retire();
+        let mut registers = [0; 32];
+        for (index, register) in registers.iter_mut().enumerate() {
+            *register = self.cpu.read_register(index as u8);
+        }
+        WorkerEvent::Failed(YoveError::Watchdog {
+            timeout_ms,
+            tid: self.tid,
+            pc: self.cpu.read_pc(),
+            registers: Box::new(registers),
+        })
+    }
+
+    /// Check a jump that just ran from `pc` against the shadow stack.
+    fn check_shadow_stack(&mut self, pc: u32) -> Result<(), YoveError> {
+        let (Some(stack), Some(policy)) = (&mut self.shadow_stack, &self.memory.shadow_stack)
+        else {
+            return Ok(());
+        };
+        let word = self.cpu.last_instruction();
+        let link = self.cpu.read_register(((word >> 7) & 0x1f) as u8) as u32;
+        stack
+            .step(policy, pc, word, self.cpu.read_pc(), link)
+            .map_err(|mismatch| YoveError::ShadowStack {
+                tid: self.tid,
+                pc,
+                expected: mismatch.expected,
+                actual: mismatch.actual,
+            })
+    }
+
+    /// Take the actions a breakpoint script has for the instruction the
+    /// thread is about to run, if it has any.
+    fn run_breakpoint_script(&mut self) -> Result<(), YoveError> {
+        use breakpoint_script::{Action, MemoryDump, Start};
+
+        let Some(script) = &self.memory.breakpoint_script else {
+            return Ok(());
+        };
+        let pc = self.cpu.read_pc();
+        let Some(actions) = script.actions_at(pc) else {
+            return Ok(());
+        };
+        for action in actions {
+            match action {
+                Action::Trace(on) => {
+                    if let Some(tracer) = &self.memory.tracer {
+                        tracer.set_recording(on);
+                    }
+                }
+                Action::Dump { start, len } => {
+                    let address = match start {
+                        Start::Address(address) => address,
+                        Start::Register(register) => self.cpu.read_register(register) as u32,
+                    };
+                    let dump = MemoryDump {
+                        tid: self.tid,
+                        pc,
+                        address,
+                        bytes: self.memory.peek(address, len).unwrap_or_default(),
+                    };
+                    let text = breakpoint_script::format_dump(&dump);
+                    self.memory.platform.write_stderr(text.as_bytes());
+                    script.record_dump(dump);
+                }
+                Action::Set {
+                    register: Some(register),
+                    value,
+                } => self.cpu.write_register(register, value as i32),
+                Action::Set {
+                    register: None,
+                    value,
+                } => self.cpu.update_pc(value),
+                Action::Stop => return Err(YoveError::ScriptStop { tid: self.tid, pc }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Catch a panic if the thread is about to enter one of the functions
+    /// panics are caught in.
+    fn check_guest_panic(&self) -> Result<(), YoveError> {
+        let Some(catcher) = &self.memory.guest_panics else {
+            return Ok(());
+        };
+        let pc = self.cpu.read_pc();
+        let Some(hook) = catcher.hook_at(pc) else {
+            return Ok(());
+        };
+        Err(self.guest_panic(catcher, pc, true, Some(hook)))
+    }
+
+    fn guest_panic(
+        &self,
+        catcher: &guest_panic::PanicCatcher,
+        pc: u32,
+        entry: bool,
+        hook: Option<String>,
+    ) -> YoveError {
+        let ra = self.cpu.read_register(1) as u32;
+        let fp = self.cpu.read_register(8) as u32;
+        let stack = guest_panic::stack(&self.memory, pc, ra, fp, entry);
+        let panic = catcher.capture(&self.memory, self.tid, pc, stack, hook);
+        YoveError::GuestPanic(Box::new(panic))
+    }
+
+    /// Wait for `wait` to finish without holding up a pause of the machine.
+    fn blocking<T>(&self, wait: impl FnOnce() -> T) -> T {
+        if !self.gated {
+            return wait();
+        }
+        self.memory.pause.leave();
+        let result = wait();
+        self.memory.pause.enter();
+        result
+    }
+
+    /// Return an error to the thread when whatever it was waiting on went
+    /// away without responding, such as a service whose host thread panicked.
+    fn service_failed(&mut self) {
+        self.resume((
+            [
+                SyscallResultNumber::Error as i32,
+                SyscallErrorNumber::InternalError as i32,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            None,
+        ));
+    }
+
+    /// The error to stop the machine with if this thread has waited on a
+    /// service for longer than the response timeout.
+    fn hung(&self) -> Option<YoveError> {
+        let timeout_ms = self.memory.response_timeout_ms?;
+        let Some((trace::BlockReason::Message { connection, opcode }, since_ms)) = self.blocked
+        else {
+            return None;
+        };
+        if self.memory.clock.host_ms().saturating_sub(since_ms) <= timeout_ms {
+            return None;
         }
+        let service = self
+            .memory
+            .connections
+            .lock()
+            .unwrap()
+            .describe(connection)
+            .unwrap_or_else(|| format!("connection {}", connection));
+        Some(YoveError::Hang {
+            timeout_ms,
+            tid: self.tid,
+            pc: self.cpu.read_pc(),
+            service,
+            opcode,
+        })
     }
 
-    fn run(&mut self) -> u32 {
+    /// Advance this thread by one instruction without ever blocking. If the thread
+    /// is waiting on a response, this returns `WorkerEvent::Blocked` immediately.
+    fn step(&mut self) -> WorkerEvent {
         use riscv_cpu::cpu::TickResult;
-        loop {
-            match self.cpu.tick() {
-                // If we get a PauseEmulation result, it will have an accompanying Receiver.
-                // Block on this receiver until we get a result, then load that result into
-                // the CPU.
-                TickResult::PauseEmulation(e) => {
-                    let (result, data) = e.recv().unwrap();
-                    if let Some(data) = data {
-                        let syscall_type = self.cpu.read_register(10);
-                        let message_kind = self.cpu.read_register(12);
-                        let memory_offset = self.cpu.read_register(14) as u32;
-                        // let memory_size = self.cpu.read_register(15);
-
-                        assert!(syscall_type == SyscallNumber::SendMessage as i32);
-                        assert!(message_kind == 1 || message_kind == 2);
-                        let mmu = self.cpu.get_mut_mmu();
-                        for (offset, byte) in data.into_iter().enumerate() {
-                            mmu.store(offset as u32 + memory_offset, byte).unwrap();
+        use std::sync::mpsc::TryRecvError;
+
+        if let Some(watchdog) = self.memory.watchdog.as_ref().filter(|w| w.expired()) {
+            return self.watchdog_expired(watchdog.timeout_ms());
+        }
+        if let Some(offset) = self.memory.flash.power_lost() {
+            self.retire();
+            return WorkerEvent::Failed(YoveError::PowerLoss { offset });
+        }
+
+        if let Some(pending) = &self.pending {
+            match pending.try_recv() {
+                Ok(response) => {
+                    self.pending = None;
+                    if let Some(event) = self.resume(response) {
+                        return event;
+                    }
+                }
+                Err(TryRecvError::Empty) => match self.hung() {
+                    Some(error) => {
+                        self.retire();
+                        return WorkerEvent::Failed(error);
+                    }
+                    None => return WorkerEvent::Blocked,
+                },
+                Err(TryRecvError::Disconnected) => {
+                    self.pending = None;
+                    self.service_failed();
+                }
+            }
+        }
+
+        // A line that stays asserted keeps `wfi` from waiting, the same as a
+        // pending interrupt would
+        let lines = self.memory.notifier.lines();
+        if lines != self.interrupt_lines || (lines != 0 && self.cpu.waiting_for_interrupt()) {
+            self.cpu.lower_interrupt(self.interrupt_lines & !lines);
+            self.cpu.raise_interrupt(lines);
+            self.interrupt_lines = lines;
+        }
+
+        if let Err(error) = self
+            .run_breakpoint_script()
+            .and_then(|_| self.check_guest_panic())
+        {
+            self.retire();
+            return WorkerEvent::Failed(error);
+        }
+
+        let pc = self.cpu.read_pc();
+        match self.cpu.tick() {
+            // Stash the receiver the syscall is waiting on, and load the
+            // result into the CPU once it arrives.
+            TickResult::Suspended(suspension) => {
+                let response = suspension
+                    .downcast::<Receiver<ResponseData>>()
+                    .expect("syscalls only suspend waiting for a response");
+                self.pending = Some(*response);
+                // The syscall's arguments are still in the registers
+                let number = self.cpu.read_register(10);
+                let reason = match SyscallNumber::from(number) {
+                    SyscallNumber::SendMessage | SyscallNumber::TrySendMessage => {
+                        trace::BlockReason::Message {
+                            connection: self.cpu.read_register(11) as u32,
+                            opcode: self.cpu.read_register(13) as u32,
                         }
                     }
-                    for (index, value) in result.iter().enumerate() {
-                        self.cpu.write_register(10 + index as u8, *value);
+                    SyscallNumber::JoinThread => {
+                        trace::BlockReason::Join(self.cpu.read_register(11))
+                    }
+                    _ => trace::BlockReason::Syscall(number as u32),
+                };
+                self.memory
+                    .schedule(self.tid, trace::SchedulerEvent::Blocked(reason));
+                self.blocked = Some((reason, self.memory.clock.host_ms()));
+                WorkerEvent::Blocked
+            }
+            TickResult::ExitThread(val) => {
+                //     self.cmd
+                //         .send(MemoryCommand::ExitThread(self.tid as u32, val))
+                //         .unwrap();
+                // eprintln!("Thread {} exited", self.tid);
+                self.exit(val)
+            }
+            TickResult::TerminateProcess(code) => self.terminate(code, pc),
+            TickResult::CpuTrap(trap) => {
+                if log::log_enabled!(log::Level::Debug) {
+                    let mut map = vec![];
+                    self.memory.dump_mmu(&mut map, MmuFormat::Text).ok();
+                    log::debug!("Memory map:\n{}", String::from_utf8_lossy(&map));
+                }
+                if let Some(metrics) = &self.memory.metrics {
+                    metrics.trap();
+                }
+                let trap = match &self.memory.read_only {
+                    Some(policy) => policy.check(&self.memory, self.tid, pc, trap),
+                    None => trap,
+                };
+                let trap = match &self.memory.w_xor_x {
+                    Some(policy) => policy.check(&self.memory, self.tid, pc, trap),
+                    None => trap,
+                };
+                self.memory.perf_events.trap(self.tid, pc, trap);
+                self.retire();
+                WorkerEvent::Failed(YoveError::Trap {
+                    tid: self.tid,
+                    pc,
+                    trap,
+                })
+            }
+            TickResult::Ok => {
+                let instructions = self.cpu.instructions_retired();
+                self.memory
+                    .thread_instructions
+                    .store(instructions, Ordering::Relaxed);
+                self.memory.perf_events.retired(self.tid, pc, instructions);
+                let sp = self.cpu.read_register(2) as u32;
+                if sp < self.lowest_sp {
+                    self.lowest_sp = sp;
+                    self.memory.thread_lowest_sp.store(sp, Ordering::Relaxed);
+                }
+                self.sample();
+                if let Err(error) = self.check_shadow_stack(pc) {
+                    self.retire();
+                    return WorkerEvent::Failed(error);
+                }
+                if let Some(depth) = &mut self.call_depth {
+                    let (returns, calls) =
+                        shadow_stack::call_or_return(self.cpu.last_instruction());
+                    *depth += calls as i32 - returns as i32;
+                }
+                if let Some(cfg) = &self.memory.cfg {
+                    let word = self.cpu.last_instruction();
+                    cfg.step(&mut self.cfg_block, pc, word, self.cpu.read_pc());
+                }
+                if let Some(stats) = &self.memory.branch_stats {
+                    stats.step(pc, self.cpu.last_instruction(), self.cpu.read_pc());
+                }
+                if let Some(tracer) = &self.memory.tracer {
+                    if tracer.covering() {
+                        tracer.cover(pc);
+                    }
+                    if tracer.recording() {
+                        tracer.record(self.tid, pc, &self.cpu);
                     }
                 }
-                TickResult::ExitThread(val) => {
-                    //     self.cmd
-                    //         .send(MemoryCommand::ExitThread(self.tid as u32, val))
-                    //         .unwrap();
-                    // eprintln!("Thread {} exited", self.tid);
-                    return val;
-                }
-                TickResult::JoinThread(handle) => {
-                    let result = handle.join().unwrap();
-                    self.cpu
-                        .write_register(10, SyscallResultNumber::Scalar1 as i32);
-                    self.cpu.write_register(11, result as i32);
-                    for reg in 12..18 {
-                        self.cpu.write_register(reg, 0);
+                if let (Some(log), Some(commit)) = (&self.memory.commit_log, self.cpu.last_commit())
+                {
+                    log.write(commit);
+                }
+                WorkerEvent::Ran
+            }
+        }
+    }
+
+    /// Run this thread to completion on the current host thread, blocking
+    /// whenever the guest waits on a response or the machine is paused.
+    /// Returns `Exited`, `Terminated`, or `Failed`.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run(&mut self) -> WorkerEvent {
+        self.gated = true;
+        self.memory.pause.enter();
+        let event = self.run_gated();
+        self.memory.pause.leave();
+        event
+    }
+
+    /// Run this thread like `run()`, but if the emulator panics while doing
+    /// so, stop only this thread rather than the whole process. Anything
+    /// joining the thread gets an `InternalError`. A panic on the main thread
+    /// still ends the process, with `YoveError::Panic`.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run_contained(&mut self) -> WorkerEvent {
+        let payload = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.run())) {
+            Ok(event) => return event,
+            Err(payload) => payload,
+        };
+        // Panics only happen while the thread is running instructions, so it
+        // still counts as running as far as pausing is concerned.
+        self.memory.pause.leave();
+        self.contain_panic(payload)
+    }
+
+    /// Stop this thread after the emulator panicked while running it, the
+    /// way `run_contained()` does, and return how it ended.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn contain_panic(&mut self, payload: Box<dyn std::any::Any + Send>) -> WorkerEvent {
+        self.memory.clear_poison();
+        self.retire();
+
+        let fault = ThreadFault {
+            tid: self.tid,
+            pc: self.cpu.read_pc(),
+            message: panic_message(&*payload),
+        };
+        self.memory
+            .thread_faults
+            .lock()
+            .unwrap()
+            .push(fault.clone());
+        if let Some(tracer) = &self.memory.tracer {
+            let elapsed_ms = self.memory.platform.elapsed_ms();
+            tracer.record_fault(elapsed_ms, fault.tid, fault.pc, fault.message.clone());
+        }
+        if self.tid == 0 {
+            return WorkerEvent::Failed(YoveError::Panic {
+                tid: fault.tid,
+                pc: fault.pc,
+                message: fault.message,
+            });
+        }
+        if let Some(join) = self.join.take() {
+            join.send((
+                [
+                    SyscallResultNumber::Error as i32,
+                    SyscallErrorNumber::InternalError as i32,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                ],
+                None,
+            ))
+            .ok();
+        }
+        WorkerEvent::Exited(!0)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run_gated(&mut self) -> WorkerEvent {
+        use std::sync::mpsc::RecvTimeoutError;
+
+        loop {
+            self.memory.pause.checkpoint();
+            match self.step() {
+                WorkerEvent::Ran => {}
+                // Wake up now and then so the watchdog and the response
+                // timeout can stop blocked threads too
+                WorkerEvent::Blocked
+                    if self.memory.watchdog.is_some()
+                        || self.memory.response_timeout_ms.is_some() =>
+                {
+                    let pending = self.pending.take().unwrap();
+                    match self.blocking(|| pending.recv_timeout(SERVICE_TICK_INTERVAL)) {
+                        Ok(response) => {
+                            if let Some(event) = self.resume(response) {
+                                return event;
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => self.pending = Some(pending),
+                        Err(RecvTimeoutError::Disconnected) => self.service_failed(),
                     }
-                    // self.cmd
-                    //     .send(MemoryCommand::ExitThread(self.tid as u32, result))
-                    //     .unwrap();
                 }
-                TickResult::CpuTrap(trap) => {
-                    self.memory.print_mmu();
-                    // called `Result::unwrap()` on an `Err` value: "Valid bit is 0, or read is 0 and write is 1 at 40002fec: 000802e6"
-                    println!(
-                        "CPU trap at PC {:08x}, exiting thread {}: {:x?}",
-                        self.cpu.read_pc(),
-                        self.tid,
-                        trap
-                    );
-                    // self.cmd
-                    //     .send(MemoryCommand::ExitThread(self.tid as u32, 1))
-                    //     .unwrap();
-                    return !0;
+                WorkerEvent::Blocked => {
+                    let pending = self.pending.take().unwrap();
+                    match self.blocking(|| pending.recv()) {
+                        Ok(response) => {
+                            if let Some(event) = self.resume(response) {
+                                return event;
+                            }
+                        }
+                        Err(_) => self.service_failed(),
+                    }
                 }
-                TickResult::Ok => {}
+                event => return event,
             }
         }
     }
@@ -174,33 +1042,182 @@ impl Worker {
 #[derive(Clone)]
 struct Memory {
     base: u32,
-    data: Arc<Vec<RwLock<Vec<u32>>>>,
+    data: Arc<backing::Backing>,
     allocated_pages: Arc<Mutex<BTreeSet<usize>>>,
     free_pages: Arc<Mutex<BTreeSet<usize>>>,
-    heap_start: Arc<AtomicU32>,
-    heap_size: Arc<AtomicU32>,
-    allocation_previous: Arc<AtomicU32>,
-    l1_pt: u32,
-    satp: u32,
-    connections: Arc<Mutex<HashMap<u32, Box<dyn services::Service + Send + Sync>>>>,
-    connection_index: Arc<AtomicU32>,
-    named_connections_index: Arc<Mutex<HashMap<[u32; 4], u32>>>,
+
+    /// The page tables and heap of the process this handle belongs to.
+    space: Arc<address_space::AddressSpace>,
+    connections: Arc<Mutex<connections::Connections>>,
     memory_cmd: Sender<MemoryCommand>,
-    translation_cache: Arc<RwLock<Vec<Option<NonZeroU32>>>>,
+    translation_cache: Arc<translation_cache::TranslationCache>,
     allocated_bytes: Arc<AtomicU32>,
-    reservations: Arc<Mutex<HashMap<u32, u32>>>,
-    thread_handles: Arc<Mutex<HashMap<i32, JoinHandle<u32>>>>,
+    /// The word each core has reserved with LR.
+    reservations: Arc<RwLock<HashMap<u32, u32>>>,
+    thread_handles: Arc<Mutex<HashMap<i32, Receiver<ResponseData>>>>,
+    thread_id_counter: Arc<AtomicI32>,
+    /// The platform, with its clock replaced by `clock`.
+    platform: Arc<dyn Platform>,
+    clock: Arc<clock::VirtualClock>,
+    pause: Arc<pause::PauseControl>,
+    faults: Option<Arc<faults::FaultInjector>>,
+    profiler: Option<Arc<profiler::Profiler>>,
+    heatmap: Option<Arc<heatmap::Heatmap>>,
+    tracer: Option<Arc<trace::Tracer>>,
+    message_stats: Option<Arc<message_stats::MessageStats>>,
+    commit_log: Option<Arc<commit_log::CommitLog>>,
+    watchdog: Option<Arc<watchdog::Watchdog>>,
+
+    /// The counters behind the metrics endpoint, if it's being served.
+    metrics: Option<Arc<metrics::Metrics>>,
+
+    /// How long a thread may wait for a service to respond before the
+    /// machine stops with `YoveError::Hang`.
+    response_timeout_ms: Option<u64>,
+    shadow_stack: Option<Arc<shadow_stack::ShadowStackPolicy>>,
+    breakpoint_script: Option<Arc<breakpoint_script::ScriptedBreakpoints>>,
+    guest_panics: Option<Arc<guest_panic::PanicCatcher>>,
+    cfg: Option<Arc<cfg::ControlFlowGraph>>,
+    branch_stats: Option<Arc<branch_stats::BranchStats>>,
+    framebuffer: Option<Arc<framebuffer::Framebuffer>>,
+    uninit: Option<Arc<uninit::UninitTracker>>,
+
+    /// The program's sections, if no page may be both writable and
+    /// executable.
+    w_xor_x: Option<Arc<w_xor_x::WxPolicy>>,
+
+    /// The program's sections, if those that aren't writable are mapped
+    /// read-only.
+    read_only: Option<Arc<read_only::ReadOnlyPolicy>>,
+
+    /// Who wants to hear about samples, traps, and context switches.
+    perf_events: Arc<perf_events::PerfEvents>,
+    ring_buffers: Arc<Mutex<HashMap<u32, Arc<RingBuffer>>>>,
+
+    /// Notifications and interrupts for the guest that it didn't ask for.
+    notifier: Arc<notify::Notifier>,
+
+    /// The battery, charger, and wifi status the COM server reports.
+    ec: Arc<ec::Ec>,
+
+    /// The keys the keyboard server reports.
+    keyboard: Arc<keyboard::Keyboard>,
+
+    /// The USB port the usb-device server drives.
+    usb: Arc<usb::UsbDevice>,
+
+    /// The host commands the program may run, if it may run any.
+    host_exec: Option<Arc<host_exec::HostExec>>,
+
+    /// Where the codec server plays audio.
+    audio: Arc<audio::Audio>,
+
+    /// The flash the `yove-flash` server reads and writes.
+    flash: Arc<flash::Flash>,
+
+    /// The device that servers chosen with `MachineBuilder::bridge` are
+    /// passed on to, if any.
+    bridge: Option<Arc<bridge::Bridge>>,
+
+    /// Names registered with the name server, with their connection limits.
+    names: Arc<Mutex<BTreeMap<String, Option<u32>>>>,
+
+    /// Instructions retired by threads that have exited.
+    instructions_retired: Arc<AtomicU64>,
+
+    /// The thread whose CPU accesses memory through this handle, for the heatmap.
+    tid: i32,
+
+    /// Instructions retired so far by that thread, kept up to date by its `Worker`.
+    thread_instructions: Arc<AtomicU64>,
+
+    /// The lowest that thread's stack pointer has been, kept up to date by
+    /// its `Worker`.
+    thread_lowest_sp: Arc<AtomicU32>,
+
+    /// The instruction counter of every thread that has been started.
+    threads: Arc<Mutex<BTreeMap<i32, ThreadAccount>>>,
+
+    /// An error raised while handling a syscall, which ends the process.
+    failure: Arc<Mutex<Option<YoveError>>>,
+
+    /// Raise access faults for addresses outside of RAM, rather than reading
+    /// zero and ignoring writes.
+    strict_memory: bool,
+
+    /// Print every syscall, its result, and how long it took to stderr.
+    strace: bool,
+
+    /// Map aligned 4MB regions with a single megapage rather than 1024 pages.
+    megapages: bool,
+
+    /// The length in bits of the vector registers, if CPUs have a vector unit.
+    vlen: Option<u32>,
+
+    /// Let CPUs access CSRs they don't implement rather than trap.
+    permissive_csrs: bool,
+
+    /// What CPUs do with compressed HINTs.
+    hints: riscv_cpu::cpu::HintPolicy,
+
+    /// The most RAM, in bytes, that may be allocated at once, if less than
+    /// all of it.
+    memory_limit: Option<u32>,
+
+    /// The most guest threads that may be running at once.
+    thread_limit: Option<usize>,
+
+    /// Guest threads that have been created and haven't exited, including
+    /// ones not yet started.
+    live_threads: Arc<AtomicUsize>,
+
+    /// What user-mode reads of the counter CSRs return.
+    counters: counters::CounterPolicy,
+
+    /// What happens to a thread that misuses a syscall.
+    abuse: abuse::AbusePolicy,
+
+    /// Allocate stack and heap pages when they're first touched rather than
+    /// when they're mapped.
+    demand_paging: bool,
+
+    /// Where the randomness comes from when the memory layout is randomized.
+    layout: Option<Arc<rng::Rng>>,
+
+    /// Where the TRNG server's numbers come from.
+    trng: Arc<rng::Rng>,
+
+    /// How many messages each server may hold without responding.
+    server_queue_depth: usize,
+
+    /// The queue entry taken by each thread that is waiting on a server.
+    queue_slots: Arc<Mutex<HashMap<i32, connections::QueueSlot>>>,
+
+    /// The first `INVALID_ACCESS_LOG_LIMIT` accesses outside of RAM.
+    invalid_accesses: Arc<Mutex<Vec<InvalidAccess>>>,
+
+    /// Threads that were stopped because the emulator panicked.
+    thread_faults: Arc<Mutex<Vec<ThreadFault>>>,
+
+    /// How many times the guest has suspended through the suspend/resume
+    /// manager.
+    suspends: Arc<AtomicU32>,
 }
 
 impl Memory {
-    pub fn new(base: u32, size: usize) -> (Self, Receiver<MemoryCommand>) {
-        let mut backing = vec![];
+    pub fn new(
+        base: u32,
+        size: usize,
+        platform: Arc<dyn Platform>,
+    ) -> (Self, Receiver<MemoryCommand>) {
+        // The heap and `MapMemory` windows each cover 5/16 of RAM, so that
+        // they grow with it while still leaving room for the program and stack
+        let window = (size as u64 * 5 / 16) as u32 & !0xfff;
         let mut free_pages = BTreeSet::new();
         let mut allocated_pages = BTreeSet::new();
 
-        // Populate the backing table as well as the list of free pages
+        // Every page starts out free, and only takes host memory once written
         for phys in (0..(size as u32)).step_by(4096) {
-            backing.push(RwLock::new(vec![0; 1024]));
             free_pages.insert((phys + base) as usize);
         }
         // Allocate the l0 page table
@@ -208,88 +1225,440 @@ impl Memory {
         assert!(allocated_pages.insert(MEMORY_BASE as usize + 4096));
 
         let (memory_cmd, memory_cmd_rx) = std::sync::mpsc::channel();
+        let clock = Arc::new(clock::VirtualClock::new(platform));
+        let thread_instructions = Arc::new(AtomicU64::new(0));
+        let thread_lowest_sp = Arc::new(AtomicU32::new(STACK_END));
+        let main_thread = ThreadAccount {
+            instructions: thread_instructions.clone(),
+            exited: false,
+            stack: STACK_START..STACK_END,
+            lowest_sp: thread_lowest_sp.clone(),
+        };
         (
             Self {
                 base,
-                data: Arc::new(backing),
+                data: Arc::new(backing::Backing::new(size / 4096)),
                 allocated_pages: Arc::new(Mutex::new(allocated_pages)),
                 free_pages: Arc::new(Mutex::new(free_pages)),
-                l1_pt: MEMORY_BASE + 4096,
-                satp: ((4096 + MEMORY_BASE) >> 12) | 0x8000_0000,
-                heap_start: Arc::new(AtomicU32::new(HEAP_START)),
-                heap_size: Arc::new(AtomicU32::new(0)),
-                allocation_previous: Arc::new(AtomicU32::new(ALLOCATION_START)),
-                connections: Arc::new(Mutex::new(HashMap::new())),
-                connection_index: Arc::new(AtomicU32::new(1)),
+                space: Arc::new(address_space::AddressSpace::new(
+                    0,
+                    MEMORY_BASE + 4096,
+                    HEAP_START,
+                    HEAP_START + window,
+                    ALLOCATION_START,
+                    ALLOCATION_START + window,
+                    STACK_START..STACK_END,
+                )),
+                connections: Arc::new(Mutex::new(connections::Connections::default())),
                 memory_cmd,
-                translation_cache: Arc::new(RwLock::new(vec![None; 0x000f_ffff])),
+                translation_cache: Arc::new(translation_cache::TranslationCache::default()),
                 allocated_bytes: Arc::new(AtomicU32::new(4096)),
-                reservations: Arc::new(Mutex::new(HashMap::new())),
+                reservations: Arc::new(RwLock::new(HashMap::new())),
                 thread_handles: Arc::new(Mutex::new(HashMap::new())),
-                named_connections_index: Arc::new(Mutex::new(HashMap::new())),
+                thread_id_counter: Arc::new(AtomicI32::new(1)),
+                platform: clock.clone(),
+                pause: Arc::new(pause::PauseControl::new(clock.clone())),
+                clock,
+                faults: None,
+                profiler: None,
+                heatmap: None,
+                tracer: None,
+                message_stats: None,
+                commit_log: None,
+                watchdog: None,
+                metrics: None,
+                response_timeout_ms: None,
+                shadow_stack: None,
+                breakpoint_script: None,
+                guest_panics: None,
+                cfg: None,
+                branch_stats: None,
+                framebuffer: None,
+                uninit: None,
+                w_xor_x: None,
+                read_only: None,
+                perf_events: Arc::new(perf_events::PerfEvents::default()),
+                ring_buffers: Arc::new(Mutex::new(HashMap::new())),
+                notifier: Arc::new(notify::Notifier::default()),
+                ec: Arc::new(ec::Ec::default()),
+                keyboard: Arc::new(keyboard::Keyboard::default()),
+                usb: Arc::new(usb::UsbDevice::default()),
+                host_exec: None,
+                audio: Arc::new(audio::Audio::default()),
+                flash: Arc::new(flash::Flash::new(0)),
+                bridge: None,
+                names: Arc::new(Mutex::new(BTreeMap::new())),
+                instructions_retired: Arc::new(AtomicU64::new(0)),
+                tid: 0,
+                thread_instructions,
+                thread_lowest_sp,
+                threads: Arc::new(Mutex::new(BTreeMap::from([(0, main_thread)]))),
+                failure: Arc::new(Mutex::new(None)),
+                strict_memory: false,
+                strace: false,
+                megapages: false,
+                vlen: None,
+                permissive_csrs: false,
+                hints: riscv_cpu::cpu::HintPolicy::Ignore,
+                memory_limit: None,
+                thread_limit: None,
+                live_threads: Arc::new(AtomicUsize::new(1)),
+                counters: counters::CounterPolicy::Native,
+                abuse: abuse::AbusePolicy::default(),
+                demand_paging: false,
+                layout: None,
+                trng: Arc::new(rng::Rng::new(0)),
+                server_queue_depth: DEFAULT_SERVER_QUEUE_DEPTH,
+                queue_slots: Arc::new(Mutex::new(HashMap::new())),
+                invalid_accesses: Arc::new(Mutex::new(vec![])),
+                thread_faults: Arc::new(Mutex::new(vec![])),
+                suspends: Arc::new(AtomicU32::new(0)),
             },
             memory_cmd_rx,
         )
     }
 
-    // fn memory_ck(&self) {
-    //     if self.turbo {
-    //         return;
-    //     }
-    //     let mut seen_pages = HashMap::new();
-    //     seen_pages.insert(self.l1_pt, 0);
-    //     for vpn1 in 0..1024 {
-    //         let l1_entry = self.read_u32(self.l1_pt as u64 + vpn1 * 4);
-    //         if l1_entry & MMUFLAG_VALID == 0 {
-    //             continue;
-    //         }
-
-    //         let superpage_addr = vpn1 as u32 * (1 << 22);
-
-    //         for vpn0 in 0..1024 {
-    //             let l0_entry = self.read_u32((((l1_entry >> 10) << 12) as u64) + vpn0 as u64 * 4);
-    //             if l0_entry & 0x1 == 0 {
-    //                 continue;
-    //             }
-    //             let phys = (l0_entry >> 10) << 12;
-    //             let current = superpage_addr + vpn0 as u32 * (1 << 12);
-    //             if let Some(existing) = seen_pages.get(&phys) {
-    //                 self.print_mmu();
-    //                 panic!(
-    //                     "Error! Page {:08x} is mapped twice! Once at {:08x} and once at {:08x}",
-    //                     phys, existing, current,
-    //                 );
-    //             }
-    //             seen_pages.insert(phys, current);
-    //         }
-    //     }
-    // }
-
-    /// Allocate a physical page from RAM.
-    fn allocate_phys_page(&self) -> Option<u32> {
-        let Some(phys) = self.free_pages.lock().unwrap().pop_first() else {
-            // panic!(
-            //     "out of memory when attempting to allocate a page. There are {} bytes allocated.",
-            //     self.allocated_bytes
-            // );
-            return None;
-        };
-        assert!(self.allocated_pages.lock().unwrap().insert(phys));
-        self.allocated_bytes.fetch_add(4096, Ordering::Relaxed);
-
-        // The root (l1) pagetable is defined to be mapped into our virtual
-        // address space at this address.
-        if phys == 0 {
-            panic!("Attempt to allocate zero page");
+    /// Check that the page tables and the allocator agree: every page that
+    /// is mapped, or holds a page table, is allocated and used only once,
+    /// every allocated page is one of those, and the translation cache
+    /// matches the page tables. Returns what's wrong otherwise.
+    fn check_page_tables(&self) -> Result<(), String> {
+        let allocated_pages = self.allocated_pages.lock().unwrap().clone();
+        let free_pages = self.free_pages.lock().unwrap();
+        if let Some(page) = allocated_pages.intersection(&free_pages).next() {
+            return Err(format!("page {:08x} is both allocated and free", page));
+        }
+        let allocated_bytes = self.allocated_bytes.load(Ordering::Relaxed) as usize;
+        if allocated_bytes != allocated_pages.len() * 4096 {
+            return Err(format!(
+                "{} bytes are counted as allocated, but {} pages are",
+                allocated_bytes,
+                allocated_pages.len()
+            ));
         }
-        Some(phys as u32)
-    }
-
-    fn free_virt_page(&self, virt: u32) -> Result<(), ()> {
-        let phys = self
-            .virt_to_phys(virt)
-            .ok_or(())
-            .expect("tried to free a page that was allocated");
+
+        // Each page in use, and what it's used for
+        let mut seen = HashMap::new();
+        let mut claim = |phys: u32, user: String| {
+            if !allocated_pages.contains(&(phys as usize)) {
+                return Err(format!(
+                    "{} is page {:08x}, which isn't allocated",
+                    user, phys
+                ));
+            }
+            if let Some(existing) = seen.get(&phys) {
+                return Err(format!(
+                    "page {:08x} is used twice, for {} and for {}",
+                    phys, existing, user
+                ));
+            }
+            seen.insert(phys, user);
+            Ok(())
+        };
+        claim(self.space.l1_pt, "the root page table".to_owned())?;
+        for vpn1 in 0..1024 {
+            let l1_entry = self.peek_u32(self.space.l1_pt + vpn1 * 4);
+            if l1_entry & MMUFLAG_VALID == 0 {
+                continue;
+            }
+            let phys = (l1_entry >> 10) << 12;
+            if self.megapage_entry(vpn1 << 22).is_some() {
+                if !phys.is_multiple_of(MEGAPAGE_SIZE) {
+                    return Err(format!("megapage {:08x} is misaligned", vpn1 << 22));
+                }
+                for offset in (0..MEGAPAGE_SIZE).step_by(4096) {
+                    claim(phys + offset, format!("page {:08x}", (vpn1 << 22) + offset))?;
+                }
+                continue;
+            }
+            claim(phys, format!("the page table for {:08x}", vpn1 << 22))?;
+            for vpn0 in 0..1024 {
+                let l0_entry = self.peek_u32(phys + vpn0 * 4);
+                if l0_entry & MMUFLAG_VALID == 0 {
+                    continue;
+                }
+                let virt = vpn1 << 22 | vpn0 << 12;
+                // The page tables are claimed as such, not as the pages
+                // they're mapped at
+                let table = if virt == PAGE_TABLE_ROOT_OFFSET {
+                    Some(self.space.l1_pt)
+                } else if (PAGE_TABLE_OFFSET..PAGE_TABLE_ROOT_OFFSET).contains(&virt) {
+                    let index = (virt - PAGE_TABLE_OFFSET) >> 12;
+                    Some((self.peek_u32(self.space.l1_pt + index * 4) >> 10) << 12)
+                } else {
+                    None
+                };
+                match table {
+                    Some(table) if table == (l0_entry >> 10) << 12 => {}
+                    Some(_) => {
+                        return Err(format!(
+                            "{:08x} maps something other than its page table",
+                            virt
+                        ))
+                    }
+                    None => claim((l0_entry >> 10) << 12, format!("page {:08x}", virt))?,
+                }
+            }
+        }
+        if let Some(page) = allocated_pages
+            .iter()
+            .find(|&&page| !seen.contains_key(&(page as u32)))
+        {
+            return Err(format!("page {:08x} is allocated but unused", page));
+        }
+
+        for (virt, phys, flags) in self.translation_cache.entries(self.space.asid) {
+            if self.virt_to_phys(virt) != Some(phys) {
+                return Err(format!(
+                    "the translation cache maps {:08x} to {:08x}, but the page tables don't",
+                    virt, phys
+                ));
+            }
+            let mapped = self.page_flags(virt).unwrap_or(0);
+            let permissions = MMUFLAG_READABLE | MMUFLAG_WRITABLE | MMUFLAG_EXECUTABLE;
+            if flags & !mapped & permissions != 0 {
+                return Err(format!(
+                    "the translation cache allows {:08x} more than its flags {:#x} do",
+                    virt, mapped
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `bytes` more of RAM can be allocated without going over the
+    /// memory limit.
+    fn within_memory_limit(&self, bytes: u32) -> bool {
+        let Some(limit) = self.memory_limit else {
+            return true;
+        };
+        let allocated = self.allocated_bytes.load(Ordering::Relaxed);
+        if allocated.saturating_add(bytes) <= limit {
+            return true;
+        }
+        log::warn!(
+            "Refusing to allocate {} bytes with {} of the {} byte memory limit in use",
+            bytes,
+            allocated,
+            limit
+        );
+        false
+    }
+
+    /// Allocate a physical page from RAM.
+    fn allocate_phys_page(&self) -> Option<u32> {
+        if !self.within_memory_limit(4096) {
+            return None;
+        }
+        let Some(phys) = self.free_pages.lock().unwrap().pop_first() else {
+            // panic!(
+            //     "out of memory when attempting to allocate a page. There are {} bytes allocated.",
+            //     self.allocated_bytes
+            // );
+            return None;
+        };
+        assert!(self.allocated_pages.lock().unwrap().insert(phys));
+        self.allocated_bytes.fetch_add(4096, Ordering::Relaxed);
+
+        // The root (l1) pagetable is defined to be mapped into our virtual
+        // address space at this address.
+        if phys == 0 {
+            panic!("Attempt to allocate zero page");
+        }
+        Some(phys as u32)
+    }
+
+    /// The level 1 page table entry that maps `virt` as part of a megapage, if any.
+    fn megapage_entry(&self, virt: u32) -> Option<u32> {
+        let entry = self.peek_u32(self.space.l1_pt + (virt >> 22) * 4);
+        let leaf = MMUFLAG_READABLE | MMUFLAG_WRITABLE | MMUFLAG_EXECUTABLE;
+        (entry & MMUFLAG_VALID != 0 && entry & leaf != 0).then_some(entry)
+    }
+
+    /// Allocate 4MB of contiguous physical RAM, aligned to 4MB as a megapage
+    /// must be.
+    fn allocate_phys_megapage(&self) -> Option<u32> {
+        if !self.within_memory_limit(MEGAPAGE_SIZE) {
+            return None;
+        }
+        let pages = (MEGAPAGE_SIZE / 4096) as usize;
+        let mut free_pages = self.free_pages.lock().unwrap();
+        let start = (self.base..self.base + (self.data.page_count() * 4096) as u32)
+            .step_by(MEGAPAGE_SIZE as usize)
+            .map(|start| start as usize)
+            .find(|&start| free_pages.range(start..start + pages * 4096).count() == pages)?;
+        let mut allocated_pages = self.allocated_pages.lock().unwrap();
+        for page in (start..start + pages * 4096).step_by(4096) {
+            free_pages.remove(&page);
+            allocated_pages.insert(page);
+        }
+        self.allocated_bytes
+            .fetch_add(MEGAPAGE_SIZE, Ordering::Relaxed);
+        Some(start as u32)
+    }
+
+    /// Map the 4MB-aligned region at `virt` with a single megapage. Returns
+    /// `None` if part of the region is already mapped, or there's no
+    /// contiguous physical memory left for it.
+    fn map_megapage(&self, virt: u32) -> Option<()> {
+        assert!(virt.is_multiple_of(MEGAPAGE_SIZE));
+        let l1_pt_entry = self.space.l1_pt + (virt >> 22) * 4;
+        if self.peek_u32(l1_pt_entry) & MMUFLAG_VALID != 0 {
+            return None;
+        }
+        let phys = self.allocate_phys_megapage()?;
+        self.poke_u32(l1_pt_entry, ((phys >> 12) << 10) | self.user_page_flags());
+        for offset in (0..MEGAPAGE_SIZE).step_by(4096) {
+            self.translation_cache.insert(
+                self.space.asid,
+                virt + offset,
+                phys + offset,
+                self.user_page_flags(),
+            );
+            if let Some(uninit) = &self.uninit {
+                uninit.map_page(phys + offset);
+            }
+        }
+        Some(())
+    }
+
+    /// Unmap the whole megapage at `virt` and free its memory.
+    fn free_megapage(&self, virt: u32) {
+        let entry = self.megapage_entry(virt).expect("not a megapage");
+        let virt = virt & !(MEGAPAGE_SIZE - 1);
+        let phys = (entry >> 10) << 12;
+        let mut allocated_pages = self.allocated_pages.lock().unwrap();
+        let mut free_pages = self.free_pages.lock().unwrap();
+        for offset in (0..MEGAPAGE_SIZE).step_by(4096) {
+            assert!(allocated_pages.remove(&((phys + offset) as usize)));
+            assert!(free_pages.insert((phys + offset) as usize));
+            self.translation_cache
+                .invalidate(self.space.asid, virt + offset);
+            if let Some(uninit) = &self.uninit {
+                uninit.unmap_page(phys + offset);
+            }
+        }
+        self.allocated_bytes
+            .fetch_sub(MEGAPAGE_SIZE, Ordering::Relaxed);
+        self.poke_u32(self.space.l1_pt + (virt >> 22) * 4, 0);
+    }
+
+    /// Turn the megapage at `virt` into a level 0 page table of 4K pages
+    /// covering the same memory, so that pages in it can be changed on their own.
+    fn split_megapage(&self, virt: u32) -> Option<()> {
+        let entry = self.megapage_entry(virt).expect("not a megapage");
+        let phys = (entry >> 10) << 12;
+        let l0_pt = self.allocate_phys_page()?;
+        for index in 0..MEGAPAGE_SIZE / 4096 {
+            let page = phys + index * 4096;
+            self.poke_u32(l0_pt + index * 4, ((page >> 12) << 10) | (entry & 0xff));
+        }
+        self.poke_u32(
+            self.space.l1_pt + (virt >> 22) * 4,
+            ((l0_pt >> 12) << 10) | MMUFLAG_VALID | MMUFLAG_DIRTY | MMUFLAG_ACCESSED,
+        );
+        self.map_page_table(PAGE_TABLE_OFFSET + (virt >> 22) * 4096, l0_pt)
+    }
+
+    /// Map the page table at `phys` at `virt`, where the guest can read but
+    /// not write it. Xous keeps every process's page tables in its address
+    /// space like this, so that it can look up its own mappings.
+    fn map_page_table(&self, virt: u32, phys: u32) -> Option<()> {
+        let l1_pt_entry_phys = self.space.l1_pt + (virt >> 22) * 4;
+        let mut l1_pt_entry = self.peek_u32(l1_pt_entry_phys);
+        if l1_pt_entry & MMUFLAG_VALID == 0 {
+            let l0_pt = self.allocate_phys_page()?;
+            l1_pt_entry = ((l0_pt >> 12) << 10) | MMUFLAG_VALID | MMUFLAG_DIRTY | MMUFLAG_ACCESSED;
+            self.poke_u32(l1_pt_entry_phys, l1_pt_entry);
+            // The table that maps the level 0 tables is one of them, and
+            // maps itself
+            self.map_page_table(PAGE_TABLE_OFFSET + (virt >> 22) * 4096, l0_pt)?;
+        }
+        let flags = MMUFLAG_VALID | MMUFLAG_READABLE | MMUFLAG_USERMODE | MMUFLAG_ACCESSED;
+        let l0_pt_entry = ((phys >> 12) << 10) | flags;
+        self.poke_u32(
+            ((l1_pt_entry >> 10) << 12) + ((virt >> 12) & 0x3ff) * 4,
+            l0_pt_entry,
+        );
+        self.translation_cache
+            .insert(self.space.asid, virt, phys, l0_pt_entry);
+        Some(())
+    }
+
+    /// Map `size` bytes starting at `start`. With megapages enabled, each
+    /// aligned 4MB that the region covers and that isn't mapped yet gets a
+    /// megapage, falling back to 4K pages if there's no contiguous physical
+    /// memory for one. Returns `None` if memory runs out, leaving whatever
+    /// was mapped so far in place.
+    fn map_region(&self, start: u32, size: u32) -> Option<()> {
+        let end = start + size;
+        let mut address = start;
+        while address < end {
+            if self.megapages
+                && address.is_multiple_of(MEGAPAGE_SIZE)
+                && end - address >= MEGAPAGE_SIZE
+                && self.map_megapage(address).is_some()
+            {
+                address += MEGAPAGE_SIZE;
+                continue;
+            }
+            self.ensure_page(address)?;
+            address += 4096;
+        }
+        Some(())
+    }
+
+    /// Unmap the pages from `start` up to `end`, detaching any ring buffer
+    /// that uses them, freeing whole megapages the region covers and
+    /// splitting any it only partly covers. Pages
+    /// that can't be freed are skipped, and the first error is returned
+    /// once the rest have been: `DoubleFree` for a page that wasn't mapped,
+    /// or `OutOfMemory` if there's no page to split a megapage with.
+    fn unmap_region(&self, start: u32, end: u32) -> Result<(), SyscallErrorNumber> {
+        let rings: Vec<_> = self
+            .ring_buffers
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for ring in rings {
+            ring.detach_unmapped(self.space.asid, &(start..end));
+        }
+        if let Some(framebuffer) = &self.framebuffer {
+            framebuffer.detach_unmapped(self.space.asid, &(start..end));
+        }
+        let mut result = Ok(());
+        let mut address = start;
+        while address < end {
+            if address.is_multiple_of(MEGAPAGE_SIZE)
+                && end - address >= MEGAPAGE_SIZE
+                && self.megapage_entry(address).is_some()
+            {
+                self.free_megapage(address);
+                address += MEGAPAGE_SIZE;
+                continue;
+            }
+            if let Err(error) = self.free_virt_page(address) {
+                result = result.and(Err(error));
+            }
+            address += 4096;
+        }
+        result
+    }
+
+    fn free_virt_page(&self, virt: u32) -> Result<(), SyscallErrorNumber> {
+        if self.megapage_entry(virt).is_some() {
+            self.split_megapage(virt)
+                .ok_or(SyscallErrorNumber::OutOfMemory)?;
+        }
+        if let Some(entry) = self.lazy_entry(virt) {
+            self.poke_u32(entry, 0);
+            return Ok(());
+        }
+        let phys = self
+            .virt_to_phys(virt)
+            .ok_or(SyscallErrorNumber::DoubleFree)?;
 
         let vpn1 = ((virt >> 22) & ((1 << 10) - 1)) as usize * 4;
         let vpn0 = ((virt >> 12) & ((1 << 10) - 1)) as usize * 4;
@@ -299,7 +1668,7 @@ impl Memory {
         // address space at this address.
 
         // If the level 1 pagetable doesn't exist, then this address is invalid
-        let l1_pt_entry = self.read_u32(self.l1_pt + vpn1 as u32);
+        let l1_pt_entry = self.peek_u32(self.space.l1_pt + vpn1 as u32);
         if l1_pt_entry & MMUFLAG_VALID == 0 {
             panic!("Tried to free a page where the level 1 pagetable didn't exist");
         }
@@ -310,24 +1679,47 @@ impl Memory {
             .unwrap()
             .remove(&(phys as usize)));
         assert!(self.free_pages.lock().unwrap().insert(phys as usize));
-        self.translation_cache.write().unwrap()[phys as usize >> 12] = None;
+        self.translation_cache.invalidate(self.space.asid, virt);
+        if let Some(uninit) = &self.uninit {
+            uninit.unmap_page(phys);
+        }
 
         let l0_pt_phys = ((l1_pt_entry >> 10) << 12) + vpn0 as u32;
-        assert!(self.read_u32(l0_pt_phys) & MMUFLAG_VALID != 0);
-        self.write_u32(l0_pt_phys, 0);
+        assert!(self.peek_u32(l0_pt_phys) & MMUFLAG_VALID != 0);
+        self.poke_u32(l0_pt_phys, 0);
 
         Ok(())
     }
 
     fn allocate_virt_region(&self, size: usize) -> Option<u32> {
+        let window = self.space.allocation.lock().unwrap().clone();
+        let (allocation_start, allocation_end) = (window.start, window.end);
+        if size == 0 || size > (allocation_end - allocation_start) as usize {
+            return None;
+        }
         let size = size as u32;
         // Look for a sequence of `size` pages that are free.
         let mut address = None;
-        let allocation_previous = self.allocation_previous.load(Ordering::Relaxed);
-        for potential_start in (allocation_previous..ALLOCATION_END - size)
-            .step_by(4096)
-            .chain((ALLOCATION_START..allocation_previous - size).step_by(4096))
-        {
+        let allocation_previous = match &self.layout {
+            Some(rng) => {
+                allocation_start + rng.below((allocation_end - allocation_start) / 4096) * 4096
+            }
+            None => self
+                .space
+                .allocation_previous
+                .load(Ordering::Relaxed)
+                .clamp(allocation_start, allocation_end),
+        };
+        // Regions big enough for a megapage try to start on a 4MB boundary first
+        let first_megapage = allocation_start.next_multiple_of(MEGAPAGE_SIZE);
+        let megapage_starts = (first_megapage..allocation_end.saturating_sub(size))
+            .step_by(MEGAPAGE_SIZE as usize)
+            .filter(|_| self.megapages && size >= MEGAPAGE_SIZE);
+        for potential_start in megapage_starts.chain(
+            (allocation_previous..allocation_end - size)
+                .step_by(4096)
+                .chain((allocation_start..allocation_previous.saturating_sub(size)).step_by(4096)),
+        ) {
             let mut all_free = true;
             for check_page in (potential_start..potential_start + size).step_by(4096) {
                 if self.virt_to_phys(check_page).is_some() {
@@ -336,70 +1728,142 @@ impl Memory {
                 }
             }
             if all_free {
-                self.allocation_previous
+                self.space
+                    .allocation_previous
                     .store(potential_start + size, Ordering::Relaxed);
                 address = Some(potential_start);
                 break;
             }
         }
         if let Some(address) = address {
-            let mut error_mark = None;
-            for page in (address..(address + size)).step_by(4096) {
-                if self.ensure_page(page).is_none() {
-                    error_mark = Some(page);
-                    break;
-                }
-            }
-            if let Some(error_mark) = error_mark {
-                for page in (address..error_mark).step_by(4096) {
-                    self.free_virt_page(page).unwrap();
-                }
+            if self.map_region(address, size).is_none() {
+                self.unmap_partial_region(address, size);
                 return None;
             }
         }
         address
     }
 
+    /// Undo a `map_region` or `reserve_region` of `size` bytes at `start`
+    /// that ran out of memory part of the way through, unmapping the pages
+    /// it got to.
+    fn unmap_partial_region(&self, start: u32, size: u32) {
+        let mapped_end = (start..start + size)
+            .step_by(4096)
+            .find(|&page| self.virt_to_phys(page).is_none() && self.lazy_entry(page).is_none())
+            .unwrap_or(start + size);
+        self.unmap_region(start, mapped_end)
+            .expect("the pages were just mapped");
+    }
+
+    /// Reserve `size` bytes starting at `start` to be allocated a page at a
+    /// time as they're touched. Pages that are already mapped are left alone.
+    /// Returns `None` if memory for the page tables runs out.
+    fn reserve_region(&self, start: u32, size: u32) -> Option<()> {
+        for virt in (start..start + size).step_by(4096) {
+            if self.megapage_entry(virt).is_some() {
+                continue;
+            }
+            let vpn1 = (virt >> 22) * 4;
+            let mut l1_pt_entry = self.peek_u32(self.space.l1_pt + vpn1);
+            if l1_pt_entry & MMUFLAG_VALID == 0 {
+                let l0_pt_phys = self.allocate_phys_page()?;
+                l1_pt_entry =
+                    ((l0_pt_phys >> 12) << 10) | MMUFLAG_VALID | MMUFLAG_DIRTY | MMUFLAG_ACCESSED;
+                self.poke_u32(self.space.l1_pt + vpn1, l1_pt_entry);
+                self.map_page_table(PAGE_TABLE_OFFSET + (virt >> 22) * 4096, l0_pt_phys)?;
+            }
+            let entry = ((l1_pt_entry >> 10) << 12) + ((virt >> 12) & 0x3ff) * 4;
+            if self.peek_u32(entry) & MMUFLAG_VALID == 0 {
+                self.poke_u32(entry, MMUFLAG_LAZY);
+            }
+        }
+        Some(())
+    }
+
+    /// The physical address of the page table entry for `virt`, if it's
+    /// reserved for demand paging and hasn't been touched yet.
+    fn lazy_entry(&self, virt: u32) -> Option<u32> {
+        let l1_pt_entry = self.peek_u32(self.space.l1_pt + (virt >> 22) * 4);
+        if l1_pt_entry & MMUFLAG_VALID == 0 || self.megapage_entry(virt).is_some() {
+            return None;
+        }
+        let entry = ((l1_pt_entry >> 10) << 12) + ((virt >> 12) & 0x3ff) * 4;
+        (self.peek_u32(entry) & (MMUFLAG_VALID | MMUFLAG_LAZY) == MMUFLAG_LAZY).then_some(entry)
+    }
+
+    /// Allocate the page at `virt` if it was reserved for demand paging,
+    /// returning whether it's mapped now. Freed pages keep their contents,
+    /// so the page is cleared before the guest sees it, but only by
+    /// whichever hart allocates it, and before it's mapped: another that
+    /// faulted on it at the same time finds it already there, and may have
+    /// written to it.
+    fn fault_in(&self, virt: u32) -> bool {
+        if self.lazy_entry(virt).is_some() {
+            let _paging = self.space.paging.lock().unwrap();
+            if self.lazy_entry(virt).is_some() {
+                return self.map_page(virt & !0xfff, true).is_some();
+            }
+        }
+        // Another hart may have faulted it in since this one looked
+        self.virt_to_phys(virt).is_some()
+    }
+
+    /// Like `virt_to_phys`, but allocates the page first if it's reserved
+    /// for demand paging, for the host to access memory the guest hasn't yet.
+    fn touch_virt_to_phys(&self, virt: u32) -> Option<u32> {
+        self.fault_in(virt);
+        self.virt_to_phys(virt)
+    }
+
     fn ensure_page(&self, virt: u32) -> Option<bool> {
+        self.map_page(virt, false)
+    }
+
+    /// Map the page at `virt` if it isn't already, clearing it first if
+    /// `clear` is set, and return whether it had to be.
+    fn map_page(&self, virt: u32, clear: bool) -> Option<bool> {
         assert!(virt != 0);
         let mut allocated = false;
         let vpn1 = ((virt >> 22) & ((1 << 10) - 1)) as usize * 4;
         let vpn0 = ((virt >> 12) & ((1 << 10) - 1)) as usize * 4;
 
         // If the level 1 pagetable doesn't exist, then this address is invalid
-        let mut l1_pt_entry = self.read_u32(self.l1_pt + vpn1 as u32);
+        let mut l1_pt_entry = self.peek_u32(self.space.l1_pt + vpn1 as u32);
+        if self.megapage_entry(virt).is_some() {
+            return Some(false);
+        }
         if l1_pt_entry & MMUFLAG_VALID == 0 {
             // Allocate a new page for the level 1 pagetable
-            let Some(l0_pt_phys) = self.allocate_phys_page() else {
-                return None;
-            };
+            let l0_pt_phys = self.allocate_phys_page()?;
             // println!("Allocating level 0 pagetable at {:08x}", l0_pt_phys);
             l1_pt_entry =
                 ((l0_pt_phys >> 12) << 10) | MMUFLAG_VALID | MMUFLAG_DIRTY | MMUFLAG_ACCESSED;
             // Map the level 1 pagetable into the root pagetable
-            self.write_u32(self.l1_pt + vpn1 as u32, l1_pt_entry);
+            self.poke_u32(self.space.l1_pt + vpn1 as u32, l1_pt_entry);
+            self.map_page_table(PAGE_TABLE_OFFSET + (virt >> 22) * 4096, l0_pt_phys)?;
             allocated = true;
         }
 
         let l0_pt_phys = ((l1_pt_entry >> 10) << 12) + vpn0 as u32;
-        let mut l0_pt_entry = self.read_u32(l0_pt_phys);
+        let mut l0_pt_entry = self.peek_u32(l0_pt_phys);
 
         // Ensure the entry hasn't already been mapped.
         if l0_pt_entry & MMUFLAG_VALID == 0 {
-            let Some(phys) = self.allocate_phys_page() else {
-                return None;
-            };
-            l0_pt_entry = ((phys >> 12) << 10)
-                | MMUFLAG_VALID
-                | MMUFLAG_WRITABLE
-                | MMUFLAG_READABLE
-                | MMUFLAG_EXECUTABLE
-                | MMUFLAG_USERMODE
-                | MMUFLAG_DIRTY
-                | MMUFLAG_ACCESSED;
+            let phys = self.allocate_phys_page()?;
+            if clear {
+                if let Some(page) = self.data.get(((phys - self.base) >> 12) as usize) {
+                    page.write().unwrap().fill(0);
+                }
+            }
+            l0_pt_entry = ((phys >> 12) << 10) | self.user_page_flags();
             // Map the level 0 pagetable into the level 1 pagetable
-            self.write_u32(l0_pt_phys, l0_pt_entry);
-            self.translation_cache.write().unwrap()[(virt >> 12) as usize] = NonZeroU32::new(phys);
+            self.poke_u32(l0_pt_phys, l0_pt_entry);
+            self.translation_cache
+                .insert(self.space.asid, virt, phys, l0_pt_entry);
+            if let Some(uninit) = &self.uninit {
+                uninit.map_page(phys);
+            }
 
             allocated = true;
         }
@@ -417,83 +1881,222 @@ impl Memory {
     }
 
     fn remove_memory_flags(&self, virt: u32, new_flags: u32) {
+        if let Some(old_flags) = self.page_flags(virt) {
+            // Ensure we're not adding flags
+            assert!(old_flags | new_flags == old_flags);
+        }
+        self.set_memory_flags(virt, new_flags);
+    }
+
+    /// Give the page at `virt` exactly the permissions in `new_flags`, which
+    /// may add to what it allowed before as well as take away.
+    fn set_memory_flags(&self, virt: u32, new_flags: u32) {
         // Ensure they're only adjusting legal flags
         assert!(new_flags & !(MMUFLAG_READABLE | MMUFLAG_WRITABLE | MMUFLAG_EXECUTABLE) == 0);
+        if self.megapage_entry(virt).is_some() && self.split_megapage(virt).is_none() {
+            return;
+        }
 
         let vpn1 = ((virt >> 22) & ((1 << 10) - 1)) as usize * 4;
         let vpn0 = ((virt >> 12) & ((1 << 10) - 1)) as usize * 4;
 
         // The root (l1) pagetable is defined to be mapped into our virtual
         // address space at this address.
-        let l1_pt_entry = self.read_u32(self.l1_pt + vpn1 as u32);
+        let l1_pt_entry = self.peek_u32(self.space.l1_pt + vpn1 as u32);
 
         // If the level 1 pagetable doesn't exist, then this address is invalid
         if l1_pt_entry & MMUFLAG_VALID == 0 {
             return;
         }
 
-        let l0_pt_entry = self.read_u32(((l1_pt_entry >> 10) << 12) + vpn0 as u32);
+        let l0_pt_entry = self.peek_u32(((l1_pt_entry >> 10) << 12) + vpn0 as u32);
 
         // Ensure the entry hasn't already been mapped.
         if l0_pt_entry & MMUFLAG_VALID == 0 {
             return;
         }
 
-        let old_flags = l0_pt_entry & 0xff;
-
-        // Ensure we're not adding flags
-        assert!(old_flags | new_flags == old_flags);
-
         let l0_pt_entry =
             (l0_pt_entry & !(MMUFLAG_READABLE | MMUFLAG_WRITABLE | MMUFLAG_EXECUTABLE)) | new_flags;
 
-        self.write_u32(((l1_pt_entry >> 10) << 12) + vpn0 as u32, l0_pt_entry);
+        self.poke_u32(((l1_pt_entry >> 10) << 12) + vpn0 as u32, l0_pt_entry);
+        // Replace the translation rather than narrowing it, so that the CPU
+        // never walks to the page and caches what it used to allow
+        self.translation_cache.insert(
+            self.space.asid,
+            virt,
+            (l0_pt_entry >> 10) << 12,
+            l0_pt_entry,
+        );
+    }
+
+    /// The flags of a newly mapped page, which is only executable if pages
+    /// may be writable and executable at once.
+    fn user_page_flags(&self) -> u32 {
+        match self.w_xor_x {
+            Some(_) => USER_PAGE_FLAGS & !MMUFLAG_EXECUTABLE,
+            None => USER_PAGE_FLAGS,
+        }
+    }
+
+    /// Forget every translation cached for this handle's address space, for
+    /// when its process has exited and its ASID may be given to another.
+    fn retire_address_space(&self) {
+        self.translation_cache.flush_space(self.space.asid);
+    }
+
+    /// Map the pages `data.len()` bytes at virtual address `start` cover and
+    /// fill them with `data`. Fails with the first address there wasn't
+    /// memory for.
+    fn write_bytes(&mut self, data: &[u8], start: u32) -> Result<(), u32> {
+        for page in (start & !0xfff..start + data.len() as u32).step_by(4096) {
+            self.ensure_page(page.max(start)).ok_or(page.max(start))?;
+        }
+        for (phys, range) in self.phys_runs(start, data.len() as u32)? {
+            self.poke_bytes(phys, &data[range]);
+        }
+        self.mark_initialized(start, data.len() as u32);
+        Ok(())
+    }
+
+    /// Split the `len` bytes at virtual address `start` into runs that don't
+    /// cross a page, as the physical address of each and the part of the
+    /// range it covers. Fails with the first address that isn't mapped.
+    fn phys_runs(&self, start: u32, len: u32) -> Result<Vec<(u32, Range<usize>)>, u32> {
+        let mut runs = vec![];
+        let mut offset = 0;
+        while offset < len {
+            let virt = start.wrapping_add(offset);
+            let span = (len - offset).min(0x1000 - (virt & 0xfff));
+            let phys = self.virt_to_phys(virt).ok_or(virt)?;
+            runs.push((phys, offset as usize..(offset + span) as usize));
+            offset += span;
+        }
+        Ok(runs)
+    }
+
+    /// Overwrite `data.len()` bytes at virtual address `start` with `data`,
+    /// whatever the permissions of the pages they're in. If any of them
+    /// isn't mapped, nothing is written and the first that isn't is
+    /// returned.
+    fn patch(&self, start: u32, data: &[u8]) -> Result<(), u32> {
+        for (phys, range) in self.phys_runs(start, data.len() as u32)? {
+            self.poke_bytes(phys, &data[range]);
+        }
+        self.mark_initialized(start, data.len() as u32);
+        Ok(())
+    }
+
+    /// Read `len` bytes from virtual address `start`, or the first address
+    /// that isn't mapped if any of them isn't.
+    fn peek(&self, start: u32, len: u32) -> Result<Vec<u8>, u32> {
+        let mut data = vec![0; len as usize];
+        for (phys, range) in self.phys_runs(start, len)? {
+            self.peek_bytes(phys, &mut data[range]);
+        }
+        Ok(data)
+    }
+
+    /// Read the `len` bytes at virtual address `start` as the program would,
+    /// counting them in the heatmap, such as to copy a lent buffer. Fails
+    /// with the first address that isn't mapped.
+    fn read_range(&self, start: u32, len: u32) -> Result<Vec<u8>, u32> {
+        let mut data = vec![0; len as usize];
+        for (phys, range) in self.phys_runs(start, len)? {
+            self.record(phys, heatmap::Access::Read);
+            self.peek_bytes(phys, &mut data[range]);
+        }
+        Ok(data)
+    }
+
+    /// Write `data` at virtual address `start` as the program would, counting
+    /// it in the heatmap and breaking other threads' reservations, such as
+    /// to return a lent buffer. Fails with the first address that isn't
+    /// mapped, before writing anything.
+    fn write_range(&self, start: u32, data: &[u8]) -> Result<(), u32> {
+        for (phys, range) in self.phys_runs(start, data.len() as u32)? {
+            self.record(phys, heatmap::Access::Write);
+            self.record_store(phys, range.len() as u32);
+            self.poke_bytes(phys, &data[range]);
+        }
+        Ok(())
+    }
+
+    /// Tell the uninitialized read detector that the host has filled in `len`
+    /// bytes at virtual address `start`.
+    fn mark_initialized(&self, start: u32, len: u32) {
+        let Some(uninit) = &self.uninit else {
+            return;
+        };
+        let end = start.saturating_add(len);
+        let mut address = start;
+        while address < end {
+            let span = (end - address).min(0x1000 - (address & 0xfff));
+            if let Some(phys) = self.virt_to_phys(address) {
+                uninit.initialize(phys, span);
+            }
+            address += span;
+        }
     }
 
-    fn write_bytes(&mut self, data: &[u8], start: u32) {
-        for (i, byte) in data.iter().enumerate() {
-            let i = i as u32;
-            self.ensure_page(start + i);
-            let phys = self.virt_to_phys(start + i).unwrap();
+    /// Map the exit trampoline, read-only, for threads to return to. Fails
+    /// if there's no memory for it.
+    fn map_exit_trampoline(&mut self) -> Result<(), u32> {
+        let code: Vec<u8> = EXIT_TRAMPOLINE_CODE
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        self.write_bytes(&code, EXIT_TRAMPOLINE)?;
+        self.set_memory_flags(EXIT_TRAMPOLINE, MMUFLAG_READABLE | MMUFLAG_EXECUTABLE);
+        Ok(())
+    }
 
-            self.write_u8(phys, *byte);
+    /// Make the shared state usable again after a thread panicked while
+    /// holding one of its locks, so that the panic doesn't spread to every
+    /// other thread. What the lock protects may have been left half-updated.
+    fn clear_poison(&self) {
+        for page in self.data.iter() {
+            page.clear_poison();
         }
+        self.allocated_pages.clear_poison();
+        self.free_pages.clear_poison();
+        self.connections.clear_poison();
+        self.translation_cache.clear_poison();
+        self.reservations.clear_poison();
+        self.thread_handles.clear_poison();
+        self.threads.clear_poison();
+        self.ring_buffers.clear_poison();
+        self.names.clear_poison();
+        self.failure.clear_poison();
+        self.invalid_accesses.clear_poison();
+        self.thread_faults.clear_poison();
     }
 
-    #[allow(dead_code)]
-    pub fn print_mmu(&self) {
-        use crate::xous::definitions::memoryflags::MemoryFlags;
-        println!();
-        println!("Memory Map:");
+    /// Every valid entry of the root page table, with the valid entries of
+    /// the level 0 table under each.
+    fn page_tables(&self) -> Vec<page_tables::Table> {
+        let mut tables = vec![];
         for vpn1 in 0..1024 {
-            let l1_entry = self.read_u32(self.l1_pt + vpn1 * 4);
-            if l1_entry & MMUFLAG_VALID == 0 {
+            let entry = self.peek_u32(self.space.l1_pt + vpn1 * 4);
+            if entry & MMUFLAG_VALID == 0 {
                 continue;
             }
-            let superpage_addr = vpn1 * (1 << 22);
-            println!(
-                "    {:4} Superpage for {:08x} @ {:08x} (flags: {})",
-                vpn1,
-                superpage_addr,
-                (l1_entry >> 10) << 12,
-                MemoryFlags::from_bits(l1_entry as usize & 0xff).unwrap(),
-            );
-
-            for vpn0 in 0..1024 {
-                let l0_entry = self.read_u32(((l1_entry >> 10) << 12) + vpn0 as u32 * 4);
-                if l0_entry & 0x1 == 0 {
-                    continue;
+            let mut pages = vec![];
+            if self.megapage_entry(vpn1 << 22).is_none() {
+                for vpn0 in 0..1024 {
+                    let l0_entry = self.peek_u32(((entry >> 10) << 12) + vpn0 * 4);
+                    if l0_entry & MMUFLAG_VALID != 0 {
+                        pages.push((vpn0, l0_entry));
+                    }
                 }
-                let page_addr = vpn0 as u32 * (1 << 12);
-                println!(
-                    "        {:4} {:08x} -> {:08x} (flags: {})",
-                    vpn0,
-                    superpage_addr + page_addr,
-                    (l0_entry >> 10) << 12,
-                    MemoryFlags::from_bits(l0_entry as usize & 0xff).unwrap()
-                );
             }
+            tables.push(page_tables::Table { vpn1, entry, pages });
         }
+        tables
+    }
+
+    fn dump_mmu(&self, output: &mut impl std::io::Write, format: MmuFormat) -> std::io::Result<()> {
+        page_tables::write(self.space.l1_pt, &self.page_tables(), format, output)
     }
 
     pub fn virt_to_phys(&self, virt: u32) -> Option<u32> {
@@ -503,17 +2106,17 @@ impl Memory {
 
         // The root (l1) pagetable is defined to be mapped into our virtual
         // address space at this address.
-        let l1_pt_entry = self.read_u32(self.l1_pt + vpn1 as u32);
+        let l1_pt_entry = self.peek_u32(self.space.l1_pt + vpn1 as u32);
 
         // If the level 1 pagetable doesn't exist, then this address is invalid
         if l1_pt_entry & MMUFLAG_VALID == 0 {
             return None;
         }
         if l1_pt_entry & (MMUFLAG_EXECUTABLE | MMUFLAG_READABLE | MMUFLAG_WRITABLE) != 0 {
-            return None;
+            return Some(((l1_pt_entry >> 10) << 12) | (virt & (MEGAPAGE_SIZE - 1)));
         }
 
-        let l0_pt_entry = self.read_u32(((l1_pt_entry >> 10) << 12) + vpn0 as u32);
+        let l0_pt_entry = self.peek_u32(((l1_pt_entry >> 10) << 12) + vpn0 as u32);
 
         // Check if the mapping is valid
         if l0_pt_entry & MMUFLAG_VALID == 0 {
@@ -522,119 +2125,570 @@ impl Memory {
             Some(((l0_pt_entry >> 10) << 12) | offset)
         }
     }
-}
-
-impl riscv_cpu::cpu::Memory for Memory {
-    fn read_u8(&self, address: u32) -> u8 {
-        let address = address - self.base;
-        let page = address as usize & !0xfff;
-        let offset = address as usize & 0xfff;
-        let index = offset >> 2;
-        let pos = (offset % 4) * 8;
 
-        self.data
-            .get(page >> 12)
-            .map(|page| page.read().unwrap()[index] >> pos)
-            .unwrap_or(0) as u8
+    /// Record a scheduler event for thread `tid`, if tracing is enabled.
+    fn schedule(&self, tid: i32, event: trace::SchedulerEvent) {
+        if let Some(tracer) = &self.tracer {
+            tracer.record_schedule(self.platform.elapsed_us(), tid, event);
+        }
+        self.perf_events.context_switch(tid, event);
     }
 
-    fn read_u16(&self, address: u32) -> u16 {
-        if address & 1 == 0 {
-            let address = address - self.base;
-            let page = address as usize & !0xfff;
-            let offset = address as usize & 0xfff;
-            let index = offset / 4;
-            let pos = (offset % 4) * 8;
-            self.data
-                .get(page >> 12)
-                .map(|page| page.read().unwrap()[index] >> pos)
-                .unwrap_or(0) as u16
-        } else {
-            let data = [self.read_u8(address), self.read_u8(address + 1)];
-            u16::from_le_bytes(data)
+    /// The permission bits of the page that maps `virt`, or `None` if it
+    /// isn't mapped.
+    fn page_flags(&self, virt: u32) -> Option<u32> {
+        if let Some(entry) = self.megapage_entry(virt) {
+            return Some(entry & 0xff);
+        }
+        let l1_pt_entry = self.peek_u32(self.space.l1_pt + (virt >> 22) * 4);
+        if l1_pt_entry & MMUFLAG_VALID == 0 {
+            return None;
         }
+        let l0_pt_entry = self.peek_u32(((l1_pt_entry >> 10) << 12) + ((virt >> 12) & 0x3ff) * 4);
+        (l0_pt_entry & MMUFLAG_VALID != 0).then_some(l0_pt_entry & 0xff)
     }
 
-    fn read_u32(&self, address: u32) -> u32 {
-        if address & 3 == 0 {
-            let address = address - self.base;
-            let page = address as usize & !0xfff;
-            let offset = address as usize & 0xfff;
-            let index = offset / 4;
-            self.data
-                .get(page >> 12)
-                .map(|page| page.read().unwrap()[index])
-                .unwrap_or(0)
-        } else {
-            let data = [
-                self.read_u8(address),
-                self.read_u8(address + 1),
-                self.read_u8(address + 2),
-                self.read_u8(address + 3),
-            ];
-            u32::from_le_bytes(data)
+    /// Whether the guest may read `size` bytes at `address`, and write them
+    /// too if `writable`. Pages reserved for demand paging are allocated.
+    fn is_accessible(&self, address: u32, size: u32, writable: bool) -> bool {
+        let Some(end) = address.checked_add(size) else {
+            return false;
+        };
+        let mut required = MMUFLAG_USERMODE | MMUFLAG_READABLE;
+        if writable {
+            required |= MMUFLAG_WRITABLE;
         }
+        (address & !0xfff..end).step_by(4096).all(|page| {
+            self.fault_in(page);
+            self.page_flags(page)
+                .is_some_and(|flags| flags & required == required)
+        })
     }
 
-    fn write_u8(&self, address: u32, value: u8) {
-        let address = address - self.base;
-        let page = address as usize & !0xfff;
-        let offset = address as usize & 0xfff;
-        let index = offset / 4;
-        let pos = (offset % 4) * 8;
-        if let Some(page) = self.data.get(page >> 12) {
-            let mut data = page.write().unwrap();
-            data[index] = (data[index] & !(0xff << pos)) | ((value as u32) << pos);
-        }
+    /// How much each thread that has been started has run.
+    fn thread_stats(&self) -> Vec<ThreadStats> {
+        self.threads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&tid, account)| ThreadStats {
+                tid,
+                instructions_retired: account.instructions.load(Ordering::Relaxed),
+                exited: account.exited,
+                stack: account.stack.clone(),
+                lowest_sp: account.lowest_sp.load(Ordering::Relaxed),
+            })
+            .collect()
     }
 
-    fn write_u16(&self, address: u32, value: u16) {
-        if address & 1 == 0 {
-            let address = address - self.base;
-            let page = address as usize & !0xfff;
-            let offset = address as usize & 0xfff;
-            let index = offset >> 2;
-            let pos = (offset % 4) * 8;
-            if let Some(page) = self.data.get(page >> 12) {
-                let mut data = page.write().unwrap();
-                data[index] = (data[index] & !(0xffff << pos)) | ((value as u32) << pos);
-            }
-        } else {
-            for (offset, byte) in value.to_le_bytes().iter().enumerate() {
-                self.write_u8(address + offset as u32, *byte);
-            }
-        }
+    /// The ring with the given ID, creating it if this is the first use.
+    fn ring_buffer(&self, id: u32) -> Arc<RingBuffer> {
+        self.ring_buffers
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_insert_with(|| Arc::new(RingBuffer::new()))
+            .clone()
     }
 
-    fn write_u32(&self, address: u32, value: u32) {
+    /// Every page and megapage mapped into the address space, in order of
+    /// virtual address.
+    fn memory_map(&self) -> Vec<Mapping> {
+        let mut mappings = vec![];
+        for vpn1 in 0..1024 {
+            let l1_entry = self.peek_u32(self.space.l1_pt + vpn1 * 4);
+            if l1_entry & MMUFLAG_VALID == 0 {
+                continue;
+            }
+            if self.megapage_entry(vpn1 << 22).is_some() {
+                mappings.push(Mapping {
+                    virt: vpn1 << 22,
+                    phys: (l1_entry >> 10) << 12,
+                    size: MEGAPAGE_SIZE,
+                });
+                continue;
+            }
+            for vpn0 in 0..1024 {
+                let l0_entry = self.peek_u32(((l1_entry >> 10) << 12) + vpn0 * 4);
+                if l0_entry & MMUFLAG_VALID == 0 {
+                    continue;
+                }
+                mappings.push(Mapping {
+                    virt: vpn1 << 22 | vpn0 << 12,
+                    phys: (l0_entry >> 10) << 12,
+                    size: 4096,
+                });
+            }
+        }
+        mappings
+    }
+
+    /// Every page mapped into the address space, as a map from physical page to virtual page.
+    fn mappings(&self) -> HashMap<u32, u32> {
+        let mut mappings = HashMap::new();
+        for mapping in self.memory_map() {
+            for offset in (0..mapping.size).step_by(4096) {
+                mappings.insert(mapping.phys + offset, mapping.virt + offset);
+            }
+        }
+        mappings
+    }
+
+    /// Give every connected service a chance to complete time-based work,
+    /// such as expiring timeouts.
+    /// The timeouts every connected service is waiting on, as
+    /// `Service::timeouts` gives them.
+    pub(super) fn service_timeouts(&self) -> Vec<(u32, u64)> {
+        let services = self.connections.lock().unwrap().services();
+        services
+            .iter()
+            .flat_map(|service| service.timeouts())
+            .collect()
+    }
+
+    /// Hand `timeouts` to every connected service, as
+    /// `Service::set_timeouts` takes them.
+    pub(super) fn set_service_timeouts(&self, timeouts: &[(u32, u64)]) {
+        let services = self.connections.lock().unwrap().services();
+        for service in services {
+            service.set_timeouts(timeouts);
+        }
+    }
+
+    pub fn tick_services(&self) {
+        // Clone the services out so they may lock the connection table themselves.
+        // If a thread panicked while holding the table, skip this tick rather
+        // than panicking too. That thread clears the poison once it has stopped.
+        let Ok(services) = self.connections.lock().map(|table| table.services()) else {
+            return;
+        };
+        for service in services {
+            service.tick(self);
+        }
+        if let Some(faults) = &self.faults {
+            faults.tick(self.platform.elapsed_ms());
+        }
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.tick(self.platform.elapsed_ms());
+        }
+        if let Some(framebuffer) = &self.framebuffer {
+            framebuffer.tick(self, self.platform.elapsed_ms());
+        }
+        self.keyboard.tick(self.platform.elapsed_ms());
+    }
+}
+
+impl Memory {
+    fn record(&self, address: u32, access: heatmap::Access) {
+        if let Some(heatmap) = &self.heatmap {
+            heatmap.record(address, access, self.tid);
+        }
+    }
+
+    /// Note a store of `width` bytes to `address`, which breaks every
+    /// reservation of the words it touches. That includes the storing
+    /// core's own, which the ISA allows, since reservations are held by
+    /// hart and a thread may not be on the hart it was when it stored.
+    fn record_store(&self, address: u32, width: u32) {
+        if let Some(uninit) = &self.uninit {
+            uninit.initialize(address, width);
+        }
+        let first = address & !3;
+        let last = address.wrapping_add(width - 1) & !3;
+        let broken = |(_, &reserved): (&u32, &u32)| (first..=last).contains(&reserved);
+        if self.reservations.read().unwrap().iter().any(broken) {
+            self.reservations
+                .write()
+                .unwrap()
+                .retain(|holder, reserved| !broken((holder, reserved)));
+        }
+    }
+
+    /// Whether there is RAM at physical `address`.
+    fn is_ram(&self, address: u32) -> bool {
+        if address < self.base {
+            return false;
+        }
+        let address = address as usize - self.base as usize;
+        address < self.data.page_count() * 4096
+    }
+
+    fn peek_u8(&self, address: u32) -> u8 {
+        let address = address.wrapping_sub(self.base);
+        let page = address as usize & !0xfff;
+        let offset = address as usize & 0xfff;
+        let index = offset >> 2;
+        let pos = (offset % 4) * 8;
+
+        self.data
+            .get(page >> 12)
+            .map(|page| page.read().unwrap()[index] >> pos)
+            .unwrap_or(0) as u8
+    }
+
+    fn peek_u16(&self, address: u32) -> u16 {
+        if address & 1 == 0 {
+            let address = address.wrapping_sub(self.base);
+            let page = address as usize & !0xfff;
+            let offset = address as usize & 0xfff;
+            let index = offset / 4;
+            let pos = (offset % 4) * 8;
+            self.data
+                .get(page >> 12)
+                .map(|page| page.read().unwrap()[index] >> pos)
+                .unwrap_or(0) as u16
+        } else {
+            let data = [self.peek_u8(address), self.peek_u8(address + 1)];
+            u16::from_le_bytes(data)
+        }
+    }
+
+    fn peek_u32(&self, address: u32) -> u32 {
         if address & 3 == 0 {
-            let address = address - self.base;
+            let address = address.wrapping_sub(self.base);
             let page = address as usize & !0xfff;
             let offset = address as usize & 0xfff;
-            let index = offset >> 2;
-            if let Some(page) = self.data.get(page >> 12) {
-                let mut page = page.write().unwrap();
-                page[index] = value;
+            let index = offset / 4;
+            self.data
+                .get(page >> 12)
+                .map(|page| page.read().unwrap()[index])
+                .unwrap_or(0)
+        } else {
+            let data = [
+                self.peek_u8(address),
+                self.peek_u8(address + 1),
+                self.peek_u8(address + 2),
+                self.peek_u8(address + 3),
+            ];
+            u32::from_le_bytes(data)
+        }
+    }
+
+    fn poke_u8(&self, address: u32, value: u8) {
+        let address = address.wrapping_sub(self.base);
+        let page = address as usize & !0xfff;
+        let offset = address as usize & 0xfff;
+        let index = offset / 4;
+        let pos = (offset % 4) * 8;
+        if let Some(page) = self.data.get_or_create(page >> 12) {
+            let mut data = page.write().unwrap();
+            data[index] = (data[index] & !(0xff << pos)) | ((value as u32) << pos);
+        }
+    }
+
+    /// Fill `buf` from physical `address` onwards, which must all be in one
+    /// page, a word at a time where the words are whole.
+    fn peek_bytes(&self, address: u32, buf: &mut [u8]) {
+        let offset = address.wrapping_sub(self.base) as usize;
+        let Some(page) = self.data.get(offset >> 12) else {
+            buf.fill(0);
+            return;
+        };
+        let words = page.read().unwrap();
+        let offset = offset & 0xfff;
+        let head = buf.len().min((4 - offset % 4) % 4);
+        let first_word = (offset + head) / 4;
+        let last_word = (offset + buf.len()) / 4;
+        let (head_bytes, rest) = buf.split_at_mut(head);
+        for (index, byte) in head_bytes.iter_mut().enumerate() {
+            let at = offset + index;
+            *byte = (words[at / 4] >> (at % 4 * 8)) as u8;
+        }
+        let mut chunks = rest.chunks_exact_mut(4);
+        for (word, chunk) in words[first_word..].iter().zip(&mut chunks) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        let tail = chunks.into_remainder();
+        for (index, byte) in tail.iter_mut().enumerate() {
+            *byte = (words[last_word] >> (index * 8)) as u8;
+        }
+    }
+
+    /// Copy `data` to physical `address` onwards, which must all be in one
+    /// page, a word at a time where the words are whole. Like `poke_u32`,
+    /// this isn't counted in the heatmap.
+    fn poke_bytes(&self, address: u32, data: &[u8]) {
+        let offset = address.wrapping_sub(self.base) as usize;
+        let Some(page) = self.data.get_or_create(offset >> 12) else {
+            return;
+        };
+        let mut words = page.write().unwrap();
+        let offset = offset & 0xfff;
+        let head = data.len().min((4 - offset % 4) % 4);
+        let first_word = (offset + head) / 4;
+        let last_word = (offset + data.len()) / 4;
+        let (head_bytes, rest) = data.split_at(head);
+        for (index, &byte) in head_bytes.iter().enumerate() {
+            let at = offset + index;
+            let pos = at % 4 * 8;
+            words[at / 4] = (words[at / 4] & !(0xff << pos)) | (byte as u32) << pos;
+        }
+        let mut chunks = rest.chunks_exact(4);
+        for (word, chunk) in words[first_word..].iter_mut().zip(&mut chunks) {
+            *word = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        let tail = chunks.remainder();
+        for (index, &byte) in tail.iter().enumerate() {
+            let pos = index * 8;
+            words[last_word] = (words[last_word] & !(0xff << pos)) | (byte as u32) << pos;
+        }
+    }
+
+    /// Run `f` on the aligned RAM word at `address` with its page locked,
+    /// so that no other hart's store to it can come in the middle.
+    fn with_word<R>(&self, address: u32, f: impl FnOnce(&mut u32) -> R) -> Option<R> {
+        let offset = address.wrapping_sub(self.base) as usize;
+        let page = self.data.get_or_create(offset >> 12)?;
+        let mut words = page.write().unwrap();
+        Some(f(&mut words[(offset & 0xfff) >> 2]))
+    }
+
+    /// Write an aligned word without counting it in the heatmap.
+    fn poke_u32(&self, address: u32, value: u32) {
+        let address = address.wrapping_sub(self.base);
+        let page = address as usize & !0xfff;
+        let index = (address as usize & 0xfff) >> 2;
+        if let Some(page) = self.data.get_or_create(page >> 12) {
+            page.write().unwrap()[index] = value;
+        }
+    }
+}
+
+impl riscv_cpu::cpu::Memory for Memory {
+    fn read_u8(&self, address: u32) -> u8 {
+        self.record(address, heatmap::Access::Read);
+        self.peek_u8(address)
+    }
+
+    fn read_u16(&self, address: u32) -> u16 {
+        self.record(address, heatmap::Access::Read);
+        self.peek_u16(address)
+    }
+
+    fn read_u32(&self, address: u32) -> u32 {
+        self.record(address, heatmap::Access::Read);
+        self.peek_u32(address)
+    }
+
+    fn fetch_u8(&self, address: u32) -> u8 {
+        self.record(address, heatmap::Access::Execute);
+        self.peek_u8(address)
+    }
+
+    fn fetch_u32(&self, address: u32) -> u32 {
+        self.record(address, heatmap::Access::Execute);
+        self.peek_u32(address)
+    }
+
+    fn page_fault(&self, v_address: u32) -> bool {
+        if let Some(metrics) = &self.metrics {
+            metrics.page_fault();
+        }
+        self.demand_paging && self.fault_in(v_address)
+    }
+
+    fn hypercall(&self, value: u32, pc: u32, registers: &[i32; 32]) -> u32 {
+        match &self.tracer {
+            Some(tracer) => {
+                tracer.hypercall(value, self.platform.elapsed_ms(), self.tid, pc, registers)
             }
+            None => trace::HYPERCALL_OK,
+        }
+    }
+
+    fn time(&self) -> Option<u64> {
+        Some(self.clock.now_us())
+    }
+
+    fn counter(&self, address: u16, value: u32) -> Option<u32> {
+        let instructions = self.thread_instructions.load(Ordering::Relaxed);
+        self.counters.read(address, value, instructions)
+    }
+
+    fn hint(&self, pc: u32, halfword: u16) {
+        log::warn!(
+            "thread {} at {:08x} ran the compressed HINT {:04x} as a no-op",
+            self.tid,
+            pc,
+            halfword
+        );
+    }
+
+    fn write_u8(&self, address: u32, value: u8) {
+        self.record(address, heatmap::Access::Write);
+        let pos = (address % 4) * 8;
+        self.with_word(address & !3, |word| {
+            self.record_store(address, 1);
+            *word = (*word & !(0xff << pos)) | ((value as u32) << pos);
+        });
+    }
+
+    fn write_u16(&self, address: u32, value: u16) {
+        self.record(address, heatmap::Access::Write);
+        if address & 1 == 0 {
+            let pos = (address % 4) * 8;
+            self.with_word(address & !3, |word| {
+                self.record_store(address, 2);
+                *word = (*word & !(0xffff << pos)) | ((value as u32) << pos);
+            });
+        } else {
+            self.record_store(address, 2);
+            for (offset, byte) in value.to_le_bytes().iter().enumerate() {
+                self.poke_u8(address + offset as u32, *byte);
+            }
+        }
+    }
+
+    fn write_u32(&self, address: u32, value: u32) {
+        self.record(address, heatmap::Access::Write);
+        if address & 3 == 0 {
+            self.with_word(address, |word| {
+                self.record_store(address, 4);
+                *word = value;
+            });
         } else {
+            self.record_store(address, 4);
             for (offset, byte) in value.to_le_bytes().iter().enumerate() {
-                self.write_u8(address + offset as u32, *byte);
+                self.poke_u8(address + offset as u32, *byte);
             }
         }
     }
 
     fn validate_address(&self, address: u32) -> bool {
-        if address < self.base {
-            return false;
+        // Uninitialized words are reported through `invalid_access`
+        self.is_ram(address)
+            && !self
+                .uninit
+                .as_ref()
+                .is_some_and(|uninit| uninit.is_uninitialized(address))
+    }
+
+    fn invalid_access(&self, address: u32, pc: u32, access_type: &MemoryAccessType) -> bool {
+        if let Some(uninit) = self.uninit.as_ref().filter(|_| self.is_ram(address)) {
+            // Only reads of uninitialized memory are a problem
+            if !matches!(access_type, MemoryAccessType::Read) {
+                return false;
+            }
+            uninit.report(self.platform.as_ref(), self.tid, pc, address);
+            return self.strict_memory;
         }
-        let address = address as usize - self.base as usize;
-        address < self.data.len()
+        let access = match access_type {
+            MemoryAccessType::Execute => heatmap::Access::Execute,
+            MemoryAccessType::Write => heatmap::Access::Write,
+            MemoryAccessType::Read | MemoryAccessType::DontCare => heatmap::Access::Read,
+        };
+        let mut invalid_accesses = self.invalid_accesses.lock().unwrap();
+        if invalid_accesses.len() < INVALID_ACCESS_LOG_LIMIT {
+            invalid_accesses.push(InvalidAccess {
+                tid: self.tid,
+                pc,
+                address,
+                access,
+            });
+        }
+        self.strict_memory
+    }
+
+    fn translate(&self, v_address: u32, access_type: &MemoryAccessType) -> Option<u32> {
+        self.translation_cache
+            .lookup(self.space.asid, v_address, access_type)
+    }
+
+    fn reserve(&self, core: u32, p_address: u32) {
+        self.reservations.write().unwrap().insert(core, p_address);
+    }
+
+    fn clear_reservation(&self, core: u32, p_address: u32) -> bool {
+        self.reservations.write().unwrap().remove(&core) == Some(p_address)
+    }
+
+    fn load_reserved(&self, core: u32, p_address: u32) -> u32 {
+        self.record(p_address, heatmap::Access::Read);
+        self.with_word(p_address, |word| {
+            self.reserve(core, p_address);
+            *word
+        })
+        .unwrap_or(0)
+    }
+
+    fn store_conditional(&self, core: u32, p_address: u32, value: u32) -> bool {
+        self.with_word(p_address, |word| {
+            let reserved = self.clear_reservation(core, p_address);
+            if reserved {
+                self.record(p_address, heatmap::Access::Write);
+                self.record_store(p_address, 4);
+                *word = value;
+            }
+            reserved
+        })
+        .unwrap_or(false)
+    }
+
+    fn update_u32(&self, p_address: u32, operation: &mut dyn FnMut(u32) -> u32) -> u32 {
+        self.record(p_address, heatmap::Access::Read);
+        self.record(p_address, heatmap::Access::Write);
+        self.with_word(p_address, |word| {
+            self.record_store(p_address, 4);
+            let data = *word;
+            *word = operation(data);
+            data
+        })
+        .unwrap_or(0)
     }
 
+    fn reservable(&self, p_address: u32) -> bool {
+        self.is_ram(p_address)
+    }
+
+    fn clone(&self) -> Box<dyn OtherMemory + Send + Sync> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+impl SyscallBackend for Memory {
     fn syscall(&self, args: [i32; 8]) -> SyscallResult {
         let syscall: Syscall = args.into();
+        if let Some(metrics) = &self.metrics {
+            metrics.syscall(&syscall);
+        }
+        if !self.strace {
+            return self.inject_syscall(args[0], syscall);
+        }
+        let call = strace::describe_call(&syscall);
+        let start = self.platform.elapsed_us();
+        let result = self.inject_syscall(args[0], syscall);
+        let line = format!(
+            "[tid {}] {} = {} <{}us>\n",
+            self.tid,
+            call,
+            strace::describe_result(&result),
+            self.platform.elapsed_us().saturating_sub(start)
+        );
+        self.platform.write_stderr(line.as_bytes());
+        result
+    }
+}
+
+impl SystemBus for Memory {}
 
-        // println!("Syscall {:?}", SyscallNumber::from(args[0]));
+impl Memory {
+    /// Run `syscall`, which is syscall number `number`, unless the fault
+    /// injector picks it to fail.
+    fn inject_syscall(&self, number: i32, syscall: Syscall) -> SyscallResult {
+        let Some(faults) = &self.faults else {
+            return self.dispatch_syscall(syscall);
+        };
+        let now = self.platform.elapsed_ms();
+        match faults.select(now, number, &syscall) {
+            None => self.dispatch_syscall(syscall),
+            Some(faults::Fault::Delay(ms)) => {
+                faults.delay(now + ms, self.dispatch_syscall(syscall))
+            }
+            Some(fault) => faults::FaultInjector::result_for(fault),
+        }
+    }
+
+    fn dispatch_syscall(&self, syscall: Syscall) -> SyscallResult {
         match syscall {
             Syscall::IncreaseHeap(bytes, flags) => syscalls::increase_heap(self, bytes, flags),
 
@@ -643,6 +2697,7 @@ impl riscv_cpu::cpu::Memory for Memory {
             }
             Syscall::Connect(id) => syscalls::connect(self, id),
             Syscall::TryConnect(id) => syscalls::try_connect(self, id),
+            Syscall::Disconnect(connection_id) => syscalls::disconnect(self, connection_id),
             Syscall::SendMessage(connection_id, kind, opcode, args) => {
                 syscalls::send_message(self, connection_id, kind, opcode, args)
             }
@@ -650,12 +2705,16 @@ impl riscv_cpu::cpu::Memory for Memory {
                 syscalls::try_send_message(self, connection_id, kind, opcode, args)
             }
             Syscall::UpdateMemoryFlags(address, range, value) => {
-                for addr in address..(address + range) {
-                    self.remove_memory_flags(addr as u32, value as u32);
-                }
+                syscalls::update_memory_flags(self, address, range, value)
+            }
+            Syscall::ReturnToParent(pid, _cpu) => syscalls::return_to_parent(pid),
+            Syscall::SetMemRegion(pid, kind, address, size) => {
+                syscalls::set_mem_region(self, pid, kind, address, size)
+            }
+            Syscall::Yield => {
+                self.schedule(self.tid, trace::SchedulerEvent::Yielded);
                 [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into()
             }
-            Syscall::Yield => [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into(),
             Syscall::CreateThread(
                 entry_point,
                 stack_pointer,
@@ -671,22 +2730,12 @@ impl riscv_cpu::cpu::Memory for Memory {
                 stack_length,
                 [argument_1, argument_2, argument_3, argument_4],
             ),
-            Syscall::UnmapMemory(address, size) => {
-                // println!("UnmapMemory({:08x}, {})", address, size);
-                for offset in (address..address + size).step_by(4096) {
-                    self.free_virt_page(offset as u32).unwrap();
-                }
-                [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into()
-            }
+            Syscall::UnmapMemory(address, size) => syscalls::unmap_memory(self, address, size),
+            Syscall::VirtToPhys(address) => syscalls::virt_to_phys(self, PROCESS_ID, address),
+            Syscall::VirtToPhysPid(pid, address) => syscalls::virt_to_phys(self, pid, address),
             Syscall::JoinThread(thread_id) => {
-                // println!("JoinThread({})", thread_id);
-                // let (tx, rx) = std::sync::mpsc::channel();
-                // self.memory_cmd
-                //     .send(MemoryCommand::JoinThread(thread_id as _, tx))
-                //     .unwrap();
-                // rx.into()
-                if let Some(val) = self.thread_handles.lock().unwrap().remove(&thread_id) {
-                    SyscallResult::JoinThread(val)
+                if let Some(rx) = self.thread_handles.lock().unwrap().remove(&thread_id) {
+                    services::wait_for(rx)
                 } else {
                     [
                         SyscallResultNumber::Error as i32,
@@ -701,76 +2750,1057 @@ impl riscv_cpu::cpu::Memory for Memory {
                     .into()
                 }
             }
-            Syscall::TerminateProcess(exit_code) => {
-                // println!("TerminateProcess({})", result);
-                syscalls::terminate_process(self, exit_code)
-            }
-            Syscall::GetProcessId => {
-                [SyscallResultNumber::ProcessId as i32, 2, 0, 0, 0, 0, 0, 0].into()
-            }
+            Syscall::TerminateProcess(exit_code) => syscalls::terminate_process(self, exit_code),
+            Syscall::GetProcessId => [
+                SyscallResultNumber::ProcessId as i32,
+                PROCESS_ID,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ]
+            .into(),
+            Syscall::ExitThread(result) => SyscallResult::ExitThread(result as u32),
+            Syscall::WaitEvent => self.notifier.wait(),
             Syscall::Unknown(args) => {
-                eprintln!(
-                    "Unhandled syscall #{} {:?}: {:?}",
-                    args[0],
-                    SyscallNumber::from(args[0]),
-                    &args[1..]
-                );
-                unimplemented!("Unhandled syscall");
-                // [SyscallResultNumber::Unimplemented as _, 0, 0, 0, 0, 0, 0, 0]
+                let mut rest = [0; 7];
+                rest.copy_from_slice(&args[1..]);
+                syscalls::handle_abuse(
+                    self,
+                    abuse::Abuse::UnknownSyscall,
+                    [SyscallResultNumber::Unimplemented as _, 0, 0, 0, 0, 0, 0, 0].into(),
+                    YoveError::Syscall {
+                        tid: self.tid,
+                        number: args[0],
+                        args: rest,
+                    },
+                )
             }
         }
     }
+}
 
-    fn translate(&self, v_address: u32) -> Option<u32> {
-        self.translation_cache.read().unwrap()[v_address as usize >> 12]
-            .map(|x| x.get() | v_address & 0xfff)
-    }
+/// How many instructions each thread may execute before `Machine::step` moves
+/// on to the next thread.
+const STEP_QUANTUM: usize = 1000;
 
-    fn reserve(&self, core: u32, p_address: u32) {
-        self.reservations.lock().unwrap().insert(p_address, core);
-    }
+/// How often the threaded `Machine::run` lets services expire their timeouts.
+#[cfg(not(target_arch = "wasm32"))]
+const SERVICE_TICK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(1);
 
-    fn clear_reservation(&self, core: u32, p_address: u32) -> bool {
-        self.reservations.lock().unwrap().remove(&{ p_address }) == Some(core)
-    }
+/// How long `Machine::run_tokio`, `Machine::step_over`, and `Machine::step_out`
+/// sleep when every guest thread is blocked.
+#[cfg(any(feature = "tokio", not(target_arch = "wasm32")))]
+const IDLE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(1);
 
-    fn clone(&self) -> Box<dyn OtherMemory + Send + Sync> {
-        Box::new(Clone::clone(self))
-    }
+/// The state of the machine after a call to `Machine::step`.
+#[derive(Debug, PartialEq)]
+pub enum MachineEvent {
+    /// At least one guest thread made progress.
+    Running,
+
+    /// Every guest thread is waiting on a service or on another thread.
+    Idle,
+
+    /// The main thread exited, or the process was terminated, with the given value.
+    Exited(u32),
+
+    /// Thread `tid` stopped before running the instruction at `pc`, because
+    /// it reached a breakpoint or finished a step. Stepping the machine again
+    /// runs that instruction.
+    Stopped { tid: i32, pc: u32 },
 }
 
-impl SystemBus for Memory {}
+/// Conditions that `Machine::run_until` can wait for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// Resolve as soon as every guest thread is blocked, or the program exits.
+    Idle,
+
+    /// Resolve only once the program exits.
+    Exited,
+}
 
 pub struct Machine {
     memory: Box<Memory>,
-    // workers: Vec<WorkerHandle>,
-    satp: u32,
+    /// Threads that are driven by `step()` rather than by their own host thread.
+    workers: Vec<Worker>,
+
+    /// Addresses that stop a thread driven by `step()` before it runs the
+    /// instruction there.
+    breakpoints: BTreeSet<u32>,
+
+    /// Breakpoints on symbols that aren't in the program, set when a
+    /// program that has them is loaded.
+    pending_breakpoints: Vec<breakpoint_script::Location>,
     // memory_cmd_sender: Sender<MemoryCommand>,
     memory_cmd: Receiver<MemoryCommand>,
-    thread_id_counter: AtomicI32,
+    exit_code: Option<u32>,
+    /// Arguments passed to the program, starting with its name.
+    args: Vec<String>,
+
+    /// Load programs built for machines other than RISC-V.
+    any_machine: bool,
+    program_info: program::ProgramInfo,
+
+    /// The functions and objects in the program's symbol table.
+    symbols: Vec<profiler::Symbol>,
+
+    /// What everything random about the run was derived from.
+    seed: u64,
+
+    /// How many host threads `run()` shares the guest threads between, if
+    /// it doesn't give each one its own.
+    harts: Option<usize>,
+
+    /// The thread serving `/metrics`, if they're being served.
+    metrics_server: Option<metrics::MetricsServer>,
+
+    /// The threads copying preopened streams to and from the guest.
+    preopened: Vec<preopen::Attachment>,
+
+    /// How to build this machine again from the start, for seeking
+    /// backwards, unless it was built with something that can't be
+    /// built twice.
+    #[cfg(not(target_arch = "wasm32"))]
+    replay: Option<Box<Replay>>,
 }
 
-impl Machine {
-    pub fn new(program: &[u8]) -> Result<Self, LoadError> {
-        let (memory, memory_cmd) = Memory::new(MEMORY_BASE, 16 * 1024 * 1024);
+/// A builder and program that build a machine the same way as before.
+#[cfg(not(target_arch = "wasm32"))]
+struct Replay {
+    builder: MachineBuilder,
+    program: Vec<u8>,
+}
+
+impl Drop for Machine {
+    fn drop(&mut self) {
+        if let Some(server) = &mut self.metrics_server {
+            server.stop();
+        }
+        for attachment in &mut self.preopened {
+            attachment.stop();
+        }
+        self.memory.usb.stop();
+    }
+}
+
+pub struct MachineBuilder {
+    platform: Option<Arc<dyn Platform>>,
+    args: Vec<String>,
+    fault_rules: Vec<faults::FaultRule>,
+    flash_faults: Vec<flash::FlashFault>,
+    seed: Option<u64>,
+    fault_seed: Option<u64>,
+    randomize_layout: bool,
+    layout_seed: Option<u64>,
+    profiler: Option<Arc<profiler::Profiler>>,
+    heatmap: bool,
+    message_stats: bool,
+    commit_log: Option<Box<dyn std::io::Write + Send>>,
+    trace: bool,
+    execution: Option<(usize, Vec<u16>)>,
+    shadow_stack: Option<Vec<shadow_stack::Allow>>,
+    breakpoint_script: Option<breakpoint_script::BreakpointScript>,
+    guest_panics: Option<Vec<String>>,
+    cfg: bool,
+    branch_stats: Option<usize>,
+    framebuffer: bool,
+    screenshot_interval_ms: Option<u64>,
+    time_scale: Option<f64>,
+    freeze_time: bool,
+    start_time_us: u64,
+    strict_memory: bool,
+    uninitialized_reads: bool,
+    w_xor_x: bool,
+    read_only: bool,
+    strace: bool,
+    megapages: bool,
+    vlen: Option<u32>,
+    permissive_csrs: bool,
+    hints: riscv_cpu::cpu::HintPolicy,
+    memory_limit: Option<u32>,
+    thread_limit: Option<usize>,
+    counters: counters::CounterPolicy,
+    abuse: abuse::AbusePolicy,
+    demand_paging: bool,
+    server_queue_depth: usize,
+    preopened: Vec<(u32, u32, preopen::Preopened)>,
+    any_machine: bool,
+    watchdog_ms: Option<u64>,
+    response_timeout_ms: Option<u64>,
+    ec: Option<ec::EcStatus>,
+    host_exec: std::collections::BTreeMap<String, std::path::PathBuf>,
+    usb_serial: Option<(
+        Box<dyn std::io::Read + Send>,
+        Box<dyn std::io::Write + Send>,
+    )>,
+    usb_keyboard: Option<Box<dyn std::io::Write + Send>>,
+    key_script: Option<keyboard::KeyScript>,
+    audio: Option<Box<dyn audio::AudioSink>>,
+    flash: Option<Arc<flash::Flash>>,
+    bridged: Vec<String>,
+    bridge: Option<(
+        Box<dyn std::io::Read + Send>,
+        Box<dyn std::io::Write + Send>,
+    )>,
+    memory_size: u32,
+    harts: Option<usize>,
+    metrics: Option<std::net::TcpListener>,
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        MachineBuilder {
+            platform: None,
+            args: vec![],
+            fault_rules: vec![],
+            flash_faults: vec![],
+            seed: None,
+            fault_seed: None,
+            randomize_layout: false,
+            layout_seed: None,
+            profiler: None,
+            heatmap: false,
+            message_stats: false,
+            commit_log: None,
+            trace: false,
+            execution: None,
+            shadow_stack: None,
+            breakpoint_script: None,
+            guest_panics: None,
+            cfg: false,
+            branch_stats: None,
+            framebuffer: false,
+            screenshot_interval_ms: None,
+            time_scale: None,
+            freeze_time: false,
+            start_time_us: 0,
+            strict_memory: false,
+            uninitialized_reads: false,
+            w_xor_x: false,
+            read_only: false,
+            strace: false,
+            megapages: false,
+            vlen: None,
+            permissive_csrs: false,
+            hints: riscv_cpu::cpu::HintPolicy::Ignore,
+            memory_limit: None,
+            thread_limit: None,
+            counters: counters::CounterPolicy::Native,
+            abuse: abuse::AbusePolicy::default(),
+            demand_paging: false,
+            server_queue_depth: DEFAULT_SERVER_QUEUE_DEPTH,
+            preopened: vec![],
+            any_machine: false,
+            watchdog_ms: None,
+            response_timeout_ms: None,
+            ec: None,
+            host_exec: std::collections::BTreeMap::new(),
+            usb_serial: None,
+            usb_keyboard: None,
+            key_script: None,
+            audio: None,
+            flash: None,
+            bridged: vec![],
+            bridge: None,
+            memory_size: DEFAULT_MEMORY_SIZE,
+            harts: None,
+            metrics: None,
+        }
+    }
+
+    /// Use `platform` for the clock, entropy, and console instead of the host's.
+    pub fn platform(mut self, platform: Arc<dyn Platform>) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
+    /// Set the argument list passed to the program, starting with its name.
+    pub fn args(mut self, args: Vec<String>) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Add a rule for injecting faults into syscalls.
+    pub fn fault(mut self, rule: faults::FaultRule) -> Self {
+        self.fault_rules.push(rule);
+        self
+    }
+
+    /// Add a fault to inject into writes to the flash.
+    pub fn flash_fault(mut self, fault: flash::FlashFault) -> Self {
+        self.flash_faults.push(fault);
+        self
+    }
+
+    /// Seed everything random about the run, so that it can be repeated:
+    /// the TRNG server, the fault injector, random flash faults, and the
+    /// layout, if it's randomized. Each gets its own stream derived from
+    /// `seed`, unless it's been given a seed of its own. Without this, the
+    /// seed is picked at random and can be read back with `Machine::seed()`.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Seed the fault injector, and any random flash faults, so that a run
+    /// can be reproduced. Overrides the stream derived from `seed()`.
+    pub fn fault_seed(mut self, seed: u64) -> Self {
+        self.fault_seed = Some(seed);
+        self
+    }
+
+    /// Place the heap, the stack, and each `MapMemory` region at a random
+    /// spot within its window, rather than at the same address every run,
+    /// to shake out code that depends on where memory ends up. The layout is
+    /// deterministic unless this is called.
+    pub fn randomize_layout(mut self) -> Self {
+        self.randomize_layout = true;
+        self
+    }
+
+    /// Seed the layout randomization so that a run can be reproduced.
+    /// Overrides the stream derived from `seed()`, and implies
+    /// `randomize_layout()`.
+    pub fn layout_seed(mut self, seed: u64) -> Self {
+        self.randomize_layout = true;
+        self.layout_seed = Some(seed);
+        self
+    }
+
+    /// Sample every thread's PC and stack once every `interval` instructions,
+    /// which must be at least 1. The samples can be exported with
+    /// `Machine::profiler()`.
+    pub fn profile(mut self, interval: u64) -> Self {
+        self.profiler = Some(Arc::new(profiler::Profiler::new(interval)));
+        self
+    }
+
+    /// Count accesses to every page of memory. The counts can be exported
+    /// with `Machine::write_heatmap()`.
+    pub fn heatmap(mut self) -> Self {
+        self.heatmap = true;
+        self
+    }
+
+    /// Count the messages sent to each opcode of each server and time how
+    /// long they take to be answered. The results are available from
+    /// `Machine::message_stats()`.
+    pub fn message_stats(mut self) -> Self {
+        self.message_stats = true;
+        self
+    }
+
+    /// Write a line to `output` for every instruction that retires, in the
+    /// format of spike's `--log-commits`. Call `Machine::commit_log()` to
+    /// flush it once the program is done.
+    pub fn log_commits(mut self, output: Box<dyn std::io::Write + Send>) -> Self {
+        self.commit_log = Some(output);
+        self
+    }
+
+    /// Act on requests the guest writes to the hypercall CSR, such as trace
+    /// markers and coverage, and record when threads are created, block, and
+    /// unblock, and when the emulator panics while running one. The results
+    /// are available from `Machine::tracer()`.
+    /// Without this, hypercalls are ignored.
+    pub fn trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+
+    /// Record the PC, privilege level, and the CSRs at `csrs` after every
+    /// instruction, keeping the most recent `limit`. These can be exported as
+    /// waveforms or traces from `Machine::tracer()`. This also enables `trace()`.
+    /// Building fails with `LoadError::InvalidTraceLimit` if `limit` is 0.
+    pub fn record_execution(mut self, limit: usize, csrs: Vec<u16>) -> Self {
+        self.execution = Some((limit, csrs));
+        self
+    }
+
+    /// Keep a shadow stack of return addresses for every thread, and stop the
+    /// program with `YoveError::ShadowStack` as soon as a return goes anywhere
+    /// else. Jumps made by the code in `allow` aren't checked, and unwind the
+    /// shadow stack to wherever they land.
+    pub fn shadow_stack(mut self, allow: Vec<shadow_stack::Allow>) -> Self {
+        self.shadow_stack = Some(allow);
+        self
+    }
+
+    /// Take the actions in `script` whenever a thread reaches one of its
+    /// breakpoints, without stopping the machine. Memory it dumps is
+    /// available from `Machine::breakpoint_dumps()`.
+    pub fn breakpoint_script(mut self, script: breakpoint_script::BreakpointScript) -> Self {
+        self.breakpoint_script = Some(script);
+        self
+    }
+
+    /// Stop the program with `YoveError::GuestPanic` as soon as it panics,
+    /// with the message, a backtrace, and, if execution is being recorded,
+    /// the last instructions the thread ran. A panic is caught when a thread
+    /// reaches one of the functions in `symbols`, such as those in
+    /// `guest_panic::DEFAULT_PANIC_SYMBOLS`, or when the process exits with a
+    /// nonzero code after writing to the panic-to-screen server.
+    pub fn catch_guest_panics(mut self, symbols: Vec<String>) -> Self {
+        self.guest_panics = Some(symbols);
+        self
+    }
+
+    /// Record the basic blocks that run and the edges between them, including
+    /// calls. The graph can be exported with `Machine::control_flow_graph()`.
+    pub fn control_flow_graph(mut self) -> Self {
+        self.cfg = true;
+        self
+    }
+
+    /// Model the branch predictor, with a branch target buffer of
+    /// `btb_entries` entries, and instruction prefetch buffer of a VexRiscv
+    /// core, counting mispredictions and fetch misses by function. The
+    /// counts are available from `Machine::branch_stats()`.
+    pub fn branch_stats(mut self, btb_entries: usize) -> Self {
+        self.branch_stats = Some(btb_entries);
+        self
+    }
+
+    /// Emulate a memory LCD that the guest can map with `MapMemory` at
+    /// `framebuffer::FRAMEBUFFER_ADDRESS`. Its contents are available from
+    /// `Machine::screenshot()`.
+    pub fn framebuffer(mut self) -> Self {
+        self.framebuffer = true;
+        self
+    }
+
+    /// Capture the display every `interval_ms`, for `Machine::screenshots()`.
+    /// This also enables `framebuffer()`. Building fails with
+    /// `LoadError::InvalidScreenshotInterval` if `interval_ms` is 0.
+    pub fn screenshot_interval(mut self, interval_ms: u64) -> Self {
+        self.framebuffer = true;
+        self.screenshot_interval_ms = Some(interval_ms);
+        self
+    }
+
+    /// Run the guest's clock `scale` times as fast as the host's. This is the
+    /// clock seen by every service and the `time` CSR.
+    pub fn time_scale(mut self, scale: f64) -> Self {
+        self.time_scale = Some(scale);
+        self
+    }
+
+    /// Start with the guest's clock stopped, so that it only moves when
+    /// `Machine::clock()` advances it.
+    pub fn freeze_time(mut self) -> Self {
+        self.freeze_time = true;
+        self
+    }
+
+    /// Start the guest's clock at `us` microseconds rather than zero, such as
+    /// where `Machine::clock()` had got to in an earlier run being resumed.
+    /// Every timeout is kept in the guest's time, so they carry on from there
+    /// rather than expiring at once or seeing time go backwards.
+    pub fn start_time_us(mut self, us: u64) -> Self {
+        self.start_time_us = us;
+        self
+    }
+
+    /// Raise an access fault when the guest touches a physical address outside
+    /// of RAM. Otherwise such reads return zero and writes are ignored. Either
+    /// way, the first few are listed by `Machine::invalid_accesses()`.
+    pub fn strict_memory(mut self) -> Self {
+        self.strict_memory = true;
+        self
+    }
+
+    /// Warn when the guest reads memory that it mapped but never wrote, which
+    /// would otherwise read as zero. The reads are listed by
+    /// `Machine::uninitialized_reads()`, and with `strict_memory()` they raise
+    /// an access fault.
+    pub fn detect_uninitialized_reads(mut self) -> Self {
+        self.uninitialized_reads = true;
+        self
+    }
+
+    /// Never let a page be writable and executable at once. Pages the
+    /// program's code was loaded into can only be run, and every other page
+    /// can only be read and written, unless the guest swaps them with
+    /// `UpdateMemoryFlags`. Writing code raises a store access fault, and
+    /// running data an instruction access fault, and the section of the
+    /// program that was there is logged.
+    pub fn enforce_w_xor_x(mut self) -> Self {
+        self.w_xor_x = true;
+        self
+    }
+
+    /// Map the pages the program's code and constants were loaded into
+    /// without write permission, so that writing to `.text` or `.rodata`
+    /// raises a store access fault, and the symbol that was written over is
+    /// logged.
+    pub fn read_only_sections(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Print every syscall the guest makes to stderr, decoded, along with its
+    /// result, the thread that made it, and how long it took.
+    pub fn strace(mut self) -> Self {
+        self.strace = true;
+        self
+    }
+
+    /// Map every aligned 4MB that a `MapMemory` or `IncreaseHeap` call covers
+    /// with a single megapage, rather than with a page table of 4K pages,
+    /// when there's 4MB of aligned physical memory free for it. Changing or
+    /// unmapping part of a megapage splits it back into 4K pages.
+    pub fn megapages(mut self) -> Self {
+        self.megapages = true;
+        self
+    }
+
+    /// Decide what the guest reads from the `cycle`, `time`, and `instret`
+    /// CSRs and their upper halves. User mode isn't allowed to read them
+    /// itself, so the emulator answers every read according to `policy`.
+    pub fn counters(mut self, policy: counters::CounterPolicy) -> Self {
+        self.counters = policy;
+        self
+    }
+
+    /// Deal with syscalls that misuse the interface in the way `abuse` says
+    /// with `handler` instead of the default, which is to warn about
+    /// everything but unknown syscalls, which end the process.
+    pub fn on_abuse(mut self, abuse: abuse::Abuse, handler: abuse::AbuseHandler) -> Self {
+        self.abuse.set(abuse, handler);
+        self
+    }
+
+    /// Allocate the pages of the stack and of each `IncreaseHeap` call the
+    /// first time the guest touches them, rather than all at once, so that
+    /// a program that reserves a large heap only uses memory for the part
+    /// of it that it uses.
+    pub fn demand_paging(mut self) -> Self {
+        self.demand_paging = true;
+        self
+    }
+
+    /// Let each server hold up to `depth` messages that it hasn't responded
+    /// to, such as threads blocked on a mutex, before sending it another
+    /// message fails with `ServerQueueFull`.
+    pub fn server_queue_depth(mut self, depth: usize) -> Self {
+        self.server_queue_depth = depth;
+        self
+    }
+
+    /// Hand the guest a host stream through two rings of the
+    /// `yove-ring-buffer` service: data from the host arrives on ring `rx`,
+    /// which the guest attaches as `HostToGuest`, and data the guest writes
+    /// to ring `tx` goes to the host. Each ring is closed when its side of
+    /// the stream ends.
+    pub fn preopen(mut self, rx: u32, tx: u32, stream: preopen::Preopened) -> Self {
+        self.preopened.push((rx, tx, stream));
+        self
+    }
+
+    /// Load programs whose ELF header names a machine other than RISC-V, such
+    /// as those from toolchains that leave it as `EM_NONE`. Programs that are
+    /// 64-bit or big-endian are still rejected.
+    pub fn allow_any_machine(mut self) -> Self {
+        self.any_machine = true;
+        self
+    }
+
+    /// Serve health metrics in the Prometheus text format to anyone who asks
+    /// for `/metrics` on `listener`: instructions retired, guest threads,
+    /// allocated memory, and counts of syscalls, messages, page faults, and
+    /// traps. They're served from a thread of their own until the machine
+    /// is dropped.
+    pub fn serve_metrics(mut self, listener: std::net::TcpListener) -> Self {
+        self.metrics = Some(listener);
+        self
+    }
+
+    /// Stop the program if it goes more than `timeout_ms` without petting the
+    /// watchdog through the ticktimer's `PingWdt` opcode. `Machine::run()` then
+    /// returns `YoveError::Watchdog` with the state of one of its threads. A
+    /// clean suspend through the suspend/resume manager also restarts it.
+    /// `timeout_ms` must be at least 1.
+    pub fn watchdog(mut self, timeout_ms: u64) -> Self {
+        self.watchdog_ms = Some(timeout_ms);
+        self
+    }
+
+    /// Stop the program if a thread waits more than `timeout_ms` of host time
+    /// for a service to respond to a message, rather than letting it hang.
+    /// `Machine::run()` then returns `YoveError::Hang` saying which service
+    /// and opcode it was waiting on. Waiting on anything else, such as
+    /// joining a thread, has no limit.
+    pub fn response_timeout(mut self, timeout_ms: u64) -> Self {
+        self.response_timeout_ms = Some(timeout_ms);
+        self
+    }
+
+    /// Have the COM server report `status` for the battery, charger, and
+    /// wifi, rather than a battery that's 80% charged and no wifi networks.
+    /// It can be changed while the program runs through `Machine::ec()`.
+    pub fn ec(mut self, status: ec::EcStatus) -> Self {
+        self.ec = Some(status);
+        self
+    }
+
+    /// Let the program run `program` on the host by asking the
+    /// yove-host-exec server for `name`, and receive its exit code and
+    /// output. The server only exists once a command has been allowed, and
+    /// refuses any command that hasn't. Every request is logged and can be
+    /// read back from `Machine::host_exec()`.
+    pub fn allow_host_exec(mut self, name: &str, program: impl Into<std::path::PathBuf>) -> Self {
+        self.host_exec.insert(name.to_owned(), program.into());
+        self
+    }
+
+    /// Bridge the USB serial port to a host stream, such as a pty: what the
+    /// program writes goes to `writer`, and what it reads comes from
+    /// `reader`. Either way it's also available through `Machine::usb()`.
+    pub fn usb_serial(
+        mut self,
+        reader: Box<dyn std::io::Read + Send>,
+        writer: Box<dyn std::io::Write + Send>,
+    ) -> Self {
+        self.usb_serial = Some((reader, writer));
+        self
+    }
+
+    /// Write whatever the program types as a USB keyboard to `writer`.
+    pub fn usb_keyboard(mut self, writer: Box<dyn std::io::Write + Send>) -> Self {
+        self.usb_keyboard = Some(writer);
+        self
+    }
+
+    /// Press keys on the keyboard as `script` says, starting as soon as the
+    /// machine is built. See `keyboard` for what a script can do.
+    pub fn key_script(mut self, script: keyboard::KeyScript) -> Self {
+        self.key_script = Some(script);
+        self
+    }
+
+    /// Play the audio the program sends to the codec server through `sink`,
+    /// such as an `audio::WavWriter` to record it or, with the `audio`
+    /// feature, an `audio::LiveOutput` to hear it. Without a sink the codec
+    /// takes every frame it's sent and throws them away.
+    pub fn audio(mut self, sink: Box<dyn audio::AudioSink>) -> Self {
+        self.audio = Some(sink);
+        self
+    }
+
+    /// Give the `yove-flash` server `flash` rather than an erased 128MB chip. The
+    /// flash keeps what's written to it, so passing the one from
+    /// `Machine::flash()` to a new machine is like rebooting the device.
+    pub fn flash(mut self, flash: Arc<flash::Flash>) -> Self {
+        self.flash = Some(flash);
+        self
+    }
+
+    /// Pass messages to the servers called `names` on to a real device over
+    /// the link made of `reader` and `writer`, such as a Precursor's USB
+    /// serial port, rather than answering them here. The device has to be
+    /// running a server that speaks the protocol in `bridge`. Any name can be
+    /// bridged, including ones the emulator has no service for.
+    pub fn bridge(
+        mut self,
+        names: Vec<String>,
+        reader: Box<dyn std::io::Read + Send>,
+        writer: Box<dyn std::io::Write + Send>,
+    ) -> Self {
+        self.bridged = names;
+        self.bridge = Some((reader, writer));
+        self
+    }
+
+    /// Give the guest `bytes` of RAM rather than 16MB. Host memory is only
+    /// used for the pages the guest writes to, so a large guest costs little
+    /// until it's filled. The heap and `MapMemory` windows grow along with it.
+    /// `build()` fails with `LoadError::InvalidMemorySize` unless `bytes` is
+    /// a multiple of 4096 between 1MB and 768MB.
+    pub fn memory_size(mut self, bytes: u32) -> Self {
+        self.memory_size = bytes;
+        self
+    }
+
+    /// Have `Machine::run()` run the guest's threads on `count` host
+    /// threads, each an emulated hart with its own `mhartid`, rather than
+    /// starting a host thread for every guest thread. Each hart runs a
+    /// thread for a while and then moves on to the next one that's ready,
+    /// and sleeps while they're all blocked or stopped in `wfi`. `build()`
+    /// fails with `LoadError::NoHarts` if `count` is 0.
+    pub fn harts(mut self, count: usize) -> Self {
+        self.harts = Some(count);
+        self
+    }
+
+    /// Give every thread's CPU a vector unit with registers `vlen` bits long,
+    /// implementing part of Zve32x: `vsetvli`, unit-stride loads and stores,
+    /// and integer arithmetic. Other vector instructions are illegal.
+    /// `build()` fails with `LoadError::InvalidVlen` unless `vlen` is a power
+    /// of two from 32 to 65536.
+    pub fn vector(mut self, vlen: u32) -> Self {
+        self.vlen = Some(vlen);
+        self
+    }
+
+    /// Let the program read and write CSRs the CPU doesn't implement, as
+    /// plain storage, rather than taking an illegal instruction exception.
+    /// For programs written against older versions of the emulator, which
+    /// allowed every CSR address.
+    pub fn permissive_csrs(mut self) -> Self {
+        self.permissive_csrs = true;
+        self
+    }
+
+    /// Decide what to do with the compressed encodings set aside as HINTs,
+    /// such as `c.li x0, 1`: run them as no-ops, which is the default, run
+    /// them and log a warning for each, or raise an illegal instruction.
+    pub fn compressed_hints(mut self, policy: riscv_cpu::cpu::HintPolicy) -> Self {
+        self.hints = policy;
+        self
+    }
+
+    /// Allocate at most `bytes` of the guest's RAM at once, so that a guest
+    /// that runs away can't take more host memory than that. Past the limit,
+    /// `MapMemory` and `IncreaseHeap` fail with `OutOfMemory`, and pages the
+    /// guest touches with demand paging fault. Pages count from when they're
+    /// allocated, whether or not they've been written, and a program that
+    /// doesn't fit in the limit fails to build with `LoadError::OutOfMemory`.
+    ///
+    /// The limit counts the guest's pages, not the host memory behind them.
+    /// A page only gets host memory once it's written, and keeps it after
+    /// it's unmapped, for whichever allocation gets the page next. So the
+    /// host can end up holding every page the guest has ever written, up to
+    /// the size of RAM, even though the guest never holds more than `bytes`
+    /// of them at once.
+    pub fn memory_limit(mut self, bytes: u32) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Run at most `count` guest threads at once, including the main thread.
+    /// Unless the machine runs on harts, each takes a host thread. Past the
+    /// limit, `CreateThread` fails with `ThreadNotAvailable`.
+    pub fn thread_limit(mut self, count: usize) -> Self {
+        self.thread_limit = Some(count);
+        self
+    }
+
+    /// A builder with the same settings as this one, or `None` if this one
+    /// holds something that can only be used once or that keeps state
+    /// between machines: a platform, flash, or any host stream.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn replica(&self) -> Option<MachineBuilder> {
+        if self.platform.is_some()
+            || self.commit_log.is_some()
+            || !self.preopened.is_empty()
+            || self.usb_serial.is_some()
+            || self.usb_keyboard.is_some()
+            || self.audio.is_some()
+            || self.flash.is_some()
+            || self.bridge.is_some()
+            || self.metrics.is_some()
+        {
+            return None;
+        }
+        Some(MachineBuilder {
+            platform: None,
+            args: self.args.clone(),
+            fault_rules: self.fault_rules.clone(),
+            flash_faults: self.flash_faults.clone(),
+            seed: self.seed,
+            fault_seed: self.fault_seed,
+            randomize_layout: self.randomize_layout,
+            layout_seed: self.layout_seed,
+            profiler: self
+                .profiler
+                .as_ref()
+                .map(|profiler| Arc::new(profiler::Profiler::new(profiler.interval()))),
+            heatmap: self.heatmap,
+            message_stats: self.message_stats,
+            commit_log: None,
+            trace: self.trace,
+            execution: self.execution.clone(),
+            shadow_stack: self.shadow_stack.clone(),
+            breakpoint_script: self.breakpoint_script.clone(),
+            guest_panics: self.guest_panics.clone(),
+            cfg: self.cfg,
+            branch_stats: self.branch_stats,
+            framebuffer: self.framebuffer,
+            screenshot_interval_ms: self.screenshot_interval_ms,
+            time_scale: self.time_scale,
+            freeze_time: self.freeze_time,
+            start_time_us: self.start_time_us,
+            strict_memory: self.strict_memory,
+            uninitialized_reads: self.uninitialized_reads,
+            w_xor_x: self.w_xor_x,
+            read_only: self.read_only,
+            strace: self.strace,
+            megapages: self.megapages,
+            vlen: self.vlen,
+            permissive_csrs: self.permissive_csrs,
+            hints: self.hints,
+            memory_limit: self.memory_limit,
+            thread_limit: self.thread_limit,
+            counters: self.counters,
+            abuse: self.abuse,
+            demand_paging: self.demand_paging,
+            server_queue_depth: self.server_queue_depth,
+            preopened: vec![],
+            any_machine: self.any_machine,
+            watchdog_ms: self.watchdog_ms,
+            response_timeout_ms: self.response_timeout_ms,
+            ec: self.ec.clone(),
+            host_exec: self.host_exec.clone(),
+            usb_serial: None,
+            usb_keyboard: None,
+            key_script: self.key_script.clone(),
+            audio: None,
+            flash: None,
+            bridged: self.bridged.clone(),
+            bridge: None,
+            memory_size: self.memory_size,
+            harts: self.harts,
+            metrics: None,
+        })
+    }
+
+    pub fn build(self, program: &[u8]) -> Result<Machine, YoveError> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let replica = self.replica();
+        let platform = self
+            .platform
+            .unwrap_or_else(|| Arc::new(platform::HostPlatform::new()));
+        if !self.memory_size.is_multiple_of(4096)
+            || !(MIN_MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&self.memory_size)
+        {
+            return Err(LoadError::InvalidMemorySize(self.memory_size).into());
+        }
+        if self.harts == Some(0) {
+            return Err(LoadError::NoHarts.into());
+        }
+        if let Some(vlen) = self.vlen {
+            if !vlen.is_power_of_two() || !(MIN_VLEN..=MAX_VLEN).contains(&vlen) {
+                return Err(LoadError::InvalidVlen(vlen).into());
+            }
+        }
+        if self.profiler.as_ref().is_some_and(|p| p.interval() == 0) {
+            return Err(LoadError::InvalidProfileInterval.into());
+        }
+        if self.watchdog_ms == Some(0) {
+            return Err(LoadError::InvalidWatchdogTimeout.into());
+        }
+        if matches!(self.execution, Some((0, _))) {
+            return Err(LoadError::InvalidTraceLimit.into());
+        }
+        if self.screenshot_interval_ms == Some(0) {
+            return Err(LoadError::InvalidScreenshotInterval.into());
+        }
+        let (mut memory, memory_cmd) =
+            Memory::new(MEMORY_BASE, self.memory_size as usize, platform.clone());
+        let random_flash_faults = self
+            .flash_faults
+            .iter()
+            .any(|fault| matches!(fault.trigger, flash::FlashTrigger::Random(_)));
+        let seed = self
+            .seed
+            .unwrap_or_else(|| (platform.random_u32() as u64) << 32 | platform.random_u32() as u64);
+        memory.trng = Arc::new(rng::Rng::new(rng::derive_seed(seed, "trng")));
+        let fault_seed = (!self.fault_rules.is_empty() || random_flash_faults).then(|| {
+            self.fault_seed.unwrap_or_else(|| {
+                let seed = rng::derive_seed(seed, "faults");
+                log::info!("Injecting faults with seed {}", seed);
+                seed
+            })
+        });
+        if !self.fault_rules.is_empty() {
+            memory.faults = Some(Arc::new(faults::FaultInjector::new(
+                self.fault_rules,
+                fault_seed.unwrap(),
+            )));
+        }
+        memory.flash = self
+            .flash
+            .unwrap_or_else(|| Arc::new(flash::Flash::new(flash::DEFAULT_FLASH_SIZE)));
+        memory
+            .flash
+            .power_on(self.flash_faults, fault_seed.unwrap_or(0));
+        if self.randomize_layout {
+            let seed = self.layout_seed.unwrap_or_else(|| {
+                let seed = rng::derive_seed(seed, "layout");
+                log::info!("Randomizing the memory layout with seed {}", seed);
+                seed
+            });
+            let rng = rng::Rng::new(seed);
+            memory.space.heap_start.store(
+                HEAP_START + rng.below(HEAP_RANDOM_PAGES) * 4096,
+                Ordering::Relaxed,
+            );
+            memory.layout = Some(Arc::new(rng));
+        }
+        memory.profiler = self.profiler;
+        if self.heatmap {
+            let size = memory.data.page_count() * 4096;
+            memory.heatmap = Some(Arc::new(heatmap::Heatmap::new(memory.base, size)));
+        }
+        if self.message_stats {
+            memory.message_stats = Some(Arc::new(message_stats::MessageStats::new()));
+        }
+        if let Some(output) = self.commit_log {
+            memory.commit_log = Some(Arc::new(commit_log::CommitLog::new(output)));
+        }
+        if let Some((limit, csrs)) = self.execution {
+            memory.tracer = Some(Arc::new(trace::Tracer::with_execution(limit, csrs)));
+        } else if self.trace {
+            memory.tracer = Some(Arc::new(trace::Tracer::new()));
+        }
+        memory.strict_memory = self.strict_memory;
+        memory.strace = self.strace;
+        memory.megapages = self.megapages;
+        memory.vlen = self.vlen;
+        memory.permissive_csrs = self.permissive_csrs;
+        memory.hints = self.hints;
+        memory.memory_limit = self.memory_limit;
+        memory.thread_limit = self.thread_limit;
+        memory.counters = self.counters;
+        memory.abuse = self.abuse;
+        memory.demand_paging = self.demand_paging;
+        memory.server_queue_depth = self.server_queue_depth;
+        memory.response_timeout_ms = self.response_timeout_ms;
+        if let Some(status) = self.ec {
+            memory.ec = Arc::new(ec::Ec::new(status));
+        }
+        if !self.host_exec.is_empty() {
+            memory.host_exec = Some(Arc::new(host_exec::HostExec::new(self.host_exec)));
+        }
+        if let Some((reader, writer)) = self.usb_serial {
+            memory.usb.bridge_serial(reader, writer);
+        }
+        if let Some(writer) = self.usb_keyboard {
+            memory.usb.bridge_keyboard(writer);
+        }
+        if let Some(script) = self.key_script {
+            memory.keyboard.run_script(script);
+        }
+        if let Some(sink) = self.audio {
+            memory.audio = Arc::new(audio::Audio::new(sink));
+        }
+        if let Some((reader, writer)) = self.bridge {
+            let bridge = bridge::Bridge::new(self.bridged, reader, writer);
+            memory.bridge = Some(Arc::new(bridge));
+        }
+        let mut preopened = vec![];
+        for (rx, tx, stream) in self.preopened {
+            preopened.push(preopen::attach(
+                stream,
+                memory.ring_buffer(rx),
+                memory.ring_buffer(tx),
+            ));
+        }
+        if self.uninitialized_reads {
+            memory.uninit = Some(Arc::new(uninit::UninitTracker::new()));
+        }
+        if self.w_xor_x {
+            memory.w_xor_x = Some(Arc::new(w_xor_x::WxPolicy::default()));
+        }
+        if self.read_only {
+            memory.read_only = Some(Arc::new(read_only::ReadOnlyPolicy::default()));
+        }
+        if let Some(scale) = self.time_scale {
+            memory.clock.set_scale(scale);
+        }
+        if self.freeze_time {
+            memory.clock.freeze();
+        }
+        memory.clock.start_at(self.start_time_us);
+        if let Some(allow) = self.shadow_stack {
+            memory.shadow_stack = Some(Arc::new(shadow_stack::ShadowStackPolicy::new(allow)));
+        }
+        if let Some(script) = self.breakpoint_script {
+            // Recording waits for the script to turn it on
+            if let (true, Some(tracer)) = (script.starts_tracing(), &memory.tracer) {
+                tracer.set_recording(false);
+            }
+            let breakpoints = breakpoint_script::ScriptedBreakpoints::new(script);
+            memory.breakpoint_script = Some(Arc::new(breakpoints));
+        }
+        if let Some(symbols) = self.guest_panics {
+            memory.guest_panics = Some(Arc::new(guest_panic::PanicCatcher::new(symbols)));
+        }
+        if self.cfg {
+            memory.cfg = Some(Arc::new(cfg::ControlFlowGraph::new()));
+        }
+        if let Some(btb_entries) = self.branch_stats {
+            memory.branch_stats = Some(Arc::new(branch_stats::BranchStats::new(btb_entries)));
+        }
+        if self.framebuffer {
+            let framebuffer = framebuffer::Framebuffer::new(self.screenshot_interval_ms);
+            memory.framebuffer = Some(Arc::new(framebuffer));
+        }
+        if let Some(timeout_ms) = self.watchdog_ms {
+            let watchdog = watchdog::Watchdog::new(timeout_ms);
+            watchdog.pet(memory.platform.elapsed_ms());
+            memory.watchdog = Some(Arc::new(watchdog));
+        }
+        let mut metrics_server = None;
+        if let Some(listener) = self.metrics {
+            memory.metrics = Some(Arc::new(metrics::Metrics::default()));
+            let server = metrics::MetricsServer::start(listener, Clone::clone(&memory))?;
+            metrics_server = Some(server);
+        }
         // let memory_cmd_sender = memory.memory_cmd.clone();
         let memory = Box::new(memory);
 
-        let mut machine = Self {
+        let mut machine = Machine {
             memory,
-            // workers: vec![],
-            satp: 0,
+            workers: vec![],
+            breakpoints: BTreeSet::new(),
+            pending_breakpoints: vec![],
             memory_cmd,
             // memory_cmd_sender,
-            thread_id_counter: AtomicI32::new(1),
+            exit_code: None,
+            args: self.args,
+            any_machine: self.any_machine,
+            program_info: program::ProgramInfo::default(),
+            symbols: vec![],
+            harts: self.harts,
+            seed,
+            metrics_server,
+            preopened,
+            #[cfg(not(target_arch = "wasm32"))]
+            replay: replica.map(|builder| {
+                // The seed may have been picked just now, so it's kept
+                // for the machine built next time
+                Box::new(Replay {
+                    builder: builder.seed(seed),
+                    program: program.to_vec(),
+                })
+            }),
         };
 
         machine.load_program(program)?;
 
-        Ok(machine)
+        Ok(machine)
+    }
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Machine {
+    pub fn new(program: &[u8]) -> Result<Self, YoveError> {
+        MachineBuilder::new().build(program)
     }
 
-    pub fn create_params() -> std::io::Result<Vec<u8>> {
+    /// Build the parameter block passed to the program, containing the host's
+    /// environment and the given argument list.
+    pub fn create_params(arg_data: &[String]) -> std::io::Result<Vec<u8>> {
         use std::io::Write;
 
         // Copy the host's environment variables into the target's environment
@@ -799,28 +3829,6 @@ impl Machine {
         env_tag.write_all(&env_data)?;
 
         let mut arg_tag = vec![];
-        let mut arg_data = vec![];
-        // Copy arguments, making sure to skip the program name and target name
-        let our_args = std::env::args().skip(1).collect::<Vec<_>>();
-        if our_args.contains(&"--".to_owned()) {
-            let mut found = false;
-            let mut first = false;
-            for arg in our_args.iter() {
-                // Always push the first argument, since it's the program name
-                if first {
-                    arg_data.push(arg);
-                    first = false;
-                } else if found {
-                    arg_data.push(arg);
-                } else if arg == "--" {
-                    found = true;
-                }
-            }
-        } else {
-            for arg in our_args.iter() {
-                arg_data.push(arg);
-            }
-        }
         arg_tag.write_all(&ARGS_MAGIC)?;
         let mut args_size = 0;
         for entry in arg_data.iter() {
@@ -851,20 +3859,70 @@ impl Machine {
         Ok(sample_data)
     }
 
-    pub fn load_program(&mut self, program: &[u8]) -> Result<(), LoadError> {
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), YoveError> {
         let mut cpu = riscv_cpu::CpuBuilder::new(self.memory.clone()).build();
+        cpu.get_mut_mmu().check_physical_addresses(true);
 
+        program::check_ident(program)?;
         let goblin::Object::Elf(elf) =
             goblin::Object::parse(program).map_err(|_| LoadError::IncorrectFormat)?
         else {
-            return Err(LoadError::IncorrectFormat);
+            return Err(LoadError::IncorrectFormat.into());
         };
-        if elf.is_64 {
-            return Err(LoadError::BitSizeError);
+        program::check_header(&elf, self.any_machine)?;
+        let start_us = self.memory.clock.host_us();
+        self.program_info = program::ProgramInfo::new(&elf);
+        program::check_sections(&self.program_info.sections, USER_AREA_END)?;
+
+        self.symbols = elf
+            .syms
+            .iter()
+            .filter(|sym| {
+                matches!(
+                    sym.st_type(),
+                    goblin::elf::sym::STT_FUNC | goblin::elf::sym::STT_OBJECT
+                ) && sym.st_value != 0
+            })
+            .filter_map(|sym| {
+                Some(profiler::Symbol {
+                    address: sym.st_value as u32,
+                    size: sym.st_size as u32,
+                    name: elf.strtab.get_at(sym.st_name)?.to_owned(),
+                })
+            })
+            .collect();
+        for location in std::mem::take(&mut self.pending_breakpoints) {
+            self.add_breakpoint_at(location);
+        }
+        if let Some(policy) = &self.memory.shadow_stack {
+            if let Some(name) = policy.set_symbols(&self.symbols).into_iter().next() {
+                return Err(LoadError::UnknownSymbol(name).into());
+            }
+        }
+        if let Some(script) = &self.memory.breakpoint_script {
+            if let Some(name) = script.set_symbols(&self.symbols).into_iter().next() {
+                return Err(LoadError::UnknownSymbol(name).into());
+            }
+        }
+        if let Some(catcher) = &self.memory.guest_panics {
+            catcher.set_symbols(&self.symbols);
+        }
+        if let Some(cfg) = &self.memory.cfg {
+            cfg.set_symbols(self.symbols.clone());
+        }
+        if let Some(stats) = &self.memory.branch_stats {
+            stats.set_symbols(self.symbols.clone());
+        }
+        if let Some(profiler) = &self.memory.profiler {
+            let program_name = self.args.first().map_or("guest", |name| name.as_str());
+            profiler.set_symbols(program_name, self.symbols.clone());
+        }
+        if let Some(policy) = &self.memory.read_only {
+            policy.set_symbols(self.symbols.clone());
         }
 
         for sh in elf.section_headers {
-            if sh.sh_flags as u32 & goblin::elf::section_header::SHF_ALLOC == 0 {
+            if !program::is_loaded(&sh) {
                 // println!(
                 //     "Ignoring section {}...",
                 //     elf.shdr_strtab.get_at(sh.sh_name).unwrap_or("???")
@@ -872,37 +3930,80 @@ impl Machine {
                 continue;
             }
 
+            log::debug!(
+                "Loading {} ({:#x} bytes at {:08x})",
+                elf.shdr_strtab.get_at(sh.sh_name).unwrap_or("???"),
+                sh.sh_size,
+                sh.sh_addr
+            );
+
             // Place the eh_frame offset into $a0 so the program can unwind correctly
             if elf.shdr_strtab.get_at(sh.sh_name).unwrap_or("???") == ".eh_frame" {
                 cpu.write_register(10, sh.sh_addr.try_into().unwrap());
             }
 
-            if sh.sh_type & goblin::elf::section_header::SHT_NOBITS != 0 {
-                for addr in sh.sh_addr..(sh.sh_addr + sh.sh_size) {
+            if sh.sh_type == goblin::elf::section_header::SHT_NOBITS {
+                let (start, end) = (sh.sh_addr as u32, (sh.sh_addr + sh.sh_size) as u32);
+                for page in (start & !0xfff..end).step_by(4096) {
                     self.memory
-                        .ensure_page(addr.try_into().unwrap())
-                        .expect("out of memory");
+                        .ensure_page(page.max(start))
+                        .ok_or(LoadError::OutOfMemory(page.max(start)))?;
                 }
+                // `.bss` is defined to start out zeroed
+                self.memory
+                    .mark_initialized(sh.sh_addr as u32, sh.sh_size as u32);
             } else {
-                self.memory.write_bytes(
-                    &program[sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize],
-                    sh.sh_addr.try_into().unwrap(),
-                );
+                self.memory
+                    .write_bytes(
+                        &program[sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize],
+                        sh.sh_addr.try_into().unwrap(),
+                    )
+                    .map_err(LoadError::OutOfMemory)?;
             }
         }
+        if let Some(policy) = &self.memory.w_xor_x {
+            policy.protect(&self.memory, &self.program_info.sections);
+        }
+        if let Some(policy) = &self.memory.read_only {
+            policy.protect(&self.memory, &self.program_info.sections);
+        }
 
-        let satp = self.memory.satp;
+        let satp = self.memory.space.satp;
+
+        let stack_top = STACK_END
+            - self
+                .memory
+                .layout
+                .as_ref()
+                .map_or(0, |rng| rng.below(STACK_RANDOM_BYTES / 16) * 16);
 
         // Create the argument block and shove it at the top of stack.
-        let param_block = Self::create_params().expect("failed to create argument block");
-        let param_block_start = STACK_END - param_block.len() as u32;
-        self.memory.write_bytes(&param_block, param_block_start);
+        let param_block = Self::create_params(&self.args)?;
+        let param_block_start = stack_top - param_block.len() as u32;
+        self.memory
+            .write_bytes(&param_block, param_block_start)
+            .map_err(LoadError::OutOfMemory)?;
         // Place the argument block into $a1
         cpu.write_register(11, param_block_start as i32);
 
+        self.memory
+            .map_page_table(PAGE_TABLE_ROOT_OFFSET, self.memory.space.l1_pt)
+            .ok_or(LoadError::OutOfMemory(PAGE_TABLE_ROOT_OFFSET))?;
+        self.memory
+            .map_exit_trampoline()
+            .map_err(LoadError::OutOfMemory)?;
+
         // Ensure stack is allocated
-        for page in (STACK_START..STACK_END).step_by(4096) {
-            self.memory.ensure_page(page).expect("out of memory");
+        if self.memory.demand_paging {
+            self.memory
+                .reserve_region(STACK_START, STACK_END - STACK_START)
+                .ok_or(LoadError::OutOfMemory(STACK_START))?;
+        } else {
+            for page in (STACK_START..STACK_END).step_by(4096) {
+                self.memory
+                    .ensure_page(page)
+                    .ok_or(LoadError::OutOfMemory(page))?;
+            }
         }
 
         cpu.write_csr(riscv_cpu::cpu::CSR_SATP_ADDRESS, satp)
@@ -919,68 +4020,890 @@ impl Machine {
         // SRET to return to user mode
         cpu.execute_opcode(0x10200073).map_err(LoadError::CpuTrap)?;
 
-        // Update the stack pointer
-        cpu.write_register(2, (STACK_END as i32 - 16 - param_block.len() as i32) & !0xf);
+        // Update the stack pointer, and return to the exit trampoline
+        cpu.write_register(2, (stack_top as i32 - 16 - param_block.len() as i32) & !0xf);
+        cpu.write_register(1, EXIT_TRAMPOLINE as i32);
 
+        self.program_info.load_us = self.memory.clock.host_us().saturating_sub(start_us);
         let memory = self.memory.clone();
-        std::thread::spawn(move || {
-            std::process::exit(Worker::new(cpu, 0, memory).run() as i32);
-        });
+        self.workers.push(Worker::new(cpu, 0, memory, None));
+
+        Ok(())
+    }
+
+    fn create_worker(
+        &self,
+        tid: i32,
+        entry_point: u32,
+        stack_pointer: u32,
+        stack_length: u32,
+        arguments: [u32; 4],
+        join: Sender<ResponseData>,
+    ) -> Result<Worker, LoadError> {
+        let mut cpu_memory = self.memory.clone();
+        cpu_memory.tid = tid;
+        let thread_instructions = Arc::new(AtomicU64::new(0));
+        cpu_memory.thread_instructions = thread_instructions.clone();
+        let stack = stack_pointer..stack_pointer.wrapping_add(stack_length);
+        let thread_lowest_sp = Arc::new(AtomicU32::new(stack.end));
+        cpu_memory.thread_lowest_sp = thread_lowest_sp.clone();
+        self.memory.threads.lock().unwrap().insert(
+            tid,
+            ThreadAccount {
+                instructions: thread_instructions.clone(),
+                exited: false,
+                stack,
+                lowest_sp: thread_lowest_sp.clone(),
+            },
+        );
+        let mut cpu = riscv_cpu::CpuBuilder::new(cpu_memory).build();
+        cpu.get_mut_mmu().check_physical_addresses(true);
+        cpu.switch_hart(tid as u32);
+
+        cpu.write_csr(riscv_cpu::cpu::CSR_SATP_ADDRESS, self.memory.space.satp)
+            .map_err(|_| LoadError::SatpWriteError)?;
+        cpu.update_pc(entry_point);
+
+        // Return to User Mode (0 << 11) with interrupts disabled (1 << 5)
+        cpu.write_csr(riscv_cpu::cpu::CSR_MSTATUS_ADDRESS, 1 << 5)
+            .map_err(|_| LoadError::MstatusWriteError)?;
+
+        cpu.write_csr(riscv_cpu::cpu::CSR_SEPC_ADDRESS, entry_point)
+            .unwrap();
+
+        // SRET to return to user mode
+        cpu.execute_opcode(0x10200073).map_err(LoadError::CpuTrap)?;
+
+        // Update the stack pointer, and return to the exit trampoline
+        cpu.write_register(2, (stack_pointer + stack_length) as i32 - 16);
+        cpu.write_register(1, EXIT_TRAMPOLINE as i32);
+        for (index, argument) in arguments.iter().enumerate() {
+            cpu.write_register(10 + index as u8, *argument as i32);
+        }
+
+        // let cmd = self.memory_cmd_sender.clone();
+        let mut memory = self.memory.clone();
+        memory.thread_instructions = thread_instructions;
+        memory.thread_lowest_sp = thread_lowest_sp;
+        Ok(Worker::new(cpu, tid, memory, Some(join)))
+    }
+
+    fn handle_command(&self, msg: MemoryCommand) -> Result<Worker, LoadError> {
+        match msg {
+            MemoryCommand::CreateThread(
+                tid,
+                entry_point,
+                stack_pointer,
+                stack_length,
+                argument_1,
+                argument_2,
+                argument_3,
+                argument_4,
+                join,
+            ) => self.create_worker(
+                tid,
+                entry_point,
+                stack_pointer,
+                stack_length,
+                [argument_1, argument_2, argument_3, argument_4],
+                join,
+            ),
+        }
+    }
+
+    /// Run the program with one host thread per guest thread, or on the harts
+    /// set with `MachineBuilder::harts`, returning the exit code once the main
+    /// guest thread exits or any thread terminates the process, or the error
+    /// that stopped it.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn run(&mut self) -> Result<u32, YoveError> {
+        use std::sync::mpsc::RecvTimeoutError;
+
+        let (exit_tx, exit_rx) = std::sync::mpsc::channel();
+        let harts = self
+            .harts
+            .map(|count| harts::Harts::start(count, &self.memory.pause, &exit_tx));
+        for mut worker in self.workers.drain(..) {
+            if let Some(harts) = &harts {
+                harts.push(worker);
+                continue;
+            }
+            let exit_tx = exit_tx.clone();
+            spawn_guest_thread(worker.tid, move || match worker.run_contained() {
+                WorkerEvent::Exited(val) | WorkerEvent::Terminated(val) => {
+                    exit_tx.send(Ok(val)).ok()
+                }
+                WorkerEvent::Failed(error) => exit_tx.send(Err(error)).ok(),
+                _ => unreachable!(),
+            });
+        }
+
+        loop {
+            if let Ok(result) = exit_rx.try_recv() {
+                self.exit_code = Some(*result.as_ref().unwrap_or(&!0));
+                return result;
+            }
+            match self.memory_cmd.recv_timeout(SERVICE_TICK_INTERVAL) {
+                Ok(msg) => {
+                    let mut worker = self.handle_command(msg)?;
+                    if let Some(harts) = &harts {
+                        harts.push(worker);
+                    } else {
+                        let exit_tx = exit_tx.clone();
+                        spawn_guest_thread(worker.tid, move || match worker.run_contained() {
+                            WorkerEvent::Terminated(val) => exit_tx.send(Ok(val)).ok(),
+                            WorkerEvent::Failed(error) => exit_tx.send(Err(error)).ok(),
+                            _ => None,
+                        });
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    unreachable!("the machine holds its own memory_cmd sender")
+                }
+            }
+            self.memory.tick_services();
+        }
+    }
+
+    /// The profiler enabled with `MachineBuilder::profile`, if any.
+    pub fn profiler(&self) -> Option<&profiler::Profiler> {
+        self.memory.profiler.as_deref()
+    }
+
+    /// The guest's clock, which can be frozen, scaled, or advanced while the
+    /// machine runs.
+    pub fn clock(&self) -> &clock::VirtualClock {
+        &self.memory.clock
+    }
+
+    /// Stop every guest thread at an instruction boundary, returning once
+    /// they have all stopped, and freeze the guest's clock. The machine stays
+    /// paused until `resume()` is called. While `run()` is blocking, use
+    /// `pause_control()` to pause the machine from another thread.
+    pub fn pause(&self) {
+        self.memory.pause.pause();
+    }
+
+    /// Let the guest threads run again after `pause()`.
+    pub fn resume(&self) {
+        self.memory.pause.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.memory.pause.is_paused()
+    }
+
+    /// A handle that pauses and resumes the machine, which can be sent to
+    /// another thread before calling `run()`.
+    pub fn pause_control(&self) -> Arc<pause::PauseControl> {
+        self.memory.pause.clone()
+    }
+
+    /// The control flow graph enabled with `MachineBuilder::control_flow_graph`, if any.
+    pub fn control_flow_graph(&self) -> Option<&cfg::ControlFlowGraph> {
+        self.memory.cfg.as_deref()
+    }
+
+    /// The branch and prefetch counts enabled with `MachineBuilder::branch_stats`, if any.
+    pub fn branch_stats(&self) -> Option<&branch_stats::BranchStats> {
+        self.memory.branch_stats.as_deref()
+    }
+
+    /// What the display enabled with `MachineBuilder::framebuffer` shows now,
+    /// or `None` if it isn't enabled or the guest hasn't mapped it.
+    pub fn screenshot(&self) -> Option<framebuffer::Screenshot> {
+        self.memory.framebuffer.as_ref()?.capture(&self.memory)
+    }
+
+    /// The screenshots taken every `MachineBuilder::screenshot_interval`, with
+    /// the time in milliseconds that each was taken.
+    pub fn screenshots(&self) -> Vec<(u64, framebuffer::Screenshot)> {
+        self.memory
+            .framebuffer
+            .as_ref()
+            .map_or_else(Vec::new, |framebuffer| framebuffer.captures())
+    }
+
+    /// The first few accesses the guest made to physical addresses outside of RAM.
+    pub fn invalid_accesses(&self) -> Vec<InvalidAccess> {
+        self.memory.invalid_accesses.lock().unwrap().clone()
+    }
+
+    /// The guest threads that were stopped because the emulator panicked
+    /// while running them.
+    pub fn thread_faults(&self) -> Vec<ThreadFault> {
+        self.memory.thread_faults.lock().unwrap().clone()
+    }
+
+    /// How many times the guest has suspended through the suspend/resume
+    /// manager.
+    pub fn suspends(&self) -> u32 {
+        self.memory.suspends.load(Ordering::Relaxed)
+    }
+
+    /// Where the machine has got to, to be restored with
+    /// `Snapshot::restore`, or `None` if it was built with something that
+    /// can't be built twice, like a machine that can't seek backwards.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn snapshot(&self) -> Option<snapshot::Snapshot> {
+        let replay = self.replay.as_ref()?;
+        Some(snapshot::Snapshot {
+            instructions: self.instructions_retired(),
+            seed: self.seed,
+            time_us: self.memory.clock.now_us(),
+            timeouts: self.memory.service_timeouts(),
+            program: replay.program.clone(),
+        })
+    }
+
+    /// The first reads of uninitialized memory found with
+    /// `MachineBuilder::detect_uninitialized_reads`.
+    pub fn uninitialized_reads(&self) -> Vec<uninit::UninitializedRead> {
+        self.memory
+            .uninit
+            .as_ref()
+            .map_or_else(Vec::new, |uninit| uninit.reads())
+    }
+
+    /// Every page and megapage mapped into the guest's address space, in
+    /// order of virtual address.
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.memory.memory_map()
+    }
+
+    /// Where the guest's heap, stack, `MapMemory` regions, and lent memory
+    /// go, including any the program moved with `SetMemRegion`. Lent memory
+    /// is only listed once the program has said where it goes.
+    pub fn memory_regions(&self) -> Vec<MemoryRegion> {
+        let space = &self.memory.space;
+        let heap_start = space.heap_start.load(Ordering::Relaxed);
+        let allocation = space.allocation.lock().unwrap().clone();
+        let stack = space.stack.lock().unwrap().clone();
+        let messages = space.messages.lock().unwrap().clone();
+        let regions = [
+            (MemoryType::Default, allocation),
+            (MemoryType::Messages, messages),
+            (MemoryType::Stack, stack),
+            (
+                MemoryType::Heap,
+                heap_start..space.heap_end.load(Ordering::Relaxed),
+            ),
+        ];
+        regions
+            .into_iter()
+            .filter(|(_, range)| !range.is_empty())
+            .map(|(kind, range)| MemoryRegion {
+                kind,
+                start: range.start,
+                size: range.end - range.start,
+            })
+            .collect()
+    }
+
+    /// Write the guest's page tables to `output` in `format`.
+    pub fn dump_mmu(
+        &self,
+        output: &mut impl std::io::Write,
+        format: MmuFormat,
+    ) -> std::io::Result<()> {
+        self.memory.dump_mmu(output, format)
+    }
+
+    /// Every name the program registered with the name server or connected
+    /// to through it, in order, for working out why a lookup failed.
+    pub fn names(&self) -> Vec<NameInfo> {
+        services::name::list(&self.memory)
+    }
+
+    /// How many bytes of the guest's RAM have been given host memory, which
+    /// happens the first time each page is written.
+    pub fn resident_memory(&self) -> usize {
+        self.memory.data.resident_pages() * 4096
+    }
+
+    /// The entry point, flags, and sections of the loaded program.
+    pub fn program_info(&self) -> &program::ProgramInfo {
+        &self.program_info
+    }
+
+    /// The message statistics enabled with `MachineBuilder::message_stats`, if any.
+    pub fn message_stats(&self) -> Option<&message_stats::MessageStats> {
+        self.memory.message_stats.as_deref()
+    }
+
+    /// The commit log enabled with `MachineBuilder::log_commits`, if any.
+    pub fn commit_log(&self) -> Option<&commit_log::CommitLog> {
+        self.memory.commit_log.as_deref()
+    }
 
-        self.satp = satp;
+    /// The memory dumped by the breakpoint script enabled with
+    /// `MachineBuilder::breakpoint_script`, in the order it was dumped.
+    pub fn breakpoint_dumps(&self) -> Vec<breakpoint_script::MemoryDump> {
+        self.memory
+            .breakpoint_script
+            .as_ref()
+            .map_or_else(Vec::new, |script| script.dumps())
+    }
+
+    /// The tracer enabled with `MachineBuilder::trace`, if any.
+    pub fn tracer(&self) -> Option<&trace::Tracer> {
+        self.memory.tracer.as_deref()
+    }
+
+    /// Deliver the events `subscription` asks for to `listener` from now
+    /// on, as services can. Subscribing the same listener again replaces
+    /// what it asked for before.
+    pub fn subscribe_perf_events(
+        &self,
+        subscription: perf_events::PerfSubscription,
+        listener: Arc<dyn perf_events::PerfListener>,
+    ) {
+        self.memory.perf_events.subscribe(subscription, listener);
+    }
+
+    /// The number of instructions retired by every guest thread that has exited,
+    /// plus those of threads still being driven by `step()`.
+    pub fn instructions_retired(&self) -> u64 {
+        self.memory.instructions_retired.load(Ordering::Relaxed)
+            + self
+                .workers
+                .iter()
+                .map(|worker| worker.cpu.instructions_retired())
+                .sum::<u64>()
+    }
+
+    /// How many instructions each guest thread that has been started has
+    /// retired, in order of thread ID. Unlike `instructions_retired()`, this
+    /// includes threads still running on their own host threads.
+    pub fn thread_stats(&self) -> Vec<ThreadStats> {
+        self.memory.thread_stats()
+    }
+
+    /// The host's end of the shared-memory ring with the given ID. The guest
+    /// attaches memory to it through the `yove-ring-buffer` service, so this
+    /// may be called before the program starts.
+    pub fn ring_buffer(&self, id: u32) -> Arc<RingBuffer> {
+        self.memory.ring_buffer(id)
+    }
+
+    /// A handle for posting notifications to the guest and raising its
+    /// interrupt lines, which can be sent to another thread before calling
+    /// `run()`.
+    pub fn notifier(&self) -> Arc<notify::Notifier> {
+        self.memory.notifier.clone()
+    }
+
+    /// The embedded controller behind the COM server, whose status can be
+    /// changed from another thread while the machine runs.
+    pub fn ec(&self) -> Arc<ec::Ec> {
+        self.memory.ec.clone()
+    }
+
+    /// The flash behind the `yove-flash` server, which can be inspected once the
+    /// machine stops or passed to another machine with `MachineBuilder::flash`.
+    pub fn flash(&self) -> Arc<flash::Flash> {
+        self.memory.flash.clone()
+    }
+
+    /// The keyboard, on which the host can press keys from another thread
+    /// while the machine runs.
+    pub fn keyboard(&self) -> Arc<keyboard::Keyboard> {
+        self.memory.keyboard.clone()
+    }
+
+    /// The USB port, through which the host can see what the program typed
+    /// and talk to it over serial while the machine runs.
+    pub fn usb(&self) -> Arc<usb::UsbDevice> {
+        self.memory.usb.clone()
+    }
+
+    /// The seed everything random about the run was derived from, which
+    /// repeats the run when passed to `MachineBuilder::seed`.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The host commands the program has asked to run, if it was allowed to
+    /// run any with `MachineBuilder::allow_host_exec`.
+    pub fn host_exec(&self) -> Option<&host_exec::HostExec> {
+        self.memory.host_exec.as_deref()
+    }
+
+    /// The codec, through which the host can see whether the program is
+    /// playing audio and how much it has played.
+    pub fn audio(&self) -> Arc<audio::Audio> {
+        self.memory.audio.clone()
+    }
+
+    /// Write the page access counts enabled with `MachineBuilder::heatmap`.
+    /// Does nothing if the heatmap wasn't enabled.
+    pub fn write_heatmap(
+        &self,
+        format: heatmap::HeatmapFormat,
+        output: &mut impl std::io::Write,
+    ) -> Result<(), YoveError> {
+        if let Some(heatmap) = &self.memory.heatmap {
+            heatmap.write(&self.memory.mappings(), format, output)?;
+        }
+        Ok(())
+    }
+
+    /// Advance every guest thread by up to `STEP_QUANTUM` instructions on the
+    /// calling thread. This never blocks, which allows the machine to be driven
+    /// from an event loop without dedicating a host thread to each guest thread.
+    /// Once an error has been returned, the machine reports that it exited with `!0`.
+    /// While the machine is paused, nothing runs and this returns `Idle`.
+    pub fn step(&mut self) -> Result<MachineEvent, YoveError> {
+        self.step_within(u64::MAX)
+    }
+
+    /// `step()`, except that once `budget` instructions have run across all
+    /// threads, the thread that ran the last of them stops.
+    fn step_within(&mut self, mut budget: u64) -> Result<MachineEvent, YoveError> {
+        if let Some(exit_code) = self.exit_code {
+            return Ok(MachineEvent::Exited(exit_code));
+        }
+        if self.is_paused() {
+            return Ok(MachineEvent::Idle);
+        }
+
+        self.accept_threads()?;
+        self.memory.tick_services();
+
+        let mut progress = false;
+        let mut index = 0;
+        while index < self.workers.len() {
+            let mut exited = false;
+            for _ in 0..STEP_QUANTUM {
+                let worker = &mut self.workers[index];
+                let pc = worker.cpu.read_pc();
+                if !worker.stopped && self.breakpoints.contains(&pc) {
+                    worker.stopped = true;
+                    return Ok(MachineEvent::Stopped {
+                        tid: worker.tid,
+                        pc,
+                    });
+                }
+                match worker.step() {
+                    WorkerEvent::Ran => {
+                        progress = true;
+                        worker.stopped = false;
+                        budget -= 1;
+                        if worker.call_depth == Some(-1) || budget == 0 {
+                            worker.call_depth = None;
+                            worker.stopped = true;
+                            return Ok(MachineEvent::Stopped {
+                                tid: worker.tid,
+                                pc: worker.cpu.read_pc(),
+                            });
+                        }
+                    }
+                    WorkerEvent::Blocked => break,
+                    event => {
+                        if let Some(result) = self.settle(index, event) {
+                            return result;
+                        }
+                        progress = true;
+                        exited = true;
+                        break;
+                    }
+                }
+            }
+            if !exited {
+                index += 1;
+            }
+        }
+
+        if progress {
+            Ok(MachineEvent::Running)
+        } else {
+            Ok(MachineEvent::Idle)
+        }
+    }
 
+    /// Start the workers for threads the guest has created since this was
+    /// last called.
+    fn accept_threads(&mut self) -> Result<(), YoveError> {
+        while let Ok(msg) = self.memory_cmd.try_recv() {
+            let worker = self.handle_command(msg).inspect_err(|_| {
+                self.exit_code = Some(!0);
+            })?;
+            self.workers.push(worker);
+        }
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        while let Ok(msg) = self.memory_cmd.recv() {
-            match msg {
-                MemoryCommand::CreateThread(
-                    entry_point,
-                    stack_pointer,
-                    stack_length,
-                    argument_1,
-                    argument_2,
-                    argument_3,
-                    argument_4,
-                    tx,
-                ) => {
-                    let mut cpu = riscv_cpu::CpuBuilder::new(self.memory.clone()).build();
-                    let tid = self.thread_id_counter.fetch_add(1, Ordering::SeqCst);
-                    cpu.write_csr(riscv_cpu::cpu::CSR_MHARTID_ADDRESS, tid as u32)
-                        .unwrap();
+    /// Remove the worker at `index`, whose thread has stopped for good with
+    /// `event`. Returns what `step()` should return if that ends the process.
+    fn settle(
+        &mut self,
+        index: usize,
+        event: WorkerEvent,
+    ) -> Option<Result<MachineEvent, YoveError>> {
+        let worker = self.workers.remove(index);
+        match event {
+            WorkerEvent::Exited(val) if worker.tid == 0 => {
+                self.exit_code = Some(val);
+                Some(Ok(MachineEvent::Exited(val)))
+            }
+            WorkerEvent::Exited(_) => None,
+            WorkerEvent::Terminated(val) => {
+                self.exit_code = Some(val);
+                Some(Ok(MachineEvent::Exited(val)))
+            }
+            WorkerEvent::Failed(error) => {
+                self.exit_code = Some(!0);
+                Some(Err(error))
+            }
+            WorkerEvent::Ran | WorkerEvent::Blocked => {
+                unreachable!("the thread is still running")
+            }
+        }
+    }
+
+    fn worker_index(&self, tid: i32) -> Result<usize, YoveError> {
+        self.workers
+            .iter()
+            .position(|worker| worker.tid == tid)
+            .ok_or(YoveError::UnknownThread { tid })
+    }
+
+    /// Overwrite the program's code or data at `address` with `bytes`, even
+    /// where it's read-only. The CPUs don't keep decoded instructions, so
+    /// every thread runs the new code the next time it gets there, as if it
+    /// had run `FENCE.I`. Nothing is written if any of the range isn't
+    /// mapped.
+    pub fn patch(&mut self, address: u32, bytes: &[u8]) -> Result<(), YoveError> {
+        self.memory
+            .patch(address, bytes)
+            .map_err(|address| YoveError::Unmapped { address })
+    }
+
+    /// Read `len` bytes of the program's memory from `address`, whatever the
+    /// permissions of the pages they're in.
+    pub fn read_memory(&self, address: u32, len: u32) -> Result<Vec<u8>, YoveError> {
+        self.memory
+            .peek(address, len)
+            .map_err(|address| YoveError::Unmapped { address })
+    }
+
+    /// The address of the function or object `name` in the program's symbol table.
+    pub fn symbol_address(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.address)
+    }
+
+    /// Patch the start of the function `name` with `bytes`, such as a jump to
+    /// a trampoline, and return its address. The patch can't be longer than
+    /// the function, if the symbol table gives its size.
+    pub fn patch_symbol(&mut self, name: &str, bytes: &[u8]) -> Result<u32, YoveError> {
+        let symbol = self
+            .symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .ok_or_else(|| LoadError::UnknownSymbol(name.to_owned()))?;
+        if symbol.size != 0 && bytes.len() > symbol.size as usize {
+            return Err(YoveError::PatchTooLarge {
+                symbol: name.to_owned(),
+                size: symbol.size,
+                patch: bytes.len(),
+            });
+        }
+        let address = symbol.address;
+        self.patch(address, bytes)?;
+        Ok(address)
+    }
+
+    /// Make the function `name` return `value` as soon as it's called,
+    /// skipping the rest of it, such as a routine that waits on hardware the
+    /// emulator doesn't have. Returns the function's address.
+    pub fn stub_symbol(&mut self, name: &str, value: i32) -> Result<u32, YoveError> {
+        // lui a0, %hi(value); addi a0, a0, %lo(value); ret
+        let low = (value << 20) >> 20;
+        let high = value.wrapping_sub(low) as u32;
+        let stub = [high | 0x537, (low as u32) << 20 | 0x5_0513, 0x0000_8067];
+        let bytes: Vec<u8> = stub.iter().flat_map(|word| word.to_le_bytes()).collect();
+        self.patch_symbol(name, &bytes)
+    }
+
+    /// Stop any thread driven by `step()` before it runs the instruction at
+    /// `address`. Threads started by `run()` don't stop at breakpoints.
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Like `add_breakpoint()`, at `location`, such as `main` or
+    /// `decrypt+0x10`. A breakpoint on a symbol that isn't in the program is
+    /// kept until a program that has it is loaded. Returns the address, if
+    /// it's known yet.
+    pub fn add_breakpoint_at(&mut self, location: breakpoint_script::Location) -> Option<u32> {
+        let address = location.resolve(&self.symbols);
+        match address {
+            Some(address) => {
+                self.breakpoints.insert(address);
+            }
+            None => self.pending_breakpoints.push(location),
+        }
+        address
+    }
+
+    /// Remove a breakpoint, returning whether there was one at `address`.
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// The breakpoints on symbols that no program loaded so far has.
+    pub fn pending_breakpoints(&self) -> &[breakpoint_script::Location] {
+        &self.pending_breakpoints
+    }
+
+    /// Run exactly one instruction of thread `tid`, even if there's a
+    /// breakpoint on it, and leave every other thread where it is. Returns
+    /// `Stopped` at the next instruction, or `Idle` without running anything
+    /// if the thread is waiting on a service or on another thread.
+    pub fn step_thread(&mut self, tid: i32) -> Result<MachineEvent, YoveError> {
+        if let Some(exit_code) = self.exit_code {
+            return Ok(MachineEvent::Exited(exit_code));
+        }
+        self.accept_threads()?;
+        self.memory.tick_services();
+
+        let index = self.worker_index(tid)?;
+        let worker = &mut self.workers[index];
+        let result = match worker.step() {
+            WorkerEvent::Ran => {
+                worker.stopped = true;
+                Ok(MachineEvent::Stopped {
+                    tid,
+                    pc: worker.cpu.read_pc(),
+                })
+            }
+            WorkerEvent::Blocked => Ok(MachineEvent::Idle),
+            event => match self.settle(index, event) {
+                Some(result) => result,
+                None => Ok(MachineEvent::Running),
+            },
+        };
+        // A thread created by this instruction can be stepped straight away
+        self.accept_threads()?;
+        result
+    }
 
-                    cpu.write_csr(riscv_cpu::cpu::CSR_SATP_ADDRESS, self.satp)
-                        .map_err(|_| LoadError::SatpWriteError)?;
-                    cpu.update_pc(entry_point);
+    /// The registers, CSRs, and PC of thread `tid`, which must be one driven
+    /// by `step()`. Comparing this with the state after `step_thread` shows
+    /// what an instruction did.
+    pub fn thread_state(&self, tid: i32) -> Result<CpuState, YoveError> {
+        Ok(self.workers[self.worker_index(tid)?].cpu.capture_state())
+    }
 
-                    // Return to User Mode (0 << 11) with interrupts disabled (1 << 5)
-                    cpu.write_csr(riscv_cpu::cpu::CSR_MSTATUS_ADDRESS, 1 << 5)
-                        .map_err(|_| LoadError::MstatusWriteError)?;
+    /// Run thread `tid` until it's past the instruction it's on, letting
+    /// every other thread run meanwhile. A call is stepped over by running
+    /// until it returns to the instruction after it, and anything else is
+    /// stepped once with `step_thread`. Returns `Stopped` at the next
+    /// instruction, or wherever a breakpoint stopped a thread first.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn step_over(&mut self, tid: i32) -> Result<MachineEvent, YoveError> {
+        let index = self.worker_index(tid)?;
+        let worker = &mut self.workers[index];
+        let pc = worker.cpu.read_pc();
+        let stack_pointer = worker.cpu.read_register(2) as u32;
+        let word = match worker.cpu.get_mut_mmu().fetch_instruction(pc) {
+            Ok(word) if shadow_stack::is_call(word) => word,
+            _ => return self.step_thread(tid),
+        };
+        let length = if word & 0x3 == 0x3 { 4 } else { 2 };
+        let return_address = pc.wrapping_add(length);
 
-                    cpu.write_csr(riscv_cpu::cpu::CSR_SEPC_ADDRESS, entry_point)
-                        .unwrap();
+        let temporary = self.breakpoints.insert(return_address);
+        let result = match self.step_thread(tid) {
+            Ok(MachineEvent::Stopped { .. }) => self.run_until_stopped(|machine, stopped, pc| {
+                // A recursive call passes through the same return address
+                // deeper in the stack, which doesn't count
+                let returned = stopped == tid
+                    && machine.worker_index(tid).is_ok_and(|index| {
+                        machine.workers[index].cpu.read_register(2) as u32 >= stack_pointer
+                    });
+                returned || !temporary || pc != return_address
+            }),
+            result => result,
+        };
+        if temporary {
+            self.breakpoints.remove(&return_address);
+        }
+        result
+    }
 
-                    // SRET to return to user mode
-                    cpu.execute_opcode(0x10200073).map_err(LoadError::CpuTrap)?;
+    /// Run until thread `tid` returns from the function it's in, letting
+    /// every other thread run meanwhile. Calls and returns are followed the
+    /// same way the shadow stack follows them, so tail calls and recursion
+    /// are handled. Returns `Stopped` at the instruction returned to, or
+    /// wherever a breakpoint stopped a thread first.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn step_out(&mut self, tid: i32) -> Result<MachineEvent, YoveError> {
+        let index = self.worker_index(tid)?;
+        self.workers[index].call_depth = Some(0);
+        let result = self.run_until_stopped(|_, _, _| true);
+        if let Ok(index) = self.worker_index(tid) {
+            self.workers[index].call_depth = None;
+        }
+        result
+    }
 
-                    // Update the stack pointer
-                    cpu.write_register(2, (stack_pointer + stack_length) as i32 - 16);
-                    cpu.write_register(10, argument_1 as i32);
-                    cpu.write_register(11, argument_2 as i32);
-                    cpu.write_register(12, argument_3 as i32);
-                    cpu.write_register(13, argument_4 as i32);
+    /// Step the machine until a thread stops somewhere `done` accepts, or
+    /// the process exits.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run_until_stopped(
+        &mut self,
+        done: impl Fn(&Self, i32, u32) -> bool,
+    ) -> Result<MachineEvent, YoveError> {
+        loop {
+            match self.step()? {
+                MachineEvent::Running => {}
+                MachineEvent::Idle => std::thread::sleep(IDLE_POLL_INTERVAL),
+                MachineEvent::Stopped { tid, pc } if !done(self, tid, pc) => {}
+                event => return Ok(event),
+            }
+        }
+    }
 
-                    // let cmd = self.memory_cmd_sender.clone();
-                    let memory = self.memory.clone();
-                    let join_handle =
-                        std::thread::spawn(move || Worker::new(cpu, tid, memory).run());
-                    tx.send((tid, join_handle)).unwrap();
+    /// Run the machine as `step()` does until exactly `instructions` have
+    /// been retired in all, as counted by `instructions_retired()`, and
+    /// return `Stopped` at the thread that retired the last of them. Stops
+    /// sooner at a breakpoint, or if the process exits, and returns `Idle`
+    /// if every thread is blocked short of the count, or if the machine is
+    /// already there.
+    ///
+    /// `step()` runs the threads in the same order every time, so with
+    /// `CounterPolicy::Deterministic` and no input from the host, two
+    /// machines seeking to the same count end up in the same state. A
+    /// machine keeps no history, so to go back it's built again from the
+    /// start, with the same builder settings, seed, and breakpoints, and
+    /// sought forward. No checkpoints are kept along the way, so every step
+    /// back costs as much as running from the first instruction to
+    /// `instructions` again, which adds up when stepping back repeatedly
+    /// through a long run. Anything else changed since it was built is lost,
+    /// and handles taken from it, such as `keyboard()`, belong to the old
+    /// machine. A machine built with a platform, flash, or any host
+    /// stream can't be built again, and seeking it backwards fails
+    /// with `YoveError::SeekBackwards`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn seek(&mut self, instructions: u64) -> Result<MachineEvent, YoveError> {
+        if instructions < self.instructions_retired() {
+            self.rewind(instructions)?;
+        }
+        loop {
+            let remaining = instructions - self.instructions_retired();
+            if remaining == 0 {
+                return Ok(MachineEvent::Idle);
+            }
+            // Instructions that run without retiring, such as `wfi`, count
+            // against the budget too, so it can run out short of the count
+            match self.step_within(remaining)? {
+                MachineEvent::Running => {}
+                MachineEvent::Stopped { tid, pc }
+                    if self.instructions_retired() == instructions
+                        || self.breakpoints.contains(&pc) =>
+                {
+                    return Ok(MachineEvent::Stopped { tid, pc })
                 }
+                MachineEvent::Stopped { .. } => {}
+                event => return Ok(event),
             }
         }
-        println!("Done! memory_cmd returned error");
+    }
 
+    /// Replace the machine with a fresh one built the same way, keeping its
+    /// breakpoints, so that it can be sought to `instructions`.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn rewind(&mut self, instructions: u64) -> Result<(), YoveError> {
+        let Some(replay) = &self.replay else {
+            return Err(YoveError::SeekBackwards {
+                retired: self.instructions_retired(),
+                instructions,
+            });
+        };
+        let builder = replay
+            .builder
+            .replica()
+            .expect("a replica builds a replica");
+        let mut fresh = builder.build(&replay.program)?;
+        fresh.breakpoints = std::mem::take(&mut self.breakpoints);
+        fresh.pending_breakpoints = std::mem::take(&mut self.pending_breakpoints);
+        *self = fresh;
         Ok(())
     }
+
+    /// Return a future that drives the machine with `step()` until `event` occurs.
+    /// The future yields back to the executor after every step, so it can share
+    /// a single-threaded async runtime with other tasks.
+    pub fn run_until(&mut self, event: Event) -> RunUntil<'_> {
+        RunUntil {
+            machine: self,
+            event,
+        }
+    }
+
+    /// Drive the machine on a tokio runtime until the main thread exits, sleeping
+    /// rather than spinning while every guest thread is blocked.
+    #[cfg(feature = "tokio")]
+    pub async fn run_tokio(&mut self) -> Result<u32, YoveError> {
+        loop {
+            match self.step()? {
+                // Breakpoints are only for callers that step the machine themselves
+                MachineEvent::Running | MachineEvent::Stopped { .. } => {
+                    tokio::task::yield_now().await
+                }
+                MachineEvent::Idle => tokio::time::sleep(IDLE_POLL_INTERVAL).await,
+                MachineEvent::Exited(val) => return Ok(val),
+            }
+        }
+    }
+}
+
+/// Run `f` on a host thread named after guest thread `tid`, so that panic
+/// messages say which guest thread was running.
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_guest_thread(tid: i32, f: impl FnOnce() -> Option<()> + Send + 'static) {
+    std::thread::Builder::new()
+        .name(format!("guest thread {}", tid))
+        .spawn(f)
+        .expect("couldn't start a host thread");
+}
+
+/// Future returned by `Machine::run_until`.
+pub struct RunUntil<'a> {
+    machine: &'a mut Machine,
+    event: Event,
+}
+
+impl std::future::Future for RunUntil<'_> {
+    type Output = Result<MachineEvent, YoveError>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let event = self.event;
+        match self.machine.step() {
+            Err(error) => std::task::Poll::Ready(Err(error)),
+            Ok(MachineEvent::Exited(val)) => std::task::Poll::Ready(Ok(MachineEvent::Exited(val))),
+            Ok(MachineEvent::Stopped { tid, pc }) => {
+                std::task::Poll::Ready(Ok(MachineEvent::Stopped { tid, pc }))
+            }
+            Ok(MachineEvent::Idle) if event == Event::Idle => {
+                std::task::Poll::Ready(Ok(MachineEvent::Idle))
+            }
+            _ => {
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }
+    }
 }
@@ -0,0 +1,146 @@
+//! What to do when the program misuses the kernel interface in a way the
+//! real kernel would refuse, such as passing a misaligned size, lending
+//! memory it hasn't mapped, or sending to a connection it never made. Each
+//! kind of misuse has its own handler, so that a run can stop at the first
+//! bad lend while only warning about misaligned sizes.
+
+use std::fmt;
+
+/// A kind of misuse the emulator detects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Abuse {
+    /// A syscall argument that's misaligned, out of range, or not one the
+    /// call takes.
+    InvalidArgument,
+
+    /// A message whose buffer isn't all mapped, or isn't all writable for a
+    /// mutable lend.
+    UnmappedLend,
+
+    /// A message or disconnect on a connection ID that isn't connected.
+    BadConnection,
+
+    /// A syscall number the emulator doesn't know.
+    UnknownSyscall,
+
+    /// A message with an opcode the service it's sent to doesn't handle.
+    UnknownOpcode,
+
+    /// A message the service can't act on, such as registering a name
+    /// twice or unlocking a mutex that isn't locked.
+    InvalidMessage,
+}
+
+impl Abuse {
+    pub const ALL: [Abuse; 6] = [
+        Abuse::InvalidArgument,
+        Abuse::UnmappedLend,
+        Abuse::BadConnection,
+        Abuse::UnknownSyscall,
+        Abuse::UnknownOpcode,
+        Abuse::InvalidMessage,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Abuse::InvalidArgument => "invalid-argument",
+            Abuse::UnmappedLend => "unmapped-lend",
+            Abuse::BadConnection => "bad-connection",
+            Abuse::UnknownSyscall => "unknown-syscall",
+            Abuse::UnknownOpcode => "unknown-opcode",
+            Abuse::InvalidMessage => "invalid-message",
+        }
+    }
+}
+
+impl fmt::Display for Abuse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Abuse {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Abuse::ALL
+            .into_iter()
+            .find(|abuse| abuse.name() == name)
+            .ok_or_else(|| format!("unknown kind of misuse {:?}", name))
+    }
+}
+
+/// What the emulator does with a syscall that misuses the interface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AbuseHandler {
+    /// Log a warning and fail the call with the error the kernel gives.
+    Warn,
+
+    /// Raise the environment call exception at the `ecall`. Programs have
+    /// no exception handlers under the emulator, so this stops the machine
+    /// with `YoveError::Trap` at the call.
+    Trap,
+
+    /// Log a warning and end the calling thread, as if it had called
+    /// `ExitThread` with `!0`.
+    KillThread,
+
+    /// End the process, so that the machine stops with `YoveError::Abuse`,
+    /// or `YoveError::Syscall` for an unknown syscall.
+    KillProcess,
+}
+
+impl std::str::FromStr for AbuseHandler {
+    type Err = String;
+
+    fn from_str(handler: &str) -> Result<Self, Self::Err> {
+        match handler {
+            "warn" => Ok(AbuseHandler::Warn),
+            "trap" => Ok(AbuseHandler::Trap),
+            "kill-thread" => Ok(AbuseHandler::KillThread),
+            "kill-process" => Ok(AbuseHandler::KillProcess),
+            _ => Err(format!("unknown misuse handler {:?}", handler)),
+        }
+    }
+}
+
+/// The handler for each kind of misuse. By default, unknown syscalls end the
+/// process and everything else is a warning.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AbusePolicy {
+    handlers: [AbuseHandler; Abuse::ALL.len()],
+}
+
+impl Default for AbusePolicy {
+    fn default() -> Self {
+        AbusePolicy {
+            handlers: Abuse::ALL.map(|abuse| match abuse {
+                Abuse::UnknownSyscall => AbuseHandler::KillProcess,
+                _ => AbuseHandler::Warn,
+            }),
+        }
+    }
+}
+
+impl AbusePolicy {
+    pub fn handler(&self, abuse: Abuse) -> AbuseHandler {
+        self.handlers[abuse as usize]
+    }
+
+    pub fn set(&mut self, abuse: Abuse, handler: AbuseHandler) {
+        self.handlers[abuse as usize] = handler;
+    }
+}
+
+/// Parse a rule of the form `<kind>=<handler>`, where `<kind>` may also be
+/// `all`, into the kinds of misuse it covers and their handler.
+pub fn parse_rule(rule: &str) -> Result<(Vec<Abuse>, AbuseHandler), String> {
+    let Some((kind, handler)) = rule.split_once('=') else {
+        return Err(format!("expected <kind>=<handler>, not {:?}", rule));
+    };
+    let kinds = match kind {
+        "all" => Abuse::ALL.to_vec(),
+        kind => vec![kind.parse()?],
+    };
+    Ok((kinds, handler.parse()?))
+}
@@ -0,0 +1,77 @@
+use std::ops::Range;
+use std::sync::atomic::AtomicU32;
+use std::sync::Mutex;
+
+/// The state that belongs to one process's address space rather than to the
+/// machine: where its page tables are, and where its heap and `MapMemory`
+/// regions go, which the process can move with `SetMemRegion`. Every thread
+/// of a process shares its address space through the `Memory` handle its CPU
+/// was built with, so a second process would get one of its own and couldn't
+/// move the first one's heap.
+pub struct AddressSpace {
+    /// The physical address of the root page table.
+    pub l1_pt: u32,
+
+    /// The ID that tags the space's translations, both in the CPU's TLB and
+    /// in the translation cache.
+    pub asid: u32,
+
+    /// What each of the process's harts loads into `satp`.
+    pub satp: u32,
+
+    pub heap_start: AtomicU32,
+    pub heap_size: AtomicU32,
+
+    /// How far the heap may grow.
+    pub heap_end: AtomicU32,
+
+    /// The window `MapMemory` regions are placed in. It's read and moved as
+    /// a whole, so that a region is never placed by the start of one window
+    /// and the end of another.
+    pub allocation: Mutex<Range<u32>>,
+
+    /// Where the last `MapMemory` region was placed, which is where the
+    /// search for the next one starts. A region placed as the window moves
+    /// can leave it outside the window, so it's clamped into it before use.
+    pub allocation_previous: AtomicU32,
+
+    /// Where the process says its main stack is, which the emulator only
+    /// reports.
+    pub stack: Mutex<Range<u32>>,
+
+    /// Where the process says lent memory goes, if it has said.
+    pub messages: Mutex<Range<u32>>,
+
+    /// Held while a page reserved for demand paging is allocated, so that
+    /// harts faulting on it at once don't each allocate it.
+    pub paging: Mutex<()>,
+}
+
+impl AddressSpace {
+    /// An empty address space with ID `asid` whose root page table is at
+    /// `l1_pt`, with its heap starting at `heap_start` and its `MapMemory`
+    /// regions at `allocation_start`, and its stack at `stack`.
+    pub fn new(
+        asid: u32,
+        l1_pt: u32,
+        heap_start: u32,
+        heap_end: u32,
+        allocation_start: u32,
+        allocation_end: u32,
+        stack: Range<u32>,
+    ) -> Self {
+        AddressSpace {
+            l1_pt,
+            asid,
+            satp: (l1_pt >> 12) | (asid << 22) | 0x8000_0000,
+            heap_start: AtomicU32::new(heap_start),
+            heap_size: AtomicU32::new(0),
+            heap_end: AtomicU32::new(heap_end),
+            allocation: Mutex::new(allocation_start..allocation_end),
+            allocation_previous: AtomicU32::new(allocation_start),
+            stack: Mutex::new(stack),
+            messages: Mutex::new(0..0),
+            paging: Mutex::new(()),
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! Where the audio the program plays through the codec server goes. The
+//! codec runs at 8kHz in stereo, and each `AudioSink` converts that to the
+//! rate it plays or records at. `WavWriter` records to a file, for headless
+//! runs and for comparing against a known-good recording, and with the
+//! `audio` feature `LiveOutput` plays through the host's speakers.
+
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The rate the codec runs at, in frames per second.
+pub const CODEC_RATE: u32 = 8000;
+
+/// A left and a right sample.
+pub type Frame = [i16; 2];
+
+/// Something that plays or records the codec's output.
+pub trait AudioSink: Send {
+    /// Take as many of `frames`, at `CODEC_RATE`, as there's room for,
+    /// returning how many that was.
+    fn play(&mut self, frames: &[Frame]) -> usize;
+
+    /// Finish playing whatever is held back for resampling, because the
+    /// stream has stopped.
+    fn flush(&mut self) {}
+}
+
+/// Throws everything away, for when the program's audio isn't wanted.
+pub struct Discard;
+
+impl AudioSink for Discard {
+    fn play(&mut self, frames: &[Frame]) -> usize {
+        frames.len()
+    }
+}
+
+/// Converts a stream of frames from one rate to another by interpolating
+/// between neighbouring frames.
+pub struct Resampler {
+    /// Input frames per output frame.
+    step: f64,
+
+    /// Where the next output frame falls after `previous`, in input frames.
+    position: f64,
+    previous: Option<Frame>,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Self {
+        Resampler {
+            step: from as f64 / to as f64,
+            position: 0.0,
+            previous: None,
+        }
+    }
+
+    /// Add `frame` to the input, adding any output frames that come before
+    /// it to `output`. The output runs one input frame behind.
+    pub fn push(&mut self, frame: Frame, output: &mut impl Extend<Frame>) {
+        let Some(previous) = self.previous.replace(frame) else {
+            return;
+        };
+        while self.position < 1.0 {
+            let lerp = |a: i16, b: i16| (a as f64 + (b as f64 - a as f64) * self.position).round();
+            output.extend([[
+                lerp(previous[0], frame[0]) as i16,
+                lerp(previous[1], frame[1]) as i16,
+            ]]);
+            self.position += self.step;
+        }
+        self.position -= 1.0;
+    }
+
+    /// Add the output frames that fall after the last input frame, holding
+    /// it steady since there's nothing to interpolate towards.
+    pub fn flush(&mut self, output: &mut impl Extend<Frame>) {
+        if let Some(last) = self.previous {
+            self.push(last, output);
+            self.previous = None;
+            self.position = 0.0;
+        }
+    }
+}
+
+/// Anything a WAV file can be written to.
+pub trait WriteSeek: Write + Seek + Send {}
+
+impl<T: Write + Seek + Send> WriteSeek for T {}
+
+/// The most bytes of samples a WAV file can hold, since the RIFF header
+/// counts them, and the 36 bytes after it, in 32 bits. Whole frames only.
+pub const MAX_WAV_LENGTH: u32 = (u32::MAX - 36) & !3;
+
+/// Records to a 16-bit stereo WAV file. The header is kept up to date after
+/// every write, so the file is complete whenever the program stops.
+/// Recording stops, with a warning, once the file is full.
+pub struct WavWriter {
+    output: Box<dyn WriteSeek>,
+    resampler: Resampler,
+
+    /// Bytes of samples written so far.
+    length: u32,
+
+    /// Bytes of samples to stop at.
+    limit: u32,
+
+    /// Whether recording has stopped, because a write failed or the file
+    /// is full.
+    stopped: bool,
+}
+
+impl WavWriter {
+    /// Record at `rate` frames per second to `output`, starting with an
+    /// empty file.
+    pub fn new(mut output: Box<dyn WriteSeek>, rate: u32) -> std::io::Result<Self> {
+        let byte_rate = rate.checked_mul(4).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "sample rate too high")
+        })?;
+        let mut header = vec![];
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, in two channels
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        // Four bytes per frame, of sixteen-bit samples
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        output.write_all(&header)?;
+        output.flush()?;
+        Ok(WavWriter {
+            output,
+            resampler: Resampler::new(CODEC_RATE, rate),
+            length: 0,
+            limit: MAX_WAV_LENGTH,
+            stopped: false,
+        })
+    }
+
+    /// Stop recording after `bytes` of samples, rounded down to whole
+    /// frames, rather than once the file is full.
+    pub fn limit(mut self, bytes: u32) -> Self {
+        self.limit = bytes.min(MAX_WAV_LENGTH) & !3;
+        self
+    }
+
+    /// Record at `rate` frames per second to the file at `path`.
+    pub fn create(path: &str, rate: u32) -> std::io::Result<Self> {
+        Self::new(Box::new(std::fs::File::create(path)?), rate)
+    }
+
+    fn write(&mut self, frames: &[Frame]) -> std::io::Result<()> {
+        let data: Vec<u8> = frames
+            .iter()
+            .flat_map(|frame| frame.iter().flat_map(|sample| sample.to_le_bytes()))
+            .take((self.limit - self.length) as usize)
+            .collect();
+        self.output.write_all(&data)?;
+        self.length += data.len() as u32;
+        self.output.seek(SeekFrom::Start(4))?;
+        self.output.write_all(&(36 + self.length).to_le_bytes())?;
+        self.output.seek(SeekFrom::Start(40))?;
+        self.output.write_all(&self.length.to_le_bytes())?;
+        self.output.seek(SeekFrom::End(0))?;
+        self.output.flush()
+    }
+
+    fn record(&mut self, frames: Vec<Frame>) {
+        if self.stopped || frames.is_empty() {
+            return;
+        }
+        if let Err(error) = self.write(&frames) {
+            log::error!("Couldn't record audio: {}", error);
+            self.stopped = true;
+        } else if self.length == self.limit {
+            log::warn!("The recording is full, so the rest of the audio is dropped");
+            self.stopped = true;
+        }
+    }
+}
+
+impl AudioSink for WavWriter {
+    fn play(&mut self, frames: &[Frame]) -> usize {
+        let mut resampled = vec![];
+        for frame in frames {
+            self.resampler.push(*frame, &mut resampled);
+        }
+        self.record(resampled);
+        frames.len()
+    }
+
+    fn flush(&mut self) {
+        let mut resampled = vec![];
+        self.resampler.flush(&mut resampled);
+        self.record(resampled);
+    }
+}
+
+#[cfg(feature = "audio")]
+pub use live::LiveOutput;
+
+#[cfg(feature = "audio")]
+mod live {
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::{Arc, Mutex};
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    use super::{AudioSink, Frame, Resampler, CODEC_RATE};
+
+    type Queue = Arc<Mutex<VecDeque<Frame>>>;
+
+    /// Plays through the host's default output device. Up to half a second
+    /// is queued ahead of the device, and the program is told there's no
+    /// room for any more beyond that, much as the real codec only asks for
+    /// frames as it plays them.
+    pub struct LiveOutput {
+        queue: Queue,
+        capacity: usize,
+        resampler: Resampler,
+
+        /// Dropped to stop the stream, which lives on a thread of its own
+        /// since not every host can move it between threads.
+        _stop: Sender<()>,
+    }
+
+    impl LiveOutput {
+        pub fn open() -> Result<Self, String> {
+            let queue = Queue::default();
+            let (ready_tx, ready_rx) = channel();
+            let (stop, stop_rx) = channel::<()>();
+            let stream_queue = queue.clone();
+            std::thread::spawn(move || match start(stream_queue) {
+                Ok((stream, rate)) => {
+                    ready_tx.send(Ok(rate)).ok();
+                    stop_rx.recv().ok();
+                    drop(stream);
+                }
+                Err(error) => {
+                    ready_tx.send(Err(error)).ok();
+                }
+            });
+            let rate = ready_rx
+                .recv()
+                .map_err(|_| "the audio thread stopped".to_owned())??;
+            Ok(LiveOutput {
+                queue,
+                capacity: rate as usize / 2,
+                resampler: Resampler::new(CODEC_RATE, rate),
+                _stop: stop,
+            })
+        }
+    }
+
+    /// Start playing `queue` on the default output device, returning the
+    /// stream and the rate it plays at.
+    fn start(queue: Queue) -> Result<(cpal::Stream, u32), String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("there's no audio output device")?;
+        let config = device
+            .default_output_config()
+            .map_err(|error| error.to_string())?;
+        let rate = config.sample_rate().0;
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => build::<f32>(&device, &config.into(), queue),
+            cpal::SampleFormat::I16 => build::<i16>(&device, &config.into(), queue),
+            cpal::SampleFormat::U16 => build::<u16>(&device, &config.into(), queue),
+            format => return Err(format!("unsupported sample format {}", format)),
+        }?;
+        stream.play().map_err(|error| error.to_string())?;
+        Ok((stream, rate))
+    }
+
+    fn build<T: cpal::SizedSample + cpal::FromSample<i16>>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        queue: Queue,
+    ) -> Result<cpal::Stream, String> {
+        let channels = config.channels as usize;
+        device
+            .build_output_stream(
+                config,
+                move |data: &mut [T], _| {
+                    let mut queue = queue.lock().unwrap();
+                    for frame in data.chunks_mut(channels) {
+                        // Play silence if the program falls behind
+                        let [left, right] = queue.pop_front().unwrap_or([0, 0]);
+                        for (channel, sample) in frame.iter_mut().enumerate() {
+                            let value = if channel % 2 == 0 { left } else { right };
+                            *sample = T::from_sample(value);
+                        }
+                    }
+                },
+                |error| log::error!("Audio output failed: {}", error),
+                None,
+            )
+            .map_err(|error| error.to_string())
+    }
+
+    impl AudioSink for LiveOutput {
+        fn play(&mut self, frames: &[Frame]) -> usize {
+            let mut queue = self.queue.lock().unwrap();
+            for (taken, frame) in frames.iter().enumerate() {
+                if queue.len() >= self.capacity {
+                    return taken;
+                }
+                self.resampler.push(*frame, &mut *queue);
+            }
+            frames.len()
+        }
+
+        fn flush(&mut self) {
+            self.resampler.flush(&mut *self.queue.lock().unwrap());
+        }
+    }
+}
+
+/// The codec, shared between the codec service and the host.
+pub struct Audio {
+    sink: Mutex<Box<dyn AudioSink>>,
+
+    /// Whether the program has started the stream and not paused it.
+    playing: AtomicBool,
+
+    /// Frames the program has played, at `CODEC_RATE`.
+    frames: AtomicU64,
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Self::new(Box::new(Discard))
+    }
+}
+
+impl Audio {
+    pub fn new(sink: Box<dyn AudioSink>) -> Self {
+        Audio {
+            sink: Mutex::new(sink),
+            playing: AtomicBool::new(false),
+            frames: AtomicU64::new(0),
+        }
+    }
+
+    /// How many frames the program has played so far.
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    pub fn playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_playing(&self, playing: bool) {
+        self.playing.store(playing, Ordering::Relaxed);
+    }
+
+    pub(super) fn play(&self, frames: &[Frame]) -> usize {
+        let taken = self.sink.lock().unwrap().play(frames);
+        self.frames.fetch_add(taken as u64, Ordering::Relaxed);
+        taken
+    }
+
+    pub(super) fn flush(&self) {
+        self.sink.lock().unwrap().flush();
+    }
+}
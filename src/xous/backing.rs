@@ -0,0 +1,51 @@
+use std::sync::{OnceLock, RwLock};
+
+/// The guest's RAM, one 4K page of words at a time. Pages are only given
+/// host memory once something writes to them, so a guest can be configured
+/// with far more RAM than it touches. Reading a page that was never written
+/// gives zeroes, the same as a page that was.
+pub struct Backing {
+    pages: Box<[OnceLock<RwLock<Vec<u32>>>]>,
+}
+
+impl Backing {
+    pub fn new(page_count: usize) -> Self {
+        Backing {
+            pages: (0..page_count).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    /// How many pages of RAM there are, whether or not they've been written.
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// The page at `index`, or `None` if it has never been written or is
+    /// past the end of RAM.
+    pub fn get(&self, index: usize) -> Option<&RwLock<Vec<u32>>> {
+        self.pages.get(index)?.get()
+    }
+
+    /// The page at `index`, giving it host memory if this is the first time
+    /// it's been needed. Returns `None` if it's past the end of RAM.
+    pub fn get_or_create(&self, index: usize) -> Option<&RwLock<Vec<u32>>> {
+        Some(
+            self.pages
+                .get(index)?
+                .get_or_init(|| RwLock::new(vec![0; 1024])),
+        )
+    }
+
+    /// How many pages have been given host memory.
+    pub fn resident_pages(&self) -> usize {
+        self.pages
+            .iter()
+            .filter(|page| page.get().is_some())
+            .count()
+    }
+
+    /// Every page that has been given host memory.
+    pub fn iter(&self) -> impl Iterator<Item = &RwLock<Vec<u32>>> {
+        self.pages.iter().filter_map(|page| page.get())
+    }
+}
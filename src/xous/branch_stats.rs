@@ -0,0 +1,190 @@
+//! Guesses at how the program would fare on a VexRiscv core, from a model of
+//! its branch predictor and instruction prefetch buffer. The model only
+//! counts, and never changes what the program does or how long the emulator
+//! takes to run it, so the numbers are for finding the functions worth
+//! optimizing rather than for predicting cycle counts.
+//!
+//! Branches and jumps are predicted by a direct-mapped branch target buffer
+//! whose entries each hold a target and a 2-bit saturating counter. A
+//! conditional branch is predicted taken if its entry's counter is 2 or 3,
+//! and jumps are predicted taken to wherever they went last time. Anything
+//! not in the buffer is predicted to fall through. The prefetch buffer holds
+//! the line being fetched from and the one after it, so only fetching from
+//! anywhere else misses. Every thread shares one model, as they would share
+//! one core.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Mutex, RwLock};
+
+use super::profiler::{Profiler, Symbol};
+
+/// How many entries the branch target buffer has unless told otherwise.
+pub const DEFAULT_BTB_ENTRIES: usize = 64;
+
+/// The size of a line of the prefetch buffer, in bytes.
+const PREFETCH_LINE_BYTES: u32 = 32;
+
+#[derive(Clone, Copy)]
+struct BtbEntry {
+    pc: u32,
+    target: u32,
+
+    /// Taken if 2 or more.
+    counter: u8,
+}
+
+/// What happened in one function.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FunctionStats {
+    pub instructions: u64,
+
+    /// Instructions fetched from outside the prefetch buffer.
+    pub fetch_misses: u64,
+
+    /// Branches and jumps, and how many of them went somewhere other than
+    /// where they were predicted to.
+    pub branches: u64,
+    pub mispredictions: u64,
+}
+
+struct State {
+    btb: Vec<Option<BtbEntry>>,
+
+    /// The line of the prefetch buffer last fetched from.
+    line: Option<u32>,
+
+    /// Keyed by the index of the function in the symbols, or `None` for code
+    /// outside of any.
+    functions: HashMap<Option<usize>, FunctionStats>,
+}
+
+pub struct BranchStats {
+    symbols: RwLock<Vec<Symbol>>,
+    state: Mutex<State>,
+}
+
+impl BranchStats {
+    /// Model a branch target buffer with `btb_entries` entries.
+    pub fn new(btb_entries: usize) -> Self {
+        BranchStats {
+            symbols: RwLock::new(vec![]),
+            state: Mutex::new(State {
+                btb: vec![None; btb_entries.max(1)],
+                line: None,
+                functions: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Provide the symbols of the loaded program so that counts can be kept
+    /// by function.
+    pub fn set_symbols(&self, mut symbols: Vec<Symbol>) {
+        symbols.sort_by_key(|symbol| symbol.address);
+        *self.symbols.write().unwrap() = symbols;
+    }
+
+    /// Count the instruction `word` at `pc`, which has just run and left the
+    /// CPU at `next_pc`.
+    pub(super) fn step(&self, pc: u32, word: u32, next_pc: u32) {
+        let function = Profiler::symbolize(&self.symbols.read().unwrap(), pc).map(|(i, _)| i);
+        let mut state = self.state.lock().unwrap();
+        let State {
+            btb,
+            line,
+            functions,
+        } = &mut *state;
+        let stats = functions.entry(function).or_default();
+        stats.instructions += 1;
+
+        let fetched = pc / PREFETCH_LINE_BYTES;
+        if !matches!(*line, Some(current) if fetched == current || fetched == current + 1) {
+            stats.fetch_misses += 1;
+        }
+        *line = Some(fetched);
+
+        let opcode = word & 0x7f;
+        if !matches!(opcode, 0x63 | 0x6f | 0x67) {
+            return;
+        }
+        stats.branches += 1;
+        let taken = next_pc != pc.wrapping_add(2) && next_pc != pc.wrapping_add(4);
+        let index = (pc as usize >> 1) % btb.len();
+        let slot = &mut btb[index];
+        let predicted = match slot {
+            Some(entry) if entry.pc == pc && entry.counter >= 2 => Some(entry.target),
+            _ => None,
+        };
+        if predicted != taken.then_some(next_pc) {
+            stats.mispredictions += 1;
+        }
+        match slot {
+            Some(entry) if entry.pc == pc => {
+                if taken {
+                    entry.counter = (entry.counter + 1).min(3);
+                    entry.target = next_pc;
+                } else {
+                    entry.counter = entry.counter.saturating_sub(1);
+                }
+            }
+            // Branches that don't go anywhere don't need an entry
+            _ if taken => {
+                *slot = Some(BtbEntry {
+                    pc,
+                    target: next_pc,
+                    counter: if opcode == 0x63 { 2 } else { 3 },
+                })
+            }
+            _ => {}
+        }
+    }
+
+    /// The counts for every function that ran, by name, with code outside
+    /// of any function as `?`, busiest first.
+    pub fn functions(&self) -> Vec<(String, FunctionStats)> {
+        let symbols = self.symbols.read().unwrap();
+        let state = self.state.lock().unwrap();
+        let mut functions: Vec<(String, FunctionStats)> = state
+            .functions
+            .iter()
+            .map(|(function, stats)| {
+                let name = function.map_or("?", |index| symbols[index].name.as_str());
+                (name.to_owned(), *stats)
+            })
+            .collect();
+        functions.sort_by(|(a_name, a), (b_name, b)| {
+            b.instructions
+                .cmp(&a.instructions)
+                .then_with(|| a_name.cmp(b_name))
+        });
+        functions
+    }
+
+    /// Write the counts as a table with a row for each function and the
+    /// miss and misprediction rates as percentages.
+    pub fn write(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let percent = |part: u64, whole: u64| match whole {
+            0 => 0.0,
+            _ => part as f64 * 100.0 / whole as f64,
+        };
+        writeln!(
+            output,
+            "{:>12} {:>10} {:>7} {:>10} {:>10} {:>7}  function",
+            "instructions", "misses", "miss%", "branches", "mispredict", "mis%"
+        )?;
+        for (name, stats) in self.functions() {
+            writeln!(
+                output,
+                "{:>12} {:>10} {:>6.2}% {:>10} {:>10} {:>6.2}%  {}",
+                stats.instructions,
+                stats.fetch_misses,
+                percent(stats.fetch_misses, stats.instructions),
+                stats.branches,
+                stats.mispredictions,
+                percent(stats.mispredictions, stats.branches),
+                name
+            )?;
+        }
+        Ok(())
+    }
+}
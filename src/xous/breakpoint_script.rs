@@ -0,0 +1,294 @@
+//! Breakpoints that the emulator handles itself, with actions attached, for
+//! debugging sessions that would otherwise mean doing the same thing in a
+//! debugger every time a function is reached. They work however the machine
+//! is run, including with `Machine::run()`.
+//!
+//! A script has one breakpoint to a line, and `#` starts a comment:
+//!
+//! ```text
+//! # Record what decrypt does, and look at its key on the way in
+//! decrypt: trace on; dump a1 32
+//! decrypt_done: trace off
+//! # Pretend the self test passed, then give up if it panics anyway
+//! 0x20001234: set a0 0
+//! panic: dump sp 64; stop
+//! ```
+//!
+//! A breakpoint is on a function from the symbol table, optionally with an
+//! offset into it as `name+offset`, or on an address, followed by the
+//! actions to take, in order, every time a thread is about to run the
+//! instruction there. `trace on` and `trace off` start and stop
+//! the execution recording enabled with `MachineBuilder::record_execution`,
+//! which starts off if the script ever turns it on. `dump` writes the bytes
+//! at an address, or at the address a register holds, to stderr and keeps
+//! them for `Machine::breakpoint_dumps()`. `set` writes a register or `pc`.
+//! `stop` ends the run with `YoveError::ScriptStop`, and `continue`, which
+//! is what happens anyway, carries on.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+use super::profiler::Symbol;
+
+/// The integer registers by ABI name, in order.
+const REGISTERS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// The register called `name`, by ABI name, as `fp`, or as `x0` to `x31`.
+fn register(name: &str) -> Option<u8> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(index) = REGISTERS.iter().position(|&known| known == name) {
+        return Some(index as u8);
+    }
+    let index: u8 = name.strip_prefix('x')?.parse().ok()?;
+    (index < 32).then_some(index)
+}
+
+fn number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        return u32::from_str_radix(&hex.replace('_', ""), 16).ok();
+    }
+    match text.strip_prefix('-') {
+        Some(negative) => negative.parse::<u32>().ok().map(u32::wrapping_neg),
+        None => text.parse().ok(),
+    }
+}
+
+/// Where a breakpoint is, written as a symbol, as `symbol+offset`, or as an
+/// address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Symbol { name: String, offset: u32 },
+    Address(u32),
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        if let Some(address) = number(text) {
+            return Ok(Location::Address(address));
+        }
+        let (name, offset) = match text.rsplit_once('+') {
+            Some((name, offset)) => (
+                name,
+                number(offset).ok_or_else(|| format!("expected an offset, not {:?}", offset))?,
+            ),
+            None => (text, 0),
+        };
+        if name.is_empty() {
+            return Err(format!("expected a symbol or an address, not {:?}", text));
+        }
+        Ok(Location::Symbol {
+            name: name.to_owned(),
+            offset,
+        })
+    }
+}
+
+impl Location {
+    /// The address of the location in a program with `symbols`, or `None`
+    /// if it's on a symbol the program doesn't have.
+    pub fn resolve(&self, symbols: &[Symbol]) -> Option<u32> {
+        match self {
+            Location::Symbol { name, offset } => symbols
+                .iter()
+                .find(|symbol| &symbol.name == name)
+                .map(|symbol| symbol.address.wrapping_add(*offset)),
+            Location::Address(address) => Some(*address),
+        }
+    }
+}
+
+/// Where a dump starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Start {
+    Address(u32),
+
+    /// The address the register holds when the breakpoint is hit.
+    Register(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Action {
+    Trace(bool),
+    Dump {
+        start: Start,
+        len: u32,
+    },
+
+    /// Write the register, or the PC if there isn't one.
+    Set {
+        register: Option<u8>,
+        value: u32,
+    },
+    Stop,
+}
+
+fn parse_action(action: &str) -> Result<Option<Action>, String> {
+    let words: Vec<&str> = action.split_whitespace().collect();
+    let action = match words[..] {
+        [] | ["continue"] => return Ok(None),
+        ["trace", "on"] => Action::Trace(true),
+        ["trace", "off"] => Action::Trace(false),
+        ["dump", start, len] => Action::Dump {
+            start: match register(start) {
+                Some(register) => Start::Register(register),
+                None => Start::Address(
+                    number(start).ok_or_else(|| format!("expected an address, not {:?}", start))?,
+                ),
+            },
+            len: number(len).ok_or_else(|| format!("expected a length, not {:?}", len))?,
+        },
+        ["set", target, value] => Action::Set {
+            register: match target {
+                "pc" => None,
+                _ => {
+                    Some(register(target).ok_or_else(|| format!("unknown register {:?}", target))?)
+                }
+            },
+            value: number(value).ok_or_else(|| format!("expected a value, not {:?}", value))?,
+        },
+        ["stop"] => Action::Stop,
+        _ => return Err(format!("unknown action {:?}", action)),
+    };
+    Ok(Some(action))
+}
+
+/// A parsed breakpoint script, ready for `MachineBuilder::breakpoint_script`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BreakpointScript {
+    breakpoints: Vec<(Location, Vec<Action>)>,
+}
+
+impl BreakpointScript {
+    pub fn parse(script: &str) -> Result<Self, String> {
+        let mut breakpoints = vec![];
+        for (index, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (location, actions) = line
+                .split_once(':')
+                .ok_or_else(|| format!("line {}: expected <location>: <actions>", index + 1))?;
+            let location: Location = location
+                .parse()
+                .map_err(|error| format!("line {}: {}", index + 1, error))?;
+            let actions = actions
+                .split(';')
+                .filter_map(|action| parse_action(action).transpose())
+                .collect::<Result<_, _>>()
+                .map_err(|error| format!("line {}: {}", index + 1, error))?;
+            breakpoints.push((location, actions));
+        }
+        Ok(BreakpointScript { breakpoints })
+    }
+
+    /// Whether any breakpoint turns execution recording on.
+    pub(super) fn starts_tracing(&self) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|(_, actions)| actions.contains(&Action::Trace(true)))
+    }
+}
+
+/// Memory written out by a `dump` action.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryDump {
+    /// The thread that hit the breakpoint, and where.
+    pub tid: i32,
+    pub pc: u32,
+    pub address: u32,
+
+    /// What was there, or nothing if it isn't all mapped.
+    pub bytes: Vec<u8>,
+}
+
+/// A script's breakpoints, once their symbols have been looked up.
+pub(super) struct ScriptedBreakpoints {
+    script: BreakpointScript,
+
+    /// The actions at each address, in the order the script gives them.
+    actions: RwLock<HashMap<u32, Vec<Action>>>,
+    dumps: Mutex<Vec<MemoryDump>>,
+}
+
+impl ScriptedBreakpoints {
+    pub fn new(script: BreakpointScript) -> Self {
+        let mut actions = HashMap::new();
+        for (location, list) in &script.breakpoints {
+            if let Location::Address(address) = location {
+                actions
+                    .entry(*address)
+                    .or_insert_with(Vec::new)
+                    .extend(list);
+            }
+        }
+        ScriptedBreakpoints {
+            script,
+            actions: RwLock::new(actions),
+            dumps: Mutex::new(vec![]),
+        }
+    }
+
+    /// Look up the functions the script names. Returns the names that
+    /// aren't in `symbols`.
+    pub fn set_symbols(&self, symbols: &[Symbol]) -> Vec<String> {
+        let mut missing = vec![];
+        let mut actions = self.actions.write().unwrap();
+        for (location, list) in &self.script.breakpoints {
+            let Location::Symbol { name, .. } = location else {
+                continue;
+            };
+            match location.resolve(symbols) {
+                Some(address) => actions.entry(address).or_default().extend(list),
+                None => missing.push(name.clone()),
+            }
+        }
+        missing
+    }
+
+    /// The actions to take before running the instruction at `pc`.
+    pub fn actions_at(&self, pc: u32) -> Option<Vec<Action>> {
+        self.actions.read().unwrap().get(&pc).cloned()
+    }
+
+    pub fn record_dump(&self, dump: MemoryDump) {
+        self.dumps.lock().unwrap().push(dump);
+    }
+
+    pub fn dumps(&self) -> Vec<MemoryDump> {
+        self.dumps.lock().unwrap().clone()
+    }
+}
+
+/// `dump` as a header line and 16 bytes to a line after it.
+pub(super) fn format_dump(dump: &MemoryDump) -> String {
+    let mut text = format!(
+        "[tid {}] breakpoint at {:08x}: {} bytes at {:08x}\n",
+        dump.tid,
+        dump.pc,
+        dump.bytes.len(),
+        dump.address
+    );
+    if dump.bytes.is_empty() {
+        text.push_str("  (not mapped)\n");
+    }
+    for (index, line) in dump.bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        text.push_str(&format!(
+            "  {:08x}  {}\n",
+            dump.address.wrapping_add(index as u32 * 16),
+            hex.join(" ")
+        ));
+    }
+    text
+}
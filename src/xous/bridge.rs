@@ -0,0 +1,302 @@
+//! A bridge to a real Precursor, so that chosen servers are answered by the
+//! device's own hardware while the program still runs in the emulator. When
+//! the program connects to a bridged name, every message it sends there is
+//! passed over a serial link, such as the device's USB serial port, to a
+//! bridge server on the device that replays it and sends back the reply.
+//!
+//! Both directions are a stream of frames. Each frame is a 32-bit length
+//! followed by that many bytes, and every number is little-endian. A frame
+//! is at most `MAX_FRAME_SIZE` bytes, so a lent buffer larger than about
+//! that can't be bridged, and a frame that claims to be larger fails the
+//! link rather than being allocated.
+//!
+//! A request is:
+//!
+//! | Field   | Size | Meaning                                                |
+//! |---------|------|--------------------------------------------------------|
+//! | kind    | 1    | 0 scalar, 1 blocking scalar, 2 lend, 3 mutable lend    |
+//! | name    | 1+n  | The length of the server's name, then the name         |
+//! | opcode  | 4    |                                                        |
+//! | args    | 16   | The four arguments, which are the buffer's length,     |
+//! |         |      | offset, and valid bytes for lends                      |
+//! | buffer  | 4+n  | The length of the lent buffer, then its contents       |
+//!
+//! Every request other than a scalar gets a response, in the order the
+//! requests were sent:
+//!
+//! | Field   | Size | Meaning                                                |
+//! |---------|------|--------------------------------------------------------|
+//! | kind    | 1    | 0 scalar1, 1 scalar2, 2 scalar5, 3 memory returned,    |
+//! |         |      | 4 error                                                |
+//! | values  | 20   | The results, or the Xous error code for an error       |
+//! | buffer  | 4+n  | The lent buffer as the server left it, for a mutable   |
+//! |         |      | lend, and empty otherwise                              |
+//!
+//! If the link fails, the bridge stops and every thread waiting on a bridged
+//! server, and every one that sends to one later, gets an `InternalError`.
+
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+/// The most bytes a frame may hold after its length.
+pub const MAX_FRAME_SIZE: usize = 16 << 20;
+
+/// What kind of message a request carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Scalar = 0,
+    BlockingScalar = 1,
+    Lend = 2,
+    MutableLend = 3,
+}
+
+/// A message to a bridged server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub kind: RequestKind,
+    pub name: String,
+    pub opcode: u32,
+    pub args: [u32; 4],
+    pub buf: Vec<u8>,
+}
+
+/// A bridged server's reply to a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Scalar1(u32),
+    Scalar2([u32; 2]),
+    Scalar5([u32; 5]),
+    /// The two results and, for a mutable lend, the buffer.
+    MemoryReturned([u32; 2], Vec<u8>),
+    /// The server failed the request with this error.
+    Error(u32),
+}
+
+fn invalid(what: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("malformed bridge frame: {}", what),
+    )
+}
+
+/// Reads the fields of a frame in order.
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn bytes(&mut self, length: usize) -> std::io::Result<&[u8]> {
+        if self.0.len() < length {
+            return Err(invalid("too short"));
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn buf(&mut self) -> std::io::Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Ok(self.bytes(length)?.to_vec())
+    }
+}
+
+fn write_frame(output: &mut impl Write, frame: &[u8]) -> std::io::Result<()> {
+    if frame.len() > MAX_FRAME_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, "frame too large"));
+    }
+    output.write_all(&(frame.len() as u32).to_le_bytes())?;
+    output.write_all(frame)?;
+    output.flush()
+}
+
+fn read_frame(input: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    input.read_exact(&mut length)?;
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(invalid("too large"));
+    }
+    let mut frame = vec![0; length];
+    input.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+impl Request {
+    pub fn write(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let name = self.name.as_bytes();
+        if name.len() > u8::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "server name too long"));
+        }
+        let mut frame = vec![self.kind as u8, name.len() as u8];
+        frame.extend_from_slice(name);
+        frame.extend_from_slice(&self.opcode.to_le_bytes());
+        for arg in self.args {
+            frame.extend_from_slice(&arg.to_le_bytes());
+        }
+        frame.extend_from_slice(&(self.buf.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&self.buf);
+        write_frame(output, &frame)
+    }
+
+    pub fn read(input: &mut impl Read) -> std::io::Result<Self> {
+        let frame = read_frame(input)?;
+        let mut fields = Fields(&frame);
+        let kind = match fields.u8()? {
+            0 => RequestKind::Scalar,
+            1 => RequestKind::BlockingScalar,
+            2 => RequestKind::Lend,
+            3 => RequestKind::MutableLend,
+            _ => return Err(invalid("unknown request kind")),
+        };
+        let name_length = fields.u8()? as usize;
+        let name = std::str::from_utf8(fields.bytes(name_length)?)
+            .map_err(|_| invalid("server name isn't UTF-8"))?
+            .to_owned();
+        let opcode = fields.u32()?;
+        let args = [fields.u32()?, fields.u32()?, fields.u32()?, fields.u32()?];
+        let buf = fields.buf()?;
+        Ok(Request {
+            kind,
+            name,
+            opcode,
+            args,
+            buf,
+        })
+    }
+}
+
+impl Response {
+    pub fn write(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let (kind, values, buf): (u8, &[u32], &[u8]) = match self {
+            Response::Scalar1(value) => (0, std::slice::from_ref(value), &[]),
+            Response::Scalar2(values) => (1, values, &[]),
+            Response::Scalar5(values) => (2, values, &[]),
+            Response::MemoryReturned(values, buf) => (3, values, buf),
+            Response::Error(code) => (4, std::slice::from_ref(code), &[]),
+        };
+        let mut frame = vec![kind];
+        for index in 0..5 {
+            let value = values.get(index).copied().unwrap_or(0);
+            frame.extend_from_slice(&value.to_le_bytes());
+        }
+        frame.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        frame.extend_from_slice(buf);
+        write_frame(output, &frame)
+    }
+
+    pub fn read(input: &mut impl Read) -> std::io::Result<Self> {
+        let frame = read_frame(input)?;
+        let mut fields = Fields(&frame);
+        let kind = fields.u8()?;
+        let values = [
+            fields.u32()?,
+            fields.u32()?,
+            fields.u32()?,
+            fields.u32()?,
+            fields.u32()?,
+        ];
+        let buf = fields.buf()?;
+        Ok(match kind {
+            0 => Response::Scalar1(values[0]),
+            1 => Response::Scalar2([values[0], values[1]]),
+            2 => Response::Scalar5(values),
+            3 => Response::MemoryReturned([values[0], values[1]], buf),
+            4 => Response::Error(values[0]),
+            _ => return Err(invalid("unknown response kind")),
+        })
+    }
+}
+
+/// Called with the response to a request, or dropped if the link failed.
+pub(crate) type OnResponse = Box<dyn FnOnce(Response) + Send>;
+
+/// A request waiting to be sent, and what to call with its response.
+type Pending = (Request, Option<OnResponse>);
+
+/// The link to the device, along with which servers are bridged over it.
+pub struct Bridge {
+    names: BTreeSet<String>,
+
+    /// Requests waiting to be sent by the thread that owns the link, until
+    /// the bridge is dropped.
+    requests: Mutex<Option<Sender<Pending>>>,
+
+    /// The thread that owns the link.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Bridge {
+    /// Stop the thread that owns the link, which closes it. A response the
+    /// device still owes is waited for first.
+    fn drop(&mut self) {
+        self.requests.lock().unwrap().take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Bridge {
+    /// Bridge the servers called `names` over the link made of `reader` and
+    /// `writer`. Requests are sent, and responses read, on a thread of its
+    /// own, one at a time. A request too large to send fails on its own,
+    /// without failing the link.
+    pub fn new(
+        names: impl IntoIterator<Item = String>,
+        mut reader: Box<dyn Read + Send>,
+        mut writer: Box<dyn Write + Send>,
+    ) -> Self {
+        let (requests, pending) = channel::<Pending>();
+        let thread = std::thread::spawn(move || {
+            for (request, on_response) in pending {
+                // Nothing is written for a request that can't be sent, and
+                // dropping `on_response` reports it
+                if let Err(error) = request.write(&mut writer) {
+                    if error.kind() == ErrorKind::InvalidInput {
+                        log::error!("Can't bridge a message to {}: {}", request.name, error);
+                        continue;
+                    }
+                    log::error!("Bridge to the device failed: {}", error);
+                    break;
+                }
+                if let Some(on_response) = on_response {
+                    match Response::read(&mut reader) {
+                        Ok(response) => on_response(response),
+                        Err(error) => {
+                            log::error!("Bridge to the device failed: {}", error);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Bridge {
+            names: names.into_iter().collect(),
+            requests: Mutex::new(Some(requests)),
+            thread: Some(thread),
+        }
+    }
+
+    /// Whether messages to the server called `name` go to the device.
+    pub fn bridges(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    /// Send `request` to the device, calling `on_response` with the reply
+    /// if there's one to wait for.
+    pub(crate) fn send(&self, request: Request, on_response: Option<OnResponse>) {
+        // If the link has failed, dropping `on_response` reports it
+        if let Some(requests) = self.requests.lock().unwrap().as_ref() {
+            requests.send((request, on_response)).ok();
+        }
+    }
+}
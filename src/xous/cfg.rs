@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::{Mutex, RwLock};
+
+use super::profiler::{Profiler, Symbol};
+use super::shadow_stack::is_link;
+use super::trace::json_string;
+
+/// Output formats understood by `ControlFlowGraph::write`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CfgFormat {
+    /// A Graphviz digraph of basic blocks, with call edges drawn dashed.
+    Dot,
+
+    /// Blocks, edges, and a call graph summarized by function.
+    Json,
+}
+
+impl CfgFormat {
+    /// Pick the format from a file's extension.
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        match path.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("dot" | "gv") => Ok(CfgFormat::Dot),
+            Some("json") => Ok(CfgFormat::Json),
+            _ => Err(format!(
+                "can't tell the control flow graph format of {:?}, use .dot or .json",
+                path
+            )),
+        }
+    }
+}
+
+/// How control got from one basic block to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// A branch that wasn't taken, or a block that ended because the next
+    /// instruction starts another block.
+    Fallthrough,
+
+    /// A taken branch or a jump that doesn't link.
+    Jump,
+
+    /// A jump that saves a return address.
+    Call,
+
+    /// A jump through a return address register.
+    Return,
+
+    /// Anything else that moved the PC, such as a trap.
+    Other,
+}
+
+impl EdgeKind {
+    fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Call => "call",
+            EdgeKind::Return => "return",
+            EdgeKind::Other => "other",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Block {
+    /// The address of the last instruction in the block.
+    end: u32,
+
+    /// Instructions in the block, as of the last time it ran.
+    instructions: u32,
+    executions: u64,
+}
+
+/// The block a thread is in the middle of running.
+#[derive(Default)]
+pub(super) struct CurrentBlock {
+    start: Option<u32>,
+    instructions: u32,
+}
+
+/// The basic blocks and edges between them observed while the program runs.
+/// Blocks are identified by their first instruction, so jumping into the
+/// middle of a block that has already been seen starts a new, overlapping one.
+#[derive(Default)]
+pub struct ControlFlowGraph {
+    symbols: RwLock<Vec<Symbol>>,
+    blocks: Mutex<HashMap<u32, Block>>,
+    edges: Mutex<HashMap<(u32, u32, EdgeKind), u64>>,
+}
+
+impl ControlFlowGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Provide the symbols of the loaded program so that blocks can be named
+    /// and calls grouped by function.
+    pub fn set_symbols(&self, mut symbols: Vec<Symbol>) {
+        symbols.sort_by_key(|symbol| symbol.address);
+        *self.symbols.write().unwrap() = symbols;
+    }
+
+    /// Follow the instruction `word` at `pc`, which has just run on a thread
+    /// whose block is `current`, and left the CPU at `next_pc`.
+    pub(super) fn step(&self, current: &mut CurrentBlock, pc: u32, word: u32, next_pc: u32) {
+        let start = *current.start.get_or_insert(pc);
+        current.instructions += 1;
+
+        let rd = (word >> 7) & 0x1f;
+        let rs1 = (word >> 15) & 0x1f;
+        let sequential = next_pc == pc.wrapping_add(2) || next_pc == pc.wrapping_add(4);
+        let kind = match word & 0x7f {
+            // Branches, taken or not
+            0x63 if sequential => EdgeKind::Fallthrough,
+            0x63 => EdgeKind::Jump,
+            // jal and jalr, where a link register is a call even if it's also a return
+            0x6f | 0x67 if is_link(rd) => EdgeKind::Call,
+            0x67 if is_link(rs1) => EdgeKind::Return,
+            0x6f | 0x67 => EdgeKind::Jump,
+            _ if !sequential => EdgeKind::Other,
+            _ => return,
+        };
+
+        {
+            let mut blocks = self.blocks.lock().unwrap();
+            let block = blocks.entry(start).or_default();
+            block.end = pc;
+            block.instructions = current.instructions;
+            block.executions += 1;
+        }
+        *self
+            .edges
+            .lock()
+            .unwrap()
+            .entry((start, next_pc, kind))
+            .or_default() += 1;
+        *current = CurrentBlock::default();
+    }
+
+    /// `address` as `symbol+0x10`, or in hex if it isn't in a known function.
+    fn describe(symbols: &[Symbol], address: u32) -> String {
+        match Profiler::symbolize(symbols, address) {
+            Some((index, 0)) => symbols[index].name.clone(),
+            Some((index, offset)) => format!("{}+0x{:x}", symbols[index].name, offset),
+            None => format!("{:08x}", address),
+        }
+    }
+
+    pub fn write(&self, format: CfgFormat, output: &mut impl Write) -> std::io::Result<()> {
+        match format {
+            CfgFormat::Dot => self.write_dot(output),
+            CfgFormat::Json => self.write_json(output),
+        }
+    }
+
+    fn write_dot(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let symbols = self.symbols.read().unwrap();
+        let blocks = self.blocks.lock().unwrap();
+        let edges = self.edges.lock().unwrap();
+
+        writeln!(output, "digraph cfg {{")?;
+        writeln!(output, "  node [shape=box, fontname=monospace];")?;
+        for (start, block) in blocks.iter().collect::<BTreeMap<_, _>>() {
+            writeln!(
+                output,
+                "  b{:08x} [label=\"{}\\n{:08x}-{:08x}\\n{} instructions, {} runs\"];",
+                start,
+                dot_escape(&Self::describe(&symbols, *start)),
+                start,
+                block.end,
+                block.instructions,
+                block.executions
+            )?;
+        }
+        for ((from, to, kind), count) in edges.iter().collect::<BTreeMap<_, _>>() {
+            // Edges can lead to code that hasn't finished a block yet
+            if !blocks.contains_key(to) {
+                writeln!(
+                    output,
+                    "  b{:08x} [label=\"{}\", style=dashed];",
+                    to,
+                    dot_escape(&Self::describe(&symbols, *to))
+                )?;
+            }
+            let style = match kind {
+                EdgeKind::Call => ", style=dashed, color=blue",
+                EdgeKind::Return => ", style=dotted, color=gray",
+                EdgeKind::Other => ", color=red",
+                EdgeKind::Fallthrough | EdgeKind::Jump => "",
+            };
+            writeln!(
+                output,
+                "  b{:08x} -> b{:08x} [label=\"{}\"{}];",
+                from, to, count, style
+            )?;
+        }
+        writeln!(output, "}}")
+    }
+
+    fn write_json(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let symbols = self.symbols.read().unwrap();
+        let blocks = self.blocks.lock().unwrap();
+        let edges = self.edges.lock().unwrap();
+
+        writeln!(output, "{{")?;
+        write!(output, "  \"blocks\": [")?;
+        let blocks = blocks.iter().collect::<BTreeMap<_, _>>();
+        for (index, (start, block)) in blocks.iter().enumerate() {
+            write!(
+                output,
+                "{}\n    {{\"start\": \"{:08x}\", \"end\": \"{:08x}\", \"name\": {}, \
+                 \"instructions\": {}, \"executions\": {}}}",
+                if index == 0 { "" } else { "," },
+                start,
+                block.end,
+                json_string(&Self::describe(&symbols, **start)),
+                block.instructions,
+                block.executions
+            )?;
+        }
+        writeln!(output, "\n  ],")?;
+
+        let edges = edges.iter().collect::<BTreeMap<_, _>>();
+        write!(output, "  \"edges\": [")?;
+        for (index, ((from, to, kind), count)) in edges.iter().enumerate() {
+            write!(
+                output,
+                "{}\n    {{\"from\": \"{:08x}\", \"to\": \"{:08x}\", \"kind\": \"{}\", \"count\": {}}}",
+                if index == 0 { "" } else { "," },
+                from,
+                to,
+                kind.name(),
+                count
+            )?;
+        }
+        writeln!(output, "\n  ],")?;
+
+        // Group call edges by the functions they leave and enter
+        let mut calls = BTreeMap::<(String, String), u64>::new();
+        for ((from, to, kind), count) in edges {
+            if *kind == EdgeKind::Call {
+                let caller = Profiler::symbolize(&symbols, *from)
+                    .map_or_else(|| format!("{:08x}", from), |(i, _)| symbols[i].name.clone());
+                *calls
+                    .entry((caller, Self::describe(&symbols, *to)))
+                    .or_default() += *count;
+            }
+        }
+        write!(output, "  \"calls\": [")?;
+        for (index, ((caller, callee), count)) in calls.iter().enumerate() {
+            write!(
+                output,
+                "{}\n    {{\"caller\": {}, \"callee\": {}, \"count\": {}}}",
+                if index == 0 { "" } else { "," },
+                json_string(caller),
+                json_string(callee),
+                count
+            )?;
+        }
+        writeln!(output, "\n  ]")?;
+        writeln!(output, "}}")
+    }
+}
+
+/// `text` escaped to go in a quoted DOT label, where a backslash starts an
+/// escape such as the `\n` that separates lines.
+fn dot_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex};
+
+use super::platform::Platform;
+
+struct ClockState {
+    /// The host time when the clock last changed speed, in microseconds.
+    host_us: u64,
+
+    /// The virtual time at that point.
+    virtual_us: u64,
+
+    /// Virtual microseconds per host microsecond.
+    scale: f64,
+    frozen: bool,
+}
+
+/// The time seen by the guest, which every service and the `time` CSR read.
+/// It follows the host's clock, but can be frozen, sped up, slowed down, or
+/// moved forward, so that code full of timeouts can be tested quickly and
+/// reproducibly. Virtual time never goes backwards.
+///
+/// This wraps the machine's `Platform`, passing everything but the clock
+/// through to it.
+pub struct VirtualClock {
+    host: Arc<dyn Platform>,
+    state: Mutex<ClockState>,
+}
+
+impl VirtualClock {
+    pub fn new(host: Arc<dyn Platform>) -> Self {
+        let host_us = host.elapsed_us();
+        VirtualClock {
+            host,
+            state: Mutex::new(ClockState {
+                host_us,
+                virtual_us: 0,
+                scale: 1.0,
+                frozen: false,
+            }),
+        }
+    }
+
+    fn now(state: &ClockState, host_us: u64) -> u64 {
+        if state.frozen {
+            return state.virtual_us;
+        }
+        let elapsed = host_us.saturating_sub(state.host_us) as f64 * state.scale;
+        state.virtual_us + elapsed as u64
+    }
+
+    /// Change the clock with `change` after bringing the virtual time up to
+    /// date, so that the change only affects time from now on.
+    fn update(&self, change: impl FnOnce(&mut ClockState)) {
+        let host_us = self.host.elapsed_us();
+        let mut state = self.state.lock().unwrap();
+        state.virtual_us = Self::now(&state, host_us);
+        state.host_us = host_us;
+        change(&mut state);
+    }
+
+    /// Milliseconds of host time since the machine was created, however
+    /// virtual time has been changed.
+    pub fn host_ms(&self) -> u64 {
+        self.host.elapsed_ms()
+    }
+
+    /// Like `host_ms()`, in microseconds.
+    pub fn host_us(&self) -> u64 {
+        self.host.elapsed_us()
+    }
+
+    /// Microseconds of virtual time since the machine was created.
+    pub fn now_us(&self) -> u64 {
+        Self::now(&self.state.lock().unwrap(), self.host.elapsed_us())
+    }
+
+    /// Stop virtual time. It only moves again with `advance_us()` or `unfreeze()`.
+    pub fn freeze(&self) {
+        self.update(|state| state.frozen = true);
+    }
+
+    /// Let virtual time follow the host's clock again.
+    pub fn unfreeze(&self) {
+        self.update(|state| state.frozen = false);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.state.lock().unwrap().frozen
+    }
+
+    /// Run virtual time `scale` times as fast as the host's clock.
+    pub fn set_scale(&self, scale: f64) {
+        assert!(scale >= 0.0, "time can't run backwards");
+        self.update(|state| state.scale = scale);
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.state.lock().unwrap().scale
+    }
+
+    /// Set virtual time to `us` microseconds. This is only for a machine that
+    /// hasn't started yet, before anything could have seen the time.
+    pub(super) fn start_at(&self, us: u64) {
+        self.update(|state| state.virtual_us = us);
+    }
+
+    /// Move virtual time forward by `us` microseconds, whether or not it's frozen.
+    pub fn advance_us(&self, us: u64) {
+        self.update(|state| state.virtual_us += us);
+    }
+}
+
+impl Platform for VirtualClock {
+    fn elapsed_ms(&self) -> u64 {
+        self.now_us() / 1000
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        self.now_us()
+    }
+
+    fn random_u32(&self) -> u32 {
+        self.host.random_u32()
+    }
+
+    fn write_stdout(&self, data: &[u8]) {
+        self.host.write_stdout(data)
+    }
+
+    fn write_stderr(&self, data: &[u8]) {
+        self.host.write_stderr(data)
+    }
+}
@@ -0,0 +1,36 @@
+//! A line for every instruction that retires, in the format of spike's
+//! `--log-commits`, so that scripts that diff spike runs against each other
+//! can diff a run under yove against one under spike. Each line has the
+//! hart, which yove sets to the thread ID, the privilege mode, the PC, the
+//! instruction, the registers and CSRs it wrote, and the addresses it loaded
+//! from and stored to.
+
+use std::io::Write;
+use std::sync::Mutex;
+
+use riscv_cpu::cpu::Commit;
+
+pub struct CommitLog {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl CommitLog {
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        CommitLog {
+            output: Mutex::new(output),
+        }
+    }
+
+    /// Write `commit` as a line of the log. Lines from different threads are
+    /// interleaved in the order the instructions ran.
+    pub(super) fn write(&self, commit: &Commit) {
+        if let Err(error) = writeln!(self.output.lock().unwrap(), "{}", commit) {
+            log::error!("unable to write to the commit log: {}", error);
+        }
+    }
+
+    /// Write out anything still buffered.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.output.lock().unwrap().flush()
+    }
+}
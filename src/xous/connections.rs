@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::services::Service;
+
+/// How the program found the service behind a connection.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Address {
+    /// With `Connect` or `TryConnect` and a server ID.
+    ServerId([u32; 4]),
+
+    /// By looking up a name with the name server.
+    Name(String),
+}
+
+/// A server ID as the sixteen characters of ASCII most of them are, if it
+/// is.
+fn ascii_name(id: &[u32; 4]) -> Option<String> {
+    let bytes: Vec<u8> = id.iter().flat_map(|word| word.to_le_bytes()).collect();
+    match String::from_utf8(bytes) {
+        Ok(name) if name.bytes().all(|byte| byte.is_ascii_graphic()) => Some(name),
+        _ => None,
+    }
+}
+
+fn hex_id(id: &[u32; 4]) -> String {
+    format!("{:08x}-{:08x}-{:08x}-{:08x}", id[0], id[1], id[2], id[3])
+}
+
+impl Address {
+    /// The name looked up or the server ID, without quotes.
+    fn name(&self) -> String {
+        match self {
+            Address::ServerId(id) => ascii_name(id).unwrap_or_else(|| hex_id(id)),
+            Address::Name(name) => name.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::ServerId(id) => match ascii_name(id) {
+                Some(name) => write!(f, "server {:?}", name),
+                None => write!(f, "server {}", hex_id(id)),
+            },
+            Address::Name(name) => write!(f, "{:?}", name),
+        }
+    }
+}
+
+struct Connection {
+    service: Arc<dyn Service + Send + Sync>,
+    address: Address,
+
+    /// How many times the connection has been made without being disconnected.
+    references: u32,
+
+    /// How many messages the service is holding without having responded.
+    queued: Arc<AtomicUsize>,
+}
+
+/// A message that a service is holding on to, which takes up one entry of
+/// its queue until it is dropped.
+pub(super) struct QueueSlot(Arc<AtomicUsize>);
+
+impl QueueSlot {
+    /// Take an entry in `queue`, or return `None` if all `depth` of them are
+    /// already taken.
+    fn take(queue: &Arc<AtomicUsize>, depth: usize) -> Option<Self> {
+        queue
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < depth).then_some(queued + 1)
+            })
+            .ok()?;
+        Some(QueueSlot(queue.clone()))
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The connections the program has open, shared by all of its threads.
+/// Connecting to the same service again returns the same connection ID, and
+/// the connection stays open until it has been disconnected as many times.
+#[derive(Default)]
+pub(super) struct Connections {
+    connections: HashMap<u32, Connection>,
+    addresses: HashMap<Address, u32>,
+}
+
+impl Connections {
+    /// Connect to the server with the given ID, calling `create` to start its
+    /// service if it isn't already connected. Returns the connection ID, or
+    /// `None` if `create` doesn't know of the server.
+    pub fn connect_server_id(
+        &mut self,
+        id: [u32; 4],
+        create: impl FnOnce() -> Option<Arc<dyn Service + Send + Sync>>,
+    ) -> Option<u32> {
+        self.connect(Address::ServerId(id), create)
+    }
+
+    /// Connect to the server registered with the name server as `name`.
+    pub fn connect_name(
+        &mut self,
+        name: &str,
+        create: impl FnOnce() -> Option<Arc<dyn Service + Send + Sync>>,
+    ) -> Option<u32> {
+        self.connect(Address::Name(name.to_owned()), create)
+    }
+
+    fn connect(
+        &mut self,
+        address: Address,
+        create: impl FnOnce() -> Option<Arc<dyn Service + Send + Sync>>,
+    ) -> Option<u32> {
+        if let Some(connection_id) = self.addresses.get(&address) {
+            let connection = self.connections.get_mut(connection_id).unwrap();
+            connection.references += 1;
+            return Some(*connection_id);
+        }
+        let service = create()?;
+        // Like the kernel, hand out the lowest free ID. Zero is never valid.
+        let connection_id = (1..).find(|id| !self.connections.contains_key(id)).unwrap();
+        self.addresses.insert(address.clone(), connection_id);
+        self.connections.insert(
+            connection_id,
+            Connection {
+                service,
+                address,
+                references: 1,
+                queued: Arc::new(AtomicUsize::new(0)),
+            },
+        );
+        Some(connection_id)
+    }
+
+    /// Drop one reference to a connection, closing it once none are left.
+    /// Returns `false` if the connection isn't open.
+    pub fn disconnect(&mut self, connection_id: u32) -> bool {
+        let Some(connection) = self.connections.get_mut(&connection_id) else {
+            return false;
+        };
+        connection.references -= 1;
+        if connection.references == 0 {
+            let connection = self.connections.remove(&connection_id).unwrap();
+            self.addresses.remove(&connection.address);
+        }
+        true
+    }
+
+    /// How many open references there are to the server registered as `name`.
+    pub fn name_references(&self, name: &str) -> u32 {
+        self.addresses
+            .get(&Address::Name(name.to_owned()))
+            .map_or(0, |id| self.connections[id].references)
+    }
+
+    pub fn service(&self, connection_id: u32) -> Option<Arc<dyn Service + Send + Sync>> {
+        self.connections
+            .get(&connection_id)
+            .map(|connection| connection.service.clone())
+    }
+
+    /// How the program found the service behind a connection, for messages
+    /// about it. Returns `None` if the connection isn't open.
+    pub fn describe(&self, connection_id: u32) -> Option<String> {
+        Some(self.connections.get(&connection_id)?.address.to_string())
+    }
+
+    /// The bare name of the service behind a connection, such as
+    /// `ticktimer-server`, for tables. Returns `None` if the connection
+    /// isn't open.
+    pub fn name(&self, connection_id: u32) -> Option<String> {
+        Some(self.connections.get(&connection_id)?.address.name())
+    }
+
+    /// Take an entry in the queue of the service behind a connection, which
+    /// holds at most `depth` messages. Returns `None` if the connection isn't
+    /// open or its queue is full.
+    pub fn queue_slot(&self, connection_id: u32, depth: usize) -> Option<QueueSlot> {
+        QueueSlot::take(&self.connections.get(&connection_id)?.queued, depth)
+    }
+
+    /// The names of the servers connected through the name server, with how
+    /// many open references there are to each.
+    pub fn names(&self) -> Vec<(String, u32)> {
+        self.connections
+            .values()
+            .filter_map(|connection| match &connection.address {
+                Address::Name(name) => Some((name.clone(), connection.references)),
+                Address::ServerId(_) => None,
+            })
+            .collect()
+    }
+
+    /// Every connected service.
+    pub fn services(&self) -> Vec<Arc<dyn Service + Send + Sync>> {
+        self.connections
+            .values()
+            .map(|connection| connection.service.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,67 @@
+/// Instructions per microsecond of the clock the `Deterministic` policy gives
+/// the guest, as if it ran at 100 MHz.
+const DETERMINISTIC_INSTRUCTIONS_PER_US: u64 = 100;
+
+/// What the guest reads from the `cycle`, `time`, and `instret` counters.
+/// User mode isn't given direct access to any of them, so every read traps
+/// to the emulator, which answers it according to this policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CounterPolicy {
+    /// The counters the hart keeps, with `time` following the guest's clock.
+    #[default]
+    Native,
+
+    /// Every counter follows the instructions the reading thread has
+    /// retired, so that the values are the same on every run: `cycle` and
+    /// `instret` count them, and `time` advances a microsecond for every
+    /// hundred.
+    Deterministic,
+
+    /// Reads are illegal instructions, as on a kernel that hides the
+    /// counters from user mode.
+    Trap,
+}
+
+impl std::str::FromStr for CounterPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "native" => Ok(CounterPolicy::Native),
+            "deterministic" => Ok(CounterPolicy::Deterministic),
+            "trap" => Ok(CounterPolicy::Trap),
+            _ => Err(format!("unknown counter policy {:?}", policy)),
+        }
+    }
+}
+
+impl CounterPolicy {
+    /// What the guest reads from the counter CSR `address`, which holds
+    /// `value`, when the reading thread has retired `instructions`.
+    pub(super) fn read(self, address: u16, value: u32, instructions: u64) -> Option<u32> {
+        use riscv_cpu::cpu::{
+            CSR_CYCLEH_ADDRESS, CSR_CYCLE_ADDRESS, CSR_INSTRETH_ADDRESS, CSR_INSTRET_ADDRESS,
+            CSR_TIMEH_ADDRESS, CSR_TIME_ADDRESS,
+        };
+        match self {
+            CounterPolicy::Native => Some(value),
+            CounterPolicy::Deterministic => {
+                let counter = match address {
+                    CSR_TIME_ADDRESS | CSR_TIMEH_ADDRESS => {
+                        instructions / DETERMINISTIC_INSTRUCTIONS_PER_US
+                    }
+                    CSR_CYCLE_ADDRESS | CSR_CYCLEH_ADDRESS | CSR_INSTRET_ADDRESS
+                    | CSR_INSTRETH_ADDRESS => instructions,
+                    // The hardware performance monitor counters don't count anything
+                    _ => 0,
+                };
+                Some(if address >= CSR_CYCLEH_ADDRESS {
+                    (counter >> 32) as u32
+                } else {
+                    counter as u32
+                })
+            }
+            CounterPolicy::Trap => None,
+        }
+    }
+}
@@ -11,6 +11,19 @@ pub enum Message {
     BlockingScalar = 4,
 }
 
+/// The regions of a process's address space that `SetMemRegion` can move.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MemoryType {
+    /// Where `MapMemory` places regions when the caller doesn't pick an
+    /// address.
+    Default = 1,
+
+    /// Where memory lent in messages is mapped.
+    Messages = 2,
+    Stack = 3,
+    Heap = 4,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum SyscallResultNumber {
     Ok = 0,
@@ -68,13 +81,14 @@ pub enum Syscall {
         i32, /* memory flags */
     ),
     MapMemory(
-        i32, /* address */
+        i32, /* physical address */
+        i32, /* virtual address */
         i32, /* size */
         i32, /* flags */
-        i32, /* name */
     ),
     Connect([u32; 4] /* Server ID */),
     TryConnect([u32; 4] /* Server ID */),
+    Disconnect(u32 /* Connection ID */),
     SendMessage(
         u32,      /* Connection ID */
         u32,      /* message kind */
@@ -92,6 +106,13 @@ pub enum Syscall {
         i32, /* range */
         i32, /* flags */
     ),
+    ReturnToParent(i32 /* process ID */, i32 /* CPU ID */),
+    SetMemRegion(
+        i32, /* process ID */
+        i32, /* memory type */
+        i32, /* address */
+        i32, /* size */
+    ),
     CreateThread(
         i32, /* entry point */
         i32, /* stack pointer */
@@ -102,9 +123,13 @@ pub enum Syscall {
         i32, /* argument 4 */
     ),
     JoinThread(i32 /* thread ID */),
+    VirtToPhys(i32 /* address */),
+    VirtToPhysPid(i32 /* process ID */, i32 /* address */),
     UnmapMemory(i32, /* address */ i32 /* size */),
     TerminateProcess(i32 /* Exit code */),
     GetProcessId,
+    WaitEvent,
+    ExitThread(i32 /* return value */),
 }
 
 #[derive(Debug)]
@@ -150,6 +175,11 @@ pub enum SyscallNumber {
     ReturnScalar = 40,
     ReplyAndReceiveNext = 41,
     VirtToPhysPid = 42,
+
+    /// Not part of Xous, whose kernel ends a thread when it faults on the
+    /// exit trampoline. Yove maps real code there instead, which makes this
+    /// call.
+    ExitThread = 64,
     Unknown = 0,
 }
 
@@ -171,6 +201,7 @@ impl From<[i32; 8]> for Syscall {
                 value[3] as u32,
                 value[4] as u32,
             ]),
+            SyscallNumber::Disconnect => Syscall::Disconnect(value[1] as u32),
             SyscallNumber::SendMessage => Syscall::SendMessage(
                 value[1] as u32,
                 value[2] as u32,
@@ -196,13 +227,21 @@ impl From<[i32; 8]> for Syscall {
             SyscallNumber::UpdateMemoryFlags => {
                 Syscall::UpdateMemoryFlags(value[1], value[2], value[3])
             }
+            SyscallNumber::ReturnToParent => Syscall::ReturnToParent(value[1], value[2]),
+            SyscallNumber::SetMemRegion => {
+                Syscall::SetMemRegion(value[1], value[2], value[3], value[4])
+            }
             SyscallNumber::CreateThread => Syscall::CreateThread(
                 value[1], value[2], value[3], value[4], value[5], value[6], value[7],
             ),
             SyscallNumber::Yield => Syscall::Yield,
             SyscallNumber::JoinThread => Syscall::JoinThread(value[1]),
+            SyscallNumber::VirtToPhys => Syscall::VirtToPhys(value[1]),
+            SyscallNumber::VirtToPhysPid => Syscall::VirtToPhysPid(value[1], value[2]),
             SyscallNumber::TerminateProcess => Syscall::TerminateProcess(value[1]),
             SyscallNumber::GetProcessId => Syscall::GetProcessId,
+            SyscallNumber::WaitEvent => Syscall::WaitEvent,
+            SyscallNumber::ExitThread => Syscall::ExitThread(value[1]),
             _ => Syscall::Unknown(value),
         }
     }
@@ -252,7 +291,82 @@ impl From<i32> for SyscallNumber {
             40 => SyscallNumber::ReturnScalar,
             41 => SyscallNumber::ReplyAndReceiveNext,
             42 => SyscallNumber::VirtToPhysPid,
+            64 => SyscallNumber::ExitThread,
             _ => SyscallNumber::Unknown,
         }
     }
 }
+
+impl TryFrom<i32> for MemoryType {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, i32> {
+        Ok(match value {
+            1 => MemoryType::Default,
+            2 => MemoryType::Messages,
+            3 => MemoryType::Stack,
+            4 => MemoryType::Heap,
+            _ => return Err(value),
+        })
+    }
+}
+
+impl TryFrom<i32> for SyscallResultNumber {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, i32> {
+        Ok(match value {
+            0 => SyscallResultNumber::Ok,
+            1 => SyscallResultNumber::Error,
+            3 => SyscallResultNumber::MemoryRange,
+            7 => SyscallResultNumber::ConnectionId,
+            9 => SyscallResultNumber::Message,
+            10 => SyscallResultNumber::ThreadId,
+            11 => SyscallResultNumber::ProcessId,
+            12 => SyscallResultNumber::Unimplemented,
+            14 => SyscallResultNumber::Scalar1,
+            15 => SyscallResultNumber::Scalar2,
+            18 => SyscallResultNumber::MemoryReturned,
+            20 => SyscallResultNumber::Scalar5,
+            _ => return Err(value),
+        })
+    }
+}
+
+impl TryFrom<i32> for SyscallErrorNumber {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, i32> {
+        Ok(match value {
+            0 => SyscallErrorNumber::NoError,
+            1 => SyscallErrorNumber::BadAlignment,
+            2 => SyscallErrorNumber::BadAddress,
+            3 => SyscallErrorNumber::OutOfMemory,
+            4 => SyscallErrorNumber::MemoryInUse,
+            5 => SyscallErrorNumber::InterruptNotFound,
+            6 => SyscallErrorNumber::InterruptInUse,
+            7 => SyscallErrorNumber::InvalidString,
+            8 => SyscallErrorNumber::ServerExists,
+            9 => SyscallErrorNumber::ServerNotFound,
+            10 => SyscallErrorNumber::ProcessNotFound,
+            11 => SyscallErrorNumber::ProcessNotChild,
+            12 => SyscallErrorNumber::ProcessTerminated,
+            13 => SyscallErrorNumber::Timeout,
+            14 => SyscallErrorNumber::InternalError,
+            15 => SyscallErrorNumber::ServerQueueFull,
+            16 => SyscallErrorNumber::ThreadNotAvailable,
+            17 => SyscallErrorNumber::UnhandledSyscall,
+            18 => SyscallErrorNumber::InvalidSyscall,
+            19 => SyscallErrorNumber::ShareViolation,
+            20 => SyscallErrorNumber::InvalidThread,
+            21 => SyscallErrorNumber::InvalidPID,
+            22 => SyscallErrorNumber::UnknownError,
+            23 => SyscallErrorNumber::AccessDenied,
+            24 => SyscallErrorNumber::UseBeforeInit,
+            25 => SyscallErrorNumber::DoubleFree,
+            26 => SyscallErrorNumber::DebugInProgress,
+            27 => SyscallErrorNumber::InvalidLimit,
+            _ => return Err(value),
+        })
+    }
+}
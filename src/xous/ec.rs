@@ -0,0 +1,145 @@
+//! The embedded controller of a Precursor, which looks after the battery,
+//! the charger, and the wifi chip, as seen through the COM server. The
+//! emulator has none of these, so the COM service reports whatever
+//! `EcStatus` it's given, which can come from a file and be changed while
+//! the program runs.
+
+use std::sync::Mutex;
+
+/// A wifi network seen by the last scan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ssid {
+    pub name: String,
+
+    /// Signal strength in dBm.
+    pub rssi: i8,
+}
+
+/// Everything the EC reports, along with the settings the program has made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EcStatus {
+    /// Battery voltage in millivolts.
+    pub voltage_mv: u16,
+
+    /// Average battery current in milliamps, negative while discharging.
+    pub current_ma: i16,
+
+    /// State of charge, in percent.
+    pub soc: u8,
+
+    /// Remaining battery capacity in milliamp hours.
+    pub remaining_mah: u16,
+
+    /// Whether the charger is charging the battery.
+    pub charging: bool,
+
+    /// Whether the wifi chip is powered.
+    pub wifi_on: bool,
+
+    /// Signal strength of the network the wifi chip is associated with, in dBm.
+    pub rssi: i8,
+
+    /// The networks the last scan found.
+    pub ssids: Vec<Ssid>,
+
+    /// The main and secondary backlight brightness last set by the program.
+    pub backlight: (u8, u8),
+
+    /// Whether the program has turned on the boost converter.
+    pub boost: bool,
+}
+
+impl Default for EcStatus {
+    /// A battery that's most of the way charged, discharging slowly.
+    fn default() -> Self {
+        EcStatus {
+            voltage_mv: 4100,
+            current_ma: -150,
+            soc: 80,
+            remaining_mah: 880,
+            charging: false,
+            wifi_on: true,
+            rssi: -50,
+            ssids: vec![],
+            backlight: (0, 0),
+            boost: false,
+        }
+    }
+}
+
+/// A status written one `<key> = <value>` setting per line, starting from the
+/// default. The keys are `voltage`, `current`, `soc`, `capacity`,
+/// `charging`, `wifi`, and `rssi`, and `ssid = <rssi> <name>` adds a network
+/// to the scan results. Anything after a `#` is a comment.
+impl std::str::FromStr for EcStatus {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        fn parse<T: std::str::FromStr>(line: usize, key: &str, value: &str) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("line {}: invalid {} {:?}", line, key, value))
+        }
+
+        let mut status = EcStatus::default();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("line {}: expected <key> = <value>", line_number));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "voltage" => status.voltage_mv = parse(line_number, key, value)?,
+                "current" => status.current_ma = parse(line_number, key, value)?,
+                "soc" => {
+                    status.soc = parse(line_number, key, value)?;
+                    if status.soc > 100 {
+                        return Err(format!("line {}: soc is over 100", line_number));
+                    }
+                }
+                "capacity" => status.remaining_mah = parse(line_number, key, value)?,
+                "charging" => status.charging = parse(line_number, key, value)?,
+                "wifi" => status.wifi_on = parse(line_number, key, value)?,
+                "rssi" => status.rssi = parse(line_number, key, value)?,
+                "ssid" => {
+                    let (rssi, name) = value.split_once(' ').unwrap_or((value, ""));
+                    status.ssids.push(Ssid {
+                        name: name.trim().to_owned(),
+                        rssi: parse(line_number, key, rssi)?,
+                    });
+                }
+                _ => return Err(format!("line {}: unknown key {:?}", line_number, key)),
+            }
+        }
+        Ok(status)
+    }
+}
+
+/// The emulated EC, shared between the COM service and the host.
+#[derive(Default)]
+pub struct Ec {
+    status: Mutex<EcStatus>,
+}
+
+impl Ec {
+    pub fn new(status: EcStatus) -> Self {
+        Ec {
+            status: Mutex::new(status),
+        }
+    }
+
+    /// What the EC reports right now.
+    pub fn status(&self) -> EcStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Change what the EC reports, such as to drain the battery or unplug
+    /// the charger while the program runs.
+    pub fn update(&self, change: impl FnOnce(&mut EcStatus)) {
+        change(&mut self.status.lock().unwrap());
+    }
+}
@@ -0,0 +1,287 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::{channel, Sender},
+    Mutex,
+};
+
+use super::definitions::{Syscall, SyscallErrorNumber, SyscallNumber, SyscallResultNumber};
+use super::rng::Rng;
+use super::services::{self, ResponseData};
+use super::SyscallResult;
+
+/// Which syscalls a fault rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultSite {
+    /// `SendMessage` and `TrySendMessage`
+    Send,
+
+    /// `MapMemory` and `IncreaseHeap`
+    Alloc,
+
+    /// Every syscall
+    Syscall,
+
+    /// One syscall, by number, named as `--strace` names it, such as
+    /// `IncreaseHeap`
+    Call(i32),
+}
+
+/// A fault that can be injected in place of the normal syscall behaviour.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Perform the syscall, but hold the response back for this many milliseconds.
+    Delay(u64),
+
+    /// Fail a message send with `ServerQueueFull`.
+    ServerQueueFull,
+
+    /// Fail an allocation with `OutOfMemory`.
+    OutOfMemory,
+
+    /// Report success for a non-blocking message without delivering it.
+    Drop,
+
+    /// Fail the syscall with this error number.
+    Error(i32),
+}
+
+/// A single fault injection rule, written as
+/// `<site>[,<condition>...]:<fault>[:<probability>[:<after-ms>]]`, for example
+/// `send:queue-full:0.05`, `syscall:delay=20:0.5:1000`, or
+/// `IncreaseHeap,nth=3:error=OutOfMemory`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    pub site: FaultSite,
+    pub fault: Fault,
+
+    /// Only the call with this number, counting from 1, of the calls the
+    /// rule matches, written `nth=<n>`.
+    pub nth: Option<u64>,
+
+    /// Only calls whose size is more than this many bytes, written
+    /// `size><bytes>`. See `size()` for what the size of a call is.
+    pub larger_than: Option<u32>,
+
+    /// Chance of the fault being injected each time the rule matches.
+    pub probability: f64,
+
+    /// Milliseconds after startup before the rule becomes active.
+    pub after_ms: u64,
+}
+
+impl std::str::FromStr for FaultRule {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut fields = spec.split(':');
+        let mut conditions = fields.next().unwrap_or_default().split(',');
+        let site = match conditions.next().unwrap_or_default() {
+            "send" => FaultSite::Send,
+            "alloc" => FaultSite::Alloc,
+            "syscall" => FaultSite::Syscall,
+            name => FaultSite::Call(
+                syscall_number(name)
+                    .ok_or_else(|| format!("unknown fault site {:?} in {:?}", name, spec))?,
+            ),
+        };
+        let (mut nth, mut larger_than) = (None, None);
+        for condition in conditions {
+            if let Some(n) = condition.strip_prefix("nth=") {
+                nth = Some(
+                    n.parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("invalid count in {:?}", spec))?,
+                );
+            } else if let Some(bytes) = condition.strip_prefix("size>") {
+                larger_than =
+                    Some(parse_number(bytes).ok_or_else(|| format!("invalid size in {:?}", spec))?);
+            } else {
+                return Err(format!("unknown condition {:?} in {:?}", condition, spec));
+            }
+        }
+        let fault = match fields.next() {
+            Some("queue-full") if site == FaultSite::Send => Fault::ServerQueueFull,
+            Some("drop") if site == FaultSite::Send => Fault::Drop,
+            Some("oom") if site == FaultSite::Alloc => Fault::OutOfMemory,
+            Some(delay) if delay.starts_with("delay=") => Fault::Delay(
+                delay["delay=".len()..]
+                    .parse()
+                    .map_err(|_| format!("invalid delay in {:?}", spec))?,
+            ),
+            Some(error) if error.starts_with("error=") => Fault::Error(
+                error_number(&error["error=".len()..])
+                    .ok_or_else(|| format!("unknown error in {:?}", spec))?,
+            ),
+            other => return Err(format!("invalid fault {:?} in {:?}", other, spec)),
+        };
+        let probability = match fields.next() {
+            Some(p) => p
+                .parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=1.0).contains(p))
+                .ok_or_else(|| format!("invalid probability in {:?}", spec))?,
+            None => 1.0,
+        };
+        let after_ms = match fields.next() {
+            Some(ms) => ms
+                .parse()
+                .map_err(|_| format!("invalid start time in {:?}", spec))?,
+            None => 0,
+        };
+        if fields.next().is_some() {
+            return Err(format!("too many fields in {:?}", spec));
+        }
+        Ok(FaultRule {
+            site,
+            fault,
+            nth,
+            larger_than,
+            probability,
+            after_ms,
+        })
+    }
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// The number of the syscall called `name`, such as `MapMemory`.
+fn syscall_number(name: &str) -> Option<i32> {
+    (0..=64).find(|&number| {
+        let known = SyscallNumber::from(number);
+        !matches!(known, SyscallNumber::Unknown) && format!("{:?}", known) == name
+    })
+}
+
+/// The error called `name`, such as `OutOfMemory`, or numbered `name`.
+fn error_number(name: &str) -> Option<i32> {
+    if let Ok(number) = name.parse() {
+        return Some(number);
+    }
+    (0..)
+        .map_while(|number| Some((number, SyscallErrorNumber::try_from(number).ok()?)))
+        .find(|(_, error)| format!("{:?}", error) == name)
+        .map(|(number, _)| number)
+}
+
+/// How many bytes `syscall` maps, unmaps, changes the flags of, or adds to
+/// the heap, if it's one of those.
+fn size(syscall: &Syscall) -> Option<u32> {
+    match *syscall {
+        Syscall::MapMemory(_, _, size, _)
+        | Syscall::UnmapMemory(_, size)
+        | Syscall::UpdateMemoryFlags(_, size, _)
+        | Syscall::IncreaseHeap(size, _) => Some(size as u32),
+        _ => None,
+    }
+}
+
+struct DelayedResponse {
+    deadline: u64,
+    response: Sender<ResponseData>,
+    result: [i32; 8],
+}
+
+/// Decides when to inject faults into syscalls. All randomness comes from a
+/// seeded generator, so a given seed produces the same sequence of decisions.
+pub struct FaultInjector {
+    rules: Vec<FaultRule>,
+
+    /// How many calls each rule has matched, for `FaultRule::nth`.
+    calls: Vec<AtomicU64>,
+    rng: Rng,
+    delayed: Mutex<Vec<DelayedResponse>>,
+}
+
+impl FaultInjector {
+    pub fn new(rules: Vec<FaultRule>, seed: u64) -> Self {
+        FaultInjector {
+            calls: rules.iter().map(|_| AtomicU64::new(0)).collect(),
+            rules,
+            rng: Rng::new(seed),
+            delayed: Mutex::new(vec![]),
+        }
+    }
+
+    /// Pick the fault, if any, to inject into `syscall`, which is syscall
+    /// number `number`, at time `now`.
+    pub fn select(&self, now: u64, number: i32, syscall: &Syscall) -> Option<Fault> {
+        let (site, blocking) = match syscall {
+            Syscall::SendMessage(_, kind, _, _) | Syscall::TrySendMessage(_, kind, _, _) => {
+                (Some(FaultSite::Send), *kind != 3 && *kind != 4)
+            }
+            Syscall::MapMemory(..) | Syscall::IncreaseHeap(..) => (Some(FaultSite::Alloc), false),
+            _ => (None, false),
+        };
+        let mut selected = None;
+        for (rule, calls) in self.rules.iter().zip(&self.calls) {
+            let matches = (rule.site == FaultSite::Syscall
+                || Some(rule.site) == site
+                || rule.site == FaultSite::Call(number))
+                // A dropped blocking message would leave the sender waiting forever
+                && !(rule.fault == Fault::Drop && blocking)
+                && now >= rule.after_ms
+                && rule
+                    .larger_than
+                    .is_none_or(|bytes| size(syscall).is_some_and(|size| size > bytes));
+            if !matches {
+                continue;
+            }
+            // Every rule counts the calls it matches, even once one of them
+            // has picked a fault, so that `nth` doesn't depend on the others
+            let call = calls.fetch_add(1, Ordering::Relaxed) + 1;
+            if selected.is_none()
+                && rule.nth.is_none_or(|nth| nth == call)
+                && self.rng.chance(rule.probability)
+            {
+                selected = Some(rule.fault);
+            }
+        }
+        selected
+    }
+
+    /// Apply a non-delay `fault`, returning the result the guest should see.
+    pub fn result_for(fault: Fault) -> SyscallResult {
+        let error = match fault {
+            Fault::ServerQueueFull => SyscallErrorNumber::ServerQueueFull as i32,
+            Fault::OutOfMemory => SyscallErrorNumber::OutOfMemory as i32,
+            Fault::Error(error) => error,
+            Fault::Drop => {
+                return [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into();
+            }
+            Fault::Delay(_) => unreachable!("delays are applied by `delay()`"),
+        };
+        [SyscallResultNumber::Error as i32, error, 0, 0, 0, 0, 0, 0].into()
+    }
+
+    /// Hold `result` back until `deadline`. Results that are already deferred
+    /// are passed through unchanged.
+    pub fn delay(&self, deadline: u64, result: SyscallResult) -> SyscallResult {
+        let SyscallResult::Ok(result) = result else {
+            return result;
+        };
+        let (tx, rx) = channel();
+        self.delayed.lock().unwrap().push(DelayedResponse {
+            deadline,
+            response: tx,
+            result,
+        });
+        services::wait_for(rx)
+    }
+
+    /// Release any delayed responses whose deadline has passed.
+    pub fn tick(&self, now: u64) {
+        self.delayed.lock().unwrap().retain(|delayed| {
+            if delayed.deadline > now {
+                return true;
+            }
+            delayed.response.send((delayed.result, None)).ok();
+            false
+        });
+    }
+}
@@ -0,0 +1,377 @@
+//! The SPI NOR flash that the PDDB keeps its data in, as seen through the
+//! `yove-flash` server. Like real NOR flash, programming can only clear bits and
+//! erasing sets a whole sector back to 0xff.
+//!
+//! To test how storage code recovers, faults can be injected into programs
+//! and erases, either at chosen offsets or at random with a seeded
+//! generator: a flipped bit, a write that stops part way through, or a power
+//! loss that stops the machine in the middle of a write. The flash keeps its
+//! contents when the machine stops, so it can be handed to a new machine with
+//! `MachineBuilder::flash` to see what the program makes of it after a reboot.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::rng::Rng;
+
+/// The size of an erasable sector, in bytes.
+pub const SECTOR: u32 = 4096;
+
+/// What `Flash::power_lost` holds while the power is on.
+const POWERED: u64 = u64::MAX;
+
+/// The size of the flash on a Precursor.
+pub const DEFAULT_FLASH_SIZE: u32 = 128 * 1024 * 1024;
+
+/// What goes wrong when a flash fault is injected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashFaultKind {
+    /// The write completes, but this bit of the byte at the fault reads back
+    /// flipped. `None` picks a bit at random.
+    BitFlip(Option<u8>),
+
+    /// The write stops at the fault, leaving everything from there on as it
+    /// was, though the program is told it succeeded.
+    TornWrite,
+
+    /// The power goes out at the fault: everything before it is written and
+    /// the machine stops with `YoveError::PowerLoss`.
+    PowerLoss,
+}
+
+/// Which writes a flash fault can happen in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashOperation {
+    Program,
+    Erase,
+}
+
+/// Where a flash fault happens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashTrigger {
+    /// The first time a write reaches this offset.
+    At(u32),
+
+    /// At a random offset in any write, with this probability.
+    Random(f64),
+}
+
+/// A flash fault, written as `[program:|erase:]<fault>@<offset>` or
+/// `[program:|erase:]<fault>[:<probability>]` where `<fault>` is
+/// `bit-flip[=<bit>]`, `torn-write`, or `power-loss`, for example
+/// `erase:power-loss@0x10000` or `bit-flip:0.01`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlashFault {
+    pub kind: FlashFaultKind,
+
+    /// The kind of write the fault happens in, or `None` for either.
+    pub operation: Option<FlashOperation>,
+    pub trigger: FlashTrigger,
+}
+
+impl std::str::FromStr for FlashFault {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (operation, fault) = if let Some(fault) = spec.strip_prefix("program:") {
+            (Some(FlashOperation::Program), fault)
+        } else if let Some(fault) = spec.strip_prefix("erase:") {
+            (Some(FlashOperation::Erase), fault)
+        } else {
+            (None, spec)
+        };
+        let (fault, trigger) = match fault.split_once('@') {
+            Some((fault, offset)) => {
+                let offset = match offset.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => offset.parse(),
+                }
+                .map_err(|_| format!("invalid offset in {:?}", spec))?;
+                (fault, FlashTrigger::At(offset))
+            }
+            None => match fault.split_once(':') {
+                Some((fault, probability)) => {
+                    let probability = probability
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|p| (0.0..=1.0).contains(p))
+                        .ok_or_else(|| format!("invalid probability in {:?}", spec))?;
+                    (fault, FlashTrigger::Random(probability))
+                }
+                None => (fault, FlashTrigger::Random(1.0)),
+            },
+        };
+        let kind = match fault {
+            "torn-write" => FlashFaultKind::TornWrite,
+            "power-loss" => FlashFaultKind::PowerLoss,
+            "bit-flip" => FlashFaultKind::BitFlip(None),
+            _ => match fault.strip_prefix("bit-flip=").map(str::parse) {
+                Some(Ok(bit @ 0..=7)) => FlashFaultKind::BitFlip(Some(bit)),
+                _ => return Err(format!("invalid flash fault {:?} in {:?}", fault, spec)),
+            },
+        };
+        Ok(FlashFault {
+            kind,
+            operation,
+            trigger,
+        })
+    }
+}
+
+/// A fault that was injected, and the offset it happened at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InjectedFault {
+    pub kind: FlashFaultKind,
+    pub offset: u32,
+}
+
+struct Faults {
+    /// Faults that haven't happened yet. Ones at an offset are removed once
+    /// they have.
+    pending: Vec<FlashFault>,
+    rng: Rng,
+    injected: Vec<InjectedFault>,
+}
+
+/// The emulated flash, which outlives the machine that uses it.
+pub struct Flash {
+    size: u32,
+
+    /// The sectors that have been written since they were last erased.
+    /// Every other sector reads as 0xff, and erasing one drops it from here
+    /// rather than filling it.
+    sectors: Mutex<BTreeMap<u32, Box<[u8]>>>,
+    faults: Mutex<Faults>,
+
+    /// Where the power went out, or `POWERED`. This is checked before every
+    /// instruction, so it's kept out of the locks.
+    power_lost: AtomicU64,
+}
+
+impl Flash {
+    /// An erased flash of `size` bytes, which is rounded up to a whole
+    /// number of sectors, or down if there's no room to round it up.
+    pub fn new(size: u32) -> Self {
+        Flash {
+            size: size
+                .checked_next_multiple_of(SECTOR)
+                .unwrap_or(size / SECTOR * SECTOR),
+            sectors: Mutex::new(BTreeMap::new()),
+            faults: Mutex::new(Faults {
+                pending: vec![],
+                rng: Rng::new(0),
+                injected: vec![],
+            }),
+            power_lost: AtomicU64::new(POWERED),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn in_range(&self, offset: u32, length: usize) -> bool {
+        (offset as u64 + length as u64) <= self.size as u64
+    }
+
+    /// Read `buf.len()` bytes starting at `offset`, returning `false` if
+    /// that runs past the end of the flash.
+    pub fn read(&self, offset: u32, buf: &mut [u8]) -> bool {
+        if !self.in_range(offset, buf.len()) {
+            return false;
+        }
+        let sectors = self.sectors.lock().unwrap();
+        for (index, byte) in buf.iter_mut().enumerate() {
+            let address = offset + index as u32;
+            *byte = sectors
+                .get(&(address / SECTOR))
+                .map_or(0xff, |sector| sector[(address % SECTOR) as usize]);
+        }
+        true
+    }
+
+    /// Set the bytes at `offset` to `data` directly, as a programmer
+    /// attached to the chip would, without injecting any faults.
+    pub fn load(&self, offset: u32, data: &[u8]) -> bool {
+        if !self.in_range(offset, data.len()) {
+            return false;
+        }
+        self.modify(offset, data.len(), |index, byte| *byte = data[index]);
+        true
+    }
+
+    /// Everything up to the end of the last sector that isn't erased, which
+    /// is all that needs saving to restore the flash with `load()`.
+    pub fn image(&self) -> Vec<u8> {
+        let end = self
+            .sectors
+            .lock()
+            .unwrap()
+            .keys()
+            .next_back()
+            .map_or(0, |last| (last + 1) * SECTOR);
+        let mut image = vec![0; end as usize];
+        self.read(0, &mut image);
+        image
+    }
+
+    /// Where the power went out part way through a write, if it did.
+    pub fn power_lost(&self) -> Option<u32> {
+        match self.power_lost.load(Ordering::Relaxed) {
+            POWERED => None,
+            offset => Some(offset as u32),
+        }
+    }
+
+    /// The faults injected so far, in the order they happened.
+    pub fn injected(&self) -> Vec<InjectedFault> {
+        self.faults.lock().unwrap().injected.clone()
+    }
+
+    /// Replace the faults to inject with `faults`, drawing any randomness
+    /// from `seed`, and restore the power, as when a new machine starts.
+    pub(super) fn power_on(&self, faults: Vec<FlashFault>, seed: u64) {
+        *self.faults.lock().unwrap() = Faults {
+            pending: faults,
+            rng: Rng::new(seed),
+            injected: vec![],
+        };
+        self.power_lost.store(POWERED, Ordering::Relaxed);
+    }
+
+    /// Apply `change` to each of the `length` bytes starting at `offset`,
+    /// which are already known to be in range.
+    fn modify(&self, offset: u32, length: usize, mut change: impl FnMut(usize, &mut u8)) {
+        let mut sectors = self.sectors.lock().unwrap();
+        for index in 0..length {
+            let address = offset + index as u32;
+            let sector = sectors
+                .entry(address / SECTOR)
+                .or_insert_with(|| vec![0xff; SECTOR as usize].into_boxed_slice());
+            change(index, &mut sector[(address % SECTOR) as usize]);
+        }
+    }
+
+    /// The fault, if any, that happens in a write of `length` bytes at
+    /// `offset`, and how far into the write it happens.
+    fn fault(
+        &self,
+        operation: FlashOperation,
+        offset: u32,
+        length: usize,
+    ) -> Option<(FlashFaultKind, usize)> {
+        let mut faults = self.faults.lock().unwrap();
+        let Faults {
+            pending,
+            rng,
+            injected,
+        } = &mut *faults;
+        let end = offset as u64 + length as u64;
+        let (index, at) = pending
+            .iter()
+            .enumerate()
+            .filter(|(_, fault)| fault.operation.is_none_or(|only| only == operation))
+            .find_map(|(index, fault)| match fault.trigger {
+                FlashTrigger::At(at) if (offset as u64..end).contains(&(at as u64)) => {
+                    Some((index, (at - offset) as usize))
+                }
+                FlashTrigger::Random(probability) if length > 0 && rng.chance(probability) => {
+                    Some((index, rng.below(length as u32) as usize))
+                }
+                _ => None,
+            })?;
+        let mut kind = pending[index].kind;
+        if let FlashFaultKind::BitFlip(None) = kind {
+            kind = FlashFaultKind::BitFlip(Some(rng.below(8) as u8));
+        }
+        if let FlashTrigger::At(_) = pending[index].trigger {
+            pending.remove(index);
+        }
+        injected.push(InjectedFault {
+            kind,
+            offset: offset + at as u32,
+        });
+        Some((kind, at))
+    }
+
+    /// Set the `length` bytes at `offset`, which are already known to be in
+    /// range, to 0xff. Sectors that end up fully erased are dropped.
+    fn erase_range(&self, offset: u32, length: u32) {
+        if length == 0 {
+            return;
+        }
+        let end = offset + length;
+        let mut sectors = self.sectors.lock().unwrap();
+        let written: Vec<u32> = sectors
+            .range(offset / SECTOR..=(end - 1) / SECTOR)
+            .map(|(&index, _)| index)
+            .collect();
+        for index in written {
+            let sector_start = index * SECTOR;
+            let start = offset.max(sector_start) - sector_start;
+            let stop = end.min(sector_start + SECTOR) - sector_start;
+            let sector = sectors.get_mut(&index).unwrap();
+            sector[start as usize..stop as usize].fill(0xff);
+            if sector.iter().all(|&byte| byte == 0xff) {
+                sectors.remove(&index);
+            }
+        }
+    }
+
+    /// Write `length` bytes at `offset` by calling `apply` with how many of
+    /// them to write, injecting whatever fault is due. Nothing is written
+    /// once the power has gone out.
+    fn write(
+        &self,
+        operation: FlashOperation,
+        offset: u32,
+        length: usize,
+        apply: impl FnOnce(usize),
+    ) {
+        if self.power_lost().is_some() {
+            return;
+        }
+        match self.fault(operation, offset, length) {
+            None => apply(length),
+            Some((FlashFaultKind::BitFlip(bit), at)) => {
+                apply(length);
+                let bit = bit.unwrap_or(0);
+                self.modify(offset + at as u32, 1, |_, byte| *byte ^= 1 << bit);
+            }
+            Some((FlashFaultKind::TornWrite, at)) => apply(at),
+            Some((FlashFaultKind::PowerLoss, at)) => {
+                apply(at);
+                self.power_lost
+                    .store((offset + at as u32) as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Program `data` at `offset`, which can only clear bits. Returns
+    /// `false` if that runs past the end of the flash.
+    pub(super) fn program(&self, offset: u32, data: &[u8]) -> bool {
+        if !self.in_range(offset, data.len()) {
+            return false;
+        }
+        self.write(FlashOperation::Program, offset, data.len(), |length| {
+            self.modify(offset, length, |index, byte| *byte &= data[index])
+        });
+        true
+    }
+
+    /// Erase the `length` bytes at `offset` to 0xff. Returns `false` unless
+    /// they're whole sectors within the flash.
+    pub(super) fn erase(&self, offset: u32, length: u32) -> bool {
+        if !offset.is_multiple_of(SECTOR)
+            || !length.is_multiple_of(SECTOR)
+            || !self.in_range(offset, length as usize)
+        {
+            return false;
+        }
+        self.write(FlashOperation::Erase, offset, length as usize, |length| {
+            self.erase_range(offset, length as u32)
+        });
+        true
+    }
+}
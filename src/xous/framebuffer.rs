@@ -0,0 +1,237 @@
+use std::ops::Range;
+use std::sync::Mutex;
+
+use super::Memory;
+
+/// The physical address the guest passes to `MapMemory` to map the display,
+/// where the Precursor's memory LCD buffer lives.
+pub const FRAMEBUFFER_ADDRESS: u32 = 0xb000_0000;
+
+/// Display width in pixels.
+pub const WIDTH: u32 = 336;
+
+/// Display height in lines.
+pub const HEIGHT: u32 = 536;
+
+/// Words per line. Each line has one more word than the pixels need, which
+/// the hardware uses for a dirty bit and which is ignored here.
+const STRIDE_WORDS: u32 = 11;
+
+/// Bytes the guest must map to cover the whole display.
+pub const FRAMEBUFFER_BYTES: u32 = HEIGHT * STRIDE_WORDS * 4;
+
+/// The contents of the display at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+
+    /// One entry per pixel, row by row, `true` where the pixel is light.
+    pixels: Vec<bool>,
+}
+
+impl Screenshot {
+    pub fn new(width: u32, height: u32, pixels: Vec<bool>) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize);
+        Screenshot {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Whether the pixel at `x`, `y` is light.
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// How many pixels differ from `other`, or `None` if they aren't the same size.
+    pub fn diff(&self, other: &Screenshot) -> Option<usize> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return None;
+        }
+        Some(
+            self.pixels
+                .iter()
+                .zip(&other.pixels)
+                .filter(|(a, b)| a != b)
+                .count(),
+        )
+    }
+
+    /// Write the screenshot as an 8-bit grayscale PNG.
+    #[cfg(feature = "png")]
+    pub fn write_png(&self, output: &mut impl std::io::Write) -> std::io::Result<()> {
+        let data: Vec<u8> = self
+            .pixels
+            .iter()
+            .map(|&light| if light { 0xff } else { 0 })
+            .collect();
+        let mut encoder = png::Encoder::new(output, self.width, self.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&data))
+            .map_err(std::io::Error::other)
+    }
+
+    /// Read a PNG, treating pixels whose first channel is at least half
+    /// brightness as light.
+    #[cfg(feature = "png")]
+    pub fn read_png(input: impl std::io::Read) -> std::io::Result<Self> {
+        let mut decoder = png::Decoder::new(input);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(std::io::Error::other)?;
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut data)
+            .map_err(std::io::Error::other)?;
+        let channels = info.color_type.samples();
+        let pixels = data[..info.buffer_size()]
+            .chunks(channels)
+            .map(|pixel| pixel[0] >= 0x80)
+            .collect();
+        Ok(Screenshot::new(info.width, info.height, pixels))
+    }
+
+    /// Compare against the golden image at `path`, panicking with the number
+    /// of differing pixels if they don't match. The screenshot is saved next
+    /// to the golden image as `<path>.actual.png` to make updating it easy.
+    /// If the golden image doesn't exist, or `YOVE_UPDATE_GOLDEN` is set, the
+    /// screenshot is written there instead.
+    #[cfg(feature = "png")]
+    pub fn assert_matches(&self, path: impl AsRef<std::path::Path>) {
+        let path = path.as_ref();
+        let write = |path: &std::path::Path| {
+            let mut output = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+            self.write_png(&mut output).unwrap();
+        };
+        if std::env::var_os("YOVE_UPDATE_GOLDEN").is_some() || !path.exists() {
+            write(path);
+            return;
+        }
+        let golden =
+            Screenshot::read_png(std::io::BufReader::new(std::fs::File::open(path).unwrap()))
+                .unwrap();
+        let difference = self.diff(&golden);
+        if difference != Some(0) {
+            let actual = path.with_extension("actual.png");
+            write(&actual);
+            match difference {
+                Some(pixels) => panic!(
+                    "screenshot differs from {} in {} pixels, see {}",
+                    path.display(),
+                    pixels,
+                    actual.display()
+                ),
+                None => panic!(
+                    "screenshot is {}x{}, but {} is {}x{}",
+                    self.width,
+                    self.height,
+                    path.display(),
+                    golden.width,
+                    golden.height
+                ),
+            }
+        }
+    }
+}
+
+/// Where the guest has mapped the display.
+struct Mapping {
+    /// The address space it's mapped in.
+    asid: u32,
+
+    /// The virtual addresses `MapMemory` handed out for it.
+    addresses: Range<u32>,
+}
+
+/// A monochrome display backed by guest memory, which the guest maps by
+/// asking `MapMemory` for `FRAMEBUFFER_ADDRESS`. Lines are `STRIDE_WORDS`
+/// words apart, with the leftmost pixel in the lowest bit of each word.
+pub struct Framebuffer {
+    /// Where the guest mapped the display, while it has.
+    mapping: Mutex<Option<Mapping>>,
+
+    /// Capture a screenshot every this many milliseconds, if set.
+    interval_ms: Option<u64>,
+    next_capture_ms: Mutex<u64>,
+    captures: Mutex<Vec<(u64, Screenshot)>>,
+}
+
+impl Framebuffer {
+    pub fn new(interval_ms: Option<u64>) -> Self {
+        Framebuffer {
+            mapping: Mutex::new(None),
+            interval_ms,
+            next_capture_ms: Mutex::new(0),
+            captures: Mutex::new(vec![]),
+        }
+    }
+
+    /// Record that the guest mapped the display at `addresses` of address
+    /// space `asid`. Returns `false` if it was already mapped.
+    pub(super) fn attach(&self, asid: u32, addresses: Range<u32>) -> bool {
+        let mut current = self.mapping.lock().unwrap();
+        if current.is_some() {
+            return false;
+        }
+        *current = Some(Mapping { asid, addresses });
+        true
+    }
+
+    /// Forget the mapping if any of it lies in `addresses` of address space
+    /// `asid`, which the guest is unmapping, so that screenshots don't read
+    /// pages that have been handed out again and the display can be mapped
+    /// once more.
+    pub(super) fn detach_unmapped(&self, asid: u32, addresses: &Range<u32>) {
+        let mut current = self.mapping.lock().unwrap();
+        let unmapped = current.as_ref().is_some_and(|mapping| {
+            mapping.asid == asid
+                && mapping.addresses.start < addresses.end
+                && addresses.start < mapping.addresses.end
+        });
+        if unmapped {
+            *current = None;
+        }
+    }
+
+    /// What the display shows now, or `None` if the guest hasn't mapped it.
+    pub(super) fn capture(&self, memory: &Memory) -> Option<Screenshot> {
+        let address = self.mapping.lock().unwrap().as_ref()?.addresses.start;
+        let mut pixels = Vec::with_capacity((WIDTH * HEIGHT) as usize);
+        for line in 0..HEIGHT {
+            let words: Vec<u32> = (0..WIDTH.div_ceil(32))
+                .map(|word| {
+                    let virt = address + (line * STRIDE_WORDS + word) * 4;
+                    memory
+                        .virt_to_phys(virt)
+                        .map_or(0, |phys| memory.peek_u32(phys))
+                })
+                .collect();
+            pixels.extend((0..WIDTH).map(|x| words[x as usize / 32] & (1 << (x % 32)) != 0));
+        }
+        Some(Screenshot::new(WIDTH, HEIGHT, pixels))
+    }
+
+    /// Take a screenshot if one is due.
+    pub(super) fn tick(&self, memory: &Memory, now_ms: u64) {
+        let Some(interval_ms) = self.interval_ms else {
+            return;
+        };
+        let mut next_capture_ms = self.next_capture_ms.lock().unwrap();
+        if now_ms < *next_capture_ms {
+            return;
+        }
+        *next_capture_ms = now_ms + interval_ms;
+        if let Some(screenshot) = self.capture(memory) {
+            self.captures.lock().unwrap().push((now_ms, screenshot));
+        }
+    }
+
+    /// The screenshots taken on the timer so far, with the time they were taken.
+    pub fn captures(&self) -> Vec<(u64, Screenshot)> {
+        self.captures.lock().unwrap().clone()
+    }
+}
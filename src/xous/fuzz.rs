@@ -0,0 +1,51 @@
+//! Drive the syscall shim straight from arbitrary bytes, without running
+//! any guest code, for the coverage-guided fuzz target in `fuzz/`. After
+//! every syscall the page tables are checked against the allocator, so the
+//! fuzzer finds syscalls that leave them corrupt as well as ones that panic.
+//! Inputs that found a bug are kept in `tests/fuzz/` and replayed by
+//! `tests/fuzz.rs`.
+//!
+//! An input is a run of records of `RECORD_SIZE` bytes, each of which makes
+//! one syscall. The first byte of a record picks an argument to point into
+//! a scratch region that's mapped before the first syscall, so that lends
+//! and memory syscalls reach mapped memory more often than chance would
+//! allow. If it's between 1 and 7, that argument is taken modulo
+//! `SCRATCH_SIZE` and added to the start of the region. The rest of the
+//! record is the eight argument registers, `a0` through `a7`, each as a
+//! little-endian `i32`. A partial record at the end is ignored.
+
+use riscv_cpu::syscall::SyscallBackend;
+
+use super::Machine;
+
+/// The size of the region that arguments can be pointed into.
+pub const SCRATCH_SIZE: u32 = 16 * 4096;
+
+/// How many bytes of input each syscall takes.
+pub const RECORD_SIZE: usize = 33;
+
+/// Make the syscalls in `input` as `machine`'s main thread, which shouldn't
+/// have run yet. Returns what's wrong with the page tables as soon as a
+/// syscall leaves them inconsistent.
+pub fn run(machine: &Machine, input: &[u8]) -> Result<(), String> {
+    let memory = &machine.memory;
+    let scratch = memory
+        .allocate_virt_region(SCRATCH_SIZE as usize)
+        .ok_or("no room for the scratch region")?;
+    for record in input.chunks_exact(RECORD_SIZE) {
+        let mut args = [0; 8];
+        for (arg, bytes) in args.iter_mut().zip(record[1..].chunks_exact(4)) {
+            *arg = i32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        if let Some(arg) = args.get_mut(record[0] as usize).filter(|_| record[0] != 0) {
+            *arg = (scratch + *arg as u32 % SCRATCH_SIZE) as i32;
+        }
+        // Whatever the syscall returns, including suspending the thread or
+        // ending the process, the fuzzer only cares that it came back
+        memory.syscall(args);
+        memory
+            .check_page_tables()
+            .map_err(|error| format!("after syscall {:x?}: {}", args, error))?;
+    }
+    Ok(())
+}
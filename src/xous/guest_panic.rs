@@ -0,0 +1,157 @@
+//! Guest panics, caught as they happen and reported as one structured error
+//! rather than as a nonzero exit code after whatever the panic handler
+//! managed to print.
+//!
+//! A panic is caught either when a thread reaches one of the functions every
+//! panic goes through, such as `rust_panic` or `abort`, or when the process
+//! terminates with a nonzero code after sending text to the panic-to-screen
+//! server. The text is the message, and the frame pointer chain gives the
+//! backtrace. If execution is being recorded, the last instructions the
+//! thread ran are kept too.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+use super::profiler::{self, Profiler, Symbol};
+use super::trace::ExecutionStep;
+use super::Memory;
+
+/// The functions that panics are caught in when no others are given.
+pub const DEFAULT_PANIC_SYMBOLS: &[&str] = &["rust_panic", "abort"];
+
+/// How many of the panicking thread's most recent instructions are kept.
+const RECENT_STEPS: usize = 32;
+
+/// A function in a backtrace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub address: u32,
+
+    /// The function the address is in and how far into it, if the symbol
+    /// table says.
+    pub function: Option<(String, u32)>,
+}
+
+/// A panic caught in the guest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuestPanic {
+    pub tid: i32,
+    pub pc: u32,
+
+    /// What the panic handler sent to the panic-to-screen server, or which
+    /// function it was caught in if it sent nothing.
+    pub message: String,
+
+    /// The panicking thread's stack, innermost first.
+    pub backtrace: Vec<Frame>,
+
+    /// The last instructions the thread ran, oldest first, if execution was
+    /// being recorded.
+    pub recent: Vec<ExecutionStep>,
+}
+
+/// Where panics are caught, and what the panic handler has said so far.
+pub(super) struct PanicCatcher {
+    names: Vec<String>,
+
+    /// The functions panics are caught in, by address.
+    hooks: RwLock<HashMap<u32, String>>,
+
+    /// The program's symbols, sorted by address, for naming frames.
+    symbols: RwLock<Vec<Symbol>>,
+    text: Mutex<String>,
+}
+
+impl PanicCatcher {
+    pub fn new(names: Vec<String>) -> Self {
+        PanicCatcher {
+            names,
+            hooks: RwLock::default(),
+            symbols: RwLock::default(),
+            text: Mutex::default(),
+        }
+    }
+
+    /// Find the functions panics are caught in. Programs only link the panic
+    /// paths they use, so it's fine for some of them not to be there.
+    pub fn set_symbols(&self, symbols: &[Symbol]) {
+        let mut hooks = self.hooks.write().unwrap();
+        for symbol in symbols {
+            if self.names.contains(&symbol.name) {
+                hooks.insert(symbol.address, symbol.name.clone());
+            }
+        }
+        let mut sorted = symbols.to_vec();
+        sorted.sort_by_key(|symbol| symbol.address);
+        *self.symbols.write().unwrap() = sorted;
+    }
+
+    /// The name of the function at `pc`, if panics are caught there.
+    pub fn hook_at(&self, pc: u32) -> Option<String> {
+        self.hooks.read().unwrap().get(&pc).cloned()
+    }
+
+    /// Note text the panic handler sent to the panic-to-screen server.
+    pub fn append_text(&self, text: &str) {
+        self.text.lock().unwrap().push_str(text);
+    }
+
+    /// Whether the panic handler has sent any text.
+    pub fn panicking(&self) -> bool {
+        !self.text.lock().unwrap().is_empty()
+    }
+
+    /// Describe the panic of thread `tid` at `pc`, whose stack is `stack`.
+    /// `hook` is the function it was caught in, if it was caught in one.
+    pub fn capture(
+        &self,
+        memory: &Memory,
+        tid: i32,
+        pc: u32,
+        stack: Vec<u32>,
+        hook: Option<String>,
+    ) -> GuestPanic {
+        let text = std::mem::take(&mut *self.text.lock().unwrap());
+        let message = match (text.trim(), hook) {
+            ("", Some(hook)) => format!("reached {}", hook),
+            (text, _) => text.to_owned(),
+        };
+        let symbols = self.symbols.read().unwrap();
+        let backtrace = stack
+            .into_iter()
+            .map(|address| Frame {
+                address,
+                function: Profiler::symbolize(&symbols, address)
+                    .map(|(index, offset)| (symbols[index].name.clone(), offset)),
+            })
+            .collect();
+        let mut recent: Vec<ExecutionStep> = memory
+            .tracer
+            .as_ref()
+            .map(|tracer| tracer.execution())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|step| step.tid == tid)
+            .collect();
+        recent.drain(..recent.len().saturating_sub(RECENT_STEPS));
+        GuestPanic {
+            tid,
+            pc,
+            message,
+            backtrace,
+            recent,
+        }
+    }
+}
+
+/// The stack of a thread at `pc` whose return address and frame pointer
+/// registers hold `ra` and `fp`. `entry` says whether `pc` is the first
+/// instruction of a function, which hasn't saved `ra` in a frame yet.
+pub(super) fn stack(memory: &Memory, pc: u32, ra: u32, fp: u32, entry: bool) -> Vec<u32> {
+    let mut stack = vec![pc];
+    if entry && ra != 0 {
+        stack.push(ra - 1);
+    }
+    profiler::walk_frames(memory, fp, &mut stack);
+    stack
+}
@@ -0,0 +1,142 @@
+//! Running guest threads on a fixed number of host threads, for
+//! `MachineBuilder::harts`. Each hart takes the next thread off a shared run
+//! queue, runs it for a quantum, and puts it back at the end. A thread that is
+//! waiting on a response or stopped in `wfi` gives up its hart straight away,
+//! and a hart that finds nothing able to run sleeps until a new thread arrives
+//! or it's time to look again.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::pause::PauseControl;
+use super::{Worker, WorkerEvent, YoveError, IDLE_POLL_INTERVAL};
+
+/// How many instructions a thread runs before it goes back on the queue.
+const QUANTUM: usize = 1000;
+
+#[derive(Default)]
+struct RunQueue {
+    workers: Mutex<VecDeque<Worker>>,
+
+    /// Signalled when a new thread is queued.
+    added: Condvar,
+
+    /// Set once the machine has stopped, to send the harts home.
+    stopping: AtomicBool,
+}
+
+/// The harts running a machine's threads. Dropping this stops them once
+/// they've finished the quantum they're on.
+pub(super) struct Harts {
+    queue: Arc<RunQueue>,
+}
+
+impl Harts {
+    /// Start `count` harts. Whatever ends the process is sent to `exit`.
+    pub fn start(
+        count: usize,
+        pause: &Arc<PauseControl>,
+        exit: &Sender<Result<u32, YoveError>>,
+    ) -> Self {
+        let queue = Arc::new(RunQueue::default());
+        for hart in 0..count {
+            let queue = queue.clone();
+            let pause = pause.clone();
+            let exit = exit.clone();
+            std::thread::Builder::new()
+                .name(format!("hart {}", hart))
+                .spawn(move || run(hart as u32, &queue, &pause, &exit))
+                .expect("couldn't start a host thread");
+        }
+        Harts { queue }
+    }
+
+    /// Queue a new thread to be run.
+    pub fn push(&self, worker: Worker) {
+        self.queue.workers.lock().unwrap().push_back(worker);
+        self.queue.added.notify_one();
+    }
+}
+
+impl Drop for Harts {
+    fn drop(&mut self) {
+        self.queue.stopping.store(true, Ordering::SeqCst);
+        self.queue.added.notify_all();
+    }
+}
+
+fn run(hart: u32, queue: &RunQueue, pause: &PauseControl, exit: &Sender<Result<u32, YoveError>>) {
+    pause.enter();
+    // Threads taken off the queue in a row that couldn't run
+    let mut idle = 0;
+    while let Some(mut worker) = next(queue, pause, &mut idle) {
+        worker.cpu.switch_hart(hart);
+        let (event, progress) = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            quantum(&mut worker, pause)
+        })) {
+            Ok(result) => result,
+            Err(payload) => (worker.contain_panic(payload), true),
+        };
+        idle = if progress { 0 } else { idle + 1 };
+        let result = match event {
+            WorkerEvent::Ran | WorkerEvent::Blocked => {
+                queue.workers.lock().unwrap().push_back(worker);
+                continue;
+            }
+            WorkerEvent::Exited(val) if worker.tid == 0 => Ok(val),
+            WorkerEvent::Exited(_) => continue,
+            WorkerEvent::Terminated(val) => Ok(val),
+            WorkerEvent::Failed(error) => Err(error),
+        };
+        exit.send(result).ok();
+    }
+    pause.leave();
+}
+
+/// Take the next thread off the queue, or `None` once the machine has
+/// stopped. If every queued thread has been tried since one last made
+/// progress, or there are none, sleep a while first rather than spin.
+fn next(queue: &RunQueue, pause: &PauseControl, idle: &mut usize) -> Option<Worker> {
+    loop {
+        if queue.stopping.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut workers = queue.workers.lock().unwrap();
+        if *idle < workers.len() {
+            if let Some(worker) = workers.pop_front() {
+                return Some(worker);
+            }
+        }
+        // A sleeping hart doesn't hold up a pause
+        pause.leave();
+        drop(
+            queue
+                .added
+                .wait_timeout(workers, IDLE_POLL_INTERVAL)
+                .unwrap(),
+        );
+        pause.enter();
+        *idle = 0;
+    }
+}
+
+/// Run `worker` for up to `QUANTUM` instructions, returning why it stopped
+/// and whether it got anything done.
+fn quantum(worker: &mut Worker, pause: &PauseControl) -> (WorkerEvent, bool) {
+    let mut progress = false;
+    for _ in 0..QUANTUM {
+        pause.checkpoint();
+        match worker.step() {
+            // `wfi` only ends when an interrupt line is raised, which is
+            // checked each time the thread gets a hart
+            WorkerEvent::Ran if worker.cpu.waiting_for_interrupt() => {
+                return (WorkerEvent::Blocked, progress)
+            }
+            WorkerEvent::Ran => progress = true,
+            event => return (event, progress),
+        }
+    }
+    (WorkerEvent::Ran, progress)
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const PAGE_SIZE: u32 = 4096;
+
+/// Number of pages per row when drawing the heatmap as an image.
+#[cfg(feature = "png")]
+const PNG_WIDTH: u32 = 64;
+
+/// The kind of memory access.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Default)]
+struct PageCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    executes: AtomicU64,
+
+    /// One bit for each thread that has touched this page, by thread ID modulo 64.
+    threads: AtomicU64,
+}
+
+/// Output formats understood by `Heatmap::write`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeatmapFormat {
+    /// One line per page that was accessed.
+    Csv,
+
+    /// The same data as `Csv`, plus a working set summary.
+    Json,
+
+    /// One pixel per physical page, with reads in green, writes in red, and
+    /// instruction fetches in blue.
+    #[cfg(feature = "png")]
+    Png,
+}
+
+impl HeatmapFormat {
+    /// Pick the format from a file's extension.
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        match path.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("csv") => Ok(HeatmapFormat::Csv),
+            Some("json") => Ok(HeatmapFormat::Json),
+            #[cfg(feature = "png")]
+            Some("png") => Ok(HeatmapFormat::Png),
+            #[cfg(not(feature = "png"))]
+            Some("png") => Err("yove was built without the `png` feature".to_owned()),
+            _ => Err(format!(
+                "can't tell the heatmap format of {:?}, use .csv or .json",
+                path
+            )),
+        }
+    }
+}
+
+/// Counts reads, writes, and instruction fetches for every physical page.
+pub struct Heatmap {
+    base: u32,
+    pages: Vec<PageCounters>,
+}
+
+impl Heatmap {
+    pub fn new(base: u32, size: usize) -> Self {
+        let pages = (0..size.div_ceil(PAGE_SIZE as usize))
+            .map(|_| PageCounters::default())
+            .collect();
+        Heatmap { base, pages }
+    }
+
+    /// Count an access to physical address `address` by thread `tid`.
+    pub(super) fn record(&self, address: u32, access: Access, tid: i32) {
+        let Some(page) = self
+            .pages
+            .get((address.wrapping_sub(self.base) / PAGE_SIZE) as usize)
+        else {
+            return;
+        };
+        let counter = match access {
+            Access::Read => &page.reads,
+            Access::Write => &page.writes,
+            Access::Execute => &page.executes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        // Avoid bouncing the cache line between threads once the bit is set
+        let bit = 1 << (tid as u32 % 64);
+        if page.threads.load(Ordering::Relaxed) & bit == 0 {
+            page.threads.fetch_or(bit, Ordering::Relaxed);
+        }
+    }
+
+    /// Pages that have been accessed at least once, as
+    /// `(physical address, reads, writes, executes, threads)`.
+    fn touched_pages(&self) -> impl Iterator<Item = (u32, u64, u64, u64, u32)> + '_ {
+        self.pages.iter().enumerate().filter_map(|(index, page)| {
+            let reads = page.reads.load(Ordering::Relaxed);
+            let writes = page.writes.load(Ordering::Relaxed);
+            let executes = page.executes.load(Ordering::Relaxed);
+            if reads + writes + executes == 0 {
+                return None;
+            }
+            let threads = page.threads.load(Ordering::Relaxed).count_ones();
+            let address = self.base + index as u32 * PAGE_SIZE;
+            Some((address, reads, writes, executes, threads))
+        })
+    }
+
+    /// Write the counters in `format`. `mappings` maps physical pages to the
+    /// virtual address they are mapped at, if any.
+    pub fn write(
+        &self,
+        mappings: &HashMap<u32, u32>,
+        format: HeatmapFormat,
+        output: &mut impl Write,
+    ) -> std::io::Result<()> {
+        match format {
+            HeatmapFormat::Csv => self.write_csv(mappings, output),
+            HeatmapFormat::Json => self.write_json(mappings, output),
+            #[cfg(feature = "png")]
+            HeatmapFormat::Png => self.write_png(output),
+        }
+    }
+
+    fn write_csv(
+        &self,
+        mappings: &HashMap<u32, u32>,
+        output: &mut impl Write,
+    ) -> std::io::Result<()> {
+        writeln!(output, "physical,virtual,reads,writes,executes,threads")?;
+        for (address, reads, writes, executes, threads) in self.touched_pages() {
+            let virt = mappings
+                .get(&address)
+                .map(|virt| format!("{:08x}", virt))
+                .unwrap_or_default();
+            writeln!(
+                output,
+                "{:08x},{},{},{},{},{}",
+                address, virt, reads, writes, executes, threads
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_json(
+        &self,
+        mappings: &HashMap<u32, u32>,
+        output: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let mut working_set = 0;
+        let mut shared = 0;
+        writeln!(output, "{{")?;
+        writeln!(output, "  \"page_size\": {},", PAGE_SIZE)?;
+        write!(output, "  \"pages\": [")?;
+        for (address, reads, writes, executes, threads) in self.touched_pages() {
+            if working_set != 0 {
+                write!(output, ",")?;
+            }
+            let virt = mappings
+                .get(&address)
+                .map(|virt| format!("\"{:08x}\"", virt))
+                .unwrap_or_else(|| "null".to_owned());
+            write!(
+                output,
+                "\n    {{\"physical\": \"{:08x}\", \"virtual\": {}, \"reads\": {}, \
+                 \"writes\": {}, \"executes\": {}, \"threads\": {}}}",
+                address, virt, reads, writes, executes, threads
+            )?;
+            working_set += 1;
+            if threads > 1 && writes > 0 {
+                shared += 1;
+            }
+        }
+        writeln!(output, "\n  ],")?;
+        writeln!(output, "  \"working_set_pages\": {},", working_set)?;
+        writeln!(
+            output,
+            "  \"working_set_bytes\": {},",
+            working_set * PAGE_SIZE as u64
+        )?;
+        writeln!(output, "  \"shared_written_pages\": {}", shared)?;
+        writeln!(output, "}}")
+    }
+
+    #[cfg(feature = "png")]
+    fn write_png(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let width = PNG_WIDTH;
+        let height = (self.pages.len() as u32).div_ceil(width);
+
+        // Scale logarithmically, otherwise a few hot pages wash everything else out
+        let counts = |page: &PageCounters| {
+            [
+                page.writes.load(Ordering::Relaxed),
+                page.reads.load(Ordering::Relaxed),
+                page.executes.load(Ordering::Relaxed),
+            ]
+        };
+        let max = self.pages.iter().flat_map(counts).max().unwrap_or(0).max(1);
+        let scale = |count: u64| ((count as f64).ln_1p() / (max as f64).ln_1p() * 255.0) as u8;
+
+        let mut pixels = vec![0; (width * height) as usize * 3];
+        for (page, pixel) in self.pages.iter().zip(pixels.chunks_mut(3)) {
+            for (channel, count) in pixel.iter_mut().zip(counts(page)) {
+                *channel = scale(count);
+            }
+        }
+
+        let mut encoder = png::Encoder::new(output, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(std::io::Error::other)
+    }
+}
@@ -0,0 +1,101 @@
+//! Host commands the program may run through the yove-host-exec server, so
+//! that an end-to-end test can have the guest start and stop fixtures on
+//! the host. Nothing can be run unless it's on the allowlist, which maps the
+//! name the guest asks for to the host program it runs. Commands are run
+//! directly rather than through a shell, and every request, whether it was
+//! run or refused, is logged and kept for the host to inspect.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// What became of a request to run a command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecOutcome {
+    /// The command isn't on the allowlist, so nothing was run.
+    Refused,
+
+    /// The host program couldn't be started.
+    Failed(String),
+
+    /// The command ran and exited with this code, or was killed by a signal
+    /// if there isn't one.
+    Exited(Option<i32>),
+}
+
+/// A request the program made, as kept in the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecRecord {
+    /// The thread that asked.
+    pub tid: u32,
+
+    /// The command's name and its arguments.
+    pub argv: Vec<String>,
+    pub outcome: ExecOutcome,
+}
+
+#[derive(Default)]
+pub struct HostExec {
+    /// The host program each command the guest may run starts, by name.
+    allowed: BTreeMap<String, PathBuf>,
+
+    /// Every request, in the order they finished.
+    audit: Mutex<Vec<ExecRecord>>,
+}
+
+impl HostExec {
+    pub fn new(allowed: BTreeMap<String, PathBuf>) -> Self {
+        HostExec {
+            allowed,
+            audit: Mutex::new(vec![]),
+        }
+    }
+
+    /// Run the command `argv[0]` with the rest of `argv` as its arguments on
+    /// behalf of thread `tid`, and return its exit code and what it wrote
+    /// to stdout. Its stdin is empty and its stderr is the emulator's.
+    pub(super) fn run(&self, tid: u32, argv: Vec<String>) -> (ExecOutcome, Vec<u8>) {
+        let Some(program) = argv.first().and_then(|name| self.allowed.get(name)) else {
+            log::warn!("thread {} may not run host command {:?}", tid, argv);
+            self.record(tid, argv, ExecOutcome::Refused);
+            return (ExecOutcome::Refused, vec![]);
+        };
+        log::info!(
+            "thread {} is running host command {:?} as {}",
+            tid,
+            argv,
+            program.display()
+        );
+        let output = Command::new(program)
+            .args(&argv[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output();
+        let (outcome, stdout) = match output {
+            Ok(output) => (ExecOutcome::Exited(output.status.code()), output.stdout),
+            Err(error) => (ExecOutcome::Failed(error.to_string()), vec![]),
+        };
+        log::info!(
+            "host command {:?} for thread {} finished: {:?}",
+            argv,
+            tid,
+            outcome
+        );
+        self.record(tid, argv, outcome.clone());
+        (outcome, stdout)
+    }
+
+    fn record(&self, tid: u32, argv: Vec<String>, outcome: ExecOutcome) {
+        self.audit
+            .lock()
+            .unwrap()
+            .push(ExecRecord { tid, argv, outcome });
+    }
+
+    /// Every command the program has asked to run, in the order they
+    /// finished.
+    pub fn audit(&self) -> Vec<ExecRecord> {
+        self.audit.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,312 @@
+//! The Betrusted keyboard, as seen through the keyboard server. The host
+//! presses keys either one at a time or by running a key script, which is
+//! how interactive sessions are driven in UI tests.
+//!
+//! A script has one step to a line, and `#` starts a comment:
+//!
+//! ```text
+//! # Wait for the menu, then pick the second entry
+//! wait-log Ready
+//! key down enter
+//! # Type a name slowly, and submit it five seconds into the run
+//! pace 50
+//! type hello, world
+//! sleep 200
+//! @5000 key f1
+//! ```
+//!
+//! `key` presses keys, each either named, such as `enter` or `up`, or a
+//! single character. `type` types the rest of the line, including any `#`,
+//! leaving the number of milliseconds set by the last `pace` between
+//! characters. `sleep` does nothing for a while, and `wait-log` waits until
+//! the program logs or prints the rest of the line. It only looks at output
+//! since the previous `wait-log` matched, so a message is never matched
+//! twice. Any step may start with `@<ms>`, which holds it until that many
+//! milliseconds of virtual time after the machine started.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+
+use super::definitions::SyscallResultNumber;
+use super::services::{ResponseData, ScalarResult};
+
+/// The named keys, and the characters the keyboard server reports them as.
+const NAMED_KEYS: &[(&str, char)] = &[
+    ("enter", '\r'),
+    ("backspace", '\u{8}'),
+    ("tab", '\t'),
+    ("space", ' '),
+    ("up", '↑'),
+    ("down", '↓'),
+    ("left", '←'),
+    ("right", '→'),
+    ("select", '∴'),
+    ("f1", '\u{11}'),
+    ("f2", '\u{12}'),
+    ("f3", '\u{13}'),
+    ("f4", '\u{14}'),
+];
+
+/// The key called `name`, which is one of the named keys or a single
+/// character.
+pub fn key(name: &str) -> Result<char, String> {
+    if let Some(&(_, key)) = NAMED_KEYS.iter().find(|(named, _)| *named == name) {
+        return Ok(key);
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(key), None) => Ok(key),
+        _ => Err(format!("unknown key {:?}", name)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Keys(Vec<char>),
+    Type(Vec<char>),
+    Pace(u64),
+    Sleep(u64),
+    WaitLog(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    /// The time the step is held until, in milliseconds since the machine
+    /// started.
+    at: Option<u64>,
+    action: Action,
+}
+
+/// A parsed key script, ready for `MachineBuilder::key_script`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KeyScript {
+    steps: Vec<Step>,
+}
+
+fn milliseconds(text: &str, line: usize) -> Result<u64, String> {
+    text.trim()
+        .parse()
+        .map_err(|_| format!("line {}: expected milliseconds, not {:?}", line, text))
+}
+
+impl KeyScript {
+    pub fn parse(script: &str) -> Result<Self, String> {
+        let mut steps = vec![];
+        for (index, line) in script.lines().enumerate() {
+            let number = index + 1;
+            let mut rest = line.trim_start();
+            let mut at = None;
+            if let Some(timed) = rest.strip_prefix('@') {
+                let (time, after) = timed.split_once(' ').unwrap_or((timed, ""));
+                at = Some(milliseconds(time, number)?);
+                rest = after.trim_start();
+            }
+            let (command, argument) = rest.split_once(' ').unwrap_or((rest, ""));
+            // `type` keeps everything after it, so only cut comments elsewhere
+            let uncommented = argument.split('#').next().unwrap().trim();
+            let action = match command {
+                _ if command.is_empty() || command.starts_with('#') => continue,
+                "key" => Action::Keys(
+                    uncommented
+                        .split_whitespace()
+                        .map(key)
+                        .collect::<Result<_, _>>()
+                        .map_err(|error| format!("line {}: {}", number, error))?,
+                ),
+                "type" => Action::Type(argument.chars().collect()),
+                "pace" => Action::Pace(milliseconds(uncommented, number)?),
+                "sleep" => Action::Sleep(milliseconds(uncommented, number)?),
+                "wait-log" => Action::WaitLog(argument.trim().to_owned()),
+                _ => return Err(format!("line {}: unknown step {:?}", number, command)),
+            };
+            steps.push(Step { at, action });
+        }
+        Ok(KeyScript { steps })
+    }
+}
+
+/// Where a running script has got to.
+struct ScriptRun {
+    steps: VecDeque<Step>,
+
+    /// How many characters of the current `type` step have been typed.
+    typed: usize,
+
+    /// The gap between typed characters, in milliseconds.
+    pace: u64,
+
+    /// Nothing more happens before this time, in milliseconds since the
+    /// machine started.
+    not_before: u64,
+
+    /// What the program has logged since the last `wait-log` matched,
+    /// less what no `wait-log` step that's left could match.
+    log: String,
+}
+
+impl ScriptRun {
+    /// Forget the output that the next `wait-log` step can't match: all of
+    /// it if there's no such step, or all but the end that could be the
+    /// start of its text if the text isn't there yet.
+    fn trim_log(&mut self) {
+        let next = self.steps.iter().find_map(|step| match &step.action {
+            Action::WaitLog(text) => Some(text),
+            _ => None,
+        });
+        let keep = match next {
+            None => 0,
+            Some(text) if !self.log.contains(text.as_str()) => text.len() - 1,
+            Some(_) => return,
+        };
+        let mut start = self.log.len().saturating_sub(keep);
+        while !self.log.is_char_boundary(start) {
+            start -= 1;
+        }
+        self.log.drain(..start);
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Keys pressed that the program hasn't read yet, oldest first.
+    pressed: VecDeque<char>,
+
+    /// Threads waiting for a key, longest waiting first.
+    listening: VecDeque<Sender<ResponseData>>,
+    script: Option<ScriptRun>,
+}
+
+/// The emulated keyboard, shared between the keyboard server and the host.
+#[derive(Default)]
+pub struct Keyboard {
+    state: Mutex<State>,
+}
+
+fn key_response(key: char) -> ResponseData {
+    (
+        [
+            SyscallResultNumber::Scalar1 as i32,
+            key as i32,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ],
+        None,
+    )
+}
+
+impl State {
+    fn press(&mut self, key: char) {
+        // Threads that have gone away leave senders nobody is listening to
+        while let Some(listening) = self.listening.pop_front() {
+            if listening.send(key_response(key)).is_ok() {
+                return;
+            }
+        }
+        self.pressed.push_back(key);
+    }
+}
+
+impl Keyboard {
+    /// Press and release `key`, as the keyboard server reports it.
+    pub fn press(&self, key: char) {
+        self.state.lock().unwrap().press(key);
+    }
+
+    /// Press the keys that type `text`, one after another.
+    pub fn type_text(&self, text: &str) {
+        let mut state = self.state.lock().unwrap();
+        for key in text.chars() {
+            state.press(key);
+        }
+    }
+
+    /// How many keys have been pressed that the program hasn't read.
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().pressed.len()
+    }
+
+    /// Start running `script`, replacing any script that's still running.
+    pub fn run_script(&self, script: KeyScript) {
+        self.state.lock().unwrap().script = Some(ScriptRun {
+            steps: script.steps.into(),
+            typed: 0,
+            pace: 0,
+            not_before: 0,
+            log: String::new(),
+        });
+    }
+
+    /// How many steps of the script are left, including the one it's on.
+    pub fn script_steps_left(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.script.as_ref().map_or(0, |run| run.steps.len())
+    }
+
+    /// Return the oldest key the program hasn't read, or wait for one.
+    pub(super) fn listen(&self) -> ScalarResult {
+        let mut state = self.state.lock().unwrap();
+        if let Some(key) = state.pressed.pop_front() {
+            return ScalarResult::Scalar1(key as u32);
+        }
+        let (tx, rx) = channel();
+        state.listening.push_back(tx);
+        ScalarResult::WaitForResponse(rx)
+    }
+
+    /// Note output from the program, for a script waiting on it.
+    pub(super) fn log(&self, output: &[u8]) {
+        if let Some(run) = self.state.lock().unwrap().script.as_mut() {
+            run.log.push_str(&String::from_utf8_lossy(output));
+            run.trim_log();
+        }
+    }
+
+    /// Carry the script on as far as it can go at `now_ms`.
+    pub(super) fn tick(&self, now_ms: u64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(run) = state.script.as_mut() else {
+            return;
+        };
+        let mut keys = vec![];
+        while let Some(step) = run.steps.front() {
+            if now_ms < run.not_before || now_ms < step.at.unwrap_or(0) {
+                break;
+            }
+            match &step.action {
+                Action::Keys(step_keys) => keys.extend(step_keys),
+                Action::Type(text) => {
+                    if let Some(&key) = text.get(run.typed) {
+                        keys.push(key);
+                        run.typed += 1;
+                        run.not_before = now_ms + run.pace;
+                        if run.typed < text.len() {
+                            continue;
+                        }
+                    }
+                    run.typed = 0;
+                }
+                &Action::Pace(pace) => run.pace = pace,
+                &Action::Sleep(duration) => run.not_before = now_ms + duration,
+                Action::WaitLog(text) => {
+                    let Some(found) = run.log.find(text.as_str()) else {
+                        break;
+                    };
+                    run.log.drain(..found + text.len());
+                }
+            }
+            run.steps.pop_front();
+            run.trim_log();
+        }
+        if run.steps.is_empty() {
+            state.script = None;
+        }
+        for key in keys {
+            state.press(key);
+        }
+    }
+}
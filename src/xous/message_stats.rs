@@ -0,0 +1,186 @@
+//! How often the program sends each opcode to each server, and how long the
+//! servers take to answer, so that the IPC calls that dominate a run under
+//! emulation stand out. Latency is host time from the send until the sender
+//! is resumed with the reply, so it includes time spent waiting on other
+//! threads for messages they answer.
+//!
+//! Latencies are kept in a histogram rather than one by one, so a long run
+//! costs no more memory than a short one. Each power of two is split into
+//! eight buckets, so the percentiles are within an eighth of the latencies
+//! they stand for, and the total and maximum are exact.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// The calls to one opcode of one server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpcodeStats {
+    /// The server, by the name it was connected with, such as
+    /// `ticktimer-server`.
+    pub service: String,
+    pub opcode: u32,
+    pub calls: u64,
+    pub total_us: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// A message whose sender is waiting for the reply, as the server and
+/// opcode it went to and when it was sent.
+type Pending = ((String, u32), u64);
+
+/// How many buckets each power of two is split into, as a shift.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// The latencies of the calls to one opcode of one server.
+#[derive(Default)]
+struct Histogram {
+    calls: u64,
+    total_us: u64,
+    max_us: u64,
+
+    /// How many calls took the latencies in each bucket, from the first
+    /// bucket up to the highest that's been used.
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    /// The bucket `latency_us` is counted in. Latencies below
+    /// `SUB_BUCKETS` get a bucket each.
+    fn bucket(latency_us: u64) -> usize {
+        if latency_us < SUB_BUCKETS {
+            return latency_us as usize;
+        }
+        let shift = latency_us.ilog2() - SUB_BUCKET_BITS;
+        ((shift as u64 + 1) * SUB_BUCKETS + ((latency_us >> shift) - SUB_BUCKETS)) as usize
+    }
+
+    /// The highest latency counted in `bucket`.
+    fn highest(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS {
+            return bucket;
+        }
+        let shift = bucket / SUB_BUCKETS - 1;
+        let lowest = (SUB_BUCKETS + bucket % SUB_BUCKETS) << shift;
+        lowest + ((1 << shift) - 1)
+    }
+
+    fn record(&mut self, latency_us: u64) {
+        self.calls += 1;
+        self.total_us = self.total_us.saturating_add(latency_us);
+        self.max_us = self.max_us.max(latency_us);
+        let bucket = Self::bucket(latency_us);
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    /// The nearest-rank percentile `fraction`, as the highest latency of
+    /// the bucket it falls in, or the maximum if that's lower.
+    fn percentile(&self, fraction: f64) -> u64 {
+        let rank = ((self.calls as f64 * fraction).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::highest(bucket).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+}
+
+#[derive(Default)]
+pub struct MessageStats {
+    /// The latencies of the calls, in microseconds, by server and opcode.
+    latencies: Mutex<BTreeMap<(String, u32), Histogram>>,
+
+    /// Messages not yet answered, by the thread that sent them.
+    pending: Mutex<HashMap<i32, Pending>>,
+}
+
+impl MessageStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a message to `opcode` of `service` that was answered in
+    /// `latency_us`.
+    pub(super) fn record(&self, service: String, opcode: u32, latency_us: u64) {
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry((service, opcode))
+            .or_default()
+            .record(latency_us);
+    }
+
+    /// Note that thread `tid` sent `opcode` to `service` at `sent_us` and is
+    /// waiting for the reply.
+    pub(super) fn wait(&self, tid: i32, service: String, opcode: u32, sent_us: u64) {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(tid, ((service, opcode), sent_us));
+    }
+
+    /// Record the reply to the message thread `tid` was waiting on, if it
+    /// was waiting on one, arriving at `now_us`.
+    pub(super) fn reply(&self, tid: i32, now_us: u64) {
+        let pending = self.pending.lock().unwrap().remove(&tid);
+        if let Some(((service, opcode), sent_us)) = pending {
+            self.record(service, opcode, now_us.saturating_sub(sent_us));
+        }
+    }
+
+    /// Every server and opcode that was called, the one the program spent
+    /// longest waiting on first.
+    pub fn report(&self) -> Vec<OpcodeStats> {
+        let latencies = self.latencies.lock().unwrap();
+        let mut report: Vec<OpcodeStats> = latencies
+            .iter()
+            .map(|((service, opcode), histogram)| OpcodeStats {
+                service: service.clone(),
+                opcode: *opcode,
+                calls: histogram.calls,
+                total_us: histogram.total_us,
+                p50_us: histogram.percentile(0.5),
+                p90_us: histogram.percentile(0.9),
+                p99_us: histogram.percentile(0.99),
+                max_us: histogram.max_us,
+            })
+            .collect();
+        report.sort_by_key(|stats| std::cmp::Reverse(stats.total_us));
+        report
+    }
+
+    /// Write `report()` as a table, with latencies in microseconds.
+    pub fn write(&self, output: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            output,
+            "{:<40} {:>8} {:>10} {:>12} {:>10} {:>10} {:>10} {:>10}",
+            "server", "opcode", "calls", "total", "p50", "p90", "p99", "max"
+        )?;
+        for stats in self.report() {
+            writeln!(
+                output,
+                "{:<40} {:>8} {:>10} {:>12} {:>10} {:>10} {:>10} {:>10}",
+                stats.service,
+                stats.opcode,
+                stats.calls,
+                stats.total_us,
+                stats.p50_us,
+                stats.p90_us,
+                stats.p99_us,
+                stats.max_us
+            )?;
+        }
+        Ok(())
+    }
+}
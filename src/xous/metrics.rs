@@ -0,0 +1,222 @@
+//! Health metrics for a machine that runs for a long time as a service, such
+//! as a remote test worker, served over HTTP in the Prometheus text format.
+//! Counters only ever go up, so rates such as instructions or messages per
+//! second are left to the scraper, which takes them between two scrapes.
+//!
+//! The server answers `GET /metrics` on a listener of the host's choosing
+//! from a thread of its own, so scraping never holds the machine up. The
+//! thread stops when the machine is dropped.
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::definitions::Syscall;
+use super::Memory;
+
+/// How long a scraper has to send its request before it's dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the server checks whether it's been stopped while nobody is
+/// connecting.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The counters that are only kept while metrics are being served. Gauges,
+/// such as how many threads are running, are read from the machine when
+/// it's scraped.
+#[derive(Default)]
+pub struct Metrics {
+    syscalls: AtomicU64,
+    messages: AtomicU64,
+    page_faults: AtomicU64,
+    traps: AtomicU64,
+}
+
+impl Metrics {
+    pub(super) fn syscall(&self, syscall: &Syscall) {
+        self.syscalls.fetch_add(1, Ordering::Relaxed);
+        if matches!(
+            syscall,
+            Syscall::SendMessage(..) | Syscall::TrySendMessage(..)
+        ) {
+            self.messages.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a page fault, whether or not demand paging served it.
+    pub(super) fn page_fault(&self) {
+        self.page_faults.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a trap that stopped a thread.
+    pub(super) fn trap(&self) {
+        self.traps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Everything there is to know about `memory`'s machine, in the
+    /// Prometheus text format.
+    fn render(&self, memory: &Memory) -> String {
+        let threads = memory.threads.lock().unwrap();
+        let instructions: u64 = threads
+            .values()
+            .map(|account| account.instructions.load(Ordering::Relaxed))
+            .sum();
+        let metrics = [
+            (
+                "yove_instructions_retired_total",
+                "counter",
+                "Instructions retired by every guest thread.",
+                instructions,
+            ),
+            (
+                "yove_guest_threads",
+                "gauge",
+                "Guest threads that haven't exited.",
+                memory.live_threads.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "yove_guest_threads_started_total",
+                "counter",
+                "Guest threads started, including the main thread.",
+                threads.len() as u64,
+            ),
+            (
+                "yove_memory_allocated_bytes",
+                "gauge",
+                "Guest RAM allocated, including page tables.",
+                memory.allocated_bytes.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "yove_syscalls_total",
+                "counter",
+                "Syscalls made by the guest.",
+                self.syscalls.load(Ordering::Relaxed),
+            ),
+            (
+                "yove_messages_total",
+                "counter",
+                "Messages sent by the guest.",
+                self.messages.load(Ordering::Relaxed),
+            ),
+            (
+                "yove_page_faults_total",
+                "counter",
+                "Page faults, including those demand paging served.",
+                self.page_faults.load(Ordering::Relaxed),
+            ),
+            (
+                "yove_traps_total",
+                "counter",
+                "Traps that stopped a guest thread.",
+                self.traps.load(Ordering::Relaxed),
+            ),
+            (
+                "yove_virtual_time_microseconds",
+                "gauge",
+                "The guest's clock.",
+                memory.clock.now_us(),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            writeln!(text, "# HELP {} {}", name, help).unwrap();
+            writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(text, "{} {}", name, value).unwrap();
+        }
+        text
+    }
+}
+
+/// Answer one request on `stream`.
+fn respond(metrics: &Metrics, memory: &Memory, stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers, which don't change the answer
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render(memory)),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Only GET is allowed\n".to_owned()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Serves a machine's metrics from a thread of its own until it's stopped.
+pub(super) struct MetricsServer {
+    /// The only strong reference to what the thread reads, so that the
+    /// thread never keeps the machine's memory alive by itself.
+    _memory: Arc<Memory>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Serve `memory`'s metrics to everyone who connects to `listener`, one
+    /// at a time.
+    pub fn start(listener: TcpListener, memory: Memory) -> std::io::Result<Self> {
+        let metrics = memory.metrics.clone().expect("metrics aren't being kept");
+        listener.set_nonblocking(true)?;
+        let memory = Arc::new(memory);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (memory, shutdown) = (Arc::downgrade(&memory), shutdown.clone());
+            std::thread::spawn(move || accept(listener, &metrics, memory, &shutdown))
+        };
+        Ok(MetricsServer {
+            _memory: memory,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop accepting connections, and wait for the thread to finish
+    /// answering the one it's on, if any.
+    pub fn stop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn accept(listener: TcpListener, metrics: &Metrics, memory: Weak<Memory>, shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(error) => {
+                log::warn!("couldn't serve metrics: {}", error);
+                continue;
+            }
+        };
+        let Some(memory) = memory.upgrade() else {
+            break;
+        };
+        let result = stream
+            .set_nonblocking(false)
+            .and_then(|()| respond(metrics, &memory, stream));
+        if let Err(error) = result {
+            log::warn!("couldn't serve metrics: {}", error);
+        }
+    }
+}
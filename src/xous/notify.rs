@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+
+use super::definitions::SyscallResultNumber;
+use super::services::{self, ResponseData};
+use super::SyscallResult;
+
+/// How many notifications are held for the guest before `post` turns any
+/// more away.
+const QUEUE_LIMIT: usize = 64;
+
+/// The message kind of a scalar, as numbered by `SendMessage`.
+const SCALAR: i32 = 4;
+
+/// A scalar message that a service or device sends to the guest without the
+/// guest asking for it first, such as a timer firing or a key being pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Notification {
+    pub opcode: u32,
+    pub args: [u32; 4],
+}
+
+impl Notification {
+    /// What `WaitEvent` returns to deliver this: the envelope of a scalar
+    /// message, from sender 0.
+    fn result(&self) -> [i32; 8] {
+        [
+            SyscallResultNumber::Message as i32,
+            0,
+            SCALAR,
+            self.opcode as i32,
+            self.args[0] as i32,
+            self.args[1] as i32,
+            self.args[2] as i32,
+            self.args[3] as i32,
+        ]
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Notifications no thread has waited for yet, oldest first.
+    notifications: VecDeque<Notification>,
+
+    /// Threads waiting in `WaitEvent`, longest waiting first.
+    waiting: VecDeque<Sender<ResponseData>>,
+}
+
+/// Lets services and host-side devices reach the guest asynchronously, rather
+/// than only by replying to its messages. Notifications go to a thread
+/// waiting in the `WaitEvent` syscall, or wait for one if there are none.
+/// Interrupt lines are raised in every guest thread's `mip`, and wake any of
+/// them that are stopped in `wfi`.
+///
+/// Both are safe to use from any host thread while the machine runs.
+#[derive(Default)]
+pub struct Notifier {
+    queue: Mutex<Queue>,
+
+    /// The `mip` bits that are asserted.
+    lines: AtomicU32,
+}
+
+impl Notifier {
+    /// Deliver `notification` to the thread that has waited longest in
+    /// `WaitEvent`, or queue it for the next thread that waits. Returns
+    /// `false` if the queue is full and the notification was dropped.
+    pub fn post(&self, notification: Notification) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        // Threads that have gone away leave senders nobody is listening to
+        while let Some(waiting) = queue.waiting.pop_front() {
+            if waiting.send((notification.result(), None)).is_ok() {
+                return true;
+            }
+        }
+        if queue.notifications.len() >= QUEUE_LIMIT {
+            return false;
+        }
+        queue.notifications.push_back(notification);
+        true
+    }
+
+    /// How many notifications are queued, waiting for a thread to wait.
+    pub fn queued(&self) -> usize {
+        self.queue.lock().unwrap().notifications.len()
+    }
+
+    /// Assert the interrupt lines `bits`, which are `mip` bits such as
+    /// `riscv_cpu::cpu::MIP_SEIP`. They stay asserted until `lower`.
+    pub fn raise(&self, bits: u32) {
+        self.lines.fetch_or(bits, Ordering::SeqCst);
+    }
+
+    /// Deassert the interrupt lines `bits`.
+    pub fn lower(&self, bits: u32) {
+        self.lines.fetch_and(!bits, Ordering::SeqCst);
+    }
+
+    /// The interrupt lines that are asserted.
+    pub fn lines(&self) -> u32 {
+        self.lines.load(Ordering::SeqCst)
+    }
+
+    /// Handle `WaitEvent`, returning the oldest queued notification or
+    /// suspending the caller until one is posted.
+    pub(super) fn wait(&self) -> SyscallResult {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(notification) = queue.notifications.pop_front() {
+            return notification.result().into();
+        }
+        let (tx, rx) = channel();
+        queue.waiting.push_back(tx);
+        services::wait_for(rx)
+    }
+}
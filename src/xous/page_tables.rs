@@ -0,0 +1,238 @@
+//! The guest's two-level Sv32 page tables, written out as text for reading,
+//! JSON for scripts, or a Graphviz graph of the root table, the level 0
+//! tables it points to, and the runs of pages they map.
+
+use std::io::Write;
+
+use super::definitions::memoryflags::MemoryFlags;
+
+/// Output formats understood by `Machine::dump_mmu`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MmuFormat {
+    /// Every table and page, one to a line, indented by level.
+    Text,
+
+    /// The root table's address, and every entry of every table.
+    Json,
+
+    /// A Graphviz digraph from the root table through the level 0 tables to
+    /// runs of pages mapped to contiguous memory with the same flags.
+    Dot,
+}
+
+impl MmuFormat {
+    /// Pick the format from a file's extension.
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        match path.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("txt") => Ok(MmuFormat::Text),
+            Some("json") => Ok(MmuFormat::Json),
+            Some("dot" | "gv") => Ok(MmuFormat::Dot),
+            _ => Err(format!(
+                "can't tell the page table format of {:?}, use .txt, .json, or .dot",
+                path
+            )),
+        }
+    }
+}
+
+/// A valid entry of the root page table.
+pub(super) struct Table {
+    /// The index of the entry, which maps the 4MB from `vpn1 << 22`.
+    pub vpn1: u32,
+    pub entry: u32,
+
+    /// The valid entries of the level 0 table it points to, by index, or
+    /// nothing if the entry is a megapage.
+    pub pages: Vec<(u32, u32)>,
+}
+
+impl Table {
+    fn is_megapage(&self) -> bool {
+        self.entry & (MemoryFlags::READ | MemoryFlags::WRITE | MemoryFlags::EXECUTE).bits() as u32
+            != 0
+    }
+
+    fn virt(&self) -> u32 {
+        self.vpn1 << 22
+    }
+}
+
+/// The physical address an entry points to.
+fn phys(entry: u32) -> u32 {
+    (entry >> 10) << 12
+}
+
+/// An entry's flags as `VRWXUGAD`, with a `-` for each that's clear.
+fn flag_letters(entry: u32) -> String {
+    "VRWXUGAD"
+        .chars()
+        .enumerate()
+        .map(|(bit, letter)| if entry & (1 << bit) != 0 { letter } else { '-' })
+        .collect()
+}
+
+fn flag_names(entry: u32) -> MemoryFlags {
+    MemoryFlags::from_bits(entry as usize & 0xff).unwrap()
+}
+
+/// Write the tables under the root table at `root`.
+pub(super) fn write(
+    root: u32,
+    tables: &[Table],
+    format: MmuFormat,
+    output: &mut impl Write,
+) -> std::io::Result<()> {
+    match format {
+        MmuFormat::Text => write_text(root, tables, output),
+        MmuFormat::Json => write_json(root, tables, output),
+        MmuFormat::Dot => write_dot(root, tables, output),
+    }
+}
+
+fn write_text(root: u32, tables: &[Table], output: &mut impl Write) -> std::io::Result<()> {
+    writeln!(output, "Root page table @ {:08x}", root)?;
+    for table in tables {
+        let kind = if table.is_megapage() {
+            "Megapage"
+        } else {
+            "Superpage"
+        };
+        writeln!(
+            output,
+            "    {:4} {} {:08x} -> {:08x} (flags: {})",
+            table.vpn1,
+            kind,
+            table.virt(),
+            phys(table.entry),
+            flag_names(table.entry)
+        )?;
+        for &(vpn0, entry) in &table.pages {
+            writeln!(
+                output,
+                "        {:4} {:08x} -> {:08x} (flags: {})",
+                vpn0,
+                table.virt() | vpn0 << 12,
+                phys(entry),
+                flag_names(entry)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_json(root: u32, tables: &[Table], output: &mut impl Write) -> std::io::Result<()> {
+    writeln!(output, "{{")?;
+    writeln!(output, "  \"root\": \"{:08x}\",", root)?;
+    write!(output, "  \"tables\": [")?;
+    for (index, table) in tables.iter().enumerate() {
+        write!(
+            output,
+            "{}\n    {{\"vpn1\": {}, \"virt\": \"{:08x}\", \"phys\": \"{:08x}\", \
+             \"flags\": \"{}\", \"megapage\": {}, \"pages\": [",
+            if index == 0 { "" } else { "," },
+            table.vpn1,
+            table.virt(),
+            phys(table.entry),
+            flag_letters(table.entry),
+            table.is_megapage()
+        )?;
+        for (index, &(vpn0, entry)) in table.pages.iter().enumerate() {
+            write!(
+                output,
+                "{}\n      {{\"vpn0\": {}, \"virt\": \"{:08x}\", \"phys\": \"{:08x}\", \"flags\": \"{}\"}}",
+                if index == 0 { "" } else { "," },
+                vpn0,
+                table.virt() | vpn0 << 12,
+                phys(entry),
+                flag_letters(entry)
+            )?;
+        }
+        if table.pages.is_empty() {
+            write!(output, "]}}")?;
+        } else {
+            write!(output, "\n    ]}}")?;
+        }
+    }
+    writeln!(output, "\n  ]")?;
+    writeln!(output, "}}")
+}
+
+/// Split the pages of a level 0 table into runs that are next to each other
+/// in both virtual and physical memory and have the same flags, as the first
+/// index and entry of each run and how many pages it has.
+fn runs(pages: &[(u32, u32)]) -> Vec<(u32, u32, u32)> {
+    let mut runs: Vec<(u32, u32, u32)> = vec![];
+    for &(vpn0, entry) in pages {
+        if let Some((start, first, count)) = runs.last_mut() {
+            if *start + *count == vpn0
+                && phys(*first) + *count * 4096 == phys(entry)
+                && *first & 0x3ff == entry & 0x3ff
+            {
+                *count += 1;
+                continue;
+            }
+        }
+        runs.push((vpn0, entry, 1));
+    }
+    runs
+}
+
+fn write_dot(root: u32, tables: &[Table], output: &mut impl Write) -> std::io::Result<()> {
+    writeln!(output, "digraph page_tables {{")?;
+    writeln!(output, "  rankdir=LR;")?;
+    writeln!(output, "  node [shape=box, fontname=monospace];")?;
+    writeln!(output, "  root [label=\"root table\\n{:08x}\"];", root)?;
+    for table in tables {
+        let end = table.virt().wrapping_add(1 << 22).wrapping_sub(1);
+        if table.is_megapage() {
+            writeln!(
+                output,
+                "  t{} [label=\"megapage\\n{:08x}-{:08x}\\n-> {:08x}\\n{}\", style=filled];",
+                table.vpn1,
+                table.virt(),
+                end,
+                phys(table.entry),
+                flag_letters(table.entry)
+            )?;
+            writeln!(
+                output,
+                "  root -> t{} [label=\"{}\"];",
+                table.vpn1, table.vpn1
+            )?;
+            continue;
+        }
+        writeln!(
+            output,
+            "  t{} [label=\"level 0 table\\n{:08x}\\n{:08x}-{:08x}\\n{} pages\"];",
+            table.vpn1,
+            phys(table.entry),
+            table.virt(),
+            end,
+            table.pages.len()
+        )?;
+        writeln!(
+            output,
+            "  root -> t{} [label=\"{}\"];",
+            table.vpn1, table.vpn1
+        )?;
+        for (start, entry, count) in runs(&table.pages) {
+            let virt = table.virt() | start << 12;
+            writeln!(
+                output,
+                "  p{:08x} [label=\"{:08x}-{:08x}\\n-> {:08x}\\n{} pages, {}\", shape=ellipse];",
+                virt,
+                virt,
+                virt + count * 4096 - 1,
+                phys(entry),
+                count,
+                flag_letters(entry)
+            )?;
+            writeln!(
+                output,
+                "  t{} -> p{:08x} [label=\"{}\"];",
+                table.vpn1, virt, start
+            )?;
+        }
+    }
+    writeln!(output, "}}")
+}
@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::clock::VirtualClock;
+
+struct PauseState {
+    paused: bool,
+
+    /// Guest threads that are executing instructions, as opposed to waiting
+    /// here or on a response.
+    running: usize,
+
+    /// Whether pausing froze the clock, so resuming should thaw it.
+    froze_clock: bool,
+}
+
+/// Stops every guest thread at an instruction boundary and holds it there
+/// until the machine is resumed, so that its state can be inspected or saved
+/// while nothing changes underneath. The guest's clock is frozen while the
+/// machine is paused, so that its timeouts don't expire all at once when it
+/// resumes.
+///
+/// Threads waiting on a service or on another thread count as stopped. If
+/// their response arrives while the machine is paused, they wait here before
+/// running any further.
+pub struct PauseControl {
+    /// Set while a pause is wanted, checked by every thread before each
+    /// instruction without taking the lock.
+    requested: AtomicBool,
+    state: Mutex<PauseState>,
+    changed: Condvar,
+    clock: Arc<VirtualClock>,
+}
+
+impl PauseControl {
+    pub fn new(clock: Arc<VirtualClock>) -> Self {
+        PauseControl {
+            requested: AtomicBool::new(false),
+            state: Mutex::new(PauseState {
+                paused: false,
+                running: 0,
+                froze_clock: false,
+            }),
+            changed: Condvar::new(),
+            clock,
+        }
+    }
+
+    /// Stop every guest thread, returning once none of them is executing.
+    /// Does nothing if the machine is already paused.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.paused {
+            state.paused = true;
+            self.requested.store(true, Ordering::SeqCst);
+            if !self.clock.is_frozen() {
+                self.clock.freeze();
+                state.froze_clock = true;
+            }
+        }
+        let _state = self
+            .changed
+            .wait_while(state, |state| state.paused && state.running > 0)
+            .unwrap();
+    }
+
+    /// Let the guest threads run again.
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.paused {
+            return;
+        }
+        state.paused = false;
+        self.requested.store(false, Ordering::SeqCst);
+        if state.froze_clock {
+            state.froze_clock = false;
+            self.clock.unfreeze();
+        }
+        self.changed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    /// Called by a thread before it starts executing instructions. Waits for
+    /// the machine to be resumed if it's paused.
+    pub(super) fn enter(&self) {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .changed
+            .wait_while(state, |state| state.paused)
+            .unwrap();
+        state.running += 1;
+    }
+
+    /// Called by a thread when it stops executing instructions, either to
+    /// wait on something or because it exited.
+    pub(super) fn leave(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+        self.changed.notify_all();
+    }
+
+    /// Called by a running thread between instructions, which stops it there
+    /// if a pause has been requested.
+    pub(super) fn checkpoint(&self) {
+        if self.requested.load(Ordering::Relaxed) {
+            self.leave();
+            self.enter();
+        }
+    }
+}
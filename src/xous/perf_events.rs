@@ -0,0 +1,149 @@
+//! Coarse milestones in the program's execution that services, and whoever
+//! embeds the machine, can subscribe to, the way perf delivers events to a
+//! program profiling itself on hardware. A service can sample where threads
+//! are every so many instructions, for instance, and hand the samples to an
+//! agent in the guest as if a timer interrupt had taken them.
+//!
+//! Nothing is checked for until something subscribes, so a machine with no
+//! subscribers runs as fast as one without this module.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use riscv_cpu::cpu::Trap;
+
+use super::trace::SchedulerEvent;
+
+/// Something that happened, as delivered to subscribers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PerfEvent {
+    /// Thread `tid` has retired another whole number of the instructions
+    /// the subscriber samples every, `instructions` in all, the last of
+    /// them at `pc`.
+    Sample {
+        tid: i32,
+        pc: u32,
+        instructions: u64,
+    },
+
+    /// Thread `tid` took a trap at `pc` that stops it.
+    Trap { tid: i32, pc: u32, trap: Trap },
+
+    /// Thread `tid` started or stopped running, as `--trace` records it.
+    ContextSwitch { tid: i32, event: SchedulerEvent },
+}
+
+/// Which events a subscriber wants.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PerfSubscription {
+    /// Sample every thread each time it retires this many instructions.
+    pub every_instructions: Option<u64>,
+    pub traps: bool,
+    pub context_switches: bool,
+}
+
+impl PerfSubscription {
+    fn wants(&self, event: &PerfEvent) -> bool {
+        match event {
+            PerfEvent::Sample { instructions, .. } => self
+                .every_instructions
+                .is_some_and(|every| every > 0 && instructions % every == 0),
+            PerfEvent::Trap { .. } => self.traps,
+            PerfEvent::ContextSwitch { .. } => self.context_switches,
+        }
+    }
+}
+
+/// Receives the events it subscribed to, on the host thread of the guest
+/// thread they happened to.
+pub trait PerfListener: Send + Sync {
+    fn event(&self, event: &PerfEvent);
+}
+
+struct Subscriber {
+    subscription: PerfSubscription,
+    listener: Arc<dyn PerfListener>,
+}
+
+#[derive(Default)]
+pub(super) struct PerfEvents {
+    subscribers: RwLock<Vec<Subscriber>>,
+
+    /// Every sampling period is a multiple of this, so a count it doesn't
+    /// divide has no one to deliver a sample to. Checked after every
+    /// instruction, and zero if nothing samples.
+    sample_every: AtomicU64,
+
+    /// Whether anything is subscribed at all.
+    active: AtomicBool,
+}
+
+impl PerfEvents {
+    /// Deliver the events `subscription` asks for to `listener`, in place
+    /// of whatever it asked for before. Subscribing to nothing unsubscribes.
+    pub fn subscribe(&self, subscription: PerfSubscription, listener: Arc<dyn PerfListener>) {
+        let mut subscribers = self.subscribers.write().unwrap();
+        subscribers.retain(|subscriber| !Arc::ptr_eq(&subscriber.listener, &listener));
+        if subscription != PerfSubscription::default() {
+            subscribers.push(Subscriber {
+                subscription,
+                listener,
+            });
+        }
+        self.sample_every.store(
+            subscribers
+                .iter()
+                .filter_map(|subscriber| subscriber.subscription.every_instructions)
+                .fold(0, gcd),
+            Ordering::Relaxed,
+        );
+        self.active
+            .store(!subscribers.is_empty(), Ordering::Relaxed);
+    }
+
+    /// Note that thread `tid` has retired `instructions`, the last at `pc`.
+    pub fn retired(&self, tid: i32, pc: u32, instructions: u64) {
+        let every = self.sample_every.load(Ordering::Relaxed);
+        if every != 0 && instructions.is_multiple_of(every) {
+            self.deliver(PerfEvent::Sample {
+                tid,
+                pc,
+                instructions,
+            });
+        }
+    }
+
+    pub fn trap(&self, tid: i32, pc: u32, trap: Trap) {
+        if self.active.load(Ordering::Relaxed) {
+            self.deliver(PerfEvent::Trap { tid, pc, trap });
+        }
+    }
+
+    pub fn context_switch(&self, tid: i32, event: SchedulerEvent) {
+        if self.active.load(Ordering::Relaxed) {
+            self.deliver(PerfEvent::ContextSwitch { tid, event });
+        }
+    }
+
+    fn deliver(&self, event: PerfEvent) {
+        // Clone the listeners out so they may subscribe again themselves
+        let listeners: Vec<Arc<dyn PerfListener>> = self
+            .subscribers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|subscriber| subscriber.subscription.wants(&event))
+            .map(|subscriber| subscriber.listener.clone())
+            .collect();
+        for listener in listeners {
+            listener.event(&event);
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
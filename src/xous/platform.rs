@@ -0,0 +1,84 @@
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Host facilities the emulated system depends on. The default implementation
+/// uses the standard library, but embedders (such as the wasm32 build) can supply
+/// their own clock, entropy source, and console.
+pub trait Platform: Send + Sync {
+    /// Milliseconds elapsed since the machine was created.
+    fn elapsed_ms(&self) -> u64;
+
+    /// Microseconds elapsed since the machine was created. Platforms with a
+    /// finer clock than milliseconds should override this.
+    fn elapsed_us(&self) -> u64 {
+        self.elapsed_ms() * 1000
+    }
+
+    /// Return 32 bits of randomness.
+    fn random_u32(&self) -> u32;
+
+    /// Write guest output destined for stdout.
+    fn write_stdout(&self, data: &[u8]) {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(data).unwrap();
+        stdout.flush().unwrap();
+    }
+
+    /// Write guest output destined for stderr.
+    fn write_stderr(&self, data: &[u8]) {
+        let mut stderr = std::io::stderr();
+        stderr.write_all(data).unwrap();
+        stderr.flush().unwrap();
+    }
+}
+
+/// A `Platform` backed by the host operating system.
+pub struct HostPlatform {
+    start: std::time::Instant,
+    rng_state: AtomicU64,
+}
+
+impl HostPlatform {
+    pub fn new() -> Self {
+        // `RandomState` is seeded by the OS, which is good enough for a non-cryptographic seed.
+        let seed = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        HostPlatform {
+            start: std::time::Instant::now(),
+            rng_state: AtomicU64::new(seed | 1),
+        }
+    }
+}
+
+impl Default for HostPlatform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Platform for HostPlatform {
+    fn elapsed_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    fn random_u32(&self) -> u32 {
+        // xorshift64*
+        let next = |mut x: u64| {
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            x
+        };
+        let state = self
+            .rng_state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(next(x)))
+            .unwrap();
+        (next(state).wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::RingBuffer;
+
+/// How long to wait for the guest before checking a ring again, in case it
+/// moved the ring without notifying.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A host stream handed to the guest before it starts, the way systemd
+/// passes already-bound sockets to the services it activates. The guest
+/// reads the stream from one ring of the `yove-ring-buffer` service and
+/// writes to it through another, so tests can pick the ports, or avoid
+/// ports altogether, without the guest knowing.
+pub enum Preopened {
+    /// A listening socket. The first connection it accepts becomes the stream.
+    Listener(TcpListener),
+
+    /// A connected socket.
+    Stream(TcpStream),
+
+    /// Any other pair of streams, such as the ends of a pipe. The writer is
+    /// dropped once the guest closes its ring. A read from the reader can't
+    /// be interrupted, so if one is still blocked when the machine is
+    /// dropped, the thread making it is left to finish by itself.
+    Pipe(Box<dyn Read + Send>, Box<dyn Write + Send>),
+}
+
+/// The reading and writing sides of a stream, and the socket to shut down
+/// once the guest has nothing more to write, if there is one.
+type Halves = (
+    Box<dyn Read + Send>,
+    Box<dyn Write + Send>,
+    Option<TcpStream>,
+);
+
+/// The threads copying between a preopened stream and the guest's rings.
+pub(super) struct Attachment {
+    stop: Arc<AtomicBool>,
+
+    /// The socket being copied, once there is one, to shut down when
+    /// stopping so that a read from it returns.
+    socket: Arc<Mutex<Option<TcpStream>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Attachment {
+    /// Stop copying, and wait for the threads doing it to finish. What the
+    /// guest already wrote to its ring is still copied out.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(socket) = self.socket.lock().unwrap().as_ref() {
+            socket.shutdown(Shutdown::Read).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Start copying between `preopened` and the guest's rings, from the host
+/// into `rx` and from `tx` to the host. Copying happens on threads of its
+/// own, which run until either side closes the stream or the attachment is
+/// stopped.
+pub(super) fn attach(preopened: Preopened, rx: Arc<RingBuffer>, tx: Arc<RingBuffer>) -> Attachment {
+    let stop = Arc::new(AtomicBool::new(false));
+    let socket = Arc::new(Mutex::new(None));
+    let thread = {
+        let (stop, socket) = (stop.clone(), socket.clone());
+        std::thread::spawn(move || {
+            let stream = match preopened {
+                Preopened::Listener(listener) => accept(&listener, &rx, &stop),
+                Preopened::Stream(stream) => Some(stream),
+                Preopened::Pipe(reader, writer) => {
+                    // Left to finish by itself if it's blocked reading
+                    let reading = stop.clone();
+                    std::thread::spawn(move || copy_to_guest(reader, &rx, &reading));
+                    copy_from_guest(&tx, writer, &stop);
+                    return;
+                }
+            };
+            let Some(stream) = stream else {
+                tx.close();
+                rx.close();
+                return;
+            };
+            let (reader, writer, shutdown) = tcp_halves(stream);
+            *socket.lock().unwrap() = shutdown.as_ref().and_then(|s| s.try_clone().ok());
+            if stop.load(Ordering::Relaxed) {
+                // Stopped while the socket was being recorded
+                if let Some(socket) = &shutdown {
+                    socket.shutdown(Shutdown::Read).ok();
+                }
+            }
+            let to_guest = {
+                let stop = stop.clone();
+                std::thread::spawn(move || copy_to_guest(reader, &rx, &stop))
+            };
+            copy_from_guest(&tx, writer, &stop);
+            if let Some(stream) = shutdown {
+                stream.shutdown(Shutdown::Write).ok();
+            }
+            to_guest.join().ok();
+        })
+    };
+    Attachment {
+        stop,
+        socket,
+        thread: Some(thread),
+    }
+}
+
+/// Wait for a connection to `listener`, until `stop` is set.
+fn accept(listener: &TcpListener, ring: &RingBuffer, stop: &AtomicBool) -> Option<TcpStream> {
+    listener.set_nonblocking(true).ok()?;
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => return stream.set_nonblocking(false).ok().map(|()| stream),
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                ring.wait(POLL_INTERVAL);
+            }
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// Split `stream` into a reader, a writer, and a handle for shutting down
+/// the writing side once the guest is done.
+fn tcp_halves(stream: TcpStream) -> Halves {
+    match (stream.try_clone(), stream.try_clone()) {
+        (Ok(writer), Ok(shutdown)) => (Box::new(stream), Box::new(writer), Some(shutdown)),
+        _ => (Box::new(std::io::empty()), Box::new(std::io::sink()), None),
+    }
+}
+
+/// Copy from `reader` into `ring` until the reader ends, then close the
+/// ring. Gives up when `stop` is set.
+fn copy_to_guest(mut reader: Box<dyn Read + Send>, ring: &RingBuffer, stop: &AtomicBool) {
+    let mut buf = [0; 4096];
+    while let Ok(count @ 1..) = reader.read(&mut buf) {
+        let mut written = 0;
+        while written < count {
+            if ring.is_closed() || stop.load(Ordering::Relaxed) {
+                return;
+            }
+            let chunk = ring.write(&buf[written..count]);
+            if chunk == 0 {
+                ring.wait(POLL_INTERVAL);
+            }
+            written += chunk;
+        }
+    }
+    // The ring can only be closed once the guest has attached it
+    while !ring.is_attached() {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        ring.wait(POLL_INTERVAL);
+    }
+    ring.close();
+}
+
+/// Copy from `ring` to `writer` until the guest closes the ring, or `stop`
+/// is set, and it has been drained.
+fn copy_from_guest(ring: &RingBuffer, mut writer: Box<dyn Write + Send>, stop: &AtomicBool) {
+    let mut buf = [0; 4096];
+    loop {
+        // Check before reading, so that data written just before closing isn't lost
+        let closed = ring.is_closed() || stop.load(Ordering::Relaxed);
+        let count = ring.read(&mut buf);
+        if count > 0 {
+            if writer.write_all(&buf[..count]).is_err() {
+                return;
+            }
+        } else if closed {
+            writer.flush().ok();
+            return;
+        } else {
+            ring.wait(POLL_INTERVAL);
+        }
+    }
+}
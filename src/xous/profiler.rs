@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Mutex, RwLock};
+
+use super::Memory;
+
+/// Give up walking the stack after this many frames.
+const MAX_STACK_DEPTH: usize = 128;
+
+/// A function symbol from the guest program.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub address: u32,
+    pub size: u32,
+    pub name: String,
+}
+
+struct Sample {
+    tid: i32,
+
+    /// Program counters, starting with the innermost frame.
+    stack: Vec<u32>,
+}
+
+/// Output formats understood by `Profiler::write`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileFormat {
+    /// An uncompressed pprof protobuf, for `pprof` and speedscope.
+    Pprof,
+
+    /// The text format produced by `perf script`, for flamegraph and speedscope.
+    PerfScript,
+}
+
+impl ProfileFormat {
+    /// Guess the format from a file name, defaulting to `PerfScript`.
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".pb") || path.ends_with(".pprof") {
+            ProfileFormat::Pprof
+        } else {
+            ProfileFormat::PerfScript
+        }
+    }
+}
+
+/// Samples the PC and stack of every guest thread once every `interval` instructions.
+pub struct Profiler {
+    interval: u64,
+    program_name: RwLock<String>,
+    symbols: RwLock<Vec<Symbol>>,
+    samples: Mutex<Vec<Sample>>,
+}
+
+/// Add the callers found by following the frame pointer chain from `fp` to
+/// `stack`, innermost first, until it holds `MAX_STACK_DEPTH` addresses.
+/// Each is an address inside the call instruction rather than the address
+/// it returns to.
+pub(super) fn walk_frames(memory: &Memory, mut fp: u32, stack: &mut Vec<u32>) {
+    let read = |address: u32| {
+        memory
+            .virt_to_phys(address)
+            .map(|phys| memory.peek_u32(phys))
+    };
+    while stack.len() < MAX_STACK_DEPTH && fp & 3 == 0 && fp >= 8 {
+        let (Some(ra), Some(next_fp)) = (read(fp - 4), read(fp - 8)) else {
+            break;
+        };
+        if ra == 0 {
+            break;
+        }
+        stack.push(ra - 1);
+        // The stack grows down, so callers' frames are always higher
+        if next_fp <= fp {
+            break;
+        }
+        fp = next_fp;
+    }
+}
+
+impl Profiler {
+    pub fn new(interval: u64) -> Self {
+        Profiler {
+            interval,
+            program_name: RwLock::new("guest".to_owned()),
+            symbols: RwLock::new(vec![]),
+            samples: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Provide the symbols of the loaded program so that samples can be named.
+    pub fn set_symbols(&self, program_name: &str, mut symbols: Vec<Symbol>) {
+        symbols.sort_by_key(|symbol| symbol.address);
+        *self.program_name.write().unwrap() = program_name.to_owned();
+        *self.symbols.write().unwrap() = symbols;
+    }
+
+    /// Record a sample for thread `tid`, following the frame pointer chain to
+    /// recover the callers. Programs built without frame pointers will only
+    /// have their innermost frame recorded.
+    pub(super) fn sample(&self, memory: &Memory, tid: i32, pc: u32, fp: u32) {
+        let mut stack = vec![pc];
+        walk_frames(memory, fp, &mut stack);
+        self.samples.lock().unwrap().push(Sample { tid, stack });
+    }
+
+    /// The index of the symbol containing `address` in `symbols`, which must be
+    /// sorted, and the offset of `address` into it.
+    pub(super) fn symbolize(symbols: &[Symbol], address: u32) -> Option<(usize, u32)> {
+        let index = symbols
+            .partition_point(|symbol| symbol.address <= address)
+            .checked_sub(1)?;
+        let symbol = &symbols[index];
+        let offset = address - symbol.address;
+        (offset < symbol.size.max(1)).then_some((index, offset))
+    }
+
+    pub fn write(&self, format: ProfileFormat, output: &mut impl Write) -> std::io::Result<()> {
+        match format {
+            ProfileFormat::Pprof => output.write_all(&self.to_pprof()),
+            ProfileFormat::PerfScript => self.write_perf_script(output),
+        }
+    }
+
+    fn write_perf_script(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let program_name = self.program_name.read().unwrap();
+        let symbols = self.symbols.read().unwrap();
+        for (index, sample) in self.samples.lock().unwrap().iter().enumerate() {
+            // Use the instruction count as a stand-in for time
+            let instructions = (index as u64 + 1) * self.interval;
+            writeln!(
+                output,
+                "yove {}/{} [000] {}.{:06}: {} instructions:",
+                sample.tid,
+                sample.tid,
+                instructions / 1_000_000,
+                instructions % 1_000_000,
+                self.interval
+            )?;
+            for address in &sample.stack {
+                match Self::symbolize(&symbols, *address) {
+                    Some((symbol, offset)) => writeln!(
+                        output,
+                        "\t{:16x} {}+0x{:x} ({})",
+                        address, symbols[symbol].name, offset, program_name
+                    )?,
+                    None => writeln!(output, "\t{:16x} [unknown] ({})", address, program_name)?,
+                }
+            }
+            writeln!(output)?;
+        }
+        Ok(())
+    }
+
+    fn to_pprof(&self) -> Vec<u8> {
+        let mut strings = StringTable::default();
+        let symbols = self.symbols.read().unwrap();
+        let mut profile = vec![];
+
+        // sample_type and period_type are both "instructions/count"
+        let mut value_type = vec![];
+        pb::int(&mut value_type, 1, strings.index("instructions"));
+        pb::int(&mut value_type, 2, strings.index("count"));
+        pb::bytes(&mut profile, 1, &value_type);
+
+        let thread_key = strings.index("thread");
+        let mut locations = Interner::default();
+        let mut functions = Interner::default();
+        for sample in self.samples.lock().unwrap().iter() {
+            let mut location_ids = vec![];
+            for address in &sample.stack {
+                pb::varint(&mut location_ids, locations.id(*address));
+            }
+            let mut message = vec![];
+            pb::bytes(&mut message, 1, &location_ids);
+            pb::int(&mut message, 2, self.interval);
+            let mut label = vec![];
+            pb::int(&mut label, 1, thread_key);
+            pb::int(&mut label, 3, sample.tid as u64);
+            pb::bytes(&mut message, 3, &label);
+            pb::bytes(&mut profile, 2, &message);
+        }
+
+        for (index, address) in locations.items.iter().enumerate() {
+            let mut location = vec![];
+            pb::int(&mut location, 1, index as u64 + 1);
+            pb::int(&mut location, 3, *address as u64);
+            if let Some((symbol, _)) = Self::symbolize(&symbols, *address) {
+                let mut line = vec![];
+                pb::int(&mut line, 1, functions.id(symbol));
+                pb::bytes(&mut location, 4, &line);
+            }
+            pb::bytes(&mut profile, 4, &location);
+        }
+
+        for (index, symbol) in functions.items.iter().enumerate() {
+            let mut function = vec![];
+            let name = strings.index(&symbols[*symbol].name);
+            pb::int(&mut function, 1, index as u64 + 1);
+            pb::int(&mut function, 2, name);
+            pb::int(&mut function, 3, name);
+            pb::bytes(&mut profile, 5, &function);
+        }
+
+        pb::bytes(&mut profile, 9, &value_type);
+        pb::int(&mut profile, 10, self.interval);
+
+        for string in strings.strings {
+            pb::bytes(&mut profile, 6, string.as_bytes());
+        }
+        profile
+    }
+}
+
+/// Assigns pprof IDs, which start at 1, in order of first use.
+struct Interner<T> {
+    items: Vec<T>,
+    ids: HashMap<T, u64>,
+}
+
+impl<T> Default for Interner<T> {
+    fn default() -> Self {
+        Interner {
+            items: vec![],
+            ids: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Copy + Eq + std::hash::Hash> Interner<T> {
+    fn id(&mut self, item: T) -> u64 {
+        *self.ids.entry(item).or_insert_with(|| {
+            self.items.push(item);
+            self.items.len() as u64
+        })
+    }
+}
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl StringTable {
+    fn index(&mut self, s: &str) -> u64 {
+        // pprof requires the first string to be empty
+        if self.strings.is_empty() {
+            self.strings.push(String::new());
+            self.indices.insert(String::new(), 0);
+        }
+        if let Some(index) = self.indices.get(s) {
+            return *index;
+        }
+        self.strings.push(s.to_owned());
+        self.indices
+            .insert(s.to_owned(), self.strings.len() as u64 - 1);
+        self.strings.len() as u64 - 1
+    }
+}
+
+/// Just enough protobuf encoding to write a pprof profile.
+mod pb {
+    pub fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    pub fn int(out: &mut Vec<u8>, field: u32, value: u64) {
+        varint(out, (field as u64) << 3);
+        varint(out, value);
+    }
+
+    pub fn bytes(out: &mut Vec<u8>, field: u32, value: &[u8]) {
+        varint(out, (field as u64) << 3 | 2);
+        varint(out, value.len() as u64);
+        out.extend_from_slice(value);
+    }
+}
@@ -0,0 +1,187 @@
+use goblin::elf::section_header::SectionHeader;
+use goblin::elf::{header, program_header, section_header, Elf};
+
+use super::LoadError;
+
+/// `e_flags` bit set by toolchains when the program uses compressed instructions.
+const EF_RISCV_RVC: u32 = 0x1;
+
+/// One section that the loader placed in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub writable: bool,
+    pub executable: bool,
+
+    /// Whether the section takes no space in the file and starts out zeroed,
+    /// like `.bss`.
+    pub zeroed: bool,
+}
+
+/// One loadable segment of the program, as its program header describes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+
+    /// How much memory the segment takes once loaded.
+    pub size: u32,
+
+    /// How much of it comes from the file, with the rest zeroed.
+    pub file_size: u32,
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+
+    /// The names of the loaded sections that lie within the segment.
+    pub sections: Vec<String>,
+}
+
+/// What the loader found in the program's ELF headers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProgramInfo {
+    pub entry: u32,
+
+    /// The ELF header's `e_flags`, which describe the floating point ABI and
+    /// whether the program uses compressed instructions.
+    pub flags: u32,
+    pub sections: Vec<Section>,
+    pub segments: Vec<Segment>,
+
+    /// How long loading the program took, in microseconds of host time.
+    pub load_us: u64,
+}
+
+impl ProgramInfo {
+    pub(super) fn new(elf: &Elf) -> Self {
+        let sections: Vec<Section> = elf
+            .section_headers
+            .iter()
+            .filter(|sh| is_loaded(sh))
+            .map(|sh| Section {
+                name: elf
+                    .shdr_strtab
+                    .get_at(sh.sh_name)
+                    .unwrap_or("???")
+                    .to_owned(),
+                address: sh.sh_addr as u32,
+                size: sh.sh_size as u32,
+                writable: sh.sh_flags as u32 & section_header::SHF_WRITE != 0,
+                executable: sh.sh_flags as u32 & section_header::SHF_EXECINSTR != 0,
+                zeroed: sh.sh_type == section_header::SHT_NOBITS,
+            })
+            .collect();
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == program_header::PT_LOAD)
+            .map(|ph| {
+                let (start, end) = (ph.p_vaddr, ph.p_vaddr.saturating_add(ph.p_memsz));
+                Segment {
+                    address: ph.p_vaddr as u32,
+                    size: ph.p_memsz as u32,
+                    file_size: ph.p_filesz as u32,
+                    readable: ph.is_read(),
+                    writable: ph.is_write(),
+                    executable: ph.is_executable(),
+                    sections: sections
+                        .iter()
+                        .filter(|section| {
+                            let address = u64::from(section.address);
+                            address >= start
+                                && address + u64::from(section.size) <= end
+                                && (section.size != 0 || address < end)
+                        })
+                        .map(|section| section.name.clone())
+                        .collect(),
+                }
+            })
+            .collect();
+        ProgramInfo {
+            entry: elf.entry as u32,
+            flags: elf.header.e_flags,
+            sections,
+            segments,
+            load_us: 0,
+        }
+    }
+
+    /// Whether the program was built to use compressed instructions.
+    pub fn compressed(&self) -> bool {
+        self.flags & EF_RISCV_RVC != 0
+    }
+}
+
+/// Whether the section `sh` describes takes up memory in the loaded program.
+/// That's every allocated section except `.tbss`, which only describes the
+/// zeroed part of each thread's own copy of the thread-local data, so it
+/// shares its addresses with whatever is placed after it.
+pub(super) fn is_loaded(sh: &SectionHeader) -> bool {
+    let flags = sh.sh_flags as u32;
+    flags & section_header::SHF_ALLOC != 0
+        && !(flags & section_header::SHF_TLS != 0 && sh.sh_type == section_header::SHT_NOBITS)
+}
+
+/// Make sure no two sections overlap, and that every section lies between
+/// the first page, which is never mapped, and `end`.
+pub(super) fn check_sections(sections: &[Section], end: u32) -> Result<(), LoadError> {
+    let mut sorted: Vec<&Section> = sections.iter().filter(|s| s.size != 0).collect();
+    sorted.sort_by_key(|section| section.address);
+    for section in &sorted {
+        let in_range = section
+            .address
+            .checked_add(section.size)
+            .is_some_and(|section_end| section.address >= 0x1000 && section_end <= end);
+        if !in_range {
+            return Err(LoadError::SectionOutOfRange {
+                name: section.name.clone(),
+                address: section.address,
+                size: section.size,
+            });
+        }
+    }
+    for pair in sorted.windows(2) {
+        if pair[0].address + pair[0].size > pair[1].address {
+            return Err(LoadError::OverlappingSections(
+                pair[0].name.clone(),
+                pair[1].name.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reject 64-bit and big-endian programs from the identification bytes at
+/// the start of the file, before their headers are parsed with the wrong
+/// layout or byte order.
+pub(super) fn check_ident(program: &[u8]) -> Result<(), LoadError> {
+    if program.len() < header::SIZEOF_IDENT || program[..header::SELFMAG] != header::ELFMAG[..] {
+        return Err(LoadError::IncorrectFormat);
+    }
+    if program[header::EI_CLASS] != header::ELFCLASS32 {
+        return Err(LoadError::BitSizeError);
+    }
+    if program[header::EI_DATA] != header::ELFDATA2LSB {
+        return Err(LoadError::BigEndian);
+    }
+    Ok(())
+}
+
+/// Make sure the program is a RISC-V executable before loading it, rather
+/// than running whatever its bytes happen to decode to. With `any_machine`,
+/// the machine type isn't checked.
+///
+/// Position-independent executables and shared objects (`ET_DYN`) are
+/// rejected along with relocatable objects: sections are loaded at their
+/// link addresses and no dynamic relocations are applied, so every pointer
+/// they hold would be wrong.
+pub(super) fn check_header(elf: &Elf, any_machine: bool) -> Result<(), LoadError> {
+    if !any_machine && elf.header.e_machine != header::EM_RISCV {
+        return Err(LoadError::WrongMachine(elf.header.e_machine));
+    }
+    if elf.header.e_type != header::ET_EXEC {
+        return Err(LoadError::NotExecutable(elf.header.e_type));
+    }
+    Ok(())
+}
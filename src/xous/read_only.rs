@@ -0,0 +1,93 @@
+//! Maps the program's code and constants read-only, so that a stray write
+//! into `.text` or `.rodata` stops the program at the store that made it,
+//! rather than corrupting a function or a constant to be tripped over later.
+//!
+//! Once the program is loaded, every page holding a section that isn't
+//! writable loses write permission. A page that's shared with a writable
+//! section keeps it, with a warning. A write to one of the protected pages
+//! raises a store access fault, and the symbol that was written over, and
+//! the section it's in, are logged.
+
+use std::sync::RwLock;
+
+use riscv_cpu::cpu::{Trap, TrapType};
+
+use super::profiler::{Profiler, Symbol};
+use super::program::Section;
+use super::section_map::SectionMap;
+use super::{Memory, MMUFLAG_EXECUTABLE, MMUFLAG_READABLE, MMUFLAG_WRITABLE};
+
+#[derive(Default)]
+pub(super) struct ReadOnlyPolicy {
+    sections: SectionMap,
+
+    /// The program's functions and objects, sorted by address, for naming
+    /// what was written over.
+    symbols: RwLock<Vec<Symbol>>,
+}
+
+impl ReadOnlyPolicy {
+    pub fn set_symbols(&self, mut symbols: Vec<Symbol>) {
+        symbols.sort_by_key(|symbol| symbol.address);
+        *self.symbols.write().unwrap() = symbols;
+    }
+
+    /// Take write permission away from the pages of the loaded `sections`
+    /// that aren't writable. A page shared with a writable section has to
+    /// stay writable, so it's left as it was, with a warning.
+    pub fn protect(&self, memory: &Memory, sections: &[Section]) {
+        self.sections.set(sections);
+        for page in self
+            .sections
+            .pages(|section| !section.writable, |section| section.writable)
+        {
+            match (page.shared, memory.page_flags(page.address)) {
+                (Some(data), _) => log::warn!(
+                    "{} and {} share the page at {:08x}, which stays writable",
+                    page.section,
+                    data,
+                    page.address
+                ),
+                (None, Some(flags)) => memory.set_memory_flags(
+                    page.address,
+                    flags & (MMUFLAG_READABLE | MMUFLAG_EXECUTABLE),
+                ),
+                (None, None) => {}
+            }
+        }
+    }
+
+    /// `trap` as an access fault if it was raised by thread `tid` at `pc`
+    /// for writing to a page of a section that isn't writable, or as it is
+    /// otherwise.
+    pub fn check(&self, memory: &Memory, tid: i32, pc: u32, trap: Trap) -> Trap {
+        if trap.trap_type != TrapType::StorePageFault {
+            return trap;
+        }
+        match memory.page_flags(trap.value) {
+            Some(flags) if flags & MMUFLAG_WRITABLE == 0 => {}
+            _ => return trap,
+        }
+        let Some(section) = self.sections.find(trap.value, |section| !section.writable) else {
+            return trap;
+        };
+        let symbols = self.symbols.read().unwrap();
+        let location = match Profiler::symbolize(&symbols, trap.value) {
+            Some((index, 0)) => symbols[index].name.clone(),
+            Some((index, offset)) => format!("{}+{:#x}", symbols[index].name, offset),
+            None => format!("{:08x}", trap.value),
+        };
+        log::error!(
+            "thread {} at {:08x} wrote to {} ({:08x} in {}), which is read-only",
+            tid,
+            pc,
+            location,
+            trap.value,
+            section
+        );
+        Trap {
+            trap_type: TrapType::StoreAccessFault,
+            value: trap.value,
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::Mutex;
+
+/// A small seeded random number generator (xorshift64*), so that anything
+/// random about a run can be repeated by reusing its seed.
+pub struct Rng {
+    state: Mutex<u64>,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng {
+            // xorshift must never have a state of zero
+            state: Mutex::new(seed ^ 0x9e37_79b9_7f4a_7c15 | 1),
+        }
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number from 0 up to, but not including, `limit`.
+    pub fn below(&self, limit: u32) -> u32 {
+        (((self.next_u64() >> 32) * limit as u64) >> 32) as u32
+    }
+
+    /// `true` with the given probability.
+    pub fn chance(&self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+/// The seed for the stream of randomness called `name`, derived from a
+/// run's seed, so that every stream can be repeated from that one seed
+/// without any two of them following each other.
+pub fn derive_seed(seed: u64, name: &str) -> u64 {
+    // FNV-1a of the name, mixed into the seed by splitmix64's finalizer
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    let mut z = seed ^ hash;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,71 @@
+//! Where each section of the loaded program lies, shared by the policies
+//! that protect pages by what's loaded in them and name the section a
+//! fault landed in.
+
+use std::sync::RwLock;
+
+use super::program::Section;
+
+/// A page holding part of a section that's to be protected.
+pub(super) struct Page {
+    pub address: u32,
+
+    /// The section being protected.
+    pub section: String,
+
+    /// A section that shares the page and needs it left as it is, if any.
+    pub shared: Option<String>,
+}
+
+#[derive(Default)]
+pub(super) struct SectionMap {
+    /// The sections of the loaded program, in the order they were loaded.
+    sections: RwLock<Vec<Section>>,
+}
+
+impl SectionMap {
+    pub fn set(&self, sections: &[Section]) {
+        *self.sections.write().unwrap() = sections.to_vec();
+    }
+
+    /// The name of the section `which` accepts that was loaded at `address`,
+    /// if any.
+    pub fn find(&self, address: u32, which: impl Fn(&Section) -> bool) -> Option<String> {
+        let sections = self.sections.read().unwrap();
+        sections
+            .iter()
+            .find(|section| which(section) && contains(section, address))
+            .map(|section| section.name.clone())
+    }
+
+    /// Every page holding part of a section that `protected` accepts, along
+    /// with the first section `shares` accepts that's on the same page.
+    pub fn pages(
+        &self,
+        protected: impl Fn(&Section) -> bool,
+        shares: impl Fn(&Section) -> bool,
+    ) -> Vec<Page> {
+        let sections = self.sections.read().unwrap();
+        let mut pages = vec![];
+        for section in sections.iter().filter(|section| protected(section)) {
+            let end = section.address + section.size;
+            for page in (section.address & !0xfff..end).step_by(4096) {
+                let shared = sections.iter().find(|other| {
+                    shares(other)
+                        && other.address < page + 4096
+                        && page < other.address + other.size
+                });
+                pages.push(Page {
+                    address: page,
+                    section: section.name.clone(),
+                    shared: shared.map(|other| other.name.clone()),
+                });
+            }
+        }
+        pages
+    }
+}
+
+fn contains(section: &Section, address: u32) -> bool {
+    (section.address..section.address + section.size).contains(&address)
+}
@@ -1,12 +1,53 @@
+//! The servers the emulator provides in place of the ones Xous runs.
+//!
+//! The crypto engine servers, `sha512`, `aes`, and `engine25519`, don't
+//! speak the protocols of the Xous servers for the same engines: their
+//! opcodes and message layouts are yove's own, so a program has to be
+//! written for them. They're served under `yove-` names so that a program
+//! built for the real servers finds no server, rather than one that
+//! misreads its messages.
+
 use std::sync::mpsc::Receiver;
+pub mod aes;
+pub mod archive;
+pub mod bridged;
+pub mod codec;
+pub mod com;
 pub mod dns;
+pub mod engine25519;
+pub mod host_exec;
+pub mod keyboard;
 pub mod log;
+pub mod message;
 pub mod name;
 pub mod panic_to_screen;
+pub mod perf_counter;
+pub mod ring_buffer;
+pub mod sha512;
+pub mod spinor;
+pub mod susres;
 pub mod ticktimer;
-use super::Memory;
+pub mod trng;
+pub mod usb;
+use super::definitions::SyscallErrorNumber;
+use super::{Memory, SyscallResult};
+pub use message::{LendBuffer, Message, MessageKind, MessageMemory, Reply};
+
+/// The registers to resume a waiting sender with, and for a mutable lend,
+/// the lent buffer with the service's writes to copy back.
+pub type ResponseData = ([i32; 8], Option<LendBuffer>);
 
-pub type ResponseData = ([i32; 8], Option<Vec<u8>>);
+/// Suspend the calling thread until `response` arrives. This is the only
+/// kind of suspension the host shim makes, so the worker running the thread
+/// can always downcast it back to a `Receiver<ResponseData>`.
+pub fn wait_for(response: Receiver<ResponseData>) -> SyscallResult {
+    SyscallResult::Suspend(Box::new(response))
+}
+
+/// A message whose opcode the service doesn't handle. The sender is refused
+/// as `Abuse::UnknownOpcode` rather than the emulator panicking.
+#[derive(Debug)]
+pub struct UnknownOpcode;
 
 #[allow(dead_code)]
 pub enum ScalarResult {
@@ -14,78 +55,120 @@ pub enum ScalarResult {
     Scalar2([u32; 2]),
     Scalar5([u32; 5]),
     WaitForResponse(Receiver<ResponseData>),
+    UnknownOpcode,
+
+    /// The message can't be acted on, for the reason given. The sender is
+    /// refused with the error as `Abuse::InvalidMessage`.
+    Refused(SyscallErrorNumber, String),
 }
 
 #[allow(dead_code)]
 pub enum LendResult {
     MemoryReturned([u32; 2]),
     WaitForResponse(Receiver<ResponseData>),
+    UnknownOpcode,
 }
 
+/// A server implemented by the emulator. Services either implement `message`,
+/// which receives every message in a typed envelope, or the per-kind methods
+/// that the default `message` dispatches to.
 pub trait Service {
-    fn scalar(&self, _memory: &Memory, sender: u32, opcode: u32, args: [u32; 4]) {
-        panic!(
-            "Unknown scalar to service {}: {} ({:?})",
-            sender, opcode, args
-        );
+    fn message(&self, memory: &Memory, mut message: Message) -> Reply {
+        let (sender, opcode, args) = (message.sender, message.opcode, message.args);
+        let extra = [args[2], args[3]];
+        match message.kind {
+            MessageKind::Scalar => match self.scalar(memory, sender, opcode, args) {
+                Ok(()) => Reply::Ok,
+                Err(UnknownOpcode) => Reply::UnknownOpcode,
+            },
+            MessageKind::BlockingScalar => {
+                self.blocking_scalar(memory, sender, opcode, args).into()
+            }
+            MessageKind::MutableLend => {
+                let buf = message.memory_mut().unwrap().buffer_mut();
+                self.lend_mut(memory, sender, opcode, buf, extra).into()
+            }
+            MessageKind::Lend => {
+                let buf = message.memory().unwrap().as_slice();
+                self.lend(memory, sender, opcode, buf, extra).into()
+            }
+            MessageKind::Send => {
+                let buf = message.memory().unwrap().as_slice();
+                match self.send(memory, sender, opcode, buf, extra) {
+                    Ok(()) => Reply::Ok,
+                    Err(UnknownOpcode) => Reply::UnknownOpcode,
+                }
+            }
+        }
+    }
+
+    fn scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        _opcode: u32,
+        _args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        Err(UnknownOpcode)
     }
 
     fn blocking_scalar(
         &self,
         _memory: &Memory,
-        sender: u32,
-        opcode: u32,
-        args: [u32; 4],
+        _sender: u32,
+        _opcode: u32,
+        _args: [u32; 4],
     ) -> ScalarResult {
-        panic!(
-            "Unknown scalar to service {}: {} ({:?})",
-            sender, opcode, args
-        );
+        ScalarResult::UnknownOpcode
     }
 
     fn lend(
         &self,
         _memory: &Memory,
-        sender: u32,
-        opcode: u32,
-        buf: &[u8],
-        extra: [u32; 2],
+        _sender: u32,
+        _opcode: u32,
+        _buf: &[u8],
+        _extra: [u32; 2],
     ) -> LendResult {
-        panic!(
-            "Unknown lend {} bytes to service {}: {} ({:?})",
-            buf.len(),
-            sender,
-            opcode,
-            extra
-        );
+        LendResult::UnknownOpcode
     }
 
     fn lend_mut(
         &self,
         _memory: &Memory,
-        sender: u32,
-        opcode: u32,
-        buf: &mut [u8],
-        extra: [u32; 2],
+        _sender: u32,
+        _opcode: u32,
+        _buf: &mut LendBuffer,
+        _extra: [u32; 2],
     ) -> LendResult {
-        panic!(
-            "Unknown lend_mut {} bytes to service {}: {} ({:?})",
-            buf.len(),
-            sender,
-            opcode,
-            extra
-        );
+        LendResult::UnknownOpcode
     }
 
-    fn send(&self, _memory: &Memory, sender: u32, opcode: u32, buf: &[u8], extra: [u32; 2]) {
-        panic!(
-            "Unknown send {} bytes to service {}: {} ({:?})",
-            buf.len(),
-            sender,
-            opcode,
-            extra
-        );
+    fn send(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        _opcode: u32,
+        _buf: &[u8],
+        _extra: [u32; 2],
+    ) -> Result<(), UnknownOpcode> {
+        Err(UnknownOpcode)
     }
+
+    /// Called periodically by the machine so that the service can complete
+    /// time-based work, such as expiring timeouts, without a host thread.
+    fn tick(&self, _memory: &Memory) {}
+
+    /// The timeouts the service is waiting on, as what's waiting and when
+    /// it times out in milliseconds of virtual time, for a snapshot to
+    /// carry across a restore.
+    fn timeouts(&self) -> Vec<(u32, u64)> {
+        vec![]
+    }
+
+    /// Put back timeouts taken with `timeouts()` from the machine a
+    /// snapshot was taken of, once the one it's restored into has caught up.
+    fn set_timeouts(&self, _timeouts: &[(u32, u64)]) {}
 }
 
 pub fn get_service(name: &[u32; 4]) -> Option<Box<dyn Service + Sync + Send>> {
@@ -107,6 +190,12 @@ pub fn get_service(name: &[u32; 4]) -> Option<Box<dyn Service + Sync + Send>> {
         }
         [0x73756f78, 0x676f6c2d, 0x7265732d, 0x20726576] => Some(Box::new(log::Log::new())),
         [0x73756f78, 0x6d616e2d, 0x65732d65, 0x72657672] => Some(Box::new(name::Name::new())),
-        _ => panic!("Unhandled service request: {:x?}", name),
+        [0x65766f79, 0x6e69722d, 0x75622d67, 0x72656666] => {
+            Some(Box::new(ring_buffer::RingBufferService::new()))
+        }
+        [0x65766f79, 0x7265702d, 0x756f6366, 0x7265746e] => {
+            Some(Box::new(perf_counter::PerfCounter::new()))
+        }
+        _ => None,
     }
 }
@@ -0,0 +1,151 @@
+//! The aes server, which drives the AES engine. Each thread loads a key of
+//! its own and then encrypts or decrypts whole blocks in place. The cipher
+//! itself is done in software.
+//!
+//! Its protocol is yove's own, as the `services` module explains.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes256};
+
+use super::{LendBuffer, LendResult, Service, UnknownOpcode};
+use crate::xous::Memory;
+
+/// The size of an AES block, in bytes.
+const BLOCK: usize = 16;
+
+/// The name programs connect to the aes server by.
+pub const NAME: &str = "yove-aes";
+
+#[allow(dead_code)]
+enum AesOpcode {
+    /// Load the key in the valid bytes of the lent buffer, which must be 16
+    /// or 32 bytes long. Returns 1 if the key was loaded.
+    SetKey = 0,
+
+    /// Encrypt the valid bytes of the lent buffer in place, as a run of
+    /// independent blocks. Returns how many bytes were encrypted, which is
+    /// 0 without a key or if they aren't a whole number of blocks.
+    EncryptBlocks = 1,
+
+    /// Decrypt the valid bytes of the lent buffer in place, as for
+    /// `EncryptBlocks`.
+    DecryptBlocks = 2,
+
+    /// Forget the caller's key.
+    ClearKey = 3,
+}
+
+enum Key {
+    Aes128(Box<Aes128>),
+    Aes256(Box<Aes256>),
+}
+
+impl Key {
+    fn new(key: &[u8]) -> Option<Self> {
+        match key.len() {
+            16 => Some(Key::Aes128(Box::new(Aes128::new(
+                GenericArray::from_slice(key),
+            )))),
+            32 => Some(Key::Aes256(Box::new(Aes256::new(
+                GenericArray::from_slice(key),
+            )))),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, encrypt: bool, blocks: &mut [u8]) {
+        for block in blocks.chunks_exact_mut(BLOCK) {
+            let block = GenericArray::from_mut_slice(block);
+            match (self, encrypt) {
+                (Key::Aes128(cipher), true) => cipher.encrypt_block(block),
+                (Key::Aes128(cipher), false) => cipher.decrypt_block(block),
+                (Key::Aes256(cipher), true) => cipher.encrypt_block(block),
+                (Key::Aes256(cipher), false) => cipher.decrypt_block(block),
+            }
+        }
+    }
+}
+
+pub struct Aes {
+    /// The key each sender has loaded.
+    keys: Mutex<HashMap<u32, Key>>,
+}
+
+impl Aes {
+    pub fn new() -> Self {
+        Aes {
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for Aes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service for Aes {
+    fn scalar(
+        &self,
+        _memory: &Memory,
+        sender: u32,
+        opcode: u32,
+        _args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        if opcode == AesOpcode::ClearKey as u32 {
+            self.keys.lock().unwrap().remove(&sender);
+            Ok(())
+        } else {
+            Err(UnknownOpcode)
+        }
+    }
+
+    fn lend(
+        &self,
+        _memory: &Memory,
+        sender: u32,
+        opcode: u32,
+        buf: &[u8],
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == AesOpcode::SetKey as u32 {
+            let length = (extra[1] as usize).min(buf.len());
+            let Some(key) = Key::new(&buf[..length]) else {
+                return LendResult::MemoryReturned([0, 0]);
+            };
+            self.keys.lock().unwrap().insert(sender, key);
+            return LendResult::MemoryReturned([0, 1]);
+        }
+        LendResult::UnknownOpcode
+    }
+
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == AesOpcode::EncryptBlocks as u32 || opcode == AesOpcode::DecryptBlocks as u32 {
+            let length = (extra[1] as usize).min(buf.len());
+            let keys = self.keys.lock().unwrap();
+            let Some(key) = keys.get(&sender) else {
+                return LendResult::MemoryReturned([0, 0]);
+            };
+            if !length.is_multiple_of(BLOCK) {
+                return LendResult::MemoryReturned([0, 0]);
+            }
+            key.apply(
+                opcode == AesOpcode::EncryptBlocks as u32,
+                buf.get_mut(0..length).unwrap(),
+            );
+            return LendResult::MemoryReturned([0, length as u32]);
+        }
+        LendResult::UnknownOpcode
+    }
+}
@@ -0,0 +1,202 @@
+//! Decoding the values Xous programs archive into the buffers they lend to
+//! services. Different versions of Xous's libstd archive the same types with
+//! different versions of rkyv, which lay them out differently, so each value
+//! can be read with any of the known [`Layout`]s, and [`Archive::decode`]
+//! tries them in turn until one of them makes sense of the buffer.
+//!
+//! Every read is checked against the buffer, and a value that doesn't fit it
+//! is an [`ArchiveError`] saying where and why, rather than a panic or a
+//! quietly wrong value.
+
+use std::fmt;
+
+/// A way of laying out archived values, named for the rkyv version that
+/// produces it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// A string is a 32-bit offset to its bytes, relative to the string,
+    /// followed by its 32-bit length. An option is a 32-bit tag followed by
+    /// the value.
+    Rkyv04,
+
+    /// A string of up to seven bytes is stored inline, with its length in
+    /// the eighth byte. A longer one is its 32-bit length followed by a
+    /// 31-bit relative offset, with the top bit set to tell the two apart.
+    /// An option is an 8-bit tag, padded to the alignment of the value.
+    Rkyv07,
+}
+
+impl Layout {
+    /// Every known layout, in the order they're tried. The older layout goes
+    /// first: a few of its strings also decode as short inline strings in the
+    /// newer one, while the newer layout's never decode in the older one.
+    pub const ALL: [Layout; 2] = [Layout::Rkyv04, Layout::Rkyv07];
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layout::Rkyv04 => write!(f, "rkyv 0.4"),
+            Layout::Rkyv07 => write!(f, "rkyv 0.7"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("{length} bytes at offset {at} run past the end of the {size}-byte buffer")]
+    OutOfBounds {
+        at: usize,
+        length: usize,
+        size: usize,
+    },
+
+    #[error("the offset {relative} from {at} points outside the buffer")]
+    BadOffset { at: usize, relative: i64 },
+
+    #[error("the string at offset {at} isn't UTF-8: {source}")]
+    InvalidUtf8 {
+        at: usize,
+        source: std::str::Utf8Error,
+    },
+
+    #[error("the option at offset {at} has tag {tag}, which is neither None nor Some")]
+    InvalidTag { at: usize, tag: u32 },
+
+    #[error("the string at offset {at} is {length} bytes, more than the {capacity} it can hold")]
+    TooLong {
+        at: usize,
+        length: usize,
+        capacity: usize,
+    },
+
+    #[error("no known layout fits the buffer ({})", describe(.0))]
+    NoLayout(Vec<(Layout, ArchiveError)>),
+}
+
+fn describe(failures: &[(Layout, ArchiveError)]) -> String {
+    failures
+        .iter()
+        .map(|(layout, error)| format!("{}: {}", layout, error))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// A lent buffer, read as archived values.
+#[derive(Clone, Copy)]
+pub struct Archive<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Archive { buf }
+    }
+
+    /// Decode a value with the first layout `decode` succeeds with, or
+    /// return why each of them failed.
+    pub fn decode<T>(
+        &self,
+        decode: impl Fn(&Self, Layout) -> Result<T, ArchiveError>,
+    ) -> Result<(Layout, T), ArchiveError> {
+        let mut failures = vec![];
+        for layout in Layout::ALL {
+            match decode(self, layout) {
+                Ok(value) => return Ok((layout, value)),
+                Err(error) => failures.push((layout, error)),
+            }
+        }
+        Err(ArchiveError::NoLayout(failures))
+    }
+
+    pub fn bytes(&self, at: usize, length: usize) -> Result<&'a [u8], ArchiveError> {
+        at.checked_add(length)
+            .and_then(|end| self.buf.get(at..end))
+            .ok_or(ArchiveError::OutOfBounds {
+                at,
+                length,
+                size: self.buf.len(),
+            })
+    }
+
+    pub fn u8(&self, at: usize) -> Result<u8, ArchiveError> {
+        Ok(self.bytes(at, 1)?[0])
+    }
+
+    pub fn u32(&self, at: usize) -> Result<u32, ArchiveError> {
+        Ok(u32::from_le_bytes(self.bytes(at, 4)?.try_into().unwrap()))
+    }
+
+    /// `length` bytes at `at`, which must be UTF-8.
+    pub fn utf8(&self, at: usize, length: usize) -> Result<&'a str, ArchiveError> {
+        std::str::from_utf8(self.bytes(at, length)?)
+            .map_err(|source| ArchiveError::InvalidUtf8 { at, source })
+    }
+
+    /// The position `relative` bytes from `at`, if it's within the buffer.
+    fn relative(&self, at: usize, relative: i32) -> Result<usize, ArchiveError> {
+        at.checked_add_signed(relative as isize)
+            .filter(|&target| target <= self.buf.len())
+            .ok_or(ArchiveError::BadOffset {
+                at,
+                relative: relative.into(),
+            })
+    }
+
+    /// An archived `String` or `&str` at `at`.
+    pub fn string(&self, at: usize, layout: Layout) -> Result<&'a str, ArchiveError> {
+        match layout {
+            Layout::Rkyv04 => {
+                let relative = self.u32(at)? as i32;
+                let length = self.u32(at + 4)? as usize;
+                self.utf8(self.relative(at, relative)?, length)
+            }
+            Layout::Rkyv07 => {
+                let tag = self.u8(at + 7)?;
+                if tag & 0x80 == 0 {
+                    let length = tag as usize;
+                    if length > 7 {
+                        return Err(ArchiveError::TooLong {
+                            at,
+                            length,
+                            capacity: 7,
+                        });
+                    }
+                    return self.utf8(at, length);
+                }
+                let length = self.u32(at)? as usize;
+                // The offset is 31 bits, sign-extended from the tag bit
+                let relative = ((self.u32(at + 4)? << 1) as i32) >> 1;
+                self.utf8(self.relative(at, relative)?, length)
+            }
+        }
+    }
+
+    /// An archived `Option<u32>` at `at`.
+    pub fn option_u32(&self, at: usize, layout: Layout) -> Result<Option<u32>, ArchiveError> {
+        let tag = match layout {
+            Layout::Rkyv04 => self.u32(at)?,
+            Layout::Rkyv07 => self.u8(at)?.into(),
+        };
+        match tag {
+            0 => Ok(None),
+            1 => Ok(Some(self.u32(at + 4)?)),
+            tag => Err(ArchiveError::InvalidTag { at, tag }),
+        }
+    }
+
+    /// A `xous_ipc::String` of up to `capacity` bytes at `at`, which is a
+    /// 32-bit length followed by room for all `capacity` bytes. Its layout is
+    /// the same in every version.
+    pub fn fixed_string(&self, at: usize, capacity: usize) -> Result<&'a str, ArchiveError> {
+        let length = self.u32(at)? as usize;
+        if length > capacity {
+            return Err(ArchiveError::TooLong {
+                at,
+                length,
+                capacity,
+            });
+        }
+        self.utf8(at + 4, length)
+    }
+}
@@ -0,0 +1,132 @@
+//! A server on a real device, reached through the machine's `Bridge`. Each
+//! message is passed on as it was sent, and the thread that sent it waits
+//! for the device's reply without holding up the rest of the machine.
+
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+
+use super::{LendBuffer, LendResult, ResponseData, ScalarResult, Service, UnknownOpcode};
+use crate::xous::bridge::{Bridge, OnResponse, Request, RequestKind, Response};
+use crate::xous::definitions::SyscallResultNumber;
+use crate::xous::Memory;
+
+pub struct Bridged {
+    name: String,
+    bridge: Arc<Bridge>,
+}
+
+impl Bridged {
+    pub fn new(name: String, bridge: Arc<Bridge>) -> Self {
+        Bridged { name, bridge }
+    }
+
+    fn request(&self, kind: RequestKind, opcode: u32, args: [u32; 4], buf: &[u8]) -> Request {
+        Request {
+            kind,
+            name: self.name.clone(),
+            opcode,
+            args,
+            buf: buf.to_vec(),
+        }
+    }
+
+    /// Send `request`, returning what the sender will be resumed with once
+    /// the device replies, or with an error if the link fails first.
+    fn call(&self, request: Request) -> std::sync::mpsc::Receiver<ResponseData> {
+        let (tx, rx) = channel();
+        // Only a mutable lend may change the sender's memory, and then only
+        // the bytes of the buffer it lent that the device changed
+        let lent = match request.kind {
+            RequestKind::MutableLend => Some(request.buf.clone()),
+            _ => None,
+        };
+        let on_response: OnResponse = Box::new(move |response| {
+            let (result, buf) = response_data(response);
+            let changed = lent.zip(buf).map(|(lent, buf)| {
+                let mut lent = LendBuffer::new(lent);
+                lent.update(&buf);
+                lent
+            });
+            tx.send((result, changed)).ok();
+        });
+        self.bridge.send(request, Some(on_response));
+        rx
+    }
+}
+
+/// The registers to resume the sender with for `response`, and the buffer
+/// the device returned, if any.
+fn response_data(response: Response) -> ([i32; 8], Option<Vec<u8>>) {
+    let (number, values, buf): (SyscallResultNumber, &[u32], _) = match &response {
+        Response::Scalar1(value) => (
+            SyscallResultNumber::Scalar1,
+            std::slice::from_ref(value),
+            None,
+        ),
+        Response::Scalar2(values) => (SyscallResultNumber::Scalar2, values, None),
+        Response::Scalar5(values) => (SyscallResultNumber::Scalar5, values, None),
+        Response::MemoryReturned(values, buf) => (
+            SyscallResultNumber::MemoryReturned,
+            values,
+            Some(buf.clone()),
+        ),
+        Response::Error(code) => (SyscallResultNumber::Error, std::slice::from_ref(code), None),
+    };
+    let mut result = [0; 8];
+    result[0] = number as i32;
+    for (slot, value) in result[1..].iter_mut().zip(values) {
+        *slot = *value as i32;
+    }
+    (result, buf)
+}
+
+impl Service for Bridged {
+    fn scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        let request = self.request(RequestKind::Scalar, opcode, args, &[]);
+        self.bridge.send(request, None);
+        Ok(())
+    }
+
+    fn blocking_scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> ScalarResult {
+        let request = self.request(RequestKind::BlockingScalar, opcode, args, &[]);
+        ScalarResult::WaitForResponse(self.call(request))
+    }
+
+    fn lend(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &[u8],
+        extra: [u32; 2],
+    ) -> LendResult {
+        let args = [0, buf.len() as u32, extra[0], extra[1]];
+        let request = self.request(RequestKind::Lend, opcode, args, buf);
+        LendResult::WaitForResponse(self.call(request))
+    }
+
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        extra: [u32; 2],
+    ) -> LendResult {
+        let args = [0, buf.len() as u32, extra[0], extra[1]];
+        let request = self.request(RequestKind::MutableLend, opcode, args, buf);
+        LendResult::WaitForResponse(self.call(request))
+    }
+}
@@ -0,0 +1,114 @@
+//! The codec server, which plays audio through the machine's `Audio`. The
+//! program sends stereo frames at 8kHz and the sink takes as many as it has
+//! room for, resampling them to whatever rate it plays or records at.
+
+use std::sync::Arc;
+
+use super::{LendResult, ScalarResult, Service, UnknownOpcode};
+use crate::xous::audio::{Audio, Frame};
+use crate::xous::Memory;
+
+#[allow(dead_code)]
+enum CodecOpcode {
+    /// Power up the codec for 8kHz stereo, with the stream paused.
+    Setup8kStereo = 0,
+
+    /// Stop the stream and finish playing what's been sent.
+    PowerOff = 1,
+
+    ResumeStream = 2,
+    PauseStream = 3,
+
+    /// Returns 1 while the stream is playing.
+    IsLive = 4,
+
+    /// Play the frames in the lent buffer, each a 32-bit word with the left
+    /// sample in the low half and the right in the high half, of which the
+    /// second argument says how many bytes are valid. Returns how many
+    /// frames were taken, which is none while the stream is paused.
+    PlayFrames = 5,
+}
+
+pub struct Codec {
+    audio: Arc<Audio>,
+}
+
+impl Codec {
+    pub fn new(audio: Arc<Audio>) -> Self {
+        Codec { audio }
+    }
+
+    fn set(&self, opcode: u32) -> bool {
+        if opcode == CodecOpcode::Setup8kStereo as u32 || opcode == CodecOpcode::PauseStream as u32
+        {
+            self.audio.set_playing(false);
+        } else if opcode == CodecOpcode::PowerOff as u32 {
+            self.audio.set_playing(false);
+            self.audio.flush();
+        } else if opcode == CodecOpcode::ResumeStream as u32 {
+            self.audio.set_playing(true);
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+impl Service for Codec {
+    fn scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        _args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        match self.set(opcode) {
+            true => Ok(()),
+            false => Err(UnknownOpcode),
+        }
+    }
+
+    fn blocking_scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        _args: [u32; 4],
+    ) -> ScalarResult {
+        if opcode == CodecOpcode::IsLive as u32 {
+            ScalarResult::Scalar1(self.audio.playing() as u32)
+        } else if self.set(opcode) {
+            ScalarResult::Scalar1(0)
+        } else {
+            ScalarResult::UnknownOpcode
+        }
+    }
+
+    fn lend(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &[u8],
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == CodecOpcode::PlayFrames as u32 {
+            if !self.audio.playing() {
+                return LendResult::MemoryReturned([0, 0]);
+            }
+            let length = (extra[1] as usize).min(buf.len());
+            let frames: Vec<Frame> = buf[..length]
+                .chunks_exact(4)
+                .map(|frame| {
+                    [
+                        i16::from_le_bytes([frame[0], frame[1]]),
+                        i16::from_le_bytes([frame[2], frame[3]]),
+                    ]
+                })
+                .collect();
+            let taken = self.audio.play(&frames);
+            return LendResult::MemoryReturned([0, taken as u32]);
+        }
+        LendResult::UnknownOpcode
+    }
+}
@@ -0,0 +1,146 @@
+//! The COM server, which passes requests on to the embedded controller. Its
+//! battery, charger, and wifi status come from the machine's `Ec`, and the
+//! settings the program makes are recorded there rather than acted on.
+
+use std::sync::Arc;
+
+use super::{LendBuffer, LendResult, ScalarResult, Service, UnknownOpcode};
+use crate::xous::ec::Ec;
+use crate::xous::Memory;
+
+#[allow(dead_code)]
+enum ComOpcode {
+    /// Returns the battery voltage and average current, then the state of
+    /// charge and remaining capacity, packed into two words.
+    BattStats = 0,
+
+    BoostOn = 2,
+    BoostOff = 3,
+
+    /// Returns 1 while the battery is charging.
+    IsCharging = 6,
+
+    /// Set the main and secondary backlight brightness.
+    SetBackLight = 7,
+
+    /// Ask the charger to start charging if there's power.
+    RequestCharging = 8,
+
+    WlanOn = 20,
+    WlanOff = 21,
+
+    /// Returns the signal strength in dBm.
+    WlanRssi = 26,
+
+    /// Fills the lent buffer with the networks the last scan found, as a
+    /// 32-bit length followed by one `<rssi> <name>` line for each.
+    SsidFetchAsString = 31,
+}
+
+pub struct Com {
+    ec: Arc<Ec>,
+}
+
+impl Com {
+    pub fn new(ec: Arc<Ec>) -> Self {
+        Com { ec }
+    }
+
+    fn set(&self, opcode: u32, args: [u32; 4]) -> bool {
+        if opcode == ComOpcode::BoostOn as u32 {
+            self.ec.update(|status| status.boost = true);
+        } else if opcode == ComOpcode::BoostOff as u32 {
+            self.ec.update(|status| status.boost = false);
+        } else if opcode == ComOpcode::SetBackLight as u32 {
+            self.ec
+                .update(|status| status.backlight = (args[0] as u8, args[1] as u8));
+        } else if opcode == ComOpcode::RequestCharging as u32 {
+            // There's no charger to ask, so charging is up to the host
+        } else if opcode == ComOpcode::WlanOn as u32 {
+            self.ec.update(|status| status.wifi_on = true);
+        } else if opcode == ComOpcode::WlanOff as u32 {
+            self.ec.update(|status| status.wifi_on = false);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn battery(&self) -> ScalarResult {
+        let status = self.ec.status();
+        ScalarResult::Scalar2([
+            status.voltage_mv as u32 | (status.current_ma as u16 as u32) << 16,
+            status.soc as u32 | (status.remaining_mah as u32) << 16,
+        ])
+    }
+
+    fn ssids(&self, buf: &mut LendBuffer) -> LendResult {
+        let listing: String = self
+            .ec
+            .status()
+            .ssids
+            .iter()
+            .map(|ssid| format!("{} {}\n", ssid.rssi, ssid.name))
+            .collect();
+        // Return as many whole lines as fit
+        let room = buf.len().saturating_sub(4);
+        let mut length = listing.len().min(room);
+        if length < listing.len() {
+            length = listing[..length].rfind('\n').map_or(0, |end| end + 1);
+        }
+        if buf.len() >= 4 {
+            buf.write(0, &(length as u32).to_le_bytes());
+            buf.write(4, &listing.as_bytes()[..length]);
+        }
+        LendResult::MemoryReturned([0, length as u32])
+    }
+}
+
+impl Service for Com {
+    fn scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        match self.set(opcode, args) {
+            true => Ok(()),
+            false => Err(UnknownOpcode),
+        }
+    }
+
+    fn blocking_scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> ScalarResult {
+        if opcode == ComOpcode::BattStats as u32 {
+            self.battery()
+        } else if opcode == ComOpcode::IsCharging as u32 {
+            ScalarResult::Scalar1(self.ec.status().charging as u32)
+        } else if opcode == ComOpcode::WlanRssi as u32 {
+            ScalarResult::Scalar1(self.ec.status().rssi as i32 as u32)
+        } else if self.set(opcode, args) {
+            ScalarResult::Scalar1(0)
+        } else {
+            ScalarResult::UnknownOpcode
+        }
+    }
+
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        _extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == ComOpcode::SsidFetchAsString as u32 {
+            return self.ssids(buf);
+        }
+        LendResult::UnknownOpcode
+    }
+}
@@ -1,6 +1,6 @@
 use std::net::{SocketAddr, ToSocketAddrs};
 
-use super::{LendResult, Service};
+use super::{Message, MessageMemory, Reply, Service};
 use crate::xous::Memory;
 const DNS_NAME_LENGTH_LIMIT: usize = 256;
 
@@ -10,16 +10,12 @@ enum DnsLendMutOpcode {
 
 pub struct DnsResolver {}
 
-fn name_from_msg(msg: &[u8], valid: u32) -> Result<String, ()> {
-    let valid_bytes = usize::min(msg.len(), valid as usize);
-    if valid_bytes == 0 || valid_bytes >= DNS_NAME_LENGTH_LIMIT {
+fn name_from_msg(msg: &MessageMemory) -> Result<String, ()> {
+    let name = msg.str().map_err(|_| ())?;
+    if name.is_empty() || name.len() >= DNS_NAME_LENGTH_LIMIT {
         return Err(());
     }
-
-    // Safe because we've already validated that it's a valid range
-    let str_slice = &msg[0..valid_bytes];
-    let name_string = core::str::from_utf8(str_slice).or(Err(()))?;
-    Ok(name_string.to_owned())
+    Ok(name.to_owned())
 }
 
 impl DnsResolver {
@@ -27,49 +23,35 @@ impl DnsResolver {
         DnsResolver {}
     }
 
-    fn lookup(&self, buf: &mut [u8], valid: u32) -> LendResult {
-        let Ok(query_string) = name_from_msg(buf, valid) else {
-            buf[0..4].copy_from_slice(&1u32.to_le_bytes());
-            buf[4..8].copy_from_slice(&1u32.to_le_bytes());
-            return LendResult::MemoryReturned([0, 0]);
-        };
-        let Ok(addrs) = (query_string.as_str(), 0u16)
-            .to_socket_addrs()
-            .map(|iter| iter.collect::<Vec<_>>())
-            .map_err(|_| {
-                buf[0..4].copy_from_slice(&1u32.to_le_bytes());
-                buf[4..8].copy_from_slice(&1u32.to_le_bytes());
-            })
-        else {
-            return LendResult::MemoryReturned([0, 0]);
+    fn lookup(&self, msg: &mut MessageMemory) -> Reply {
+        let Ok(addrs) = name_from_msg(msg).and_then(|query_string| {
+            (query_string.as_str(), 0u16)
+                .to_socket_addrs()
+                .map(|iter| iter.collect::<Vec<_>>())
+                .map_err(|_| ())
+        }) else {
+            msg.write_u32(0, 1).and_then(|()| msg.write_u32(4, 1));
+            return Reply::MemoryReturned([0, 0]);
         };
 
-        let mut cursor = buf.iter_mut();
-
-        // No error
-        *cursor.next().unwrap() = 0;
-
-        // Number of entries
-        *cursor.next().unwrap() = addrs.len() as u8;
-
+        // No error, followed by the number of entries
+        let mut response = vec![0, 0];
         for entry in addrs {
-            match entry {
-                SocketAddr::V4(a) => {
-                    *cursor.next().unwrap() = 4;
-                    for byte in a.ip().octets().iter() {
-                        *cursor.next().unwrap() = *byte;
-                    }
-                }
-                SocketAddr::V6(a) => {
-                    *cursor.next().unwrap() = 6;
-                    for byte in a.ip().octets().iter() {
-                        *cursor.next().unwrap() = *byte;
-                    }
-                }
+            let (kind, octets) = match entry {
+                SocketAddr::V4(a) => (4, a.ip().octets().to_vec()),
+                SocketAddr::V6(a) => (6, a.ip().octets().to_vec()),
+            };
+            // Return as many entries as fit in the buffer
+            if response[1] == u8::MAX || response.len() + 1 + octets.len() > msg.as_slice().len() {
+                break;
             }
+            response[1] += 1;
+            response.push(kind);
+            response.extend(octets);
         }
+        msg.write_bytes(0, &response);
 
-        LendResult::MemoryReturned([0, 0])
+        Reply::MemoryReturned([0, 0])
     }
 }
 
@@ -80,17 +62,11 @@ impl Default for DnsResolver {
 }
 
 impl Service for DnsResolver {
-    fn lend_mut(
-        &self,
-        _memory: &Memory,
-        _sender: u32,
-        opcode: u32,
-        buf: &mut [u8],
-        extra: [u32; 2],
-    ) -> LendResult {
-        if opcode == DnsLendMutOpcode::RawLookup as u32 {
-            return self.lookup(buf, extra[1]);
+    fn message(&self, _memory: &Memory, mut message: Message) -> Reply {
+        let opcode = message.opcode;
+        match message.memory_mut() {
+            Some(buf) if opcode == DnsLendMutOpcode::RawLookup as u32 => self.lookup(buf),
+            _ => Reply::UnknownOpcode,
         }
-        panic!("Unhandled dns opcode {}", opcode);
     }
 }
@@ -0,0 +1,51 @@
+//! The engine-25519 server, which drives the Curve25519 engine. Rather than
+//! run the engine's microcode, the Diffie-Hellman function it's used for is
+//! done directly in software.
+//!
+//! Its protocol is yove's own, as the `services` module explains.
+
+use super::{LendBuffer, LendResult, Service};
+use crate::xous::Memory;
+
+/// The name programs connect to the engine-25519 server by.
+pub const NAME: &str = "yove-engine25519";
+
+#[allow(dead_code)]
+enum Engine25519Opcode {
+    /// Multiply the point whose u-coordinate is in the second 32 bytes of
+    /// the lent buffer by the scalar in the first 32, as in RFC 7748, and
+    /// write the u-coordinate of the result over the scalar. Returns 32,
+    /// or 0 if the buffer is too short.
+    X25519 = 0,
+}
+
+#[derive(Default)]
+pub struct Engine25519;
+
+impl Engine25519 {
+    pub fn new() -> Self {
+        Engine25519
+    }
+}
+
+impl Service for Engine25519 {
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        _extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == Engine25519Opcode::X25519 as u32 {
+            if buf.len() < 64 {
+                return LendResult::MemoryReturned([0, 0]);
+            }
+            let scalar: [u8; 32] = buf[..32].try_into().unwrap();
+            let point: [u8; 32] = buf[32..64].try_into().unwrap();
+            buf.write(0, &x25519_dalek::x25519(scalar, point));
+            return LendResult::MemoryReturned([0, 32]);
+        }
+        LendResult::UnknownOpcode
+    }
+}
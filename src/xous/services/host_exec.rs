@@ -0,0 +1,99 @@
+//! The yove-host-exec server, which runs commands on the host for the
+//! program. It only exists when the machine was given an allowlist, and
+//! hands every request to the machine's `HostExec`. The sender waits for
+//! the command to finish without holding up the rest of the machine.
+
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+
+use super::{LendBuffer, LendResult, Service};
+use crate::xous::definitions::{SyscallErrorNumber, SyscallResultNumber};
+use crate::xous::host_exec::{ExecOutcome, HostExec};
+use crate::xous::Memory;
+
+/// The name the program connects to.
+pub const NAME: &str = "yove-host-exec";
+
+enum HostExecOpcode {
+    /// Run the command in the valid bytes of the lent buffer, which are its
+    /// name and arguments separated by NUL bytes. The buffer is filled with
+    /// as much of what it wrote to stdout as fits, and the memory returned
+    /// is its exit code, or `!0` if it was killed by a signal, and the
+    /// length of everything it wrote. Fails with `AccessDenied` if the
+    /// command isn't allowed, and `InternalError` if it couldn't be started.
+    Run = 0,
+}
+
+pub struct HostExecService {
+    exec: Arc<HostExec>,
+}
+
+impl HostExecService {
+    pub fn new(exec: Arc<HostExec>) -> Self {
+        HostExecService { exec }
+    }
+}
+
+fn error(number: SyscallErrorNumber) -> [i32; 8] {
+    [
+        SyscallResultNumber::Error as i32,
+        number as i32,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ]
+}
+
+impl Service for HostExecService {
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode != HostExecOpcode::Run as u32 {
+            return LendResult::UnknownOpcode;
+        }
+        let (tx, rx) = channel();
+        let valid = &buf[..buf.len().min(extra[1] as usize)];
+        let argv = match std::str::from_utf8(valid) {
+            Ok(command) => command.split('\0').map(str::to_owned).collect(),
+            Err(_) => {
+                tx.send((error(SyscallErrorNumber::InvalidString), None))
+                    .ok();
+                return LendResult::WaitForResponse(rx);
+            }
+        };
+        let exec = self.exec.clone();
+        let capacity = buf.len();
+        std::thread::spawn(move || {
+            let response = match exec.run(sender, argv) {
+                (ExecOutcome::Refused, _) => (error(SyscallErrorNumber::AccessDenied), None),
+                (ExecOutcome::Failed(_), _) => (error(SyscallErrorNumber::InternalError), None),
+                (ExecOutcome::Exited(code), stdout) => {
+                    let length = stdout.len();
+                    let mut lent = LendBuffer::new(vec![0; capacity]);
+                    lent.write(0, &stdout[..length.min(capacity)]);
+                    let result = [
+                        SyscallResultNumber::MemoryReturned as i32,
+                        code.unwrap_or(!0),
+                        length as i32,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                    ];
+                    (result, Some(lent))
+                }
+            };
+            tx.send(response).ok();
+        });
+        LendResult::WaitForResponse(rx)
+    }
+}
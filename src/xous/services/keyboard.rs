@@ -0,0 +1,80 @@
+//! The keyboard server, which on hardware scans the Betrusted keyboard
+//! matrix and maps it to characters. Under emulation the keys come from the
+//! machine's `Keyboard`, which the host presses directly or through a key
+//! script.
+//!
+//! The Xous keyboard server pushes each key to a server that the program
+//! registers with `RegisterListener`. Programs can't run servers of their
+//! own here, so keys are pulled instead, with `BlockingKeyListener`. That
+//! makes the protocol yove's own, so it's served under a name of its own:
+//! a program built for the real server finds no server rather than one that
+//! misreads its messages.
+
+use std::sync::Arc;
+
+use super::{ScalarResult, Service, UnknownOpcode};
+use crate::xous::keyboard::Keyboard;
+use crate::xous::Memory;
+
+/// The name programs connect to the keyboard server by.
+pub const NAME: &str = "yove-keyboard";
+
+enum KeyboardOpcode {
+    /// Returns the next key pressed as a Scalar1 of its character, waiting
+    /// for one if the program has read them all.
+    BlockingKeyListener = 0,
+
+    /// Press the key whose character is the first argument, as if it had
+    /// been typed on the keyboard.
+    InjectKey = 1,
+}
+
+pub struct KeyboardService {
+    keyboard: Arc<Keyboard>,
+}
+
+impl KeyboardService {
+    pub fn new(keyboard: Arc<Keyboard>) -> Self {
+        KeyboardService { keyboard }
+    }
+
+    fn inject(&self, key: u32) {
+        match char::from_u32(key) {
+            Some(key) => self.keyboard.press(key),
+            None => log::warn!("Program injected an invalid key {:#x}", key),
+        }
+    }
+}
+
+impl Service for KeyboardService {
+    fn scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        if opcode != KeyboardOpcode::InjectKey as u32 {
+            return Err(UnknownOpcode);
+        }
+        self.inject(args[0]);
+        Ok(())
+    }
+
+    fn blocking_scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> ScalarResult {
+        if opcode == KeyboardOpcode::BlockingKeyListener as u32 {
+            self.keyboard.listen()
+        } else if opcode == KeyboardOpcode::InjectKey as u32 {
+            self.inject(args[0]);
+            ScalarResult::Scalar1(0)
+        } else {
+            ScalarResult::UnknownOpcode
+        }
+    }
+}
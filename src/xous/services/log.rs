@@ -1,6 +1,5 @@
-use super::{LendResult, Service};
+use super::{archive::Archive, LendResult, Service, UnknownOpcode};
 use crate::xous::Memory;
-use std::io::Write;
 
 enum LendOpcode {
     /// A `LogRecord` message, delivering structured log output
@@ -64,24 +63,22 @@ impl Log {
         Log {}
     }
 
-    fn str_from_log_record<'a>(&self, buf: &'a [u8], offset: usize) -> &'a str {
-        let length =
-            u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap_or([0; 4])) as usize;
-        core::str::from_utf8(
-            buf[offset + 4..offset + 4 + length]
-                .try_into()
-                .unwrap_or(&[]),
-        )
-        .unwrap_or("<invalid utf-8>")
+    fn str_from_log_record<'a>(archive: &Archive<'a>, at: usize, capacity: usize) -> &'a str {
+        archive.fixed_string(at, capacity).unwrap_or("<invalid>")
     }
 
-    fn log_record(&self, buf: &[u8]) -> LendResult {
-        let filename = self.str_from_log_record(buf, 0);
-        let line_num = u32::from_le_bytes(buf[132..136].try_into().unwrap_or([0; 4]));
-        let module = self.str_from_log_record(buf, 136);
-        let args = self.str_from_log_record(buf, 272);
-
-        let level = match u32::from_le_bytes(buf[268..272].try_into().unwrap_or([0; 4])) {
+    /// Write a `LogRecord` from the program to its stderr. These are the
+    /// program's own diagnostics, so they're kept apart from the emulator's.
+    fn log_record(&self, memory: &Memory, buf: &[u8]) -> LendResult {
+        // A `LogRecord` is three `xous_ipc::String`s and two words, in a fixed
+        // layout that has never changed between versions
+        let archive = Archive::new(buf);
+        let filename = Self::str_from_log_record(&archive, 0, 128);
+        let line_num = archive.u32(132).unwrap_or(0);
+        let module = Self::str_from_log_record(&archive, 136, 128);
+        let args = Self::str_from_log_record(&archive, 272, buf.len().saturating_sub(276));
+
+        let level = match archive.u32(268).unwrap_or(0) {
             1 => "ERR ",
             2 => "WARN",
             3 => "INFO",
@@ -90,7 +87,12 @@ impl Log {
             _ => "UNKNOWN",
         };
 
-        println!("{}:{} {} ({}:{})", level, module, args, filename, line_num);
+        let line = format!(
+            "{}:{} {} ({}:{})\n",
+            level, module, args, filename, line_num
+        );
+        memory.platform.write_stderr(line.as_bytes());
+        memory.keyboard.log(line.as_bytes());
 
         LendResult::MemoryReturned([0, 0])
     }
@@ -103,12 +105,17 @@ impl Default for Log {
 }
 
 impl Service for Log {
-    fn scalar(&self, _memory: &Memory, sender: u32, opcode: u32, args: [u32; 4]) {
+    fn scalar(
+        &self,
+        memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
         if ScalarOpcode::PanicStarted as u32 == opcode {
-            println!("Panic started");
+            memory.platform.write_stderr(b"Panic started\n");
         } else if ScalarOpcode::PanicFinished as u32 == opcode {
-            println!();
-            println!("Panic finished");
+            memory.platform.write_stderr(b"\nPanic finished\n");
         } else if opcode >= ScalarOpcode::PanicMessage0 as u32
             && opcode <= ScalarOpcode::PanicMessage32 as u32
         {
@@ -125,39 +132,39 @@ impl Service for Log {
                 //     *(output_iter.next().unwrap()) = *src;
                 // }
             }
-            eprint!(
-                "{}",
-                std::str::from_utf8(&output_bfr[0..message_bytes as usize]).unwrap_or("<invalid>")
-            );
+            memory
+                .platform
+                .write_stderr(&output_bfr[0..message_bytes as usize]);
         } else {
-            println!("Log scalar {}: {} {:x?}", sender, opcode, args);
+            return Err(UnknownOpcode);
         }
+        Ok(())
     }
 
     fn lend(
         &self,
-        _memory: &Memory,
-        sender: u32,
+        memory: &Memory,
+        _sender: u32,
         opcode: u32,
         buf: &[u8],
         extra: [u32; 2],
     ) -> LendResult {
         if opcode == LendOpcode::LogRecord as u32 {
-            self.log_record(buf)
+            self.log_record(memory, buf)
         } else if opcode == LendOpcode::StandardOutput as u32 {
             let print_buffer = &buf[0..extra[1] as usize];
             // println!("Log stdout:");
-            std::io::stdout().write_all(print_buffer).unwrap();
-            std::io::stdout().flush().unwrap();
+            memory.platform.write_stdout(print_buffer);
+            memory.keyboard.log(print_buffer);
             LendResult::MemoryReturned([0, 0])
         } else if opcode == LendOpcode::StandardError as u32 {
             let print_buffer = &buf[0..extra[1] as usize];
             // println!("Log stderr:");
-            std::io::stderr().write_all(print_buffer).unwrap();
-            std::io::stderr().flush().unwrap();
+            memory.platform.write_stderr(print_buffer);
+            memory.keyboard.log(print_buffer);
             LendResult::MemoryReturned([0, 0])
         } else {
-            panic!("Unhandled log lend {}: {} {:x?}", sender, opcode, buf);
+            LendResult::UnknownOpcode
         }
     }
 }
@@ -0,0 +1,271 @@
+//! A typed view of a message sent to a service, so that services don't need
+//! to pick apart raw buffers and argument arrays themselves.
+
+use std::ops::{Deref, Range};
+use std::sync::mpsc::Receiver;
+
+use super::archive::{Archive, ArchiveError};
+use super::{LendResult, ResponseData, ScalarResult};
+use crate::xous::definitions::SyscallErrorNumber;
+
+/// How a message was sent, which decides what it carries and whether the
+/// sender waits for a reply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    /// Memory is lent to the service, which may change it before returning it.
+    MutableLend,
+
+    /// Memory is lent to the service, which may only read it.
+    Lend,
+
+    /// Memory is given to the service, and the sender doesn't wait.
+    Send,
+
+    /// Four words of arguments, and the sender doesn't wait.
+    Scalar,
+
+    /// Four words of arguments, and the sender waits for a scalar reply.
+    BlockingScalar,
+}
+
+impl MessageKind {
+    /// Decode the kind as numbered by `SendMessage`.
+    pub fn from_u32(kind: u32) -> Option<Self> {
+        match kind {
+            1 => Some(MessageKind::MutableLend),
+            2 => Some(MessageKind::Lend),
+            3 => Some(MessageKind::Send),
+            4 => Some(MessageKind::Scalar),
+            5 => Some(MessageKind::BlockingScalar),
+            _ => None,
+        }
+    }
+
+    /// Whether the message carries memory rather than scalar arguments.
+    pub fn has_memory(self) -> bool {
+        matches!(
+            self,
+            MessageKind::MutableLend | MessageKind::Lend | MessageKind::Send
+        )
+    }
+}
+
+/// A copy of the memory the sender lent or sent. Services can read all of
+/// it, but can only change it through methods that check the write stays
+/// inside the buffer and record where it went. Once a mutable lend returns
+/// successfully, just the recorded writes are copied back to the sender, so a
+/// service that fails part way through leaves the sender's memory untouched.
+pub struct LendBuffer {
+    data: Vec<u8>,
+
+    /// The ranges that have been written, in the order they were written.
+    writes: Vec<Range<usize>>,
+}
+
+impl LendBuffer {
+    pub fn new(data: Vec<u8>) -> Self {
+        LendBuffer {
+            data,
+            writes: vec![],
+        }
+    }
+
+    /// Copy `data` into the buffer at `at`, or return `None` without writing
+    /// anything if it doesn't fit.
+    pub fn write(&mut self, at: usize, data: &[u8]) -> Option<()> {
+        self.get_mut(at..at.checked_add(data.len())?)?
+            .copy_from_slice(data);
+        Some(())
+    }
+
+    /// The bytes in `range` to change in place, or `None` if it isn't inside
+    /// the buffer. The whole range counts as written.
+    pub fn get_mut(&mut self, range: Range<usize>) -> Option<&mut [u8]> {
+        if range.start > range.end || range.end > self.data.len() {
+            return None;
+        }
+        self.writes.push(range.clone());
+        Some(&mut self.data[range])
+    }
+
+    /// Replace the contents with `data`, as from a service that hands back
+    /// the whole buffer, counting only the bytes that change as written.
+    /// Anything past the end of the buffer is dropped.
+    pub fn update(&mut self, data: &[u8]) {
+        let length = data.len().min(self.data.len());
+        let mut at = 0;
+        while at < length {
+            if self.data[at] == data[at] {
+                at += 1;
+                continue;
+            }
+            let start = at;
+            while at < length && self.data[at] != data[at] {
+                at += 1;
+            }
+            self.write(start, &data[start..at]);
+        }
+    }
+
+    /// Every write made so far, as the offset it was made at and the bytes
+    /// there now. Later writes come after earlier ones they overlap.
+    pub fn writes(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.writes
+            .iter()
+            .map(|range| (range.start, &self.data[range.clone()]))
+    }
+}
+
+impl Deref for LendBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// The memory attached to a lend or send. Every accessor is bounds-checked
+/// and returns `None` rather than panicking when the guest passes a buffer
+/// that is too small or malformed.
+pub struct MessageMemory<'a> {
+    buf: &'a mut LendBuffer,
+    offset: u32,
+    valid: u32,
+}
+
+impl<'a> MessageMemory<'a> {
+    pub fn new(buf: &'a mut LendBuffer, offset: u32, valid: u32) -> Self {
+        MessageMemory { buf, offset, valid }
+    }
+
+    /// The whole buffer. Only changes made during a mutable lend are seen by
+    /// the guest.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..]
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut LendBuffer {
+        self.buf
+    }
+
+    /// The sender's `offset` argument. For rkyv-encoded buffers this is the
+    /// position of the root object.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// The first `valid` bytes of the buffer, or all of it if the sender's
+    /// `valid` argument is too large. This is usually the data being sent.
+    pub fn valid_bytes(&self) -> &[u8] {
+        &self.buf[..self.buf.len().min(self.valid as usize)]
+    }
+
+    /// The valid bytes of the buffer as a string, as sent by `&str` arguments.
+    pub fn str(&self) -> Result<&str, ArchiveError> {
+        let valid = self.valid_bytes();
+        Archive::new(valid).utf8(0, valid.len())
+    }
+
+    /// The whole buffer, for decoding the values archived in it.
+    pub fn archive(&self) -> Archive<'_> {
+        Archive::new(&self.buf[..])
+    }
+
+    pub fn write_bytes(&mut self, at: usize, data: &[u8]) -> Option<()> {
+        self.buf.write(at, data)
+    }
+
+    pub fn write_u32(&mut self, at: usize, value: u32) -> Option<()> {
+        self.write_bytes(at, &value.to_le_bytes())
+    }
+}
+
+/// A message sent to a service.
+pub struct Message<'a> {
+    /// The thread that sent the message.
+    pub sender: u32,
+    pub opcode: u32,
+    pub kind: MessageKind,
+
+    /// The arguments to a scalar message. For messages with memory, these are
+    /// the address, size, offset, and valid arguments.
+    pub args: [u32; 4],
+    memory: Option<MessageMemory<'a>>,
+}
+
+impl<'a> Message<'a> {
+    pub fn new(
+        sender: u32,
+        opcode: u32,
+        kind: MessageKind,
+        args: [u32; 4],
+        memory: Option<MessageMemory<'a>>,
+    ) -> Self {
+        Message {
+            sender,
+            opcode,
+            kind,
+            args,
+            memory,
+        }
+    }
+
+    /// The memory carried by a lend or send.
+    pub fn memory(&self) -> Option<&MessageMemory<'a>> {
+        self.memory.as_ref()
+    }
+
+    /// The memory carried by a mutable lend. Other messages can't change the
+    /// sender's memory, so this returns `None` for them.
+    pub fn memory_mut(&mut self) -> Option<&mut MessageMemory<'a>> {
+        match self.kind {
+            MessageKind::MutableLend => self.memory.as_mut(),
+            _ => None,
+        }
+    }
+}
+
+/// What a service returns for a message.
+pub enum Reply {
+    /// Nothing, for messages whose sender doesn't wait.
+    Ok,
+    Scalar1(u32),
+    Scalar2([u32; 2]),
+    Scalar5([u32; 5]),
+
+    /// Lent memory is returned to the sender along with two words.
+    MemoryReturned([u32; 2]),
+
+    /// The sender is paused until a response arrives on the channel.
+    WaitForResponse(Receiver<ResponseData>),
+
+    /// The service doesn't handle the message's opcode.
+    UnknownOpcode,
+
+    /// The service can't act on the message, for the reason given, and the
+    /// sender gets the error.
+    Refused(SyscallErrorNumber, String),
+}
+
+impl From<ScalarResult> for Reply {
+    fn from(result: ScalarResult) -> Self {
+        match result {
+            ScalarResult::Scalar1(value) => Reply::Scalar1(value),
+            ScalarResult::Scalar2(values) => Reply::Scalar2(values),
+            ScalarResult::Scalar5(values) => Reply::Scalar5(values),
+            ScalarResult::WaitForResponse(receiver) => Reply::WaitForResponse(receiver),
+            ScalarResult::UnknownOpcode => Reply::UnknownOpcode,
+            ScalarResult::Refused(error, reason) => Reply::Refused(error, reason),
+        }
+    }
+}
+
+impl From<LendResult> for Reply {
+    fn from(result: LendResult) -> Self {
+        match result {
+            LendResult::MemoryReturned(values) => Reply::MemoryReturned(values),
+            LendResult::WaitForResponse(receiver) => Reply::WaitForResponse(receiver),
+            LendResult::UnknownOpcode => Reply::UnknownOpcode,
+        }
+    }
+}
@@ -1,12 +1,8 @@
-use std::{
-    collections::HashMap,
-    sync::{atomic::Ordering, mpsc::channel, Arc, Mutex},
-    thread,
-};
+use std::sync::Arc;
 
-use crate::xous::{definitions::SyscallResultNumber, Memory};
+use crate::xous::{definitions::SyscallErrorNumber, Memory};
 
-use super::{LendResult, Service};
+use super::{Message, MessageMemory, Reply, Service};
 
 #[allow(dead_code)]
 enum NameLendOpcode {
@@ -33,7 +29,7 @@ enum NameLendOpcode {
     ///
     /// # Message Types
     ///
-    ///     * MutableLend
+    /// * MutableLend
     ///
     /// # Arguments
     ///
@@ -45,7 +41,7 @@ enum NameLendOpcode {
     /// Memory is overwritten to contain a return value.  This return value can be defined
     /// as the following enum:
     ///
-    /// ```rust
+    /// ```ignore
     /// #[repr(C)]
     /// #[non_exhaustive]
     /// enum ConnectResult {
@@ -61,7 +57,7 @@ enum NameLendOpcode {
     ///
     /// # Message Types
     ///
-    ///     * MutableLend
+    /// * MutableLend
     ///
     /// # Arguments
     ///
@@ -73,7 +69,7 @@ enum NameLendOpcode {
     /// Memory is overwritten to contain a return value.  This return value can be defined
     /// as the following enum:
     ///
-    /// ```rust
+    /// ```ignore
     /// #[repr(C)]
     /// #[non_exhaustive]
     /// enum ConnectResult {
@@ -83,19 +79,77 @@ enum NameLendOpcode {
     /// }
     /// ```
     TryConnect = 7,
+
+    /// Not part of Xous. Fill the lent buffer with every name the program has
+    /// registered or connected to, as a line for each of the form
+    /// `name\thash\tconnections\tlimit\n`, with the hash in hex and a limit
+    /// of `-` when there isn't one. Returns the length of the whole listing,
+    /// which is more than the buffer holds if it was cut short.
+    ///
+    /// # Message Types
+    ///
+    /// * MutableLend
+    List = 256,
+}
+
+/// A name known to the name server, either because the program registered
+/// it or because the program connected to a service by that name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameInfo {
+    pub name: String,
+
+    /// The server ID the name server hands out for the name.
+    pub hash: u128,
+
+    /// How many open connections there are to the name.
+    pub connections: u32,
+
+    /// The most connections the program allowed when it registered the name,
+    /// if it registered it with a limit.
+    pub connection_limit: Option<u32>,
+
+    /// Whether the program registered the name, rather than only connecting
+    /// to a service the emulator provides under it.
+    pub registered: bool,
 }
 
-pub struct Name {
-    connection_index: Arc<Mutex<HashMap<String, u32>>>,
-    name_map: Arc<Mutex<HashMap<String, u128>>>,
+/// Every name registered with the name server or connected to through it,
+/// in order.
+pub(crate) fn list(memory: &Memory) -> Vec<NameInfo> {
+    let mut names: Vec<NameInfo> = memory
+        .names
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, limit)| NameInfo {
+            name: name.clone(),
+            hash: Name::djb2_hash(name),
+            connections: 0,
+            connection_limit: *limit,
+            registered: true,
+        })
+        .collect();
+    for (name, connections) in memory.connections.lock().unwrap().names() {
+        match names.iter_mut().find(|info| info.name == name) {
+            Some(info) => info.connections = connections,
+            None => names.push(NameInfo {
+                hash: Name::djb2_hash(&name),
+                name,
+                connections,
+                connection_limit: None,
+                registered: false,
+            }),
+        }
+    }
+    names.sort_by(|a, b| a.name.cmp(&b.name));
+    names
 }
 
+pub struct Name;
+
 impl Name {
     pub fn new() -> Self {
-        Name {
-            connection_index: Arc::new(Mutex::new(HashMap::default())),
-            name_map: Arc::new(Mutex::new(HashMap::default())),
-        }
+        Name
     }
 
     fn djb2_hash(path: &str) -> u128 {
@@ -108,31 +162,43 @@ impl Name {
         hash
     }
 
-    fn register_name(&self, buf: &mut [u8], rkyv_header: u32) -> LendResult {
-        let rkyv_header = rkyv_header as usize;
-
-        let conn_limit_is_some =
-            u32::from_le_bytes(buf[rkyv_header..rkyv_header + 4].try_into().unwrap()) != 0;
-        let conn_limit = if conn_limit_is_some {
-            Some(u32::from_le_bytes(
-                buf[rkyv_header + 4..rkyv_header + 8].try_into().unwrap(),
-            ))
-        } else {
-            None
+    fn register_name(&self, memory: &Memory, buf: &mut MessageMemory) -> Reply {
+        // The registration is an rkyv-encoded `(Option<u32>, String)` of the
+        // connection limit and the name.
+        let root = buf.offset() as usize;
+        let registration = buf.archive().decode(|archive, layout| {
+            let conn_limit = archive.option_u32(root, layout)?;
+            let server_name = archive.string(root + 8, layout)?;
+            Ok((conn_limit, server_name.to_owned()))
+        });
+        let (conn_limit, server_name) = match registration {
+            Ok((_, registration)) => registration,
+            Err(error) => {
+                // Leave the buffer as it was, rather than registering a name
+                // the program never asked for
+                log::warn!("Program sent an invalid registration: {}", error);
+                return Reply::MemoryReturned([0, 0]);
+            }
         };
-
-        let name_header = rkyv_header + 8;
-        let name_offset = i32::from_le_bytes(buf[name_header..name_header + 4].try_into().unwrap());
-        let name_length =
-            u32::from_le_bytes(buf[name_header + 4..name_header + 8].try_into().unwrap());
-        let name_slice = &buf[name_header + name_offset as usize
-            ..name_header + name_offset as usize + name_length as usize];
-
-        let server_name = std::str::from_utf8(name_slice)
-            .unwrap_or("<invalid>")
-            .to_owned();
+        // The response is a tag and the 16-byte hash
+        if buf.as_slice().len() < 20 {
+            return Reply::Refused(
+                SyscallErrorNumber::BadAddress,
+                format!(
+                    "registration buffer of {} bytes is too small",
+                    buf.as_slice().len()
+                ),
+            );
+        }
+        let mut names = memory.names.lock().unwrap();
+        if names.contains_key(&server_name) {
+            return Reply::Refused(
+                SyscallErrorNumber::ServerExists,
+                format!("\"{}\" is already registered", server_name),
+            );
+        }
         let hash = Self::djb2_hash(&server_name);
-        println!(
+        log::info!(
             "Program is registering service \"{}\" with {}",
             server_name,
             if let Some(max) = conn_limit {
@@ -152,16 +218,120 @@ impl Name {
 
         // Construct the rkyv object by hand.
         let rkyv_offset = 0;
-        buf[rkyv_offset..rkyv_offset + 4].copy_from_slice(&2u32.to_le_bytes());
-        buf[rkyv_offset + 4..rkyv_offset + 20].copy_from_slice(&hash.to_le_bytes());
+        buf.write_u32(rkyv_offset, 2)
+            .and_then(|()| buf.write_bytes(rkyv_offset + 4, &hash.to_le_bytes()))
+            .expect("the buffer was checked to hold the response");
 
-        assert!(self
-            .name_map
-            .lock()
-            .unwrap()
-            .insert(server_name, hash)
-            .is_none());
-        LendResult::MemoryReturned([rkyv_offset as u32, 0])
+        names.insert(server_name, conn_limit);
+        Reply::MemoryReturned([rkyv_offset as u32, 0])
+    }
+
+    fn list(&self, memory: &Memory, buf: &mut MessageMemory) -> Reply {
+        let listing: String = list(memory)
+            .iter()
+            .map(|info| {
+                let limit = info
+                    .connection_limit
+                    .map_or_else(|| "-".to_owned(), |limit| limit.to_string());
+                format!(
+                    "{}\t{:032x}\t{}\t{}\n",
+                    info.name, info.hash, info.connections, limit
+                )
+            })
+            .collect();
+        let length = listing.len().min(buf.as_slice().len());
+        buf.write_bytes(0, &listing.as_bytes()[..length]);
+        Reply::MemoryReturned([0, listing.len() as u32])
+    }
+
+    fn connect(&self, memory: &Memory, buf: &mut MessageMemory) -> Reply {
+        // The response is a `ConnectResult`'s tag and value
+        if buf.as_slice().len() < 8 {
+            return Reply::Refused(
+                SyscallErrorNumber::BadAddress,
+                format!(
+                    "connect buffer of {} bytes is too small",
+                    buf.as_slice().len()
+                ),
+            );
+        }
+        let name = match buf.str() {
+            Ok(name) => name.to_owned(),
+            Err(error) => {
+                log::warn!("Program tried to connect to an invalid name: {}", error);
+                Self::connect_result(buf, 1, SyscallErrorNumber::InvalidString as u32);
+                return Reply::MemoryReturned([0, 0]);
+            }
+        };
+        // println!("Connecting to {}", name);
+
+        let mut connections = memory.connections.lock().unwrap();
+        if let Some(Some(limit)) = memory.names.lock().unwrap().get(&name) {
+            if connections.name_references(&name) >= *limit {
+                Self::connect_result(buf, 1, SyscallErrorNumber::AccessDenied as u32);
+                return Reply::MemoryReturned([0, 0]);
+            }
+        }
+
+        let connection_id = connections.connect_name(&name, || {
+            let bridge = memory
+                .bridge
+                .as_ref()
+                .filter(|bridge| bridge.bridges(&name));
+            let service: Arc<dyn Service + Send + Sync> = if let Some(bridge) = bridge {
+                Arc::new(super::bridged::Bridged::new(name.clone(), bridge.clone()))
+            } else if name == "panic-to-screen!" {
+                Arc::new(super::panic_to_screen::PanicToScreen::new())
+            } else if name == "_DNS Resolver Middleware_" {
+                Arc::new(super::dns::DnsResolver::new())
+            } else if name == "_Suspend/resume manager_" {
+                Arc::new(super::susres::Susres::new())
+            } else if name == "_COM manager_" {
+                Arc::new(super::com::Com::new(memory.ec.clone()))
+            } else if name == super::keyboard::NAME {
+                Arc::new(super::keyboard::KeyboardService::new(
+                    memory.keyboard.clone(),
+                ))
+            } else if name == "_Xous USB device driver_" {
+                Arc::new(super::usb::Usb::new(memory.usb.clone()))
+            } else if name == "_Audio Codec_" {
+                Arc::new(super::codec::Codec::new(memory.audio.clone()))
+            } else if name == super::sha512::NAME {
+                Arc::new(super::sha512::Sha512::new())
+            } else if name == super::aes::NAME {
+                Arc::new(super::aes::Aes::new())
+            } else if name == "_TRNG manager_" {
+                Arc::new(super::trng::Trng::new(memory.trng.clone()))
+            } else if name == super::engine25519::NAME {
+                Arc::new(super::engine25519::Engine25519::new())
+            } else if name == super::spinor::NAME {
+                Arc::new(super::spinor::Spinor::new(memory.flash.clone()))
+            } else if name == super::host_exec::NAME {
+                Arc::new(super::host_exec::HostExecService::new(
+                    memory.host_exec.clone()?,
+                ))
+            } else {
+                return None;
+            };
+            Some(service)
+        });
+        match connection_id {
+            Some(connection_id) => Self::connect_result(buf, 0, connection_id),
+            None => {
+                log::warn!("Unrecognized service name {}", name);
+                // ConnectResult::Error(ServerNotFound)
+                Self::connect_result(buf, 1, SyscallErrorNumber::ServerNotFound as u32);
+            }
+        }
+        Reply::MemoryReturned([0, 0])
+    }
+
+    /// Write a `ConnectResult`, which is a tag followed by the connection ID
+    /// or error code.
+    fn connect_result(buf: &mut MessageMemory, tag: u32, value: u32) {
+        buf.write_u32(0, tag)
+            .and_then(|()| buf.write_u32(4, value))
+            .expect("the buffer was checked to hold the response");
     }
 }
 
@@ -172,87 +342,21 @@ impl Default for Name {
 }
 
 impl Service for Name {
-    fn lend_mut(
-        &self,
-        memory: &Memory,
-        sender: u32,
-        opcode: u32,
-        buf: &mut [u8],
-        extra: [u32; 2],
-    ) -> LendResult {
+    fn message(&self, memory: &Memory, mut message: Message) -> Reply {
+        let opcode = message.opcode;
+        let Some(buf) = message.memory_mut() else {
+            return Reply::UnknownOpcode;
+        };
         if opcode == NameLendOpcode::Register as u32 {
-            self.register_name(buf, extra[0])
+            self.register_name(memory, buf)
+        } else if opcode == NameLendOpcode::List as u32 {
+            self.list(memory, buf)
         } else if opcode == NameLendOpcode::TryConnect as u32
             || opcode == NameLendOpcode::BlockingConnect as u32
         {
-            let buf_len = buf.len().min(extra[1] as usize);
-            let name = std::str::from_utf8(&buf[0..buf_len]).unwrap_or("<invalid>");
-            // println!("Connecting to {}", name);
-
-            if let Some(connection_id) = self.connection_index.lock().unwrap().get(name) {
-                println!(
-                    "Existing server found at connection index {}",
-                    connection_id
-                );
-                buf[0..4].copy_from_slice(&0u32.to_le_bytes());
-                buf[4..8].copy_from_slice(&connection_id.to_le_bytes());
-                return LendResult::MemoryReturned([0, 0]);
-            }
-
-            let service: Box<dyn Service + Send + Sync> = if name == "panic-to-screen!" {
-                Box::new(super::panic_to_screen::PanicToScreen::new())
-            } else if name == "_DNS Resolver Middleware_" {
-                Box::new(super::dns::DnsResolver::new())
-            } else {
-                eprintln!("Unrecognized service name {}", name);
-                std::process::exit(1);
-            };
-
-            // Insert the connection into the system bus' connection table
-            let (tx, rx) = channel();
-            let connection_id = memory.connection_index.fetch_add(1, Ordering::Relaxed);
-            let connections: Arc<Mutex<HashMap<u32, Box<dyn Service + Send + Sync>>>> =
-                memory.connections.clone();
-            let name_connection_mapping = self.connection_index.clone();
-            let buffer_length = buf.len();
-            let name = name.to_owned();
-            thread::spawn(move || {
-                let mut connections = connections.lock().unwrap();
-                connections.insert(connection_id, service);
-
-                // Insert it into the connection map so subsequent lookups get the same service
-                name_connection_mapping
-                    .lock()
-                    .unwrap()
-                    .insert(name, connection_id);
-
-                // println!("Inserted new connection {}", connection_id);
-
-                let mut buf = vec![0u8; buffer_length];
-                buf[0..4].copy_from_slice(&0u32.to_le_bytes());
-                buf[4..8].copy_from_slice(&connection_id.to_le_bytes());
-                tx.send((
-                    [
-                        SyscallResultNumber::MemoryReturned as i32,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                    ],
-                    Some(buf),
-                ))
-                .unwrap();
-            });
-            LendResult::WaitForResponse(rx)
+            self.connect(memory, buf)
         } else {
-            panic!(
-                "Unhandled name lend_mut {}: {} {:x?}",
-                sender, opcode, extra
-            );
+            Reply::UnknownOpcode
         }
-        //
     }
 }
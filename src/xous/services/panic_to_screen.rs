@@ -1,4 +1,4 @@
-use super::{LendResult, Service};
+use super::{LendBuffer, LendResult, Service};
 use crate::xous::Memory;
 
 enum PanicToScreenLendMutOpcode {
@@ -12,9 +12,12 @@ impl PanicToScreen {
         PanicToScreen {}
     }
 
-    fn append_panic_text(&self, buf: &[u8], valid: u32) -> LendResult {
-        let _panic_str: &str = std::str::from_utf8(&buf[0..valid as usize]).unwrap_or("<invalid>");
-        // println!("Panic to screen: {}", _panic_str);
+    fn append_panic_text(&self, memory: &Memory, buf: &[u8], valid: u32) -> LendResult {
+        let panic_str: &str = std::str::from_utf8(&buf[0..valid as usize]).unwrap_or("<invalid>");
+        // println!("Panic to screen: {}", panic_str);
+        if let Some(catcher) = &memory.guest_panics {
+            catcher.append_text(panic_str);
+        }
         LendResult::MemoryReturned([0, 0])
     }
 }
@@ -28,39 +31,29 @@ impl Default for PanicToScreen {
 impl Service for PanicToScreen {
     fn lend(
         &self,
-        _memory: &Memory,
+        memory: &Memory,
         _sender: u32,
         opcode: u32,
         buf: &[u8],
         extra: [u32; 2],
     ) -> LendResult {
-        if opcode == PanicToScreenLendMutOpcode::AppendPanicText as _ {
-            return self.append_panic_text(buf, extra[1]);
+        if opcode == PanicToScreenLendMutOpcode::AppendPanicText as u32 {
+            return self.append_panic_text(memory, buf, extra[1]);
         }
-        panic!(
-            "panic-to-screen lent {} bytes to service for opcode {} ({:?})",
-            buf.len(),
-            opcode,
-            extra
-        );
+        LendResult::UnknownOpcode
     }
 
     fn lend_mut(
         &self,
-        _memory: &Memory,
+        memory: &Memory,
         _sender: u32,
         opcode: u32,
-        buf: &mut [u8],
+        buf: &mut LendBuffer,
         extra: [u32; 2],
     ) -> LendResult {
-        if opcode == PanicToScreenLendMutOpcode::AppendPanicText as _ {
-            return self.append_panic_text(buf, extra[1]);
+        if opcode == PanicToScreenLendMutOpcode::AppendPanicText as u32 {
+            return self.append_panic_text(memory, buf, extra[1]);
         }
-        panic!(
-            "panic-to-screen mutably lent {} bytes to service for opcode {} ({:?})",
-            buf.len(),
-            opcode,
-            extra
-        );
+        LendResult::UnknownOpcode
     }
 }
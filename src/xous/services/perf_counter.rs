@@ -0,0 +1,152 @@
+//! Performance counters for instrumented guest code. On hardware these come
+//! from the perfcounter block, but under emulation the guest asks this
+//! service instead, which counts the calling thread's retired instructions
+//! and reads the host's clock. It can also subscribe to perf events and
+//! count them, as a sampling profiler in the guest would.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{Message, MessageKind, Reply, Service};
+use crate::xous::perf_events::{PerfEvent, PerfListener, PerfSubscription};
+use crate::xous::Memory;
+
+enum ScalarOpcode {
+    /// Returns a Scalar2 with the number of instructions the calling thread
+    /// has retired.
+    InstructionsRetired = 0,
+
+    /// Returns a Scalar2 with the number of microseconds since the machine
+    /// started.
+    ElapsedUs = 1,
+
+    /// Returns a Scalar5 with both of the above, read at the same time, and the
+    /// calling thread's ID: instructions (low, high), microseconds (low, high), tid.
+    Snapshot = 2,
+
+    /// Returns a Scalar5 with the number of instructions the thread whose ID
+    /// is the first argument has retired: instructions (low, high), whether
+    /// the thread exists, whether it has exited, and its tid. Threads that
+    /// were never started read as zero.
+    ThreadInstructionsRetired = 3,
+
+    /// Start counting perf events in place of whatever was counted before:
+    /// a sample every time a thread retires as many instructions as the
+    /// first argument, unless it's zero, traps if bit 0 of the second
+    /// argument is set, and context switches if bit 1 is. Counts start
+    /// again from zero. Returns a Scalar1 with the first argument.
+    Subscribe = 4,
+
+    /// Returns a Scalar5 with what has been counted since `Subscribe`:
+    /// samples, traps, context switches, and the PC and tid of the latest
+    /// sample.
+    Events = 5,
+}
+
+/// The perf events counted since the guest last subscribed.
+#[derive(Default)]
+struct EventCounts {
+    samples: AtomicU64,
+    traps: AtomicU64,
+    context_switches: AtomicU64,
+
+    /// Where the latest sample was taken, and in which thread.
+    latest: Mutex<(u32, i32)>,
+}
+
+impl PerfListener for EventCounts {
+    fn event(&self, event: &PerfEvent) {
+        match event {
+            PerfEvent::Sample { tid, pc, .. } => {
+                self.samples.fetch_add(1, Ordering::Relaxed);
+                *self.latest.lock().unwrap() = (*pc, *tid);
+            }
+            PerfEvent::Trap { .. } => {
+                self.traps.fetch_add(1, Ordering::Relaxed);
+            }
+            PerfEvent::ContextSwitch { .. } => {
+                self.context_switches.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+pub struct PerfCounter {
+    events: Mutex<Arc<EventCounts>>,
+}
+
+impl PerfCounter {
+    pub fn new() -> Self {
+        PerfCounter {
+            events: Mutex::new(Arc::new(EventCounts::default())),
+        }
+    }
+}
+
+impl Default for PerfCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service for PerfCounter {
+    fn message(&self, memory: &Memory, message: Message) -> Reply {
+        if message.kind != MessageKind::BlockingScalar {
+            return Reply::UnknownOpcode;
+        }
+        let instructions = memory.thread_instructions.load(Ordering::Relaxed);
+        let elapsed_us = memory.platform.elapsed_us();
+        if message.opcode == ScalarOpcode::InstructionsRetired as u32 {
+            Reply::Scalar2([instructions as u32, (instructions >> 32) as u32])
+        } else if message.opcode == ScalarOpcode::ElapsedUs as u32 {
+            Reply::Scalar2([elapsed_us as u32, (elapsed_us >> 32) as u32])
+        } else if message.opcode == ScalarOpcode::Snapshot as u32 {
+            Reply::Scalar5([
+                instructions as u32,
+                (instructions >> 32) as u32,
+                elapsed_us as u32,
+                (elapsed_us >> 32) as u32,
+                message.sender,
+            ])
+        } else if message.opcode == ScalarOpcode::ThreadInstructionsRetired as u32 {
+            let tid = message.args[0] as i32;
+            let stats = memory
+                .thread_stats()
+                .into_iter()
+                .find(|stats| stats.tid == tid);
+            let instructions = stats.as_ref().map_or(0, |stats| stats.instructions_retired);
+            Reply::Scalar5([
+                instructions as u32,
+                (instructions >> 32) as u32,
+                stats.is_some() as u32,
+                stats.is_some_and(|stats| stats.exited) as u32,
+                tid as u32,
+            ])
+        } else if message.opcode == ScalarOpcode::Subscribe as u32 {
+            let subscription = PerfSubscription {
+                every_instructions: (message.args[0] != 0).then_some(message.args[0] as u64),
+                traps: message.args[1] & 1 != 0,
+                context_switches: message.args[1] & 2 != 0,
+            };
+            let mut events = self.events.lock().unwrap();
+            memory
+                .perf_events
+                .subscribe(PerfSubscription::default(), events.clone());
+            *events = Arc::new(EventCounts::default());
+            memory.perf_events.subscribe(subscription, events.clone());
+            Reply::Scalar1(message.args[0])
+        } else if message.opcode == ScalarOpcode::Events as u32 {
+            let events = self.events.lock().unwrap().clone();
+            let (pc, tid) = *events.latest.lock().unwrap();
+            Reply::Scalar5([
+                events.samples.load(Ordering::Relaxed) as u32,
+                events.traps.load(Ordering::Relaxed) as u32,
+                events.context_switches.load(Ordering::Relaxed) as u32,
+                pc,
+                tid as u32,
+            ])
+        } else {
+            Reply::UnknownOpcode
+        }
+    }
+}
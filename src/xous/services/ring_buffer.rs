@@ -0,0 +1,504 @@
+//! A ring buffer in guest memory for streaming data between the guest and the
+//! host without copying every chunk through `SendMessage`.
+//!
+//! The guest maps a page-aligned region and hands it to the service with
+//! `Attach`. The region begins with a 16-byte header, and the rest holds data:
+//!
+//! | Offset | Field    | Written by                               |
+//! |--------|----------|------------------------------------------|
+//! | 0      | head     | the producer, after writing data         |
+//! | 4      | tail     | the consumer, after reading data         |
+//! | 8      | capacity | the service, when the region is attached |
+//! | 12     | flags    | whichever side closes the stream         |
+//!
+//! `head` and `tail` count the total number of bytes written and read, and
+//! wrap at 2^32. Byte `n` of the stream lives at `16 + n % capacity`. Each
+//! side only ever writes its own counter, so neither needs a lock.
+
+use std::{
+    ops::Range,
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use super::{ResponseData, ScalarResult, Service, UnknownOpcode};
+use crate::xous::{backing::Backing, definitions::SyscallResultNumber, Memory};
+
+const HEADER_SIZE: u32 = 16;
+const HEAD_OFFSET: u32 = 0;
+const TAIL_OFFSET: u32 = 4;
+const CAPACITY_OFFSET: u32 = 8;
+const FLAGS_OFFSET: u32 = 12;
+
+/// Set in the flags word once no more data will be produced.
+const FLAG_CLOSED: u32 = 1;
+
+enum ScalarOpcode {
+    /// Attach a region of memory to a ring.
+    ///
+    /// # Arguments
+    ///
+    /// * ring ID
+    /// * page-aligned address of the region
+    /// * length of the region in bytes
+    /// * `RingDirection`
+    ///
+    /// # Return Values
+    ///
+    /// Scalar1 containing the data capacity of the ring, or 0 if the region is
+    /// invalid or the ring is already attached.
+    Attach = 0,
+
+    /// Block until at least `args[1]` bytes can be read from ring `args[0]`,
+    /// or the ring is closed. Returns the number of bytes available, which is
+    /// only zero once the ring is closed and drained.
+    WaitReadable = 1,
+
+    /// Block until at least `args[1]` bytes can be written to ring `args[0]`,
+    /// or the ring is closed. Returns the number of bytes free.
+    WaitWritable = 2,
+
+    /// Tell the host that the guest has moved `head` or `tail` of ring `args[0]`.
+    /// This is a non-blocking scalar.
+    Notify = 3,
+
+    /// Close ring `args[0]`. This is a non-blocking scalar.
+    Close = 4,
+}
+
+/// Which side of a ring produces data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RingDirection {
+    GuestToHost = 0,
+    HostToGuest = 1,
+}
+
+/// The guest memory backing an attached ring.
+struct Region {
+    backing: Arc<Backing>,
+    memory_base: u32,
+
+    /// Where the region is mapped, so that unmapping it detaches the ring.
+    asid: u32,
+    addresses: Range<u32>,
+
+    /// The physical address of each page of the region, in order.
+    pages: Vec<u32>,
+    capacity: u32,
+    direction: RingDirection,
+}
+
+impl Region {
+    /// Call `f` with each page-sized span of `length` bytes starting at
+    /// `offset`, giving the words of the page, the offset within the page,
+    /// and the offset within the span.
+    fn for_each_span(
+        &self,
+        offset: u32,
+        length: usize,
+        mut f: impl FnMut(&RwLock<Vec<u32>>, usize, usize, usize),
+    ) {
+        let mut done = 0;
+        while done < length {
+            let address = offset as usize + done;
+            let page = self.pages[address / 4096];
+            let page_offset = address % 4096;
+            let count = (4096 - page_offset).min(length - done);
+            let words = self
+                .backing
+                .get_or_create(((page - self.memory_base) >> 12) as usize)
+                .unwrap();
+            f(words, page_offset, done, count);
+            done += count;
+        }
+    }
+
+    fn read_word(&self, offset: u32) -> u32 {
+        let mut value = 0;
+        self.for_each_span(offset, 4, |words, page_offset, _, _| {
+            value = words.read().unwrap()[page_offset / 4];
+        });
+        value
+    }
+
+    fn write_word(&self, offset: u32, value: u32) {
+        self.for_each_span(offset, 4, |words, page_offset, _, _| {
+            words.write().unwrap()[page_offset / 4] = value;
+        });
+    }
+
+    fn read_bytes(&self, offset: u32, buf: &mut [u8]) {
+        self.for_each_span(offset, buf.len(), |words, page_offset, done, count| {
+            let words = words.read().unwrap();
+            for (index, byte) in buf[done..done + count].iter_mut().enumerate() {
+                let position = page_offset + index;
+                *byte = (words[position / 4] >> ((position % 4) * 8)) as u8;
+            }
+        });
+    }
+
+    fn write_bytes(&self, offset: u32, data: &[u8]) {
+        self.for_each_span(offset, data.len(), |words, page_offset, done, count| {
+            let mut words = words.write().unwrap();
+            for (index, byte) in data[done..done + count].iter().enumerate() {
+                let position = page_offset + index;
+                let shift = (position % 4) * 8;
+                words[position / 4] =
+                    (words[position / 4] & !(0xff << shift)) | (*byte as u32) << shift;
+            }
+        });
+    }
+
+    fn used(&self) -> u32 {
+        self.read_word(HEAD_OFFSET)
+            .wrapping_sub(self.read_word(TAIL_OFFSET))
+            .min(self.capacity)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.read_word(FLAGS_OFFSET) & FLAG_CLOSED != 0
+    }
+
+    /// Copy `data` in at stream position `position`, wrapping around the end.
+    fn write_stream(&self, position: u32, data: &[u8]) {
+        let start = position % self.capacity;
+        let first = data.len().min((self.capacity - start) as usize);
+        self.write_bytes(HEADER_SIZE + start, &data[..first]);
+        self.write_bytes(HEADER_SIZE, &data[first..]);
+    }
+
+    /// Copy data out from stream position `position`, wrapping around the end.
+    fn read_stream(&self, position: u32, buf: &mut [u8]) {
+        let start = position % self.capacity;
+        let first = buf.len().min((self.capacity - start) as usize);
+        self.read_bytes(HEADER_SIZE + start, &mut buf[..first]);
+        self.read_bytes(HEADER_SIZE, &mut buf[first..]);
+    }
+}
+
+/// A guest thread blocked in `WaitReadable` or `WaitWritable`.
+struct Waiter {
+    response: Sender<ResponseData>,
+    readable: bool,
+    minimum: u32,
+}
+
+/// The host's end of a ring. Obtain one with `Machine::ring_buffer()`, which
+/// may be called before the guest attaches its memory.
+pub struct RingBuffer {
+    region: Mutex<Option<Region>>,
+    waiters: Mutex<Vec<Waiter>>,
+
+    /// Bumped whenever the guest attaches, notifies, or closes the ring.
+    guest_events: (Mutex<u64>, Condvar),
+}
+
+fn scalar1_response(value: u32) -> ResponseData {
+    (
+        [
+            SyscallResultNumber::Scalar1 as i32,
+            value as i32,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ],
+        None,
+    )
+}
+
+impl RingBuffer {
+    pub(crate) fn new() -> Self {
+        RingBuffer {
+            region: Mutex::new(None),
+            waiters: Mutex::new(vec![]),
+            guest_events: (Mutex::new(0), Condvar::new()),
+        }
+    }
+
+    /// Whether the guest has attached memory to this ring.
+    pub fn is_attached(&self) -> bool {
+        self.region.lock().unwrap().is_some()
+    }
+
+    /// The number of bytes the ring can hold, or 0 if it isn't attached yet.
+    pub fn capacity(&self) -> usize {
+        self.region
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |region| region.capacity as usize)
+    }
+
+    /// The number of bytes waiting to be read.
+    pub fn len(&self) -> usize {
+        self.region
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |region| region.used() as usize)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether either side has closed the ring.
+    pub fn is_closed(&self) -> bool {
+        self.region
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|region| region.is_closed())
+    }
+
+    /// Write as much of `data` as fits into a `HostToGuest` ring, returning the
+    /// number of bytes written. Returns 0 if the ring is full, closed, not yet
+    /// attached, or flows the other way.
+    pub fn write(&self, data: &[u8]) -> usize {
+        let region = self.region.lock().unwrap();
+        let Some(region) = region.as_ref() else {
+            return 0;
+        };
+        if region.direction != RingDirection::HostToGuest || region.is_closed() {
+            return 0;
+        }
+        let head = region.read_word(HEAD_OFFSET);
+        let count = data.len().min((region.capacity - region.used()) as usize);
+        region.write_stream(head, &data[..count]);
+        // Publish the data only once it has been written
+        region.write_word(HEAD_OFFSET, head.wrapping_add(count as u32));
+        self.wake_waiters(region);
+        count
+    }
+
+    /// Read from a `GuestToHost` ring into `buf`, returning the number of bytes
+    /// read. Returns 0 if the ring is empty, not yet attached, or flows the other way.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let region = self.region.lock().unwrap();
+        let Some(region) = region.as_ref() else {
+            return 0;
+        };
+        if region.direction != RingDirection::GuestToHost {
+            return 0;
+        }
+        let tail = region.read_word(TAIL_OFFSET);
+        let count = buf.len().min(region.used() as usize);
+        region.read_stream(tail, &mut buf[..count]);
+        region.write_word(TAIL_OFFSET, tail.wrapping_add(count as u32));
+        self.wake_waiters(region);
+        count
+    }
+
+    /// Mark the ring closed. A guest reading from it sees the end of the
+    /// stream once it has drained the remaining data.
+    pub fn close(&self) {
+        let region = self.region.lock().unwrap();
+        if let Some(region) = region.as_ref() {
+            let flags = region.read_word(FLAGS_OFFSET);
+            region.write_word(FLAGS_OFFSET, flags | FLAG_CLOSED);
+            self.wake_waiters(region);
+        }
+    }
+
+    /// Block until the guest attaches, notifies, or closes the ring, or until
+    /// `timeout` passes. Returns `false` on timeout.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (events, condvar) = &self.guest_events;
+        let events = events.lock().unwrap();
+        let start = *events;
+        let (_events, result) = condvar
+            .wait_timeout_while(events, timeout, |events| *events == start)
+            .unwrap();
+        !result.timed_out()
+    }
+
+    fn guest_event(&self) {
+        let (events, condvar) = &self.guest_events;
+        *events.lock().unwrap() += 1;
+        condvar.notify_all();
+    }
+
+    fn wake_waiters(&self, region: &Region) {
+        let used = region.used();
+        let closed = region.is_closed();
+        self.waiters.lock().unwrap().retain(|waiter| {
+            let available = if waiter.readable {
+                used
+            } else {
+                region.capacity - used
+            };
+            if available < waiter.minimum && !closed {
+                return true;
+            }
+            waiter.response.send(scalar1_response(available)).ok();
+            false
+        });
+    }
+
+    fn attach(&self, memory: &Memory, address: u32, length: u32, direction: u32) -> u32 {
+        let direction = match direction {
+            0 => RingDirection::GuestToHost,
+            1 => RingDirection::HostToGuest,
+            _ => return 0,
+        };
+        if address & 0xfff != 0 || length <= HEADER_SIZE {
+            return 0;
+        }
+        let mut region = self.region.lock().unwrap();
+        if region.is_some() {
+            return 0;
+        }
+        let Some(pages) = (address..address.saturating_add(length))
+            .step_by(4096)
+            .map(|page| memory.touch_virt_to_phys(page))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return 0;
+        };
+        let new_region = Region {
+            backing: memory.data.clone(),
+            memory_base: memory.base,
+            asid: memory.space.asid,
+            addresses: address..address.saturating_add(length),
+            pages,
+            capacity: length - HEADER_SIZE,
+            direction,
+        };
+        new_region.write_word(HEAD_OFFSET, 0);
+        new_region.write_word(TAIL_OFFSET, 0);
+        new_region.write_word(CAPACITY_OFFSET, new_region.capacity);
+        new_region.write_word(FLAGS_OFFSET, 0);
+        memory.mark_initialized(address, length);
+        let capacity = new_region.capacity;
+        *region = Some(new_region);
+        drop(region);
+        self.guest_event();
+        capacity
+    }
+
+    /// Let go of the region if any of it lies in `addresses` of address
+    /// space `asid`, which the guest is unmapping, so that the host never
+    /// touches its pages once they've been handed out again. Waiting threads
+    /// are told nothing is available, and the ring can be attached again.
+    pub(crate) fn detach_unmapped(&self, asid: u32, addresses: &Range<u32>) {
+        let mut region = self.region.lock().unwrap();
+        let unmapped = region.as_ref().is_some_and(|region| {
+            region.asid == asid
+                && region.addresses.start < addresses.end
+                && addresses.start < region.addresses.end
+        });
+        if !unmapped {
+            return;
+        }
+        *region = None;
+        drop(region);
+        for waiter in self.waiters.lock().unwrap().drain(..) {
+            waiter.response.send(scalar1_response(0)).ok();
+        }
+        self.guest_event();
+    }
+
+    fn wait_guest(&self, readable: bool, minimum: u32) -> ScalarResult {
+        let region = self.region.lock().unwrap();
+        let Some(region) = region.as_ref() else {
+            return ScalarResult::Scalar1(0);
+        };
+        let used = region.used();
+        let available = if readable {
+            used
+        } else {
+            region.capacity - used
+        };
+        if available >= minimum.min(region.capacity) || region.is_closed() {
+            return ScalarResult::Scalar1(available);
+        }
+        let (tx, rx) = channel();
+        self.waiters.lock().unwrap().push(Waiter {
+            response: tx,
+            readable,
+            minimum: minimum.min(region.capacity),
+        });
+        ScalarResult::WaitForResponse(rx)
+    }
+}
+
+/// The guest's interface to the rings. The rings themselves live in `Memory`
+/// so that the host can find them.
+pub struct RingBufferService {}
+
+impl RingBufferService {
+    pub fn new() -> Self {
+        RingBufferService {}
+    }
+}
+
+impl Default for RingBufferService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service for RingBufferService {
+    fn scalar(
+        &self,
+        memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        let ring = memory.ring_buffer(args[0]);
+        if opcode == ScalarOpcode::Notify as u32 {
+            if let Some(region) = ring.region.lock().unwrap().as_ref() {
+                ring.wake_waiters(region);
+            }
+            ring.guest_event();
+        } else if opcode == ScalarOpcode::Close as u32 {
+            ring.close();
+            ring.guest_event();
+        } else {
+            return Err(UnknownOpcode);
+        }
+        Ok(())
+    }
+
+    fn blocking_scalar(
+        &self,
+        memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> ScalarResult {
+        let ring = memory.ring_buffer(args[0]);
+        if opcode == ScalarOpcode::Attach as u32 {
+            ScalarResult::Scalar1(ring.attach(memory, args[1], args[2], args[3]))
+        } else if opcode == ScalarOpcode::WaitReadable as u32 {
+            ring.wait_guest(true, args[1])
+        } else if opcode == ScalarOpcode::WaitWritable as u32 {
+            ring.wait_guest(false, args[1])
+        } else {
+            ScalarResult::UnknownOpcode
+        }
+    }
+
+    fn tick(&self, memory: &Memory) {
+        // Another guest thread may have moved a ring without notifying
+        let rings: Vec<_> = memory
+            .ring_buffers
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for ring in rings {
+            if let Some(region) = ring.region.lock().unwrap().as_ref() {
+                ring.wake_waiters(region);
+            }
+        }
+    }
+}
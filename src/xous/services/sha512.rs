@@ -0,0 +1,163 @@
+//! The sha512 server, which drives the SHA-512 hash engine. There's one
+//! engine, so a program acquires it, feeds it data, and releases it by
+//! finalizing or resetting. The hashing itself is done in software.
+//!
+//! Its protocol is yove's own, as the `services` module explains.
+
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha512 as Sha512Hasher, Sha512_256};
+
+use super::{LendBuffer, LendResult, ScalarResult, Service, UnknownOpcode};
+use crate::xous::Memory;
+
+/// The name programs connect to the sha512 server by.
+pub const NAME: &str = "yove-sha512";
+
+#[allow(dead_code)]
+enum Sha512Opcode {
+    /// Take the engine in the mode given by the first argument, 0 for
+    /// SHA-512 and 1 for SHA-512/256. Returns 1 if the engine was free, or
+    /// 0 if another thread has it.
+    AcquireExclusive = 0,
+
+    /// Hash the valid bytes of the lent buffer, of which the second
+    /// argument says how many there are.
+    Update = 1,
+
+    /// Write the digest to the start of the lent buffer and release the
+    /// engine. Returns the length of the digest, or 0 if the caller doesn't
+    /// hold the engine.
+    Finalize = 2,
+
+    /// Throw away the hash in progress and release the engine.
+    Reset = 3,
+
+    /// Returns 1 if no one holds the engine.
+    IsIdle = 4,
+}
+
+enum Hasher {
+    Sha512(Sha512Hasher),
+    Sha512_256(Sha512_256),
+}
+
+pub struct Sha512 {
+    /// Whoever holds the engine, and the hash they're computing.
+    engine: Mutex<Option<(u32, Hasher)>>,
+}
+
+impl Sha512 {
+    pub fn new() -> Self {
+        Sha512 {
+            engine: Mutex::new(None),
+        }
+    }
+
+    fn release(&self, sender: u32) {
+        let mut engine = self.engine.lock().unwrap();
+        if engine.as_ref().is_some_and(|(owner, _)| *owner == sender) {
+            *engine = None;
+        }
+    }
+}
+
+impl Default for Sha512 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service for Sha512 {
+    fn scalar(
+        &self,
+        _memory: &Memory,
+        sender: u32,
+        opcode: u32,
+        _args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        if opcode == Sha512Opcode::Reset as u32 {
+            self.release(sender);
+            Ok(())
+        } else {
+            Err(UnknownOpcode)
+        }
+    }
+
+    fn blocking_scalar(
+        &self,
+        _memory: &Memory,
+        sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> ScalarResult {
+        if opcode == Sha512Opcode::AcquireExclusive as u32 {
+            let mut engine = self.engine.lock().unwrap();
+            if engine.is_some() {
+                return ScalarResult::Scalar1(0);
+            }
+            let hasher = match args[0] {
+                0 => Hasher::Sha512(Sha512Hasher::new()),
+                _ => Hasher::Sha512_256(Sha512_256::new()),
+            };
+            *engine = Some((sender, hasher));
+            ScalarResult::Scalar1(1)
+        } else if opcode == Sha512Opcode::Reset as u32 {
+            self.release(sender);
+            ScalarResult::Scalar1(0)
+        } else if opcode == Sha512Opcode::IsIdle as u32 {
+            ScalarResult::Scalar1(self.engine.lock().unwrap().is_none() as u32)
+        } else {
+            ScalarResult::UnknownOpcode
+        }
+    }
+
+    fn lend(
+        &self,
+        _memory: &Memory,
+        sender: u32,
+        opcode: u32,
+        buf: &[u8],
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == Sha512Opcode::Update as u32 {
+            let length = (extra[1] as usize).min(buf.len());
+            let mut engine = self.engine.lock().unwrap();
+            match engine.as_mut() {
+                Some((owner, Hasher::Sha512(hasher))) if *owner == sender => {
+                    hasher.update(&buf[..length])
+                }
+                Some((owner, Hasher::Sha512_256(hasher))) if *owner == sender => {
+                    hasher.update(&buf[..length])
+                }
+                _ => return LendResult::MemoryReturned([0, 0]),
+            }
+            return LendResult::MemoryReturned([0, length as u32]);
+        }
+        LendResult::UnknownOpcode
+    }
+
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        _extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == Sha512Opcode::Finalize as u32 {
+            let mut engine = self.engine.lock().unwrap();
+            if engine.as_ref().is_none_or(|(owner, _)| *owner != sender) {
+                return LendResult::MemoryReturned([0, 0]);
+            }
+            let digest = match engine.take().unwrap().1 {
+                Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+                Hasher::Sha512_256(hasher) => hasher.finalize().to_vec(),
+            };
+            let length = digest.len().min(buf.len());
+            buf.write(0, &digest[..length]);
+            return LendResult::MemoryReturned([0, length as u32]);
+        }
+        LendResult::UnknownOpcode
+    }
+}
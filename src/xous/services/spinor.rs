@@ -0,0 +1,108 @@
+//! The flash server, which reads, programs, and erases the machine's
+//! `Flash`. Any faults injected into the flash happen here, and the program
+//! is told its writes succeeded whether they did or not, as it would be by
+//! real hardware that lost power or glitched.
+//!
+//! Xous's own spinor server takes exclusive locks and stages writes through
+//! memory messages, none of which is emulated. This server speaks a simpler
+//! protocol of its own, under a name of its own, so that a program built for
+//! the real one finds no server rather than one that misreads its messages.
+
+use std::sync::Arc;
+
+use super::{LendBuffer, LendResult, ScalarResult, Service};
+use crate::xous::flash::Flash;
+use crate::xous::Memory;
+
+/// The name programs connect to the flash server by.
+pub const NAME: &str = "yove-flash";
+
+/// The status a request that's out of range or misaligned returns.
+const INVALID: u32 = 1;
+
+#[allow(dead_code)]
+enum SpinorOpcode {
+    /// Fill the lent buffer from the flash, starting at the message's
+    /// offset argument, for as many bytes as its valid argument. Returns a
+    /// status and the number of bytes read.
+    Read = 0,
+
+    /// Program the valid bytes of the lent buffer into the flash at the
+    /// message's offset argument, clearing bits only. Returns a status and
+    /// the number of bytes programmed.
+    Program = 1,
+
+    /// Erase the sectors from the offset in the first argument for the
+    /// number of bytes in the second. Returns a status.
+    Erase = 2,
+
+    /// Returns the size of the flash in bytes.
+    Size = 3,
+}
+
+pub struct Spinor {
+    flash: Arc<Flash>,
+}
+
+impl Spinor {
+    pub fn new(flash: Arc<Flash>) -> Self {
+        Spinor { flash }
+    }
+}
+
+impl Service for Spinor {
+    fn blocking_scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> ScalarResult {
+        if opcode == SpinorOpcode::Erase as u32 {
+            match self.flash.erase(args[0], args[1]) {
+                true => ScalarResult::Scalar1(0),
+                false => ScalarResult::Scalar1(INVALID),
+            }
+        } else if opcode == SpinorOpcode::Size as u32 {
+            ScalarResult::Scalar1(self.flash.size())
+        } else {
+            ScalarResult::UnknownOpcode
+        }
+    }
+
+    fn lend(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &[u8],
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == SpinorOpcode::Program as u32 {
+            let length = (extra[1] as usize).min(buf.len());
+            return match self.flash.program(extra[0], &buf[..length]) {
+                true => LendResult::MemoryReturned([0, length as u32]),
+                false => LendResult::MemoryReturned([INVALID, 0]),
+            };
+        }
+        LendResult::UnknownOpcode
+    }
+
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == SpinorOpcode::Read as u32 {
+            let length = (extra[1] as usize).min(buf.len());
+            return match self.flash.read(extra[0], buf.get_mut(0..length).unwrap()) {
+                true => LendResult::MemoryReturned([0, length as u32]),
+                false => LendResult::MemoryReturned([INVALID, 0]),
+            };
+        }
+        LendResult::UnknownOpcode
+    }
+}
@@ -0,0 +1,169 @@
+//! The suspend/resume manager. Programs register callbacks so they can save
+//! state before the system suspends. The emulator can't power down, so a
+//! suspend request completes immediately: it is refused while any thread
+//! holds a `SuspendDeny`, and otherwise counts as a clean suspend and resume.
+//! Callbacks are accepted but never called, since the emulator has no way of
+//! sending messages to servers in the guest. As on the hardware, where the
+//! watchdog is stopped while suspended, a clean suspend restarts the
+//! watchdog's countdown. Every suspend is counted, for `Machine::suspends()`,
+//! so that the host can snapshot the machine when the guest suspends.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::{LendBuffer, LendResult, ScalarResult, Service, UnknownOpcode};
+use crate::xous::Memory;
+
+#[allow(dead_code)]
+enum SusresOpcode {
+    /// Ask the system to suspend. Returns 1 if the suspend happened and 0 if
+    /// it was refused.
+    SuspendRequest = 0,
+
+    /// Register a callback to be called before suspending. The argument is
+    /// lent as a buffer.
+    SuspendEventSubscribe = 1,
+
+    /// A subscriber has finished preparing for suspend. The argument is the
+    /// token it was given.
+    SuspendReady = 2,
+
+    /// Returns 1 while a suspend is in progress.
+    SuspendingNow = 3,
+
+    /// Refuse to suspend until a matching `SuspendAllow`.
+    SuspendDeny = 4,
+
+    /// Undo an earlier `SuspendDeny`.
+    SuspendAllow = 5,
+
+    /// Returns 1 if the system has suspended, since every suspend is clean.
+    WasSuspendClean = 10,
+}
+
+pub struct Susres {
+    /// How many `SuspendDeny`s are outstanding.
+    denials: AtomicU32,
+
+    /// Whether a suspend has happened.
+    suspended: AtomicBool,
+}
+
+impl Susres {
+    pub fn new() -> Self {
+        Susres {
+            denials: AtomicU32::new(0),
+            suspended: AtomicBool::new(false),
+        }
+    }
+
+    fn subscribe(&self) -> LendResult {
+        // The callback will never be called, so there's no need to remember it
+        LendResult::MemoryReturned([0, 0])
+    }
+
+    fn suspend(&self, memory: &Memory) -> ScalarResult {
+        if self.denials.load(Ordering::Relaxed) != 0 {
+            return ScalarResult::Scalar1(0);
+        }
+        self.suspended.store(true, Ordering::Relaxed);
+        memory.suspends.fetch_add(1, Ordering::Relaxed);
+        if let Some(watchdog) = &memory.watchdog {
+            watchdog.pet(memory.platform.elapsed_ms());
+        }
+        ScalarResult::Scalar1(1)
+    }
+
+    fn deny(&self) {
+        self.denials.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn allow(&self) {
+        // An unmatched `SuspendAllow` is harmless, so don't let it underflow
+        self.denials
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |denials| {
+                denials.checked_sub(1)
+            })
+            .ok();
+    }
+}
+
+impl Default for Susres {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service for Susres {
+    fn scalar(
+        &self,
+        memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        _args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        if opcode == SusresOpcode::SuspendRequest as u32 {
+            self.suspend(memory);
+        } else if opcode == SusresOpcode::SuspendReady as u32 {
+            // Nobody is ever asked to get ready, so there's nothing to wait for
+        } else if opcode == SusresOpcode::SuspendDeny as u32 {
+            self.deny();
+        } else if opcode == SusresOpcode::SuspendAllow as u32 {
+            self.allow();
+        } else {
+            return Err(UnknownOpcode);
+        }
+        Ok(())
+    }
+
+    fn blocking_scalar(
+        &self,
+        memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        _args: [u32; 4],
+    ) -> ScalarResult {
+        if opcode == SusresOpcode::SuspendRequest as u32 {
+            self.suspend(memory)
+        } else if opcode == SusresOpcode::SuspendingNow as u32 {
+            ScalarResult::Scalar1(0)
+        } else if opcode == SusresOpcode::SuspendDeny as u32 {
+            self.deny();
+            ScalarResult::Scalar1(0)
+        } else if opcode == SusresOpcode::SuspendAllow as u32 {
+            self.allow();
+            ScalarResult::Scalar1(0)
+        } else if opcode == SusresOpcode::WasSuspendClean as u32 {
+            ScalarResult::Scalar1(self.suspended.load(Ordering::Relaxed) as u32)
+        } else {
+            ScalarResult::UnknownOpcode
+        }
+    }
+
+    fn lend(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        _buf: &[u8],
+        _extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == SusresOpcode::SuspendEventSubscribe as u32 {
+            return self.subscribe();
+        }
+        LendResult::UnknownOpcode
+    }
+
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        _buf: &mut LendBuffer,
+        _extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == SusresOpcode::SuspendEventSubscribe as u32 {
+            return self.subscribe();
+        }
+        LendResult::UnknownOpcode
+    }
+}
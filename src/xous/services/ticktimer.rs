@@ -2,27 +2,34 @@
 use std::{
     collections::{HashMap, VecDeque},
     sync::{
-        atomic::AtomicUsize,
         mpsc::{channel, Sender},
-        Arc, Condvar, Mutex,
+        Arc, Mutex,
     },
-    thread,
 };
 
-use super::ScalarResult;
-use crate::xous::{definitions::SyscallResultNumber, Memory};
+use super::{Message, MessageKind, Reply, ResponseData, ScalarResult};
+use crate::xous::{
+    definitions::{SyscallErrorNumber, SyscallResultNumber},
+    Memory,
+};
+
+/// A thread blocked in `WaitForCondition`.
+struct ConditionWaiter {
+    response: Sender<ResponseData>,
 
-type CondvarIndex = Arc<(Condvar, AtomicUsize)>;
+    /// When the wait times out, in milliseconds since the machine started.
+    deadline: Option<u64>,
+}
 
 pub struct Ticktimer {
-    start: std::time::SystemTime,
-    condvars: Arc<Mutex<HashMap<usize, CondvarIndex>>>,
+    condvars: Arc<Mutex<HashMap<usize, VecDeque<ConditionWaiter>>>>,
     mutexes: Arc<Mutex<HashMap<u32, bool>>>,
-    mutex_unlockers: Arc<Mutex<HashMap<u32, VecDeque<Sender<()>>>>>,
+    mutex_unlockers: Arc<Mutex<HashMap<u32, VecDeque<Sender<ResponseData>>>>>,
 }
 
 enum ScalarOpcode {
     ElapsedMs = 0,
+    PingWdt = 4,
     LockMutex = 6,
     UnlockMutex = 7,
     FreeMutex = 10,
@@ -31,11 +38,26 @@ enum ScalarOpcode {
     FreeCondition = 11,
 }
 
+fn scalar1_response(value: u32) -> ResponseData {
+    (
+        [
+            SyscallResultNumber::Scalar1 as i32,
+            value as i32,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ],
+        None,
+    )
+}
+
 impl Ticktimer {
     pub fn new() -> Self {
         // eprintln!("Created new Ticktimer");
         Ticktimer {
-            start: std::time::SystemTime::now(),
             condvars: Arc::new(Mutex::new(HashMap::new())),
             mutexes: Arc::new(Mutex::new(HashMap::new())),
             mutex_unlockers: Arc::new(Mutex::new(HashMap::new())),
@@ -47,23 +69,15 @@ impl Ticktimer {
         let mut mutexes = self.mutexes.lock().unwrap();
         let mutex_locked = mutexes.entry(mutex_index).or_default();
         if *mutex_locked {
-            let (wakeup_tx, wakeup_rx) = channel();
-            // Mutex was locked by a different thread. Pause this thread until it is unlocked.
+            // Mutex was locked by a different thread. Pause this thread until it is
+            // handed the lock by `unlock_mutex()`.
             let (tx, rx) = channel();
-            thread::spawn(move || {
-                wakeup_rx.recv().unwrap();
-                tx.send((
-                    [SyscallResultNumber::Scalar1 as i32, 0, 0, 0, 0, 0, 0, 0],
-                    None,
-                ))
-                .unwrap();
-            });
             self.mutex_unlockers
                 .lock()
                 .unwrap()
                 .entry(mutex_index)
                 .or_default()
-                .push_back(wakeup_tx);
+                .push_back(tx);
             return ScalarResult::WaitForResponse(rx);
         }
         *mutex_locked = true;
@@ -73,82 +87,62 @@ impl Ticktimer {
     fn unlock_mutex(&self, mutex_index: u32) -> ScalarResult {
         // eprintln!("Unlocking mutex {:08x}", mutex_index);
         let mut mutexes = self.mutexes.lock().unwrap();
-        let mutex_locked = mutexes.get_mut(&mutex_index).expect("mutex didn't exist");
-        assert!(*mutex_locked);
-        *mutex_locked = false;
+        let Some(mutex_locked) = mutexes.get_mut(&mutex_index).filter(|locked| **locked) else {
+            return ScalarResult::Refused(
+                SyscallErrorNumber::AccessDenied,
+                format!("unlocking mutex {:08x}, which isn't locked", mutex_index),
+            );
+        };
 
-        // Wake up one waiter if one existed
-        if let Some(Some(unlocker)) = self
-            .mutex_unlockers
-            .lock()
-            .unwrap()
-            .get_mut(&mutex_index)
-            .map(|v| v.pop_front())
-        {
-            unlocker.send(()).unwrap();
+        // Hand the lock directly to the next waiter, if one exists
+        if let Some(waiters) = self.mutex_unlockers.lock().unwrap().get_mut(&mutex_index) {
+            while let Some(waiter) = waiters.pop_front() {
+                if waiter.send(scalar1_response(0)).is_ok() {
+                    return ScalarResult::Scalar1(0);
+                }
+            }
         }
+        *mutex_locked = false;
         ScalarResult::Scalar1(0)
     }
 
     fn free_mutex(&self, mutex_index: u32) -> ScalarResult {
         // eprintln!("Freeing mutex {:08x}", mutex_index);
-        self.mutexes
-            .lock()
-            .unwrap()
-            .remove(&mutex_index)
-            .expect("mutex didn't exist");
+        if self.mutexes.lock().unwrap().remove(&mutex_index).is_none() {
+            return ScalarResult::Refused(
+                SyscallErrorNumber::DoubleFree,
+                format!("freeing mutex {:08x}, which doesn't exist", mutex_index),
+            );
+        }
         ScalarResult::Scalar1(0)
     }
 
-    fn wait_for_condition(&self, condition_index: usize, wait_count: u64) -> ScalarResult {
-        let (tx, rx) = channel();
-        let condvar = self
-            .condvars
-            .lock()
-            .unwrap()
-            .entry(condition_index)
-            .or_insert_with(|| Arc::new((Condvar::new(), AtomicUsize::new(0))))
-            .clone();
+    fn wait_for_condition(
+        &self,
+        memory: &Memory,
+        condition_index: usize,
+        wait_count: u64,
+    ) -> ScalarResult {
         // println!(
         //     "Waiting for condition {:08x} with a count of {} ms",
         //     condition_index, wait_count
         // );
-        condvar.1.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-
-        thread::spawn(move || {
-            let dummy_mutex = Mutex::new(());
-            let guard = dummy_mutex.lock().unwrap();
-            let timeout_value = if wait_count == 0 {
-                let _ignored = condvar.0.wait(guard).unwrap();
-                0
-            } else if condvar
-                .0
-                .wait_timeout(guard, std::time::Duration::from_millis(wait_count))
-                .unwrap()
-                .1
-                .timed_out()
-            {
-                1
-            } else {
-                0
-            };
-            condvar.1.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
-            tx.send((
-                [
-                    super::super::definitions::SyscallResultNumber::Scalar1 as i32,
-                    timeout_value,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                ],
-                None,
-            ))
-            .unwrap();
-        });
-        super::ScalarResult::WaitForResponse(rx)
+        let (tx, rx) = channel();
+        let deadline = if wait_count == 0 {
+            None
+        } else {
+            Some(memory.platform.elapsed_ms() + wait_count)
+        };
+        self.condvars
+            .lock()
+            .unwrap()
+            .entry(condition_index)
+            .or_default()
+            .push_back(ConditionWaiter {
+                response: tx,
+                deadline,
+            });
+        ScalarResult::WaitForResponse(rx)
     }
 
     fn notify_condition(&self, condition_index: usize, condition_count: usize) -> ScalarResult {
@@ -156,56 +150,59 @@ impl Ticktimer {
         //     "Notifying condition {:08x} {} times",
         //     condition_index, condition_count
         // );
-        if condition_count == 0 || !self.condvars.lock().unwrap().contains_key(&condition_index) {
-            return super::ScalarResult::Scalar5([0, 0, 0, 0, 0]);
+        let mut condvars = self.condvars.lock().unwrap();
+        let Some(waiters) = condvars.get_mut(&condition_index) else {
+            return ScalarResult::Scalar5([0, 0, 0, 0, 0]);
+        };
+        if condition_count == 0 {
+            return ScalarResult::Scalar5([0, 0, 0, 0, 0]);
         }
         let mut notify_count = 0;
-        if let Some(condvar) = self.condvars.lock().unwrap().get(&condition_index) {
-            if condition_count == 0 {
-                notify_count = condvar.1.load(std::sync::atomic::Ordering::Relaxed);
-                condvar.0.notify_all();
-            } else {
-                for _ in 0..condition_count {
-                    notify_count += 1;
-                    condvar.0.notify_one();
-                }
+        while notify_count < condition_count {
+            let Some(waiter) = waiters.pop_front() else {
+                break;
+            };
+            if waiter.response.send(scalar1_response(0)).is_ok() {
+                notify_count += 1;
             }
         }
-        super::ScalarResult::Scalar1(notify_count as u32)
+        ScalarResult::Scalar1(notify_count as u32)
     }
-}
 
-impl Default for Ticktimer {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl super::Service for Ticktimer {
-    fn scalar(&self, _memory: &Memory, _sender: u32, opcode: u32, args: [u32; 4]) {
-        if opcode == ScalarOpcode::FreeCondition as u32 {
+    fn handle_scalar(&self, memory: &Memory, message: &Message) -> Reply {
+        let (opcode, args) = (message.opcode, message.args);
+        if opcode == ScalarOpcode::PingWdt as u32 {
+            if let Some(watchdog) = &memory.watchdog {
+                watchdog.pet(memory.platform.elapsed_ms());
+            }
+        } else if opcode == ScalarOpcode::FreeCondition as u32 {
             let condition_index = args[0] as usize;
-            if let Some(condvar) = self.condvars.lock().unwrap().remove(&condition_index) {
-                assert!(condvar.1.load(std::sync::atomic::Ordering::Relaxed) == 0);
+            let mut condvars = self.condvars.lock().unwrap();
+            if condvars
+                .get(&condition_index)
+                .is_some_and(|waiters| !waiters.is_empty())
+            {
+                // The waiters are left waiting rather than stranded
+                return Reply::Refused(
+                    SyscallErrorNumber::MemoryInUse,
+                    format!(
+                        "freeing condition {:08x}, which threads are waiting on",
+                        condition_index
+                    ),
+                );
             }
+            condvars.remove(&condition_index);
         } else {
-            println!("Unhandled scalar: {}", opcode);
+            return Reply::UnknownOpcode;
         }
+        Reply::Ok
     }
 
-    fn blocking_scalar(
-        &self,
-        _memory: &Memory,
-        sender: u32,
-        opcode: u32,
-        args: [u32; 4],
-    ) -> super::ScalarResult {
+    fn handle_blocking_scalar(&self, memory: &Memory, message: &Message) -> ScalarResult {
+        let (opcode, args) = (message.opcode, message.args);
         if opcode == ScalarOpcode::ElapsedMs as u32 {
-            let elapsed_ms = std::time::SystemTime::now()
-                .duration_since(self.start)
-                .unwrap()
-                .as_millis() as u64;
-            super::ScalarResult::Scalar2([elapsed_ms as u32, (elapsed_ms >> 32) as u32])
+            let elapsed_ms = memory.platform.elapsed_ms();
+            ScalarResult::Scalar2([elapsed_ms as u32, (elapsed_ms >> 32) as u32])
         } else if opcode == ScalarOpcode::LockMutex as u32 {
             self.lock_mutex(args[0])
         } else if opcode == ScalarOpcode::UnlockMutex as u32 {
@@ -213,14 +210,78 @@ impl super::Service for Ticktimer {
         } else if opcode == ScalarOpcode::FreeMutex as u32 {
             self.free_mutex(args[0])
         } else if opcode == ScalarOpcode::WaitForCondition as u32 {
-            self.wait_for_condition(args[0] as usize, args[1] as u64)
+            self.wait_for_condition(memory, args[0] as usize, args[1] as u64)
         } else if opcode == ScalarOpcode::NotifyCondition as u32 {
             self.notify_condition(args[0] as usize, args[1] as usize)
         } else {
-            panic!(
-                "Ticktimer unhandled blocking_scalar {}: {} {:x?}",
-                sender, opcode, args
-            );
+            ScalarResult::UnknownOpcode
+        }
+    }
+}
+
+impl Default for Ticktimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl super::Service for Ticktimer {
+    fn message(&self, memory: &Memory, message: Message) -> Reply {
+        match message.kind {
+            MessageKind::Scalar => self.handle_scalar(memory, &message),
+            MessageKind::BlockingScalar => self.handle_blocking_scalar(memory, &message).into(),
+            _ => Reply::UnknownOpcode,
+        }
+    }
+
+    /// The deadlines of the threads waiting on conditions with a timeout,
+    /// by condition, in the order they're waiting.
+    fn timeouts(&self) -> Vec<(u32, u64)> {
+        let mut timeouts: Vec<(u32, u64)> = self
+            .condvars
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(&condition, waiters)| {
+                waiters
+                    .iter()
+                    .filter_map(move |waiter| Some((condition as u32, waiter.deadline?)))
+            })
+            .collect();
+        // A stable sort, so each condition's waiters stay in order
+        timeouts.sort_by_key(|&(condition, _)| condition);
+        timeouts
+    }
+
+    fn set_timeouts(&self, timeouts: &[(u32, u64)]) {
+        for (&condition, waiters) in self.condvars.lock().unwrap().iter_mut() {
+            let mut deadlines = timeouts
+                .iter()
+                .filter(|&&(waiting_on, _)| waiting_on as usize == condition)
+                .map(|&(_, deadline)| deadline);
+            for waiter in waiters
+                .iter_mut()
+                .filter(|waiter| waiter.deadline.is_some())
+            {
+                let Some(deadline) = deadlines.next() else {
+                    break;
+                };
+                waiter.deadline = Some(deadline);
+            }
+        }
+    }
+
+    fn tick(&self, memory: &Memory) {
+        let now = memory.platform.elapsed_ms();
+        for waiters in self.condvars.lock().unwrap().values_mut() {
+            waiters.retain(|waiter| match waiter.deadline {
+                Some(deadline) if deadline <= now => {
+                    // The waiter may have gone away, in which case there's nobody to tell.
+                    waiter.response.send(scalar1_response(1)).ok();
+                    false
+                }
+                _ => true,
+            });
         }
     }
 }
@@ -0,0 +1,66 @@
+//! The TRNG server, which on hardware reads the SoC's true random number
+//! generator. Under emulation the numbers come from the machine's seeded
+//! generator instead, so that a run can be repeated from its seed.
+
+use std::sync::Arc;
+
+use super::{LendBuffer, LendResult, ScalarResult, Service};
+use crate::xous::rng::Rng;
+use crate::xous::Memory;
+
+enum TrngOpcode {
+    /// Returns a Scalar2 of random words. The first argument is how many
+    /// the caller wants, one or two, but both are always filled in.
+    GetTrng = 0,
+
+    /// Fill the valid bytes of the lent buffer, of which the second
+    /// argument says how many there are, with random data. Returns how many
+    /// bytes were filled.
+    FillTrng = 1,
+}
+
+pub struct Trng {
+    rng: Arc<Rng>,
+}
+
+impl Trng {
+    pub fn new(rng: Arc<Rng>) -> Self {
+        Trng { rng }
+    }
+}
+
+impl Service for Trng {
+    fn blocking_scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        _args: [u32; 4],
+    ) -> ScalarResult {
+        if opcode != TrngOpcode::GetTrng as u32 {
+            return ScalarResult::UnknownOpcode;
+        }
+        let random = self.rng.next_u64();
+        ScalarResult::Scalar2([random as u32, (random >> 32) as u32])
+    }
+
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode != TrngOpcode::FillTrng as u32 {
+            return LendResult::UnknownOpcode;
+        }
+        let length = (extra[1] as usize).min(buf.len());
+        let mut random = Vec::with_capacity(length.next_multiple_of(8));
+        while random.len() < length {
+            random.extend_from_slice(&self.rng.next_u64().to_le_bytes());
+        }
+        buf.write(0, &random[..length]);
+        LendResult::MemoryReturned([0, length as u32])
+    }
+}
@@ -0,0 +1,162 @@
+//! The usb-device server, which lets the program type on the host as a USB
+//! keyboard and talk to it over a USB serial port. Everything is handed to
+//! the machine's `UsbDevice`.
+
+use std::sync::Arc;
+
+use super::archive::Archive;
+use super::{LendBuffer, LendResult, ScalarResult, Service, UnknownOpcode};
+use crate::xous::usb::{UsbCore, UsbDevice};
+use crate::xous::Memory;
+
+/// What `LinkStatus` reports: the host has configured the device.
+const CONFIGURED: u32 = 3;
+
+#[allow(dead_code)]
+enum UsbOpcode {
+    /// Returns the state of the link, which is always configured.
+    LinkStatus = 0,
+
+    /// Type the keys whose HID usage IDs are in the arguments, ignoring
+    /// any that are zero.
+    SendKeyCode = 1,
+
+    /// Type the string in the lent buffer, which is a 32-bit length
+    /// followed by the text. Returns how many characters were typed.
+    SendString = 2,
+
+    /// Returns the keyboard LEDs the host has lit.
+    GetLedState = 3,
+
+    /// Present the core in the first argument to the host.
+    SwitchCores = 4,
+    EnsureCore = 5,
+
+    /// Returns the core the host sees.
+    WhichCore = 6,
+
+    /// Write the valid bytes of the lent buffer to the serial port.
+    SerialTx = 20,
+
+    /// Fill the lent buffer with whatever serial input has arrived, without
+    /// waiting. Returns how many bytes that was.
+    SerialRx = 21,
+}
+
+/// The character a HID keyboard usage ID types, without any modifiers held.
+fn key(usage: u32) -> Option<char> {
+    match usage {
+        0x04..=0x1d => Some((b'a' + (usage - 0x04) as u8) as char),
+        0x1e..=0x26 => Some((b'1' + (usage - 0x1e) as u8) as char),
+        0x27 => Some('0'),
+        0x28 => Some('\n'),
+        0x2b => Some('\t'),
+        0x2c => Some(' '),
+        _ => None,
+    }
+}
+
+pub struct Usb {
+    device: Arc<UsbDevice>,
+}
+
+impl Usb {
+    pub fn new(device: Arc<UsbDevice>) -> Self {
+        Usb { device }
+    }
+
+    fn switch_cores(&self, core: u32) {
+        self.device.set_core(match core {
+            0 => UsbCore::Debug,
+            _ => UsbCore::Device,
+        });
+    }
+}
+
+impl Service for Usb {
+    fn scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> Result<(), UnknownOpcode> {
+        if opcode == UsbOpcode::SwitchCores as u32 || opcode == UsbOpcode::EnsureCore as u32 {
+            self.switch_cores(args[0]);
+            Ok(())
+        } else {
+            Err(UnknownOpcode)
+        }
+    }
+
+    fn blocking_scalar(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        args: [u32; 4],
+    ) -> ScalarResult {
+        if opcode == UsbOpcode::LinkStatus as u32 {
+            ScalarResult::Scalar1(CONFIGURED)
+        } else if opcode == UsbOpcode::SendKeyCode as u32 {
+            let text: String = args.iter().filter_map(|&usage| key(usage)).collect();
+            self.device.type_text(&text);
+            ScalarResult::Scalar1(0)
+        } else if opcode == UsbOpcode::GetLedState as u32 {
+            ScalarResult::Scalar1(self.device.leds())
+        } else if opcode == UsbOpcode::SwitchCores as u32 || opcode == UsbOpcode::EnsureCore as u32
+        {
+            self.switch_cores(args[0]);
+            ScalarResult::Scalar1(0)
+        } else if opcode == UsbOpcode::WhichCore as u32 {
+            ScalarResult::Scalar1(self.device.core() as u32)
+        } else {
+            ScalarResult::UnknownOpcode
+        }
+    }
+
+    fn lend(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &[u8],
+        extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == UsbOpcode::SerialTx as u32 {
+            let length = (extra[1] as usize).min(buf.len());
+            self.device.write_serial(&buf[..length]);
+            return LendResult::MemoryReturned([0, length as u32]);
+        }
+        LendResult::UnknownOpcode
+    }
+
+    fn lend_mut(
+        &self,
+        _memory: &Memory,
+        _sender: u32,
+        opcode: u32,
+        buf: &mut LendBuffer,
+        _extra: [u32; 2],
+    ) -> LendResult {
+        if opcode == UsbOpcode::SendString as u32 {
+            let capacity = buf.len().saturating_sub(4);
+            let typed = match Archive::new(buf).fixed_string(0, capacity) {
+                Ok(text) => {
+                    self.device.type_text(text);
+                    text.chars().count() as u32
+                }
+                Err(error) => {
+                    log::warn!("Program typed an invalid string over USB: {}", error);
+                    0
+                }
+            };
+            return LendResult::MemoryReturned([typed, 0]);
+        } else if opcode == UsbOpcode::SerialRx as u32 {
+            let length = buf.len();
+            let count = self.device.read_serial(buf.get_mut(0..length).unwrap());
+            return LendResult::MemoryReturned([0, count as u32]);
+        }
+        LendResult::UnknownOpcode
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::RwLock;
+
+use super::profiler::Symbol;
+
+/// Forget the oldest return addresses once a thread has this many, so that
+/// code that calls without ever returning can't use up host memory.
+const MAX_DEPTH: usize = 4096;
+
+/// Code that is allowed to break the call/return discipline, such as
+/// `longjmp` or a hand-written context switch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Allow {
+    /// The instructions in this address range.
+    Range(Range<u32>),
+
+    /// The function with this name in the program's symbol table.
+    Symbol(String),
+}
+
+impl std::str::FromStr for Allow {
+    type Err = String;
+
+    /// Parse `<start>-<end>` or a single `<address>` in hex, or a symbol name.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let address = |text: &str| {
+            u32::from_str_radix(text.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("invalid address {:?}", text))
+        };
+        if !spec.starts_with("0x") {
+            return Ok(Allow::Symbol(spec.to_owned()));
+        }
+        match spec.split_once('-') {
+            Some((start, end)) => Ok(Allow::Range(address(start)?..address(end)?)),
+            None => {
+                let start = address(spec)?;
+                Ok(Allow::Range(start..start.saturating_add(1)))
+            }
+        }
+    }
+}
+
+/// Which code is exempt from shadow stack checks, shared by every thread.
+pub struct ShadowStackPolicy {
+    allow: Vec<Allow>,
+    ranges: RwLock<Vec<Range<u32>>>,
+}
+
+impl ShadowStackPolicy {
+    pub fn new(allow: Vec<Allow>) -> Self {
+        let ranges = allow
+            .iter()
+            .filter_map(|allow| match allow {
+                Allow::Range(range) => Some(range.clone()),
+                Allow::Symbol(_) => None,
+            })
+            .collect();
+        ShadowStackPolicy {
+            allow,
+            ranges: RwLock::new(ranges),
+        }
+    }
+
+    /// Look up the symbols named in the allowlist. Returns the names that
+    /// aren't in `symbols`.
+    pub(super) fn set_symbols(&self, symbols: &[Symbol]) -> Vec<String> {
+        let mut missing = vec![];
+        let mut ranges = self.ranges.write().unwrap();
+        for allow in &self.allow {
+            let Allow::Symbol(name) = allow else {
+                continue;
+            };
+            match symbols.iter().find(|symbol| &symbol.name == name) {
+                // A symbol that runs to the end of the address space
+                // stops short of its last byte rather than wrapping
+                Some(symbol) => {
+                    ranges.push(symbol.address..symbol.address.saturating_add(symbol.size.max(1)));
+                }
+                None => missing.push(name.clone()),
+            }
+        }
+        missing
+    }
+
+    fn allows(&self, pc: u32) -> bool {
+        self.ranges
+            .read()
+            .unwrap()
+            .iter()
+            .any(|range| range.contains(&pc))
+    }
+}
+
+/// A return that didn't go back to where the matching call came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Mismatch {
+    /// Where the most recent call should have returned to.
+    pub expected: u32,
+
+    /// Where the return actually went.
+    pub actual: u32,
+}
+
+/// Whether a register is used for return addresses by the calling convention.
+pub(super) fn is_link(register: u32) -> bool {
+    register == 1 || register == 5
+}
+
+/// Whether the instruction `word` returns from a call, and whether it makes
+/// one, going by the hints in the RISC-V specification: a jump that writes
+/// `ra` or `t0` is a call, and a `jalr` through either of them is a return.
+/// `word` must already be uncompressed. A `jalr` that does both swaps
+/// coroutines.
+pub(super) fn call_or_return(word: u32) -> (bool, bool) {
+    let rd = (word >> 7) & 0x1f;
+    let rs1 = (word >> 15) & 0x1f;
+    match word & 0x7f {
+        // jal
+        0x6f => (false, is_link(rd)),
+        // jalr
+        0x67 if (word >> 12) & 0x7 == 0 => match (is_link(rd), is_link(rs1)) {
+            (false, false) => (false, false),
+            (true, false) => (false, true),
+            (false, true) => (true, false),
+            (true, true) => (rd != rs1, true),
+        },
+        _ => (false, false),
+    }
+}
+
+/// Whether the instruction `word`, which may be compressed, is a call.
+pub(super) fn is_call(word: u32) -> bool {
+    if word & 0x3 == 0x3 {
+        return call_or_return(word).1;
+    }
+    let word = word & 0xffff;
+    match (word & 0x3, word >> 13) {
+        // c.jal, which always links through `ra`
+        (0x1, 0x1) => true,
+        // c.jalr, which is c.ebreak when rs1 is zero
+        (0x2, 0x4) => (word >> 12) & 1 == 1 && (word >> 2) & 0x1f == 0 && (word >> 7) & 0x1f != 0,
+        _ => false,
+    }
+}
+
+/// The return addresses of one thread's calls that haven't returned yet.
+#[derive(Default)]
+pub(super) struct ShadowStack {
+    returns: VecDeque<u32>,
+}
+
+impl ShadowStack {
+    /// Follow the instruction `word` at `pc`, which has just run and left the
+    /// CPU at `next_pc` with `link` in its `rd` register. Calls and returns are
+    /// recognized by `call_or_return`.
+    pub fn step(
+        &mut self,
+        policy: &ShadowStackPolicy,
+        pc: u32,
+        word: u32,
+        next_pc: u32,
+        link: u32,
+    ) -> Result<(), Mismatch> {
+        // Only `jal` and `jalr` can call or return
+        if word & 0x7f != 0x6f && word & 0x707f != 0x67 {
+            return Ok(());
+        }
+        let (pops, pushes) = call_or_return(word);
+
+        if policy.allows(pc) {
+            // Unwind to wherever an allowed jump lands, as with `longjmp`
+            if let Some(depth) = self.returns.iter().rposition(|&address| address == next_pc) {
+                self.returns.truncate(depth);
+            }
+            return Ok(());
+        }
+
+        if pops {
+            // Returning from a thread's entry point leaves nothing to check
+            if let Some(expected) = self.returns.pop_back() {
+                if expected != next_pc {
+                    return Err(Mismatch {
+                        expected,
+                        actual: next_pc,
+                    });
+                }
+            }
+        }
+        if pushes {
+            if self.returns.len() == MAX_DEPTH {
+                self.returns.pop_front();
+            }
+            self.returns.push_back(link);
+        }
+        Ok(())
+    }
+}
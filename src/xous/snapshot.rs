@@ -0,0 +1,180 @@
+//! Saving where a machine has got to, so that it can be carried on from
+//! there later, even by another process.
+//!
+//! A machine keeps no history, and nothing serializes its RAM, page tables,
+//! threads, and services. What a snapshot holds instead is what it takes to
+//! get back there: the program, the seed, and how many instructions had
+//! been retired. Restoring builds the program again with the same builder
+//! settings and seeks to that count, as `Machine::seek` does when it goes
+//! backwards. That only lands in the same state if the run is
+//! deterministic: driven by `step()` rather than `run()`, with
+//! `CounterPolicy::Deterministic`, and with no input from the host.
+//!
+//! The replay doesn't take as long as the run did, so the snapshot also
+//! holds the virtual time and the ticktimer's timeouts. The restored
+//! machine's clock carries on from the snapshot's time rather than the
+//! replay's, and its timeouts expire when they would have.
+
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use super::{Machine, MachineBuilder, MachineEvent};
+use crate::YoveError;
+
+/// What a snapshot file starts with.
+const MAGIC: &[u8; 8] = b"YOVESNAP";
+
+/// The version of the layout after `MAGIC`.
+const VERSION: u32 = 2;
+
+/// How far the clock is moved on at a time while every thread of a replay
+/// is blocked, in microseconds, so that timeouts expire in the order they
+/// did without waiting for the host's clock to get there.
+const IDLE_ADVANCE_US: u64 = 1000;
+
+/// How long a replay may sit with every thread blocked once its clock has
+/// caught up with the snapshot's before restoring gives up. Only the host
+/// can wake it by then, as a command run with `yove-host-exec` would.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where a machine had got to, as taken by `Machine::snapshot()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub(super) instructions: u64,
+    pub(super) seed: u64,
+
+    /// The virtual time, in microseconds.
+    pub(super) time_us: u64,
+
+    /// The ticktimer's timeouts, as `Service::timeouts` gives them.
+    pub(super) timeouts: Vec<(u32, u64)>,
+    pub(super) program: Vec<u8>,
+}
+
+impl Snapshot {
+    /// How many instructions had been retired when the snapshot was taken.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The seed the machine was built with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The virtual time when the snapshot was taken, in microseconds.
+    pub fn time_us(&self) -> u64 {
+        self.time_us
+    }
+
+    /// The program the machine was built from.
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// Build the program again with `builder`, which should have the same
+    /// settings as the one the snapshot was taken from, and the snapshot's
+    /// seed, and run it to where the snapshot was taken. Fails with
+    /// `YoveError::Restore` if the program exits short of there, or every
+    /// thread blocks with nothing left to wake them, which means the run
+    /// wasn't deterministic or the settings differ.
+    pub fn restore(&self, builder: MachineBuilder) -> Result<Machine, YoveError> {
+        let mut machine = builder.seed(self.seed).build(&self.program)?;
+        let mut idle_since = None;
+        while machine.instructions_retired() < self.instructions {
+            match machine.seek(self.instructions)? {
+                MachineEvent::Exited(_) => break,
+                MachineEvent::Idle => {
+                    // A thread may be waiting on a timeout, as it was when
+                    // the snapshot's run got past here
+                    let clock = machine.clock();
+                    let behind = self.time_us.saturating_sub(clock.now_us());
+                    if behind > 0 {
+                        clock.advance_us(behind.min(IDLE_ADVANCE_US));
+                        continue;
+                    }
+                    if idle_since.get_or_insert_with(Instant::now).elapsed() >= IDLE_TIMEOUT {
+                        break;
+                    }
+                    std::thread::sleep(super::IDLE_POLL_INTERVAL);
+                }
+                MachineEvent::Running | MachineEvent::Stopped { .. } => idle_since = None,
+            }
+        }
+        let reached = machine.instructions_retired();
+        if reached != self.instructions {
+            return Err(YoveError::Restore {
+                instructions: self.instructions,
+                reached,
+            });
+        }
+        // Carry on from the snapshot's time, which the replay may not have
+        // got to, and its timeouts, which the replay set by its own clock
+        let clock = machine.clock();
+        clock.advance_us(self.time_us.saturating_sub(clock.now_us()));
+        machine.memory.set_service_timeouts(&self.timeouts);
+        Ok(machine)
+    }
+
+    /// Write the snapshot to `output`, to be read back with `read()`.
+    pub fn write(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let length = u32::try_from(self.program.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "program is too large")
+        })?;
+        output.write_all(MAGIC)?;
+        output.write_all(&VERSION.to_le_bytes())?;
+        output.write_all(&self.instructions.to_le_bytes())?;
+        output.write_all(&self.seed.to_le_bytes())?;
+        output.write_all(&self.time_us.to_le_bytes())?;
+        output.write_all(&(self.timeouts.len() as u32).to_le_bytes())?;
+        for (waiting_on, deadline) in &self.timeouts {
+            output.write_all(&waiting_on.to_le_bytes())?;
+            output.write_all(&deadline.to_le_bytes())?;
+        }
+        output.write_all(&length.to_le_bytes())?;
+        output.write_all(&self.program)
+    }
+
+    /// Read a snapshot written by `write()`.
+    pub fn read(input: &mut impl Read) -> std::io::Result<Snapshot> {
+        let invalid = |message| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        let mut word = [0; 4];
+        let mut double = [0; 8];
+        input.read_exact(&mut word)?;
+        if u32::from_le_bytes(word) != VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+        input.read_exact(&mut double)?;
+        let instructions = u64::from_le_bytes(double);
+        input.read_exact(&mut double)?;
+        let seed = u64::from_le_bytes(double);
+        input.read_exact(&mut double)?;
+        let time_us = u64::from_le_bytes(double);
+        input.read_exact(&mut word)?;
+        let mut timeouts = vec![];
+        for _ in 0..u32::from_le_bytes(word) {
+            input.read_exact(&mut word)?;
+            input.read_exact(&mut double)?;
+            timeouts.push((u32::from_le_bytes(word), u64::from_le_bytes(double)));
+        }
+        input.read_exact(&mut word)?;
+        let length = u32::from_le_bytes(word) as u64;
+        let mut program = vec![];
+        input.take(length).read_to_end(&mut program)?;
+        if program.len() as u64 != length {
+            return Err(invalid("snapshot is truncated"));
+        }
+        Ok(Snapshot {
+            instructions,
+            seed,
+            time_us,
+            timeouts,
+            program,
+        })
+    }
+}
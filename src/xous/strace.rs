@@ -0,0 +1,166 @@
+use riscv_cpu::syscall::SyscallResult;
+
+use super::definitions::memoryflags::MemoryFlags;
+use super::definitions::{
+    MemoryType, Syscall, SyscallErrorNumber, SyscallNumber, SyscallResultNumber,
+};
+use super::services::MessageKind;
+
+/// Memory flags the way `MapMemory` callers write them, such as `RW`.
+fn describe_flags(flags: i32) -> String {
+    let mut description = String::new();
+    let mut rest = flags as u32 as usize;
+    for (flag, letter) in [
+        (MemoryFlags::READ, 'R'),
+        (MemoryFlags::WRITE, 'W'),
+        (MemoryFlags::EXECUTE, 'X'),
+    ] {
+        if rest & flag.bits() != 0 {
+            description.push(letter);
+            rest &= !flag.bits();
+        }
+    }
+    match (description.is_empty(), rest) {
+        (true, rest) => format!("{:#x}", rest),
+        (false, 0) => description,
+        (false, rest) => format!("{}|{:#x}", description, rest),
+    }
+}
+
+/// `values` in hex, separated by commas, all on one line, which `{:#x?}`
+/// doesn't keep them to.
+fn hex_list(values: &[impl std::fmt::LowerHex]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{:#x}", value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The arguments of a message, which are a buffer for the kinds that carry memory.
+fn describe_message(kind: u32, opcode: u32, args: [u32; 4]) -> String {
+    match MessageKind::from_u32(kind) {
+        Some(kind) if kind.has_memory() => format!(
+            "{:?}, opcode={}, buf={:#x}, len={:#x}, offset={:#x}, valid={:#x}",
+            kind, opcode, args[0], args[1], args[2], args[3]
+        ),
+        Some(kind) => format!("{:?}, opcode={}, args=[{}]", kind, opcode, hex_list(&args)),
+        None => format!(
+            "kind={}, opcode={}, args=[{}]",
+            kind,
+            opcode,
+            hex_list(&args)
+        ),
+    }
+}
+
+/// A syscall and its arguments, such as
+/// `MapMemory(phys=0, virt=0, size=0x4000, flags=RW)`.
+pub(super) fn describe_call(syscall: &Syscall) -> String {
+    match syscall {
+        Syscall::Yield => "Yield".to_owned(),
+        Syscall::IncreaseHeap(bytes, flags) => format!(
+            "IncreaseHeap(bytes={:#x}, flags={})",
+            bytes,
+            describe_flags(*flags)
+        ),
+        Syscall::MapMemory(phys, virt, size, flags) => format!(
+            "MapMemory(phys={:#x}, virt={:#x}, size={:#x}, flags={})",
+            phys,
+            virt,
+            size,
+            describe_flags(*flags)
+        ),
+        Syscall::Connect(id) => format!("Connect(sid={:08x?})", id),
+        Syscall::TryConnect(id) => format!("TryConnect(sid={:08x?})", id),
+        Syscall::Disconnect(connection_id) => format!("Disconnect(cid={})", connection_id),
+        Syscall::SendMessage(connection_id, kind, opcode, args) => format!(
+            "SendMessage(cid={}, {})",
+            connection_id,
+            describe_message(*kind, *opcode, *args)
+        ),
+        Syscall::TrySendMessage(connection_id, kind, opcode, args) => format!(
+            "TrySendMessage(cid={}, {})",
+            connection_id,
+            describe_message(*kind, *opcode, *args)
+        ),
+        Syscall::UpdateMemoryFlags(address, range, flags) => format!(
+            "UpdateMemoryFlags(address={:#x}, size={:#x}, flags={})",
+            address,
+            range,
+            describe_flags(*flags)
+        ),
+        Syscall::ReturnToParent(pid, cpu) => format!("ReturnToParent(pid={}, cpu={})", pid, cpu),
+        Syscall::SetMemRegion(pid, kind, address, size) => format!(
+            "SetMemRegion(pid={}, type={}, address={:#x}, size={:#x})",
+            pid,
+            MemoryType::try_from(*kind)
+                .map_or_else(|kind| kind.to_string(), |kind| format!("{:?}", kind)),
+            address,
+            size
+        ),
+        Syscall::CreateThread(entry_point, stack_pointer, stack_length, a1, a2, a3, a4) => {
+            format!(
+                "CreateThread(entry={:#x}, stack={:#x}, stack_size={:#x}, args=[{:#x}, {:#x}, {:#x}, {:#x}])",
+                entry_point, stack_pointer, stack_length, a1, a2, a3, a4
+            )
+        }
+        Syscall::JoinThread(thread_id) => format!("JoinThread(tid={})", thread_id),
+        Syscall::VirtToPhys(address) => format!("VirtToPhys(address={:#x})", address),
+        Syscall::VirtToPhysPid(pid, address) => {
+            format!("VirtToPhysPid(pid={}, address={:#x})", pid, address)
+        }
+        Syscall::UnmapMemory(address, size) => {
+            format!("UnmapMemory(address={:#x}, size={:#x})", address, size)
+        }
+        Syscall::TerminateProcess(exit_code) => format!("TerminateProcess(code={})", exit_code),
+        Syscall::GetProcessId => "GetProcessId".to_owned(),
+        Syscall::WaitEvent => "WaitEvent".to_owned(),
+        Syscall::ExitThread(result) => format!("ExitThread(result={})", result),
+        Syscall::Unknown(args) => match SyscallNumber::from(args[0]) {
+            SyscallNumber::Unknown => format!("Syscall{}({})", args[0], hex_list(&args[1..])),
+            number => format!("{:?}({})", number, hex_list(&args[1..])),
+        },
+    }
+}
+
+/// What a syscall returned, such as `MemoryRange(0x40080000, 0x4000)`.
+pub(super) fn describe_result(result: &SyscallResult) -> String {
+    let args = match result {
+        SyscallResult::Ok(args) => args,
+        SyscallResult::Suspend(_) => return "<blocked>".to_owned(),
+        SyscallResult::Terminate(exit_code) => return format!("<exit {}>", exit_code),
+        SyscallResult::ExitThread(result) => return format!("<thread exit {}>", result),
+        SyscallResult::Continue => return "<exception>".to_owned(),
+    };
+    let words = |count: usize| {
+        args[1..=count]
+            .iter()
+            .map(|word| format!("{:#x}", word))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let number = match SyscallResultNumber::try_from(args[0]) {
+        Ok(number) => number,
+        Err(number) => return format!("Result{}({})", number, words(7)),
+    };
+    match number {
+        SyscallResultNumber::Ok => "Ok".to_owned(),
+        SyscallResultNumber::Error => match SyscallErrorNumber::try_from(args[1]) {
+            Ok(error) => format!("Error({:?})", error),
+            Err(error) => format!("Error({})", error),
+        },
+        SyscallResultNumber::MemoryRange | SyscallResultNumber::MemoryReturned => {
+            format!("{:?}({})", number, words(2))
+        }
+        SyscallResultNumber::ConnectionId
+        | SyscallResultNumber::ThreadId
+        | SyscallResultNumber::ProcessId => format!("{:?}({})", number, args[1]),
+        SyscallResultNumber::Scalar1 => format!("Scalar1({})", words(1)),
+        SyscallResultNumber::Scalar2 => format!("Scalar2({})", words(2)),
+        SyscallResultNumber::Scalar5 => format!("Scalar5({})", words(5)),
+        SyscallResultNumber::Message | SyscallResultNumber::Unimplemented => {
+            format!("{:?}({})", number, words(7))
+        }
+    }
+}
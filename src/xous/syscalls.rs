@@ -2,22 +2,108 @@ use std::sync::atomic::Ordering;
 use std::sync::mpsc::channel;
 
 use super::super::xous::services::get_service;
-use super::definitions::{SyscallErrorNumber, SyscallResultNumber};
-use super::services;
-use super::Memory;
+use super::abuse::{Abuse, AbuseHandler};
+use super::definitions::{MemoryType, SyscallErrorNumber, SyscallResultNumber};
+use super::services::{self, LendBuffer, Message, MessageKind, MessageMemory, Reply};
 use super::SyscallResult;
-use riscv_cpu::cpu::Memory as OtherMemory;
+use super::{
+    Memory, KERNEL_PROCESS_ID, MMUFLAG_EXECUTABLE, MMUFLAG_READABLE, MMUFLAG_WRITABLE, PROCESS_ID,
+    USER_AREA_END,
+};
+use crate::YoveError;
+
+/// The most memory a single message may carry. Rejecting larger messages up
+/// front keeps a corrupt size from having the host copy gigabytes.
+const MAX_MESSAGE_BYTES: u32 = 4 * 1024 * 1024;
+
+fn error(error: SyscallErrorNumber) -> SyscallResult {
+    [
+        SyscallResultNumber::Error as i32,
+        error as i32,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ]
+    .into()
+}
+
+/// Deal with the calling thread misusing a syscall as the abuse policy says
+/// for `abuse`. A warning gives the thread `result`, and ending the process
+/// stops the machine with `failure`.
+pub(super) fn handle_abuse(
+    memory: &Memory,
+    abuse: Abuse,
+    result: SyscallResult,
+    failure: YoveError,
+) -> SyscallResult {
+    match memory.abuse.handler(abuse) {
+        AbuseHandler::Warn => {
+            log::warn!("{}", failure);
+            result
+        }
+        AbuseHandler::Trap => {
+            log::warn!("{}, raising an exception", failure);
+            SyscallResult::Continue
+        }
+        AbuseHandler::KillThread => {
+            log::warn!("{}, ending the thread", failure);
+            SyscallResult::ExitThread(!0)
+        }
+        AbuseHandler::KillProcess => {
+            *memory.failure.lock().unwrap() = Some(failure);
+            SyscallResult::Terminate(!0)
+        }
+    }
+}
+
+/// Refuse a call that misuses the interface in the way `abuse` and `detail`
+/// describe, failing it with `number` if the policy is only to warn.
+fn refuse(
+    memory: &Memory,
+    abuse: Abuse,
+    number: SyscallErrorNumber,
+    detail: String,
+) -> SyscallResult {
+    let failure = YoveError::Abuse {
+        tid: memory.tid,
+        abuse,
+        detail,
+    };
+    handle_abuse(memory, abuse, error(number), failure)
+}
 
 pub fn map_memory(memory: &Memory, phys: i32, virt: i32, size: i32, _flags: i32) -> SyscallResult {
-    // print!(
-    //     "MapMemory(phys: {:08x}, virt: {:08x}, bytes: {}, flags: {:02x})",
-    //     phys, virt, size, _flags
-    // );
+    // Regions can't be placed at a chosen address
     if virt != 0 {
-        unimplemented!("Non-zero virt address");
+        return error(SyscallErrorNumber::BadAddress);
+    }
+    if size <= 0 {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::BadAddress,
+            format!("MapMemory of {} bytes", size),
+        );
     }
+    if size & 0xfff != 0 {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::BadAlignment,
+            format!("MapMemory of {:#x} bytes, which isn't whole pages", size),
+        );
+    }
+    // The only physical memory there is to map is the framebuffer
     if phys != 0 {
-        unimplemented!("Non-zero phys address");
+        return match &memory.framebuffer {
+            Some(framebuffer) if phys as u32 == super::framebuffer::FRAMEBUFFER_ADDRESS => {
+                map_framebuffer(memory, framebuffer, size)
+            }
+            _ => error(SyscallErrorNumber::BadAddress),
+        };
     }
     if let Some(region) = memory.allocate_virt_region(size as usize) {
         [
@@ -33,7 +119,7 @@ pub fn map_memory(memory: &Memory, phys: i32, virt: i32, size: i32, _flags: i32)
         .into()
     } else {
         // self.print_mmu();
-        println!(
+        log::warn!(
             "Couldn't find a free spot to allocate {} bytes of virtual memory, or out of memory",
             size as usize
         );
@@ -51,23 +137,17 @@ pub fn map_memory(memory: &Memory, phys: i32, virt: i32, size: i32, _flags: i32)
     }
 }
 
-pub fn connect(memory: &Memory, id: [u32; 4]) -> SyscallResult {
-    // println!(
-    //     "Connect([0x{:08x}, 0x{:08x}, 0x{:08x}, 0x{:08x}])",
-    //     id[0], id[1], id[2], id[3]
-    // );
-
-    // If a connection already exists, use that.
-    let existing_connection = memory
-        .named_connections_index
-        .lock()
-        .unwrap()
-        .get(&id)
-        .copied();
-    if let Some(connection_id) = existing_connection {
+/// Back the display with ordinary memory, which the host reads when it
+/// takes a screenshot.
+fn map_framebuffer(
+    memory: &Memory,
+    framebuffer: &super::framebuffer::Framebuffer,
+    size: i32,
+) -> SyscallResult {
+    let error = |error: SyscallErrorNumber| {
         [
-            SyscallResultNumber::ConnectionId as i32,
-            connection_id as i32,
+            SyscallResultNumber::Error as i32,
+            error as i32,
             0,
             0,
             0,
@@ -76,45 +156,74 @@ pub fn connect(memory: &Memory, id: [u32; 4]) -> SyscallResult {
             0,
         ]
         .into()
-    } else if let Some(service) = get_service(&id) {
-        let connection_id = memory.connection_index.fetch_add(1, Ordering::Relaxed);
-        let mut connections = memory.connections.lock().unwrap();
-        connections.insert(connection_id, service);
+    };
+    if (size as u32) < super::framebuffer::FRAMEBUFFER_BYTES {
+        return error(SyscallErrorNumber::BadAddress);
+    }
+    let Some(region) = memory.allocate_virt_region(size as usize) else {
+        return error(SyscallErrorNumber::OutOfMemory);
+    };
+    if !framebuffer.attach(memory.space.asid, region..region + size as u32) {
         memory
-            .named_connections_index
-            .lock()
-            .unwrap()
-            .insert(id, connection_id);
-        [
-            SyscallResultNumber::ConnectionId as i32,
-            connection_id as i32,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-        ]
-        .into()
-    } else {
-        [
-            SyscallResultNumber::ConnectionId as i32,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-        ]
-        .into()
+            .unmap_region(region, region + size as u32)
+            .expect("the region was just mapped");
+        return error(SyscallErrorNumber::MemoryInUse);
     }
+    [
+        SyscallResultNumber::MemoryRange as i32,
+        region as i32,
+        size,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ]
+    .into()
+}
+
+pub fn connect(memory: &Memory, id: [u32; 4]) -> SyscallResult {
+    let Some(connection_id) = memory
+        .connections
+        .lock()
+        .unwrap()
+        .connect_server_id(id, || get_service(&id).map(Into::into))
+    else {
+        return error(SyscallErrorNumber::ServerNotFound);
+    };
+    [
+        SyscallResultNumber::ConnectionId as i32,
+        connection_id as i32,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ]
+    .into()
 }
 
 pub fn try_connect(memory: &Memory, id: [u32; 4]) -> SyscallResult {
     connect(memory, id)
 }
 
+pub fn disconnect(memory: &Memory, connection_id: u32) -> SyscallResult {
+    if memory.connections.lock().unwrap().disconnect(connection_id) {
+        [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into()
+    } else {
+        refuse(
+            memory,
+            Abuse::BadConnection,
+            SyscallErrorNumber::ServerNotFound,
+            format!(
+                "Disconnect from connection {}, which isn't connected",
+                connection_id
+            ),
+        )
+    }
+}
+
 pub fn send_message(
     memory: &Memory,
     connection_id: u32,
@@ -122,31 +231,87 @@ pub fn send_message(
     opcode: u32,
     args: [u32; 4],
 ) -> SyscallResult {
-    // println!(
-    //     "SendMessage({}, {}, {}: {:x?})",
-    //     connection_id, kind, opcode, args
-    // );
-    let memory_region = if kind == 1 || kind == 2 || kind == 3 {
-        let mut memory_region = vec![0; args[1] as usize];
-        for (offset, value) in memory_region.iter_mut().enumerate() {
-            *value = memory.read_u8(
-                memory
-                    .virt_to_phys(args[0] + offset as u32)
-                    .expect("invalid memory address"),
+    let Some(kind) = MessageKind::from_u32(kind) else {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::InvalidSyscall,
+            format!("message of unknown kind {}", kind),
+        );
+    };
+    let mut memory_region = if kind.has_memory() {
+        if args[1] > MAX_MESSAGE_BYTES {
+            return error(SyscallErrorNumber::OutOfMemory);
+        }
+        // The whole buffer must be mapped, and writable if the server may change it
+        let writable = kind == MessageKind::MutableLend;
+        if !memory.is_accessible(args[0], args[1], writable) {
+            return refuse(
+                memory,
+                Abuse::UnmappedLend,
+                SyscallErrorNumber::BadAddress,
+                format!(
+                    "lent {} bytes at {:08x}, which aren't all mapped{}",
+                    args[1],
+                    args[0],
+                    if writable { " and writable" } else { "" }
+                ),
             );
         }
-        Some(memory_region)
+        let memory_region = memory.read_range(args[0], args[1]).unwrap();
+        Some(LendBuffer::new(memory_region))
     } else {
         None
     };
     // Pull the service out of the connections table so that we can send
-    // a mutable copy of the memory object to the service.
-    let connections = memory.connections.lock().unwrap();
-    let Some(service) = connections.get(&connection_id) else {
-        println!("Unhandled connection ID {}", connection_id);
-        return [
-            SyscallResultNumber::Error as i32,
-            SyscallErrorNumber::ServerNotFound as i32,
+    // a mutable copy of the memory object to the service. The table is
+    // unlocked before calling into the service so that it may add connections.
+    let (service, slot, description) = {
+        let connections = memory.connections.lock().unwrap();
+        (
+            connections.service(connection_id),
+            connections.queue_slot(connection_id, memory.server_queue_depth),
+            memory
+                .message_stats
+                .as_ref()
+                .and_then(|_| connections.name(connection_id)),
+        )
+    };
+    let Some(service) = service else {
+        return refuse(
+            memory,
+            Abuse::BadConnection,
+            SyscallErrorNumber::ServerNotFound,
+            format!(
+                "message to connection {}, which isn't connected",
+                connection_id
+            ),
+        );
+    };
+    let Some(slot) = slot else {
+        return error(SyscallErrorNumber::ServerQueueFull);
+    };
+
+    let message_memory = memory_region
+        .as_mut()
+        .map(|region| MessageMemory::new(region, args[2], args[3]));
+    let message = Message::new(memory.tid as u32, opcode, kind, args, message_memory);
+    let sent_us = memory.platform.elapsed_us();
+    let reply = service.message(memory, message);
+    if let (Some(stats), Some(service)) = (&memory.message_stats, description) {
+        match reply {
+            Reply::WaitForResponse(_) => stats.wait(memory.tid, service, opcode, sent_us),
+            _ => {
+                let latency_us = memory.platform.elapsed_us().saturating_sub(sent_us);
+                stats.record(service, opcode, latency_us);
+            }
+        }
+    }
+    match reply {
+        Reply::Ok => [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into(),
+        Reply::Scalar1(result) => [
+            SyscallResultNumber::Scalar1 as i32,
+            result as i32,
             0,
             0,
             0,
@@ -154,75 +319,39 @@ pub fn send_message(
             0,
             0,
         ]
-        .into();
-    };
-
-    match kind {
-        1..=3 => {
-            let mut memory_region = memory_region.unwrap();
-            let extra = [args[2], args[3]];
-            match kind {
-                1 => match service.lend_mut(memory, 0, opcode, &mut memory_region, extra) {
-                    services::LendResult::WaitForResponse(msg) => msg.into(),
-                    services::LendResult::MemoryReturned(result) => {
-                        for (offset, value) in memory_region.into_iter().enumerate() {
-                            memory.write_u8(
-                                memory.virt_to_phys(args[0] + offset as u32).unwrap(),
-                                value,
-                            );
-                        }
-                        [
-                            SyscallResultNumber::MemoryReturned as i32,
-                            result[0] as i32,
-                            result[1] as i32,
-                            0,
-                            0,
-                            0,
-                            0,
-                            0,
-                        ]
-                        .into()
-                    }
-                },
-                2 => match service.lend(memory, 0, opcode, &memory_region, extra) {
-                    services::LendResult::WaitForResponse(msg) => msg.into(),
-                    services::LendResult::MemoryReturned(result) => [
-                        SyscallResultNumber::MemoryReturned as i32,
-                        result[0] as i32,
-                        result[1] as i32,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                    ]
-                    .into(),
-                },
-                3 => {
-                    service.send(memory, 0, opcode, &memory_region, extra);
-                    [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into()
+        .into(),
+        Reply::Scalar2(result) => [
+            SyscallResultNumber::Scalar2 as i32,
+            result[0] as i32,
+            result[1] as i32,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]
+        .into(),
+        Reply::Scalar5(result) => [
+            SyscallResultNumber::Scalar5 as i32,
+            result[0] as i32,
+            result[1] as i32,
+            result[2] as i32,
+            result[3] as i32,
+            result[4] as i32,
+            0,
+            0,
+        ]
+        .into(),
+        Reply::MemoryReturned(result) => {
+            // Only a mutable lend may change the sender's memory, and then
+            // only where the service wrote to it
+            if kind == MessageKind::MutableLend {
+                for (at, data) in memory_region.as_ref().unwrap().writes() {
+                    memory.write_range(args[0] + at as u32, data).unwrap();
                 }
-                _ => unreachable!(),
             }
-        }
-        4 => {
-            service.scalar(memory, 0, opcode, args);
-            [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into()
-        }
-        5 => match service.blocking_scalar(memory, 0, opcode, args) {
-            services::ScalarResult::Scalar1(result) => [
-                SyscallResultNumber::Scalar1 as i32,
-                result as i32,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-            ]
-            .into(),
-            services::ScalarResult::Scalar2(result) => [
-                SyscallResultNumber::Scalar2 as i32,
+            [
+                SyscallResultNumber::MemoryReturned as i32,
                 result[0] as i32,
                 result[1] as i32,
                 0,
@@ -231,34 +360,31 @@ pub fn send_message(
                 0,
                 0,
             ]
-            .into(),
-            services::ScalarResult::Scalar5(result) => [
-                SyscallResultNumber::Scalar5 as i32,
-                result[0] as i32,
-                result[1] as i32,
-                result[2] as i32,
-                result[3] as i32,
-                result[4] as i32,
-                0,
-                0,
-            ]
-            .into(),
-            services::ScalarResult::WaitForResponse(msg) => msg.into(),
-        },
-        _ => {
-            panic!("Unknown message kind {}", kind);
-            // [
-            //     SyscallResultNumber::Error as i32,
-            //     9, /* ServerNotFound */
-            //     0,
-            //     0,
-            //     0,
-            //     0,
-            //     0,
-            //     0,
-            // ]
-            // .into()
+            .into()
+        }
+        Reply::WaitForResponse(msg) => {
+            // The message stays in the server's queue until the response arrives
+            memory.queue_slots.lock().unwrap().insert(memory.tid, slot);
+            services::wait_for(msg)
         }
+        Reply::UnknownOpcode => refuse(
+            memory,
+            Abuse::UnknownOpcode,
+            SyscallErrorNumber::UnhandledSyscall,
+            format!(
+                "{:?} message with opcode {} to connection {}, which doesn't handle it",
+                kind, opcode, connection_id
+            ),
+        ),
+        Reply::Refused(number, reason) => refuse(
+            memory,
+            Abuse::InvalidMessage,
+            number,
+            format!(
+                "{:?} message with opcode {} to connection {}: {}",
+                kind, opcode, connection_id, reason
+            ),
+        ),
     }
 }
 
@@ -273,18 +399,28 @@ pub fn try_send_message(
 }
 
 pub fn increase_heap(memory: &Memory, delta: i32, _flags: i32) -> SyscallResult {
-    assert!(delta & 0xfff == 0, "delta must be page-aligned");
+    if delta & 0xfff != 0 {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::BadAlignment,
+            format!(
+                "IncreaseHeap by {:#x} bytes, which isn't whole pages",
+                delta
+            ),
+        );
+    }
     let increase_bytes = delta as u32;
-    let heap_address =
-        memory.heap_start.load(Ordering::Relaxed) + memory.heap_size.load(Ordering::Relaxed);
+    let heap_address = memory.space.heap_start.load(Ordering::Relaxed)
+        + memory.space.heap_size.load(Ordering::Relaxed);
     if delta == 0 {
         return [
             SyscallResultNumber::MemoryRange as i32,
-            memory.heap_start.load(Ordering::Relaxed) as i32,
-            if memory.heap_size.load(Ordering::Relaxed) == 0 {
+            memory.space.heap_start.load(Ordering::Relaxed) as i32,
+            if memory.space.heap_size.load(Ordering::Relaxed) == 0 {
                 4096
             } else {
-                memory.heap_size.load(Ordering::Relaxed)
+                memory.space.heap_size.load(Ordering::Relaxed)
             } as i32,
             0,
             0,
@@ -294,7 +430,7 @@ pub fn increase_heap(memory: &Memory, delta: i32, _flags: i32) -> SyscallResult
         ]
         .into();
     }
-    if heap_address.saturating_add(increase_bytes) > super::HEAP_END {
+    if heap_address.saturating_add(increase_bytes) > memory.space.heap_end.load(Ordering::Relaxed) {
         [
             SyscallResultNumber::Error as i32,
             SyscallErrorNumber::OutOfMemory as i32,
@@ -307,12 +443,19 @@ pub fn increase_heap(memory: &Memory, delta: i32, _flags: i32) -> SyscallResult
         ]
         .into()
     } else {
-        for new_address in (heap_address..(heap_address + increase_bytes)).step_by(4096) {
-            memory.ensure_page(new_address);
+        let mapped = if memory.demand_paging {
+            memory.reserve_region(heap_address, increase_bytes)
+        } else {
+            memory.map_region(heap_address, increase_bytes)
+        };
+        if mapped.is_none() {
+            memory.unmap_partial_region(heap_address, increase_bytes);
+            return error(SyscallErrorNumber::OutOfMemory);
         }
-        let new_heap_region =
-            memory.heap_start.load(Ordering::Relaxed) + memory.heap_size.load(Ordering::Relaxed);
+        let new_heap_region = memory.space.heap_start.load(Ordering::Relaxed)
+            + memory.space.heap_size.load(Ordering::Relaxed);
         memory
+            .space
             .heap_size
             .fetch_add(increase_bytes, Ordering::Relaxed);
         [
@@ -329,6 +472,210 @@ pub fn increase_heap(memory: &Memory, delta: i32, _flags: i32) -> SyscallResult
     }
 }
 
+/// Stop running the child process `pid` and go back to its parent. Only
+/// the emulated process and its parent, the kernel, exist, and neither is
+/// the emulated process's child.
+pub fn return_to_parent(pid: i32) -> SyscallResult {
+    if pid == PROCESS_ID || pid == KERNEL_PROCESS_ID {
+        error(SyscallErrorNumber::ProcessNotChild)
+    } else {
+        error(SyscallErrorNumber::ProcessNotFound)
+    }
+}
+
+/// The physical address that `address` maps to in process `pid`, which has
+/// to be the emulated process.
+pub fn virt_to_phys(memory: &Memory, pid: i32, address: i32) -> SyscallResult {
+    if pid != PROCESS_ID {
+        return error(SyscallErrorNumber::ProcessNotFound);
+    }
+    match memory.virt_to_phys(address as u32) {
+        Some(phys) => [
+            SyscallResultNumber::Scalar1 as i32,
+            phys as i32,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]
+        .into(),
+        None => error(SyscallErrorNumber::BadAddress),
+    }
+}
+
+/// Move the region of the process's address space that `kind` names to
+/// `size` bytes at `address`. The heap can only move before it has grown,
+/// and the stack is only noted, since the main thread's is already mapped.
+pub fn set_mem_region(
+    memory: &Memory,
+    pid: i32,
+    kind: i32,
+    address: i32,
+    size: i32,
+) -> SyscallResult {
+    let (address, size) = (address as u32, size as u32);
+    if pid != PROCESS_ID {
+        return error(SyscallErrorNumber::ProcessNotFound);
+    }
+    let Ok(kind) = MemoryType::try_from(kind) else {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::InvalidSyscall,
+            format!("SetMemRegion of unknown memory type {}", kind),
+        );
+    };
+    if address & 0xfff != 0 || size & 0xfff != 0 {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::BadAlignment,
+            format!(
+                "SetMemRegion of {:#x} bytes at {:08x}, which isn't whole pages",
+                size, address
+            ),
+        );
+    }
+    let end = match address.checked_add(size) {
+        Some(end) if address != 0 && end <= USER_AREA_END => end,
+        _ => {
+            return refuse(
+                memory,
+                Abuse::InvalidArgument,
+                SyscallErrorNumber::BadAddress,
+                format!(
+                    "SetMemRegion of {:#x} bytes at {:08x}, which isn't all user memory",
+                    size, address
+                ),
+            )
+        }
+    };
+    let space = &memory.space;
+    match kind {
+        MemoryType::Default => {
+            let mut allocation = space.allocation.lock().unwrap();
+            *allocation = address..end;
+            space.allocation_previous.store(address, Ordering::Relaxed);
+        }
+        MemoryType::Messages => *space.messages.lock().unwrap() = address..end,
+        MemoryType::Stack => *space.stack.lock().unwrap() = address..end,
+        MemoryType::Heap => {
+            if space.heap_size.load(Ordering::Relaxed) != 0 {
+                return error(SyscallErrorNumber::MemoryInUse);
+            }
+            space.heap_start.store(address, Ordering::Relaxed);
+            space.heap_end.store(end, Ordering::Relaxed);
+        }
+    }
+    [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into()
+}
+
+/// Unmap the pages from `address` for `size` bytes and free their memory.
+/// Pages in the range that aren't mapped are skipped, and make the call fail
+/// with `DoubleFree` once the rest have been freed.
+pub fn unmap_memory(memory: &Memory, address: i32, size: i32) -> SyscallResult {
+    let (address, size) = (address as u32, size as u32);
+    if address & 0xfff != 0 || size & 0xfff != 0 {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::BadAlignment,
+            format!(
+                "UnmapMemory of {:#x} bytes at {:08x}, which isn't whole pages",
+                size, address
+            ),
+        );
+    }
+    let end = match address.checked_add(size) {
+        Some(end) if size != 0 && end <= USER_AREA_END => end,
+        _ => {
+            return refuse(
+                memory,
+                Abuse::InvalidArgument,
+                SyscallErrorNumber::BadAddress,
+                format!(
+                    "UnmapMemory of {:#x} bytes at {:08x}, which isn't user memory",
+                    size, address
+                ),
+            )
+        }
+    };
+    match memory.unmap_region(address, end) {
+        Ok(()) => [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into(),
+        Err(number) => error(number),
+    }
+}
+
+/// Restrict the pages from `address` for `range` bytes to the permissions
+/// in `flags`. Permissions can only be taken away, unless pages are kept
+/// writable or executable but not both, when they can be swapped as well.
+/// Pages that aren't mapped are skipped.
+pub fn update_memory_flags(memory: &Memory, address: i32, range: i32, flags: i32) -> SyscallResult {
+    let (address, range, flags) = (address as u32, range as u32, flags as u32);
+    if address & 0xfff != 0 || range & 0xfff != 0 {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::BadAlignment,
+            format!(
+                "UpdateMemoryFlags of {:#x} bytes at {:08x}, which isn't whole pages",
+                range, address
+            ),
+        );
+    }
+    if flags & !(MMUFLAG_READABLE | MMUFLAG_WRITABLE | MMUFLAG_EXECUTABLE) != 0 {
+        return refuse(
+            memory,
+            Abuse::InvalidArgument,
+            SyscallErrorNumber::InvalidSyscall,
+            format!("UpdateMemoryFlags with unknown flags {:#x}", flags),
+        );
+    }
+    let end = match address.checked_add(range) {
+        Some(end) if end <= USER_AREA_END => end,
+        _ => {
+            return refuse(
+                memory,
+                Abuse::InvalidArgument,
+                SyscallErrorNumber::BadAddress,
+                format!(
+                    "UpdateMemoryFlags of {:#x} bytes at {:08x}, which isn't all in the user area",
+                    range, address
+                ),
+            )
+        }
+    };
+    let both = MMUFLAG_WRITABLE | MMUFLAG_EXECUTABLE;
+    if memory.w_xor_x.is_some() && flags & both == both {
+        return error(SyscallErrorNumber::AccessDenied);
+    }
+    // Flags can only be taken away, except that when pages are writable or
+    // executable but never both, a page may swap one for the other. Check
+    // every page before changing any, so that a failure changes nothing.
+    let pages = (address..end).step_by(4096);
+    let allowed = |old: u32| match memory.w_xor_x {
+        Some(_) if old & both != 0 => old | both,
+        _ => old,
+    };
+    let adds_flags = pages.clone().any(|page| {
+        memory
+            .page_flags(page)
+            .is_some_and(|old| allowed(old) & flags != flags)
+    });
+    if adds_flags {
+        return error(SyscallErrorNumber::AccessDenied);
+    }
+    for page in pages {
+        match memory.w_xor_x {
+            Some(_) => memory.set_memory_flags(page, flags),
+            None => memory.remove_memory_flags(page, flags),
+        }
+    }
+    [SyscallResultNumber::Ok as i32, 0, 0, 0, 0, 0, 0, 0].into()
+}
+
 pub fn create_thread(
     memory: &Memory,
     entry_point: i32,
@@ -336,10 +683,34 @@ pub fn create_thread(
     stack_length: i32,
     arguments: [i32; 4],
 ) -> SyscallResult {
+    if let Some(limit) = memory.thread_limit {
+        let claimed =
+            memory
+                .live_threads
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+                    (live < limit).then_some(live + 1)
+                });
+        if claimed.is_err() {
+            log::warn!("Refusing to create a thread past the limit of {}", limit);
+            return error(SyscallErrorNumber::ThreadNotAvailable);
+        }
+    } else {
+        memory.live_threads.fetch_add(1, Ordering::Relaxed);
+    }
+    let thread_id = memory.thread_id_counter.fetch_add(1, Ordering::SeqCst);
+    memory.schedule(
+        thread_id,
+        super::trace::SchedulerEvent::Created {
+            parent: memory.tid,
+            entry: entry_point as u32,
+        },
+    );
     let (tx, rx) = channel();
+    memory.thread_handles.lock().unwrap().insert(thread_id, rx);
     memory
         .memory_cmd
         .send(super::MemoryCommand::CreateThread(
+            thread_id,
             entry_point as _,
             stack_pointer as _,
             stack_length as _,
@@ -350,12 +721,6 @@ pub fn create_thread(
             tx,
         ))
         .unwrap();
-    let (thread_id, join_handle) = rx.recv().unwrap();
-    memory
-        .thread_handles
-        .lock()
-        .unwrap()
-        .insert(thread_id, join_handle);
     [
         SyscallResultNumber::ThreadId as i32,
         thread_id,
@@ -369,6 +734,6 @@ pub fn create_thread(
     .into()
 }
 
-pub fn terminate_process(_memory: &Memory, exit_code: i32) -> ! {
-    std::process::exit(exit_code)
+pub fn terminate_process(_memory: &Memory, exit_code: i32) -> SyscallResult {
+    SyscallResult::Terminate(exit_code as u32)
 }
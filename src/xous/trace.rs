@@ -0,0 +1,935 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use riscv_cpu::cpu::{Cpu, CpuState, PrivilegeMode, Register};
+
+/// Returned from the hypercall CSR once a request has been handled.
+pub const HYPERCALL_OK: u32 = 0;
+
+/// Returned from the hypercall CSR when the request wasn't understood.
+pub const HYPERCALL_UNKNOWN: u32 = !0;
+
+/// A request written by the guest to `CSR_HYPERCALL_ADDRESS`. The low eight
+/// bits of the value select the action and the upper 24 bits are its argument.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hypercall {
+    /// Add a marker with the given ID to the trace.
+    Marker(u32),
+
+    /// Record the contents of every register.
+    DumpRegisters,
+
+    /// Ask the host to take a snapshot of the machine at this point.
+    Snapshot,
+
+    /// Start recording the address of every instruction that runs.
+    CoverageStart,
+
+    /// Stop recording instruction addresses.
+    CoverageStop,
+}
+
+impl Hypercall {
+    pub fn decode(value: u32) -> Option<Self> {
+        let argument = value >> 8;
+        match value & 0xff {
+            0 => Some(Hypercall::Marker(argument)),
+            1 => Some(Hypercall::DumpRegisters),
+            2 => Some(Hypercall::Snapshot),
+            3 => Some(Hypercall::CoverageStart),
+            4 => Some(Hypercall::CoverageStop),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happened in response to a hypercall, or a fault that
+/// stopped a thread.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    Marker(u32),
+    Registers([i32; 32]),
+    SnapshotRequested,
+    CoverageStarted,
+    CoverageStopped,
+
+    /// The emulator panicked while running the thread, which stopped it,
+    /// with this message.
+    Fault(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub elapsed_ms: u64,
+    pub tid: i32,
+    pub pc: u32,
+    pub event: TraceEvent,
+}
+
+/// What a thread was waiting on when it stopped running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockReason {
+    /// A server hasn't responded to a message yet.
+    Message { connection: u32, opcode: u32 },
+
+    /// Another thread, given by its ID, hasn't exited yet.
+    Join(i32),
+
+    /// Any other syscall that doesn't return right away, given by its number,
+    /// such as one delayed by fault injection.
+    Syscall(u32),
+}
+
+/// A change in whether a thread is running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchedulerEvent {
+    /// The thread was created by `parent` to start running at `entry`.
+    Created {
+        parent: i32,
+        entry: u32,
+    },
+    Blocked(BlockReason),
+
+    /// Whatever the thread was blocked on is done, so it runs again.
+    Unblocked,
+
+    /// The thread gave up the rest of its turn with `Yield`.
+    Yielded,
+    Exited(u32),
+}
+
+impl std::fmt::Display for SchedulerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchedulerEvent::Created { parent, entry } => {
+                write!(f, "created by {} at {:08x}", parent, entry)
+            }
+            SchedulerEvent::Blocked(reason) => write!(f, "blocked on {}", reason),
+            SchedulerEvent::Unblocked => write!(f, "unblocked"),
+            SchedulerEvent::Yielded => write!(f, "yielded"),
+            SchedulerEvent::Exited(code) => write!(f, "exited with {}", code),
+        }
+    }
+}
+
+impl std::fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockReason::Message { connection, opcode } => {
+                write!(f, "message {} to connection {}", opcode, connection)
+            }
+            BlockReason::Join(tid) => write!(f, "join of thread {}", tid),
+            BlockReason::Syscall(number) => write!(f, "syscall {}", number),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleRecord {
+    pub elapsed_us: u64,
+    pub tid: i32,
+    pub event: SchedulerEvent,
+}
+
+/// CSRs that can be named in `parse_csr` rather than given by number.
+const CSR_NAMES: &[(&str, u16)] = &[
+    ("sstatus", 0x100),
+    ("sie", 0x104),
+    ("stvec", 0x105),
+    ("sscratch", 0x140),
+    ("sepc", 0x141),
+    ("scause", 0x142),
+    ("stval", 0x143),
+    ("sip", 0x144),
+    ("satp", 0x180),
+    ("mstatus", 0x300),
+    ("medeleg", 0x302),
+    ("mideleg", 0x303),
+    ("mie", 0x304),
+    ("mtvec", 0x305),
+    ("mscratch", 0x340),
+    ("mepc", 0x341),
+    ("mcause", 0x342),
+    ("mtval", 0x343),
+    ("mip", 0x344),
+];
+
+/// Parse a CSR given by name, such as `satp`, or by number, such as `0x180`.
+pub fn parse_csr(csr: &str) -> Result<u16, String> {
+    if let Some((_, address)) = CSR_NAMES.iter().find(|(name, _)| *name == csr) {
+        return Ok(*address);
+    }
+    let address = match csr.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => csr.parse(),
+    };
+    match address {
+        Ok(address) if address < 0x1000 => Ok(address),
+        _ => Err(format!("unknown CSR {:?}", csr)),
+    }
+}
+
+/// The name `parse_csr` understands for `address`, or its number in hex.
+fn csr_name(address: u16) -> String {
+    match CSR_NAMES.iter().find(|(_, known)| *known == address) {
+        Some((name, _)) => (*name).to_owned(),
+        None => format!("csr_{:03x}", address),
+    }
+}
+
+/// One instruction that retired while execution was being recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionStep {
+    /// Counts instructions recorded across all threads, starting from zero.
+    /// This is used as the time in waveform and trace exports.
+    pub sequence: u64,
+    pub tid: i32,
+    pub pc: u32,
+
+    /// The privilege level after the instruction, encoded as in `mstatus.MPP`.
+    pub privilege: u8,
+
+    /// The integer registers that changed since the thread's previous
+    /// step, by number, in order, each as `(number, before, after)`, as in
+    /// a `StateDelta`. The first step recorded for a thread counts every
+    /// register as changing from zero.
+    pub registers: Vec<(u8, i32, i32)>,
+
+    /// The values of the recorded CSRs after the instruction, in the order
+    /// they were passed to `Tracer::with_execution`.
+    pub csrs: Vec<u32>,
+}
+
+#[derive(Default)]
+struct ExecutionLog {
+    sequence: u64,
+    steps: VecDeque<ExecutionStep>,
+
+    /// Each thread's registers before its oldest step still in `steps`.
+    base: BTreeMap<i32, [i32; 32]>,
+
+    /// Each thread's registers after its newest step.
+    last: BTreeMap<i32, [i32; 32]>,
+
+    /// Markers from hypercalls, as `(sequence, tid, id)`.
+    markers: VecDeque<(u64, i32, u32)>,
+}
+
+/// The settings and most recent steps of an execution recording.
+struct Execution {
+    limit: usize,
+    csrs: Vec<u16>,
+    log: Mutex<ExecutionLog>,
+}
+
+/// Collects the events that guests request through the hypercall CSR, and
+/// optionally a record of every instruction that runs.
+#[derive(Default)]
+pub struct Tracer {
+    records: Mutex<Vec<TraceRecord>>,
+    schedule: Mutex<Vec<ScheduleRecord>>,
+    covering: AtomicBool,
+    coverage: Mutex<BTreeSet<u32>>,
+    execution: Option<Execution>,
+
+    /// Whether the execution recording has been turned off for a while,
+    /// such as by a breakpoint script.
+    paused: AtomicBool,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also record the PC, privilege level, and the CSRs at `csrs` after
+    /// every instruction, keeping the most recent `limit` of them.
+    pub fn with_execution(limit: usize, csrs: Vec<u16>) -> Self {
+        Tracer {
+            execution: Some(Execution {
+                limit,
+                csrs,
+                log: Mutex::default(),
+            }),
+            ..Self::default()
+        }
+    }
+
+    /// Act on a hypercall from thread `tid` at `pc`, returning the value the
+    /// guest reads back from the CSR.
+    pub(super) fn hypercall(
+        &self,
+        value: u32,
+        elapsed_ms: u64,
+        tid: i32,
+        pc: u32,
+        registers: &[i32; 32],
+    ) -> u32 {
+        let event = match Hypercall::decode(value) {
+            Some(Hypercall::Marker(id)) => TraceEvent::Marker(id),
+            Some(Hypercall::DumpRegisters) => TraceEvent::Registers(*registers),
+            Some(Hypercall::Snapshot) => TraceEvent::SnapshotRequested,
+            Some(Hypercall::CoverageStart) => {
+                self.covering.store(true, Ordering::Relaxed);
+                TraceEvent::CoverageStarted
+            }
+            Some(Hypercall::CoverageStop) => {
+                self.covering.store(false, Ordering::Relaxed);
+                TraceEvent::CoverageStopped
+            }
+            None => return HYPERCALL_UNKNOWN,
+        };
+        if let (TraceEvent::Marker(id), Some(execution)) = (&event, &self.execution) {
+            let mut log = execution.log.lock().unwrap();
+            let sequence = log.sequence;
+            log.markers.push_back((sequence, tid, *id));
+        }
+        self.records.lock().unwrap().push(TraceRecord {
+            elapsed_ms,
+            tid,
+            pc,
+            event,
+        });
+        HYPERCALL_OK
+    }
+
+    /// Record that the emulator panicked with `message` while running thread
+    /// `tid` at `pc`.
+    pub(super) fn record_fault(&self, elapsed_ms: u64, tid: i32, pc: u32, message: String) {
+        self.records.lock().unwrap().push(TraceRecord {
+            elapsed_ms,
+            tid,
+            pc,
+            event: TraceEvent::Fault(message),
+        });
+    }
+
+    /// Record that thread `tid` started or stopped running.
+    pub(super) fn record_schedule(&self, elapsed_us: u64, tid: i32, event: SchedulerEvent) {
+        self.schedule.lock().unwrap().push(ScheduleRecord {
+            elapsed_us,
+            tid,
+            event,
+        });
+    }
+
+    /// Whether instruction addresses are currently being recorded.
+    pub(super) fn covering(&self) -> bool {
+        self.covering.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn cover(&self, pc: u32) {
+        self.coverage.lock().unwrap().insert(pc);
+    }
+
+    /// Whether every instruction is being recorded.
+    pub(super) fn recording(&self) -> bool {
+        self.execution.is_some() && !self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Start or stop recording every instruction, if it was enabled with
+    /// `with_execution`.
+    pub(super) fn set_recording(&self, recording: bool) {
+        self.paused.store(!recording, Ordering::Relaxed);
+    }
+
+    /// Record the instruction at `pc` that thread `tid` just retired on `cpu`.
+    pub(super) fn record(&self, tid: i32, pc: u32, cpu: &Cpu) {
+        let Some(execution) = &self.execution else {
+            return;
+        };
+        let privilege = match cpu.privilege_mode() {
+            PrivilegeMode::User => 0,
+            PrivilegeMode::Supervisor => 1,
+            PrivilegeMode::Reserved => 2,
+            PrivilegeMode::Machine => 3,
+        };
+        let csrs = execution
+            .csrs
+            .iter()
+            .map(|&csr| cpu.peek_csr(csr))
+            .collect();
+
+        let mut log = execution.log.lock().unwrap();
+        let sequence = log.sequence;
+        log.sequence += 1;
+        if log.steps.len() >= execution.limit {
+            if let Some(oldest) = log.steps.pop_front() {
+                let base = log.base.entry(oldest.tid).or_insert([0; 32]);
+                for (index, _, after) in oldest.registers {
+                    base[index as usize] = after;
+                }
+            }
+        }
+        let after = cpu.registers();
+        let before = log.last.insert(tid, *after).unwrap_or([0; 32]);
+        let registers = (0..32)
+            .filter(|&index| before[index] != after[index])
+            .map(|index| (index as u8, before[index], after[index]))
+            .collect();
+        log.steps.push_back(ExecutionStep {
+            sequence,
+            tid,
+            pc,
+            privilege,
+            registers,
+            csrs,
+        });
+        // Markers from before the oldest step have nothing to line up with
+        let oldest = log.steps.front().map_or(sequence, |step| step.sequence);
+        while log.markers.front().is_some_and(|marker| marker.0 < oldest) {
+            log.markers.pop_front();
+        }
+    }
+
+    /// The most recently recorded instructions, oldest first.
+    pub fn execution(&self) -> Vec<ExecutionStep> {
+        self.execution.as_ref().map_or_else(Vec::new, |execution| {
+            execution
+                .log
+                .lock()
+                .unwrap()
+                .steps
+                .iter()
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Every event recorded so far, in the order they happened.
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// When each thread started and stopped running, ordered by time.
+    pub fn schedule(&self) -> Vec<ScheduleRecord> {
+        let mut schedule = self.schedule.lock().unwrap().clone();
+        // Threads record their own events, so they can arrive slightly out of order
+        schedule.sort_by_key(|record| record.elapsed_us);
+        schedule
+    }
+
+    /// The addresses of instructions that ran while coverage was enabled.
+    pub fn coverage(&self) -> Vec<u32> {
+        self.coverage.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Write one line per event, followed by one line per covered address.
+    pub fn write(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let mut schedule = self.schedule().into_iter().peekable();
+        for record in self.records.lock().unwrap().iter() {
+            while let Some(scheduled) =
+                schedule.next_if(|scheduled| scheduled.elapsed_us / 1000 <= record.elapsed_ms)
+            {
+                write_schedule_record(output, &scheduled)?;
+            }
+            write!(
+                output,
+                "{}.{:03} {} {:08x} ",
+                record.elapsed_ms / 1000,
+                record.elapsed_ms % 1000,
+                record.tid,
+                record.pc
+            )?;
+            match &record.event {
+                TraceEvent::Marker(id) => writeln!(output, "marker {}", id)?,
+                TraceEvent::Registers(registers) => {
+                    write!(output, "registers")?;
+                    for (index, value) in registers.iter().enumerate().skip(1) {
+                        let name = Register::from_field(index as u32).name();
+                        write!(output, " {}={:08x}", name, value)?;
+                    }
+                    writeln!(output)?;
+                }
+                TraceEvent::SnapshotRequested => writeln!(output, "snapshot")?,
+                TraceEvent::CoverageStarted => writeln!(output, "coverage-start")?,
+                TraceEvent::CoverageStopped => writeln!(output, "coverage-stop")?,
+                TraceEvent::Fault(message) => writeln!(output, "fault {}", message)?,
+            }
+        }
+        for scheduled in schedule {
+            write_schedule_record(output, &scheduled)?;
+        }
+        for pc in self.coverage.lock().unwrap().iter() {
+            writeln!(output, "covered {:08x}", pc)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_schedule_record(output: &mut impl Write, record: &ScheduleRecord) -> std::io::Result<()> {
+    let elapsed_ms = record.elapsed_us / 1000;
+    writeln!(
+        output,
+        "{}.{:03} {} -------- {}",
+        elapsed_ms / 1000,
+        elapsed_ms % 1000,
+        record.tid,
+        record.event
+    )
+}
+
+/// The start of the CTF metadata, up to the CSR fields of instruction events.
+const CTF_METADATA_HEAD: &str = r#"/* CTF 1.8 */
+
+typealias integer { size = 8; align = 8; signed = false; } := uint8_t;
+typealias integer { size = 32; align = 8; signed = false; } := uint32_t;
+typealias integer { size = 32; align = 8; signed = true; } := int32_t;
+
+trace {
+    major = 1;
+    minor = 8;
+    byte_order = le;
+    packet.header := struct {
+        uint32_t magic;
+        uint32_t stream_id;
+    };
+};
+
+env {
+    domain = "yove";
+};
+
+clock {
+    name = instructions;
+    description = "Instructions retired by all threads";
+    freq = 1000000000;
+};
+
+typealias integer {
+    size = 64; align = 8; signed = false;
+    map = clock.instructions.value;
+} := instructions_t;
+
+stream {
+    id = 0;
+    event.header := struct {
+        uint32_t id;
+        instructions_t timestamp;
+    };
+};
+
+event {
+    name = "instruction";
+    id = 0;
+    stream_id = 0;
+    fields := struct {
+        int32_t tid;
+        uint32_t pc;
+        uint8_t privilege;
+"#;
+
+/// The rest of the CTF metadata, after the CSR fields.
+const CTF_METADATA_TAIL: &str = r#"    };
+};
+
+event {
+    name = "marker";
+    id = 1;
+    stream_id = 0;
+    fields := struct {
+        int32_t tid;
+        uint32_t id;
+    };
+};
+"#;
+
+/// An entry in a recorded execution, in the order exporters write them.
+enum Timeline<'a> {
+    Step(&'a ExecutionStep),
+
+    /// A hypercall marker as `(sequence, tid, id)`.
+    Marker(u64, i32, u32),
+}
+
+impl ExecutionLog {
+    /// The steps and markers, with each marker placed before the first
+    /// instruction that ran after it.
+    fn timeline(&self) -> Vec<Timeline<'_>> {
+        let mut timeline = Vec::with_capacity(self.steps.len() + self.markers.len());
+        let mut markers = self.markers.iter().peekable();
+        for step in &self.steps {
+            while let Some(&(sequence, tid, id)) = markers.next_if(|m| m.0 <= step.sequence) {
+                timeline.push(Timeline::Marker(sequence, tid, id));
+            }
+            timeline.push(Timeline::Step(step));
+        }
+        timeline.extend(markers.map(|&(sequence, tid, id)| Timeline::Marker(sequence, tid, id)));
+        timeline
+    }
+}
+
+impl ExecutionStep {
+    /// The recorded part of the CPU's state after this step, given the
+    /// thread's registers before it, with the CSRs that weren't recorded
+    /// reading as zero.
+    fn state(&self, mut registers: [i32; 32], csrs: &[u16]) -> CpuState {
+        for &(index, _, after) in &self.registers {
+            registers[index as usize] = after;
+        }
+        CpuState {
+            pc: self.pc,
+            privilege_mode: match self.privilege {
+                0 => PrivilegeMode::User,
+                1 => PrivilegeMode::Supervisor,
+                2 => PrivilegeMode::Reserved,
+                _ => PrivilegeMode::Machine,
+            },
+            registers,
+            csrs: csrs
+                .iter()
+                .copied()
+                .zip(self.csrs.iter().copied())
+                .collect(),
+        }
+    }
+}
+
+/// Short identifiers for VCD signals, built from the printable ASCII characters.
+fn vcd_identifier(mut index: usize) -> String {
+    let mut identifier = String::new();
+    loop {
+        identifier.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return identifier;
+        }
+        index -= 1;
+    }
+}
+
+impl Tracer {
+    /// Write the recorded instructions as a Value Change Dump, with one scope
+    /// per thread holding its PC, privilege level, and recorded CSRs, and a
+    /// `marker` signal for hypercall markers. Each instruction takes one
+    /// nanosecond of simulated time.
+    pub fn write_vcd(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let Some(execution) = &self.execution else {
+            return Ok(());
+        };
+        let log = execution.log.lock().unwrap();
+
+        // Signal 0 is the marker, then each thread has its PC, privilege, and CSRs
+        let signals_per_thread = 2 + execution.csrs.len();
+        let threads: BTreeMap<i32, usize> = log
+            .steps
+            .iter()
+            .map(|step| step.tid)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .enumerate()
+            .map(|(index, tid)| (tid, 1 + index * signals_per_thread))
+            .collect();
+
+        writeln!(output, "$version yove $end")?;
+        writeln!(output, "$timescale 1ns $end")?;
+        writeln!(output, "$scope module yove $end")?;
+        writeln!(output, "$var wire 32 {} marker $end", vcd_identifier(0))?;
+        for (tid, first) in &threads {
+            writeln!(output, "$scope module thread_{} $end", tid)?;
+            writeln!(output, "$var wire 32 {} pc $end", vcd_identifier(*first))?;
+            writeln!(
+                output,
+                "$var wire 2 {} privilege $end",
+                vcd_identifier(first + 1)
+            )?;
+            for (index, csr) in execution.csrs.iter().enumerate() {
+                writeln!(
+                    output,
+                    "$var wire 32 {} {} $end",
+                    vcd_identifier(first + 2 + index),
+                    csr_name(*csr)
+                )?;
+            }
+            writeln!(output, "$upscope $end")?;
+        }
+        writeln!(output, "$upscope $end")?;
+        writeln!(output, "$enddefinitions $end")?;
+
+        // Only write values that changed since they were last written
+        let mut values = vec![None; 1 + threads.len() * signals_per_thread];
+        let mut time = None;
+        for entry in log.timeline() {
+            let sequence = match entry {
+                Timeline::Step(step) => step.sequence,
+                Timeline::Marker(sequence, _, _) => sequence,
+            };
+            if time != Some(sequence) {
+                writeln!(output, "#{}", sequence)?;
+                time = Some(sequence);
+            }
+            let changes: Vec<(usize, usize, u32)> = match entry {
+                Timeline::Marker(_, _, id) => vec![(0, 32, id)],
+                Timeline::Step(step) => {
+                    let first = threads[&step.tid];
+                    [(first, 32, step.pc), (first + 1, 2, step.privilege as u32)]
+                        .into_iter()
+                        .chain(
+                            step.csrs
+                                .iter()
+                                .enumerate()
+                                .map(|(index, value)| (first + 2 + index, 32, *value)),
+                        )
+                        .collect()
+                }
+            };
+            for (signal, width, value) in changes {
+                if values[signal] == Some(value) {
+                    continue;
+                }
+                values[signal] = Some(value);
+                writeln!(
+                    output,
+                    "b{:0width$b} {}",
+                    value,
+                    vcd_identifier(signal),
+                    width = width
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the recorded instructions as text, one line each with its
+    /// sequence number, thread, and PC, then the registers, recorded CSRs,
+    /// and privilege level it changed, with registers by ABI name. The first
+    /// instruction recorded for a thread shows every register and CSR that
+    /// isn't zero. Markers get lines of their own.
+    pub fn write_execution(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let Some(execution) = &self.execution else {
+            return Ok(());
+        };
+        let log = execution.log.lock().unwrap();
+        let mut previous: BTreeMap<i32, CpuState> = BTreeMap::new();
+        for entry in log.timeline() {
+            let step = match entry {
+                Timeline::Marker(sequence, tid, id) => {
+                    writeln!(output, "{} {} marker {}", sequence, tid, id)?;
+                    continue;
+                }
+                Timeline::Step(step) => step,
+            };
+            let before = match previous.get(&step.tid) {
+                Some(previous) => previous.registers,
+                None => log.base.get(&step.tid).copied().unwrap_or([0; 32]),
+            };
+            let state = step.state(before, &execution.csrs);
+            let delta = match previous.get(&step.tid) {
+                Some(previous) => state.delta(previous),
+                None => state.delta(&CpuState {
+                    registers: [0; 32],
+                    csrs: BTreeMap::new(),
+                    ..state.clone()
+                }),
+            };
+            write!(output, "{} {} {:08x}", step.sequence, step.tid, step.pc)?;
+            if let Some((before, after)) = delta.privilege_mode {
+                write!(output, " privilege {:?} -> {:?}", before, after)?;
+            }
+            for (index, before, after) in delta.registers {
+                let name = Register::from_field(index as u32).name();
+                write!(output, " {} {:08x} -> {:08x}", name, before, after)?;
+            }
+            for (csr, before, after) in delta.csrs {
+                write!(output, " {} {:08x} -> {:08x}", csr_name(csr), before, after)?;
+            }
+            writeln!(output)?;
+            previous.insert(step.tid, state);
+        }
+        Ok(())
+    }
+
+    /// Write the recorded instructions as a Common Trace Format trace that
+    /// Trace Compass and babeltrace can open. `directory` is created if needed
+    /// and gets a `metadata` file and a single stream, with timestamps
+    /// counting instructions.
+    pub fn write_ctf(&self, directory: &Path) -> std::io::Result<()> {
+        let Some(execution) = &self.execution else {
+            return Ok(());
+        };
+        let log = execution.log.lock().unwrap();
+        std::fs::create_dir_all(directory)?;
+
+        let mut metadata =
+            std::io::BufWriter::new(std::fs::File::create(directory.join("metadata"))?);
+        metadata.write_all(CTF_METADATA_HEAD.as_bytes())?;
+        for csr in &execution.csrs {
+            writeln!(metadata, "        uint32_t {};", csr_name(*csr))?;
+        }
+        metadata.write_all(CTF_METADATA_TAIL.as_bytes())?;
+        metadata.flush()?;
+
+        let mut stream =
+            std::io::BufWriter::new(std::fs::File::create(directory.join("stream_0"))?);
+        stream.write_all(&0xc1fc_1fc1u32.to_le_bytes())?;
+        stream.write_all(&0u32.to_le_bytes())?;
+        for entry in log.timeline() {
+            match entry {
+                Timeline::Step(step) => {
+                    stream.write_all(&0u32.to_le_bytes())?;
+                    stream.write_all(&step.sequence.to_le_bytes())?;
+                    stream.write_all(&step.tid.to_le_bytes())?;
+                    stream.write_all(&step.pc.to_le_bytes())?;
+                    stream.write_all(&[step.privilege])?;
+                    for value in &step.csrs {
+                        stream.write_all(&value.to_le_bytes())?;
+                    }
+                }
+                Timeline::Marker(sequence, tid, id) => {
+                    stream.write_all(&1u32.to_le_bytes())?;
+                    stream.write_all(&sequence.to_le_bytes())?;
+                    stream.write_all(&tid.to_le_bytes())?;
+                    stream.write_all(&id.to_le_bytes())?;
+                }
+            }
+        }
+        stream.flush()
+    }
+}
+
+/// `text` as a JSON string, quoted and escaped.
+pub(super) fn json_string(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// What a thread is doing between two scheduler events, as a Chrome trace slice.
+fn slice_name(event: &SchedulerEvent) -> Option<String> {
+    match event {
+        SchedulerEvent::Created { .. } | SchedulerEvent::Unblocked => Some("running".to_owned()),
+        SchedulerEvent::Blocked(reason) => Some(format!("blocked on {}", reason)),
+        SchedulerEvent::Yielded | SchedulerEvent::Exited(_) => None,
+    }
+}
+
+impl Tracer {
+    /// Write the scheduler events and markers in the Chrome trace event
+    /// format, which Perfetto and `chrome://tracing` can open. Each thread
+    /// gets a track of slices showing when it was running and what it was
+    /// blocked on, with instant events for yields, thread creation, and
+    /// hypercall markers.
+    pub fn write_chrome_trace(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let schedule = self.schedule();
+        let records = self.records();
+        let end = schedule
+            .iter()
+            .map(|record| record.elapsed_us)
+            .chain(records.iter().map(|record| record.elapsed_ms * 1000))
+            .max()
+            .unwrap_or(0);
+
+        let mut events = vec![];
+        let threads: BTreeSet<i32> = schedule
+            .iter()
+            .map(|record| record.tid)
+            .chain(records.iter().map(|record| record.tid))
+            .collect();
+        // Threads that were running when they recorded their first event,
+        // like the main thread, are shown as running from the start
+        let mut open: BTreeMap<i32, String> = threads
+            .iter()
+            .filter(|tid| {
+                schedule
+                    .iter()
+                    .find(|record| record.tid == **tid)
+                    .is_none_or(|record| !matches!(record.event, SchedulerEvent::Created { .. }))
+            })
+            .map(|&tid| (tid, "running".to_owned()))
+            .collect();
+        for &tid in &threads {
+            events.push(format!(
+                r#"{{"name": "thread_name", "ph": "M", "pid": 0, "tid": {0}, "args": {{"name": "thread {0}"}}}}"#,
+                tid
+            ));
+            if open.contains_key(&tid) {
+                events.push(format!(
+                    r#"{{"name": "running", "ph": "B", "ts": 0, "pid": 0, "tid": {}}}"#,
+                    tid
+                ));
+            }
+        }
+
+        for record in &schedule {
+            let (ts, tid) = (record.elapsed_us, record.tid);
+            match record.event {
+                SchedulerEvent::Created { parent, entry } => events.push(format!(
+                    r#"{{"name": "create thread {}", "ph": "i", "s": "t", "ts": {}, "pid": 0, "tid": {}, "args": {{"entry": "{:08x}"}}}}"#,
+                    tid, ts, parent, entry
+                )),
+                SchedulerEvent::Yielded => {
+                    events.push(format!(
+                        r#"{{"name": "yield", "ph": "i", "s": "t", "ts": {}, "pid": 0, "tid": {}}}"#,
+                        ts, tid
+                    ));
+                    // The thread keeps running afterwards
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(name) = open.remove(&tid) {
+                events.push(format!(
+                    r#"{{"name": "{}", "ph": "E", "ts": {}, "pid": 0, "tid": {}}}"#,
+                    name, ts, tid
+                ));
+            }
+            if let Some(name) = slice_name(&record.event) {
+                events.push(format!(
+                    r#"{{"name": "{}", "ph": "B", "ts": {}, "pid": 0, "tid": {}}}"#,
+                    name, ts, tid
+                ));
+                open.insert(tid, name);
+            }
+        }
+        for (tid, name) in open {
+            events.push(format!(
+                r#"{{"name": "{}", "ph": "E", "ts": {}, "pid": 0, "tid": {}}}"#,
+                name, end, tid
+            ));
+        }
+
+        for record in &records {
+            match &record.event {
+                TraceEvent::Marker(id) => events.push(format!(
+                    r#"{{"name": "marker {}", "ph": "i", "s": "t", "ts": {}, "pid": 0, "tid": {}, "args": {{"pc": "{:08x}"}}}}"#,
+                    id,
+                    record.elapsed_ms * 1000,
+                    record.tid,
+                    record.pc
+                )),
+                TraceEvent::Fault(message) => events.push(format!(
+                    r#"{{"name": "fault", "ph": "i", "s": "t", "ts": {}, "pid": 0, "tid": {}, "args": {{"pc": "{:08x}", "message": {}}}}}"#,
+                    record.elapsed_ms * 1000,
+                    record.tid,
+                    record.pc,
+                    json_string(message)
+                )),
+                _ => {}
+            }
+        }
+
+        writeln!(output, "{{")?;
+        writeln!(output, "  \"displayTimeUnit\": \"ms\",")?;
+        write!(output, "  \"traceEvents\": [")?;
+        for (index, event) in events.iter().enumerate() {
+            if index != 0 {
+                write!(output, ",")?;
+            }
+            write!(output, "\n    {}", event)?;
+        }
+        writeln!(output, "\n  ]")?;
+        writeln!(output, "}}")
+    }
+}
@@ -0,0 +1,96 @@
+//! Translations of the pages the emulator has mapped, so that the CPU can
+//! skip walking the page tables for them. Each is kept under the address
+//! space it belongs to and the virtual page it maps, along with what that
+//! page may be used for: an access the page doesn't allow misses the cache,
+//! and the CPU then walks the page tables and raises the page fault itself.
+//!
+//! Whatever changes a page table entry has to update its translation here
+//! too. Mapping a page adds it, changing its flags replaces it with what the
+//! page now allows, unmapping drops it, and an address space whose process
+//! has gone is flushed before its ASID is handed to another.
+
+use std::sync::RwLock;
+
+use riscv_cpu::mmu::MemoryAccessType;
+
+use super::{MMUFLAG_EXECUTABLE, MMUFLAG_READABLE, MMUFLAG_VALID, MMUFLAG_WRITABLE};
+
+/// The number of virtual pages in an address space.
+const PAGES: usize = 1 << 20;
+
+/// The bits of a page table entry's flags that are kept with a translation.
+const PERMISSIONS: u32 = MMUFLAG_READABLE | MMUFLAG_WRITABLE | MMUFLAG_EXECUTABLE;
+
+/// The flag an access needs the page to have.
+fn required(access_type: &MemoryAccessType) -> u32 {
+    match access_type {
+        MemoryAccessType::Execute => MMUFLAG_EXECUTABLE,
+        MemoryAccessType::Read => MMUFLAG_READABLE,
+        MemoryAccessType::Write => MMUFLAG_WRITABLE,
+        MemoryAccessType::DontCare => 0,
+    }
+}
+
+#[derive(Default)]
+pub struct TranslationCache {
+    /// One table for each ASID that has anything cached, indexed by virtual
+    /// page number. An entry is the physical page with the permission bits
+    /// and `MMUFLAG_VALID` in its low 12 bits, or 0 if nothing is cached.
+    spaces: RwLock<Vec<Option<Box<[u32]>>>>,
+}
+
+impl TranslationCache {
+    /// The physical address `virt` maps to in address space `asid`, if it's
+    /// cached and the page allows `access_type`.
+    pub fn lookup(&self, asid: u32, virt: u32, access_type: &MemoryAccessType) -> Option<u32> {
+        let spaces = self.spaces.read().unwrap();
+        let entry = spaces.get(asid as usize)?.as_ref()?[virt as usize >> 12];
+        let required = required(access_type) | MMUFLAG_VALID;
+        (entry & required == required).then_some(entry & !0xfff | virt & 0xfff)
+    }
+
+    /// Every translation cached for `asid`, as the virtual page, the
+    /// physical page, and the permission bits.
+    pub fn entries(&self, asid: u32) -> Vec<(u32, u32, u32)> {
+        let spaces = self.spaces.read().unwrap();
+        let Some(Some(table)) = spaces.get(asid as usize) else {
+            return vec![];
+        };
+        table
+            .iter()
+            .enumerate()
+            .filter(|(_, &entry)| entry != 0)
+            .map(|(vpn, &entry)| ((vpn as u32) << 12, entry & !0xfff, entry & PERMISSIONS))
+            .collect()
+    }
+
+    /// Cache that the page at `virt` in address space `asid` maps to the
+    /// page at `phys`, with the permission bits of `flags`.
+    pub fn insert(&self, asid: u32, virt: u32, phys: u32, flags: u32) {
+        let mut spaces = self.spaces.write().unwrap();
+        if spaces.len() <= asid as usize {
+            spaces.resize_with(asid as usize + 1, || None);
+        }
+        let table = spaces[asid as usize].get_or_insert_with(|| vec![0; PAGES].into());
+        table[virt as usize >> 12] = phys & !0xfff | flags & PERMISSIONS | MMUFLAG_VALID;
+    }
+
+    /// Drop the translation of the page at `virt` in address space `asid`.
+    pub fn invalidate(&self, asid: u32, virt: u32) {
+        let mut spaces = self.spaces.write().unwrap();
+        if let Some(Some(table)) = spaces.get_mut(asid as usize) {
+            table[virt as usize >> 12] = 0;
+        }
+    }
+
+    /// Drop every translation cached for `asid`.
+    pub fn flush_space(&self, asid: u32) {
+        if let Some(table) = self.spaces.write().unwrap().get_mut(asid as usize) {
+            *table = None;
+        }
+    }
+
+    pub fn clear_poison(&self) {
+        self.spaces.clear_poison();
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::platform::Platform;
+
+/// Keep this many uninitialized reads for `Machine::uninitialized_reads()`.
+const READ_LOG_LIMIT: usize = 32;
+
+/// Stop warning about uninitialized reads after this many, since a single
+/// bug tends to cause a flood of them.
+const WARNING_LIMIT: usize = 16;
+
+/// A read by the guest from memory it never wrote.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UninitializedRead {
+    pub tid: i32,
+
+    /// Address of the instruction that made the read.
+    pub pc: u32,
+
+    /// The physical address that was read.
+    pub address: u32,
+}
+
+/// Which words of recently mapped pages haven't been written yet. The
+/// emulator's RAM starts out zeroed, which hides bugs that read memory before
+/// writing it, so pages are tagged as uninitialized when they're mapped and
+/// each word is cleared as soon as anything is stored to it. Pages filled in
+/// by the loader count as initialized, as does `.bss`.
+#[derive(Default)]
+pub struct UninitTracker {
+    /// One bit per word, set while the word is uninitialized, for each
+    /// physical page number that still has some.
+    pages: Mutex<HashMap<u32, [u64; 16]>>,
+    reads: Mutex<Vec<UninitializedRead>>,
+
+    /// Instructions already warned about, so that a loop only warns once.
+    warned: Mutex<HashSet<u32>>,
+}
+
+impl UninitTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tag the page at `phys` as freshly mapped.
+    pub(super) fn map_page(&self, phys: u32) {
+        self.pages.lock().unwrap().insert(phys >> 12, [!0; 16]);
+    }
+
+    /// Forget about the page at `phys`, which has been freed.
+    pub(super) fn unmap_page(&self, phys: u32) {
+        self.pages.lock().unwrap().remove(&(phys >> 12));
+    }
+
+    /// Mark the words covering `len` bytes at `phys` as written.
+    pub(super) fn initialize(&self, phys: u32, len: u32) {
+        let mut pages = self.pages.lock().unwrap();
+        for word in (phys & !3..phys.saturating_add(len)).step_by(4) {
+            let Some(page) = pages.get_mut(&(word >> 12)) else {
+                continue;
+            };
+            let index = (word as usize & 0xfff) >> 2;
+            page[index / 64] &= !(1 << (index % 64));
+            if page.iter().all(|&bits| bits == 0) {
+                pages.remove(&(word >> 12));
+            }
+        }
+    }
+
+    /// Whether the word at `phys` hasn't been written since it was mapped.
+    pub(super) fn is_uninitialized(&self, phys: u32) -> bool {
+        let index = (phys as usize & 0xfff) >> 2;
+        self.pages
+            .lock()
+            .unwrap()
+            .get(&(phys >> 12))
+            .is_some_and(|page| page[index / 64] & (1 << (index % 64)) != 0)
+    }
+
+    /// Record that thread `tid` read the uninitialized word at `address`
+    /// from `pc`, and warn about it unless that instruction has already been
+    /// reported or there have been too many warnings.
+    pub(super) fn report(&self, platform: &dyn Platform, tid: i32, pc: u32, address: u32) {
+        let mut reads = self.reads.lock().unwrap();
+        if reads.len() < READ_LOG_LIMIT {
+            reads.push(UninitializedRead { tid, pc, address });
+        }
+
+        let mut warned = self.warned.lock().unwrap();
+        if warned.len() > WARNING_LIMIT || !warned.insert(pc) {
+            return;
+        }
+        let message = if warned.len() > WARNING_LIMIT {
+            "Too many uninitialized reads, not warning about any more\n".to_owned()
+        } else {
+            format!(
+                "Thread {} at pc {:08x} read uninitialized memory at {:08x}\n",
+                tid, pc, address
+            )
+        };
+        platform.write_stderr(message.as_bytes());
+    }
+
+    /// The first uninitialized reads, in the order they happened.
+    pub fn reads(&self) -> Vec<UninitializedRead> {
+        self.reads.lock().unwrap().clone()
+    }
+}
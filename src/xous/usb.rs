@@ -0,0 +1,201 @@
+//! The USB device port, as seen through the usb-device server. There's no
+//! USB controller to drive, so the port is always plugged into a host that
+//! has configured it, keystrokes the program types are collected as text,
+//! and its serial port can be bridged to a host stream such as a pty. Only
+//! the last `HISTORY_LIMIT` bytes of what the program types and writes are
+//! kept.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The most bytes of serial output, and of typed text, that are kept for
+/// `serial_output()` and `typed()`. Older ones are dropped. A bridged
+/// serial port also stops being read while this much input is waiting for
+/// the program.
+pub const HISTORY_LIMIT: usize = 1 << 20;
+
+/// How long the thread reading a bridged serial port waits before trying
+/// again when there's nothing to read or no room for it.
+const SERIAL_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Which function the port presents to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbCore {
+    /// The debug bridge built into the SoC.
+    Debug = 0,
+
+    /// The program's own keyboard and serial port.
+    Device = 1,
+}
+
+#[derive(Default)]
+struct Serial {
+    /// Bytes from the host that the program hasn't read yet.
+    input: VecDeque<u8>,
+
+    /// The last `HISTORY_LIMIT` bytes the program has written.
+    output: Vec<u8>,
+
+    /// Where the program's writes are copied, if the port is bridged.
+    writer: Option<Box<dyn Write + Send>>,
+}
+
+/// The emulated USB port, shared between the usb-device service and the host.
+pub struct UsbDevice {
+    core: Mutex<UsbCore>,
+
+    /// The keyboard LEDs the host has lit, as in a HID output report.
+    leds: AtomicU32,
+
+    /// The last `HISTORY_LIMIT` bytes the program has typed.
+    typed: Mutex<String>,
+
+    /// Where typed text is copied, if anywhere.
+    keyboard: Mutex<Option<Box<dyn Write + Send>>>,
+    serial: Mutex<Serial>,
+
+    /// The thread reading a bridged serial port, and whether it's been
+    /// asked to stop.
+    reader: Mutex<Option<JoinHandle<()>>>,
+    stopping: AtomicBool,
+}
+
+impl Default for UsbDevice {
+    fn default() -> Self {
+        UsbDevice {
+            core: Mutex::new(UsbCore::Debug),
+            leds: AtomicU32::new(0),
+            typed: Mutex::new(String::new()),
+            keyboard: Mutex::new(None),
+            serial: Mutex::new(Serial::default()),
+            reader: Mutex::new(None),
+            stopping: AtomicBool::new(false),
+        }
+    }
+}
+
+impl UsbDevice {
+    /// Copy everything the program types to `writer` as it's typed.
+    pub fn bridge_keyboard(&self, writer: Box<dyn Write + Send>) {
+        *self.keyboard.lock().unwrap() = Some(writer);
+    }
+
+    /// Connect the serial port to a host stream: what the program writes
+    /// goes to `writer`, and what arrives from `reader` is there for it to
+    /// read. The reader is read on a thread of its own until it ends or
+    /// the machine is dropped. Dropping the machine waits for a read in
+    /// progress, so a reader that may wait a long time for input should be
+    /// non-blocking: one that returns `WouldBlock` is tried again later.
+    pub fn bridge_serial(
+        self: &Arc<Self>,
+        mut reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+    ) {
+        self.serial.lock().unwrap().writer = Some(writer);
+        let usb = self.clone();
+        let thread = std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while !usb.stopping.load(Ordering::Relaxed) {
+                if usb.serial.lock().unwrap().input.len() >= HISTORY_LIMIT {
+                    std::thread::sleep(SERIAL_POLL_INTERVAL);
+                    continue;
+                }
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(count) => usb.send_serial(&buf[..count]),
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                        std::thread::sleep(SERIAL_POLL_INTERVAL)
+                    }
+                    Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        });
+        *self.reader.lock().unwrap() = Some(thread);
+    }
+
+    /// Stop reading a bridged serial port, and wait for the thread reading
+    /// it to finish.
+    pub(super) fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.reader.lock().unwrap().take() {
+            thread.join().ok();
+        }
+    }
+
+    pub fn core(&self) -> UsbCore {
+        *self.core.lock().unwrap()
+    }
+
+    pub(super) fn set_core(&self, core: UsbCore) {
+        *self.core.lock().unwrap() = core;
+    }
+
+    pub fn leds(&self) -> u32 {
+        self.leds.load(Ordering::Relaxed)
+    }
+
+    /// Light the keyboard LEDs `leds`, such as caps lock, as the host would.
+    pub fn set_leds(&self, leds: u32) {
+        self.leds.store(leds, Ordering::Relaxed);
+    }
+
+    /// The last `HISTORY_LIMIT` bytes the program has typed.
+    pub fn typed(&self) -> String {
+        self.typed.lock().unwrap().clone()
+    }
+
+    pub(super) fn type_text(&self, text: &str) {
+        let mut typed = self.typed.lock().unwrap();
+        typed.push_str(text);
+        if let Some(mut excess) = typed.len().checked_sub(HISTORY_LIMIT) {
+            while !typed.is_char_boundary(excess) {
+                excess += 1;
+            }
+            typed.drain(..excess);
+        }
+        drop(typed);
+        if let Some(writer) = self.keyboard.lock().unwrap().as_mut() {
+            writer
+                .write_all(text.as_bytes())
+                .and_then(|()| writer.flush())
+                .ok();
+        }
+    }
+
+    /// Send `data` to the program over the serial port.
+    pub fn send_serial(&self, data: &[u8]) {
+        self.serial.lock().unwrap().input.extend(data);
+    }
+
+    /// The last `HISTORY_LIMIT` bytes the program has written to the
+    /// serial port.
+    pub fn serial_output(&self) -> Vec<u8> {
+        self.serial.lock().unwrap().output.clone()
+    }
+
+    pub(super) fn write_serial(&self, data: &[u8]) {
+        let mut serial = self.serial.lock().unwrap();
+        serial.output.extend_from_slice(data);
+        if let Some(excess) = serial.output.len().checked_sub(HISTORY_LIMIT) {
+            serial.output.drain(..excess);
+        }
+        if let Some(writer) = serial.writer.as_mut() {
+            writer.write_all(data).and_then(|()| writer.flush()).ok();
+        }
+    }
+
+    /// Move as much serial input as fits into `buf`, returning how much that was.
+    pub(super) fn read_serial(&self, buf: &mut [u8]) -> usize {
+        let mut serial = self.serial.lock().unwrap();
+        let count = buf.len().min(serial.input.len());
+        for (byte, input) in buf.iter_mut().zip(serial.input.drain(..count)) {
+            *byte = input;
+        }
+        count
+    }
+}
@@ -0,0 +1,85 @@
+//! Keeps every page either writable or executable, never both, so that a
+//! stray write into code, or a jump into data, stops the program where it
+//! happens rather than much later.
+//!
+//! Pages are mapped writable and not executable, and once the program is
+//! loaded, those holding an executable section lose write permission and
+//! gain execute permission instead. The guest can still change its mind
+//! about a page with `UpdateMemoryFlags`, as a JIT would, so long as it
+//! doesn't ask for both at once. A write to an executable page raises a
+//! store access fault, and running a writable one an instruction access
+//! fault, either of which is logged along with the section that was loaded
+//! there.
+
+use riscv_cpu::cpu::{Trap, TrapType};
+
+use super::program::Section;
+use super::section_map::SectionMap;
+use super::{Memory, MMUFLAG_EXECUTABLE, MMUFLAG_READABLE, MMUFLAG_WRITABLE};
+
+#[derive(Default)]
+pub(super) struct WxPolicy {
+    sections: SectionMap,
+}
+
+impl WxPolicy {
+    /// Make the pages of the loaded `sections` that are executable
+    /// executable rather than writable. A page shared by code and writable
+    /// data can't be both, so it's left as it was, with a warning.
+    pub fn protect(&self, memory: &Memory, sections: &[Section]) {
+        self.sections.set(sections);
+        for page in self.sections.pages(
+            |section| section.executable,
+            |section| section.writable && !section.executable,
+        ) {
+            match page.shared {
+                Some(data) => log::warn!(
+                    "{} and {} share the page at {:08x}, which stays writable and executable",
+                    page.section,
+                    data,
+                    page.address
+                ),
+                None => {
+                    memory.set_memory_flags(page.address, MMUFLAG_READABLE | MMUFLAG_EXECUTABLE)
+                }
+            }
+        }
+    }
+
+    /// `trap` as an access fault if it was raised by thread `tid` at `pc`
+    /// for writing to an executable page or running a writable one, or as
+    /// it is otherwise.
+    pub fn check(&self, memory: &Memory, tid: i32, pc: u32, trap: Trap) -> Trap {
+        let (forbidden, trap_type, access) = match trap.trap_type {
+            TrapType::StorePageFault => {
+                (MMUFLAG_EXECUTABLE, TrapType::StoreAccessFault, "wrote to")
+            }
+            TrapType::InstructionPageFault => {
+                (MMUFLAG_WRITABLE, TrapType::InstructionAccessFault, "ran")
+            }
+            _ => return trap,
+        };
+        match memory.page_flags(trap.value) {
+            Some(flags) if flags & forbidden != 0 => {}
+            _ => return trap,
+        }
+        let section = self.sections.find(trap.value, |_| true);
+        log::error!(
+            "thread {} at {:08x} {} {:08x} ({}), which isn't {}",
+            tid,
+            pc,
+            access,
+            trap.value,
+            section.as_deref().unwrap_or("not part of the program"),
+            if forbidden == MMUFLAG_EXECUTABLE {
+                "writable"
+            } else {
+                "executable"
+            }
+        );
+        Trap {
+            trap_type,
+            value: trap.value,
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// An emulated watchdog timer. Like the one in the Precursor SoC, it must be
+/// petted through the ticktimer's `PingWdt` opcode at least once every
+/// `timeout_ms`, or it stops every thread of the program.
+pub struct Watchdog {
+    timeout_ms: u64,
+
+    /// When the watchdog was last petted, in milliseconds since the machine started.
+    last_pet_ms: AtomicU64,
+    expired: AtomicBool,
+}
+
+impl Watchdog {
+    pub fn new(timeout_ms: u64) -> Self {
+        Watchdog {
+            timeout_ms,
+            last_pet_ms: AtomicU64::new(0),
+            expired: AtomicBool::new(false),
+        }
+    }
+
+    pub fn timeout_ms(&self) -> u64 {
+        self.timeout_ms
+    }
+
+    /// Restart the countdown. Petting an expired watchdog has no effect.
+    pub(super) fn pet(&self, now_ms: u64) {
+        self.last_pet_ms.fetch_max(now_ms, Ordering::Relaxed);
+    }
+
+    /// Check whether the watchdog has run out, which is done periodically
+    /// alongside the services' timeouts.
+    pub(super) fn tick(&self, now_ms: u64) {
+        if now_ms.saturating_sub(self.last_pet_ms.load(Ordering::Relaxed)) > self.timeout_ms {
+            self.expired.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the program failed to pet the watchdog in time.
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,91 @@
+//! What happens to a program that misuses syscalls under each handler. The
+//! guest in `guests/abuse.S` sends to a connection it never made, lends
+//! memory it never mapped, grows the heap by less than a page, and sends the
+//! ticktimer an opcode it doesn't have, and exits with 0 if each call failed
+//! as the kernel would fail it. The one in `guests/refused.S` registers a
+//! name twice, asks to connect in a buffer too small for the answer, and
+//! unlocks and frees a mutex it never locked, and exits with 0 if each
+//! message was refused with the error the service gives.
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::abuse::{parse_rule, Abuse, AbuseHandler};
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+fn run(abuse: Abuse, handler: AbuseHandler) -> Result<u32, YoveError> {
+    MachineBuilder::new()
+        .on_abuse(abuse, handler)
+        .build(include_bytes!("guests/abuse.elf"))
+        .unwrap()
+        .run()
+}
+
+#[test]
+fn warnings_fail_the_calls() {
+    for abuse in Abuse::ALL {
+        assert_eq!(0, run(abuse, AbuseHandler::Warn).unwrap());
+    }
+}
+
+#[test]
+fn killing_the_process_stops_at_the_first_of_its_kind() {
+    for abuse in [
+        Abuse::BadConnection,
+        Abuse::UnmappedLend,
+        Abuse::InvalidArgument,
+        Abuse::UnknownOpcode,
+    ] {
+        match run(abuse, AbuseHandler::KillProcess) {
+            Err(YoveError::Abuse { abuse: kind, .. }) => assert_eq!(abuse, kind),
+            result => panic!("expected {} to end the process, got {:?}", abuse, result),
+        }
+    }
+}
+
+#[test]
+fn services_refuse_messages_they_cant_act_on() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/refused.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let mut machine = MachineBuilder::new()
+        .on_abuse(Abuse::InvalidMessage, AbuseHandler::KillProcess)
+        .build(include_bytes!("guests/refused.elf"))
+        .unwrap();
+    match machine.run() {
+        Err(YoveError::Abuse { abuse, .. }) => assert_eq!(Abuse::InvalidMessage, abuse),
+        result => panic!("expected the process to end, got {:?}", result),
+    }
+}
+
+#[test]
+fn killing_the_thread_ends_the_program() {
+    assert_eq!(
+        !0,
+        run(Abuse::UnmappedLend, AbuseHandler::KillThread).unwrap()
+    );
+}
+
+#[test]
+fn trapping_raises_an_environment_call() {
+    match run(Abuse::BadConnection, AbuseHandler::Trap) {
+        Err(YoveError::Trap { trap, .. }) => {
+            assert!(matches!(trap.trap_type, TrapType::EnvironmentCallFromUMode));
+        }
+        result => panic!("expected an exception, got {:?}", result),
+    }
+}
+
+#[test]
+fn rules_name_a_kind_or_all() {
+    let (kinds, handler) = parse_rule("unmapped-lend=trap").unwrap();
+    assert_eq!(
+        (vec![Abuse::UnmappedLend], AbuseHandler::Trap),
+        (kinds, handler)
+    );
+    let (kinds, _) = parse_rule("all=warn").unwrap();
+    assert_eq!(Abuse::ALL.to_vec(), kinds);
+    assert!(parse_rule("unmapped-lend").is_err());
+    assert!(parse_rule("bad-lend=warn").is_err());
+}
@@ -0,0 +1,24 @@
+//! Threads of a process sharing its address space. The guest in
+//! `guests/sharedspace.S` grows the heap and maps memory from two threads,
+//! and checks that each sees what the other did.
+
+use yove::xous::{MachineBuilder, MachineEvent};
+
+const PROGRAM: &[u8] = include_bytes!("guests/sharedspace.elf");
+
+#[test]
+fn threads_share_the_heap_and_mappings() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn stepped_threads_share_the_heap_and_mappings() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    loop {
+        if let MachineEvent::Exited(code) = machine.step().unwrap() {
+            assert_eq!(0, code);
+            break;
+        }
+    }
+}
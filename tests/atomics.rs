@@ -0,0 +1,12 @@
+//! LR/SC between guest threads. The guest in `guests/lrsc.S` exits with the
+//! number of the first check that failed.
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn store_from_another_thread_breaks_reservation() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/lrsc.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
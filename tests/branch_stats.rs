@@ -0,0 +1,39 @@
+//! Branch and prefetch statistics. The guest in `guests/countdown.S` loops 100
+//! times in `spin`, whose branch is taken every time but the last.
+
+use yove::xous::branch_stats::{FunctionStats, DEFAULT_BTB_ENTRIES};
+use yove::xous::MachineBuilder;
+
+#[test]
+fn loops_mispredict_on_entry_and_exit() {
+    let mut machine = MachineBuilder::new()
+        .branch_stats(DEFAULT_BTB_ENTRIES)
+        .build(include_bytes!("guests/countdown.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let functions = machine.branch_stats().unwrap().functions();
+    let (name, spin) = &functions[0];
+    assert_eq!("spin", name);
+    // The loop's first and last branches go the other way to the one
+    // predicted, and the return has never been seen before
+    assert_eq!(
+        FunctionStats {
+            instructions: 201,
+            fetch_misses: spin.fetch_misses,
+            branches: 101,
+            mispredictions: 3,
+        },
+        *spin
+    );
+    assert!(spin.fetch_misses <= 1, "{:?}", spin);
+
+    let mut table = vec![];
+    machine.branch_stats().unwrap().write(&mut table).unwrap();
+    let table = String::from_utf8(table).unwrap();
+    assert!(
+        table.lines().nth(1).unwrap().ends_with("  spin"),
+        "{}",
+        table
+    );
+}
@@ -0,0 +1,58 @@
+//! Breakpoint scripts. The guest in `guests/script.S` points `a1` at a
+//! message and exits with 1 from `verdict`, where the scripts here dump the
+//! message, change the exit code, turn recording on, or stop the run.
+
+use yove::xous::breakpoint_script::BreakpointScript;
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+fn build(script: &str) -> MachineBuilder {
+    MachineBuilder::new().breakpoint_script(BreakpointScript::parse(script).unwrap())
+}
+
+#[test]
+fn actions_dump_memory_and_set_registers() {
+    let mut machine = build("# comments are ignored\nverdict: dump a1 8; set a0 0; continue\n")
+        .build(include_bytes!("guests/script.elf"))
+        .unwrap();
+    let verdict = machine.symbol_address("verdict").unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let dumps = machine.breakpoint_dumps();
+    assert_eq!(1, dumps.len());
+    assert_eq!(verdict, dumps[0].pc);
+    assert_eq!(b"scripted", &dumps[0].bytes[..]);
+}
+
+#[test]
+fn recording_starts_at_trace_on() {
+    let mut machine = build("verdict: trace on")
+        .record_execution(100, vec![])
+        .build(include_bytes!("guests/script.elf"))
+        .unwrap();
+    let verdict = machine.symbol_address("verdict").unwrap();
+    assert_eq!(1, machine.run().unwrap());
+    let execution = machine.tracer().unwrap().execution();
+    assert_eq!(verdict, execution[0].pc);
+}
+
+#[test]
+fn stop_ends_the_run() {
+    let mut machine = build("verdict: stop")
+        .build(include_bytes!("guests/script.elf"))
+        .unwrap();
+    let verdict = machine.symbol_address("verdict").unwrap();
+    match machine.run() {
+        Err(YoveError::ScriptStop { pc, .. }) => assert_eq!(verdict, pc),
+        result => panic!("expected the script to stop the run, got {:?}", result),
+    }
+}
+
+#[test]
+fn unknown_symbols_and_actions_are_rejected() {
+    assert!(BreakpointScript::parse("verdict: jump").is_err());
+    assert!(BreakpointScript::parse("set a0 0").is_err());
+    match build("nowhere: stop").build(include_bytes!("guests/script.elf")) {
+        Err(YoveError::Load(_)) => {}
+        result => panic!("expected an unknown symbol, got {:?}", result.err()),
+    }
+}
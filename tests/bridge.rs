@@ -0,0 +1,187 @@
+//! Servers bridged to a real device. A thread stands in for the device's
+//! bridge server at the other end of a socket, and the guest in
+//! `guests/bridge.S` checks that its replies come back as sent. The guest in
+//! `guests/lostlink.S` checks that messages to a device whose link has gone
+//! come back as an `InternalError`.
+#![cfg(unix)]
+
+use std::io::{ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use yove::xous::bridge::{Request, RequestKind, Response, MAX_FRAME_SIZE};
+use yove::xous::{Machine, MachineBuilder, MachineEvent};
+
+const PROGRAM: &[u8] = include_bytes!("guests/bridge.elf");
+
+/// Answer requests the way the guest expects until the link closes,
+/// returning every request that arrived.
+fn device(mut link: UnixStream) -> JoinHandle<Vec<Request>> {
+    std::thread::spawn(move || {
+        let mut requests = vec![];
+        while let Ok(request) = Request::read(&mut link) {
+            let response = match (request.name.as_str(), request.kind, request.opcode) {
+                (_, RequestKind::Scalar, _) => None,
+                ("_TRNG manager_", _, 0) => Some(Response::Scalar2([0x12345678, 0x9abcdef0])),
+                ("_TRNG manager_", _, 1) => Some(Response::Error(23)),
+                ("_COM manager_", RequestKind::MutableLend, 31) => {
+                    let mut buf = request.buf.clone();
+                    buf[..7].copy_from_slice(b"bridged");
+                    Some(Response::MemoryReturned([0, 7], buf))
+                }
+                // Try to write to a buffer that was only lent to read
+                ("_COM manager_", RequestKind::Lend, 5) => {
+                    Some(Response::MemoryReturned([0, 4], b"pong".to_vec()))
+                }
+                _ => panic!("unexpected request {:?}", request),
+            };
+            if let Some(response) = response {
+                response.write(&mut link).unwrap();
+            }
+            requests.push(request);
+        }
+        requests
+    })
+}
+
+fn bridged() -> Vec<String> {
+    vec!["_TRNG manager_".to_owned(), "_COM manager_".to_owned()]
+}
+
+#[test]
+fn messages_are_answered_by_the_device() {
+    let (link, device_end) = UnixStream::pair().unwrap();
+    let device = device(device_end);
+    let mut machine = MachineBuilder::new()
+        .bridge(
+            bridged(),
+            Box::new(link.try_clone().unwrap()),
+            Box::new(link),
+        )
+        .build(PROGRAM)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    drop(machine);
+
+    let requests = device.join().unwrap();
+    let kinds: Vec<_> = requests
+        .iter()
+        .map(|request| (request.kind, request.opcode))
+        .collect();
+    assert_eq!(
+        vec![
+            (RequestKind::BlockingScalar, 0),
+            (RequestKind::Scalar, 7),
+            (RequestKind::BlockingScalar, 1),
+            (RequestKind::MutableLend, 31),
+            (RequestKind::Lend, 5),
+        ],
+        kinds
+    );
+    assert_eq!([1, 2, 3, 4], requests[1].args);
+    assert_eq!([0, 4096, 0, 4], requests[4].args);
+    assert_eq!(b"ping", &requests[4].buf[..4]);
+}
+
+#[test]
+fn a_lost_link_fails_the_message() {
+    let (link, device_end) = UnixStream::pair().unwrap();
+    drop(device_end);
+    let mut machine = MachineBuilder::new()
+        .bridge(
+            bridged(),
+            Box::new(link.try_clone().unwrap()),
+            Box::new(link),
+        )
+        .build(PROGRAM)
+        .unwrap();
+    // The first message to the device fails rather than waiting forever
+    assert_eq!(3, machine.run().unwrap());
+}
+
+/// A machine running `guests/lostlink.S` bridged to a device that has
+/// already hung up.
+fn lost_link() -> Machine {
+    let (link, device_end) = UnixStream::pair().unwrap();
+    drop(device_end);
+    MachineBuilder::new()
+        .bridge(
+            bridged(),
+            Box::new(link.try_clone().unwrap()),
+            Box::new(link),
+        )
+        .build(include_bytes!("guests/lostlink.elf"))
+        .unwrap()
+}
+
+#[test]
+fn a_lost_link_is_an_internal_error() {
+    assert_eq!(0, lost_link().run().unwrap());
+}
+
+#[test]
+fn a_lost_link_is_an_internal_error_when_stepping() {
+    let mut machine = lost_link();
+    loop {
+        match machine.step().unwrap() {
+            MachineEvent::Exited(code) => return assert_eq!(0, code),
+            MachineEvent::Idle => std::thread::sleep(std::time::Duration::from_millis(1)),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn oversized_frames_are_refused() {
+    let length = (MAX_FRAME_SIZE as u32 + 1).to_le_bytes();
+    let error = Response::read(&mut &length[..]).unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, error.kind());
+
+    let request = Request {
+        kind: RequestKind::Lend,
+        name: "_COM manager_".to_owned(),
+        opcode: 5,
+        args: [0; 4],
+        buf: vec![0; MAX_FRAME_SIZE],
+    };
+    let mut sent = vec![];
+    let error = request.write(&mut sent).unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, error.kind());
+    assert!(sent.is_empty());
+}
+
+/// A link to a device that never answers, which notes when it's closed.
+struct Closed(Arc<AtomicBool>);
+
+impl Write for Closed {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Closed {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn dropping_the_machine_closes_the_link() {
+    let closed = Arc::new(AtomicBool::new(false));
+    let machine = MachineBuilder::new()
+        .bridge(
+            bridged(),
+            Box::new(std::io::empty()),
+            Box::new(Closed(closed.clone())),
+        )
+        .build(PROGRAM)
+        .unwrap();
+    drop(machine);
+    assert!(closed.load(Ordering::SeqCst));
+}
@@ -0,0 +1,54 @@
+//! The control flow graph. The guest in `guests/cfgnames.S` calls a
+//! function twice, whose name, `say \"hi\" \\ twice`, has quotes and
+//! backslashes in it that have to be escaped in both formats.
+
+use yove::xous::{cfg::CfgFormat, MachineBuilder};
+
+const NAME: &str = r#"say \"hi\" \\ twice"#;
+
+/// The graph of a run of the guest, written as `format`.
+fn graph(format: CfgFormat) -> String {
+    let mut machine = MachineBuilder::new()
+        .control_flow_graph()
+        .build(include_bytes!("guests/cfgnames.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let mut output = vec![];
+    machine
+        .control_flow_graph()
+        .unwrap()
+        .write(format, &mut output)
+        .unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn json_has_the_calls_and_escapes_names() {
+    let json = graph(CfgFormat::Json);
+    let escaped = NAME.replace('\\', "\\\\").replace('"', "\\\"");
+    assert!(json.contains(&format!(
+        "{{\"caller\": \"_start\", \"callee\": \"{}\", \"count\": 2}}",
+        escaped
+    )));
+    assert!(json.contains(&format!("\"name\": \"{}\",", escaped)));
+    assert!(json.contains("\"kind\": \"call\", \"count\": 1}"));
+    assert!(json.contains("\"kind\": \"return\", \"count\": 1}"));
+}
+
+#[test]
+fn dot_escapes_names() {
+    let dot = graph(CfgFormat::Dot);
+    let escaped = NAME.replace('\\', "\\\\").replace('"', "\\\"");
+    assert!(dot.starts_with("digraph cfg {"));
+    assert!(dot.contains(&format!("[label=\"{}\\n", escaped)));
+    assert!(!dot.contains(NAME));
+    assert!(dot.contains("style=dashed, color=blue"));
+}
+
+#[test]
+fn formats_come_from_extensions() {
+    assert_eq!(CfgFormat::Dot, CfgFormat::from_path("graph.dot").unwrap());
+    assert_eq!(CfgFormat::Dot, CfgFormat::from_path("graph.gv").unwrap());
+    assert_eq!(CfgFormat::Json, CfgFormat::from_path("graph.json").unwrap());
+    assert!(CfgFormat::from_path("graph").is_err());
+}
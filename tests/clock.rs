@@ -0,0 +1,31 @@
+//! Starting the guest's clock somewhere other than zero. The guest in
+//! `guests/spin.S` spins until its clock reaches 100ms.
+
+use yove::xous::{MachineBuilder, MachineEvent};
+
+#[test]
+fn a_resumed_clock_carries_on_where_it_was() {
+    let mut machine = MachineBuilder::new()
+        .freeze_time()
+        .start_time_us(100_000)
+        .build(include_bytes!("guests/spin.elf"))
+        .unwrap();
+    assert_eq!(100_000, machine.clock().now_us());
+    // The deadline has already passed, so the frozen clock doesn't hold it up
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn a_frozen_clock_holds_the_guest_before_its_deadline() {
+    let mut machine = MachineBuilder::new()
+        .freeze_time()
+        .start_time_us(99_000)
+        .build(include_bytes!("guests/spin.elf"))
+        .unwrap();
+    for _ in 0..10 {
+        assert_eq!(MachineEvent::Running, machine.step().unwrap());
+    }
+    machine.clock().advance_us(1_000);
+    while machine.step().unwrap() == MachineEvent::Running {}
+    assert_eq!(99_000 + 1_000, machine.clock().now_us());
+}
@@ -0,0 +1,115 @@
+//! The codec server and the sinks behind it. The guest in `guests/codec.S`
+//! plays four stereo frames at 8kHz and powers the codec off, and the WAV it
+//! produces is compared against the samples it should contain.
+
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use yove::xous::audio::WavWriter;
+use yove::xous::MachineBuilder;
+
+const PROGRAM: &[u8] = include_bytes!("guests/codec.elf");
+
+/// A WAV file the test can still read once it's been handed over.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Shared {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        self.0.lock().unwrap().seek(position)
+    }
+}
+
+/// Run the guest recording at `rate`, returning the WAV it wrote.
+fn record(rate: u32) -> Vec<u8> {
+    record_with(|wav| WavWriter::new(wav, rate).unwrap())
+}
+
+/// Run the guest recording to the writer `sink` makes, returning the WAV it
+/// wrote.
+fn record_with(sink: impl FnOnce(Box<Shared>) -> WavWriter) -> Vec<u8> {
+    let wav = Shared::default();
+    let sink = sink(Box::new(wav.clone()));
+    let mut machine = MachineBuilder::new()
+        .audio(Box::new(sink))
+        .build(PROGRAM)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let bytes = wav.0.lock().unwrap().get_ref().clone();
+    bytes
+}
+
+/// The header of a 16-bit stereo WAV at `rate` holding `frames` frames.
+fn header(rate: u32, frames: u32) -> Vec<u8> {
+    let mut header = vec![];
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + frames * 4).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&[16, 0, 0, 0, 1, 0, 2, 0]);
+    header.extend_from_slice(&rate.to_le_bytes());
+    header.extend_from_slice(&(rate * 4).to_le_bytes());
+    header.extend_from_slice(&[4, 0, 16, 0]);
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&(frames * 4).to_le_bytes());
+    header
+}
+
+fn samples(wav: &[u8]) -> Vec<i16> {
+    wav[44..]
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect()
+}
+
+#[test]
+fn frames_are_recorded_as_played() {
+    let wav = record(8000);
+    assert_eq!(header(8000, 4), wav[..44]);
+    assert_eq!(
+        vec![0, 0, 1000, -1000, 2000, -2000, -4000, 4000],
+        samples(&wav)
+    );
+}
+
+#[test]
+fn frames_are_resampled_to_the_recording_rate() {
+    let wav = record(16000);
+    assert_eq!(header(16000, 8), wav[..44]);
+    assert_eq!(
+        vec![
+            0, 0, 500, -500, 1000, -1000, 1500, -1500, 2000, -2000, -1000, 1000, -4000, 4000,
+            -4000, 4000
+        ],
+        samples(&wav)
+    );
+}
+
+#[test]
+fn recording_stops_at_the_limit() {
+    let wav = record_with(|wav| WavWriter::new(wav, 8000).unwrap().limit(10));
+    assert_eq!(header(8000, 2), wav[..44]);
+    assert_eq!(vec![0, 0, 1000, -1000], samples(&wav));
+}
+
+#[test]
+fn rates_too_high_for_the_header_are_refused() {
+    assert!(WavWriter::new(Box::new(Shared::default()), u32::MAX / 2).is_err());
+}
+
+#[test]
+fn audio_is_discarded_without_a_sink() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    assert_eq!(4, machine.audio().frames());
+    assert!(!machine.audio().playing());
+}
@@ -0,0 +1,65 @@
+//! The emulated EC behind the COM server. The guest in `guests/com.S` reads
+//! the battery, charger, and wifi status, then turns the wifi off and sets
+//! the backlight.
+
+use yove::xous::ec::{EcStatus, Ssid};
+use yove::xous::MachineBuilder;
+
+const CONFIG: &str = "
+# A battery that's running low, but charging
+voltage = 3900
+current = -200
+soc = 42
+capacity = 500
+charging = true
+
+rssi = -70
+ssid = -60 Home
+ssid = -80 Cafe Wifi
+";
+
+#[test]
+fn reports_the_configured_status() {
+    let status: EcStatus = CONFIG.parse().unwrap();
+    let mut machine = MachineBuilder::new()
+        .ec(status)
+        .build(include_bytes!("guests/com.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let status = machine.ec().status();
+    assert!(!status.wifi_on);
+    assert_eq!((10, 20), status.backlight);
+}
+
+#[test]
+fn status_can_change_before_running() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/com.elf"))
+        .unwrap();
+    machine.ec().update(|status| {
+        *status = CONFIG.parse().unwrap();
+        status.ssids[1] = Ssid {
+            name: "Elsewhere".to_owned(),
+            rssi: -80,
+        };
+    });
+    // The scan results no longer match
+    assert_eq!(5, machine.run().unwrap());
+}
+
+#[test]
+fn bad_configs_say_where() {
+    assert_eq!(
+        Err("line 2: unknown key \"volts\"".to_owned()),
+        "soc = 10\nvolts = 3\n".parse::<EcStatus>()
+    );
+    assert_eq!(
+        Err("line 1: soc is over 100".to_owned()),
+        "soc = 101".parse::<EcStatus>()
+    );
+    assert_eq!(
+        Err("line 1: invalid charging \"maybe\"".to_owned()),
+        "charging = maybe".parse::<EcStatus>()
+    );
+}
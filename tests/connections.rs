@@ -0,0 +1,13 @@
+//! Connection lifecycle tests. The guest in `guests/connections.S` makes the
+//! connections and checks each result itself, exiting with the number of the
+//! first check that failed.
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn reconnect_after_disconnect() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/connections.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
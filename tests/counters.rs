@@ -0,0 +1,37 @@
+//! What user mode reads from the counter CSRs under each policy. The guest
+//! in `guests/counters.S` runs three instructions and exits with what it
+//! reads from `cycle`.
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::{counters::CounterPolicy, MachineBuilder};
+use yove::YoveError;
+
+fn run(policy: CounterPolicy) -> Result<u32, YoveError> {
+    MachineBuilder::new()
+        .counters(policy)
+        .build(include_bytes!("guests/counters.elf"))
+        .unwrap()
+        .run()
+}
+
+#[test]
+fn native_counters_count_cycles() {
+    assert!(run(CounterPolicy::Native).unwrap() > 3);
+}
+
+#[test]
+fn deterministic_counters_count_instructions() {
+    assert_eq!(3, run(CounterPolicy::Deterministic).unwrap());
+}
+
+#[test]
+fn trapping_counters_are_illegal() {
+    match run(CounterPolicy::Trap) {
+        Err(YoveError::Trap { trap, .. }) => {
+            assert!(matches!(trap.trap_type, TrapType::IllegalInstruction));
+            // rdcycle a0
+            assert_eq!(0xc000_2573, trap.value);
+        }
+        result => panic!("expected an illegal instruction, got {:?}", result),
+    }
+}
@@ -0,0 +1,41 @@
+//! The crypto accelerator servers. The guest in `guests/crypto.S` checks
+//! the sha512, aes, and engine-25519 servers against published test vectors.
+
+use yove::xous::MachineBuilder;
+
+const PROGRAM: &[u8] = include_bytes!("guests/crypto.elf");
+
+#[test]
+fn accelerators_match_published_vectors() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn messages_are_counted_by_server_and_opcode() {
+    let mut machine = MachineBuilder::new()
+        .message_stats()
+        .build(PROGRAM)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let report = machine.message_stats().unwrap().report();
+    let calls = |service: &str, opcode: u32| {
+        report
+            .iter()
+            .find(|stats| stats.service == service && stats.opcode == opcode)
+            .map_or(0, |stats| stats.calls)
+    };
+    // Three acquisitions of the sha512 engine, two X25519s, and one decryption
+    assert_eq!(3, calls("yove-sha512", 0));
+    assert_eq!(2, calls("yove-engine25519", 0));
+    assert_eq!(1, calls("yove-aes", 2));
+    for stats in &report {
+        assert!(stats.p50_us <= stats.p90_us && stats.p99_us <= stats.max_us);
+        assert!(stats.max_us <= stats.total_us);
+    }
+    // Sorted by the time spent waiting
+    assert!(report
+        .windows(2)
+        .all(|pair| pair[0].total_us >= pair[1].total_us));
+}
@@ -0,0 +1,47 @@
+//! Demand paging. The guest in `guests/demand.S` grows the heap by 1MB and
+//! touches three pages of it, and the one in `guests/faultrace.S` has four
+//! threads write to the same new pages at once.
+
+use yove::xous::MachineBuilder;
+
+const HEAP: std::ops::Range<u32> = 0xa000_0000..0xa050_0000;
+const STACK: std::ops::Range<u32> = 0xc000_0000..0xc002_0000;
+
+/// How many bytes are mapped in `window` once the guest has run.
+fn mapped(builder: MachineBuilder, window: std::ops::Range<u32>) -> u32 {
+    let mut machine = builder.build(include_bytes!("guests/demand.elf")).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    machine
+        .mappings()
+        .iter()
+        .filter(|mapping| window.contains(&mapping.virt))
+        .map(|mapping| mapping.size)
+        .sum()
+}
+
+#[test]
+fn only_touched_pages_are_allocated() {
+    assert_eq!(0x100000, mapped(MachineBuilder::new(), HEAP.clone()));
+    assert_eq!(
+        3 * 4096,
+        mapped(MachineBuilder::new().demand_paging(), HEAP)
+    );
+}
+
+#[test]
+fn stack_is_allocated_on_demand() {
+    assert_eq!(0x20000, mapped(MachineBuilder::new(), STACK.clone()));
+    assert!(mapped(MachineBuilder::new().demand_paging(), STACK) <= 2 * 4096);
+}
+
+#[test]
+fn harts_faulting_on_one_page_keep_each_others_writes() {
+    for _ in 0..20 {
+        let mut machine = MachineBuilder::new()
+            .demand_paging()
+            .harts(4)
+            .build(include_bytes!("guests/faultrace.elf"))
+            .unwrap();
+        assert_eq!(0, machine.run().unwrap());
+    }
+}
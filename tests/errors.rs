@@ -0,0 +1,80 @@
+//! How a machine reports what stopped it. The guest in `guests/unhandled.S`
+//! makes syscall 99, which doesn't exist, with the arguments 1 to 7, and if
+//! that returns, runs an illegal instruction at `illegal`.
+
+use std::error::Error;
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::abuse::{Abuse, AbuseHandler};
+use yove::xous::{MachineBuilder, MachineEvent};
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/unhandled.elf");
+
+/// The `unimp` instruction at `illegal`.
+const UNIMP: u32 = 0xc000_1073;
+
+#[test]
+fn unknown_syscalls_stop_the_machine() {
+    let error = MachineBuilder::new()
+        .build(PROGRAM)
+        .unwrap()
+        .run()
+        .unwrap_err();
+    let YoveError::Syscall { tid, number, args } = error else {
+        panic!("expected an unhandled syscall, got {:?}", error);
+    };
+    assert_eq!((0, 99), (tid, number));
+    assert_eq!([1, 2, 3, 4, 5, 6, 7], args);
+}
+
+#[test]
+fn unknown_syscalls_stop_a_stepped_machine() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    let error = loop {
+        match machine.step() {
+            Ok(MachineEvent::Exited(code)) => panic!("exited with {}", code),
+            Ok(_) => {}
+            Err(error) => break error,
+        }
+    };
+    assert!(matches!(error, YoveError::Syscall { number: 99, .. }));
+}
+
+#[test]
+fn unhandled_traps_say_where_they_were_taken() {
+    let mut machine = MachineBuilder::new()
+        .on_abuse(Abuse::UnknownSyscall, AbuseHandler::Warn)
+        .build(PROGRAM)
+        .unwrap();
+    let illegal = machine.symbol_address("illegal").unwrap();
+    let error = machine.run().unwrap_err();
+    let YoveError::Trap { tid, pc, trap } = &error else {
+        panic!("expected a trap, got {:?}", error);
+    };
+    assert_eq!((0, illegal), (*tid, *pc));
+    assert!(matches!(trap.trap_type, TrapType::IllegalInstruction));
+    assert_eq!(UNIMP, trap.value);
+
+    assert_eq!(
+        format!("thread 0 took an unhandled trap at pc {:08x}", illegal),
+        error.to_string()
+    );
+    let source = error.source().unwrap().to_string();
+    assert!(
+        source.ends_with(&format!("(value {:08x})", UNIMP)),
+        "{}",
+        source
+    );
+}
+
+#[test]
+fn load_failures_say_so() {
+    let error = MachineBuilder::new()
+        .build(b"not an ELF file")
+        .err()
+        .unwrap();
+    assert!(matches!(error, YoveError::Load(_)));
+    assert!(error.to_string().starts_with("couldn't load program: "));
+    assert!(error.source().is_some());
+}
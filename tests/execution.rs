@@ -0,0 +1,112 @@
+//! Execution recording and its exports. The guest in `guests/countdown.S`
+//! runs a few hundred instructions in one thread, of which only the last
+//! few are kept.
+
+use yove::xous::trace::parse_csr;
+use yove::xous::{LoadError, Machine, MachineBuilder};
+use yove::YoveError;
+
+const LIMIT: usize = 10;
+
+fn record() -> Machine {
+    let mut machine = MachineBuilder::new()
+        .record_execution(LIMIT, vec![parse_csr("sepc").unwrap()])
+        .build(include_bytes!("guests/countdown.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    machine
+}
+
+#[test]
+fn vcd_holds_the_last_instructions() {
+    let machine = record();
+    let mut output = vec![];
+    machine.tracer().unwrap().write_vcd(&mut output).unwrap();
+    let vcd = String::from_utf8(output).unwrap();
+
+    assert!(vcd.starts_with("$version yove $end\n$timescale 1ns $end\n"));
+    assert!(vcd.contains("$scope module thread_0 $end\n"));
+    assert!(vcd.contains(" pc $end\n"));
+    assert!(vcd.contains(" sepc $end\n"));
+    let (_, changes) = vcd.split_once("$enddefinitions $end\n").unwrap();
+
+    // One timestamp per instruction kept, counting every instruction run
+    let times: Vec<u64> = changes
+        .lines()
+        .filter_map(|line| line.strip_prefix('#')?.parse().ok())
+        .collect();
+    assert_eq!(LIMIT, times.len());
+    assert!(times[0] > LIMIT as u64);
+    assert!(times.windows(2).all(|pair| pair[1] == pair[0] + 1));
+
+    // Every signal gets a value at the first timestamp
+    let first = changes
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.starts_with('#'));
+    assert_eq!(3, first.count());
+}
+
+#[test]
+fn ctf_holds_the_last_instructions() {
+    let machine = record();
+    let directory = std::env::temp_dir().join(format!("yove-ctf-{}", std::process::id()));
+    machine.tracer().unwrap().write_ctf(&directory).unwrap();
+    let metadata = std::fs::read_to_string(directory.join("metadata")).unwrap();
+    let stream = std::fs::read(directory.join("stream_0")).unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert!(metadata.starts_with("/* CTF 1.8 */"));
+    assert!(metadata.contains("uint32_t sepc;"));
+    assert_eq!(0xc1fc_1fc1u32.to_le_bytes(), stream[..4]);
+
+    // Each instruction is an event id, timestamp, tid, pc, privilege, and sepc
+    let events = &stream[8..];
+    let size = 4 + 8 + 4 + 4 + 1 + 4;
+    assert_eq!(LIMIT * size, events.len());
+    let word = |at: usize| u32::from_le_bytes(events[at..at + 4].try_into().unwrap());
+    let timestamp = |at: usize| u64::from_le_bytes(events[at + 4..at + 12].try_into().unwrap());
+    for index in 0..LIMIT {
+        let at = index * size;
+        assert_eq!(0, word(at));
+        assert_eq!(timestamp(0) + index as u64, timestamp(at));
+        assert_eq!(0, word(at + 12));
+        assert!(word(at + 16) >= 0x2000_0000);
+    }
+}
+
+#[test]
+fn zero_limit_is_refused() {
+    let result = MachineBuilder::new()
+        .record_execution(0, vec![])
+        .build(include_bytes!("guests/countdown.elf"));
+    assert!(matches!(
+        result,
+        Err(YoveError::Load(LoadError::InvalidTraceLimit))
+    ));
+}
+
+#[test]
+fn text_shows_what_each_instruction_changed() {
+    let machine = record();
+    let tracer = machine.tracer().unwrap();
+    let mut output = vec![];
+    tracer.write_execution(&mut output).unwrap();
+    let text = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(LIMIT, lines.len());
+
+    // The first instruction kept is the `addi` that leaves one more to go,
+    // shown with every register that isn't zero
+    assert!(lines[0].starts_with("199 0 20000014 ra 00000000 -> 2000000c sp 00000000 -> "));
+    assert!(lines[0].contains(" a0 00000000 -> 00000001 "));
+    assert!(lines[0].ends_with(" sepc 00000000 -> 20000000"));
+    assert_eq!("200 0 20000016", lines[1]);
+    assert_eq!("201 0 20000014 a0 00000001 -> 00000000", lines[2]);
+    assert_eq!("205 0 2000000e t0 00000000 -> ff803000", lines[6]);
+
+    // Steps hold only the registers they changed
+    let steps = tracer.execution();
+    assert_eq!(vec![(10, 1, 0)], steps[2].registers);
+    assert!(steps[1].registers.is_empty());
+}
@@ -0,0 +1,17 @@
+//! Thread exit through the exit trampoline. The guest in `guests/exit.S`
+//! starts a thread that returns from its entry point and one that calls
+//! `ExitThread`, then returns from `_start`.
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn threads_exit_by_returning() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/exit.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let stats = machine.thread_stats();
+    assert_eq!(3, stats.len());
+    assert!(stats.iter().all(|stats| stats.exited));
+}
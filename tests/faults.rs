@@ -0,0 +1,123 @@
+//! Injecting errors into chosen syscalls. The guest in `guests/inject.S`
+//! maps a page and then 64KB, grows its heap three times, and exits with a
+//! bit set for each call that failed. The one in `guests/sendfault.S` sends
+//! the ticktimer a scalar and then a blocking scalar, and exits the same way,
+//! with the last error in bits 8 and up.
+
+use std::time::{Duration, Instant};
+
+use yove::xous::faults::{Fault, FaultRule, FaultSite};
+use yove::xous::MachineBuilder;
+
+const INJECT: &[u8] = include_bytes!("guests/inject.elf");
+const SENDFAULT: &[u8] = include_bytes!("guests/sendfault.elf");
+
+fn run_with(program: &[u8], builder: MachineBuilder, rule: &str) -> u32 {
+    let mut machine = builder.fault(rule.parse().unwrap()).build(program).unwrap();
+    machine.run().unwrap()
+}
+
+fn run(rule: &str) -> u32 {
+    run_with(INJECT, MachineBuilder::new(), rule)
+}
+
+#[test]
+fn rules_name_syscalls_and_errors() {
+    let rule: FaultRule = "IncreaseHeap,nth=2:error=OutOfMemory".parse().unwrap();
+    assert_eq!(FaultSite::Call(10), rule.site);
+    assert_eq!(Fault::Error(3), rule.fault);
+    assert_eq!(Some(2), rule.nth);
+    let rule: FaultRule = "MapMemory,size>0x1000:error=23".parse().unwrap();
+    assert_eq!(Fault::Error(23), rule.fault);
+    assert_eq!(Some(0x1000), rule.larger_than);
+    assert!("NoSuchCall:error=OutOfMemory".parse::<FaultRule>().is_err());
+    assert!("MapMemory:error=NoSuchError".parse::<FaultRule>().is_err());
+    assert!("MapMemory,nth=0:error=OutOfMemory"
+        .parse::<FaultRule>()
+        .is_err());
+}
+
+#[test]
+fn rules_name_sites_faults_and_timing() {
+    let rule: FaultRule = "send:queue-full:0.05".parse().unwrap();
+    assert_eq!(FaultSite::Send, rule.site);
+    assert_eq!(Fault::ServerQueueFull, rule.fault);
+    assert_eq!(0.05, rule.probability);
+    assert_eq!(0, rule.after_ms);
+    let rule: FaultRule = "syscall:delay=20:0.5:1000".parse().unwrap();
+    assert_eq!(FaultSite::Syscall, rule.site);
+    assert_eq!(Fault::Delay(20), rule.fault);
+    assert_eq!(1000, rule.after_ms);
+    let rule: FaultRule = "alloc:oom".parse().unwrap();
+    assert_eq!(Fault::OutOfMemory, rule.fault);
+    assert_eq!(1.0, rule.probability);
+    assert_eq!(Fault::Drop, "send:drop".parse::<FaultRule>().unwrap().fault);
+    assert!("alloc:queue-full".parse::<FaultRule>().is_err());
+    assert!("send:oom".parse::<FaultRule>().is_err());
+    assert!("send:drop:1.5".parse::<FaultRule>().is_err());
+    assert!("send:drop:1:soon".parse::<FaultRule>().is_err());
+    assert!("send:drop:1:0:extra".parse::<FaultRule>().is_err());
+}
+
+#[test]
+fn every_allocation_fails() {
+    assert_eq!(0b11111, run("alloc:oom"));
+    assert_eq!(
+        0,
+        run_with(INJECT, MachineBuilder::new(), "send:queue-full")
+    );
+}
+
+#[test]
+fn every_send_fails_with_a_full_queue() {
+    let full = run_with(SENDFAULT, MachineBuilder::new(), "send:queue-full");
+    assert_eq!(0b11, full & 0xff);
+    assert_eq!(15, full >> 8, "ServerQueueFull");
+    assert_eq!(0, run_with(SENDFAULT, MachineBuilder::new(), "alloc:oom"));
+}
+
+#[test]
+fn only_non_blocking_sends_are_dropped() {
+    // The blocking scalar would never be answered if it were dropped
+    assert_eq!(0, run_with(SENDFAULT, MachineBuilder::new(), "send:drop"));
+}
+
+#[test]
+fn delayed_responses_arrive_late() {
+    let start = Instant::now();
+    assert_eq!(
+        0,
+        run_with(SENDFAULT, MachineBuilder::new(), "send:delay=100")
+    );
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn rules_can_be_unlikely_or_late() {
+    assert_eq!(0, run("alloc:oom:0"));
+    assert_eq!(0, run("alloc:oom:1:3600000"));
+}
+
+#[test]
+fn the_seed_decides_which_calls_fail() {
+    let run_seeded = |seed| {
+        run_with(
+            INJECT,
+            MachineBuilder::new().fault_seed(seed),
+            "alloc:oom:0.5",
+        )
+    };
+    let results: Vec<u32> = (0..16).map(run_seeded).collect();
+    assert_eq!(results, (0..16).map(run_seeded).collect::<Vec<_>>());
+    assert!(results.iter().any(|&result| result != results[0]));
+}
+
+#[test]
+fn only_the_nth_call_fails() {
+    assert_eq!(0b01000, run("IncreaseHeap,nth=2:error=OutOfMemory"));
+}
+
+#[test]
+fn only_calls_over_the_size_fail() {
+    assert_eq!(0b00010, run("MapMemory,size>0x1000:error=OutOfMemory"));
+}
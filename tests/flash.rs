@@ -0,0 +1,123 @@
+//! Faults injected into the flash behind the `yove-flash` server. The guest in
+//! `guests/flash.S` commits a record and a marker to a journal sector and
+//! reads them back, exiting with the number of the first check that failed.
+//! A flash that isn't erased is handed to it to check erases that tear.
+
+use std::sync::Arc;
+
+use yove::xous::flash::{Flash, FlashFaultKind, InjectedFault, DEFAULT_FLASH_SIZE, SECTOR};
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/flash.elf");
+
+const JOURNAL: u32 = 0x10000;
+
+fn journal(flash: &yove::xous::flash::Flash) -> Vec<u8> {
+    let mut journal = vec![0; 20];
+    assert!(flash.read(JOURNAL, &mut journal));
+    journal
+}
+
+#[test]
+fn writes_reach_the_flash() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    assert_eq!(b"entry 0001: 4242DONE", &journal(&machine.flash())[..]);
+    assert!(machine.flash().injected().is_empty());
+    // Only the journal's sector holds anything
+    assert_eq!((JOURNAL + SECTOR) as usize, machine.flash().image().len());
+}
+
+#[test]
+fn power_loss_stops_the_machine_and_survives_a_reboot() {
+    let mut machine = MachineBuilder::new()
+        .flash_fault("program:power-loss@0x10008".parse().unwrap())
+        .build(PROGRAM)
+        .unwrap();
+    let Err(YoveError::PowerLoss { offset }) = machine.run() else {
+        panic!("the machine kept running without power");
+    };
+    assert_eq!(JOURNAL + 8, offset);
+    let flash = machine.flash();
+    assert_eq!(
+        b"entry 00\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff",
+        &journal(&flash)[..]
+    );
+
+    // The half-written record is still there when the device comes back up
+    let mut machine = MachineBuilder::new().flash(flash).build(PROGRAM).unwrap();
+    assert_eq!(b"entry 00", &journal(&machine.flash())[..8]);
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn torn_writes_report_success() {
+    let mut machine = MachineBuilder::new()
+        .flash_fault("program:torn-write@0x10004".parse().unwrap())
+        .build(PROGRAM)
+        .unwrap();
+    // The record reads back wrong, but the marker after it was written
+    assert_eq!(4, machine.run().unwrap());
+    assert_eq!(
+        b"entr\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xffDONE",
+        &journal(&machine.flash())[..]
+    );
+    assert_eq!(
+        vec![InjectedFault {
+            kind: FlashFaultKind::TornWrite,
+            offset: JOURNAL + 4
+        }],
+        machine.flash().injected()
+    );
+}
+
+#[test]
+fn bit_flips_corrupt_one_byte() {
+    let mut machine = MachineBuilder::new()
+        .flash_fault("program:bit-flip=2@0x10011".parse().unwrap())
+        .build(PROGRAM)
+        .unwrap();
+    assert_eq!(5, machine.run().unwrap());
+    assert_eq!(b"entry 0001: 4242DKNE", &journal(&machine.flash())[..]);
+}
+
+#[test]
+fn random_faults_repeat_with_the_same_seed() {
+    let injected = || {
+        let mut machine = MachineBuilder::new()
+            .flash_fault("bit-flip".parse().unwrap())
+            .fault_seed(7)
+            .build(PROGRAM)
+            .unwrap();
+        machine.run().unwrap();
+        machine.flash().injected()
+    };
+    let first = injected();
+    assert!(!first.is_empty());
+    assert_eq!(first, injected());
+}
+
+#[test]
+fn torn_erases_leave_the_rest_of_the_sector() {
+    let flash = Flash::new(DEFAULT_FLASH_SIZE);
+    assert!(flash.load(JOURNAL, &[0; SECTOR as usize]));
+    let mut machine = MachineBuilder::new()
+        .flash(Arc::new(flash))
+        .flash_fault("erase:torn-write@0x10010".parse().unwrap())
+        .build(PROGRAM)
+        .unwrap();
+    // The marker is programmed over bytes that were never erased
+    assert_eq!(5, machine.run().unwrap());
+    let flash = machine.flash();
+    assert_eq!(b"entry 0001: 4242\0\0\0\0", &journal(&flash)[..]);
+    let mut last = [0xff];
+    assert!(flash.read(JOURNAL + SECTOR - 1, &mut last));
+    assert_eq!([0], last);
+}
+
+#[test]
+fn sizes_are_whole_sectors() {
+    assert_eq!(2 * SECTOR, Flash::new(SECTOR + 1).size());
+    assert_eq!(u32::MAX / SECTOR * SECTOR, Flash::new(u32::MAX).size());
+}
@@ -0,0 +1,54 @@
+//! Display tests. The guest in `guests/framebuffer.S` maps the display and
+//! lights two pixels, exiting with the number of the first check that failed.
+//! The one in `guests/fbremap.S` maps it, unmaps it, and maps it again.
+
+use yove::xous::{framebuffer, LoadError, MachineBuilder};
+use yove::YoveError;
+
+#[test]
+fn screenshot_shows_guest_pixels() {
+    let mut machine = MachineBuilder::new()
+        .framebuffer()
+        .build(include_bytes!("guests/framebuffer.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let screenshot = machine.screenshot().unwrap();
+    assert_eq!(
+        (framebuffer::WIDTH, framebuffer::HEIGHT),
+        (screenshot.width, screenshot.height)
+    );
+    assert!(screenshot.pixel(0, 0));
+    assert!(screenshot.pixel(framebuffer::WIDTH - 1, 10));
+    assert!(!screenshot.pixel(1, 0));
+
+    let blank = framebuffer::Screenshot::new(
+        screenshot.width,
+        screenshot.height,
+        vec![false; (screenshot.width * screenshot.height) as usize],
+    );
+    assert_eq!(Some(2), screenshot.diff(&blank));
+}
+
+#[test]
+fn display_can_be_mapped_again_after_unmapping() {
+    let mut machine = MachineBuilder::new()
+        .framebuffer()
+        .build(include_bytes!("guests/fbremap.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let screenshot = machine.screenshot().unwrap();
+    assert!(screenshot.pixel(1, 0));
+}
+
+#[test]
+fn a_zero_screenshot_interval_is_refused() {
+    let result = MachineBuilder::new()
+        .screenshot_interval(0)
+        .build(include_bytes!("guests/framebuffer.elf"));
+    assert!(matches!(
+        result,
+        Err(YoveError::Load(LoadError::InvalidScreenshotInterval))
+    ));
+}
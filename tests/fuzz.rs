@@ -0,0 +1,24 @@
+//! Inputs with which the syscall fuzz target in `/fuzz` found bugs in the
+//! shim, replayed to make sure they stay fixed. Each file in `fuzz/` is one
+//! input, named after what it found.
+
+use yove::xous::{fuzz, MachineBuilder};
+
+#[test]
+fn fuzzed_syscalls_stay_fixed() {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fuzz");
+    let mut replayed = 0;
+    for entry in std::fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        let machine = MachineBuilder::new()
+            .memory_size(1024 * 1024)
+            .build(include_bytes!("guests/exit.elf"))
+            .unwrap();
+        let input = std::fs::read(&path).unwrap();
+        if let Err(error) = fuzz::run(&machine, &input) {
+            panic!("{}: {}", path.display(), error);
+        }
+        replayed += 1;
+    }
+    assert!(replayed > 0);
+}
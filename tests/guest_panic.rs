@@ -0,0 +1,58 @@
+//! Catching guest panics. The guest in `guests/guestpanic.S` sends a message
+//! to the panic-to-screen server from `report`, then calls `rust_panic`,
+//! which terminates the process with 101.
+
+use yove::xous::guest_panic::GuestPanic;
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+fn caught(builder: MachineBuilder) -> GuestPanic {
+    let mut machine = builder
+        .build(include_bytes!("guests/guestpanic.elf"))
+        .unwrap();
+    match machine.run() {
+        Err(YoveError::GuestPanic(panic)) => *panic,
+        result => panic!("expected a guest panic, got {:?}", result),
+    }
+}
+
+fn functions(panic: &GuestPanic) -> Vec<&str> {
+    panic
+        .backtrace
+        .iter()
+        .map(|frame| {
+            frame
+                .function
+                .as_ref()
+                .map_or("?", |(name, _)| name.as_str())
+        })
+        .collect()
+}
+
+#[test]
+fn panics_are_caught_entering_rust_panic() {
+    let panic = caught(
+        MachineBuilder::new()
+            .catch_guest_panics(vec!["rust_panic".to_owned()])
+            .record_execution(100, vec![]),
+    );
+    assert_eq!("index out of bounds", panic.message);
+    assert_eq!(vec!["rust_panic", "report", "_start"], functions(&panic));
+    assert_eq!(panic.pc, panic.recent.last().unwrap().pc + 4);
+}
+
+#[test]
+fn panics_are_caught_terminating_after_panic_text() {
+    let panic = caught(MachineBuilder::new().catch_guest_panics(vec![]));
+    assert_eq!("index out of bounds", panic.message);
+    assert_eq!(vec!["rust_panic", "report", "_start"], functions(&panic));
+    assert!(panic.recent.is_empty());
+}
+
+#[test]
+fn panics_only_exit_unless_caught() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/guestpanic.elf"))
+        .unwrap();
+    assert_eq!(101, machine.run().unwrap());
+}
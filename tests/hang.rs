@@ -0,0 +1,48 @@
+//! Stopping a program whose thread waits too long on a service. The guest in
+//! `guests/hang.S` waits on a ticktimer condition that's never notified.
+
+use yove::xous::{MachineBuilder, MachineEvent};
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/hang.elf");
+
+fn check(error: YoveError) {
+    let YoveError::Hang {
+        timeout_ms,
+        tid,
+        service,
+        opcode,
+        ..
+    } = error
+    else {
+        panic!("expected a hang, got {}", error);
+    };
+    assert_eq!(50, timeout_ms);
+    assert_eq!(0, tid);
+    assert_eq!("server \"ticktimer-server\"", service);
+    assert_eq!(8, opcode);
+}
+
+#[test]
+fn run_stops_threads_that_wait_too_long() {
+    let mut machine = MachineBuilder::new()
+        .response_timeout(50)
+        .build(PROGRAM)
+        .unwrap();
+    check(machine.run().unwrap_err());
+}
+
+#[test]
+fn step_stops_threads_that_wait_too_long() {
+    let mut machine = MachineBuilder::new()
+        .response_timeout(50)
+        .build(PROGRAM)
+        .unwrap();
+    loop {
+        match machine.step() {
+            Ok(MachineEvent::Exited(code)) => panic!("exited with {}", code),
+            Ok(_) => std::thread::sleep(std::time::Duration::from_millis(1)),
+            Err(error) => return check(error),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Running guest threads on a fixed number of harts. The guest in
+//! `guests/harts.S` starts 100 threads that each bump two shared counters
+//! many times, one with AMOs and one with LR/SC, and joins them all.
+
+use std::time::Duration;
+
+use riscv_cpu::cpu::MIP_SEIP;
+use yove::xous::notify::Notification;
+use yove::xous::{LoadError, MachineBuilder};
+use yove::YoveError;
+
+fn run_on(harts: usize) -> u32 {
+    MachineBuilder::new()
+        .harts(harts)
+        .build(include_bytes!("guests/harts.elf"))
+        .unwrap()
+        .run()
+        .unwrap()
+}
+
+#[test]
+fn one_hart_runs_every_thread() {
+    assert_eq!(0, run_on(1));
+}
+
+#[test]
+fn threads_share_several_harts() {
+    assert_eq!(0, run_on(4));
+}
+
+#[test]
+fn threads_in_wfi_wake_on_harts() {
+    let mut machine = MachineBuilder::new()
+        .harts(2)
+        .build(include_bytes!("guests/notify.elf"))
+        .unwrap();
+    let notifier = machine.notifier();
+    let device = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        for opcode in 1..=2 {
+            notifier.post(Notification {
+                opcode,
+                args: [5, 6, 7, 8],
+            });
+        }
+        notifier.raise(MIP_SEIP);
+    });
+    assert_eq!(0, machine.run().unwrap());
+    device.join().unwrap();
+}
+
+#[test]
+fn zero_harts_is_an_error() {
+    assert!(matches!(
+        MachineBuilder::new()
+            .harts(0)
+            .build(include_bytes!("guests/harts.elf")),
+        Err(YoveError::Load(LoadError::NoHarts))
+    ));
+}
@@ -0,0 +1,85 @@
+//! Page access counts. The guest in `guests/lrsc.S` runs its code from one
+//! page, and two threads read and write a word on another.
+
+use yove::xous::heatmap::HeatmapFormat;
+use yove::xous::MachineBuilder;
+
+const PROGRAM: &[u8] = include_bytes!("guests/lrsc.elf");
+
+/// Run the guest with the heatmap enabled and write it out in `format`.
+fn heatmap(format: HeatmapFormat) -> String {
+    let mut machine = MachineBuilder::new().heatmap().build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let mut output = vec![];
+    machine.write_heatmap(format, &mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn formats_come_from_the_extension() {
+    assert_eq!(
+        HeatmapFormat::Csv,
+        HeatmapFormat::from_path("pages.csv").unwrap()
+    );
+    assert_eq!(
+        HeatmapFormat::Json,
+        HeatmapFormat::from_path("out/pages.json").unwrap()
+    );
+    assert!(HeatmapFormat::from_path("pages.txt").is_err());
+    assert!(HeatmapFormat::from_path("pages").is_err());
+}
+
+#[test]
+fn csv_counts_each_kind_of_access() {
+    let csv = heatmap(HeatmapFormat::Csv);
+    let mut lines = csv.lines();
+    assert_eq!(
+        Some("physical,virtual,reads,writes,executes,threads"),
+        lines.next()
+    );
+    let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+    let count = |row: &[&str], column: usize| row[column].parse::<u64>().unwrap();
+
+    let code = rows.iter().find(|row| row[1] == "20000000").unwrap();
+    assert!(count(code, 4) > 0);
+    assert_eq!((0, 0), (count(code, 2), count(code, 3)));
+
+    let data = rows.iter().find(|row| row[1] == "20001000").unwrap();
+    assert!(count(data, 2) > 0);
+    assert!(count(data, 3) > 0);
+    assert_eq!(0, count(data, 4));
+    assert_eq!(2, count(data, 5));
+}
+
+#[test]
+fn json_summarizes_the_working_set() {
+    let json = heatmap(HeatmapFormat::Json);
+    let pages = json.matches("\"physical\"").count();
+    assert!(pages >= 2);
+    assert!(json.contains(&format!("\"working_set_pages\": {},", pages)));
+    assert!(json.contains(&format!("\"working_set_bytes\": {},", pages * 4096)));
+    assert!(json.contains("\"shared_written_pages\": 1"));
+}
+
+#[cfg(feature = "png")]
+#[test]
+fn png_is_an_image() {
+    let mut machine = MachineBuilder::new().heatmap().build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let mut output = vec![];
+    machine
+        .write_heatmap(HeatmapFormat::Png, &mut output)
+        .unwrap();
+    assert!(output.starts_with(b"\x89PNG\r\n\x1a\n"));
+}
+
+#[test]
+fn nothing_is_written_without_the_heatmap() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let mut output = vec![];
+    machine
+        .write_heatmap(HeatmapFormat::Csv, &mut output)
+        .unwrap();
+    assert!(output.is_empty());
+}
@@ -0,0 +1,34 @@
+//! Compressed HINTs. The guest in `guests/hints.S` runs `c.li x0, 1` two
+//! bytes into `_start` and exits with 0.
+
+use riscv_cpu::cpu::{HintPolicy, TrapType};
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+#[test]
+fn hints_are_no_ops_unless_strict() {
+    for policy in [HintPolicy::Ignore, HintPolicy::Report] {
+        let mut machine = MachineBuilder::new()
+            .compressed_hints(policy)
+            .build(include_bytes!("guests/hints.elf"))
+            .unwrap();
+        assert_eq!(0, machine.run().unwrap());
+    }
+}
+
+#[test]
+fn strict_hints_are_illegal() {
+    let mut machine = MachineBuilder::new()
+        .compressed_hints(HintPolicy::Trap)
+        .build(include_bytes!("guests/hints.elf"))
+        .unwrap();
+    let start = machine.symbol_address("_start").unwrap();
+    match machine.run() {
+        Err(YoveError::Trap { pc, trap, .. }) => {
+            assert_eq!(TrapType::IllegalInstruction, trap.trap_type);
+            assert_eq!(0x4005, trap.value);
+            assert_eq!(start + 2, pc);
+        }
+        result => panic!("expected an illegal instruction, got {:?}", result),
+    }
+}
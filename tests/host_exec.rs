@@ -0,0 +1,73 @@
+//! Running host commands for the guest. The guest in `guests/hostexec.S`
+//! runs `echo hello` and `false`, which are allowed, and `sh -c true`, which
+//! isn't. The one in `guests/lendgone.S` makes the page it lent for a command
+//! read-only before the command finishes.
+#![cfg(unix)]
+
+use yove::xous::abuse::{Abuse, AbuseHandler};
+use yove::xous::host_exec::{ExecOutcome, ExecRecord};
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/hostexec.elf");
+
+fn argv(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn allowed_commands_run_and_the_rest_are_refused() {
+    let mut machine = MachineBuilder::new()
+        .allow_host_exec("echo", "/bin/echo")
+        .allow_host_exec("false", "/bin/false")
+        .build(PROGRAM)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    assert_eq!(
+        vec![
+            ExecRecord {
+                tid: 0,
+                argv: argv(&["echo", "hello"]),
+                outcome: ExecOutcome::Exited(Some(0)),
+            },
+            ExecRecord {
+                tid: 0,
+                argv: argv(&["false"]),
+                outcome: ExecOutcome::Exited(Some(1)),
+            },
+            ExecRecord {
+                tid: 0,
+                argv: argv(&["sh", "-c", "true"]),
+                outcome: ExecOutcome::Refused,
+            },
+        ],
+        machine.host_exec().unwrap().audit()
+    );
+}
+
+#[test]
+fn the_server_only_exists_when_something_is_allowed() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    assert_eq!(2, machine.run().unwrap());
+    assert!(machine.host_exec().is_none());
+}
+
+#[test]
+fn output_for_a_page_taken_away_meanwhile_is_dropped() {
+    const LEND_GONE: &[u8] = include_bytes!("guests/lendgone.elf");
+    let mut machine = MachineBuilder::new()
+        .allow_host_exec("sh", "/bin/sh")
+        .build(LEND_GONE)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let mut machine = MachineBuilder::new()
+        .allow_host_exec("sh", "/bin/sh")
+        .on_abuse(Abuse::UnmappedLend, AbuseHandler::KillProcess)
+        .build(LEND_GONE)
+        .unwrap();
+    match machine.run() {
+        Err(YoveError::Abuse { abuse, .. }) => assert_eq!(Abuse::UnmappedLend, abuse),
+        result => panic!("expected the process to end, got {:?}", result),
+    }
+}
@@ -0,0 +1,97 @@
+//! Requests made through the hypercall CSR. The guest in
+//! `guests/hypercall.S` makes each one once, with coverage around a call to
+//! `covered` but not to `uncovered`, and then one that isn't understood,
+//! keeping what it read back in `unknown`.
+
+use yove::xous::trace::{Hypercall, TraceEvent, HYPERCALL_OK, HYPERCALL_UNKNOWN};
+use yove::xous::{Machine, MachineBuilder};
+
+const PROGRAM: &[u8] = include_bytes!("guests/hypercall.elf");
+
+fn run(builder: MachineBuilder) -> Machine {
+    let mut machine = builder.build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    machine
+}
+
+/// What the guest read back from its last hypercall.
+fn unknown(machine: &Machine) -> u32 {
+    let address = machine.symbol_address("unknown").unwrap();
+    let word = machine.read_memory(address, 4).unwrap();
+    u32::from_le_bytes(word.try_into().unwrap())
+}
+
+#[test]
+fn values_decode_into_an_action_and_an_argument() {
+    assert_eq!(Some(Hypercall::Marker(42)), Hypercall::decode(42 << 8));
+    assert_eq!(Some(Hypercall::DumpRegisters), Hypercall::decode(1));
+    assert_eq!(Some(Hypercall::Snapshot), Hypercall::decode(2));
+    assert_eq!(Some(Hypercall::CoverageStart), Hypercall::decode(3));
+    assert_eq!(Some(Hypercall::CoverageStop), Hypercall::decode(0x100 | 4));
+    assert_eq!(None, Hypercall::decode(0xff));
+}
+
+#[test]
+fn each_request_is_recorded() {
+    let machine = run(MachineBuilder::new().trace());
+    let tracer = machine.tracer().unwrap();
+    let events: Vec<TraceEvent> = tracer
+        .records()
+        .into_iter()
+        .map(|record| {
+            assert_eq!(0, record.tid);
+            record.event
+        })
+        .collect();
+    assert_eq!(5, events.len());
+    assert_eq!(TraceEvent::Marker(42), events[0]);
+    let TraceEvent::Registers(registers) = events[1] else {
+        panic!("expected registers, got {:?}", events[1]);
+    };
+    assert_eq!(0x1234, registers[19], "s3");
+    assert_eq!(
+        &[
+            TraceEvent::SnapshotRequested,
+            TraceEvent::CoverageStarted,
+            TraceEvent::CoverageStopped
+        ],
+        &events[2..]
+    );
+    assert_eq!(HYPERCALL_UNKNOWN, unknown(&machine));
+}
+
+#[test]
+fn coverage_only_records_while_enabled() {
+    let machine = run(MachineBuilder::new().trace());
+    let coverage = machine.tracer().unwrap().coverage();
+    let covered = machine.symbol_address("covered").unwrap();
+    let uncovered = machine.symbol_address("uncovered").unwrap();
+    assert!(coverage.contains(&covered));
+    assert!(!coverage.contains(&uncovered));
+}
+
+#[test]
+fn the_trace_is_written_one_line_per_event() {
+    let machine = run(MachineBuilder::new().trace());
+    let mut output = vec![];
+    machine.tracer().unwrap().write(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let events: Vec<&str> = output
+        .lines()
+        .filter_map(|line| line.splitn(4, ' ').nth(3))
+        .collect();
+    assert!(events.contains(&"marker 42"));
+    assert!(events.iter().any(|event| event.contains(" s3=00001234 ")));
+    assert!(events.contains(&"snapshot"));
+    assert!(events.contains(&"coverage-start"));
+    assert!(events.contains(&"coverage-stop"));
+    let covered = machine.symbol_address("covered").unwrap();
+    assert!(output.contains(&format!("covered {:08x}\n", covered)));
+}
+
+#[test]
+fn hypercalls_are_ignored_without_tracing() {
+    let machine = run(MachineBuilder::new());
+    assert!(machine.tracer().is_none());
+    assert_eq!(HYPERCALL_OK, unknown(&machine));
+}
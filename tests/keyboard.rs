@@ -0,0 +1,60 @@
+//! Keys pressed by the host and by key scripts. The guest in `guests/keys.S`
+//! prints "Ready", then reads keys until enter and exits with a hash of them.
+
+use yove::xous::keyboard::KeyScript;
+use yove::xous::{Machine, MachineBuilder, MachineEvent};
+
+fn machine(builder: MachineBuilder) -> Machine {
+    builder.build(include_bytes!("guests/keys.elf")).unwrap()
+}
+
+/// The guest's hash of the keys in `text`.
+fn hash(text: &str) -> u32 {
+    text.chars().fold(0u32, |hash, key| {
+        hash.wrapping_mul(31).wrapping_add(key as u32)
+    })
+}
+
+#[test]
+fn the_host_can_press_keys() {
+    let mut machine = machine(MachineBuilder::new());
+    machine.keyboard().type_text("ok");
+    machine.keyboard().press('\r');
+    assert_eq!(hash("ok"), machine.run().unwrap());
+}
+
+#[test]
+fn a_script_types_once_the_program_is_ready() {
+    let script = KeyScript::parse(
+        "# Hold everything until the guest is listening\n\
+         wait-log Ready\n\
+         pace 2\n\
+         type hi, you # all of it\n\
+         key up select enter\n",
+    )
+    .unwrap();
+    let mut machine = machine(MachineBuilder::new().key_script(script));
+    assert_eq!(hash("hi, you # all of it↑∴"), machine.run().unwrap());
+    assert_eq!(0, machine.keyboard().script_steps_left());
+}
+
+#[test]
+fn a_script_waits_for_the_log_message() {
+    let script = KeyScript::parse("wait-log Never\nkey enter\n").unwrap();
+    let mut machine = machine(MachineBuilder::new().key_script(script));
+    while machine.step().unwrap() != MachineEvent::Idle {}
+    for _ in 0..10 {
+        machine.step().unwrap();
+    }
+    assert_eq!(2, machine.keyboard().script_steps_left());
+    assert_eq!(0, machine.keyboard().pending());
+}
+
+#[test]
+fn bad_scripts_are_refused() {
+    assert!(KeyScript::parse("key enter f1 x").is_ok());
+    assert!(KeyScript::parse("key shift").is_err());
+    assert!(KeyScript::parse("sleep soon").is_err());
+    assert!(KeyScript::parse("@later key enter").is_err());
+    assert!(KeyScript::parse("press enter").is_err());
+}
@@ -0,0 +1,49 @@
+//! Layout randomization. The guest in `guests/layout.S` grows the heap by a
+//! page and maps a page of memory.
+
+use yove::xous::{MachineBuilder, Mapping};
+
+const HEAP_WINDOW: std::ops::Range<u32> = 0xa000_0000..0xa050_0000;
+const ALLOCATION_WINDOW: std::ops::Range<u32> = 0x4000_0000..0x4050_0000;
+
+/// Where the heap page and the mapped page ended up.
+fn layout(builder: MachineBuilder) -> (u32, u32) {
+    let mut machine = builder.build(include_bytes!("guests/layout.elf")).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let mappings = machine.mappings();
+    let find = |window: std::ops::Range<u32>| {
+        let found: Vec<&Mapping> = mappings
+            .iter()
+            .filter(|mapping| window.contains(&mapping.virt))
+            .collect();
+        assert_eq!(1, found.len(), "{:x?}", mappings);
+        found[0].virt
+    };
+    (find(HEAP_WINDOW), find(ALLOCATION_WINDOW))
+}
+
+#[test]
+fn default_layout_is_fixed() {
+    assert_eq!((0xa000_0000, 0x4000_0000), layout(MachineBuilder::new()));
+}
+
+#[test]
+fn seeded_layout_is_reproducible() {
+    let first = layout(MachineBuilder::new().layout_seed(1234));
+    assert_eq!(first, layout(MachineBuilder::new().layout_seed(1234)));
+    assert_ne!(first, layout(MachineBuilder::new().layout_seed(5678)));
+    assert_ne!((0xa000_0000, 0x4000_0000), first);
+}
+
+#[test]
+fn run_seed_decides_the_layout() {
+    let first = layout(MachineBuilder::new().seed(1234).randomize_layout());
+    assert_eq!(
+        first,
+        layout(MachineBuilder::new().seed(1234).randomize_layout())
+    );
+    assert_ne!(
+        first,
+        layout(MachineBuilder::new().seed(5678).randomize_layout())
+    );
+}
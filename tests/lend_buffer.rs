@@ -0,0 +1,53 @@
+//! Tracking what a service writes to lent memory, so that only those bytes
+//! are copied back to the sender, whether the service replies right away or
+//! later.
+
+use yove::xous::LendBuffer;
+
+fn writes(buf: &LendBuffer) -> Vec<(usize, Vec<u8>)> {
+    buf.writes().map(|(at, data)| (at, data.to_vec())).collect()
+}
+
+#[test]
+fn writes_are_recorded_in_order() {
+    let mut buf = LendBuffer::new(vec![0; 8]);
+    assert!(writes(&buf).is_empty());
+    buf.write(4, b"ab").unwrap();
+    buf.get_mut(0..2).unwrap().copy_from_slice(b"xy");
+    buf.write(5, b"c").unwrap();
+    assert_eq!(b"xy\0\0ac\0\0", &buf[..]);
+    // The earlier write reads back as the buffer is now
+    assert_eq!(
+        vec![(4, b"ac".to_vec()), (0, b"xy".to_vec()), (5, b"c".to_vec())],
+        writes(&buf)
+    );
+}
+
+#[test]
+fn writes_outside_the_buffer_change_nothing() {
+    let mut buf = LendBuffer::new(vec![1; 4]);
+    assert!(buf.write(2, b"abc").is_none());
+    assert!(buf.write(usize::MAX, b"a").is_none());
+    let (start, end) = (3, 1);
+    assert!(buf.get_mut(start..end).is_none());
+    assert!(buf.get_mut(0..5).is_none());
+    assert_eq!([1; 4], &buf[..]);
+    assert!(writes(&buf).is_empty());
+}
+
+#[test]
+fn updates_only_count_the_bytes_that_change() {
+    let mut buf = LendBuffer::new(b"unchanged bytes".to_vec());
+    buf.update(b"unchanGED bytes and more");
+    assert_eq!(b"unchanGED bytes", &buf[..]);
+    assert_eq!(vec![(6, b"GED".to_vec())], writes(&buf));
+
+    let mut buf = LendBuffer::new(b"abcdef".to_vec());
+    buf.update(b"Xbc");
+    assert_eq!(b"Xbcdef", &buf[..]);
+    assert_eq!(vec![(0, b"X".to_vec())], writes(&buf));
+
+    let mut buf = LendBuffer::new(b"same".to_vec());
+    buf.update(b"same");
+    assert!(writes(&buf).is_empty());
+}
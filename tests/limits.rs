@@ -0,0 +1,56 @@
+//! Limiting the RAM and threads a guest may use at once. The guest in
+//! `guests/bigmem.S` maps 64MB, and the one in `guests/harts.S` starts 100
+//! threads.
+
+use yove::xous::{LoadError, MachineBuilder};
+use yove::YoveError;
+
+const BIGMEM: &[u8] = include_bytes!("guests/bigmem.elf");
+const HARTS: &[u8] = include_bytes!("guests/harts.elf");
+
+#[test]
+fn allocations_past_the_memory_limit_fail() {
+    let mut machine = MachineBuilder::new()
+        .memory_size(512 * 1024 * 1024)
+        .memory_limit(32 * 1024 * 1024)
+        .build(BIGMEM)
+        .unwrap();
+    // The mapping fails with OutOfMemory rather than taking the memory
+    assert_eq!(1, machine.run().unwrap());
+}
+
+#[test]
+fn allocations_within_the_memory_limit_succeed() {
+    let mut machine = MachineBuilder::new()
+        .memory_size(512 * 1024 * 1024)
+        .memory_limit(128 * 1024 * 1024)
+        .build(BIGMEM)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn programs_that_dont_fit_in_the_memory_limit_dont_load() {
+    // Two pages: the root page table and one more
+    let result = MachineBuilder::new().memory_limit(8192).build(BIGMEM);
+    assert!(matches!(
+        result,
+        Err(YoveError::Load(LoadError::OutOfMemory(_)))
+    ));
+}
+
+#[test]
+fn threads_past_the_thread_limit_fail() {
+    let mut machine = MachineBuilder::new().thread_limit(1).build(HARTS).unwrap();
+    // The first CreateThread fails, as the main thread is already running
+    assert_eq!(1, machine.run().unwrap());
+}
+
+#[test]
+fn threads_within_the_thread_limit_start() {
+    let mut machine = MachineBuilder::new()
+        .thread_limit(101)
+        .build(HARTS)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
@@ -0,0 +1,172 @@
+//! ELF validation and the metadata the loader exposes, using the guest from
+//! `guests/uninit.S` with its headers patched, run where the patched program
+//! still loads, and the guest from `guests/tls.S`, whose `.tbss` shares its
+//! addresses with `.data`.
+
+use yove::xous::{LoadError, MachineBuilder};
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/uninit.elf");
+
+/// Offset of `e_type` in a 32-bit ELF header.
+const E_TYPE: usize = 16;
+
+/// Offset of `e_machine` in a 32-bit ELF header.
+const E_MACHINE: usize = 18;
+
+fn load(program: &[u8]) -> Result<yove::xous::Machine, YoveError> {
+    MachineBuilder::new().build(program)
+}
+
+#[test]
+fn program_info_describes_the_program() {
+    let machine = load(PROGRAM).unwrap();
+    let info = machine.program_info();
+    assert_eq!(0x2000_0000, info.entry);
+    assert!(info.compressed());
+
+    let text = info.sections.iter().find(|s| s.name == ".text").unwrap();
+    assert_eq!(0x2000_0000, text.address);
+    assert!(text.executable && !text.writable && !text.zeroed);
+    let bss = info.sections.iter().find(|s| s.name == ".bss").unwrap();
+    assert!(bss.writable && bss.zeroed);
+
+    assert_eq!(2, info.segments.len());
+    let (code, data) = (&info.segments[0], &info.segments[1]);
+    assert_eq!(0x2000_0000, code.address);
+    assert!(code.readable && code.executable && !code.writable);
+    assert_eq!([".text"], &code.sections[..]);
+    assert!(data.writable && !data.executable);
+    assert_eq!(data.file_size + 4, data.size);
+    assert_eq!([".data", ".bss"], &data.sections[..]);
+}
+
+#[test]
+fn tbss_takes_no_memory() {
+    let mut machine = load(include_bytes!("guests/tls.elf")).unwrap();
+    let info = machine.program_info();
+    assert!(info.sections.iter().any(|s| s.name == ".tdata"));
+    assert!(!info.sections.iter().any(|s| s.name == ".tbss"));
+    let data = info.segments.iter().find(|s| s.address == 0x2000_1000);
+    assert_eq!([".data"], &data.unwrap().sections[..]);
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn rejects_other_machines() {
+    let mut program = PROGRAM.to_vec();
+    // EM_386
+    program[E_MACHINE..E_MACHINE + 2].copy_from_slice(&3u16.to_le_bytes());
+    assert!(matches!(
+        load(&program),
+        Err(YoveError::Load(LoadError::WrongMachine(3)))
+    ));
+
+    let machine = MachineBuilder::new()
+        .allow_any_machine()
+        .build(&program)
+        .unwrap();
+    assert_eq!(0x2000_0000, machine.program_info().entry);
+}
+
+#[test]
+fn rejects_big_endian_programs() {
+    let mut program = PROGRAM.to_vec();
+    // EI_DATA = ELFDATA2MSB
+    program[5] = 2;
+    assert!(matches!(
+        load(&program),
+        Err(YoveError::Load(LoadError::BigEndian))
+    ));
+}
+
+#[test]
+fn rejects_position_independent_programs() {
+    let mut program = PROGRAM.to_vec();
+    // ET_DYN
+    program[E_TYPE..E_TYPE + 2].copy_from_slice(&3u16.to_le_bytes());
+    assert!(matches!(
+        load(&program),
+        Err(YoveError::Load(LoadError::NotExecutable(3)))
+    ));
+}
+
+/// The offset of the header of the section loaded at `address` in
+/// `program`.
+fn section_header(program: &[u8], address: u32) -> usize {
+    let word =
+        |program: &[u8], at: usize| u32::from_le_bytes(program[at..at + 4].try_into().unwrap());
+    let shoff = word(program, 0x20) as usize;
+    let shentsize = u16::from_le_bytes([program[0x2e], program[0x2f]]) as usize;
+    let shnum = u16::from_le_bytes([program[0x30], program[0x31]]) as usize;
+    (0..shnum)
+        .map(|index| shoff + index * shentsize)
+        // sh_addr
+        .find(|at| word(program, at + 12) == address)
+        .unwrap_or_else(|| panic!("no section at {:08x}", address))
+}
+
+/// Move the section loaded at `from` to `to` in `program`'s section headers.
+fn move_section(program: &mut [u8], from: u32, to: u32) {
+    let at = section_header(program, from) + 12;
+    program[at..at + 4].copy_from_slice(&to.to_le_bytes());
+}
+
+#[test]
+fn only_nobits_sections_are_zeroed() {
+    let machine = load(PROGRAM).unwrap();
+    let data = machine
+        .program_info()
+        .sections
+        .iter()
+        .find(|s| s.name == ".data")
+        .unwrap()
+        .clone();
+    let mut program = PROGRAM.to_vec();
+    // sh_type = SHT_INIT_ARRAY, which shares a bit with SHT_NOBITS
+    let at = section_header(&program, data.address) + 4;
+    program[at..at + 4].copy_from_slice(&14u32.to_le_bytes());
+    let mut machine = load(&program).unwrap();
+    let data = machine
+        .program_info()
+        .sections
+        .iter()
+        .find(|s| s.name == ".data")
+        .unwrap();
+    assert!(!data.zeroed);
+    // The program checks that `.data` holds what it was built with.
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn rejects_overlapping_sections() {
+    let machine = load(PROGRAM).unwrap();
+    let sections = &machine.program_info().sections;
+    let text = sections.iter().find(|s| s.name == ".text").unwrap();
+    let bss = sections.iter().find(|s| s.name == ".bss").unwrap();
+    let mut program = PROGRAM.to_vec();
+    move_section(&mut program, bss.address, text.address + 4);
+    assert!(matches!(
+        load(&program),
+        Err(YoveError::Load(LoadError::OverlappingSections(a, b))) if a == ".text" && b == ".bss"
+    ));
+}
+
+#[test]
+fn rejects_sections_outside_of_memory() {
+    let machine = load(PROGRAM).unwrap();
+    let bss = machine
+        .program_info()
+        .sections
+        .iter()
+        .find(|s| s.name == ".bss")
+        .unwrap()
+        .clone();
+    let mut program = PROGRAM.to_vec();
+    move_section(&mut program, bss.address, 0xff80_0000);
+    assert!(matches!(
+        load(&program),
+        Err(YoveError::Load(LoadError::SectionOutOfRange { name, address: 0xff80_0000, .. }))
+            if name == ".bss"
+    ));
+}
@@ -0,0 +1,34 @@
+//! Choosing which of the emulator's own messages are written.
+
+use log::LevelFilter;
+use yove::logger::LogFilter;
+
+#[test]
+fn the_most_specific_module_decides() {
+    let filter: LogFilter = "warn,yove::xous=info,yove::xous::services=debug"
+        .parse()
+        .unwrap();
+    assert_eq!(LevelFilter::Warn, filter.level("yove::logger"));
+    assert_eq!(LevelFilter::Info, filter.level("yove::xous"));
+    assert_eq!(LevelFilter::Info, filter.level("yove::xous::syscalls"));
+    assert_eq!(
+        LevelFilter::Debug,
+        filter.level("yove::xous::services::name")
+    );
+    // Only whole path segments match
+    assert_eq!(LevelFilter::Warn, filter.level("yove::xousish"));
+}
+
+#[test]
+fn levels_must_be_known() {
+    assert_eq!(
+        LevelFilter::Info,
+        "".parse::<LogFilter>().unwrap().level("yove")
+    );
+    assert_eq!(
+        LevelFilter::Off,
+        "off".parse::<LogFilter>().unwrap().level("yove")
+    );
+    assert!("loud".parse::<LogFilter>().is_err());
+    assert!("yove::xous=loud".parse::<LogFilter>().is_err());
+}
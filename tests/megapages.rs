@@ -0,0 +1,50 @@
+//! Megapage mappings. The guest in `guests/megapage.S` maps 4MB and two
+//! pages, grows the heap by 4MB, then unmaps the second page of the first
+//! region.
+
+use yove::xous::{MachineBuilder, Mapping};
+
+const REGION: u32 = 0x4000_0000;
+const HEAP: u32 = 0xa000_0000;
+const MEGAPAGE: u32 = 4 * 1024 * 1024;
+
+fn mapping(mappings: &[Mapping], virt: u32) -> Option<&Mapping> {
+    mappings
+        .iter()
+        .find(|mapping| (mapping.virt..mapping.virt + mapping.size).contains(&virt))
+}
+
+#[test]
+fn large_regions_are_mapped_with_megapages() {
+    let mut machine = MachineBuilder::new()
+        .megapages()
+        .build(include_bytes!("guests/megapage.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let mappings = machine.mappings();
+    let heap = mapping(&mappings, HEAP).unwrap();
+    assert_eq!((HEAP, MEGAPAGE), (heap.virt, heap.size));
+    assert_eq!(0, heap.phys % MEGAPAGE);
+
+    // Unmapping a page split the region's megapage back into pages
+    assert_eq!(None, mapping(&mappings, REGION + 0x1000));
+    for virt in [REGION, REGION + 0x2000, REGION + MEGAPAGE + 0x1000] {
+        assert_eq!(4096, mapping(&mappings, virt).unwrap().size);
+    }
+    let first = mapping(&mappings, REGION).unwrap().phys;
+    let third = mapping(&mappings, REGION + 0x2000).unwrap().phys;
+    assert_eq!(first + 0x2000, third);
+}
+
+#[test]
+fn pages_are_used_without_megapages() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/megapage.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    assert!(machine
+        .mappings()
+        .iter()
+        .all(|mapping| mapping.size == 4096));
+}
@@ -0,0 +1,34 @@
+//! Configuring the amount of RAM. The guest in `guests/bigmem.S` maps 64MB
+//! and writes to its first and last words.
+
+use yove::xous::{LoadError, MachineBuilder};
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/bigmem.elf");
+
+#[test]
+fn large_guests_only_use_the_memory_they_touch() {
+    let mut machine = MachineBuilder::new()
+        .memory_size(512 * 1024 * 1024)
+        .build(PROGRAM)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    // The program, its stack, the page tables, and the two pages written
+    assert!(machine.resident_memory() < 1024 * 1024);
+}
+
+#[test]
+fn the_default_is_too_small_for_the_region() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    assert_eq!(1, machine.run().unwrap());
+}
+
+#[test]
+fn rejects_invalid_sizes() {
+    for size in [4096, 16 * 1024 * 1024 + 1, 1024 * 1024 * 1024] {
+        assert!(matches!(
+            MachineBuilder::new().memory_size(size).build(PROGRAM),
+            Err(YoveError::Load(LoadError::InvalidMemorySize(s))) if s == size
+        ));
+    }
+}
@@ -0,0 +1,14 @@
+//! Messages the kernel refuses. The guest in `guests/messages.S` sends each
+//! one and checks the error itself, exiting with the number of the first
+//! check that failed.
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn bad_messages_are_refused() {
+    let mut machine = MachineBuilder::new()
+        .server_queue_depth(2)
+        .build(include_bytes!("guests/messages.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
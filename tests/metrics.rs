@@ -0,0 +1,73 @@
+//! The metrics endpoint. The guests in `guests/trng.S`, which sends the TRNG
+//! server messages, and `guests/protect.S`, which ends with a page fault,
+//! are run with metrics served on a local port, which is then scraped.
+//! `guests/trng.S` is also run to check that the port is closed once the
+//! machine is dropped.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use yove::xous::{Machine, MachineBuilder};
+
+/// Run `program` and return the machine that ran it, along with the address
+/// its metrics are served on for as long as it's kept.
+fn run(program: &[u8]) -> (Machine, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut machine = MachineBuilder::new()
+        .serve_metrics(listener)
+        .build(program)
+        .unwrap();
+    machine.run().ok();
+    (machine, address)
+}
+
+fn get(address: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: yove\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+/// The value of the metric called `name` in `response`.
+fn metric(response: &str, name: &str) -> u64 {
+    response
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in {:?}", name, response))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn metrics_count_what_the_program_did() {
+    let (_machine, address) = run(include_bytes!("guests/trng.elf"));
+    let response = get(address, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("# TYPE yove_messages_total counter\n"));
+    assert!(metric(&response, "yove_instructions_retired_total") > 0);
+    assert!(metric(&response, "yove_messages_total") >= 2);
+    assert!(metric(&response, "yove_syscalls_total") >= metric(&response, "yove_messages_total"));
+    assert_eq!(1, metric(&response, "yove_guest_threads_started_total"));
+    assert_eq!(0, metric(&response, "yove_guest_threads"));
+    assert_eq!(0, metric(&response, "yove_traps_total"));
+    assert!(metric(&response, "yove_memory_allocated_bytes") > 0);
+}
+
+#[test]
+fn traps_are_counted() {
+    let (_machine, address) = run(include_bytes!("guests/protect.elf"));
+    let response = get(address, "/metrics");
+    assert_eq!(1, metric(&response, "yove_traps_total"));
+    assert!(metric(&response, "yove_page_faults_total") >= 1);
+    assert!(get(address, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn dropping_the_machine_stops_the_server() {
+    let (machine, address) = run(include_bytes!("guests/trng.elf"));
+    assert!(get(address, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    drop(machine);
+    assert!(TcpStream::connect(address).is_err());
+}
@@ -0,0 +1,43 @@
+//! Listing the names the name server knows. The guest in `guests/names.S`
+//! registers `my-server` with a limit of two connections, connects to the
+//! DNS resolver by name, and checks the listing the name server gives it.
+//! The one in `guests/badnames.S` sends it requests it should refuse.
+
+use yove::xous::{MachineBuilder, NameInfo};
+
+#[test]
+fn lists_registered_and_connected_names() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/names.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    assert_eq!(
+        vec![
+            NameInfo {
+                name: "_DNS Resolver Middleware_".to_owned(),
+                hash: 0x655c127a7ce67bf2bc5bbf0904e29356,
+                connections: 1,
+                connection_limit: None,
+                registered: false,
+            },
+            NameInfo {
+                name: "my-server".to_owned(),
+                hash: 0x3776d4f8dcea539,
+                connections: 0,
+                connection_limit: Some(2),
+                registered: true,
+            },
+        ],
+        machine.names()
+    );
+}
+
+#[test]
+fn malformed_requests_are_refused() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/badnames.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    assert_eq!(Vec::<NameInfo>::new(), machine.names());
+}
@@ -0,0 +1,63 @@
+//! Notifications and interrupt lines that reach the guest without it asking.
+//! The guest in `guests/notify.S` waits for two notifications, then stops in
+//! `wfi` until an interrupt line is raised.
+
+use std::time::Duration;
+
+use riscv_cpu::cpu::MIP_SEIP;
+use yove::xous::notify::Notification;
+use yove::xous::{Machine, MachineBuilder, MachineEvent};
+
+fn machine() -> Machine {
+    MachineBuilder::new()
+        .build(include_bytes!("guests/notify.elf"))
+        .unwrap()
+}
+
+fn notification(opcode: u32) -> Notification {
+    Notification {
+        opcode,
+        args: [5, 6, 7, 8],
+    }
+}
+
+#[test]
+fn notifications_and_interrupts_wake_the_guest() {
+    let mut machine = machine();
+    let notifier = machine.notifier();
+    while machine.step().unwrap() != MachineEvent::Idle {}
+
+    // The first goes to the waiting thread, and the second waits for it
+    assert!(notifier.post(notification(1)));
+    assert!(notifier.post(notification(2)));
+    assert_eq!(1, notifier.queued());
+
+    // The guest gets as far as `wfi` and stays there
+    for _ in 0..10 {
+        assert_eq!(MachineEvent::Running, machine.step().unwrap());
+    }
+    assert_eq!(0, notifier.queued());
+
+    notifier.raise(MIP_SEIP);
+    loop {
+        if let MachineEvent::Exited(code) = machine.step().unwrap() {
+            assert_eq!(0, code);
+            break;
+        }
+    }
+}
+
+#[test]
+fn notifications_can_come_from_other_threads() {
+    let mut machine = machine();
+    let notifier = machine.notifier();
+    let device = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        notifier.post(notification(1));
+        notifier.post(notification(2));
+        // Raising the line before the guest reaches `wfi` still wakes it
+        notifier.raise(MIP_SEIP);
+    });
+    assert_eq!(0, machine.run().unwrap());
+    device.join().unwrap();
+}
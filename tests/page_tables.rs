@@ -0,0 +1,74 @@
+//! Dumping the page tables. The guest in `guests/megapage.S` maps 4MB and
+//! two pages, grows the heap by 4MB, then unmaps the second page of the
+//! first region.
+
+use yove::xous::{Machine, MachineBuilder, MmuFormat};
+
+fn run() -> Machine {
+    let mut machine = MachineBuilder::new()
+        .megapages()
+        .build(include_bytes!("guests/megapage.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    machine
+}
+
+fn dump(machine: &Machine, format: MmuFormat) -> String {
+    let mut output = vec![];
+    machine.dump_mmu(&mut output, format).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn text_lists_every_page() {
+    let machine = run();
+    let text = dump(&machine, MmuFormat::Text);
+    assert!(text.starts_with("Root page table @ "));
+    assert!(text.contains(" 640 Megapage a0000000 -> "));
+    // The exit trampoline, and the region around the page that was unmapped
+    assert!(text.contains("    3 ff803000 -> "));
+    assert!(text.contains("    0 40000000 -> "));
+    assert!(!text.contains("    1 40001000 -> "));
+    let pages = text
+        .lines()
+        .filter(|line| line.starts_with("        "))
+        .count();
+    assert_eq!(
+        machine
+            .mappings()
+            .iter()
+            .filter(|mapping| mapping.size == 4096)
+            .count(),
+        pages
+    );
+}
+
+#[test]
+fn json_has_both_levels() {
+    let json = dump(&run(), MmuFormat::Json);
+    assert!(json.contains(
+        "{\"vpn1\": 640, \"virt\": \"a0000000\", \"phys\": \"80800000\", \
+         \"flags\": \"VRWXU-AD\", \"megapage\": true, \"pages\": []}"
+    ));
+    assert!(json.contains("{\"vpn0\": 3, \"virt\": \"ff803000\", "));
+}
+
+#[test]
+fn dot_groups_pages_into_runs() {
+    let dot = dump(&run(), MmuFormat::Dot);
+    assert!(dot.starts_with("digraph page_tables {"));
+    assert!(dot.trim_end().ends_with('}'));
+    assert!(dot.contains("root -> t640"));
+    // The unmapped page splits the region into two runs
+    assert!(dot.contains("t256 -> p40000000 [label=\"0\"];"));
+    assert!(dot.contains("t256 -> p40002000 [label=\"2\"];"));
+    assert_eq!(2, dot.matches("t256 -> ").count());
+}
+
+#[test]
+fn formats_come_from_extensions() {
+    assert_eq!(Ok(MmuFormat::Json), MmuFormat::from_path("mmu.json"));
+    assert_eq!(Ok(MmuFormat::Dot), MmuFormat::from_path("mmu.gv"));
+    assert_eq!(Ok(MmuFormat::Text), MmuFormat::from_path("mmu.txt"));
+    assert!(MmuFormat::from_path("mmu").is_err());
+}
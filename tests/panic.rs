@@ -0,0 +1,49 @@
+//! Containing emulator panics to the guest thread that caused them. The
+//! guest in `guests/panic.S` starts a thread that lends the log server more
+//! valid bytes than it lends it memory, then joins it.
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn panic_stops_only_the_thread_that_caused_it() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/panic.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let faults = machine.thread_faults();
+    assert_eq!(1, faults.len(), "{:x?}", faults);
+    assert_eq!(1, faults[0].tid);
+    assert!(
+        faults[0].message.contains("out of range"),
+        "{}",
+        faults[0].message
+    );
+}
+
+#[test]
+fn faults_are_in_the_trace() {
+    let mut machine = MachineBuilder::new()
+        .trace()
+        .build(include_bytes!("guests/panic.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let tracer = machine.tracer().unwrap();
+
+    let mut output = vec![];
+    tracer.write(&mut output).unwrap();
+    let text = String::from_utf8(output).unwrap();
+    let line = text.lines().find(|line| line.contains(" fault ")).unwrap();
+    assert!(line.contains(" 1 "), "{}", line);
+    assert!(line.contains("out of range"), "{}", line);
+
+    let mut output = vec![];
+    tracer.write_chrome_trace(&mut output).unwrap();
+    let json = String::from_utf8(output).unwrap();
+    let event = json
+        .lines()
+        .find(|line| line.contains(r#""name": "fault""#))
+        .unwrap();
+    assert!(event.contains(r#""tid": 1,"#), "{}", event);
+    assert!(event.contains(r#""message": ""#), "{}", event);
+}
@@ -0,0 +1,87 @@
+//! Patching guest code. The guest in `guests/patch.S` calls `hardware_init`,
+//! which never returns, and `answer`, which returns 7, and only exits with 0
+//! once both have been patched. Its stack is used for reading and writing
+//! data across pages.
+
+use yove::xous::{LoadError, MachineBuilder};
+use yove::YoveError;
+
+/// `ret`
+const RET: [u8; 4] = 0x0000_8067u32.to_le_bytes();
+
+#[test]
+fn patched_functions_run() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/patch.elf"))
+        .unwrap();
+    let address = machine.patch_symbol("hardware_init", &RET).unwrap();
+    assert_eq!(machine.symbol_address("hardware_init"), Some(address));
+    machine.stub_symbol("answer", 42).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn patch_at_an_address() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/patch.elf"))
+        .unwrap();
+    let address = machine.symbol_address("hardware_init").unwrap();
+    machine.patch(address, &RET).unwrap();
+    // Without `answer` stubbed the guest gets past check 1 and fails check 2
+    assert_eq!(2, machine.run().unwrap());
+}
+
+#[test]
+fn bad_patches_are_refused() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/patch.elf"))
+        .unwrap();
+    assert!(matches!(
+        machine.patch_symbol("missing", &RET),
+        Err(YoveError::Load(LoadError::UnknownSymbol(name))) if name == "missing"
+    ));
+    assert!(matches!(
+        machine.stub_symbol("hardware_init", 42),
+        Err(YoveError::PatchTooLarge {
+            size: 4,
+            patch: 12,
+            ..
+        })
+    ));
+    assert!(matches!(
+        machine.patch(0x7000_0000, &RET),
+        Err(YoveError::Unmapped {
+            address: 0x7000_0000
+        })
+    ));
+}
+
+#[test]
+fn data_reads_back_across_pages() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/patch.elf"))
+        .unwrap();
+    // Three pages in a row, which needn't be next to each other in RAM
+    let pages: Vec<u32> = machine.mappings().iter().map(|m| m.virt).collect();
+    let start = *pages
+        .iter()
+        .find(|&&page| pages.contains(&(page + 4096)) && pages.contains(&(page + 8192)))
+        .unwrap();
+
+    let data: Vec<u8> = (0..2 * 4096 + 7).map(|i| (i * 7 + 3) as u8).collect();
+    let around = machine.read_memory(start, 3 * 4096).unwrap();
+    machine.patch(start + 3, &data).unwrap();
+    assert_eq!(
+        data,
+        machine.read_memory(start + 3, data.len() as u32).unwrap()
+    );
+
+    // The bytes on either side are left alone
+    let after = machine.read_memory(start, 3 * 4096).unwrap();
+    assert_eq!(around[..3], after[..3]);
+    assert_eq!(around[3 + data.len()..], after[3 + data.len()..]);
+    assert!(matches!(
+        machine.read_memory(0x7000_0000 - 2, 4),
+        Err(YoveError::Unmapped { .. })
+    ));
+}
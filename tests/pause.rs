@@ -0,0 +1,30 @@
+//! Pausing and resuming a machine that's running on its own threads. The
+//! guest in `guests/spin.S` spins until its clock reaches 100ms.
+
+use std::time::Duration;
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn paused_machine_stops_running_and_stops_its_clock() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/spin.elf"))
+        .unwrap();
+    let control = machine.pause_control();
+    machine.pause();
+    let runner = std::thread::spawn(move || machine.run().unwrap());
+
+    // Paused before it started, so the guest never gets to run
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(!runner.is_finished());
+
+    // The clock stops too, so the guest doesn't reach its deadline while paused
+    control.resume();
+    std::thread::sleep(Duration::from_millis(20));
+    control.pause();
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(!runner.is_finished());
+
+    control.resume();
+    assert_eq!(0, runner.join().unwrap());
+}
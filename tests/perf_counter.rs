@@ -0,0 +1,51 @@
+//! The perf counter service. The guest in `guests/perfcounter.S` asks it
+//! how many instructions it has retired either side of a 100-iteration,
+//! two-instruction loop, then for the time, then for both at once, and
+//! keeps the replies in `results`.
+
+use yove::xous::MachineBuilder;
+
+/// The words the guest kept in `results`, running with `builder`.
+fn results(builder: MachineBuilder) -> Vec<u32> {
+    let mut machine = builder
+        .build(include_bytes!("guests/perfcounter.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let address = machine.symbol_address("results").unwrap();
+    machine
+        .read_memory(address, 44)
+        .unwrap()
+        .chunks(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect()
+}
+
+fn double(low: u32, high: u32) -> u64 {
+    low as u64 | (high as u64) << 32
+}
+
+#[test]
+fn counts_the_callers_instructions() {
+    let results = results(MachineBuilder::new());
+    let before = double(results[0], results[1]);
+    let after = double(results[2], results[3]);
+    assert!(before > 0);
+    assert!((200..216).contains(&(after - before)));
+
+    let snapshot = double(results[6], results[7]);
+    assert!(snapshot > after);
+    assert_eq!(0, results[10], "the main thread's ID");
+}
+
+#[test]
+fn reports_the_guests_clock_in_microseconds() {
+    let results = results(MachineBuilder::new().freeze_time().start_time_us(7_123));
+    assert_eq!(7_123, double(results[4], results[5]));
+    assert_eq!(7_123, double(results[8], results[9]));
+}
+
+#[test]
+fn the_clock_only_goes_forwards() {
+    let results = results(MachineBuilder::new());
+    assert!(double(results[8], results[9]) >= double(results[4], results[5]));
+}
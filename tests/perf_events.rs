@@ -0,0 +1,99 @@
+//! Perf events. The guest in `guests/perfevents.S` has the perf counter
+//! service sample it every 100 instructions and count context switches while
+//! it and a thread it starts each spin through 2000 instructions, and exits
+//! with a bitmask of the checks that failed. `guests/countdown.S` just
+//! runs a few hundred instructions to be sampled.
+
+use std::sync::{Arc, Mutex};
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::perf_events::{PerfEvent, PerfListener, PerfSubscription};
+use yove::xous::MachineBuilder;
+
+#[derive(Default)]
+struct Recorder(Mutex<Vec<PerfEvent>>);
+
+impl PerfListener for Recorder {
+    fn event(&self, event: &PerfEvent) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+#[test]
+fn services_sample_and_count_context_switches() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/perfevents.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn the_host_hears_about_samples_and_traps() {
+    let mut machine = MachineBuilder::new()
+        .enforce_w_xor_x()
+        .build(include_bytes!("guests/wx.elf"))
+        .unwrap();
+    let recorder = Arc::new(Recorder::default());
+    machine.subscribe_perf_events(
+        PerfSubscription {
+            every_instructions: Some(4),
+            traps: true,
+            context_switches: false,
+        },
+        recorder.clone(),
+    );
+    assert!(machine.run().is_err());
+
+    let events = recorder.0.lock().unwrap();
+    let samples: Vec<u64> = events
+        .iter()
+        .filter_map(|event| match event {
+            PerfEvent::Sample { instructions, .. } => Some(*instructions),
+            _ => None,
+        })
+        .collect();
+    assert!(!samples.is_empty());
+    assert!(samples.iter().all(|instructions| instructions % 4 == 0));
+    match events.last() {
+        Some(PerfEvent::Trap { tid: 0, trap, .. }) => {
+            assert_eq!(TrapType::StoreAccessFault, trap.trap_type)
+        }
+        event => panic!("expected a trap, got {:?}", event),
+    }
+}
+
+#[test]
+fn each_subscriber_samples_at_its_own_period() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/countdown.elf"))
+        .unwrap();
+    let sample = |every| PerfSubscription {
+        every_instructions: Some(every),
+        ..PerfSubscription::default()
+    };
+    let (fours, sixes) = (Arc::new(Recorder::default()), Arc::new(Recorder::default()));
+    machine.subscribe_perf_events(sample(4), fours.clone());
+    machine.subscribe_perf_events(sample(6), sixes.clone());
+    assert_eq!(0, machine.run().unwrap());
+
+    let sampled = |recorder: &Recorder| -> Vec<u64> {
+        let events = recorder.0.lock().unwrap();
+        events
+            .iter()
+            .filter_map(|event| match event {
+                PerfEvent::Sample { instructions, .. } => Some(*instructions),
+                _ => None,
+            })
+            .collect()
+    };
+    let (fours, sixes) = (sampled(&fours), sampled(&sixes));
+    assert_eq!(
+        (1..=fours.len() as u64).map(|n| n * 4).collect::<Vec<_>>(),
+        fours
+    );
+    assert_eq!(
+        (1..=sixes.len() as u64).map(|n| n * 6).collect::<Vec<_>>(),
+        sixes
+    );
+    assert!(!sixes.is_empty());
+}
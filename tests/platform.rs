@@ -0,0 +1,68 @@
+//! Embedding the emulator with its own `Platform`, driven by `step()` with
+//! no host threads, as the wasm32 build is. The guest in `guests/timeout.S`
+//! prints "Waiting", then waits 50ms on a ticktimer condition that nothing
+//! notifies, exiting with 0 if the wait timed out no sooner than that.
+
+use std::sync::{Arc, Mutex};
+
+use yove::xous::{platform::Platform, Machine, MachineBuilder, MachineEvent};
+
+const PROGRAM: &[u8] = include_bytes!("guests/timeout.elf");
+
+/// A platform whose clock never moves on its own, and which keeps what's
+/// written to stdout.
+#[derive(Default)]
+struct Embedder {
+    stdout: Mutex<Vec<u8>>,
+}
+
+impl Platform for Embedder {
+    fn elapsed_ms(&self) -> u64 {
+        0
+    }
+
+    fn random_u32(&self) -> u32 {
+        4
+    }
+
+    fn write_stdout(&self, data: &[u8]) {
+        self.stdout.lock().unwrap().extend_from_slice(data);
+    }
+}
+
+/// Step `machine` until it exits or has nothing to do.
+fn step_until_idle(machine: &mut Machine) -> Option<u32> {
+    loop {
+        match machine.step().unwrap() {
+            MachineEvent::Exited(code) => return Some(code),
+            MachineEvent::Idle => return None,
+            MachineEvent::Running | MachineEvent::Stopped { .. } => {}
+        }
+    }
+}
+
+#[test]
+fn timeouts_expire_as_the_clock_moves() {
+    let embedder = Arc::new(Embedder::default());
+    let mut machine = MachineBuilder::new()
+        .platform(embedder.clone())
+        .build(PROGRAM)
+        .unwrap();
+
+    // However often it's stepped, the wait doesn't end until time passes
+    for _ in 0..10 {
+        assert_eq!(None, step_until_idle(&mut machine));
+    }
+    assert_eq!(b"Waiting\n", embedder.stdout.lock().unwrap().as_slice());
+
+    machine.clock().advance_us(49_000);
+    assert_eq!(None, step_until_idle(&mut machine));
+    machine.clock().advance_us(1_000);
+    assert_eq!(Some(0), step_until_idle(&mut machine));
+}
+
+#[test]
+fn timeouts_expire_on_the_host_clock_when_run() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
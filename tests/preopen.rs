@@ -0,0 +1,97 @@
+//! Host streams handed to the guest before it starts. The guest in
+//! `guests/echo.S` copies ring 0 to ring 1 until ring 0 is closed. It's also
+//! loaded and dropped without running, to check that the streams are let go.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use yove::xous::preopen::Preopened;
+use yove::xous::MachineBuilder;
+
+#[test]
+fn guest_echoes_a_connection_to_a_preopened_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut machine = MachineBuilder::new()
+        .preopen(0, 1, Preopened::Listener(listener))
+        .build(include_bytes!("guests/echo.elf"))
+        .unwrap();
+    let guest = std::thread::spawn(move || machine.run().unwrap());
+
+    let mut client = TcpStream::connect(address).unwrap();
+    client.write_all(b"hello from the host").unwrap();
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut echoed = vec![];
+    client.read_to_end(&mut echoed).unwrap();
+    assert_eq!(b"hello from the host", &echoed[..]);
+    assert_eq!(0, guest.join().unwrap());
+}
+
+/// A writer whose output can be read after it has been handed off.
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn guest_echoes_a_pipe_larger_than_its_rings() {
+    let input: Vec<u8> = (0..20000).map(|i| (i * 7) as u8).collect();
+    let output = Arc::new(Mutex::new(vec![]));
+    let mut machine = MachineBuilder::new()
+        .preopen(
+            0,
+            1,
+            Preopened::Pipe(
+                Box::new(std::io::Cursor::new(input.clone())),
+                Box::new(Shared(output.clone())),
+            ),
+        )
+        .build(include_bytes!("guests/echo.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    // The writer is dropped once everything has been copied out
+    while Arc::strong_count(&output) > 1 {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert!(input == *output.lock().unwrap());
+}
+
+#[test]
+fn dropping_the_machine_closes_an_unused_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let machine = MachineBuilder::new()
+        .preopen(0, 1, Preopened::Listener(listener))
+        .build(include_bytes!("guests/echo.elf"))
+        .unwrap();
+    drop(machine);
+    assert!(TcpStream::connect(address).is_err());
+}
+
+#[test]
+fn dropping_the_machine_ends_an_idle_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let machine = MachineBuilder::new()
+        .preopen(0, 1, Preopened::Stream(stream))
+        .build(include_bytes!("guests/echo.elf"))
+        .unwrap();
+    drop(machine);
+    client
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let mut received = vec![];
+    client.read_to_end(&mut received).unwrap();
+    assert!(received.is_empty());
+}
@@ -0,0 +1,13 @@
+//! The process hierarchy, which under Yove is only the emulated process and
+//! its parent, the kernel. The guest in `guests/parent.S` tries to return to
+//! the parent of processes that aren't its children.
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn only_children_can_be_returned_from() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/parent.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
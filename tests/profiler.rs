@@ -0,0 +1,57 @@
+//! The sampling profiler. The guest in `guests/countdown.S` spends almost
+//! all of its time in `spin`, so nearly every sample should land there.
+
+use yove::xous::profiler::ProfileFormat;
+use yove::xous::{LoadError, MachineBuilder};
+use yove::YoveError;
+
+fn profile(format: ProfileFormat) -> Vec<u8> {
+    let mut machine = MachineBuilder::new()
+        .profile(10)
+        .build(include_bytes!("guests/countdown.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let mut output = vec![];
+    machine
+        .profiler()
+        .unwrap()
+        .write(format, &mut output)
+        .unwrap();
+    output
+}
+
+#[test]
+fn samples_name_the_function_they_land_in() {
+    let output = String::from_utf8(profile(ProfileFormat::PerfScript)).unwrap();
+    let samples: Vec<&str> = output.split("\n\n").filter(|s| !s.is_empty()).collect();
+    // 100 trips around a two-instruction loop
+    assert!(samples.len() >= 19, "{}", output);
+    let in_spin = samples.iter().filter(|s| s.contains(" spin+0x")).count();
+    assert!(in_spin >= samples.len() - 1, "{}", output);
+    assert!(
+        samples[0].starts_with("yove 0/0 [000] 0.000010: 10 instructions:"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn pprof_names_the_functions_sampled() {
+    let output = profile(ProfileFormat::Pprof);
+    let contains = |needle: &[u8]| output.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"spin"));
+    assert!(contains(b"instructions"));
+    // The string table starts with the empty string
+    assert!(contains(&[0x32, 0x00]));
+}
+
+#[test]
+fn sampling_every_zero_instructions_is_refused() {
+    let result = MachineBuilder::new()
+        .profile(0)
+        .build(include_bytes!("guests/countdown.elf"));
+    assert!(matches!(
+        result,
+        Err(YoveError::Load(LoadError::InvalidProfileInterval))
+    ));
+}
@@ -0,0 +1,22 @@
+//! Page permissions. The guest in `guests/protect.S` writes to a page it
+//! mapped, makes it read-only with `UpdateMemoryFlags`, and writes to it
+//! again, which has to fault even though the page was already in use.
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+#[test]
+fn writing_a_page_made_read_only_faults() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/protect.elf"))
+        .unwrap();
+    let store = machine.symbol_address("store").unwrap();
+    match machine.run() {
+        Err(YoveError::Trap { trap, pc, .. }) => {
+            assert!(matches!(trap.trap_type, TrapType::StorePageFault));
+            assert_eq!(store, pc);
+        }
+        result => panic!("expected a page fault, got {:?}", result),
+    }
+}
@@ -0,0 +1,42 @@
+//! A smoke test of the Python bindings. `python/smoke.py` drives the guest
+//! in `guests/hypercall.S` through them, with the library built alongside
+//! this test copied to where Python will import it as `yove`. Python is run
+//! as `$PYTHON`, or `python3` if that isn't set.
+#![cfg(feature = "python")]
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The library cargo built for this test, which is two directories up from
+/// the test itself.
+fn library() -> PathBuf {
+    let test = std::env::current_exe().unwrap();
+    let directory = test.parent().unwrap().parent().unwrap();
+    directory.join(format!(
+        "{}yove{}",
+        std::env::consts::DLL_PREFIX,
+        std::env::consts::DLL_SUFFIX
+    ))
+}
+
+#[test]
+fn the_bindings_drive_a_machine() {
+    let directory = std::env::temp_dir().join(format!("yove-python-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::copy(library(), directory.join("yove.so")).unwrap();
+
+    let tests = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let output = Command::new(std::env::var_os("PYTHON").unwrap_or("python3".into()))
+        .arg(tests.join("python/smoke.py"))
+        .arg(tests.join("guests/hypercall.elf"))
+        .env("PYTHONPATH", &directory)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&directory).ok();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!("ok\n", String::from_utf8_lossy(&output.stdout));
+}
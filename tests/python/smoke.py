@@ -0,0 +1,48 @@
+# Drives the guest in `guests/hypercall.elf`, given as the first argument,
+# through the Python bindings, and prints "ok" if everything worked. Run by
+# `tests/python.rs` with the built library importable as `yove`.
+
+import sys
+
+import yove
+
+program = open(sys.argv[1], "rb").read()
+
+# Breakpoints call back and carry on, and the exit is reported
+machine = yove.Machine(program, seed=1234)
+assert machine.seed == 1234
+covered = machine.symbol("covered")
+assert covered is not None
+assert machine.symbol("no_such_symbol") is None
+machine.add_breakpoint(covered)
+assert machine.breakpoints() == [covered]
+stops = []
+machine.on_breakpoint(lambda tid, pc: stops.append((tid, pc)))
+exits = []
+machine.on_exit(exits.append)
+assert machine.run() == 0
+assert stops == [(0, covered)]
+assert exits == [0]
+assert machine.instructions_retired > 0
+# Hypercalls are ignored without tracing, so the unknown one read back 0
+assert machine.read(machine.symbol("unknown"), 4) == b"\0\0\0\0"
+
+# Without a callback, the run stops at the breakpoint
+machine = yove.Machine(program)
+machine.add_breakpoint(covered)
+assert machine.run() is None
+pc, registers = machine.registers(0)
+assert pc == covered
+assert len(registers) == 32
+assert machine.remove_breakpoint(covered)
+assert machine.step()[0] in ("running", "idle", "exited")
+
+# Errors are raised as yove.YoveError
+try:
+    yove.Machine(b"not an ELF file")
+except yove.YoveError as error:
+    assert "couldn't load program" in str(error), error
+else:
+    raise AssertionError("a program that isn't an ELF file was loaded")
+
+print("ok")
@@ -0,0 +1,55 @@
+//! Mapping the program's code and constants read-only. The guest in
+//! `guests/rodata.S` reads a constant from its `.rodata` and then writes
+//! over the one after it, which only goes through when its sections are
+//! left writable.
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+/// Where the linker put `table`, the guest's constants, at the start of
+/// the program.
+const TABLE: u32 = 0x2000_0000;
+
+#[test]
+fn constants_are_writable_by_default() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/rodata.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn writing_constants_faults() {
+    let mut machine = MachineBuilder::new()
+        .read_only_sections()
+        .build(include_bytes!("guests/rodata.elf"))
+        .unwrap();
+    let store = machine.symbol_address("store").unwrap();
+    // Objects are looked up the same way as functions
+    assert_eq!(Some(TABLE), machine.symbol_address("table"));
+    match machine.run() {
+        Err(YoveError::Trap { trap, pc, .. }) => {
+            assert_eq!(TrapType::StoreAccessFault, trap.trap_type);
+            assert_eq!(TABLE + 4, trap.value);
+            assert_eq!(store, pc);
+        }
+        result => panic!("expected an access fault, got {:?}", result),
+    }
+}
+
+#[test]
+fn works_alongside_w_xor_x() {
+    let mut machine = MachineBuilder::new()
+        .read_only_sections()
+        .enforce_w_xor_x()
+        .build(include_bytes!("guests/rodata.elf"))
+        .unwrap();
+    match machine.run() {
+        Err(YoveError::Trap { trap, .. }) => {
+            assert_eq!(TrapType::StoreAccessFault, trap.trap_type);
+            assert_eq!(TABLE + 4, trap.value);
+        }
+        result => panic!("expected an access fault, got {:?}", result),
+    }
+}
@@ -0,0 +1,28 @@
+//! Memory regions. The guest in `guests/regions.S` moves its heap, its
+//! `MapMemory` window, and its stack with `SetMemRegion`, and checks that
+//! memory is then given out where they were moved to.
+
+use yove::xous::{MachineBuilder, MemoryRegion, MemoryType};
+
+fn region(kind: MemoryType, start: u32, size: u32) -> MemoryRegion {
+    MemoryRegion { kind, start, size }
+}
+
+#[test]
+fn regions_move_where_the_program_sets_them() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/regions.elf"))
+        .unwrap();
+    let stack = region(MemoryType::Stack, 0xc000_0000, 0x2_0000);
+    assert!(machine.memory_regions().contains(&stack));
+
+    assert_eq!(0, machine.run().unwrap());
+    assert_eq!(
+        vec![
+            region(MemoryType::Default, 0x6000_0000, 0x10_0000),
+            region(MemoryType::Stack, 0x7000_0000, 0x4000),
+            region(MemoryType::Heap, 0x5000_0000, 0x1_0000),
+        ],
+        machine.memory_regions()
+    );
+}
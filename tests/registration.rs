@@ -0,0 +1,33 @@
+//! Decoding name registrations archived by different versions of Xous's
+//! libstd. The guest in `guests/register.S` registers a name in the rkyv 0.4
+//! layout and two in the rkyv 0.7 layout, then sends a registration that
+//! neither layout can decode.
+
+use yove::xous::{MachineBuilder, NameInfo};
+
+fn registered(name: &str, hash: u128, connection_limit: Option<u32>) -> NameInfo {
+    NameInfo {
+        name: name.to_owned(),
+        hash,
+        connections: 0,
+        connection_limit,
+        registered: true,
+    }
+}
+
+#[test]
+fn registrations_decode_in_every_layout() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/register.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    assert_eq!(
+        vec![
+            registered("a-much-longer-name", 0x9252babdb2539668ed8b10c6a0, Some(3)),
+            registered("old-layout", 0x7265fe61c890fd75, Some(2)),
+            registered("short", 0x310baefa97, None),
+        ],
+        machine.names()
+    );
+}
@@ -0,0 +1,56 @@
+//! Rings shared between the host and the guest. The guest in
+//! `guests/echo.S` copies ring 0 to ring 1 until ring 0 is closed, and the
+//! one in `guests/ringunmap.S` unmaps a ring's memory while a thread waits
+//! on it, then attaches the ring somewhere else.
+
+use std::time::Duration;
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn the_guest_echoes_more_than_a_ring_holds() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/echo.elf"))
+        .unwrap();
+    let (input, output) = (machine.ring_buffer(0), machine.ring_buffer(1));
+    assert!(!input.is_attached());
+    let guest = std::thread::spawn(move || machine.run().unwrap());
+    while !(input.is_attached() && output.is_attached()) {
+        input.wait(Duration::from_millis(10));
+    }
+    assert_eq!(8192 - 16, input.capacity());
+
+    let data: Vec<u8> = (0..20000).map(|i| (i * 13) as u8).collect();
+    let (mut written, mut echoed) = (0, vec![]);
+    let mut buf = [0; 1000];
+    while !(output.is_closed() && output.is_empty()) {
+        if written < data.len() {
+            written += input.write(&data[written..]);
+            if written == data.len() {
+                input.close();
+            }
+        }
+        let count = output.read(&mut buf);
+        echoed.extend_from_slice(&buf[..count]);
+        if count == 0 {
+            output.wait(Duration::from_millis(10));
+        }
+    }
+    assert!(data == echoed);
+    // Nothing can be written once a ring is closed, or to a ring that flows
+    // the other way
+    assert_eq!(0, input.write(b"late"));
+    assert_eq!(0, output.write(b"wrong way"));
+    assert_eq!(0, guest.join().unwrap());
+}
+
+#[test]
+fn unmapping_a_ring_detaches_it() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/ringunmap.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+    let ring = machine.ring_buffer(0);
+    assert!(ring.is_attached());
+    assert_eq!(4096 - 16, ring.capacity());
+}
@@ -0,0 +1,73 @@
+//! Scheduler events. The guest in `guests/threadstats.S` starts a thread,
+//! joins it, then sends blocking scalars to the perf counter service.
+
+use yove::xous::trace::{BlockReason, SchedulerEvent};
+use yove::xous::MachineBuilder;
+
+#[test]
+fn thread_lifecycle_is_recorded() {
+    let mut machine = MachineBuilder::new()
+        .trace()
+        .build(include_bytes!("guests/threadstats.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let schedule = machine.tracer().unwrap().schedule();
+    let events = |tid: i32| -> Vec<SchedulerEvent> {
+        schedule
+            .iter()
+            .filter(|record| record.tid == tid)
+            .map(|record| record.event)
+            .collect()
+    };
+    let child = events(1);
+    assert!(
+        matches!(
+            child[..],
+            [
+                SchedulerEvent::Created { parent: 0, .. },
+                SchedulerEvent::Exited(0)
+            ]
+        ),
+        "{:?}",
+        child
+    );
+    let main = events(0);
+    let join = main
+        .iter()
+        .position(|event| *event == SchedulerEvent::Blocked(BlockReason::Join(1)))
+        .unwrap();
+    assert_eq!(SchedulerEvent::Unblocked, main[join + 1]);
+    assert!(schedule
+        .windows(2)
+        .all(|pair| pair[0].elapsed_us <= pair[1].elapsed_us));
+}
+
+#[test]
+fn chrome_trace_slices_are_balanced() {
+    let mut machine = MachineBuilder::new()
+        .trace()
+        .build(include_bytes!("guests/threadstats.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let mut output = vec![];
+    machine
+        .tracer()
+        .unwrap()
+        .write_chrome_trace(&mut output)
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(r#""name": "blocked on join of thread 1""#));
+    assert!(output.contains(r#""name": "create thread 1""#));
+    for tid in [0, 1] {
+        let count = |phase: &str| {
+            output
+                .lines()
+                .filter(|line| line.contains(phase))
+                .filter(|line| line.contains(&format!(r#""tid": {}}}"#, tid)))
+                .count()
+        };
+        assert_eq!(count(r#""ph": "B""#), count(r#""ph": "E""#));
+    }
+}
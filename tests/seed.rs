@@ -0,0 +1,26 @@
+//! Repeating a run from its seed. The guest in `guests/trng.S` draws four
+//! words from the TRNG server as scalars and three more by having it fill a
+//! buffer, and exits with their XOR, or with 0 if it couldn't.
+
+use yove::xous::MachineBuilder;
+
+fn draw(builder: MachineBuilder) -> (u32, u64) {
+    let mut machine = builder.build(include_bytes!("guests/trng.elf")).unwrap();
+    let drawn = machine.run().unwrap();
+    assert_ne!(0, drawn);
+    (drawn, machine.seed())
+}
+
+#[test]
+fn the_seed_decides_the_trng() {
+    let (first, seed) = draw(MachineBuilder::new().seed(1234));
+    assert_eq!(1234, seed);
+    assert_eq!(first, draw(MachineBuilder::new().seed(1234)).0);
+    assert_ne!(first, draw(MachineBuilder::new().seed(5678)).0);
+}
+
+#[test]
+fn a_picked_seed_repeats_the_run() {
+    let (first, seed) = draw(MachineBuilder::new());
+    assert_eq!(first, draw(MachineBuilder::new().seed(seed)).0);
+}
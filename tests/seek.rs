@@ -0,0 +1,108 @@
+//! Seeking to an instruction count. The guest in `guests/spin.S` reads the
+//! `time` CSR in a loop, which with deterministic counters follows the
+//! instructions retired, so every count it passes has a different state.
+//! The guest in `guests/keys.S` blocks waiting for a key that never comes.
+
+use std::sync::Arc;
+
+use yove::xous::{
+    counters::CounterPolicy, platform::HostPlatform, Machine, MachineBuilder, MachineEvent,
+};
+use yove::YoveError;
+
+fn spinning() -> Machine {
+    MachineBuilder::new()
+        .counters(CounterPolicy::Deterministic)
+        .build(include_bytes!("guests/spin.elf"))
+        .unwrap()
+}
+
+#[test]
+fn seek_stops_at_the_exact_count() {
+    let mut machine = spinning();
+    let MachineEvent::Stopped { tid, .. } = machine.seek(1234).unwrap() else {
+        panic!("expected the machine to stop");
+    };
+    assert_eq!(1234, machine.instructions_retired());
+    assert_eq!(MachineEvent::Idle, machine.seek(1234).unwrap());
+
+    // The thread that stopped can be stepped from there
+    machine.step_thread(tid).unwrap();
+    assert_eq!(1235, machine.instructions_retired());
+}
+
+#[test]
+fn seeking_backwards_replays_from_the_start() {
+    let mut machine = spinning();
+    machine.seek(9_000).unwrap();
+    let earlier = machine.thread_state(0).unwrap();
+    machine.seek(10_000).unwrap();
+    let later = machine.thread_state(0).unwrap();
+    assert_ne!(earlier, later);
+
+    machine.seek(9_000).unwrap();
+    assert_eq!(9_000, machine.instructions_retired());
+    assert_eq!(earlier, machine.thread_state(0).unwrap());
+    machine.seek(10_000).unwrap();
+    assert_eq!(later, machine.thread_state(0).unwrap());
+}
+
+#[test]
+fn seeking_backwards_keeps_breakpoints() {
+    let mut machine = spinning();
+    let entry = machine.program_info().entry;
+    machine.seek(1000).unwrap();
+    machine.add_breakpoint(entry + 6);
+    let event = machine.seek(10).unwrap();
+    assert_eq!(
+        MachineEvent::Stopped {
+            tid: 0,
+            pc: entry + 6
+        },
+        event
+    );
+}
+
+#[test]
+fn machines_that_cant_be_built_again_cant_go_back() {
+    let mut machine = MachineBuilder::new()
+        .counters(CounterPolicy::Deterministic)
+        .platform(Arc::new(HostPlatform::new()))
+        .build(include_bytes!("guests/spin.elf"))
+        .unwrap();
+    machine.seek(10_000).unwrap();
+    assert!(matches!(
+        machine.seek(9_000),
+        Err(YoveError::SeekBackwards {
+            retired: 10_000,
+            instructions: 9_000
+        })
+    ));
+}
+
+#[test]
+fn seek_returns_idle_when_every_thread_is_blocked() {
+    let mut machine = MachineBuilder::new()
+        .counters(CounterPolicy::Deterministic)
+        .build(include_bytes!("guests/keys.elf"))
+        .unwrap();
+    assert_eq!(MachineEvent::Idle, machine.seek(u64::MAX).unwrap());
+    assert!(machine.instructions_retired() < 10_000);
+}
+
+#[test]
+fn seek_stops_at_breakpoints() {
+    let mut machine = spinning();
+    let entry = machine.program_info().entry;
+    // The `rdtime` at the top of the loop, after `li t0, DEADLINE_US`
+    machine.add_breakpoint(entry + 6);
+    let event = machine.seek(1000).unwrap();
+    assert_eq!(
+        MachineEvent::Stopped {
+            tid: 0,
+            pc: entry + 6
+        },
+        event
+    );
+    assert!(machine.instructions_retired() < 1000);
+}
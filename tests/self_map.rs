@@ -0,0 +1,33 @@
+//! The page tables, mapped into the guest where Xous maps them. The guest in
+//! `guests/selfmap.S` looks up its own code with `VirtToPhys` and in its
+//! page tables, checks that they agree, does the same for a page of its stack
+//! and a page of heap, and then writes to the root table, which has to fault
+//! since the guest may only read them. It's run with and without demand
+//! paging, which reserves the heap's page rather than mapping it.
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+fn run(builder: MachineBuilder) {
+    let mut machine = builder.build(include_bytes!("guests/selfmap.elf")).unwrap();
+    let store = machine.symbol_address("store").unwrap();
+    match machine.run() {
+        Err(YoveError::Trap { trap, pc, .. }) => {
+            assert!(matches!(trap.trap_type, TrapType::StorePageFault));
+            assert_eq!(0xff80_0000, trap.value);
+            assert_eq!(store, pc);
+        }
+        result => panic!("expected a page fault, got {:?}", result),
+    }
+}
+
+#[test]
+fn page_tables_are_readable_but_not_writable() {
+    run(MachineBuilder::new());
+}
+
+#[test]
+fn tables_for_reserved_memory_are_readable() {
+    run(MachineBuilder::new().demand_paging());
+}
@@ -0,0 +1,15 @@
+//! `yove selftest` passes on the platform the tests run on, and reports the
+//! riscv-tests it runs by name.
+
+#[test]
+fn every_check_passes() {
+    let checks = yove::selftest::run();
+    let failed: Vec<_> = checks
+        .iter()
+        .filter(|check| check.result.is_err())
+        .collect();
+    assert!(failed.is_empty(), "{:#?}", failed);
+    for name in ["rv32ui-p-add", "rv32uc-p-rvc", "decoder", "mmu", "lr/sc"] {
+        assert!(checks.iter().any(|check| check.name == name), "{}", name);
+    }
+}
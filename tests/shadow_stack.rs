@@ -0,0 +1,79 @@
+//! The shadow stack. The guest in `guests/smash.S` calls a function that
+//! overwrites its return address, as a stack smash would, and has a
+//! function symbol that runs to the end of the address space.
+
+use yove::xous::{shadow_stack::Allow, MachineBuilder};
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/smash.elf");
+
+#[test]
+fn smashed_returns_are_caught() {
+    let mut machine = MachineBuilder::new()
+        .shadow_stack(vec![])
+        .build(PROGRAM)
+        .unwrap();
+    let victim = machine.symbol_address("victim").unwrap();
+    let landing = machine.symbol_address("landing").unwrap();
+    let entry = machine.program_info().entry;
+    match machine.run() {
+        Err(YoveError::ShadowStack {
+            tid,
+            expected,
+            actual,
+            pc,
+        }) => {
+            assert_eq!(0, tid);
+            assert_eq!(landing, actual);
+            // The instruction after the `call`, which is an `auipc` and `jalr`
+            assert_eq!(entry + 8, expected);
+            assert!((victim..landing).contains(&pc));
+        }
+        result => panic!("expected a shadow stack mismatch, got {:?}", result),
+    }
+}
+
+#[test]
+fn allowed_functions_may_return_anywhere() {
+    let mut machine = MachineBuilder::new()
+        .shadow_stack(vec![Allow::Symbol("victim".to_owned())])
+        .build(PROGRAM)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn allowed_ranges_may_return_anywhere() {
+    let machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    let victim = machine.symbol_address("victim").unwrap();
+    let landing = machine.symbol_address("landing").unwrap();
+    let allow = format!("{:#x}-{:#x}", victim, landing).parse().unwrap();
+    let mut machine = MachineBuilder::new()
+        .shadow_stack(vec![allow])
+        .build(PROGRAM)
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn symbols_at_the_end_of_the_address_space_can_be_allowed() {
+    let mut machine = MachineBuilder::new()
+        .shadow_stack(vec![Allow::Symbol("top".to_owned())])
+        .build(PROGRAM)
+        .unwrap();
+    assert!(matches!(machine.run(), Err(YoveError::ShadowStack { .. })));
+}
+
+#[test]
+fn allowlist_entries_parse() {
+    assert_eq!(Allow::Range(0x100..0x200), "0x100-0x200".parse().unwrap());
+    assert_eq!(
+        Allow::Range(0xffff_ffff..0xffff_ffff),
+        "0xffffffff".parse().unwrap()
+    );
+    assert_eq!(
+        Allow::Symbol("longjmp".to_owned()),
+        "longjmp".parse().unwrap()
+    );
+    assert!("0xnope".parse::<Allow>().is_err());
+}
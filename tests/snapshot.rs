@@ -0,0 +1,135 @@
+//! Snapshots and restoring them. The guest in `guests/suspend.S` folds the
+//! numbers 0 to 199 into a checksum, asking for a suspend halfway through,
+//! and exits with the checksum's low byte, 100. The one in
+//! `guests/resumetime.S` asks for a suspend and then exits with the
+//! ticktimer's elapsed time in seconds, and the one in `guests/blocked.S`
+//! waits for good on a condition nothing notifies.
+
+use std::sync::Arc;
+
+use yove::xous::{
+    counters::CounterPolicy, platform::HostPlatform, snapshot::Snapshot, Machine, MachineBuilder,
+    MachineEvent,
+};
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/suspend.elf");
+const RESUME_TIME: &[u8] = include_bytes!("guests/resumetime.elf");
+const BLOCKED: &[u8] = include_bytes!("guests/blocked.elf");
+
+fn builder() -> MachineBuilder {
+    MachineBuilder::new().counters(CounterPolicy::Deterministic)
+}
+
+/// Step `machine` until the guest has suspended.
+fn suspended(mut machine: Machine) -> Machine {
+    while machine.suspends() == 0 {
+        assert!(!matches!(machine.step().unwrap(), MachineEvent::Exited(_)));
+    }
+    machine
+}
+
+/// Step `machine` until it exits, returning the exit code.
+fn finish(machine: &mut Machine) -> u32 {
+    loop {
+        if let MachineEvent::Exited(code) = machine.step().unwrap() {
+            return code;
+        }
+    }
+}
+
+#[test]
+fn restoring_carries_on_from_the_suspend() {
+    let mut machine = suspended(builder().build(PROGRAM).unwrap());
+    let snapshot = machine.snapshot().unwrap();
+    assert_eq!(machine.instructions_retired(), snapshot.instructions());
+    assert_eq!(machine.seed(), snapshot.seed());
+
+    let mut restored = snapshot.restore(builder()).unwrap();
+    assert_eq!(snapshot.instructions(), restored.instructions_retired());
+    assert_eq!(1, restored.suspends());
+    assert_eq!(
+        machine.thread_state(0).unwrap(),
+        restored.thread_state(0).unwrap()
+    );
+    assert_eq!(100, finish(&mut restored));
+    assert_eq!(100, finish(&mut machine));
+}
+
+#[test]
+fn snapshots_survive_being_written_out() {
+    let machine = suspended(builder().build(PROGRAM).unwrap());
+    let snapshot = machine.snapshot().unwrap();
+    let mut image = vec![];
+    snapshot.write(&mut image).unwrap();
+    let read = Snapshot::read(&mut image.as_slice()).unwrap();
+    assert_eq!(snapshot, read);
+    assert_eq!(100, finish(&mut read.restore(builder()).unwrap()));
+}
+
+#[test]
+fn restoring_past_the_exit_fails() {
+    let machine = suspended(builder().build(PROGRAM).unwrap());
+    let mut image = vec![];
+    machine.snapshot().unwrap().write(&mut image).unwrap();
+    // The instruction count follows the magic and the version
+    image[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+    let snapshot = Snapshot::read(&mut image.as_slice()).unwrap();
+    assert!(matches!(
+        snapshot.restore(builder()),
+        Err(YoveError::Restore {
+            instructions: u64::MAX,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn restoring_a_run_that_blocks_for_good_fails() {
+    let mut machine = builder().build(BLOCKED).unwrap();
+    while !matches!(machine.step().unwrap(), MachineEvent::Idle) {}
+    let blocked_at = machine.instructions_retired();
+    let mut image = vec![];
+    machine.snapshot().unwrap().write(&mut image).unwrap();
+    image[12..20].copy_from_slice(&(blocked_at + 1000).to_le_bytes());
+    let snapshot = Snapshot::read(&mut image.as_slice()).unwrap();
+    match snapshot.restore(builder()) {
+        Err(YoveError::Restore { reached, .. }) => assert_eq!(blocked_at, reached),
+        result => panic!("expected the restore to fail, got {:?}", result.map(|_| ())),
+    }
+}
+
+#[test]
+fn the_clock_carries_on_from_the_snapshot() {
+    let machine = builder().build(RESUME_TIME).unwrap();
+    machine.clock().advance_us(60_000_000);
+    let snapshot = suspended(machine).snapshot().unwrap();
+    assert!(snapshot.time_us() >= 60_000_000);
+
+    // The replay gets to the suspend in far less than a minute
+    let mut restored = snapshot.restore(builder()).unwrap();
+    assert!(restored.clock().now_us() >= snapshot.time_us());
+    assert!(finish(&mut restored) >= 60);
+}
+
+#[test]
+fn other_files_are_refused() {
+    let error = Snapshot::read(&mut b"not a snapshot at all".as_slice()).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+
+    let machine = suspended(builder().build(PROGRAM).unwrap());
+    let mut image = vec![];
+    machine.snapshot().unwrap().write(&mut image).unwrap();
+    image.truncate(image.len() - 1);
+    let error = Snapshot::read(&mut image.as_slice()).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+}
+
+#[test]
+fn machines_that_cant_be_built_again_cant_be_snapshotted() {
+    let machine = builder()
+        .platform(Arc::new(HostPlatform::new()))
+        .build(PROGRAM)
+        .unwrap();
+    assert!(machine.snapshot().is_none());
+}
@@ -0,0 +1,129 @@
+//! Single stepping, stepping over calls, and stepping out of them. The guest
+//! in `guests/stepping.S` calls `add_two` at offset 0x40 once with a `jal`
+//! and once with a `c.jal`, and `add_two` calls `add_one` at offset 0x80
+//! twice.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use yove::xous::{Machine, MachineBuilder, MachineEvent};
+
+/// A writer whose output the test can still see once it's been handed over.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn machine() -> (Machine, u32) {
+    let machine = MachineBuilder::new()
+        .build(include_bytes!("guests/stepping.elf"))
+        .unwrap();
+    let entry = machine.program_info().entry;
+    (machine, entry)
+}
+
+fn stopped(pc: u32) -> MachineEvent {
+    MachineEvent::Stopped { tid: 0, pc }
+}
+
+fn run_to_exit(machine: &mut Machine) -> u32 {
+    loop {
+        if let MachineEvent::Exited(code) = machine.step().unwrap() {
+            return code;
+        }
+    }
+}
+
+#[test]
+fn steps_over_calls_of_either_size_and_branches() {
+    let (mut machine, entry) = machine();
+    assert_eq!(stopped(entry + 0x04), machine.step_thread(0).unwrap());
+    assert_eq!(stopped(entry + 0x08), machine.step_thread(0).unwrap());
+    // jal
+    assert_eq!(stopped(entry + 0x0c), machine.step_over(0).unwrap());
+    // c.jal returns to the next halfword
+    assert_eq!(stopped(entry + 0x0e), machine.step_over(0).unwrap());
+    // A branch isn't a call, so it's stepped once, and this one is taken
+    assert_eq!(stopped(entry + 0x16), machine.step_over(0).unwrap());
+    assert_eq!(0, machine.breakpoints().count());
+    assert_eq!(4, run_to_exit(&mut machine));
+}
+
+#[test]
+fn steps_out_from_a_breakpoint() {
+    let (mut machine, entry) = machine();
+    machine.add_breakpoint(entry + 0x80);
+    assert_eq!(stopped(entry + 0x80), machine.step().unwrap());
+    assert_eq!(stopped(entry + 0x4c), machine.step_out(0).unwrap());
+    // Stepping out of `add_two` is cut short by its second call to `add_one`
+    assert_eq!(stopped(entry + 0x80), machine.step_out(0).unwrap());
+    assert_eq!(stopped(entry + 0x50), machine.step_out(0).unwrap());
+    assert_eq!(stopped(entry + 0x0c), machine.step_out(0).unwrap());
+
+    // Stepping over the second call stops at the breakpoint inside it
+    assert_eq!(stopped(entry + 0x80), machine.step_over(0).unwrap());
+    assert!(machine.remove_breakpoint(entry + 0x80));
+    assert_eq!(4, run_to_exit(&mut machine));
+}
+
+#[test]
+fn shows_what_a_step_changed() {
+    let (mut machine, entry) = machine();
+    machine.step_thread(0).unwrap();
+    machine.step_thread(0).unwrap();
+
+    let before = machine.thread_state(0).unwrap();
+    assert_eq!(stopped(entry + 0x0c), machine.step_over(0).unwrap());
+    let delta = machine.thread_state(0).unwrap().delta(&before);
+    assert_eq!(Some((entry + 0x08, entry + 0x0c)), delta.pc);
+    // `add_two` put the stack pointer back, and left `ra` pointing after the call
+    assert_eq!(
+        vec![(1, before.registers[1], (entry + 0x0c) as i32), (10, 0, 2)],
+        delta.registers
+    );
+    assert!(delta.to_string().ends_with(", a0 00000000 -> 00000002"));
+}
+
+#[test]
+fn logs_commits_in_spike_format() {
+    let log = Shared::default();
+    let mut machine = MachineBuilder::new()
+        .log_commits(Box::new(log.clone()))
+        .build(include_bytes!("guests/stepping.elf"))
+        .unwrap();
+    let entry = machine.program_info().entry;
+    for _ in 0..4 {
+        machine.step_thread(0).unwrap();
+    }
+
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(4, lines.len());
+    // li a0, 0
+    assert_eq!(
+        format!(
+            "core   0: 0 0x{:08x} (0x00000513) x10 0x00000000",
+            entry + 0x04
+        ),
+        lines[1]
+    );
+    // jal ra, add_two
+    assert!(lines[2].ends_with(&format!(" x1  0x{:08x}", entry + 0x0c)));
+    // addi sp, sp, -16
+    assert!(lines[3].starts_with(&format!("core   0: 0 0x{:08x} (", entry + 0x40)));
+    assert!(lines[3].contains(" x2  0x"));
+}
+
+#[test]
+fn unknown_threads_cannot_be_stepped() {
+    let (mut machine, _) = machine();
+    assert!(machine.step_thread(7).is_err());
+}
@@ -0,0 +1,79 @@
+//! Printing syscalls with `MachineBuilder::strace`. The guest in
+//! `guests/regions.S` sets memory regions and maps memory in them, and the
+//! one in `guests/suspend.S` sends messages to the suspend/resume manager.
+
+use std::sync::{Arc, Mutex};
+
+use yove::xous::{platform::Platform, MachineBuilder};
+
+/// A platform that keeps what's written to stderr.
+#[derive(Default)]
+struct Capture {
+    stderr: Mutex<Vec<u8>>,
+}
+
+impl Platform for Capture {
+    fn elapsed_ms(&self) -> u64 {
+        0
+    }
+
+    fn random_u32(&self) -> u32 {
+        4
+    }
+
+    fn write_stderr(&self, data: &[u8]) {
+        self.stderr.lock().unwrap().extend_from_slice(data);
+    }
+}
+
+/// The lines printed for the syscalls `program` makes, without their
+/// durations, which vary.
+fn strace(program: &[u8]) -> Vec<String> {
+    let capture = Arc::new(Capture::default());
+    let mut machine = MachineBuilder::new()
+        .strace()
+        .platform(capture.clone())
+        .build(program)
+        .unwrap();
+    machine.run().unwrap();
+    let stderr = String::from_utf8(capture.stderr.lock().unwrap().clone()).unwrap();
+    stderr
+        .lines()
+        .map(|line| line.rsplit_once(" <").unwrap().0.to_owned())
+        .collect()
+}
+
+#[test]
+fn syscalls_are_decoded() {
+    let lines = strace(include_bytes!("guests/regions.elf"));
+    assert!(lines.contains(
+        &"[tid 0] MapMemory(phys=0x0, virt=0x0, size=0x1000, flags=RW) \
+          = MemoryRange(0x60000000, 0x1000)"
+            .to_owned()
+    ));
+    assert!(lines.contains(
+        &"[tid 0] SetMemRegion(pid=3, type=Stack, address=0x70000000, size=0x4000) \
+          = Error(ProcessNotFound)"
+            .to_owned()
+    ));
+    assert_eq!(
+        Some(&"[tid 0] ExitThread(result=0) = <thread exit 0>".to_owned()),
+        lines.last()
+    );
+}
+
+#[test]
+fn messages_are_printed_on_one_line() {
+    let lines = strace(include_bytes!("guests/suspend.elf"));
+    assert_eq!(
+        vec![
+            "[tid 0] Connect(sid=[73756f78, 6d616e2d, 65732d65, 72657672]) = ConnectionId(1)",
+            "[tid 0] SendMessage(cid=1, MutableLend, opcode=7, buf=0x20001000, len=0x1000, \
+             offset=0x0, valid=0x18) = MemoryReturned(0x0, 0x0)",
+            "[tid 0] SendMessage(cid=2, BlockingScalar, opcode=0, args=[0x0, 0x0, 0x0, 0x0]) \
+             = Scalar1(0x1)",
+            "[tid 0] ExitThread(result=100) = <thread exit 100>",
+        ],
+        lines
+    );
+}
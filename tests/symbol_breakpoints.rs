@@ -0,0 +1,66 @@
+//! Breakpoints given as `symbol+offset` rather than as addresses. The guest
+//! in `guests/countdown.S` loops in `spin`, a `c.addi` followed by a
+//! `c.bnez`, and `guests/script.S` is loaded after it to resolve a
+//! breakpoint on a symbol only it has.
+
+use yove::xous::breakpoint_script::Location;
+use yove::xous::{Machine, MachineBuilder, MachineEvent};
+
+fn machine() -> Machine {
+    MachineBuilder::new()
+        .build(include_bytes!("guests/countdown.elf"))
+        .unwrap()
+}
+
+#[test]
+fn stops_at_an_offset_into_a_function() {
+    let mut machine = machine();
+    let spin = machine.symbol_address("spin").unwrap();
+    assert_eq!(
+        Some(spin + 2),
+        machine.add_breakpoint_at("spin+2".parse().unwrap())
+    );
+    assert_eq!(
+        MachineEvent::Stopped {
+            tid: 0,
+            pc: spin + 2
+        },
+        machine.step().unwrap()
+    );
+    // The `c.addi` has run once
+    assert_eq!(99, machine.thread_state(0).unwrap().registers[10]);
+    assert!(machine.remove_breakpoint(spin + 2));
+}
+
+#[test]
+fn unknown_symbols_wait_for_a_program_that_has_them() {
+    let mut machine = machine();
+    let verdict: Location = "verdict+0x0".parse().unwrap();
+    assert_eq!(None, machine.add_breakpoint_at(verdict.clone()));
+    assert_eq!(&[verdict][..], machine.pending_breakpoints());
+    assert_eq!(0, machine.breakpoints().count());
+
+    machine
+        .load_program(include_bytes!("guests/script.elf"))
+        .unwrap();
+    let address = machine.symbol_address("verdict").unwrap();
+    assert!(machine.pending_breakpoints().is_empty());
+    assert_eq!(vec![address], machine.breakpoints().collect::<Vec<_>>());
+}
+
+#[test]
+fn locations_are_symbols_offsets_or_addresses() {
+    assert_eq!(
+        Location::Address(0x2000_1234),
+        "0x20001234".parse().unwrap()
+    );
+    assert_eq!(
+        Location::Symbol {
+            name: "decrypt".into(),
+            offset: 16
+        },
+        "decrypt+0x10".parse().unwrap()
+    );
+    assert!("decrypt+x".parse::<Location>().is_err());
+    assert!("+0x4".parse::<Location>().is_err());
+}
@@ -0,0 +1,44 @@
+//! Per-thread instruction counts and stack usage. The guest in
+//! `guests/threadstats.S` starts a thread that spins through 1000 iterations
+//! of a two-instruction loop, and checks the count the perf counter service
+//! reports for it. The one in `guests/stackuse.S` recurses through 64-byte
+//! frames, 8 deep on the main thread and 4 deep on a thread with a 4096-byte
+//! stack.
+
+use yove::xous::MachineBuilder;
+
+#[test]
+fn counts_each_threads_instructions() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/threadstats.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let stats = machine.thread_stats();
+    assert_eq!(
+        vec![0, 1],
+        stats.iter().map(|stats| stats.tid).collect::<Vec<_>>()
+    );
+    assert!(stats[1].exited);
+    assert!((2000..2016).contains(&stats[1].instructions_retired));
+    assert!(stats[0].instructions_retired > 0);
+}
+
+#[test]
+fn marks_how_far_each_stack_went() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/stackuse.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let stats = machine.thread_stats();
+    // The main thread's stack also holds its arguments
+    assert!((8 * 64..8 * 64 + 4096).contains(&stats[0].stack_used()));
+    assert_eq!(4096, stats[1].stack.len());
+    // Threads start 16 bytes below the top of their stack
+    assert_eq!(16 + 4 * 64, stats[1].stack_used());
+    assert_eq!(
+        stats[1].stack.end - stats[1].stack_used(),
+        stats[1].lowest_sp
+    );
+}
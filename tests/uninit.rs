@@ -0,0 +1,22 @@
+//! Uninitialized read detection. The guest in `guests/uninit.S` reads one
+//! word of freshly mapped memory that it never wrote, at the label
+//! `uninitialized_load`, exiting with the number of the first check that failed.
+
+use yove::xous::MachineBuilder;
+
+/// The address of `uninitialized_load` in `guests/uninit.elf`.
+const UNINITIALIZED_LOAD: u32 = 0x2000_0054;
+
+#[test]
+fn reports_only_the_uninitialized_read() {
+    let mut machine = MachineBuilder::new()
+        .detect_uninitialized_reads()
+        .build(include_bytes!("guests/uninit.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    let reads = machine.uninitialized_reads();
+    assert_eq!(1, reads.len(), "{:x?}", reads);
+    assert_eq!(UNINITIALIZED_LOAD, reads[0].pc);
+    assert_eq!(8, reads[0].address & 0xfff);
+}
@@ -0,0 +1,28 @@
+//! Unmapping memory. The guest in `guests/unmap.S` maps three pages, unmaps
+//! the middle one twice and then the whole range, and tries ranges that are
+//! misaligned or belong to the kernel, all of which fail without stopping
+//! the emulator.
+
+use yove::xous::MachineBuilder;
+
+const REGION: u32 = 0x4000_0000;
+
+#[test]
+fn unmapping_unmapped_pages_fails() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/unmap.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+
+    // Only the three pages mapped afterwards are left
+    let pages: Vec<u32> = machine
+        .mappings()
+        .iter()
+        .map(|mapping| mapping.virt)
+        .filter(|virt| (REGION..REGION + 0x6000).contains(virt))
+        .collect();
+    assert_eq!(
+        vec![REGION + 0x3000, REGION + 0x4000, REGION + 0x5000],
+        pages
+    );
+}
@@ -0,0 +1,98 @@
+//! The emulated USB port behind the usb-device server. The guest in
+//! `guests/usb.S` types as a keyboard, then writes "ping" to the serial port
+//! and waits for "pong". The guest in `guests/usbflood.S` writes and types
+//! more than the host keeps.
+
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use yove::xous::usb::{UsbCore, HISTORY_LIMIT};
+use yove::xous::MachineBuilder;
+
+const PROGRAM: &[u8] = include_bytes!("guests/usb.elf");
+
+/// A writer whose output the test can still see once it's been handed over.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn keystrokes_and_serial_reach_the_host() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    let usb = machine.usb();
+    usb.set_leds(2);
+    usb.send_serial(b"pong");
+    assert_eq!(0, machine.run().unwrap());
+
+    assert_eq!("hi there\n", usb.typed());
+    assert_eq!(b"ping", &usb.serial_output()[..]);
+    assert_eq!(UsbCore::Device, usb.core());
+}
+
+#[test]
+fn ports_can_be_bridged_to_host_streams() {
+    let (keyboard, serial) = (Shared::default(), Shared::default());
+    let mut machine = MachineBuilder::new()
+        .usb_keyboard(Box::new(keyboard.clone()))
+        .usb_serial(Box::new(&b"pong"[..]), Box::new(serial.clone()))
+        .build(PROGRAM)
+        .unwrap();
+    machine.usb().set_leds(2);
+    assert_eq!(0, machine.run().unwrap());
+
+    assert_eq!(b"hi there\n", &keyboard.0.lock().unwrap()[..]);
+    assert_eq!(b"ping", &serial.0.lock().unwrap()[..]);
+}
+
+#[test]
+fn only_the_latest_output_is_kept() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/usbflood.elf"))
+        .unwrap();
+    let usb = machine.usb();
+    assert_eq!(0, machine.run().unwrap());
+
+    let output = usb.serial_output();
+    assert_eq!(HISTORY_LIMIT, output.len());
+    assert!(output.iter().all(|&byte| byte == b's'));
+    let typed = usb.typed();
+    assert_eq!(HISTORY_LIMIT, typed.len());
+    assert!(typed.chars().all(|c| c == 't'));
+}
+
+/// A serial port that never has anything to read, which notes when it's
+/// dropped.
+struct Quiet(Arc<AtomicBool>);
+
+impl Read for Quiet {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(ErrorKind::WouldBlock.into())
+    }
+}
+
+impl Drop for Quiet {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn dropping_the_machine_stops_reading_the_serial_port() {
+    let dropped = Arc::new(AtomicBool::new(false));
+    let machine = MachineBuilder::new()
+        .usb_serial(Box::new(Quiet(dropped.clone())), Box::new(std::io::sink()))
+        .build(PROGRAM)
+        .unwrap();
+    drop(machine);
+    assert!(dropped.load(Ordering::SeqCst));
+}
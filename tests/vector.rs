@@ -0,0 +1,37 @@
+//! The vector unit. The guest in `guests/vector.S` adds two arrays of ten
+//! words with vector instructions and checks the sums.
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::{LoadError, MachineBuilder};
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/vector.elf");
+
+#[test]
+fn adds_arrays_a_register_at_a_time() {
+    let mut machine = MachineBuilder::new().vector(128).build(PROGRAM).unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn vector_instructions_are_illegal_without_a_vector_unit() {
+    let mut machine = MachineBuilder::new().build(PROGRAM).unwrap();
+    match machine.run() {
+        Err(YoveError::Trap { trap, .. }) => {
+            assert!(matches!(trap.trap_type, TrapType::IllegalInstruction));
+            // vsetvli t0, a0, e32, m1, ta, ma
+            assert_eq!(0x0d05_72d7, trap.value);
+        }
+        result => panic!("expected an illegal instruction, got {:?}", result),
+    }
+}
+
+#[test]
+fn rejects_invalid_lengths() {
+    for vlen in [16, 96, 131072] {
+        assert!(matches!(
+            MachineBuilder::new().vector(vlen).build(PROGRAM),
+            Err(YoveError::Load(LoadError::InvalidVlen(v))) if v == vlen
+        ));
+    }
+}
@@ -0,0 +1,61 @@
+//! Keeping pages writable or executable but not both. The guest in
+//! `guests/wx.S` writes a function into its data, swaps the page to
+//! executable with `UpdateMemoryFlags`, and calls it, then writes to its own
+//! code, which only goes through when pages can be both. The one in
+//! `guests/wxdata.S` checks that a read-only page can't be made writable
+//! again, then calls a function it wrote into its data without swapping it.
+
+use riscv_cpu::cpu::TrapType;
+use yove::xous::MachineBuilder;
+use yove::YoveError;
+
+#[test]
+fn code_is_writable_by_default() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/wx.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn writing_code_faults() {
+    let mut machine = MachineBuilder::new()
+        .enforce_w_xor_x()
+        .build(include_bytes!("guests/wx.elf"))
+        .unwrap();
+    let start = machine.symbol_address("_start").unwrap();
+    let store = machine.symbol_address("store").unwrap();
+    match machine.run() {
+        Err(YoveError::Trap { trap, pc, .. }) => {
+            assert!(matches!(trap.trap_type, TrapType::StoreAccessFault));
+            assert_eq!(start, trap.value);
+            assert_eq!(store, pc);
+        }
+        result => panic!("expected an access fault, got {:?}", result),
+    }
+}
+
+#[test]
+fn data_is_executable_by_default() {
+    let mut machine = MachineBuilder::new()
+        .build(include_bytes!("guests/wxdata.elf"))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn running_data_faults() {
+    let mut machine = MachineBuilder::new()
+        .enforce_w_xor_x()
+        .build(include_bytes!("guests/wxdata.elf"))
+        .unwrap();
+    let jit = machine.symbol_address("jit").unwrap();
+    match machine.run() {
+        Err(YoveError::Trap { trap, pc, .. }) => {
+            assert!(matches!(trap.trap_type, TrapType::InstructionAccessFault));
+            assert_eq!(jit, trap.value);
+            assert_eq!(jit, pc);
+        }
+        result => panic!("expected an access fault, got {:?}", result),
+    }
+}
@@ -0,0 +1,63 @@
+//! The emulated watchdog. The guest in `guests/watchdog.S` runs for 200ms,
+//! calling `keep_alive` all the while, which pets the watchdog unless it's
+//! been stubbed out or patched to ask for a suspend instead.
+
+use yove::xous::{LoadError, Machine, MachineBuilder};
+use yove::YoveError;
+
+const PROGRAM: &[u8] = include_bytes!("guests/watchdog.elf");
+
+fn machine() -> Machine {
+    MachineBuilder::new().watchdog(50).build(PROGRAM).unwrap()
+}
+
+/// A `j` from `from` to `to`.
+fn jump(from: u32, to: u32) -> [u8; 4] {
+    let offset = to.wrapping_sub(from);
+    let instruction = (offset & 0x10_0000) << 11
+        | (offset & 0x7fe) << 20
+        | (offset & 0x800) << 9
+        | (offset & 0xf_f000)
+        | 0x6f;
+    instruction.to_le_bytes()
+}
+
+#[test]
+fn petting_keeps_the_program_running() {
+    assert_eq!(0, machine().run().unwrap());
+}
+
+#[test]
+fn forgetting_to_pet_stops_the_program() {
+    let mut machine = machine();
+    machine.stub_symbol("keep_alive", 0).unwrap();
+    match machine.run() {
+        Err(YoveError::Watchdog {
+            timeout_ms, tid, ..
+        }) => {
+            assert_eq!(50, timeout_ms);
+            assert_eq!(0, tid);
+        }
+        result => panic!("expected the watchdog to expire, got {:?}", result),
+    }
+}
+
+#[test]
+fn suspending_restarts_the_countdown() {
+    let mut machine = machine();
+    let keep_alive = machine.symbol_address("keep_alive").unwrap();
+    let suspend = machine.symbol_address("suspend").unwrap();
+    machine
+        .patch(keep_alive, &jump(keep_alive, suspend))
+        .unwrap();
+    assert_eq!(0, machine.run().unwrap());
+}
+
+#[test]
+fn a_zero_timeout_is_refused() {
+    let result = MachineBuilder::new().watchdog(0).build(PROGRAM);
+    assert!(matches!(
+        result,
+        Err(YoveError::Load(LoadError::InvalidWatchdogTimeout))
+    ));
+}